@@ -0,0 +1,117 @@
+// vpn_client/src/auth.rs
+// 客户端认证凭据获取：OIDC 设备授权流程 / 直接提供 token / LDAP 用户名密码
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use vpn_core::handshake::AuthCredential;
+
+/// 根据命令行参数和环境变量准备认证凭据，未配置时返回 None
+///
+/// * `--oidc-token <token>` 或环境变量 VPN_OIDC_TOKEN
+/// * `--oidc-device-url <url> --oidc-token-url <url> --oidc-client-id <id>`：设备授权流程
+/// * `--ldap-user <name>`，密码取自环境变量 VPN_LDAP_PASSWORD
+pub async fn credential_from_args(args: &[String]) -> Result<Option<AuthCredential>> {
+    if let Some(token) = crate::arg_value(args, "--oidc-token").or_else(|| std::env::var("VPN_OIDC_TOKEN").ok()) {
+        return Ok(Some(AuthCredential::OidcToken { access_token: token }));
+    }
+
+    if let Some(device_url) = crate::arg_value(args, "--oidc-device-url") {
+        let token_url = crate::arg_value(args, "--oidc-token-url")
+            .ok_or_else(|| anyhow!("设备授权流程需要 --oidc-token-url"))?;
+        let client_id = crate::arg_value(args, "--oidc-client-id")
+            .ok_or_else(|| anyhow!("设备授权流程需要 --oidc-client-id"))?;
+
+        let access_token = device_flow(&device_url, &token_url, &client_id).await?;
+        return Ok(Some(AuthCredential::OidcToken { access_token }));
+    }
+
+    if let Some(username) = crate::arg_value(args, "--ldap-user") {
+        let password = std::env::var("VPN_LDAP_PASSWORD")
+            .map_err(|_| anyhow!("LDAP 认证需要环境变量 VPN_LDAP_PASSWORD"))?;
+        return Ok(Some(AuthCredential::LdapBind { username, password }));
+    }
+
+    Ok(None)
+}
+
+/// OIDC 设备授权流程（RFC 8628）：提示用户在浏览器中授权，轮询直到拿到 access token
+async fn device_flow(device_url: &str, token_url: &str, client_id: &str) -> Result<String> {
+    let response = post_form(device_url, &[("client_id", client_id), ("scope", "openid")]).await?;
+
+    let device_code = response["device_code"].as_str()
+        .ok_or_else(|| anyhow!("设备授权响应缺少 device_code"))?
+        .to_string();
+    let user_code = response["user_code"].as_str().unwrap_or("");
+    let verification_uri = response["verification_uri_complete"].as_str()
+        .or_else(|| response["verification_uri"].as_str())
+        .ok_or_else(|| anyhow!("设备授权响应缺少 verification_uri"))?;
+    let mut interval = response["interval"].as_u64().unwrap_or(5);
+    let expires_in = response["expires_in"].as_u64().unwrap_or(600);
+
+    println!("🪪 请在浏览器中打开以下地址完成登录:");
+    println!("   {}", verification_uri);
+    if !user_code.is_empty() {
+        println!("   验证码: {}", user_code);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(expires_in);
+    while Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+
+        let response = post_form(token_url, &[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", &device_code),
+            ("client_id", client_id),
+        ]).await?;
+
+        if let Some(token) = response["access_token"].as_str() {
+            println!("   ✅ 登录成功");
+            return Ok(token.to_string());
+        }
+
+        match response["error"].as_str() {
+            Some("authorization_pending") => {}
+            Some("slow_down") => interval += 5,
+            Some(err) => return Err(anyhow!("设备授权失败: {}", err)),
+            None => return Err(anyhow!("token 响应格式错误")),
+        }
+    }
+
+    Err(anyhow!("设备授权超时"))
+}
+
+/// 通过 curl 发送 application/x-www-form-urlencoded 请求并解析 JSON 响应
+///
+/// 参数经由 `curl --config -` 从 stdin 传入，避免出现在进程列表里
+async fn post_form(url: &str, fields: &[(&str, &str)]) -> Result<serde_json::Value> {
+    let mut config = String::from("silent\n");
+    for value in std::iter::once(url).chain(fields.iter().map(|(_, v)| *v)) {
+        if value.contains(['"', '\\', '\n', '\r']) {
+            return Err(anyhow!("请求参数包含非法字符"));
+        }
+    }
+    for (key, value) in fields {
+        config.push_str(&format!("data-urlencode = \"{}={}\"\n", key, value));
+    }
+    config.push_str(&format!("url = \"{}\"\n", url));
+
+    let output = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let mut child = Command::new("curl")
+            .args(["--config", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("无法执行 curl: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(config.as_bytes())?;
+        }
+        Ok(child.wait_with_output()?.stdout)
+    }).await??;
+
+    serde_json::from_slice(&output).map_err(|e| anyhow!("响应不是合法 JSON: {}", e))
+}
@@ -0,0 +1,178 @@
+// vpn_client/src/bond.rs
+// 多路径绑定（--bond <网卡>，实验性，仅 Linux）：经第二块网卡同时发送上行数据
+//
+// 第二个 UDP socket 绑定到指定网卡（SO_BINDTODEVICE），会话建立后周期性发送 PathJoin，
+// 收到服务端的 PathAck 后第二条路径才参与发送；超过 PATH_TIMEOUT 没有确认则退回只用主路径。
+// 下行和控制消息仍只走主路径。两种模式（--bond-mode）的区别见 vpn_core::multipath。
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+
+use vpn_core::control::KeyRing;
+use vpn_core::datapath_log::DataPathLog;
+use vpn_core::handshake::{HandshakeMessage, deserialize_message, serialize_message};
+use vpn_core::multipath::{self, BondMode};
+use vpn_core::trace_packet;
+
+use crate::endpoint::ServerEndpoint;
+
+/// 第二条路径加入前 PathJoin 的重发间隔
+const JOIN_RETRY: Duration = Duration::from_secs(1);
+
+/// 加入后 PathJoin 兼作第二条路径的保活
+const JOIN_REFRESH: Duration = Duration::from_secs(5);
+
+/// 这么久没有收到 PathAck，认为第二条路径不可用
+const PATH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// 第二条上行路径
+pub struct BondPath {
+    socket: UdpSocket,
+    interface: String,
+    mode: BondMode,
+    /// 最近一次收到 PathAck 的时间
+    last_ack: Mutex<Option<Instant>>,
+    /// round-robin 模式的包序号（偶数走主路径，奇数走第二条路径）
+    next_seq: AtomicU64,
+}
+
+impl BondPath {
+    /// `--bond <网卡> [--bond-mode duplicate|round-robin]`，未指定 --bond 时返回 None
+    ///
+    /// 使用策略路由时第二个 socket 也要打上 fwmark，否则它的包会被路由回隧道
+    pub fn from_args(args: &[String], fwmark: Option<u32>) -> Result<Option<Self>> {
+        let Some(interface) = crate::arg_value(args, "--bond") else { return Ok(None) };
+        let mode = BondMode::parse(crate::arg_value(args, "--bond-mode").as_deref())?;
+        let socket = bind_secondary(&interface, fwmark)?;
+        Ok(Some(Self {
+            socket: UdpSocket::from_std(socket)?,
+            interface,
+            mode,
+            last_ack: Mutex::new(None),
+            next_seq: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    pub fn mode(&self) -> BondMode {
+        self.mode
+    }
+
+    /// 第二条路径是否可用（最近收到过 PathAck）
+    pub fn is_active(&self) -> bool {
+        self.last_ack.lock().unwrap().is_some_and(|at| at.elapsed() < PATH_TIMEOUT)
+    }
+
+    /// 启动加入任务：发送 PathJoin 并接收 PathAck
+    pub fn spawn(self: &Arc<Self>, endpoint: Arc<ServerEndpoint>, keys: Arc<KeyRing>) {
+        let path = self.clone();
+        let join_keys = keys.clone();
+        tokio::spawn(async move {
+            let mut was_active = false;
+            loop {
+                let active = path.is_active();
+                if was_active && !active {
+                    println!("⚠️ 第二条路径 {} 无响应，暂时只使用主路径", path.interface);
+                }
+                was_active = active;
+
+                let session_key = join_keys.current_key();
+                if let Ok(proof) = multipath::join_proof(&session_key)
+                    && let Ok(data) = serialize_message(&HandshakeMessage::PathJoin { path_id: multipath::path_id(&session_key), proof })
+                    && let Err(e) = path.socket.send_to(&data, endpoint.addr()).await
+                {
+                    trace_packet!("❌ 经 {} 发送 PathJoin 失败: {}", path.interface, e);
+                }
+                tokio::time::sleep(if active { JOIN_REFRESH } else { JOIN_RETRY }).await;
+            }
+        });
+
+        let path = self.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                let Ok((n, _)) = path.socket.recv_from(&mut buf).await else { continue };
+                let Ok(HandshakeMessage::PathAck { proof }) = deserialize_message(&buf[..n]) else { continue };
+                if multipath::verify_join_ack(&keys.current_key(), &proof).is_err() {
+                    continue;
+                }
+                let previous = path.last_ack.lock().unwrap().replace(Instant::now());
+                if previous.is_none_or(|at| at.elapsed() >= PATH_TIMEOUT) {
+                    println!("🔀 第二条路径 {} 已加入（{} 模式）", path.interface, path.mode.as_str());
+                }
+            }
+        });
+    }
+
+    /// 经两条路径发送一个上行 IP 包（调用前确认 is_active）
+    pub async fn send_uplink(&self, primary: &UdpSocket, server_addr: SocketAddr, keys: &KeyRing, datapath: &DataPathLog, ip_packet: &[u8]) {
+        // (明文, 是否经第二条路径发送, 是否还要经主路径发送)
+        let (plaintext, via_secondary, via_primary) = match self.mode {
+            BondMode::Duplicate => (None, true, true),
+            BondMode::RoundRobin => {
+                let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+                let primary_turn = seq.is_multiple_of(2);
+                (Some(multipath::wrap(seq, ip_packet)), !primary_turn, primary_turn)
+            }
+        };
+        let encrypted = match keys.encrypt(plaintext.as_deref().unwrap_or(ip_packet)) {
+            Ok(data) => data,
+            Err(e) => {
+                trace_packet!("❌ 加密失败: {}", e);
+                datapath.dropped("encrypt_failed");
+                return;
+            }
+        };
+
+        // duplicate：同一份密文两条路径各发一次；round-robin：轮流使用，第二条路径发送失败时改走主路径
+        if via_secondary {
+            let sent = self.socket.send_to(&encrypted, server_addr).await.is_ok();
+            if sent && !via_primary {
+                datapath.forwarded("uplink_bonded", ip_packet.len());
+                return;
+            }
+        }
+        match primary.send_to(&encrypted, server_addr).await {
+            Ok(_) => datapath.forwarded("uplink", ip_packet.len()),
+            Err(e) => {
+                trace_packet!("❌ UDP 发送错误: {}", e);
+                datapath.dropped("udp_send_failed");
+            }
+        }
+    }
+}
+
+/// 创建绑定到指定网卡的 UDP socket
+#[cfg(target_os = "linux")]
+fn bind_secondary(interface: &str, fwmark: Option<u32>) -> Result<std::net::UdpSocket> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    multipath::bind_to_device(&socket, interface)?;
+    if let Some(mark) = fwmark {
+        vpn_core::local_tun::set_fwmark(&socket, mark)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_secondary(_interface: &str, _fwmark: Option<u32>) -> Result<std::net::UdpSocket> {
+    Err(anyhow!("--bond 目前只支持 Linux"))
+}
+
+/// --bond-mode 只在指定了 --bond 时有意义
+pub fn check_args(args: &[String]) -> Result<()> {
+    let mode = crate::arg_value(args, "--bond-mode");
+    if mode.is_some() && crate::arg_value(args, "--bond").is_none() {
+        return Err(anyhow!("--bond-mode 需要配合 --bond <网卡> 使用"));
+    }
+    BondMode::parse(mode.as_deref())?;
+    Ok(())
+}
@@ -0,0 +1,278 @@
+// vpn_client/src/diagnose.rs
+// 握手诊断（--diagnose）：逐项检查连接服务端的各个环节，失败时给出可能的原因
+//
+// 正常启动时握手失败只能看到一个超时错误，分不清是域名解析不到、UDP 被防火墙拦截、
+// 服务端公钥不匹配还是两端 PSK 不一致。诊断模式不需要 root、不创建 TUN、不修改路由，按顺序执行：
+//
+// 1. 解析服务器地址
+// 2. 加载服务端公钥和客户端身份
+// 3. 发送 ClientHello，等待服务端的任何 UDP 响应（要求 cookie 时带上重发）
+// 4. 验证 ServerHello 的签名，派生会话密钥
+// 5. 在隧道内发送一个 Echo：收到回复说明两端的 PSK 一致、会话可用
+//
+// 诊断会用本机身份和服务端完成一次真实握手，结束时发送 Disconnect；
+// 隧道正在运行时，服务端可能按 --duplicate-policy 替换掉现有的会话。
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+
+use vpn_core::asymmetric::{ClientVerifier, get_keys_dir};
+use vpn_core::control::{self, ControlMessage, KeyRing, PayloadKind};
+use vpn_core::resume;
+use vpn_core::wire::{self, Datagram};
+use vpn_core::handshake::{
+    ClientHandshake, HandshakeErrorCode, HandshakeMessage, describe_handshake_error, deserialize_message, handshake_error_message,
+    serialize_message, client_finish, verify_server_finish,
+};
+
+use crate::endpoint;
+
+/// 每一步等待服务端响应的时间
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 诊断用 Echo 的 ID
+const ECHO_ID: u32 = 0x0d1a_0001;
+
+/// 服务端对一次请求的响应：握手消息，或没有收到握手消息的原因
+type Reply = Result<HandshakeMessage, NoReply>;
+
+enum NoReply {
+    /// 服务器地址发来了 UDP 包，但不是握手消息（端口上可能是别的服务）
+    Unrecognized(usize),
+    /// 超时，没有收到服务器地址发来的任何包
+    Silence,
+}
+
+/// 打印失败原因和建议，返回结束诊断的错误
+fn failed(stage: &str, reason: impl std::fmt::Display, hints: &[&str]) -> anyhow::Error {
+    println!("   ❌ {}", reason);
+    for hint in hints {
+        println!("   💡 {}", hint);
+    }
+    anyhow!("诊断未通过：{}", stage)
+}
+
+/// 握手没有任何响应时的可能原因
+const NO_RESPONSE_HINTS: &[&str] = &[
+    "服务端没有运行，或监听的不是这个端口（服务端 --listen）",
+    "防火墙 / 安全组拦截了 UDP：服务端需要放行入站 UDP，本机网络可能封锁了出站 UDP",
+    "服务端拒绝了请求但不回复（服务端启用了 --stealth，或是不支持 HandshakeError 的旧版本），可在服务端运行 vpn_server denials 查看原因",
+];
+
+/// `--diagnose`：server 为命令行给出的 host:port，virtual_ip 为 ClientHello 中声明的虚拟 IP
+pub async fn run(args: &[String], server: &str, virtual_ip: String) -> Result<()> {
+    println!("🩺 握手诊断: {}", server);
+
+    println!("1️⃣  解析服务器地址");
+    let started = Instant::now();
+    let addr = endpoint::lookup(server).await.map_err(|e| failed("解析服务器地址", e, &[
+        "检查地址的拼写和端口（host:port）",
+        "检查本机 DNS 是否可用（例如 nslookup 该域名）；使用 DDNS 时确认记录已经更新",
+    ]))?;
+    println!("   ✅ {} -> {}（{} ms）", server, addr, started.elapsed().as_millis());
+
+    println!("2️⃣  加载密钥");
+    let verifier = match crate::arg_value(args, "--server-key") {
+        Some(key) => {
+            let verifier = ClientVerifier::from_hex(&key).map_err(|e| failed("加载服务端公钥", e, &[
+                "--server-key 应为 64 个十六进制字符（例如 xxd -p -c 32 server_public.key 的输出）",
+            ]))?;
+            println!("   ✅ 服务端公钥: --server-key {}", key.trim());
+            verifier
+        }
+        None => {
+            let public_key_path = get_keys_dir()?.join("server_public.key");
+            let verifier = ClientVerifier::load_from_file(&public_key_path).map_err(|e| {
+                failed("加载服务端公钥", format!("无法读取 {}: {}", public_key_path.display(), e), &[
+                    "从服务端复制 server_public.key 到这个位置（服务端首次启动时生成），或用 --server-key 指定",
+                ])
+            })?;
+            println!("   ✅ 服务端公钥: {}", public_key_path.display());
+            verifier
+        }
+    };
+    let identity = crate::load_identity(args).map_err(|e| failed("加载客户端身份", e, &[
+        "检查 --identity-dir 的权限；使用 --agent-socket 时确认密钥代理正在运行",
+    ]))?;
+    println!("   ✅ 客户端身份: {}（公钥 {}）", identity.id(), identity.fingerprint());
+
+    println!("3️⃣  UDP 可达性（发送 ClientHello，最多等待 {} 秒）", PROBE_TIMEOUT.as_secs());
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let psk = vpn_core::psk::from_args(args).map_err(|e| failed("加载 PSK", e, &["检查 --psk-file 指定的文件"]))?;
+    let mut handshake = ClientHandshake::new(&psk);
+    let mut hello = handshake.create_client_hello(&identity, virtual_ip)?;
+    let HandshakeMessage::ClientHello { client_pubkey, .. } = hello else { unreachable!() };
+    socket.send_to(&serialize_message(&hello)?, addr).await?;
+    let mut reply = recv_reply(&socket, addr).await;
+    if let Ok(HandshakeMessage::Cookie { cookie }) = reply {
+        println!("   🍪 服务端要求验证来源地址，带上 cookie 重发");
+        if let HandshakeMessage::ClientHello { cookie: hello_cookie, .. } = &mut hello {
+            *hello_cookie = cookie;
+        }
+        socket.send_to(&serialize_message(&hello)?, addr).await?;
+        reply = recv_reply(&socket, addr).await;
+    }
+    let server_hello = match reply {
+        Ok(server_hello @ HandshakeMessage::ServerHello { .. }) => server_hello,
+        Ok(HandshakeMessage::HandshakeError { code, detail, observed_addr, signature }) => {
+            let message = handshake_error_message(code, &detail, &client_pubkey, observed_addr);
+            let verified = !signature.is_empty() && verifier.verify(&message, &signature).is_ok();
+            let reason = format!(
+                "服务端拒绝了握手{}：{}",
+                if verified { "" } else { "（响应未经验证）" },
+                describe_handshake_error(code, &detail)
+            );
+            let hints: &[&str] = match HandshakeErrorCode::from_u8(code) {
+                Some(HandshakeErrorCode::BadVersion) => &["将客户端和服务端升级到同一版本"],
+                Some(HandshakeErrorCode::Unauthorized) => &[
+                    "客户端重新生成过身份：在服务端 keys/known_clients 中删除该客户端 ID 的旧记录",
+                    "服务端轮换过 PSK：把新的 PSK 文件复制到 --psk-file 指定的位置",
+                ],
+                Some(HandshakeErrorCode::ServerFull) => &["稍后重试，或请管理员调大服务端 --max-clients"],
+                Some(HandshakeErrorCode::IpConflict) => &[
+                    "换一个虚拟 IP，或检查服务端 --client-ip-map 中绑定给本机身份的地址",
+                    "另一台设备正在使用这个虚拟 IP；旧会话刚断开时等保活超时后再试",
+                ],
+                Some(HandshakeErrorCode::UpgradeRequired) => &["按上面的说明升级客户端（服务端 --min-client-version）"],
+                Some(HandshakeErrorCode::BadKeyShare) => &[
+                    "两端使用同一版本的官方构建；自行修改过握手代码时检查 ML-KEM-768 公钥的编码",
+                    "网络中间有设备改写了握手消息",
+                ],
+                Some(HandshakeErrorCode::DuplicateSession) => &[
+                    "同一身份已在其他地方连接（服务端 --duplicate-policy reject）；本机隧道正在运行时先断开再诊断",
+                ],
+                None => &["两端版本可能不一致"],
+            };
+            return Err(failed("握手", reason, hints));
+        }
+        Ok(HandshakeMessage::ServerFinish { success: false, .. }) => {
+            return Err(failed("握手", "服务端拒绝了握手", &[
+                "旧版本服务端：同一身份已在其他地方连接（服务端 --duplicate-policy reject）；本机隧道正在运行时先断开再诊断",
+            ]));
+        }
+        Ok(other) => return Err(failed("握手", format!("收到意外的握手消息: {:?}", other), &["两端版本可能不一致"])),
+        Err(NoReply::Unrecognized(n)) => {
+            return Err(failed("UDP 可达性", format!("{} 返回了 {} 字节的非握手数据", addr, n), &[
+                "这个端口上运行的可能不是 VPN 服务端，检查端口号",
+                "两端版本差异过大，握手消息格式不兼容",
+            ]));
+        }
+        Err(NoReply::Silence) => return Err(failed("UDP 可达性", format!("{} 秒内没有收到 {} 的任何响应", PROBE_TIMEOUT.as_secs(), addr), NO_RESPONSE_HINTS)),
+    };
+    let HandshakeMessage::ServerHello { server_pubkey, ref mlkem_ciphertext, observed_addr, server_time, assigned_ip, .. } = server_hello else { unreachable!() };
+    println!("   ✅ 收到 ServerHello（服务端看到的本机地址: {}）", observed_addr);
+
+    println!("4️⃣  服务端身份");
+    handshake.verify_server_hello(&hello, &server_hello, &verifier).map_err(|e| failed("服务端身份", format!("签名验证失败: {}", e), &[
+        "server_public.key（或 --server-key）与这台服务端不匹配：服务端重新生成过密钥，或地址指向了另一台服务端",
+        "网络中间有设备改写了握手（签名覆盖整个握手转录，包括服务端看到的本机地址；对称 NAT 不影响验证）",
+    ]))?;
+    let session_key = handshake.process_server_hello(server_pubkey, mlkem_ciphertext)?;
+    println!("   ✅ 签名有效，会话密钥已派生");
+    if let Some(ip) = assigned_ip {
+        println!("   ✅ 服务端分配的虚拟 IP: {}", ip);
+    }
+    match server_time.map(resume::record_server_time) {
+        Some(offset) if offset.unsigned_abs() > resume::SKEW_WARN_THRESHOLD => {
+            println!("   ⚠️ 本机时钟与服务端相差 {} 秒（隧道会按服务端时间校正，但请检查本机的时间同步）", offset);
+        }
+        Some(offset) => println!("   ✅ 本机时钟与服务端相差 {} 秒", offset),
+        None => println!("   ℹ️  服务端没有提供时间（旧版本），无法检查时钟偏差"),
+    }
+    socket.send_to(&serialize_message(&client_finish(&session_key)?)?, addr).await?;
+    match recv_reply(&socket, addr).await {
+        Ok(HandshakeMessage::ServerFinish { success: true, encrypted_confirm }) => {
+            verify_server_finish(&encrypted_confirm, &session_key).map_err(|e| failed("服务端身份", format!("ServerFinish 验证失败: {}", e), &[
+                "网络中间有设备改写了握手消息",
+            ]))?;
+            println!("   ✅ 双方已确认会话密钥");
+        }
+        Ok(HandshakeMessage::ServerFinish { success: false, .. }) => {
+            return Err(failed("服务端身份", "服务端拒绝了密钥确认（ClientFinish）", &["两端版本可能不一致：密钥确认需要两端同时升级"]));
+        }
+        Err(NoReply::Silence) => {
+            return Err(failed("服务端身份", "服务端没有回复 ClientFinish", &["服务端是不支持密钥确认的旧版本，两端需要同时升级"]));
+        }
+        _ => return Err(failed("服务端身份", "预期收到 ServerFinish", &["两端版本可能不一致"])),
+    }
+
+    println!("5️⃣  隧道内往返（Echo）");
+    let keys = KeyRing::new(session_key)?;
+    let echo = ControlMessage::Echo { id: ECHO_ID, timestamp_us: control::monotonic_micros() };
+    crate::send_control(&socket, addr, &keys, &echo).await;
+    let result = await_echo_reply(&socket, addr, &keys).await;
+    crate::send_control(&socket, addr, &keys, &ControlMessage::Disconnect { reason: "diagnose finished".to_string() }).await;
+    match result {
+        EchoResult::Reply(rtt) => println!("   ✅ 收到 EchoReply，RTT {:.1} ms", rtt.as_secs_f64() * 1000.0),
+        EchoResult::Undecryptable(n) => {
+            return Err(failed("隧道内往返", format!("收到 {} 个无法解密的包", n), &[
+                "两端的 PSK 不一致：检查两端的 --psk-file（未指定时使用内置的 PSK）",
+            ]));
+        }
+        EchoResult::Silence => {
+            return Err(failed("隧道内往返", "握手成功，但服务端没有回复 Echo", &[
+                "服务端启用了外部认证（OIDC / LDAP），需要提供认证凭据，诊断模式不发送凭据",
+                "两端的 PSK 不一致时服务端无法解密，会静默丢弃",
+            ]));
+        }
+    }
+
+    println!("🎉 所有检查通过，可以正常建立隧道");
+    Ok(())
+}
+
+/// 等待服务器地址发来的下一个包（其他来源的包忽略）
+async fn recv_reply(socket: &UdpSocket, server: SocketAddr) -> Reply {
+    let deadline = tokio::time::Instant::now() + PROBE_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    loop {
+        match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(Ok((n, from))) if from == server => {
+                return match deserialize_message(&buf[..n]) {
+                    Ok(msg) => Ok(msg),
+                    Err(_) => Err(NoReply::Unrecognized(n)),
+                };
+            }
+            Ok(Ok((_, from))) => println!("   ℹ️  忽略来自 {} 的包", from),
+            Ok(Err(e)) => {
+                println!("   ⚠️  接收出错: {}", e);
+                return Err(NoReply::Silence);
+            }
+            Err(_) => return Err(NoReply::Silence),
+        }
+    }
+}
+
+enum EchoResult {
+    Reply(Duration),
+    /// 超时，期间收到的无法解密的包数
+    Undecryptable(usize),
+    Silence,
+}
+
+/// 等待诊断 Echo 的回复（服务端在第一个包之后下发的会话信息等控制消息忽略）
+async fn await_echo_reply(socket: &UdpSocket, server: SocketAddr, keys: &KeyRing) -> EchoResult {
+    let deadline = tokio::time::Instant::now() + PROBE_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    let mut undecryptable = 0;
+    while let Ok(Ok((n, from))) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        if from != server {
+            continue;
+        }
+        let Datagram::Data(body) = wire::classify(&buf[..n]) else { continue };
+        let Ok(plaintext) = keys.decrypt(body) else {
+            undecryptable += 1;
+            continue;
+        };
+        let Some(plaintext) = plaintext else { continue };
+        if control::classify(&plaintext) == PayloadKind::Control
+            && let Ok(ControlMessage::EchoReply { id: ECHO_ID, timestamp_us }) = ControlMessage::decode(&plaintext)
+        {
+            return EchoResult::Reply(Duration::from_micros(control::monotonic_micros().saturating_sub(timestamp_us)));
+        }
+    }
+    if undecryptable > 0 { EchoResult::Undecryptable(undecryptable) } else { EchoResult::Silence }
+}
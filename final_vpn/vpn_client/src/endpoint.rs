@@ -0,0 +1,62 @@
+// vpn_client/src/endpoint.rs
+// 服务器地址：启动时解析一次，之后所有发送都使用解析结果
+//
+// 服务器以域名给出时（例如配合 DDNS），记录的 IP 可能在客户端运行期间变化，
+// 控制任务在保活失败时调用 refresh 重新解析，地址变化后由网络任务重新握手。
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+
+use anyhow::{Result, anyhow};
+
+/// 服务器地址（原始的 host:port 和当前使用的解析结果）
+pub struct ServerEndpoint {
+    host: String,
+    addr: RwLock<SocketAddr>,
+}
+
+impl ServerEndpoint {
+    /// 解析 `host:port`（本地 socket 绑定在 0.0.0.0，只使用 IPv4 结果）
+    pub async fn resolve(host: &str) -> Result<Self> {
+        let addr = lookup(host).await?;
+        Ok(Self { host: host.to_string(), addr: RwLock::new(addr) })
+    }
+
+    /// 当前使用的地址
+    pub fn addr(&self) -> SocketAddr {
+        *self.addr.read().unwrap()
+    }
+
+    /// 命令行给出的原始地址
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// 是否以域名给出（IP 字面量不需要重新解析）
+    pub fn is_hostname(&self) -> bool {
+        self.host.parse::<SocketAddr>().is_err()
+    }
+
+    /// 重新解析域名，地址变化时更新并返回新地址
+    pub async fn refresh(&self) -> Result<Option<SocketAddr>> {
+        if !self.is_hostname() {
+            return Ok(None);
+        }
+        let addr = lookup(&self.host).await?;
+        let mut current = self.addr.write().unwrap();
+        if *current == addr {
+            return Ok(None);
+        }
+        *current = addr;
+        Ok(Some(addr))
+    }
+}
+
+/// 解析 `host:port`，只取 IPv4 结果（--stun 等其他地址也用它解析）
+pub async fn lookup(host: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(host)
+        .await
+        .map_err(|e| anyhow!("无法解析服务器地址 {}: {}", host, e))?
+        .find(|addr| matches!(addr.ip(), IpAddr::V4(_)))
+        .ok_or_else(|| anyhow!("服务器地址 {} 没有 IPv4 记录", host))
+}
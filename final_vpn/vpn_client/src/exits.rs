@@ -0,0 +1,734 @@
+// vpn_client/src/exits.rs
+// 多出口 / 多隧道：一个客户端进程同时维护多条到不同服务器的隧道
+//
+// * `--exit <虚拟IP>@<服务器>=<目的>,<目的>`：按目的地址选择出口，各出口的虚拟网段可以相同
+// * `--tunnel <名称>:<虚拟IP>@<服务器>[=<目的>,...]`：独立的隧道，各自使用不同的虚拟网段，
+//   虚拟网段路由写入主表；也可以再附加经由它的目的地址
+//
+// 每条隧道在本进程内运行：独立线程上的 tokio 运行时里执行普通单隧道模式的流程（main.rs 的 run_tunnel），
+// 各自握手、持有自己的 UDP socket、TUN 设备和转发引擎；设备名和路由表由监督任务统一分配，互不争抢。
+// 隧道结束时关闭它的运行时，这条隧道的所有任务随之结束，不影响其他隧道。监督任务不转发数据，只负责：
+//
+// * 隧道的设备出现后，把这个出口的目的网段路由到它的设备
+// * 定期重新解析域名，解析结果以 /32 路由到对应设备，地址变化时增删
+// * 隧道结束后等几秒重新启动，设备重建后重新添加路由
+// * 通过控制 socket（tunnels.rs）按名称启动、停止、重启单条隧道，查看状态
+// * Ctrl+C 时通知所有隧道断开
+//
+// 最多一个出口使用 default（其余所有流量），它以 --full-tunnel 启动。Linux 全隧道的策略路由先查主表中
+// 前缀长度大于 0 的路由，其他平台用两条 /1 覆盖默认路由，所以其他出口更具体的目的网段不受影响；
+// 其他出口的服务器地址需要加路由例外直连，否则它们的隧道流量会被套进默认出口。
+//
+// 各出口的服务端通常使用相同的虚拟网段，出口隧道都带 --allow-subnet-overlap 启动，Linux 上虚拟网段
+// 路由写入各自的路由表（EXIT_ROUTE_TABLE_BASE + 序号），互不覆盖。域名按本机 DNS 解析。
+
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use vpn_core::config::parse_cidr;
+use vpn_core::crash;
+use vpn_core::dryrun::{self, Category, Plan};
+use vpn_core::local_tun;
+use vpn_core::netwatch;
+use vpn_core::preflight;
+use vpn_core::sigguard;
+
+use crate::endpoint::ServerEndpoint;
+use crate::{arg_value, arg_values};
+
+/// 隧道结束后重启前的等待时间
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+/// 隧道结束后关闭它的运行时时，等待其余任务结束的时间
+const RUNTIME_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+/// 等待隧道创建设备时的轮询间隔
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// 域名重新解析的间隔
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+/// 有路由添加失败时的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// 出口隧道的虚拟网段路由表（Linux），第 i 个出口使用 BASE + i
+const EXIT_ROUTE_TABLE_BASE: u32 = 52100;
+
+/// 由监督任务为每条隧道单独指定、不透传的参数（带值）
+const PER_EXIT_OPTIONS: [&str; 17] = [
+    "--exit", "--tunnel", "--control-socket", "--virtual-ip", "--server", "--config", "--tun-name", "--route-table", "--route-metric", "--route", "--dns", "--discover-name",
+    // 启动隧道前已经等过网络（--wait-online）
+    "--wait-online-timeout",
+    // 日志在进程启动时已经打开，各隧道共用
+    "--log", "--log-max-size", "--log-rotate", "--log-keep",
+];
+/// 不透传的开关
+const PER_EXIT_FLAGS: [&str; 6] = ["--full-tunnel", "--discover", "--tun-reuse", "--no-session-resume", "--allow-subnet-overlap", "--wait-online"];
+
+/// 出口的一个目的地址
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    /// IPv4 网段（单个地址记为 /32）
+    Cidr(String),
+    /// 域名，定期解析
+    Domain(String),
+}
+
+/// 一条隧道（--exit / --tunnel 或配置文件中的 exits / tunnels）
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExitSpec {
+    /// 控制命令中使用的名称；--exit 依次命名为 exit0、exit1……
+    pub name: String,
+    /// 出口模式：虚拟网段路由放进单独的路由表，允许与其他隧道网段重叠
+    pub isolate: bool,
+    pub virtual_ip: Ipv4Addr,
+    pub server: String,
+    /// 目的中有 default：其余所有流量走这个出口
+    pub default_route: bool,
+    pub destinations: Vec<Destination>,
+}
+
+impl ExitSpec {
+    /// 解析 --exit：`<虚拟IP>@<host:port>=<目的>[,<目的>...]`，目的为 IPv4 网段/地址、域名或 default
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("无效的 --exit: {}（格式为 <虚拟IP>@<服务器>=<目的>,<目的>）", spec);
+        let (virtual_ip, rest) = spec.split_once('@').ok_or_else(invalid)?;
+        let (server, targets) = rest.split_once('=').ok_or_else(invalid)?;
+        let exit = Self::build(String::new(), true, virtual_ip, server, targets)?;
+        if !exit.default_route && exit.destinations.is_empty() {
+            return Err(anyhow!("--exit {} 没有指定目的地址", exit.server));
+        }
+        Ok(exit)
+    }
+
+    /// 解析 --tunnel：`<名称>:<虚拟IP>@<host:port>[=<目的>,...]`
+    pub fn parse_tunnel(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("无效的 --tunnel: {}（格式为 <名称>:<虚拟IP>@<服务器>[=<目的>,...]）", spec);
+        let (name, rest) = spec.split_once(':').ok_or_else(invalid)?;
+        let (virtual_ip, rest) = rest.split_once('@').ok_or_else(invalid)?;
+        let (server, targets) = rest.split_once('=').unwrap_or((rest, ""));
+        let valid_name = !name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_name {
+            return Err(anyhow!("隧道名称只能包含字母、数字、- 和 _（最长 32 个字符）: {}", name));
+        }
+        Self::build(name.to_string(), false, virtual_ip, server, targets)
+    }
+
+    fn build(name: String, isolate: bool, virtual_ip: &str, server: &str, targets: &str) -> Result<Self> {
+        let virtual_ip = virtual_ip.trim().parse().map_err(|_| anyhow!("--exit 中的虚拟 IP 无效: {}", virtual_ip))?;
+        let server = server.trim().to_string();
+        if !server.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p != 0)) {
+            return Err(anyhow!("--exit 中的服务器应为 host:port: {}", server));
+        }
+
+        let mut exit = ExitSpec { name, isolate, virtual_ip, server, default_route: false, destinations: Vec::new() };
+        for target in targets.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            match parse_destination(target)? {
+                None => exit.default_route = true,
+                Some(destination) => exit.destinations.push(destination),
+            }
+        }
+        Ok(exit)
+    }
+
+    /// 需要由监督任务添加的固定网段
+    fn cidrs(&self) -> impl Iterator<Item = &str> {
+        self.destinations.iter().filter_map(|d| match d {
+            Destination::Cidr(cidr) => Some(cidr.as_str()),
+            Destination::Domain(_) => None,
+        })
+    }
+
+    fn domains(&self) -> impl Iterator<Item = &str> {
+        self.destinations.iter().filter_map(|d| match d {
+            Destination::Domain(domain) => Some(domain.as_str()),
+            Destination::Cidr(_) => None,
+        })
+    }
+}
+
+/// 解析一个目的地址；default（或 0.0.0.0/0）返回 None
+fn parse_destination(target: &str) -> Result<Option<Destination>> {
+    if target == "default" || target == "0.0.0.0/0" {
+        return Ok(None);
+    }
+    if let Ok(ip) = target.parse::<IpAddr>() {
+        return match ip {
+            IpAddr::V4(ip) => Ok(Some(Destination::Cidr(format!("{}/32", ip)))),
+            IpAddr::V6(_) => Err(anyhow!("多出口只支持 IPv4 目的地址: {}", target)),
+        };
+    }
+    if target.contains('/') {
+        return match parse_cidr(target) {
+            Some((IpAddr::V4(_), _)) => Ok(Some(Destination::Cidr(target.to_string()))),
+            Some(_) => Err(anyhow!("多出口只支持 IPv4 目的地址: {}", target)),
+            None => Err(anyhow!("--exit 中的网段无效: {}", target)),
+        };
+    }
+    let valid_domain = target.contains('.')
+        && target.split('.').all(|label| !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if !valid_domain {
+        return Err(anyhow!("--exit 中的目的地址无效: {}", target));
+    }
+    Ok(Some(Destination::Domain(target.to_ascii_lowercase())))
+}
+
+/// 读取全部 --exit 和 --tunnel；都没有时返回空列表（普通单隧道模式）
+pub fn from_args(args: &[String]) -> Result<Vec<ExitSpec>> {
+    let mut exits = Vec::new();
+    for (i, spec) in arg_values(args, "--exit").iter().enumerate() {
+        exits.push(ExitSpec { name: format!("exit{}", i), ..ExitSpec::parse(spec)? });
+    }
+    for spec in arg_values(args, "--tunnel") {
+        exits.push(ExitSpec::parse_tunnel(&spec)?);
+    }
+    if exits.is_empty() {
+        return Ok(exits);
+    }
+    if exits.iter().filter(|e| e.default_route).count() > 1 {
+        return Err(anyhow!("最多只能有一条隧道使用 default"));
+    }
+    if let Some(exit) = exits.iter().enumerate().find(|(i, e)| exits[..*i].iter().any(|other| other.name == e.name)).map(|(_, e)| e) {
+        return Err(anyhow!("隧道名称重复: {}", exit.name));
+    }
+    let positional = args.get(1).is_some_and(|a| !a.starts_with("--"));
+    if positional || arg_value(args, "--server").is_some() || args.contains(&"--discover".to_string()) {
+        return Err(anyhow!("--exit / --tunnel 不能与单个服务器（位置参数、--server、--discover）同时使用"));
+    }
+    if arg_value(args, "--route").is_some() {
+        return Err(anyhow!("--route 只用于单个服务器；多出口 / 多隧道时在 --exit / --tunnel 中给出目的地址"));
+    }
+    Ok(exits)
+}
+
+/// 第 index 个出口的设备名
+fn device_name(index: usize) -> String {
+    if cfg!(target_os = "macos") {
+        // macOS 的 utun 设备名必须是 utunN
+        format!("utun{}", 20 + index)
+    } else {
+        format!("vpnexit{}", index)
+    }
+}
+
+/// 第 index 条隧道的命令行参数：与单隧道模式的命令行相同，交给 run_tunnel / dry_run
+fn tunnel_args(args: &[String], exit: &ExitSpec, index: usize) -> Vec<String> {
+    let program = args.first().cloned().unwrap_or_else(|| "vpn_client".to_string());
+    let mut child = vec![program, exit.virtual_ip.to_string(), exit.server.clone()];
+    // 跳过位置参数和按出口单独指定的参数，其余（调优、身份、日志等）原样透传
+    let mut rest = args.iter().skip(1).skip_while(|a| !a.starts_with("--"));
+    while let Some(arg) = rest.next() {
+        if PER_EXIT_OPTIONS.contains(&arg.as_str()) {
+            rest.next();
+        } else if !PER_EXIT_FLAGS.contains(&arg.as_str()) {
+            child.push(arg.clone());
+        }
+    }
+
+    child.extend(["--tun-name".to_string(), device_name(index)]);
+    // 会话恢复缓存按身份目录保存，多条隧道共用会互相覆盖
+    child.push("--no-session-resume".to_string());
+    if exit.isolate {
+        child.push("--allow-subnet-overlap".to_string());
+        if cfg!(target_os = "linux") {
+            child.extend(["--route-table".to_string(), (EXIT_ROUTE_TABLE_BASE + index as u32).to_string()]);
+        }
+    }
+    if exit.default_route {
+        child.push("--full-tunnel".to_string());
+        // DNS 跟随默认出口
+        if let Some(dns) = arg_value(args, "--dns") {
+            child.extend(["--dns".to_string(), dns]);
+        }
+    }
+    child
+}
+
+/// 多隧道模式入口：启动并看护所有隧道，直到 Ctrl+C
+pub async fn run(args: &[String], exits: Vec<ExitSpec>) -> Result<()> {
+    if dryrun::requested(args) {
+        return dry_run(args, &exits).await;
+    }
+    if !preflight::skipped(args) {
+        crate::preflight_checks(args).run()?;
+    }
+
+    println!("🔀 多隧道模式：{} 条隧道", exits.len());
+    for (i, exit) in exits.iter().enumerate() {
+        println!("   {} {} via {} (虚拟 IP {}): {}", exit.name, device_name(i), exit.server, exit.virtual_ip, describe(exit));
+    }
+
+    // 其他出口的服务器经物理网关直连，不套进默认出口
+    let mut exceptions = Vec::new();
+    if exits.iter().any(|e| e.default_route) {
+        match netwatch::default_gateway() {
+            Some(gateway) => {
+                for exit in exits.iter().filter(|e| !e.default_route) {
+                    let ip = ServerEndpoint::resolve(&exit.server).await?.addr().ip().to_string();
+                    crate::add_server_route_exception(&ip, &gateway);
+                    exceptions.push(ip);
+                }
+            }
+            None => eprintln!("⚠️ 未检测到默认网关，其他出口的隧道流量将经过默认出口"),
+        }
+    }
+
+    let mut handles = Vec::new();
+    let mut tasks = Vec::new();
+    for (i, exit) in exits.into_iter().enumerate() {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let status = Arc::new(Mutex::new(TunnelStatus::default()));
+        handles.push(TunnelHandle { name: exit.name.clone(), dev_name: device_name(i), server: exit.server.clone(), commands, status: status.clone() });
+        let supervisor = ExitSupervisor { args: tunnel_args(args, &exit, i), dev_name: device_name(i), exit, status };
+        tasks.push(tokio::spawn(supervisor.run(commands_rx)));
+    }
+    let handles = Arc::new(handles);
+    #[cfg(unix)]
+    {
+        let path = arg_value(args, "--control-socket").unwrap_or_else(|| crate::tunnels::DEFAULT_CONTROL_SOCKET.to_string());
+        if let Err(e) = crate::tunnels::spawn(handles.clone(), &path) {
+            eprintln!("⚠️ 控制接口启动失败，无法单独启停隧道: {}", e);
+        }
+    }
+
+    // 任一任务 panic 时同样停止所有隧道，恢复网络配置后退出
+    let crashed = tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            println!("\n\n🛑 收到退出信号，正在断开所有隧道...");
+            false
+        }
+        report = crash::wait() => {
+            eprintln!("{}", report);
+            true
+        }
+    };
+    for handle in handles.iter() {
+        handle.send(Lifecycle::Shutdown);
+    }
+    for task in tasks {
+        let _ = task.await;
+    }
+    for ip in &exceptions {
+        crate::remove_server_route_exception(ip);
+    }
+    if crashed {
+        std::process::exit(crash::CRASH_EXIT);
+    }
+    println!("👋 已退出");
+    Ok(())
+}
+
+/// 出口的目的地址（打印用）
+fn describe(exit: &ExitSpec) -> String {
+    let mut targets: Vec<&str> = exit.destinations.iter().map(|d| match d {
+        Destination::Cidr(cidr) => cidr.as_str(),
+        Destination::Domain(domain) => domain.as_str(),
+    }).collect();
+    if exit.default_route {
+        targets.insert(0, "default");
+    }
+    targets.join(", ")
+}
+
+/// 试运行：依次列出每条隧道的修改，再列出监督任务添加的路由
+async fn dry_run(args: &[String], exits: &[ExitSpec]) -> Result<()> {
+    for (i, exit) in exits.iter().enumerate() {
+        println!("=== 隧道 {}: {} ({}) ===", exit.name, exit.server, describe(exit));
+        crate::dry_run(&tunnel_args(args, exit, i)).await.map_err(|e| anyhow!("隧道 {} 试运行失败: {}", exit.name, e))?;
+        println!();
+    }
+    let mut plan = Plan::new();
+    for (i, exit) in exits.iter().enumerate() {
+        let dev_name = device_name(i);
+        for cidr in exit.cidrs() {
+            local_tun::plan_route(&mut plan, &dev_name, cidr, &local_tun::RouteOptions::default());
+        }
+        for domain in exit.domains() {
+            plan.note(Category::Route, format!("{} 的 IPv4 地址以 /32 路由到 {}，每 {} 秒重新解析", domain, dev_name, RESOLVE_INTERVAL.as_secs()));
+        }
+        if exits.iter().any(|e| e.default_route) && !exit.default_route {
+            plan.note(Category::Route, format!("添加到 {} 的服务器路由例外（经物理网关）", exit.server));
+        }
+    }
+    println!("=== 多隧道监督任务 ===");
+    plan.print();
+    Ok(())
+}
+
+/// 对单条隧道的生命周期操作（来自控制 socket 或退出流程）
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub enum Lifecycle {
+    Up,
+    Down,
+    Restart,
+    /// 客户端退出
+    Shutdown,
+}
+
+/// 隧道当前所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TunnelState {
+    /// 已停止（down 命令）
+    #[default]
+    Down,
+    /// 隧道已启动，设备尚未出现（握手中）
+    Starting,
+    /// 设备就绪，路由已添加
+    Up,
+    /// 隧道结束，等待重启
+    Backoff,
+}
+
+impl TunnelState {
+    pub fn label(self) -> &'static str {
+        match self {
+            TunnelState::Down => "已停止",
+            TunnelState::Starting => "连接中",
+            TunnelState::Up => "已连接",
+            TunnelState::Backoff => "等待重启",
+        }
+    }
+}
+
+/// 隧道状态（供控制命令查询）
+#[derive(Debug, Clone, Default)]
+pub struct TunnelStatus {
+    pub state: TunnelState,
+    /// 意外退出后自动重启的次数
+    pub restarts: u32,
+    /// 监督任务添加的路由条数
+    pub routes: usize,
+}
+
+/// 监督任务持有的隧道句柄
+pub struct TunnelHandle {
+    pub name: String,
+    pub dev_name: String,
+    pub server: String,
+    commands: mpsc::UnboundedSender<Lifecycle>,
+    status: Arc<Mutex<TunnelStatus>>,
+}
+
+impl TunnelHandle {
+    pub fn send(&self, command: Lifecycle) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn status(&self) -> TunnelStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// 看护一条隧道
+struct ExitSupervisor {
+    args: Vec<String>,
+    dev_name: String,
+    exit: ExitSpec,
+    status: Arc<Mutex<TunnelStatus>>,
+}
+
+impl ExitSupervisor {
+    async fn run(self, mut commands: mpsc::UnboundedReceiver<Lifecycle>) {
+        let mut running: Option<RunningTunnel> = None;
+        // 设备随隧道结束而删除，上面的路由也随之消失，每次启动从空集合开始
+        let mut routes = ExitRoutes::new(self.dev_name.clone());
+        // 下次启动隧道的时间；为 None 时保持停止
+        let mut start_at = Some(Instant::now());
+        let mut next = DEVICE_POLL_INTERVAL;
+        let mut crashed = false;
+        loop {
+            if running.is_none() && start_at.is_some_and(|t| t <= Instant::now()) {
+                start_at = None;
+                routes = ExitRoutes::new(self.dev_name.clone());
+                next = DEVICE_POLL_INTERVAL;
+                match RunningTunnel::start(&self.exit, self.args.clone()) {
+                    Ok(started) => {
+                        self.update(|s| {
+                            s.state = TunnelState::Starting;
+                            s.routes = 0;
+                            s.restarts += crashed as u32;
+                        });
+                        running = Some(started);
+                    }
+                    Err(e) => {
+                        eprintln!("❌ 无法启动隧道 {}: {}", self.exit.name, e);
+                        start_at = Some(Instant::now() + RESTART_DELAY);
+                        self.update(|s| s.state = TunnelState::Backoff);
+                    }
+                }
+                crashed = false;
+            }
+
+            tokio::select! {
+                command = commands.recv() => {
+                    let command = command.unwrap_or(Lifecycle::Shutdown);
+                    if command != Lifecycle::Up && let Some(tunnel) = running.take() {
+                        println!("⏹️  正在停止隧道 {}", self.exit.name);
+                        tunnel.stop(&self.exit.name).await;
+                    }
+                    match command {
+                        Lifecycle::Up if running.is_none() => start_at = Some(Instant::now()),
+                        Lifecycle::Up => {}
+                        Lifecycle::Restart => start_at = Some(Instant::now()),
+                        Lifecycle::Down | Lifecycle::Shutdown => start_at = None,
+                    }
+                    if running.is_none() && start_at.is_none() {
+                        self.update(|s| {
+                            s.state = TunnelState::Down;
+                            s.routes = 0;
+                        });
+                    }
+                    if command == Lifecycle::Shutdown {
+                        return;
+                    }
+                }
+                outcome = wait_tunnel(&mut running) => {
+                    running = None;
+                    // 服务端签名反复验证失败（可能是中间人）：重启只会继续把 ClientHello 交给对方，停在 Down 等人工处理
+                    if outcome == Ok(sigguard::SIGNATURE_FAILURE_EXIT) {
+                        eprintln!("🚨 隧道 {} 的服务端签名验证失败，不再自动重启；检查服务端公钥后用 vpn_client tunnel up {} 重新启动", self.exit.name, self.exit.name);
+                        start_at = None;
+                        self.update(|s| {
+                            s.state = TunnelState::Down;
+                            s.routes = 0;
+                        });
+                        continue;
+                    }
+                    let outcome = match outcome {
+                        Ok(code) => format!("退出码 {}", code),
+                        Err(e) => e,
+                    };
+                    eprintln!("⚠️ 隧道 {} 已结束（{}），{} 秒后重启", self.exit.name, outcome, RESTART_DELAY.as_secs());
+                    crashed = true;
+                    start_at = Some(Instant::now() + RESTART_DELAY);
+                    self.update(|s| {
+                        s.state = TunnelState::Backoff;
+                        s.routes = 0;
+                    });
+                }
+                _ = tokio::time::sleep_until(start_at.unwrap_or_else(Instant::now)), if running.is_none() && start_at.is_some() => {}
+                _ = tokio::time::sleep(next), if running.is_some() => {
+                    next = if !local_tun::device_exists(&self.dev_name) {
+                        DEVICE_POLL_INTERVAL
+                    } else if routes.sync(&self.exit).await {
+                        RESOLVE_INTERVAL
+                    } else {
+                        RETRY_INTERVAL
+                    };
+                    if local_tun::device_exists(&self.dev_name) {
+                        self.update(|s| {
+                            s.state = TunnelState::Up;
+                            s.routes = routes.installed.len();
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TunnelStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+}
+
+/// 在本进程内运行的一条隧道：独立线程上的 tokio 运行时，持有这条隧道的 socket、TUN 设备和转发引擎
+struct RunningTunnel {
+    /// 置为 true 时隧道通知服务端断开、恢复网络配置后结束
+    stop: watch::Sender<bool>,
+    /// 隧道结束且运行时关闭后送达：退出码，或启动失败的原因
+    done: oneshot::Receiver<Result<i32, String>>,
+}
+
+impl RunningTunnel {
+    fn start(exit: &ExitSpec, args: Vec<String>) -> std::io::Result<Self> {
+        let (stop, stop_rx) = watch::channel(false);
+        let (done_tx, done) = oneshot::channel();
+        let thread_name = format!("tunnel-{}", exit.name);
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().thread_name(thread_name.clone()).build()?;
+        let (virtual_ip, server) = (exit.virtual_ip.to_string(), exit.server.clone());
+        std::thread::Builder::new().name(thread_name).spawn(move || {
+            let outcome = runtime.block_on(run_tunnel(args, virtual_ip, server, stop_rx));
+            // 关闭运行时：这条隧道剩下的任务随之结束，TUN 设备和 socket 被关闭
+            runtime.shutdown_timeout(RUNTIME_SHUTDOWN_TIMEOUT);
+            let _ = done_tx.send(outcome);
+        })?;
+        Ok(Self { stop, done })
+    }
+
+    /// 通知隧道断开并等待它结束（退出清理最多 crash::CLEANUP_TIMEOUT）
+    async fn stop(mut self, name: &str) {
+        // 隧道已经自行结束时没有接收端，done 会立即送达
+        self.stop.send_replace(true);
+        match tokio::time::timeout(crash::CLEANUP_TIMEOUT + RESTART_DELAY, &mut self.done).await {
+            Ok(Ok(Ok(_))) => {}
+            Ok(Ok(Err(e))) => eprintln!("⚠️ 隧道 {} 启动失败: {}", name, e),
+            Ok(Err(_)) => eprintln!("⚠️ 隧道 {} 的线程意外结束，部分网络配置可能需要手动恢复", name),
+            Err(_) => eprintln!("⚠️ 隧道 {} 在 {} 秒内没有结束，部分网络配置可能需要手动恢复", name, (crash::CLEANUP_TIMEOUT + RESTART_DELAY).as_secs()),
+        }
+    }
+}
+
+/// 在隧道自己的运行时里运行 run_tunnel，返回退出码；启动失败时返回错误信息
+async fn run_tunnel(args: Vec<String>, virtual_ip: String, server: String, mut stop: watch::Receiver<bool>) -> Result<i32, String> {
+    let (code_tx, mut code_rx) = mpsc::unbounded_channel();
+    let end = crate::TunnelEnd::Supervised { stop: stop.clone(), code: code_tx };
+    tokio::select! {
+        result = crate::run_tunnel(&args, virtual_ip, server, end) => result.map(|()| 0).map_err(|e| e.to_string()),
+        Some(code) = code_rx.recv() => Ok(code),
+        _ = stop.wait_for(|stop| *stop) => {
+            // 还在启动时直接放弃（撤销日志恢复已做的修改），此时已经没有发送端，recv 立即返回 None；
+            // 已经启动的隧道由它的退出任务通知服务端、恢复网络配置后交回退出码
+            Ok(code_rx.recv().await.unwrap_or(0))
+        }
+    }
+}
+
+/// 等待隧道结束；没有运行的隧道时永远等待
+async fn wait_tunnel(running: &mut Option<RunningTunnel>) -> Result<i32, String> {
+    match running {
+        Some(tunnel) => (&mut tunnel.done).await.unwrap_or_else(|_| Err("隧道线程意外结束".to_string())),
+        None => std::future::pending().await,
+    }
+}
+
+/// 监督任务在一个出口设备上添加的路由
+struct ExitRoutes {
+    dev_name: String,
+    installed: BTreeSet<String>,
+}
+
+impl ExitRoutes {
+    fn new(dev_name: String) -> Self {
+        Self { dev_name, installed: BTreeSet::new() }
+    }
+
+    /// 让设备上的路由与目的列表（固定网段 + 域名当前的解析结果）一致；全部成功时返回 true
+    async fn sync(&mut self, exit: &ExitSpec) -> bool {
+        let mut wanted: BTreeSet<String> = exit.cidrs().map(str::to_string).collect();
+        let mut ok = true;
+        for domain in exit.domains() {
+            match tokio::net::lookup_host((domain, 0)).await {
+                Ok(addrs) => wanted.extend(addrs.filter(|a| a.is_ipv4()).map(|a| format!("{}/32", a.ip()))),
+                Err(e) => {
+                    eprintln!("⚠️ 无法解析 {}: {}", domain, e);
+                    // 解析失败时保留上次的结果
+                    let previous = self.installed.iter().filter(|cidr| !exit.cidrs().any(|c| c == cidr.as_str()));
+                    wanted.extend(previous.cloned().collect::<Vec<_>>());
+                    ok = false;
+                }
+            }
+        }
+
+        for stale in self.installed.difference(&wanted).cloned().collect::<Vec<_>>() {
+            let _ = local_tun::remove_route(&self.dev_name, &stale);
+            self.installed.remove(&stale);
+        }
+        for cidr in wanted.difference(&self.installed).cloned().collect::<Vec<_>>() {
+            match local_tun::configure_route(&self.dev_name, &cidr) {
+                Ok(_) => {
+                    self.installed.insert(cidr);
+                }
+                Err(e) => {
+                    eprintln!("⚠️ 出口路由 {} -> {} 添加失败: {}", cidr, self.dev_name, e);
+                    ok = false;
+                }
+            }
+        }
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_exit() {
+        let exit = ExitSpec::parse("10.1.0.2@eu.example.com:9000=192.168.50.0/24, 1.2.3.4,intranet.example.eu").unwrap();
+        assert_eq!(exit.virtual_ip, Ipv4Addr::new(10, 1, 0, 2));
+        assert_eq!(exit.server, "eu.example.com:9000");
+        assert!(!exit.default_route);
+        assert_eq!(
+            exit.destinations,
+            vec![
+                Destination::Cidr("192.168.50.0/24".to_string()),
+                Destination::Cidr("1.2.3.4/32".to_string()),
+                Destination::Domain("intranet.example.eu".to_string()),
+            ]
+        );
+        assert!(ExitSpec::parse("10.0.0.2@us.example.com:9000=default").unwrap().default_route);
+
+        for invalid in [
+            "10.1.0.2@eu.example.com=10.0.0.0/8",
+            "10.1.0.300@eu.example.com:9000=10.0.0.0/8",
+            "eu.example.com:9000=10.0.0.0/8",
+            "10.1.0.2@eu.example.com:9000=",
+            "10.1.0.2@eu.example.com:9000=10.0.0.0/33",
+            "10.1.0.2@eu.example.com:9000=fd00::/64",
+            "10.1.0.2@eu.example.com:9000=localhost",
+            "10.1.0.2@eu.example.com:9000=bad_name.example",
+        ] {
+            assert!(ExitSpec::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        // 最多一个 default；不能与单个服务器同时使用
+        let args = strings(&["vpn_client", "--exit", "10.0.0.2@a.example:9000=default", "--exit", "10.0.0.3@b.example:9000=0.0.0.0/0"]);
+        assert!(from_args(&args).is_err());
+        let args = strings(&["vpn_client", "10.0.0.2", "--exit", "10.0.0.2@a.example:9000=default"]);
+        assert!(from_args(&args).is_err());
+        assert!(from_args(&strings(&["vpn_client", "10.0.0.2", "a.example:9000"])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_tunnel() {
+        let tunnel = ExitSpec::parse_tunnel("office:10.8.0.2@office.example.com:9000").unwrap();
+        assert_eq!((tunnel.name.as_str(), tunnel.server.as_str(), tunnel.isolate), ("office", "office.example.com:9000", false));
+        assert!(tunnel.destinations.is_empty() && !tunnel.default_route);
+        let tunnel = ExitSpec::parse_tunnel("lab_2:10.9.0.2@[::1]:9000=172.16.0.0/12").unwrap();
+        assert_eq!(tunnel.server, "[::1]:9000");
+        assert_eq!(tunnel.destinations, vec![Destination::Cidr("172.16.0.0/12".to_string())]);
+        for invalid in ["10.8.0.2@office.example.com:9000", "of fice:10.8.0.2@office.example.com:9000", ":10.8.0.2@a.example:9000"] {
+            assert!(ExitSpec::parse_tunnel(invalid).is_err(), "{}", invalid);
+        }
+
+        // --exit 依次命名，与 --tunnel 混用；名称不能重复
+        let args = strings(&["vpn_client", "--tunnel", "office:10.8.0.2@a.example:9000", "--exit", "10.0.0.2@b.example:9000=default"]);
+        let names: Vec<String> = from_args(&args).unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["exit0", "office"]);
+        let args = strings(&["vpn_client", "--tunnel", "a:10.8.0.2@a.example:9000", "--tunnel", "a:10.9.0.2@b.example:9000"]);
+        assert!(from_args(&args).is_err());
+    }
+
+    #[test]
+    fn test_tunnel_args() {
+        let args = strings(&[
+            "vpn_client", "--exit", "10.0.0.2@us.example.com:9000=default", "--exit", "10.0.0.3@eu.example.com:9000=10.20.0.0/16",
+            "--dns", "1.1.1.1", "--handshake-timeout", "10", "--tun-name", "ignored", "--no-session-resume", "--ipv6",
+            "--tunnel", "office:10.8.0.2@office.example.com:9000", "--control-socket", "/tmp/x.sock",
+        ]);
+        let exits = from_args(&args).unwrap();
+        let table = |i: u32| if cfg!(target_os = "linux") { strings(&["--route-table", &(EXIT_ROUTE_TABLE_BASE + i).to_string()]) } else { Vec::new() };
+
+        let mut expected = strings(&["vpn_client", "10.0.0.2", "us.example.com:9000", "--handshake-timeout", "10", "--ipv6", "--tun-name", &device_name(0)]);
+        expected.extend(strings(&["--no-session-resume", "--allow-subnet-overlap"]));
+        expected.extend(table(0));
+        expected.extend(strings(&["--full-tunnel", "--dns", "1.1.1.1"]));
+        assert_eq!(tunnel_args(&args, &exits[0], 0), expected);
+
+        let mut expected = strings(&["vpn_client", "10.0.0.3", "eu.example.com:9000", "--handshake-timeout", "10", "--ipv6", "--tun-name", &device_name(1)]);
+        expected.extend(strings(&["--no-session-resume", "--allow-subnet-overlap"]));
+        expected.extend(table(1));
+        assert_eq!(tunnel_args(&args, &exits[1], 1), expected);
+
+        // 独立隧道的虚拟网段路由留在主表
+        let expected = strings(&["vpn_client", "10.8.0.2", "office.example.com:9000", "--handshake-timeout", "10", "--ipv6", "--tun-name", &device_name(2), "--no-session-resume"]);
+        assert_eq!(tunnel_args(&args, &exits[2], 2), expected);
+    }
+}
@@ -1,68 +1,68 @@
 // vpn_client/src/main.rs
 
-#[cfg(target_os = "macos")]
-const TUN_READ_OFFSET: usize = 4; // macOS 读出来的头 4 字节是 header
-
-#[cfg(target_os = "linux")]
-const TUN_READ_OFFSET: usize = 0; // Linux 配置了 no_pi，所以是 0
-
 use std::env; // 引入环境模块读取参数
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use std::error::Error;
 use std::process::Command;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::{IpAddr, SocketAddr};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tun::Device; // 这一行可能需要依赖具体的 tun 库导出，如果报错可尝试删掉或检查 vpn_core
+use tokio::sync::{Mutex, mpsc, watch};
 
 // === 引用核心库 (Workspace 改动) ===
 use vpn_core::local_tun; 
-use vpn_core::symmetric::Cipher;
-use vpn_core::handshake::{ClientHandshake, HandshakeMessage, serialize_message, deserialize_message};
-use vpn_core::asymmetric::{ClientVerifier, get_keys_dir};
+use vpn_core::pmtu::{self, PmtuMessage, PmtuProber};
+use vpn_core::control::{self, ControlMessage, KeyRing, LinkHealth, PayloadKind, RttEstimator};
+use vpn_core::gateway;
+use vpn_core::mdns;
+use vpn_core::netwatch::{self, NetworkEvent};
+use vpn_core::handshake::{Capabilities, ClientHandshake, HandshakeMessage, AuthCredential, serialize_message, deserialize_message, handshake_error_message, describe_handshake_error, client_finish, verify_server_finish, AUTO_VIRTUAL_IP};
+use vpn_core::asymmetric::{ClientIdentity, ClientVerifier, default_client_dir, get_keys_dir};
+use vpn_core::tpm::KeyProtection;
+use vpn_core::telemetry::Telemetry;
+use vpn_core::datapath_log::{self, DataPathLog};
+use vpn_core::keylog::{self, KeyEvent};
+use vpn_core::logsink;
+use vpn_core::crash;
+use vpn_core::sigguard::{self, SignatureError, SignatureGuard, OnSignatureFailure};
+use vpn_core::trace_packet;
+use vpn_core::tuning::Tuning;
+use vpn_core::engine::{PacketHandler, Role, TunnelEngine};
+use vpn_core::wire::{self, Datagram};
+use vpn_core::firewall::{Allowlist, InboundFirewall};
+use vpn_core::resume::{self, CachedSession, SessionCache};
+use vpn_core::config;
+use vpn_core::dryrun::{self, Category, Plan};
+use vpn_core::preflight::{self, Preflight};
+use vpn_core::undo::UndoJournal;
 
-// 全局状态：保存原始网关，用于退出时恢复
-static ORIGINAL_GATEWAY: Mutex<Option<String>> = Mutex::const_new(None);
+mod auth;
+mod bond;
+mod diagnose;
+mod endpoint;
+mod exits;
+mod nat;
+mod online;
+mod pace;
+mod profiles;
+mod resume_cache;
+mod roster;
+#[cfg(unix)]
+mod tunnels;
 
-// 预共享密钥 (PSK) - 用于握手认证
-// 注意：服务端必须使用完全相同的 PSK！
-const PSK: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+use bond::BondPath;
+use vpn_core::fec::{self, FecLink};
+use vpn_core::recursion;
+use vpn_core::psk;
+use endpoint::ServerEndpoint;
+use nat::NatProbe;
+use pace::Pacing;
+use resume_cache::ResumeState;
+use roster::Roster;
 
-/// 检测当前默认网关
-fn detect_default_gateway() -> Option<String> {
-    #[cfg(target_os = "macos")]
-    {
-        let output = Command::new("route")
-            .args(&["-n", "get", "default"])
-            .output()
-            .ok()?;
-        
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        for line in stdout.lines() {
-            if line.trim().starts_with("gateway:") {
-                if let Some(gateway) = line.split(':').nth(1).map(|s| s.trim()) {
-                    return Some(gateway.to_string());
-                }
-            }
-        }
-    }
-    
-    #[cfg(target_os = "linux")]
-    {
-        let output = Command::new("ip")
-            .args(&["route", "show", "default"])
-            .output()
-            .ok()?;
-        
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        // 格式: default via 192.168.1.1 dev eth0
-        if let Some(gateway) = stdout.split_whitespace().nth(2) {
-            return Some(gateway.to_string());
-        }
-    }
-    
-    None
-}
+// 全局状态：保存原始网关，用于退出时恢复
+static ORIGINAL_GATEWAY: Mutex<Option<String>> = Mutex::const_new(None);
 
 /// 恢复原始默认网关
 async fn restore_default_gateway() {
@@ -74,35 +74,16 @@ async fn restore_default_gateway() {
     if let Some(gw) = gateway {
         println!("   🔄 恢复默认路由 -> {}", gw);
         
-        #[cfg(target_os = "macos")]
-        {
-            // 删除 VPN 默认路由
-            let _ = Command::new("route")
-                .args(&["-n", "delete", "default", "10.0.0.1"])
-                .status();
-            
-            // 恢复原始默认路由
-            let status = Command::new("route")
-                .args(&["-n", "add", "default", &gw])
-                .status();
-            
-            if status.is_ok() && status.unwrap().success() {
-                println!("   ✅ 网络已恢复");
-            } else {
-                eprintln!("   ⚠️  自动恢复失败，请手动执行: sudo route add default {}", gw);
-            }
-        }
-        
         #[cfg(target_os = "linux")]
         {
             // 删除 VPN 默认路由
             let _ = Command::new("ip")
-                .args(&["route", "del", "default", "via", "10.0.0.1"])
+                .args(["route", "del", "default", "via", "10.0.0.1"])
                 .status();
             
             // 恢复原始默认路由
             let status = Command::new("ip")
-                .args(&["route", "add", "default", "via", &gw])
+                .args(["route", "add", "default", "via", &gw])
                 .status();
             
             if status.is_ok() && status.unwrap().success() {
@@ -111,40 +92,228 @@ async fn restore_default_gateway() {
                 eprintln!("   ⚠️  自动恢复失败，请手动执行: sudo ip route add default via {}", gw);
             }
         }
+        
+        #[cfg(not(target_os = "linux"))]
+        {
+            // macOS / Windows 上全隧道使用两条 /1 路由覆盖默认路由（退出时删除），原默认路由未被修改
+            println!("   ✅ 原默认路由 {} 未被修改", gw);
+        }
     } else {
         eprintln!("   ⚠️  未找到原始网关信息");
     }
 }
 
 
-/// 执行握手协议，获取会话密钥
-async fn perform_handshake(
-    socket: &UdpSocket,
-    server_addr: &str,
-    client_id: String,
-    virtual_ip: String,
-) -> Result<[u8; 32], Box<dyn Error>> {
-    println!("🤝 开始握手...");
+/// 添加（或更新）到服务器的主机路由，经由本地物理网关，避免隧道流量被路由回隧道
+fn add_server_route_exception(server_ip: &str, gateway: &str) {
+    println!("   🛡️  添加服务器路由例外: {} via {}", server_ip, gateway);
     
-    // 0. 加载服务端公钥
-    let keys_dir = get_keys_dir()?;
-    let public_key_path = keys_dir.join("server_public.key");
+    let commands = server_route_commands(server_ip, gateway);
+    for (i, command) in commands.iter().enumerate() {
+        let mut cmd = Command::new(command[0]);
+        cmd.args(&command[1..]);
+        // 前面的删除命令在路由不存在时会失败，不打印输出
+        if i + 1 < commands.len() {
+            let _ = cmd.output();
+        } else {
+            let _ = cmd.status();
+        }
+    }
+}
+
+/// 删除到服务器的主机路由（撤销 add_server_route_exception，忽略错误）
+fn remove_server_route_exception(server_ip: &str) {
+    #[cfg(target_os = "linux")]
+    let command = ["ip", "route", "del", server_ip];
+    #[cfg(target_os = "macos")]
+    let command = ["route", "-n", "delete", "-host", server_ip];
+    #[cfg(target_os = "windows")]
+    let command = ["route", "delete", server_ip];
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    let _ = Command::new(command[0]).args(&command[1..]).output();
+}
+
+/// 服务器路由例外依次执行的命令（第一项为程序名）
+fn server_route_commands<'a>(server_ip: &'a str, gateway: &'a str) -> Vec<Vec<&'a str>> {
+    #[cfg(target_os = "macos")]
+    return vec![vec!["route", "-n", "delete", "-host", server_ip], vec!["route", "-n", "add", "-host", server_ip, gateway]];
+    
+    #[cfg(target_os = "linux")]
+    return vec![vec!["ip", "route", "replace", server_ip, "via", gateway]];
+    
+    #[cfg(target_os = "windows")]
+    return vec![vec!["route", "delete", server_ip], vec!["route", "add", server_ip, "mask", "255.255.255.255", gateway]];
     
+    #[cfg(not(any(target_os = "macos", target_os = "linux", target_os = "windows")))]
+    {
+        let _ = (server_ip, gateway);
+        Vec::new()
+    }
+}
+
+/// 身份目录（--identity-dir，默认 ~/.config/rust-vpn）
+fn identity_dir(args: &[String]) -> Result<std::path::PathBuf, Box<dyn Error>> {
+    match arg_value(args, "--identity-dir") {
+        Some(dir) => Ok(std::path::PathBuf::from(dir)),
+        None => Ok(default_client_dir()?),
+    }
+}
+
+/// --tpm-seal：私钥密封到本机 TPM，TPM 不可用时回退为明文文件
+fn key_protection(args: &[String]) -> KeyProtection {
+    if args.contains(&"--tpm-seal".to_string()) { KeyProtection::Tpm } else { KeyProtection::File }
+}
+
+/// 客户端身份：指定了密钥代理（--agent-socket 或 RUST_VPN_AGENT_SOCK）时由代理签名，否则从 --identity-dir 加载私钥
+fn load_identity(args: &[String]) -> Result<ClientIdentity, Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        let socket = arg_value(args, "--agent-socket").or_else(|| env::var(vpn_core::agent::AGENT_SOCKET_ENV).ok());
+        if let Some(socket) = socket {
+            return Ok(vpn_core::agent::connect(std::path::Path::new(&socket))?);
+        }
+    }
+    Ok(ClientIdentity::load_or_generate(&identity_dir(args)?, key_protection(args))?)
+}
+
+/// `vpn_client agent`：在前台运行密钥代理，持有身份私钥并为隧道客户端签名
+fn run_agent(args: &[String]) -> Result<(), Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        let identity = ClientIdentity::load_or_generate(&identity_dir(args)?, key_protection(args))?;
+        let socket = match arg_value(args, "--agent-socket") {
+            Some(path) => std::path::PathBuf::from(path),
+            None => vpn_core::agent::default_socket_path(),
+        };
+        println!("🪪 客户端身份: {} (公钥 {})", identity.id(), identity.fingerprint());
+        let agent = vpn_core::agent::Agent::bind(identity, &socket)?;
+        println!("🗝️  密钥代理已启动: {}", socket.display());
+        println!("   export {}={}", vpn_core::agent::AGENT_SOCKET_ENV, socket.display());
+        agent.run()?;
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = args;
+        Err("密钥代理只支持 Unix 平台".into())
+    }
+}
+
+/// 从命令行参数中读取 `--name value` 形式的值
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// 读取可重复出现的 `--name value` 参数
+fn arg_values(args: &[String], name: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|w| w[0] == name)
+        .map(|w| w[1].clone())
+        .collect()
+}
+
+/// 握手消息的来源：启动时直接读 socket；隧道建立后 socket 由下行任务读取，握手消息经它转交
+enum HandshakeRx<'a> {
+    Socket(&'a UdpSocket),
+    Channel(&'a mut mpsc::Receiver<HandshakeMessage>),
+}
+
+impl HandshakeRx<'_> {
+    async fn recv(&mut self, timeout: Duration) -> Result<HandshakeMessage, Box<dyn Error>> {
+        match self {
+            HandshakeRx::Socket(socket) => {
+                let mut buf = [0u8; 2048];
+                let (n, from_addr) = tokio::time::timeout(timeout, socket.recv_from(&mut buf)).await
+                    .map_err(|_| format!("{} 秒内没有收到服务端的响应", timeout.as_secs()))??;
+                println!("   📥 收到数据包: {} 字节，来自 {}", n, from_addr);
+                Ok(deserialize_message(&buf[..n])?)
+            }
+            HandshakeRx::Channel(rx) => tokio::time::timeout(timeout, rx.recv()).await
+                .map_err(|_| format!("{} 秒内没有收到服务端的响应", timeout.as_secs()))?
+                .ok_or_else(|| "下行任务已退出".into()),
+        }
+    }
+}
+
+/// ClientHello 中声明的会话参数
+struct HelloOptions {
+    virtual_ip: String,
+    /// 请求的 FEC 分组大小（--fec）
+    fec: Option<u8>,
+    /// 本端的功能标志
+    capabilities: Capabilities,
+    /// PSK 文件（--psk-file），未指定时使用内置的 PSK
+    psk_file: Option<std::path::PathBuf>,
+    /// 固定的服务端公钥（--server-key），未指定时读取 keys/server_public.key
+    server_key: Option<String>,
+}
+
+/// 服务端公钥：--server-key 固定的公钥，否则读取 keys/server_public.key
+fn server_verifier(server_key: Option<&str>) -> Result<ClientVerifier, Box<dyn Error>> {
+    if let Some(key) = server_key {
+        let verifier = ClientVerifier::from_hex(key)?;
+        println!("   🔑 使用固定的服务端公钥（--server-key）");
+        return Ok(verifier);
+    }
+    let public_key_path = get_keys_dir()?.join("server_public.key");
     if !public_key_path.exists() {
         return Err(format!(
-            "❗ 找不到服务端公钥文件: {}\n\n请先启动服务端生成密钥对！",
+            "❗ 找不到服务端公钥文件: {}\n\n请先启动服务端生成密钥对，或用 --server-key 指定服务端公钥！",
             public_key_path.display()
         ).into());
     }
-    
     let verifier = ClientVerifier::load_from_file(&public_key_path)?;
     println!("   🔑 已加载服务端公钥");
+    Ok(verifier)
+}
+
+/// 完整握手的结果
+struct Handshake {
+    session_key: [u8; 32],
+    /// 服务端接受的 FEC 分组大小
+    fec: Option<u8>,
+    /// 请求 auto 时服务端分配的虚拟 IP
+    assigned_ip: Option<std::net::Ipv4Addr>,
+    /// 双方都支持的功能（服务端没有声明功能标志时为 None）
+    capabilities: Option<Capabilities>,
+}
+
+/// 执行握手协议，获取会话密钥、服务端接受的 FEC 分组大小和分配的虚拟 IP
+async fn perform_handshake(
+    socket: &UdpSocket,
+    server_addr: SocketAddr,
+    identity: &ClientIdentity,
+    hello: HelloOptions,
+    telemetry: &Telemetry,
+    rx: &mut HandshakeRx<'_>,
+    timeout: Duration,
+) -> Result<Handshake, Box<dyn Error>> {
+    println!("🤝 开始握手...");
+    
+    let mut span = telemetry.start_span("client_handshake");
+    span.set_attribute("server_addr", server_addr.to_string());
+    span.set_attribute("client_id", identity.id());
+    
+    // 0. 加载服务端公钥
+    let verifier = server_verifier(hello.server_key.as_deref())?;
     
-    // 1. 创建客户端握手实例
-    let client_handshake = ClientHandshake::new(PSK);
+    // 1. 创建客户端握手实例（每次握手重新读取 PSK 文件：服务端轮换 PSK 后替换文件即可，不需要重启客户端）
+    let psk = match &hello.psk_file {
+        Some(path) => psk::load(path)?,
+        None => psk::DEFAULT_PSK,
+    };
+    let mut client_handshake = ClientHandshake::new(&psk);
     
-    // 2. 发送 ClientHello
-    let client_hello = client_handshake.create_client_hello(client_id, virtual_ip);
+    // 2. 发送 ClientHello（附带客户端身份签名）
+    let auto_ip = hello.virtual_ip == AUTO_VIRTUAL_IP;
+    let mut client_hello = client_handshake.create_client_hello(identity, hello.virtual_ip)?;
+    if let HandshakeMessage::ClientHello { fec, capabilities, .. } = &mut client_hello {
+        *fec = hello.fec;
+        *capabilities = Some(hello.capabilities);
+    }
     
     // 保存 client_pubkey 用于验证
     let client_pubkey = match &client_hello {
@@ -152,44 +321,224 @@ async fn perform_handshake(
         _ => unreachable!(),
     };
     
+    let phase = span.child("send_client_hello");
     let hello_data = serialize_message(&client_hello)?;
     socket.send_to(&hello_data, server_addr).await?;
+    phase.end();
     println!("   📤 已发送 ClientHello ({} 字节)", hello_data.len());
     
     // 3. 接收 ServerHello（增加超时时间并添加重试）
-    // ServerHello 包含：32字节公钥 + 1088字节ML-KEM密文 + 64字节签名 + bincode开销 ≈ 1200+ 字节
-    let mut buf = [0u8; 2048];
-    println!("   ⏳ 等待 ServerHello 响应（超时 30 秒）...");
-    let (n, from_addr) = tokio::time::timeout(
-        std::time::Duration::from_secs(30),
-        socket.recv_from(&mut buf)
-    ).await??;
-    
-    println!("   📥 收到数据包: {} 字节，来自 {}", n, from_addr);
-    
-    let server_hello = deserialize_message(&buf[..n])?;
-    let (server_pubkey, mlkem_ciphertext, signature) = match server_hello {
-        HandshakeMessage::ServerHello { server_pubkey, mlkem_ciphertext, signature } => (server_pubkey, mlkem_ciphertext, signature),
+    // ServerHello 包含：32字节公钥 + 1088字节ML-KEM密文 + 64字节签名 + 字段头 ≈ 1200+ 字节
+    println!("   ⏳ 等待 ServerHello 响应（超时 {} 秒）...", timeout.as_secs());
+    let mut phase = span.child("await_server_hello");
+    let mut server_hello = match recv_hello_reply(rx, &verifier, &client_pubkey, timeout).await {
+        Ok(msg) => msg,
+        Err(e) => {
+            phase.set_error("no server_hello");
+            span.set_error("server_hello failed");
+            return Err(e);
+        }
+    };
+    
+    // 服务端先要求证明来源地址可达：带上 cookie 重发一次 ClientHello
+    if let HandshakeMessage::Cookie { cookie } = server_hello {
+        if let HandshakeMessage::ClientHello { cookie: hello_cookie, .. } = &mut client_hello {
+            *hello_cookie = cookie;
+        }
+        socket.send_to(&serialize_message(&client_hello)?, server_addr).await?;
+        println!("   🍪 已带上 cookie 重发 ClientHello");
+        server_hello = match recv_hello_reply(rx, &verifier, &client_pubkey, timeout).await {
+            Ok(msg) => msg,
+            Err(e) => {
+                phase.set_error("no server_hello");
+                span.set_error("server_hello failed");
+                return Err(e);
+            }
+        };
+    }
+    phase.end();
+    
+    let (server_pubkey, mlkem_ciphertext, observed_addr, fec, server_time, assigned_ip, server_capabilities) = match &server_hello {
+        HandshakeMessage::ServerHello { server_pubkey, mlkem_ciphertext, observed_addr, fec, server_time, assigned_ip, capabilities, .. } => {
+            (*server_pubkey, mlkem_ciphertext.clone(), *observed_addr, *fec, *server_time, *assigned_ip, *capabilities)
+        }
+        // 例如同一身份已在其他地方连接（服务端 --duplicate-policy reject）
+        HandshakeMessage::ServerFinish { success: false, .. } => return Err("服务端拒绝了握手（同一身份可能已在其他地方连接）".into()),
         _ => return Err("预期收到 ServerHello".into()),
     };
     println!("   📥 收到 ServerHello");
     
-    // 3.5. 验证服务端签名
-    let message_to_verify = [
-        &server_pubkey[..],
-        &client_pubkey[..],
-    ].concat();
+    // 3.5. 验证服务端签名（覆盖整个握手转录：双方的 Hello、服务端看到的本机地址、分配的虚拟 IP 和协商的功能）
+    let mut phase = span.child("verify_signature");
+    if let Err(e) = client_handshake.verify_server_hello(&client_hello, &server_hello, &verifier) {
+        phase.set_error(&e);
+        span.set_error("bad server signature");
+        return Err(SignatureError { detail: e.to_string() }.into());
+    }
+    phase.end();
+    println!("   ✅ 服务端身份验证成功！（服务端所见地址: {}）", observed_addr);
+    let assigned_ip = assigned_ip.filter(|_| auto_ip);
+    match assigned_ip {
+        Some(ip) => println!("   🏷️  服务端分配的虚拟 IP: {}", ip),
+        None if auto_ip => return Err("服务端没有分配虚拟 IP（服务端版本过旧，不支持 --virtual-ip auto）".into()),
+        None => {}
+    }
     
-    verifier.verify(&message_to_verify, &signature)?;
-    println!("   ✅ 服务端身份验证成功！");
+    // 时钟偏差：之后发给服务端的时间戳（会话恢复、多路径加入）按服务端时间校正
+    if let Some(server_time) = server_time {
+        let offset = resume::record_server_time(server_time);
+        if offset.unsigned_abs() > resume::SKEW_WARN_THRESHOLD {
+            println!(
+                "   ⚠️ 本机时钟{}服务端 {} 秒，已按服务端时间校正；请检查本机的时间同步（NTP）",
+                if offset > 0 { "慢于" } else { "快于" },
+                offset.unsigned_abs()
+            );
+        }
+    }
     
     // 4. 计算会话密钥（混合：X25519 + ML-KEM，消耗 client_handshake）
+    let phase = span.child("derive_session_key");
     let session_key = client_handshake.process_server_hello(server_pubkey, &mlkem_ciphertext)?;
+    phase.end();
     println!("   🔑 会话密钥协商成功（X25519 + ML-KEM-768）");
+    keylog::record(KeyEvent::Handshake, server_addr, &session_key);
+    
+    // 5. 密钥确认：ClientFinish 证明本端持有会话密钥，服务端回复的 ServerFinish 证明对端也持有
+    //    服务端在收到有效的 ClientFinish 之前不接受这个会话的数据包
+    let mut phase = span.child("key_confirm");
+    socket.send_to(&serialize_message(&client_finish(&session_key)?)?, server_addr).await?;
+    let confirmed: Result<(), Box<dyn Error>> = match rx.recv(timeout).await {
+        Ok(HandshakeMessage::ServerFinish { success: true, encrypted_confirm }) => verify_server_finish(&encrypted_confirm, &session_key).map_err(Into::into),
+        Ok(HandshakeMessage::ServerFinish { success: false, .. }) => Err("服务端拒绝了密钥确认（ClientFinish）".into()),
+        Ok(_) => Err("预期收到 ServerFinish".into()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = confirmed {
+        phase.set_error(&e);
+        span.set_error("key confirmation failed");
+        return Err(e);
+    }
+    phase.end();
+    println!("   ✅ 双方已确认会话密钥");
+    let capabilities = server_capabilities.map(|c| c.intersect(hello.capabilities));
+    match capabilities {
+        Some(c) => println!("   🧩 双方支持的功能: {}", c),
+        None => println!("   🧩 服务端没有声明功能标志（旧版本），按原有方式工作"),
+    }
+    Ok(Handshake { session_key, fec: accepted_fec(hello.fec, fec), assigned_ip, capabilities })
+}
+
+/// 等待服务端对 ClientHello 的响应，处理其中的 HandshakeError
+///
+/// 签名有效的错误直接作为握手失败的原因返回；未签名或签名无效的（可能是伪造的）不中断握手，
+/// 继续等待到超时，超时错误中附上这个未经验证的原因
+async fn recv_hello_reply(
+    rx: &mut HandshakeRx<'_>,
+    verifier: &ClientVerifier,
+    client_pubkey: &[u8; 32],
+    timeout: Duration,
+) -> Result<HandshakeMessage, Box<dyn Error>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut unverified = None;
+    loop {
+        match rx.recv(deadline.saturating_duration_since(tokio::time::Instant::now())).await {
+            Ok(HandshakeMessage::HandshakeError { code, detail, observed_addr, signature }) => {
+                let reason = describe_handshake_error(code, &detail);
+                let message = handshake_error_message(code, &detail, client_pubkey, observed_addr);
+                if !signature.is_empty() && verifier.verify(&message, &signature).is_ok() {
+                    return Err(format!("服务端拒绝了握手：{}", reason).into());
+                }
+                println!("   ⚠️ 收到未经验证的错误响应（可能是伪造的，继续等待）: {}", reason);
+                unverified = Some(reason);
+            }
+            Ok(msg) => return Ok(msg),
+            Err(e) => return Err(match unverified {
+                Some(reason) => format!("{} 秒内没有收到 ServerHello，期间收到未经验证的错误响应：{}", timeout.as_secs(), reason).into(),
+                None => e,
+            }),
+        }
+    }
+}
+
+/// 服务端接受的 FEC 分组大小（只在本端请求过时使用）
+fn accepted_fec(requested: Option<u8>, accepted: Option<u8>) -> Option<u8> {
+    match (requested, accepted) {
+        (Some(_), Some(k)) => {
+            let k = k.clamp(fec::MIN_GROUP_SIZE, fec::MAX_GROUP_SIZE);
+            println!("   🧩 前向纠错已启用（每 {} 个包一个校验包）", k);
+            Some(k)
+        }
+        (Some(_), None) => {
+            println!("   ⚠️ 服务端未接受前向纠错（--no-fec 或旧版本），不使用 FEC");
+            None
+        }
+        (None, _) => None,
+    }
+}
+
+/// 发送 ClientAuth 并等待服务端的 ServerFinish
+async fn authenticate(
+    socket: &UdpSocket,
+    server_addr: SocketAddr,
+    session_key: &[u8; 32],
+    credential: &AuthCredential,
+    rx: &mut HandshakeRx<'_>,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let auth_msg = credential.seal(session_key)?;
+    socket.send_to(&serialize_message(&auth_msg)?, server_addr).await?;
+    println!("   🪪 已发送认证凭据，等待服务端确认...");
+    
+    match rx.recv(timeout).await? {
+        HandshakeMessage::ServerFinish { success: true, .. } => {
+            println!("   ✅ 认证通过");
+            Ok(())
+        }
+        HandshakeMessage::ServerFinish { success: false, .. } => Err("服务端拒绝了认证凭据".into()),
+        _ => Err("预期收到 ServerFinish".into()),
+    }
+}
+
+/// 用缓存的票据恢复上次的会话（见 vpn_core::resume），返回派生出的新会话密钥和服务端接受的 FEC 分组大小
+async fn resume_session(
+    socket: &UdpSocket,
+    server_addr: SocketAddr,
+    cached: &CachedSession,
+    fec: Option<u8>,
+    rx: &mut HandshakeRx<'_>,
+    timeout: Duration,
+) -> Result<([u8; 32], Option<u8>), Box<dyn Error>> {
+    println!("🎫 尝试恢复上次的会话...");
+    let proof = resume::resume_proof(&cached.session_key, &cached.ticket, resume::server_now())?;
+    let msg = HandshakeMessage::Resume { ticket: cached.ticket, proof: proof.clone(), fec };
+    socket.send_to(&serialize_message(&msg)?, server_addr).await?;
     
-    // 注意：这里简化了协议，省略了 ClientFinish/ServerFinish
-    // 完整实现应该继续发送确认消息    
-    Ok(session_key)
+    match rx.recv(timeout).await? {
+        HandshakeMessage::ResumeAck { proof: ack, fec: accepted } => {
+            let session_key = resume::resumed_key(&cached.session_key, &proof);
+            resume::verify_resume_ack(&session_key, &cached.ticket, &ack)?;
+            println!("   ✅ 会话已恢复，跳过完整握手");
+            keylog::record(KeyEvent::Resume, server_addr, &session_key);
+            Ok((session_key, accepted_fec(fec, accepted)))
+        }
+        HandshakeMessage::ServerFinish { success: false, .. } => Err("服务端拒绝了会话恢复".into()),
+        _ => Err("预期收到 ResumeAck".into()),
+    }
+}
+
+/// 通过 mDNS 在局域网上查找服务端（--discover），有多个时使用第一个或 --discover-name 指定的那个
+async fn discover_server(name: Option<&str>) -> Result<String, Box<dyn Error>> {
+    println!("🔎 正在局域网上查找服务端（{}）...", mdns::SERVICE_TYPE);
+    let servers = mdns::discover(Duration::from_secs(3)).await?;
+    for server in &servers {
+        println!("   • {} {} [{}]", server.instance, server.addr, server.txt.join(" "));
+    }
+    let chosen = match name {
+        Some(name) => servers.iter().find(|s| s.instance == name).ok_or_else(|| format!("没有找到名为 {} 的服务端", name))?,
+        None => servers.first().ok_or("局域网上没有发现服务端（服务端需要以 --mdns 启动）")?,
+    };
+    println!("   ✅ 使用 {} ({})", chosen.instance, chosen.addr);
+    Ok(chosen.addr.to_string())
 }
 
 #[tokio::main]
@@ -197,21 +546,136 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // === 1. 获取命令行参数 ===
     let args: Vec<String> = env::args().collect();
     
-    // 用法: ./vpn_client <虚拟IP> [服务器地址] [--full-tunnel]
+    // 用法: ./vpn_client <虚拟IP|auto> [服务器地址] [--full-tunnel] [--otlp-endpoint <url>]
+    //       配置文件: [--config <文件>]（TOML，见 README；位置参数也可以写成 --virtual-ip / --server） [--check-config]
+    //       连接配置: ./vpn_client connect <名称> [参数]（使用 ~/.config/rust-vpn/profiles/<名称>.toml） ./vpn_client profiles（列出）
+    //       试运行: [--dry-run]（列出将对系统做的修改后退出） [--skip-preflight]（跳过启动前的权限和依赖检查）
+    //       多出口: [--exit <虚拟IP>@<服务器>=<网段|域名|default>,...]（可重复，每个出口一个隧道，按目的地址选择）
+    //       多隧道: [--tunnel <名称>:<虚拟IP>@<服务器>[=<目的>,...]]（可重复） [--control-socket <路径>]
+    //       隧道控制: ./vpn_client tunnel list|up|down|restart [<名称>] [--control-socket <路径>]
+    //       多实例: [--tun-name <名称>] [--tun-reuse] [--route-metric <n>] [--route-table <id>] [--allow-subnet-overlap]
+    //       多路径（实验性，Linux）: [--bond <第二块网卡>] [--bond-mode duplicate|round-robin]（默认 duplicate）
+    //       性能: [--tun-offload]（Linux TSO/GSO 卸载） [--pmtu-probe]（隧道内 PMTU 探测）
+    //       上行限速: [--pace <速率>|auto]（如 20mbit；auto 按 RTT 和探测丢失自动调整），避免填满上行链路的缓冲区
+    //       前向纠错: [--fec <分组大小>]（2~16，推荐 4），每组多发一个校验包，组内丢一个包时不用重传即可恢复
+    //       诊断: [--diagnose]（逐项检查握手各环节并给出建议，不需要 root，不创建 TUN）
+    //       控制通道: [--rekey-interval <秒>]（默认 3600） [--keepalive <秒>]（默认 25） [--exit-on-link-down]
+    //       运行档位: [--profile gaming|bulk|default]（一次调好批处理、FEC、保活和限速，显式给出的参数优先）
+    //       网络: [--dns <ip,ip>] [--route <网段>]（可重复，额外经由隧道的 IPv4 网段） [--no-network-watch]（不监听休眠唤醒/网络切换）
+    //       开机自启: [--wait-online] [--wait-online-timeout <秒>]（默认 120，握手前等待默认路由和服务器域名可以解析）
+    //       主机名: [--name <名称>]（默认取本机主机名，服务端开启 --dns-forwarder 时解析为 <名称>.vpn）
+    //       设备信息: [--report-metadata]（向服务端上报主机名、操作系统和客户端版本，显示在 vpn_server clients 中；默认不上报）
+    //       在线对端: ./vpn_client peers|services [--virtual-ip <ip>]（列出按 ACL 可以访问的在线对端 / 它们登记的服务）
+    //       服务登记: [--advertise <名称>=<tcp|udp>:<端口>]（可重复，同时对隧道开放该端口，其他客户端用 vpn_client services 查询）
+    //       Linux 全隧道: [--fwmark <mark>] [--no-policy-routing]（改为替换默认路由）
+    //       IPv6: [--ipv6]（配置隧道 IPv6 地址 fd00::/96，全隧道时 IPv6 流量也走 VPN）
+    //       局域网发现: [--discover] [--discover-name <实例名>]（省略服务器地址，通过 mDNS 查找）
+    //       身份: [--identity-dir <目录>]（默认 ~/.config/rust-vpn，保存客户端 UUID 和身份私钥） [--tpm-seal]
+    //             [--no-session-resume]（不保存会话，重启后总是完整握手）
+    //             [--agent-socket <路径>]（或 RUST_VPN_AGENT_SOCK，通过密钥代理签名）
+    //       服务端公钥: [--server-key <hex>]（固定服务端公钥，代替 keys/server_public.key）
+    //       签名验证: [--max-signature-failures <n>]（默认 3） [--on-signature-failure exit|hold]（ServerHello 签名连续验证失败后停止重连）
+    //       日志: [--log stdout|file:<路径>|journald|syslog|oslog] [--log-max-size <大小>] [--log-rotate hourly|daily] [--log-keep <n>]（默认 5）
+    //       协议调试: [--debug-key-log <路径>]（把会话密钥追加写入文件，供 Wireshark 解密抓包；不要在生产环境使用）
+    //       性能自测: ./vpn_client bench [--duration <秒>] [--size <字节>] [--batch-size <n>] [--mtu <字节>]（本机回环，不需要 root）
+    //       密钥代理: ./vpn_client agent [--identity-dir <目录>] [--agent-socket <路径>] [--tpm-seal]
+    //       入站防火墙: [--expose <规则>]（可重复，如 tcp:22,icmp；all 关闭防火墙），默认只放行本机发起的连接的回包
+    //       NAT 检测: [--stun <host:port>]（与服务端看到的公网映射比较，判断是否为对称型 NAT）
+    //       调优: [--recv-buffer <大小>] [--send-buffer <大小>] [--handshake-timeout <秒>] [--handshake-retries <n>] [--batch-size <n>] [--mtu <字节>] [--watchdog <秒>]
     // 示例: ./vpn_client 10.0.0.2 example.com:9000 --full-tunnel
-    let tun_ip = if args.len() > 1 { args[1].clone() } else { "10.0.0.1".to_string() };
-    let server_addr = if args.len() > 2 { 
-        args[2].clone()
-    } else { 
-        "127.0.0.1:9000".to_string()
+    if args.get(1).map(String::as_str) == Some("agent") {
+        return run_agent(&args);
+    }
+    if args.get(1).map(String::as_str) == Some("bench") {
+        return Ok(vpn_core::bench::run_command(&args).await?);
+    }
+    #[cfg(unix)]
+    if args.get(1).map(String::as_str) == Some("tunnel") {
+        return Ok(tunnels::run_client(&args).await?);
+    }
+    #[cfg(unix)]
+    if matches!(args.get(1).map(String::as_str), Some("peers" | "services")) {
+        return Ok(roster::run_client(&args).await?);
+    }
+    if args.get(1).map(String::as_str) == Some("profiles") {
+        return Ok(profiles::run_list(&args)?);
+    }
+    // 命名的连接配置：connect <名称> 展开为 --config <配置目录>/profiles/<名称>.toml
+    let args = profiles::expand(args)?;
+    // 配置文件（--config）展开为命令行参数，命令行上的值优先
+    let args = config::load_args(&args, Role::Client)?;
+    if args.contains(&"--check-config".to_string()) {
+        return check_config(&args);
+    }
+    if let Some(profile) = arg_value(&args, "--profile") {
+        println!("🎚️  运行档位: {}（未显式指定的参数按档位取值）", profile);
+    }
+    // 日志输出（--log），多隧道时各隧道共用
+    if !dryrun::requested(&args) {
+        logsink::init_from_args(&args, "vpn_client")?;
+    }
+    crash::install();
+    // 多出口 / 多隧道：每条隧道在本进程内单独运行（见 exits.rs），这里只负责看护和配置路由
+    let exits = exits::from_args(&args)?;
+    if !exits.is_empty() {
+        let servers: Vec<String> = exits.iter().map(|exit| exit.server.clone()).collect();
+        online::wait_from_args(&args, &servers).await?;
+        return Ok(exits::run(&args, exits).await?);
+    }
+    if dryrun::requested(&args) {
+        return dry_run(&args).await;
+    }
+    let positional = |i: usize| args.get(i).filter(|a| !a.starts_with("--")).cloned();
+    let tun_ip = positional(1).or_else(|| arg_value(&args, "--virtual-ip")).unwrap_or_else(|| "10.0.0.1".to_string());
+    let server_arg = positional(1).and(positional(2)).or_else(|| arg_value(&args, "--server"));
+    // 开机自启时网络可能还没就绪（--wait-online）：先等默认路由和服务器域名解析，再解析地址和握手
+    if !args.contains(&"--diagnose".to_string()) {
+        online::wait_from_args(&args, server_arg.as_slice()).await?;
+    }
+    let server_addr = match server_arg {
+        Some(addr) => addr,
+        None if args.contains(&"--discover".to_string()) => discover_server(arg_value(&args, "--discover-name").as_deref()).await?,
+        None => "127.0.0.1:9000".to_string(),
     };
-    
+    // 握手诊断：不需要 root，不修改系统状态
+    if args.contains(&"--diagnose".to_string()) {
+        return Ok(diagnose::run(&args, &server_addr, tun_ip).await?);
+    }
+    // 在修改任何系统状态之前检查权限和依赖的命令
+    if !preflight::skipped(&args) {
+        preflight_checks(&args).run()?;
+    }
+    run_tunnel(&args, tun_ip, server_addr, TunnelEnd::Process).await
+}
+
+/// 建立一条隧道并运行到结束：握手、创建 TUN 设备、配置路由，然后转发
+///
+/// 单隧道模式由 main 调用，结束时退出进程；多隧道模式下每条隧道在自己的运行时里调用（见 exits.rs），
+/// 结束时把退出码交给监督任务。启动失败时返回错误，已经做的修改由撤销日志恢复。
+async fn run_tunnel(args: &[String], mut tun_ip: String, server_addr: String, end: TunnelEnd) -> Result<(), Box<dyn Error>> {
+    // --virtual-ip auto：由服务端分配，握手完成后才知道地址
+    let auto_ip = tun_ip == AUTO_VIRTUAL_IP;
+
     // 检查是否启用全隧道模式（所有流量走VPN）
     let full_tunnel = args.contains(&"--full-tunnel".to_string());
     
+    // 可选：OTLP 导出（--otlp-endpoint 或 OTEL_EXPORTER_OTLP_ENDPOINT）
+    let telemetry = Telemetry::from_env_or_arg(arg_value(args, "--otlp-endpoint").as_deref(), "vpn_client");
+    
     println!("🛡️ VPN Client Starting...");
-    println!("📍 虚拟 IP: {}", tun_ip);
-    println!("🌐 服务器: {}", server_addr);
+    datapath_log::init_trace_from_args(args);
+    keylog::init_from_args(args)?;
+    if auto_ip {
+        println!("📍 虚拟 IP: 由服务端分配");
+    } else {
+        println!("📍 虚拟 IP: {}", tun_ip);
+    }
+    let endpoint = Arc::new(ServerEndpoint::resolve(&server_addr).await?);
+    println!("🌐 服务器: {} ({})", endpoint.host(), endpoint.addr());
+    let stun_server = match arg_value(args, "--stun") {
+        Some(host) => Some(endpoint::lookup(&host).await?),
+        None => None,
+    };
     if full_tunnel {
         println!("🌍 全隧道模式：所有流量将通过VPN");
     } else {
@@ -220,7 +684,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     // === 全隧道模式：保存原始网关（用于退出时恢复） ===
     if full_tunnel {
-        let gateway = detect_default_gateway();
+        let gateway = netwatch::default_gateway();
         if let Some(gw) = &gateway {
             let mut orig_gw = ORIGINAL_GATEWAY.lock().await;
             *orig_gw = Some(gw.clone());
@@ -230,74 +694,225 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     // === 配置 ===
     let tun_mask = "255.255.255.0";
-    let target_cidr = if full_tunnel {
-        "0.0.0.0/0" // 默认路由，所有流量
-    } else {
-        "10.0.0.0/24" // 仅VPN网段
+    
+    // === 多实例：设备名、路由 metric/表，以及网段冲突检查 ===
+    let device_options = local_tun::DeviceOptions {
+        name: arg_value(args, "--tun-name"),
+        reuse_existing: args.contains(&"--tun-reuse".to_string()),
+        offload: args.contains(&"--tun-offload".to_string()),
     };
+    let route_options = local_tun::RouteOptions {
+        metric: arg_value(args, "--route-metric").map(|v| v.parse()).transpose()?,
+        table: arg_value(args, "--route-table").map(|v| v.parse()).transpose()?,
+    };
+    
+    // 自动分配的地址在握手后检查
+    if !auto_ip {
+        check_subnet_conflict(args, &tun_ip, tun_mask, &device_options)?;
+    }
+
+    // === 可选：外部认证凭据（OIDC / LDAP），设备授权流程需要在握手前完成 ===
+    let credential = auth::credential_from_args(args).await?;
 
     // === 3. 创建 UDP Socket（握手前需要先创建） ===
+    let tuning = Tuning::from_args(args)?;
+    let signature_guard = Arc::new(SignatureGuard::from_args(args)?);
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
     println!("📡 UDP Socket: {}", socket.local_addr()?);
+    if tuning.recv_buffer.is_some() || tuning.send_buffer.is_some() {
+        let (recv, send) = tuning.apply_socket(&socket)?;
+        println!("   📦 socket 缓冲区: 接收 {} 字节，发送 {} 字节", recv, send);
+    }
+    
+    // PMTU 探测需要外层 UDP 设置 DF，否则超大的探测包会被分片而不是丢弃
+    let pmtu_probe = args.contains(&"--pmtu-probe".to_string());
+    if pmtu_probe {
+        pmtu::set_dont_fragment(&socket)?;
+    }
+    
+    // Linux 全隧道默认使用策略路由：隧道自身的 UDP 包打上 fwmark，不修改默认路由
+    #[cfg(target_os = "linux")]
+    let mut policy_routing = if full_tunnel && !args.contains(&"--no-policy-routing".to_string()) {
+        let policy = local_tun::PolicyRouting {
+            fwmark: arg_value(args, "--fwmark").map(|v| local_tun::parse_fwmark(&v)).transpose()?.unwrap_or(local_tun::DEFAULT_FWMARK),
+            table: route_options.table.unwrap_or(local_tun::DEFAULT_POLICY_TABLE),
+        };
+        match local_tun::set_fwmark(&socket, policy.fwmark) {
+            Ok(_) => Some(policy),
+            Err(e) => {
+                eprintln!("⚠️ 设置 fwmark 失败，改为替换默认路由: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(not(target_os = "linux"))]
+    let policy_routing: Option<local_tun::PolicyRouting> = None;
     
     // === 执行握手，获取会话密钥 ===
-    let session_key = perform_handshake(&socket, &server_addr, format!("client_{}", tun_ip), tun_ip.clone()).await?;
+    let identity = Arc::new(load_identity(args)?);
+    println!("🪪 客户端身份: {} (公钥 {}，{})", identity.id(), identity.fingerprint(), identity.backend_name());
+    println!("🔐 加密后端: {}", vpn_core::crypto::backend_name());
+    // PSK 文件（--psk-file）：启动时先检查一次，之后每次握手重新读取
+    let psk_file = arg_value(args, "--psk-file").map(std::path::PathBuf::from);
+    if let Some(path) = &psk_file {
+        println!("🔑 PSK: {}（ID {}）", path.display(), hex::encode(psk::psk_id(&psk::load(path)?)));
+    }
+    // 固定的服务端公钥（--server-key），每次握手都用它验证，不读取 keys/server_public.key
+    let server_key = arg_value(args, "--server-key");
+    if let Some(key) = &server_key {
+        ClientVerifier::from_hex(key)?;
+    }
+    let mut startup_rx = HandshakeRx::Socket(&socket);
+    // 可选：前向纠错（--fec），分组大小在握手时与服务端协商
+    let fec_link = Arc::new(FecLink::new(arg_value(args, "--fec").map(|v| fec::parse_group_size(&v)).transpose()?));
+    
+    // 会话恢复：进程重启前的会话还在有效期内时，凭票据恢复，跳过完整握手和认证
+    let resume_state = if args.contains(&"--no-session-resume".to_string()) {
+        None
+    } else {
+        match SessionCache::open(&identity_dir(args)?, key_protection(args)) {
+            Ok(cache) => Some(Arc::new(ResumeState::new(cache, tun_ip.clone()))),
+            Err(e) => {
+                eprintln!("⚠️ 无法打开会话恢复缓存: {}", e);
+                None
+            }
+        }
+    };
+    let mut resumed = None;
+    if let Some(state) = &resume_state
+        && let Some(cached) = state.cached_for(endpoint.addr())
+    {
+        match resume_session(&socket, endpoint.addr(), &cached, fec_link.requested(), &mut startup_rx, tuning.handshake_timeout).await {
+            Ok((session_key, fec)) => {
+                // 恢复的会话沿用上次分配的地址
+                if auto_ip {
+                    tun_ip = cached.virtual_ip.clone();
+                    state.set_virtual_ip(&tun_ip);
+                    println!("   🏷️  沿用上次分配的虚拟 IP: {}", tun_ip);
+                }
+                state.set_ticket(cached.ticket, cached.lifetime_secs);
+                state.save(endpoint.addr(), session_key);
+                resumed = Some((session_key, fec));
+            }
+            Err(e) => {
+                println!("   ↩️  会话恢复失败（{}），改为完整握手", e);
+                state.clear();
+            }
+        }
+    }
+    
+    // 本端的功能标志：只声明已经启用的功能
+    let capabilities = Capabilities::REKEY
+        .with(Capabilities::ROAMING, resume_state.is_some())
+        .with(Capabilities::IPV6, args.contains(&"--ipv6".to_string()))
+        .with(Capabilities::FEC, fec_link.requested().is_some())
+        .with(Capabilities::BONDING, arg_value(args, "--bond").is_some());
+    // 会话恢复时没有交换功能标志，与服务端未声明时相同
+    let mut negotiated = None;
+    let (session_key, fec) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let hello = HelloOptions {
+                virtual_ip: tun_ip.clone(),
+                fec: fec_link.requested(),
+                capabilities,
+                psk_file: psk_file.clone(),
+                server_key: server_key.clone(),
+            };
+            let Handshake { session_key, fec, assigned_ip, capabilities } = match perform_handshake(&socket, endpoint.addr(), &identity, hello, &telemetry, &mut startup_rx, tuning.handshake_timeout).await {
+                Ok(result) => result,
+                Err(e) => {
+                    // 启动时还没有配置隧道，两种策略都直接退出，不回退到其他方式连接
+                    if let Some(error) = e.downcast_ref::<SignatureError>() {
+                        signature_guard.record_failure(error);
+                        signature_guard.alert(endpoint.host());
+                        end.finish(sigguard::SIGNATURE_FAILURE_EXIT).await;
+                    }
+                    eprintln!("💡 加上 --diagnose 逐项检查域名解析、UDP 可达性、服务端公钥和 PSK");
+                    return Err(e);
+                }
+            };
+            if let Some(cred) = &credential {
+                authenticate(&socket, endpoint.addr(), &session_key, cred, &mut startup_rx, tuning.handshake_timeout).await?;
+            }
+            negotiated = capabilities;
+            // 之后的重新握手请求同一个地址
+            if let Some(ip) = assigned_ip {
+                tun_ip = ip.to_string();
+                if let Some(state) = &resume_state {
+                    state.set_virtual_ip(&tun_ip);
+                }
+            }
+            (session_key, fec)
+        }
+    };
+    if auto_ip {
+        check_subnet_conflict(args, &tun_ip, tun_mask, &device_options)?;
+    }
+    
+    let target_cidr = if full_tunnel {
+        "0.0.0.0/0".to_string() // 默认路由，所有流量
+    } else {
+        local_tun::network_cidr(&tun_ip, tun_mask)? // 仅VPN网段（由虚拟 IP 和掩码推出）
+    };
+    fec_link.apply(fec);
     
     // === 使用会话密钥初始化加密模块 ===
-    let cipher = Arc::new(Cipher::new(&session_key)?);
+    let keys = Arc::new(KeyRing::new(session_key)?);
     println!("🔐 加密通道已建立");
 
     // === 2. 创建 TUN 设备（握手成功后再创建，避免影响握手） ===
-    let dev = local_tun::create_device(&tun_ip, tun_mask)?;
-    let dev_name = dev.get_ref().name()?; 
+    // 启动失败（返回错误或 panic）时按相反顺序撤销下面已应用的配置；启动完成后交给 Ctrl+C 处理
+    let mut journal = UndoJournal::new();
+    let (dev, dev_name) = local_tun::open_device(&tun_ip, tun_mask, &device_options)?;
     
-    // === 全隧道模式：添加服务器路由例外（在配置默认路由之前） ===
-    if full_tunnel {
-        // 解析服务器地址，提取 IP
-        let server_ip = server_addr.split(':').next().unwrap_or(&server_addr);
-        
-        // 添加到服务器的路由例外（通过本地网关）
-        #[cfg(target_os = "macos")]
-        {
-            // 获取当前默认网关
-            let gateway_output = std::process::Command::new("route")
-                .args(&["-n", "get", "default"])
-                .output();
-            
-            if let Ok(output) = gateway_output {
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                if let Some(gateway_line) = stdout.lines().find(|l| l.trim().starts_with("gateway:")) {
-                    if let Some(gateway) = gateway_line.split(':').nth(1).map(|s| s.trim()) {
-                        println!("   🛡️  添加服务器路由例外: {} via {}", server_ip, gateway);
-                        let _ = std::process::Command::new("route")
-                            .args(&["-n", "add", server_ip, gateway])
-                            .status();
-                    }
-                }
+    // === 全隧道策略路由（Linux） ===
+    #[cfg(target_os = "linux")]
+    if let Some(policy) = policy_routing {
+        match local_tun::enable_policy_routing(&dev_name, &policy) {
+            Ok(_) => {
+                println!("✅ 策略路由已启用（fwmark {:#x}，路由表 {}），所有流量走VPN", policy.fwmark, policy.table);
+                journal.record("移除策略路由", move || local_tun::disable_policy_routing(&policy));
+            }
+            Err(e) => {
+                eprintln!("⚠️ 策略路由配置失败，改为替换默认路由: {}", e);
+                policy_routing = None;
             }
         }
-        
-        #[cfg(target_os = "linux")]
-        {
-            // Linux 上添加例外路由
-            let gateway_output = std::process::Command::new("ip")
-                .args(&["route", "show", "default"])
-                .output();
-            
-            if let Ok(output) = gateway_output {
-                let stdout = String::from_utf8_lossy(&output.stdout);
-                if let Some(gateway) = stdout.split_whitespace().nth(2) {
-                    println!("   🛡️  添加服务器路由例外: {} via {}", server_ip, gateway);
-                    let _ = std::process::Command::new("ip")
-                        .args(&["route", "add", server_ip, "via", gateway])
-                        .status();
-                }
+    }
+    
+    // === 全隧道模式：添加服务器路由例外（在配置默认路由之前） ===
+    if full_tunnel && policy_routing.is_none() {
+        // 添加到服务器的路由例外（通过本地网关）
+        if let Some(gateway) = netwatch::default_gateway() {
+            // STUN 查询需要和隧道一样直接走物理网卡，否则看到的是服务端的出口地址
+            for ip in std::iter::once(endpoint.addr().ip()).chain(stun_server.map(|s| s.ip())) {
+                let ip = ip.to_string();
+                add_server_route_exception(&ip, &gateway);
+                journal.record(format!("删除路由例外 {}", ip), move || remove_server_route_exception(&ip));
             }
         }
     }
     
     // === 路由配置 (容错处理) ===
-    match local_tun::configure_route(&dev_name, target_cidr) {
+    if let Some(table) = route_options.table.filter(|_| policy_routing.is_none()) {
+        println!("📋 路由写入表 {}（需要配合 ip rule 使用）", table);
+    }
+    let route_result = match policy_routing {
+        Some(_) => Ok(()),
+        None => local_tun::configure_route_with(&dev_name, &target_cidr, &route_options),
+    };
+    if route_result.is_ok() && policy_routing.is_none() {
+        let (dev, cidr) = (dev_name.clone(), target_cidr.clone());
+        journal.record(format!("删除路由 {}", target_cidr), move || {
+            let _ = local_tun::remove_route(&dev, &cidr);
+        });
+    }
+    match route_result {
+        // 策略路由已在上面打印
+        Ok(_) if policy_routing.is_some() => {}
         Ok(_) => {
             if full_tunnel {
                 println!("✅ 默认路由已设置（所有流量走VPN）");
@@ -309,136 +924,1278 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Err(e) => eprintln!("⚠️ 路由配置警告 (本地多开时可忽略): {}", e),
     }
     
-    println!("🚀 TUN 设备 {} 就绪", dev_name);
-
-    // === 注册 Ctrl+C 信号处理器（优雅退出） ===
-    if full_tunnel {
-        tokio::spawn(async move {
-            tokio::signal::ctrl_c().await.ok();
-            println!("\n\n🛑 收到退出信号，正在恢复网络...");
-            restore_default_gateway().await;
-            std::process::exit(0);
-        });
+    // === DNS（--dns 1.1.1.1,8.8.8.8） ===
+    let dns_servers: Vec<std::net::Ipv4Addr> = match arg_value(args, "--dns") {
+        Some(list) => list.split(',').map(|s| s.trim().parse()).collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    if !dns_servers.is_empty() {
+        match local_tun::set_dns(&dev_name, &dns_servers) {
+            Ok(_) => {
+                println!("🧭 DNS 已设置: {:?}", dns_servers);
+                let dev = dev_name.clone();
+                journal.record("恢复 DNS", move || {
+                    let _ = local_tun::clear_dns(&dev);
+                });
+            }
+            Err(e) => eprintln!("⚠️ DNS 配置失败: {}", e),
+        }
     }
-
-    // === Socket 已在握手前创建，这里转为 Arc ===
-    let socket = Arc::new(socket);
-
-    // === 4. 分离资源 ===
-    let (mut tun_reader, mut tun_writer) = tokio::io::split(dev);
     
-    let socket_uplink = socket.clone();
-    let socket_downlink = socket.clone();
-
-    let cipher_uplink = cipher.clone();
-    let cipher_downlink = cipher.clone();
+    // === 额外经由隧道的网段（--route，连接配置中的 network.routes） ===
+    for cidr in arg_values(args, "--route") {
+        match local_tun::configure_route_with(&dev_name, &cidr, &route_options) {
+            Ok(_) => {
+                println!("🧭 已添加路由: {}", cidr);
+                let dev = dev_name.clone();
+                journal.record(format!("删除路由 {}", cidr), move || {
+                    let _ = local_tun::remove_route(&dev, &cidr);
+                });
+            }
+            Err(e) => eprintln!("⚠️ 路由 {} 配置失败: {}", cidr, e),
+        }
+    }
     
-    // 克隆 server_addr 用于 uplink task
-    let server_addr_uplink = server_addr.clone();
-
-    // === 5. 上行任务 (TUN -> Encrypt -> UDP) ===
-    let uplink_task = tokio::spawn(async move {
-        let mut buf = [0u8; 1500];
-        println!("⬆️ 上行任务启动...");
-        
-        loop {
-            let n = match tun_reader.read(&mut buf).await {
-                Ok(n) => n,
-                Err(e) => {
-                    eprintln!("❌ TUN 读取错误: {}", e);
-                    break;
-                }
-            };
-            if n == 0 { break; }
-
-            // 过滤坏包
-            if n <= TUN_READ_OFFSET { 
-                continue; 
-            }
-            
-            // 提取纯 IP 数据
-            let ip_packet = &buf[TUN_READ_OFFSET..n];
-            
-            // 打印 IP 包信息（仅 ICMP）
-            if ip_packet.len() >= 20 {
-                let proto = ip_packet[9];
-                if proto == 1 { // ICMP
-                    let src = format!("{}.{}.{}.{}", ip_packet[12], ip_packet[13], ip_packet[14], ip_packet[15]);
-                    let dst = format!("{}.{}.{}.{}", ip_packet[16], ip_packet[17], ip_packet[18], ip_packet[19]);
-                    println!("📮 [发送] {} -> {} (ICMP)", src, dst);
+    // === IPv6（--ipv6）：地址由虚拟 IPv4 地址推出，需要服务端同样开启 --ipv6 ===
+    let mut ipv6_full_tunnel = false;
+    let server_lacks_ipv6 = negotiated.is_some_and(|c: Capabilities| !c.contains(Capabilities::IPV6));
+    if args.contains(&"--ipv6".to_string()) && server_lacks_ipv6 {
+        eprintln!("⚠️ 服务端没有开启 --ipv6，跳过隧道 IPv6 配置");
+    } else if args.contains(&"--ipv6".to_string()) {
+        let tun_ipv6 = local_tun::tunnel_ipv6(tun_ip.parse()?);
+        match local_tun::add_ipv6_address(&dev_name, tun_ipv6) {
+            Ok(_) => println!("✅ 隧道 IPv6 地址: {}", tun_ipv6),
+            Err(e) => eprintln!("⚠️ IPv6 地址配置失败: {}", e),
+        }
+        if full_tunnel {
+            match local_tun::configure_ipv6_full_tunnel(&dev_name) {
+                Ok(_) => {
+                    ipv6_full_tunnel = true;
+                    println!("✅ IPv6 默认路由已设置");
+                    let dev = dev_name.clone();
+                    journal.record("删除 IPv6 默认路由", move || local_tun::remove_ipv6_full_tunnel(&dev));
                 }
+                Err(e) => eprintln!("⚠️ IPv6 路由配置失败: {}", e),
             }
+        }
+    }
+    
+    println!("🚀 TUN 设备 {} 就绪", dev_name);
+
+    // === 可选：TUN MTU（--mtu，巨型帧需要服务端使用相同的值） ===
+    if let Some(mtu) = tuning.mtu {
+        match local_tun::set_mtu(&dev_name, mtu) {
+            Ok(_) => println!("✅ TUN MTU: {}", mtu),
+            Err(e) => eprintln!("⚠️ MTU 配置失败: {}", e),
+        }
+    }
+
+    // === 可选：隧道内 PMTU 探测，收敛前先使用保守的 MTU ===
+    if pmtu_probe {
+        let dev = dev_name.clone();
+        journal.record("删除 MSS 钳制规则", move || gateway::clear_mss_clamp(&dev));
+        match local_tun::set_mtu(&dev_name, pmtu::INITIAL_MTU)
+            .and_then(|_| gateway::set_mss_clamp(&dev_name, pmtu::INITIAL_MTU - 40))
+        {
+            Ok(_) => println!("📏 PMTU 探测已启用，初始 MTU {}", pmtu::INITIAL_MTU),
+            Err(e) => eprintln!("⚠️ 设置初始 MTU 失败: {}", e),
+        }
+    }
+
+    // === Socket 已在握手前创建，这里转为 Arc ===
+    let socket = Arc::new(socket);
+    let tunnel = Arc::new(TunnelContext {
+        dev_name: dev_name.clone(),
+        full_tunnel,
+        pmtu_probe,
+        route_options,
+        target_cidr: target_cidr.clone(),
+        policy_routing,
+        exit_on_link_down: args.contains(&"--exit-on-link-down".to_string()),
+        custom_dns: !dns_servers.is_empty(),
+        ipv6_full_tunnel,
+        end: end.clone(),
+    });
 
-            // 加密
-            let encrypted_packet = match cipher_uplink.encrypt(ip_packet) {
-                Ok(data) => data,
-                Err(e) => { eprintln!("❌ 加密失败: {}", e); continue; }
+    // === 注册退出处理（Ctrl+C 或监督任务要求停止时通知服务端后优雅退出）；运行期间的致命错误（任务 panic、转发循环退出）同样处理 ===
+    {
+        let socket = socket.clone();
+        let endpoint = endpoint.clone();
+        let keys = keys.clone();
+        let tunnel = tunnel.clone();
+        let resume_state = resume_state.clone();
+        let end = end.clone();
+        tokio::spawn(async move {
+            let code = tokio::select! {
+                _ = end.stopped() => 0,
+                report = crash::wait() => {
+                    // 多隧道时报告由监督任务打印一次
+                    if matches!(end, TunnelEnd::Process) {
+                        eprintln!("{}", report);
+                    }
+                    crash::CRASH_EXIT
+                }
             };
+            let cleanup = tokio::spawn(async move {
+                let disconnect = ControlMessage::Disconnect { reason: "client exit".to_string() };
+                send_control(&socket, endpoint.addr(), &keys, &disconnect).await;
+                // 主动断开后服务端作废票据，缓存不再有用
+                if let Some(state) = &resume_state {
+                    state.clear();
+                }
+                tunnel.exit(code).await;
+            });
+            end.finish_after(cleanup, code).await;
+        });
+    }
 
-            // 发送给 Server
-            if let Err(e) = socket_uplink.send_to(&encrypted_packet, &server_addr_uplink).await {
-                eprintln!("❌ UDP 发送错误: {}", e);
+    // 入站防火墙：默认丢弃隧道内其他客户端/服务端主动发来的包，--expose 和 --advertise 开放指定服务
+    let services = advertised_services(args)?;
+    let mut exposed = arg_values(args, "--expose").join(",");
+    if !exposed.trim().eq_ignore_ascii_case("all") {
+        for service in &services {
+            if !exposed.is_empty() {
+                exposed.push(',');
             }
+            exposed.push_str(&service.rule());
         }
+    }
+    let allowlist = if exposed.is_empty() { Allowlist::empty() } else { Allowlist::parse(&exposed)? };
+    let firewall = if allowlist.is_unrestricted() {
+        println!("🧱 入站防火墙已关闭（--expose all）");
+        None
+    } else {
+        println!("🧱 入站防火墙已启用，开放的服务: {}", if exposed.is_empty() { "（无）" } else { &exposed });
+        Some(Arc::new(std::sync::Mutex::new(InboundFirewall::new(allowlist))))
+    };
+
+    // === 控制通道：保活、密钥轮换、路由下发、断开 ===
+    let rekey_interval = match arg_value(args, "--rekey-interval") {
+        Some(secs) => Duration::from_secs(secs.parse()?),
+        None => control::DEFAULT_REKEY_INTERVAL,
+    };
+    let keepalive = match arg_value(args, "--keepalive") {
+        Some(secs) => Duration::from_secs(secs.parse::<u64>().ok().filter(|s| *s > 0).ok_or_else(|| format!("无效的 --keepalive: {}", secs))?),
+        None => control::KEEPALIVE_INTERVAL,
+    };
+    let hostname = client_hostname(args)?;
+    let metadata = args.contains(&"--report-metadata".to_string()).then(client_metadata);
+    let (control_tx, control_rx) = mpsc::channel(EVENT_QUEUE_DEPTH);
+    let (stun_tx, stun_rx) = mpsc::channel(EVENT_QUEUE_DEPTH);
+    let (migrate_tx, migrate_rx) = mpsc::unbounded_channel();
+    let nat = NatProbe::new(stun_server, socket.local_addr()?.port(), tunnel.policy_routing.as_ref().map(|p| p.fwmark));
+    // 可选：上行发送节奏控制（--pace）
+    let pacing = Pacing::from_args(args)?.map(Arc::new);
+    if let Some(pacing) = &pacing {
+        println!("🐢 上行限速: {}", pacing.summary());
+        pacing.spawn(socket.clone(), endpoint.clone(), keys.clone());
+    }
+    // 在线对端查询接口（vpn_client peers）
+    let roster = Arc::new(Roster::default());
+    #[cfg(unix)]
+    if let Err(e) = roster::spawn(roster.clone(), &roster::socket_path(&tun_ip), socket.clone(), endpoint.clone(), keys.clone()) {
+        eprintln!("⚠️  在线对端查询接口启动失败: {}", e);
+    }
+    let control_task = ControlTask {
+        socket: socket.clone(),
+        endpoint: endpoint.clone(),
+        keys: keys.clone(),
+        tunnel: tunnel.clone(),
+        migrations: migrate_tx.clone(),
+        nat,
+        resume: resume_state.clone(),
+        pacing: pacing.clone(),
+        fec: fec_link.clone(),
+        keepalive,
+        hostname,
+        metadata,
+        services: services.clone(),
+        firewall: firewall.clone(),
+        roster,
+        psk_file: psk_file.clone(),
+    };
+    tokio::spawn(run_control(control_task, control_rx, stun_rx, rekey_interval));
+
+    let (pmtu_ack_tx, pmtu_ack_rx) = mpsc::channel(EVENT_QUEUE_DEPTH);
+    if pmtu_probe {
+        tokio::spawn(run_pmtu_probe(socket.clone(), endpoint.clone(), keys.clone(), dev_name.clone(), pmtu_ack_rx));
+    }
+    // === 网络变化（休眠唤醒、切换网络）或服务器地址变化后重新应用路由并重新握手 ===
+    let (handshake_tx, handshake_rx) = mpsc::channel(EVENT_QUEUE_DEPTH);
+    let params = HandshakeParams {
+        endpoint: endpoint.clone(),
+        identity,
+        virtual_ip: tun_ip.clone(),
+        fec: fec_link.clone(),
+        credential,
+        telemetry: telemetry.clone(),
+        timeout: tuning.handshake_timeout,
+        retries: tuning.handshake_retries,
+        resume: resume_state,
+        signature: signature_guard,
+        capabilities,
+        psk_file: psk_file.clone(),
+        server_key,
+    };
+    let watch_system = !args.contains(&"--no-network-watch".to_string());
+    tokio::spawn(run_network_watch(socket.clone(), keys.clone(), tunnel.clone(), params, handshake_rx, migrate_rx, watch_system));
+    let downlink_events = DownlinkEvents { pmtu_acks: pmtu_ack_tx, control: control_tx, handshake: handshake_tx, stun: stun_tx };
+
+    // 可选：经第二块网卡同时发送上行数据（--bond），服务端需要以 --bonding 启动
+    let bond = BondPath::from_args(args, tunnel.policy_routing.as_ref().map(|p| p.fwmark))?.map(Arc::new);
+    if let Some(bond) = &bond {
+        println!("🔀 多路径绑定: 第二条路径经 {}（{} 模式），等待服务端确认", bond.interface(), bond.mode().as_str());
+        bond.spawn(endpoint.clone(), keys.clone());
+    }
+
+    // 数据面汇总日志（代替逐包打印）
+    let datapath = Arc::new(DataPathLog::new());
+    datapath.spawn_reporter(datapath_log::report_interval_from_args(args));
+
+    // === 4. 转发核心：上行 TUN -> 加密 -> UDP，下行 UDP -> 解密 -> TUN ===
+    let handler = Arc::new(ClientHandler {
+        socket: socket.clone(),
+        endpoint,
+        keys,
+        datapath: datapath.clone(),
+        events: downlink_events,
+        firewall,
+        bond,
+        pacing,
+        fec: fec_link,
+        recursion_warned: AtomicBool::new(false),
+        reconnect: migrate_tx,
     });
+    // 启动完成，此后由 Ctrl+C / 服务端断开的处理流程负责清理
+    journal.commit();
+    TunnelEngine::new(Role::Client, handler, datapath, &tuning).run(dev, socket).await;
+    // 转发循环只会因为 TUN 设备或 socket 出错而结束：单隧道时和 panic 一样交给退出任务，多隧道时只结束这一条隧道
+    let what = "转发循环意外退出（TUN 设备或 UDP socket 已关闭）";
+    match end {
+        TunnelEnd::Process => crash::fail(what),
+        TunnelEnd::Supervised { .. } => {
+            eprintln!("❌ {}", what);
+            tunnel.exit(crash::CRASH_EXIT).await;
+        }
+    }
+    std::future::pending().await
+}
 
-    // === 6. 下行任务 (UDP -> Decrypt -> TUN) ===
-    let downlink_task = tokio::spawn(async move {
-        let mut buf = [0u8; 2048]; 
-        println!("⬇️ 下行任务启动...");
+/// 虚拟 IP 所在网段与本机其他接口的地址冲突时报错（--allow-subnet-overlap 跳过）
+fn check_subnet_conflict(args: &[String], tun_ip: &str, tun_mask: &str, device_options: &local_tun::DeviceOptions) -> Result<(), Box<dyn Error>> {
+    if !args.contains(&"--allow-subnet-overlap".to_string())
+        && let Some((iface, cidr)) = local_tun::find_subnet_conflict(tun_ip, tun_mask, device_options.name.as_deref())?
+    {
+        return Err(format!(
+            "❗ 虚拟 IP {} 所在网段与接口 {} 的地址 {} 冲突。\n\n\
+             同一主机上运行多个客户端时，请为每个实例使用不同的网段，\n\
+             或者使用 --route-table 隔离路由后加上 --allow-subnet-overlap 强制启动。",
+            tun_ip, iface, cidr
+        ).into());
+    }
+    Ok(())
+}
 
-        loop {
-            let (n, src_addr) = match socket_downlink.recv_from(&mut buf).await {
-                Ok(res) => res,
-                Err(_) => break,
-            };
+/// `--check-config`：解析（配置文件展开后的）全部参数但不启动，打印生效的参数
+fn check_config(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let positional = |i: usize| args.get(i).filter(|a| !a.starts_with("--")).cloned();
+    let virtual_ip = positional(1).or_else(|| arg_value(args, "--virtual-ip"));
+    if let Some(ip) = virtual_ip.as_ref().filter(|ip| *ip != AUTO_VIRTUAL_IP) {
+        ip.parse::<std::net::Ipv4Addr>().map_err(|_| format!("无效的虚拟 IP: {}", ip))?;
+    }
+    if let Some(list) = arg_value(args, "--dns") {
+        list.split(',').map(|s| s.trim().parse::<std::net::Ipv4Addr>()).collect::<Result<Vec<_>, _>>()?;
+    }
+    for cidr in arg_values(args, "--route") {
+        if !matches!(config::parse_cidr(&cidr), Some((IpAddr::V4(_), _))) {
+            return Err(format!("无效的 --route: {}（只支持 IPv4 网段）", cidr).into());
+        }
+    }
+    if let Some(key) = arg_value(args, "--server-key") {
+        ClientVerifier::from_hex(&key)?;
+    }
+    online::timeout_from_args(args)?;
+    client_hostname(args)?;
+    advertised_services(args)?;
+    for name in ["--rekey-interval", "--keepalive", "--route-metric", "--route-table"] {
+        if let Some(v) = arg_value(args, name) {
+            v.parse::<u64>().map_err(|_| format!("无效的 {}: {}", name, v))?;
+        }
+    }
+    let exposed = arg_values(args, "--expose").join(",");
+    if !exposed.is_empty() {
+        Allowlist::parse(&exposed)?;
+    }
+    Tuning::from_args(args)?;
+    bond::check_args(args)?;
+    if let Some(pace) = arg_value(args, "--pace") {
+        vpn_core::pacing::PacingMode::parse(&pace)?;
+    }
+    if let Some(k) = arg_value(args, "--fec") {
+        fec::parse_group_size(&k)?;
+    }
+    exits::from_args(args)?;
+    println!("✅ 配置有效，生效的参数: {}", args[1..].iter().filter(|a| *a != "--check-config").cloned().collect::<Vec<_>>().join(" "));
+    Ok(())
+}
+
+/// `--dry-run`：按参数列出连接成功后对系统的修改，不握手、不创建设备、不写入身份目录
+///
+/// 步骤与 main 中的顺序一致；设备名未指定时由系统分配，这里用 <tun> 代替
+async fn dry_run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut plan = Plan::new();
+    let positional = |i: usize| args.get(i).filter(|a| !a.starts_with("--")).cloned();
+    let mut tun_ip = positional(1).or_else(|| arg_value(args, "--virtual-ip")).unwrap_or_else(|| "10.0.0.1".to_string());
+    let server_addr = positional(1).and(positional(2)).or_else(|| arg_value(args, "--server"));
+    let server_addr = match server_addr {
+        Some(addr) => Some(addr),
+        None if args.contains(&"--discover".to_string()) => None,
+        None => Some("127.0.0.1:9000".to_string()),
+    };
+    let full_tunnel = args.contains(&"--full-tunnel".to_string());
+    let tun_mask = "255.255.255.0";
+    let dev_name = arg_value(args, "--tun-name").unwrap_or_else(|| "<tun>".to_string());
+    let device_options = local_tun::DeviceOptions {
+        name: arg_value(args, "--tun-name"),
+        reuse_existing: args.contains(&"--tun-reuse".to_string()),
+        offload: args.contains(&"--tun-offload".to_string()),
+    };
+    let route_options = local_tun::RouteOptions {
+        metric: arg_value(args, "--route-metric").map(|v| v.parse()).transpose()?,
+        table: arg_value(args, "--route-table").map(|v| v.parse()).transpose()?,
+    };
+    let tuning = Tuning::from_args(args)?;
+
+    // 自动分配的地址要握手后才知道，设备地址、VPN 网段路由和隧道 IPv6 地址用 <auto> 代替
+    let auto_ip = tun_ip == AUTO_VIRTUAL_IP;
+    if auto_ip {
+        plan.note(Category::Tun, "虚拟 IP 由服务端分配（--virtual-ip auto），下面的 <auto> 在握手后确定");
+        tun_ip = "<auto>".to_string();
+    }
+    local_tun::plan_device(&mut plan, &tun_ip, tun_mask, &device_options);
+
+    let policy_routing = cfg!(target_os = "linux") && full_tunnel && !args.contains(&"--no-policy-routing".to_string());
+    if policy_routing {
+        #[cfg(target_os = "linux")]
+        local_tun::plan_policy_routing(&mut plan, &dev_name, &local_tun::PolicyRouting {
+            fwmark: arg_value(args, "--fwmark").map(|v| local_tun::parse_fwmark(&v)).transpose()?.unwrap_or(local_tun::DEFAULT_FWMARK),
+            table: route_options.table.unwrap_or(local_tun::DEFAULT_POLICY_TABLE),
+        });
+    } else if full_tunnel {
+        // 服务器地址和默认网关都是只读查询
+        let mut exceptions = Vec::new();
+        match &server_addr {
+            Some(addr) => exceptions.push(ServerEndpoint::resolve(addr).await?.addr().ip().to_string()),
+            None => plan.note(Category::Route, "服务器地址由 mDNS 发现后确定，届时添加到服务器的路由例外"),
+        }
+        if let Some(host) = arg_value(args, "--stun") {
+            exceptions.push(endpoint::lookup(&host).await?.ip().to_string());
+        }
+        match netwatch::default_gateway() {
+            Some(gateway) => {
+                for ip in &exceptions {
+                    for command in server_route_commands(ip, &gateway) {
+                        plan.command(Category::Route, command[0], &command[1..]);
+                    }
+                }
+            }
+            None => plan.note(Category::Route, "未检测到默认网关，不添加服务器路由例外"),
+        }
+        local_tun::plan_route(&mut plan, &dev_name, "0.0.0.0/0", &route_options);
+    } else if auto_ip {
+        local_tun::plan_route(&mut plan, &dev_name, "<auto>/24", &route_options);
+    } else {
+        local_tun::plan_route(&mut plan, &dev_name, &local_tun::network_cidr(&tun_ip, tun_mask)?, &route_options);
+    }
+
+    if let Some(list) = arg_value(args, "--dns") {
+        let servers = list.split(',').map(|s| s.trim().parse()).collect::<Result<Vec<_>, _>>()?;
+        local_tun::plan_dns(&mut plan, &dev_name, &servers);
+    }
+    for cidr in arg_values(args, "--route") {
+        local_tun::plan_route(&mut plan, &dev_name, &cidr, &route_options);
+    }
+    if args.contains(&"--ipv6".to_string()) {
+        if auto_ip {
+            plan.note(Category::Tun, "隧道 IPv6 地址由分配的虚拟 IP 推出");
+        } else {
+            local_tun::plan_ipv6_address(&mut plan, &dev_name, local_tun::tunnel_ipv6(tun_ip.parse()?));
+        }
+        if full_tunnel {
+            local_tun::plan_ipv6_full_tunnel(&mut plan, &dev_name);
+        }
+    }
+    if let Some(mtu) = tuning.mtu {
+        local_tun::plan_mtu(&mut plan, &dev_name, mtu);
+    }
+    if args.contains(&"--pmtu-probe".to_string()) {
+        local_tun::plan_mtu(&mut plan, &dev_name, pmtu::INITIAL_MTU);
+        gateway::plan_mss_clamp(&mut plan, &dev_name, pmtu::INITIAL_MTU - 40);
+        plan.note(Category::Tun, "探测收敛后按结果调整 MTU 和 MSS 钳制");
+    }
+    plan.note(Category::Firewall, "入站防火墙（--expose）在进程内过滤，不修改系统防火墙");
+    plan.print();
+    let issues = preflight_checks(args).check();
+    if !issues.is_empty() {
+        println!();
+        preflight::print_issues(&issues);
+    }
+    Ok(())
+}
+
+/// 按参数确定启动需要的权限和外部命令
+fn preflight_checks(args: &[String]) -> Preflight {
+    let has = |flag: &str| args.iter().any(|a| a == flag);
+    let mut checks = Preflight::new(has("--tun-reuse")).require("ip", "配置地址和路由");
+    if has("--full-tunnel") && !has("--no-policy-routing") {
+        checks = checks.recommend("sysctl", "策略路由需要的 src_valid_mark");
+    }
+    if has("--dns") {
+        checks = checks.recommend("resolvectl", "为隧道设置 DNS");
+    }
+    if has("--pmtu-probe") {
+        checks = checks.recommend("iptables", "MSS 钳制");
+    }
+    checks
+}
+
+/// 客户端的转发逻辑：上行包全部发往服务器，下行包解密后分发
+struct ClientHandler {
+    socket: Arc<UdpSocket>,
+    endpoint: Arc<ServerEndpoint>,
+    keys: Arc<KeyRing>,
+    datapath: Arc<DataPathLog>,
+    events: DownlinkEvents,
+    /// 入站防火墙（--expose all 时为 None）
+    firewall: Option<Arc<std::sync::Mutex<InboundFirewall>>>,
+    /// 经第二块网卡的上行路径（--bond）
+    bond: Option<Arc<BondPath>>,
+    /// 上行限速（--pace）
+    pacing: Option<Arc<Pacing>>,
+    /// 前向纠错（--fec，握手时协商）
+    fec: Arc<FecLink>,
+    /// 是否已经提示过隧道递归（只提示一次，之后只计数）
+    recursion_warned: AtomicBool,
+    /// 看门狗重启转发任务后通知网络任务重新握手
+    reconnect: mpsc::UnboundedSender<Reconnect>,
+}
+
+impl PacketHandler for ClientHandler {
+    async fn on_tun_packet(&self, ip_packet: &[u8]) {
+        // 隧道自己发往服务器的包被路由进了 TUN：再加密发出只会一层层套娃，直接丢弃
+        let server_addr = self.endpoint.addr();
+        if recursion::targets_endpoint(ip_packet, server_addr) {
+            self.datapath.dropped(recursion::DROP_REASON);
+            if !self.recursion_warned.swap(true, Ordering::Relaxed) {
+                println!("⚠️ 发往服务器 {} 的隧道包被路由进了 TUN（服务器地址的路由例外缺失？），已丢弃", server_addr);
+            }
+            return;
+        }
+        if let Some(firewall) = &self.firewall {
+            firewall.lock().unwrap().record_outbound(ip_packet);
+        }
+        if let Some(pacing) = &self.pacing {
+            pacing.wait(ip_packet.len()).await;
+        }
+        // 第二条路径可用时上行不做 FEC 编码（duplicate 模式本身就是冗余发送）
+        match &self.bond {
+            Some(bond) if bond.is_active() => {
+                bond.send_uplink(&self.socket, self.endpoint.addr(), &self.keys, &self.datapath, ip_packet).await;
+            }
+            _ => send_uplink_packet(&self.socket, self.endpoint.addr(), &self.keys, &self.datapath, &self.fec, ip_packet).await,
+        }
+    }
+
+    async fn on_datagram(&self, data: &[u8], src_addr: SocketAddr) -> Option<Vec<u8>> {
+        self.decrypt_downlink_packet(data, src_addr)
+    }
+
+    fn queue_depths(&self) -> Vec<(&'static str, usize)> {
+        let events = &self.events;
+        vec![
+            ("control", queued(&events.control)),
+            ("handshake", queued(&events.handshake)),
+            ("pmtu", queued(&events.pmtu_acks)),
+            ("stun", queued(&events.stun)),
+        ]
+    }
+
+    fn on_stall(&self, task: &'static str) {
+        let _ = self.reconnect.send(Reconnect::Stalled(task));
+    }
+}
+
+/// 加密一个上行 IP 包并发送给服务器；启用 FEC 时发送编码后的数据包，凑满一组时紧跟着发出校验包
+async fn send_uplink_packet(socket: &UdpSocket, server_addr: SocketAddr, keys: &KeyRing, datapath: &DataPathLog, fec: &FecLink, ip_packet: &[u8]) {
+    // 打印 IP 包信息（仅 ICMP，trace 级别）
+    if datapath_log::trace_enabled() && ip_packet.len() >= 20 && ip_packet[0] >> 4 == 4 {
+        let proto = ip_packet[9];
+        if proto == 1 { // ICMP
+            let src = format!("{}.{}.{}.{}", ip_packet[12], ip_packet[13], ip_packet[14], ip_packet[15]);
+            let dst = format!("{}.{}.{}.{}", ip_packet[16], ip_packet[17], ip_packet[18], ip_packet[19]);
+            trace_packet!("📮 [发送] {} -> {} (ICMP)", src, dst);
+        }
+    }
+
+    // 加密
+    let frames = fec.encode(ip_packet);
+    let plaintext = frames.as_ref().map_or(ip_packet, |f| &f.data[..]);
+    let encrypted_packet = match keys.encrypt(plaintext) {
+        Ok(data) => data,
+        Err(e) => {
+            trace_packet!("❌ 加密失败: {}", e);
+            datapath.dropped("encrypt_failed");
+            return;
+        }
+    };
+
+    // 发送给 Server
+    match socket.send_to(&encrypted_packet, server_addr).await {
+        Ok(_) => datapath.forwarded("uplink", ip_packet.len()),
+        Err(e) => {
+            trace_packet!("❌ UDP 发送错误: {}", e);
+            datapath.dropped("udp_send_failed");
+        }
+    }
+    if let Some(parity) = frames.and_then(|f| f.parity)
+        && let Ok(encrypted) = keys.encrypt(&parity)
+    {
+        let _ = socket.send_to(&encrypted, server_addr).await;
+    }
+}
+
+impl ClientHandler {
+    /// 解密一个下行包，返回需要写入 TUN 的 IP 包
+    fn decrypt_downlink_packet(&self, data: &[u8], src_addr: SocketAddr) -> Option<Vec<u8>> {
+        let (keys, datapath, events) = (&self.keys, &self.datapath, &self.events);
+        trace_packet!("📦 收到 UDP 包: {} 字节，来自 {}", data.len(), src_addr);
+
+        // 按数据报头分派：STUN 响应和重新握手的响应交给对应的任务
+        let body = match wire::classify(data) {
+            Datagram::Data(body) => body,
+            Datagram::Stun => {
+                forward_event(&events.stun, (data.to_vec(), src_addr), datapath);
+                return None;
+            }
+            Datagram::Handshake => {
+                match deserialize_message(data) {
+                    Ok(msg) => forward_event(&events.handshake, msg, datapath),
+                    Err(_) => datapath.dropped("malformed_handshake"),
+                }
+                return None;
+            }
+            Datagram::Invalid(reason) => {
+                datapath.dropped(reason);
+                return None;
+            }
+        };
+
+        // 解密；同一个包被链路复制多次或被重放时只处理第一份
+        let decrypted = match keys.decrypt(body) {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                trace_packet!("♻️  丢弃重复包");
+                datapath.dropped("duplicate");
+                return None;
+            }
+            Err(e) => {
+                trace_packet!("❌ 解密失败: {}", e);
+                datapath.dropped("decrypt_failed");
+                return None;
+            }
+        };
+
+        // PMTU 探测确认和控制消息交给对应的任务，不写入 TUN
+        let decrypted_ip_packet = match control::classify(&decrypted) {
+            PayloadKind::Ip => decrypted,
+            // 前向纠错：数据包取出其中的 IP 包，校验包在组内恰好丢了一个包时恢复出这个包
+            PayloadKind::Fec => {
+                let recovered = decrypted[0] == fec::KIND_FEC_PARITY;
+                let inner = self.fec.decode(&decrypted)?;
+                if recovered {
+                    trace_packet!("🧩 FEC 恢复了一个丢失的包（{} 字节）", inner.len());
+                }
+                inner
+            }
+            PayloadKind::Pmtu => {
+                if let Ok(PmtuMessage::Ack { id, size }) = PmtuMessage::decode(&decrypted) {
+                    forward_event(&events.pmtu_acks, (id, size), datapath);
+                }
+                return None;
+            }
+            PayloadKind::Control => {
+                match ControlMessage::decode(&decrypted) {
+                    Ok(msg) => forward_event(&events.control, msg, datapath),
+                    Err(e) => {
+                        trace_packet!("❌ 控制消息解析失败: {}", e);
+                        datapath.dropped("malformed_control");
+                    }
+                }
+                return None;
+            }
+            // 服务端不会在下行方向使用带序号的多路径封装
+            PayloadKind::Bonded | PayloadKind::Unknown => {
+                datapath.dropped("unknown_payload");
+                return None;
+            }
+        };
+
+        // 不是本机发起的连接的回包，也不是开放的服务
+        if let Some(firewall) = &self.firewall
+            && !firewall.lock().unwrap().check_inbound(&decrypted_ip_packet).allowed()
+        {
+            trace_packet!("🧱 入站防火墙丢弃未经请求的包");
+            datapath.dropped("firewall");
+            return None;
+        }
+        datapath.forwarded("downlink", decrypted_ip_packet.len());
+
+        // === 日志: 打印 ICMP 信息（trace 级别） ===
+        if datapath_log::trace_enabled() && decrypted_ip_packet.len() >= 20 && decrypted_ip_packet[0] >> 4 == 4 {
+            let p = &decrypted_ip_packet;
+            let proto = p[9]; 
             
-            println!("📦 收到 UDP 包: {} 字节，来自 {}", n, src_addr);
-
-            // 解密
-            let decrypted_ip_packet = match cipher_downlink.decrypt(&buf[..n]) {
-                Ok(data) => data,
-                Err(e) => { 
-                    eprintln!("❌ 解密失败: {}", e); 
-                    continue; 
+            // 仅打印 ICMP (Ping) 包
+            if proto == 1 {
+                let src = format!("{}.{}.{}.{}", p[12], p[13], p[14], p[15]);
+                let dst = format!("{}.{}.{}.{}", p[16], p[17], p[18], p[19]);
+                trace_packet!("📨 [收到] {} -> {} (ICMP)", src, dst);
+            }
+        }
+
+        Some(decrypted_ip_packet)
+    }
+}
+
+/// 隧道内 PMTU 探测任务：二分查找可通过的最大包长，据此调整 TUN MTU 和 MSS，
+/// 收敛后周期性重新探测以发现路径变化
+async fn run_pmtu_probe(
+    socket: Arc<UdpSocket>,
+    endpoint: Arc<ServerEndpoint>,
+    keys: Arc<KeyRing>,
+    dev_name: String,
+    mut acks: mpsc::Receiver<(u32, u16)>,
+) {
+    let mut prober = PmtuProber::new();
+    let mut current_mtu = pmtu::INITIAL_MTU;
+
+    loop {
+        while let Some(probe) = prober.next_probe() {
+            let sent = match keys.encrypt(&probe.encode()) {
+                // 超过本地接口 MTU 时 send 直接返回 EMSGSIZE，按超时处理
+                Ok(encrypted) => socket.send_to(&encrypted, endpoint.addr()).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                prober.on_timeout();
+                continue;
+            }
+
+            let deadline = tokio::time::Instant::now() + pmtu::PROBE_TIMEOUT;
+            let acked = loop {
+                match tokio::time::timeout_at(deadline, acks.recv()).await {
+                    Ok(Some((id, size))) => if prober.on_ack(id, size) { break true },
+                    Ok(None) => return,
+                    Err(_) => break false,
                 }
             };
+            if !acked {
+                prober.on_timeout();
+            }
+        }
 
-            // === 日志: 打印 ICMP 信息 ===
-            if decrypted_ip_packet.len() >= 20 {
-                let p = &decrypted_ip_packet;
-                let proto = p[9]; 
-                
-                // 仅打印 ICMP (Ping) 包
-                if proto == 1 {
-                    let src = format!("{}.{}.{}.{}", p[12], p[13], p[14], p[15]);
-                    let dst = format!("{}.{}.{}.{}", p[16], p[17], p[18], p[19]);
-                    println!("📨 [收到] {} -> {} (ICMP)", src, dst);
+        let mtu = prober.result();
+        if mtu != current_mtu {
+            println!("📏 路径 MTU 变化: {} -> {}", current_mtu, mtu);
+            if let Err(e) = local_tun::set_mtu(&dev_name, mtu)
+                .and_then(|_| gateway::set_mss_clamp(&dev_name, mtu - 40))
+            {
+                eprintln!("⚠️ 调整 MTU 失败: {}", e);
+            } else {
+                current_mtu = mtu;
+            }
+        }
+
+        tokio::time::sleep(pmtu::REPROBE_INTERVAL).await;
+        prober.restart();
+    }
+}
+
+/// 隧道运行期间各任务共享的本地配置，用于退出清理和应用服务端下发的路由
+struct TunnelContext {
+    dev_name: String,
+    full_tunnel: bool,
+    pmtu_probe: bool,
+    route_options: local_tun::RouteOptions,
+    /// 隧道路由（全隧道时为 0.0.0.0/0），网络切换后重新应用
+    target_cidr: String,
+    /// Linux 全隧道使用的策略路由（为 None 时替换默认路由）
+    policy_routing: Option<local_tun::PolicyRouting>,
+    /// 链路中断时退出（交给 systemd 等重启并切换服务器）
+    exit_on_link_down: bool,
+    /// 是否设置了 --dns，退出时需要撤销
+    custom_dns: bool,
+    /// 是否添加了 IPv6 全隧道路由，退出时需要删除
+    ipv6_full_tunnel: bool,
+    /// 清理完成后退出进程，或结束多隧道模式中的这一条隧道
+    end: TunnelEnd,
+}
+
+impl TunnelContext {
+    /// 恢复网络配置并结束隧道
+    async fn shutdown(&self) -> ! {
+        self.exit(0).await
+    }
+
+    /// 恢复网络配置并以指定的退出码结束隧道（单隧道模式下退出进程）
+    async fn exit(&self, code: i32) -> ! {
+        println!("🧹 正在恢复网络...");
+        #[cfg(target_os = "linux")]
+        if let Some(policy) = &self.policy_routing {
+            local_tun::disable_policy_routing(policy);
+            println!("   ✅ 策略路由已移除");
+        }
+        if self.full_tunnel && self.policy_routing.is_none() {
+            #[cfg(not(target_os = "linux"))]
+            let _ = local_tun::remove_route(&self.dev_name, "0.0.0.0/0");
+            restore_default_gateway().await;
+        }
+        if self.ipv6_full_tunnel {
+            local_tun::remove_ipv6_full_tunnel(&self.dev_name);
+        }
+        if self.custom_dns {
+            let _ = local_tun::clear_dns(&self.dev_name);
+        }
+        if self.pmtu_probe {
+            gateway::clear_mss_clamp(&self.dev_name);
+        }
+        self.end.finish(code).await
+    }
+}
+
+/// 隧道如何结束
+#[derive(Clone)]
+enum TunnelEnd {
+    /// 单隧道模式：Ctrl+C 时断开，结束时退出进程
+    Process,
+    /// 多隧道模式中的一条隧道：监督任务把 stop 置为 true 时断开，结束时把退出码发给监督任务，进程继续运行
+    Supervised { stop: watch::Receiver<bool>, code: mpsc::UnboundedSender<i32> },
+}
+
+impl TunnelEnd {
+    /// 等待退出信号
+    async fn stopped(&self) {
+        match self {
+            TunnelEnd::Process => {
+                let _ = tokio::signal::ctrl_c().await;
+                println!("\n\n🛑 收到退出信号，正在断开...");
+            }
+            TunnelEnd::Supervised { stop, .. } => {
+                let _ = stop.clone().wait_for(|stop| *stop).await;
+            }
+        }
+    }
+
+    /// 结束隧道：退出进程，或交出退出码后停在这里（监督任务随后关闭这条隧道的运行时）
+    async fn finish(&self, code: i32) -> ! {
+        match self {
+            TunnelEnd::Process => std::process::exit(code),
+            TunnelEnd::Supervised { code: done, .. } => {
+                let _ = done.send(code);
+                loop {
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    }
+
+    /// 等待退出清理完成后结束；清理 panic 或超时同样结束（见 crash::finish）
+    async fn finish_after<T>(&self, cleanup: tokio::task::JoinHandle<T>, code: i32) -> ! {
+        match self {
+            TunnelEnd::Process => crash::finish(cleanup, code).await,
+            TunnelEnd::Supervised { .. } => {
+                if !matches!(tokio::time::timeout(crash::CLEANUP_TIMEOUT, cleanup).await, Ok(Ok(_))) {
+                    eprintln!("⚠️  隧道退出清理失败或超时，部分网络配置可能需要手动恢复");
                 }
+                self.finish(code).await
             }
+        }
+    }
+}
 
-            // 适配 macOS/Linux 头部差异
-            #[cfg(target_os = "macos")]
-            let data_to_write = {
-                // macOS utun 需要 4 字节协议头
-                // AF_INET (2) 的网络字节序 (大端)
-                let mut out = Vec::with_capacity(4 + decrypted_ip_packet.len());
-                out.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]); // AF_INET = 2
-                out.extend_from_slice(&decrypted_ip_packet);
-                out
-            };
+/// 下行任务分发给其他任务的消息队列深度
+///
+/// 这些消息都来自网络：接收方处理不过来（或暂时不读，例如没有在重新握手时的握手消息）时丢弃新消息，
+/// 计入 event_queue_full，不让伪造或重复的包在内存里无限堆积
+const EVENT_QUEUE_DEPTH: usize = 64;
+
+/// 下行任务分发给其他任务的隧道内消息
+struct DownlinkEvents {
+    pmtu_acks: mpsc::Sender<(u32, u16)>,
+    control: mpsc::Sender<ControlMessage>,
+    /// 重新握手期间服务端的 ServerHello / ServerFinish
+    handshake: mpsc::Sender<HandshakeMessage>,
+    /// STUN 服务器的 Binding 响应（--stun）
+    stun: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+}
+
+/// 队列里还没被取走的消息数
+fn queued<T>(queue: &mpsc::Sender<T>) -> usize {
+    queue.max_capacity() - queue.capacity()
+}
+
+/// 把下行消息交给对应的任务；队列已满时丢弃（接收方已退出时同样丢弃，不计数）
+fn forward_event<T>(queue: &mpsc::Sender<T>, msg: T, datapath: &DataPathLog) {
+    if let Err(mpsc::error::TrySendError::Full(_)) = queue.try_send(msg) {
+        datapath.dropped("event_queue_full");
+    }
+}
+
+/// 重新握手所需的参数
+struct HandshakeParams {
+    endpoint: Arc<ServerEndpoint>,
+    identity: Arc<ClientIdentity>,
+    virtual_ip: String,
+    /// 重新握手时重新协商 FEC，编解码状态随之重置
+    fec: Arc<FecLink>,
+    credential: Option<AuthCredential>,
+    telemetry: Telemetry,
+    /// 等待握手响应的超时（--handshake-timeout）
+    timeout: Duration,
+    /// 重新握手的最大尝试次数（--handshake-retries）
+    retries: u32,
+    /// 会话恢复缓存（重新握手后旧票据失效）
+    resume: Option<Arc<ResumeState>>,
+    /// 服务端签名连续验证失败的计数（--max-signature-failures）
+    signature: Arc<SignatureGuard>,
+    /// 本端的功能标志
+    capabilities: Capabilities,
+    /// PSK 文件（--psk-file）
+    psk_file: Option<std::path::PathBuf>,
+    /// 固定的服务端公钥（--server-key）
+    server_key: Option<String>,
+}
 
-            #[cfg(target_os = "linux")]
-            let data_to_write = decrypted_ip_packet;
+/// 在隧道运行期间重新握手（和认证），返回新的会话密钥和服务端接受的 FEC 分组大小
+async fn rehandshake(
+    socket: &UdpSocket,
+    params: &HandshakeParams,
+    handshake_rx: &mut mpsc::Receiver<HandshakeMessage>,
+) -> Result<([u8; 32], Option<u8>), Box<dyn Error>> {
+    // 丢弃之前残留的握手消息
+    while handshake_rx.try_recv().is_ok() {}
+    let mut rx = HandshakeRx::Channel(handshake_rx);
+    
+    let hello = HelloOptions {
+        virtual_ip: params.virtual_ip.clone(),
+        fec: params.fec.requested(),
+        capabilities: params.capabilities,
+        psk_file: params.psk_file.clone(),
+        server_key: params.server_key.clone(),
+    };
+    let Handshake { session_key, fec, .. } = perform_handshake(
+        socket,
+        params.endpoint.addr(),
+        &params.identity,
+        hello,
+        &params.telemetry,
+        &mut rx,
+        params.timeout,
+    ).await?;
+    if let Some(cred) = &params.credential {
+        authenticate(socket, params.endpoint.addr(), &session_key, cred, &mut rx, params.timeout).await?;
+    }
+    Ok((session_key, fec))
+}
 
-            // 写入 TUN
-            if let Err(e) = tun_writer.write_all(&data_to_write).await {
-                eprintln!("❌ TUN 写入错误: {}", e);
-                break;
+/// 网络任务之外发起的重新握手
+enum Reconnect {
+    /// 控制任务发现服务器地址变化
+    Migrate(SocketAddr),
+    /// 看门狗重启了卡住的转发任务（上行 / 下行）
+    Stalled(&'static str),
+}
+
+/// 网络变化任务：休眠唤醒、默认网关变化、服务器地址变化或转发任务卡住后，更新服务器路由例外、重新应用隧道路由并重新握手
+///
+/// 休眠期间服务端可能已经清理了会话，NAT 映射和出口地址也可能变化，
+/// 等待保活超时再恢复太慢，这里直接重新握手。
+/// watch_system 为 false（--no-network-watch）时只处理控制任务和看门狗发起的重新握手
+async fn run_network_watch(
+    socket: Arc<UdpSocket>,
+    keys: Arc<KeyRing>,
+    tunnel: Arc<TunnelContext>,
+    params: HandshakeParams,
+    mut handshake_rx: mpsc::Receiver<HandshakeMessage>,
+    mut reconnects: mpsc::UnboundedReceiver<Reconnect>,
+    watch_system: bool,
+) {
+    // 不监听系统事件时保留发送端，避免通道关闭导致任务退出
+    let (_idle, idle_rx) = mpsc::unbounded_channel();
+    let mut events = if watch_system { netwatch::spawn_watcher() } else { idle_rx };
+    
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else { return };
+                match event {
+                    NetworkEvent::Wake { slept } => {
+                        println!("💤 系统从休眠中唤醒（约 {} 秒），重新握手...", slept.as_secs());
+                    }
+                    NetworkEvent::DefaultRouteChanged { gateway } => {
+                        println!("🔀 默认网关变化: {}，重新应用路由并重新握手...", gateway.as_deref().unwrap_or("无"));
+                        let Some(gateway) = gateway else { continue };
+                        // 策略路由下隧道的包按 fwmark 走主表，不需要例外路由
+                        if tunnel.full_tunnel && tunnel.policy_routing.is_none() {
+                            add_server_route_exception(&params.endpoint.addr().ip().to_string(), &gateway);
+                            *ORIGINAL_GATEWAY.lock().await = Some(gateway);
+                        }
+                        // 路由已存在时会失败，忽略
+                        if tunnel.policy_routing.is_none() {
+                            let _ = local_tun::configure_route_with(&tunnel.dev_name, &tunnel.target_cidr, &tunnel.route_options);
+                        }
+                    }
+                    // spawn_watcher 不监听出口网卡，网关变化已覆盖这种情况
+                    NetworkEvent::DefaultInterfaceChanged { .. } => continue,
+                }
+            }
+            reconnect = reconnects.recv() => {
+                let Some(reconnect) = reconnect else { return };
+                match reconnect {
+                    Reconnect::Migrate(addr) => {
+                        println!("🔀 服务器 {} 的地址变为 {}，迁移并重新握手...", params.endpoint.host(), addr);
+                        // 新地址同样需要绕过隧道
+                        if tunnel.full_tunnel && tunnel.policy_routing.is_none() {
+                            let gateway = ORIGINAL_GATEWAY.lock().await.clone();
+                            if let Some(gateway) = gateway {
+                                add_server_route_exception(&addr.ip().to_string(), &gateway);
+                            }
+                        }
+                    }
+                    // 卡住的原因可能在 socket 或会话上（例如网络切换后发送一直阻塞），重新握手一次
+                    Reconnect::Stalled(task) => println!("🐕 {}任务已重启，重新握手...", task),
+                }
             }
         }
-    });
+        
+        for attempt in 1..=params.retries {
+            let error = match rehandshake(&socket, &params, &mut handshake_rx).await {
+                Ok((session_key, fec)) => match keys.replace(session_key) {
+                    Ok(_) => {
+                        params.fec.apply(fec);
+                        // 新会话的票据稍后由服务端下发
+                        if let Some(state) = &params.resume {
+                            state.clear();
+                        }
+                        params.signature.reset();
+                        println!("🔐 重新握手成功，隧道已恢复");
+                        break;
+                    }
+                    Err(e) => e.to_string(),
+                },
+                Err(e) => {
+                    if let Some(error) = e.downcast_ref::<SignatureError>() {
+                        params.signature.record_failure(error);
+                    }
+                    e.to_string()
+                }
+            };
+            // 签名验证失败不是网络问题，重试不会好转：达到上限后停止，不再把 ClientHello 交给对方
+            if params.signature.tripped() {
+                params.signature.alert(params.endpoint.host());
+                match params.signature.policy() {
+                    OnSignatureFailure::Exit => tunnel.exit(sigguard::SIGNATURE_FAILURE_EXIT).await,
+                    OnSignatureFailure::Hold => return,
+                }
+            }
+            eprintln!("⚠️ 重新握手失败 ({}/{}): {}", attempt, params.retries, error);
+            tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+        }
+    }
+}
 
-    let _ = tokio::join!(uplink_task, downlink_task);
-    Ok(())
-}
\ No newline at end of file
+/// 加密并发送一条控制消息
+async fn send_control(socket: &UdpSocket, server_addr: SocketAddr, keys: &KeyRing, msg: &ControlMessage) {
+    let encrypted = msg.encode().and_then(|plaintext| keys.encrypt(&plaintext));
+    match encrypted {
+        Ok(data) => {
+            if let Err(e) = socket.send_to(&data, server_addr).await {
+                eprintln!("❌ 控制消息发送失败: {}", e);
+            }
+        }
+        Err(e) => eprintln!("❌ 控制消息编码失败: {}", e),
+    }
+}
+
+/// 向服务端登记的服务（--advertise，可重复）
+fn advertised_services(args: &[String]) -> Result<Vec<control::Service>, Box<dyn Error>> {
+    let services = arg_values(args, "--advertise").iter().map(|spec| control::Service::parse(spec)).collect::<Result<Vec<_>, _>>()?;
+    if services.len() > control::MAX_SERVICES {
+        return Err(format!("--advertise 最多 {} 个", control::MAX_SERVICES).into());
+    }
+    Ok(services)
+}
+
+/// 上报给服务端的主机名：`--name` 必须是合法的 DNS 标签；未指定时取本机主机名（无法转换时不上报）
+fn client_hostname(args: &[String]) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(name) = arg_value(args, "--name") {
+        if !control::is_valid_hostname(&name) {
+            return Err(format!("无效的 --name: {}（1~63 个小写字母、数字或 -）", name).into());
+        }
+        return Ok(Some(name));
+    }
+    let output = std::process::Command::new("hostname").output().ok();
+    Ok(output.and_then(|o| control::normalize_hostname(&String::from_utf8_lossy(&o.stdout))))
+}
+
+/// --report-metadata 上报的设备信息：本机主机名（原样）、操作系统和架构、客户端版本
+fn client_metadata() -> control::ClientMetadata {
+    let hostname = std::process::Command::new("hostname").output().map(|o| String::from_utf8_lossy(&o.stdout).into_owned()).unwrap_or_default();
+    let mut os = format!("{} {}", std::env::consts::OS, std::env::consts::ARCH);
+    // Linux 发行版名称，例如 "Ubuntu 24.04 LTS"
+    if let Some(name) = std::fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+        release.lines().find_map(|line| line.strip_prefix("PRETTY_NAME=").map(|v| v.trim_matches('"').to_string()))
+    }) {
+        os = format!("{} ({})", os, name);
+    }
+    control::ClientMetadata {
+        hostname: metadata_field(&hostname),
+        os: metadata_field(&os),
+        version: metadata_field(env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// 去掉控制字符和首尾空白，截断到 MAX_METADATA_LEN 字节（不切断字符）
+fn metadata_field(value: &str) -> String {
+    let mut field = String::new();
+    for c in value.trim().chars().filter(|c| !c.is_control()) {
+        if field.len() + c.len_utf8() > control::MAX_METADATA_LEN {
+            break;
+        }
+        field.push(c);
+    }
+    field
+}
+
+/// 链路中断期间重新解析服务器域名的最小间隔
+const RERESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// 控制任务使用的共享资源
+struct ControlTask {
+    socket: Arc<UdpSocket>,
+    endpoint: Arc<ServerEndpoint>,
+    keys: Arc<KeyRing>,
+    tunnel: Arc<TunnelContext>,
+    /// 服务器地址变化时通知网络任务重新握手
+    migrations: mpsc::UnboundedSender<Reconnect>,
+    /// 公网映射地址与 NAT 类型检测
+    nat: NatProbe,
+    /// 会话恢复缓存（--no-session-resume 时为 None）
+    resume: Option<Arc<ResumeState>>,
+    /// 上行限速（自动模式的探测回复交给它）
+    pacing: Option<Arc<Pacing>>,
+    /// 前向纠错（状态输出）
+    fec: Arc<FecLink>,
+    /// 链路正常时的 Echo 周期（--keepalive）
+    keepalive: Duration,
+    /// 上报给服务端的主机名（--name）
+    hostname: Option<String>,
+    /// 上报给服务端的设备信息（--report-metadata，默认不上报）
+    metadata: Option<control::ClientMetadata>,
+    /// 向服务端登记的服务（--advertise）
+    services: Vec<control::Service>,
+    /// 入站防火墙（对端下线时清理与它的连接）
+    firewall: Option<Arc<std::sync::Mutex<InboundFirewall>>>,
+    /// 等待服务端回复的在线对端查询
+    roster: Arc<Roster>,
+    /// PSK 文件（--psk-file），收到 PskUpdated 时检查是否已换上新的 PSK
+    psk_file: Option<std::path::PathBuf>,
+}
+
+/// 控制通道任务：周期性 Echo（保活 + RTT 测量）和密钥轮换，处理服务端发来的控制消息
+async fn run_control(
+    task: ControlTask,
+    mut messages: mpsc::Receiver<ControlMessage>,
+    mut stun_responses: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    rekey_interval: Duration,
+) {
+    let ControlTask { socket, endpoint, keys, tunnel, migrations, mut nat, resume, pacing, fec, keepalive, hostname, metadata, services, firewall, roster, psk_file } = task;
+    let save_resume = || {
+        if let Some(state) = &resume {
+            state.save(endpoint.addr(), keys.current_key());
+        }
+    };
+    // 隧道建立后马上发一次 Echo，服务端据此下发路由
+    let mut rtt = RttEstimator::new();
+    let mut next_echo = tokio::time::Instant::now();
+    let mut echo_id: u32 = 0;
+    let mut last_health = LinkHealth::Up;
+    let mut next_resolve = tokio::time::Instant::now();
+    let mut rekey = tokio::time::interval_at(tokio::time::Instant::now() + rekey_interval, rekey_interval);
+    let mut status = tokio::time::interval_at(tokio::time::Instant::now() + control::STATUS_INTERVAL, control::STATUS_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_echo) => {
+                // 链路状态变化：有未回复的 Echo 时按 RTO 加快探测，连续无回复判定中断
+                let health = rtt.health();
+                if health != last_health {
+                    println!("📶 链路状态: {:?} -> {:?}", last_health, health);
+                    last_health = health;
+                    if health == LinkHealth::Down {
+                        eprintln!("⚠️ 服务端连续 {} 次无响应，链路中断", control::MAX_MISSED_ECHOES);
+                        if tunnel.exit_on_link_down {
+                            tunnel.shutdown().await;
+                        }
+                    }
+                }
+
+                // 服务器以域名给出时，中断期间定期重新解析（DDNS 记录可能已经更新）
+                if health == LinkHealth::Down && endpoint.is_hostname() && tokio::time::Instant::now() >= next_resolve {
+                    next_resolve = tokio::time::Instant::now() + RERESOLVE_INTERVAL;
+                    match endpoint.refresh().await {
+                        Ok(Some(addr)) => { let _ = migrations.send(Reconnect::Migrate(addr)); }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("⚠️ 重新解析服务器地址失败: {}", e),
+                    }
+                }
+
+                rtt.on_echo_sent();
+                let echo = ControlMessage::Echo { id: echo_id, timestamp_us: control::monotonic_micros() };
+                echo_id = echo_id.wrapping_add(1);
+                send_control(&socket, endpoint.addr(), &keys, &echo).await;
+                next_echo = tokio::time::Instant::now() + rtt.next_echo_interval(keepalive);
+            }
+            _ = status.tick() => {
+                println!("📶 链路状态: {}", rtt.summary());
+                if let Some(pacing) = &pacing {
+                    println!("🐢 上行限速: {}", pacing.summary());
+                }
+                if let Some(k) = fec.group_size() {
+                    println!("🧩 FEC 分组 {}，已恢复 {} 个下行包", k, fec.recovered());
+                }
+                // 链路正常时刷新会话恢复缓存的有效期
+                if last_health == LinkHealth::Up {
+                    save_resume();
+                }
+            }
+            Some((data, src)) = stun_responses.recv() => {
+                if let Some((mapped, mapping)) = nat.on_stun_response(&data, src) {
+                    println!("🌐 STUN 服务器看到的地址: {}", mapped);
+                    if let Some(mapping) = mapping {
+                        println!("   NAT 类型: {}", mapping.describe());
+                    }
+                }
+            }
+            _ = rekey.tick() => {
+                println!("🔄 发起密钥轮换...");
+                let request = keys.begin_rekey();
+                send_control(&socket, endpoint.addr(), &keys, &request).await;
+            }
+            msg = messages.recv() => {
+                let Some(msg) = msg else { return };
+                match msg {
+                    ControlMessage::Keepalive => {}
+                    ControlMessage::Echo { id, timestamp_us } => {
+                        send_control(&socket, endpoint.addr(), &keys, &ControlMessage::EchoReply { id, timestamp_us }).await;
+                    }
+                    ControlMessage::EchoReply { id, timestamp_us } => {
+                        // 限速探测的回复同样说明链路正常
+                        if let Some(pacing) = &pacing {
+                            pacing.on_echo_reply(id, timestamp_us);
+                        }
+                        rtt.on_echo_reply(timestamp_us);
+                        if last_health != LinkHealth::Up {
+                            println!("📶 链路恢复: {}", rtt.summary());
+                            last_health = LinkHealth::Up;
+                        }
+                        next_echo = tokio::time::Instant::now() + rtt.next_echo_interval(keepalive);
+                    }
+                    ControlMessage::RoutePush { routes } => {
+                        for cidr in routes {
+                            match local_tun::configure_route_with(&tunnel.dev_name, &cidr, &tunnel.route_options) {
+                                Ok(_) => println!("🧭 已应用服务端下发的路由: {}", cidr),
+                                Err(e) => eprintln!("⚠️ 下发路由 {} 配置失败: {}", cidr, e),
+                            }
+                        }
+                    }
+                    ControlMessage::RekeyResponse { public_key } => match keys.complete_rekey(public_key) {
+                        Ok(_) => {
+                            println!("🔑 会话密钥已轮换");
+                            keylog::record(KeyEvent::Rekey, endpoint.addr(), &keys.current_key());
+                            save_resume();
+                        }
+                        Err(e) => eprintln!("⚠️ 密钥轮换失败: {}", e),
+                    },
+                    ControlMessage::Disconnect { reason } => {
+                        println!("\n👋 服务端断开连接: {}", reason);
+                        if let Some(state) = &resume {
+                            state.clear();
+                        }
+                        tunnel.shutdown().await;
+                    }
+                    ControlMessage::SessionTicket { id, lifetime_secs } => {
+                        if let Some(state) = &resume {
+                            state.set_ticket(id, lifetime_secs);
+                            save_resume();
+                            println!("🎫 已保存会话恢复票据（重启后 {} 秒内可恢复）", lifetime_secs);
+                        }
+                    }
+                    // 每次（重新）握手后服务端都会下发一次，NAT 映射可能已经变化
+                    ControlMessage::ObservedAddr { addr } => {
+                        println!("🌐 公网地址（服务端所见）: {}", addr);
+                        let (mapping, request) = nat.on_observed(endpoint.addr(), addr);
+                        if let Some(mapping) = mapping {
+                            println!("   NAT 类型: {}", mapping.describe());
+                        }
+                        if let Some((stun_server, request)) = request
+                            && let Err(e) = socket.send_to(&request, stun_server).await
+                        {
+                            eprintln!("⚠️ STUN 请求发送失败: {}", e);
+                        }
+                        // 新会话没有主机名，每次（重新）握手后上报一次
+                        if let Some(name) = &hostname {
+                            send_control(&socket, endpoint.addr(), &keys, &ControlMessage::Hostname { name: name.clone() }).await;
+                        }
+                        if let Some(client) = &metadata {
+                            send_control(&socket, endpoint.addr(), &keys, &ControlMessage::Metadata { client: client.clone() }).await;
+                        }
+                        if !services.is_empty() {
+                            send_control(&socket, endpoint.addr(), &keys, &ControlMessage::ServiceRegister { services: services.clone() }).await;
+                        }
+                    }
+                    // 最近通信过的对端上下线，"👥" 行的格式固定，供外部工具解析
+                    ControlMessage::PeerStatus { peer, online } => {
+                        if online {
+                            println!("👥 对端 {} 重新上线", peer);
+                        } else {
+                            let cleared = firewall.as_ref().map_or(0, |fw| {
+                                let mut fw = fw.lock().unwrap();
+                                fw.forget_peer(IpAddr::V4(peer)) + fw.forget_peer(IpAddr::V6(local_tun::tunnel_ipv6(peer)))
+                            });
+                            println!("👥 对端 {} 已离线（清理 {} 条连接）", peer, cleared);
+                        }
+                    }
+                    ControlMessage::PeerList { id, .. } | ControlMessage::ServiceList { id, .. } => roster.complete(id, msg),
+                    // 本次会话用的是服务端轮换前的旧 PSK：会话不受影响，但宽限期过后无法再用旧 PSK 握手
+                    ControlMessage::PskUpdated { new_id, grace_secs } => {
+                        let installed = psk_file.as_deref().and_then(|path| psk::load(path).ok()).is_some_and(|p| psk::psk_id(&p) == new_id);
+                        if installed {
+                            println!("🔁 服务端已更换 PSK，--psk-file 中已是新的 PSK，下次握手时使用");
+                        } else {
+                            eprintln!("⚠️  服务端已更换 PSK（新 PSK 的 ID {}），当前的 PSK 在 {} 小时后失效", hex::encode(new_id), grace_secs / 3600);
+                            eprintln!("   把新的 PSK 文件复制到 --psk-file 指定的位置（不需要重启客户端）");
+                        }
+                    }
+                    // 只应由客户端发出
+                    ControlMessage::RekeyRequest { .. }
+                    | ControlMessage::Hostname { .. }
+                    | ControlMessage::Metadata { .. }
+                    | ControlMessage::PeersRequest { .. }
+                    | ControlMessage::ServiceRegister { .. }
+                    | ControlMessage::ServicesRequest { .. } => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// vpn_client/src/nat.rs
+// 公网映射地址与 NAT 类型检测
+//
+// 服务端在会话建立后下发它看到的来源地址（ControlMessage::ObservedAddr）。
+// 配置了 --stun 时，再用隧道的同一个 socket 向 STUN 服务器发一次 Binding 请求，
+// 比较两个目的地看到的映射判断是否为对称型 NAT。STUN 响应由下行任务转交（见 DownlinkEvents）。
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use vpn_core::stun::{self, NatMapping, TransactionId};
+
+/// NAT 探测状态（由控制任务持有）
+pub struct NatProbe {
+    /// STUN 服务器（--stun）
+    stun_server: Option<SocketAddr>,
+    /// 隧道 socket 的本地端口
+    local_port: u16,
+    /// Linux 策略路由的 fwmark，查询本机出口地址时需要同样打上
+    fwmark: Option<u32>,
+    local: Option<SocketAddr>,
+    observed: Option<SocketAddr>,
+    pending: Option<TransactionId>,
+}
+
+impl NatProbe {
+    pub fn new(stun_server: Option<SocketAddr>, local_port: u16, fwmark: Option<u32>) -> Self {
+        Self { stun_server, local_port, fwmark, local: None, observed: None, pending: None }
+    }
+
+    /// 收到服务端下发的映射地址：配置了 STUN 时返回需要发送的 Binding 请求，否则直接给出判断
+    pub fn on_observed(&mut self, server: SocketAddr, observed: SocketAddr) -> (Option<NatMapping>, Option<(SocketAddr, Vec<u8>)>) {
+        self.observed = Some(observed);
+        self.local = local_endpoint(server, self.local_port, self.fwmark).ok();
+        if let Some(stun_server) = self.stun_server {
+            let (id, request) = stun::binding_request();
+            self.pending = Some(id);
+            return (None, Some((stun_server, request)));
+        }
+        (self.local.map(|local| NatMapping::classify(local, observed, None)), None)
+    }
+
+    /// 收到 STUN 响应：事务 ID 匹配时返回 STUN 看到的地址和判断结果
+    pub fn on_stun_response(&mut self, data: &[u8], src: SocketAddr) -> Option<(SocketAddr, Option<NatMapping>)> {
+        let (id, mapped) = stun::parse_binding_response(data)?;
+        if Some(src) != self.stun_server || self.pending != Some(id) {
+            return None;
+        }
+        self.pending = None;
+        let mapping = match (self.local, self.observed) {
+            (Some(local), Some(observed)) => Some(NatMapping::classify(local, observed, Some(mapped))),
+            _ => None,
+        };
+        Some((mapped, mapping))
+    }
+}
+
+/// 本机到服务器的出口地址（不发包，只让内核选路）
+fn local_endpoint(server: SocketAddr, local_port: u16, fwmark: Option<u32>) -> Result<SocketAddr> {
+    let probe = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    // 策略路由下不带 fwmark 的包会走隧道，得到的是隧道地址
+    #[cfg(target_os = "linux")]
+    if let Some(mark) = fwmark {
+        vpn_core::local_tun::set_fwmark(&probe, mark)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = fwmark;
+    probe.connect(server)?;
+    Ok(SocketAddr::new(probe.local_addr()?.ip(), local_port))
+}
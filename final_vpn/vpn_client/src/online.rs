@@ -0,0 +1,121 @@
+// vpn_client/src/online.rs
+// 启动时等待网络就绪（--wait-online）
+//
+// 开机自启时客户端经常比网络先起来：DHCP 还没拿到地址、默认路由还没有、DNS 解析不了服务器域名，
+// 第一次握手失败后进程就退出了。systemd 的 network-online.target 在不少发行版上并不可靠
+// （NetworkManager / networkd 的 wait-online 服务没有启用，或只等到了某一块网卡），所以由客户端自己等：
+//
+// * 有默认路由（见 netwatch::default_gateway）
+// * 服务器以域名给出时，能解析出 IPv4 地址
+//
+// 两项都满足后继续启动；超时（--wait-online-timeout，默认 120 秒）后打印警告并照常继续，
+// 由握手给出真正的失败原因（例如服务器只通过非默认路由可达时，不会因为等待而启动失败）。
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::time::Instant;
+use vpn_core::netwatch;
+
+use crate::endpoint;
+
+/// 默认最多等待的时间
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+/// 两次检查之间的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// 单次域名解析最多等待的时间（DNS 服务器不可达时解析可能卡住很久）
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `--wait-online-timeout <秒>`
+pub fn timeout_from_args(args: &[String]) -> Result<Duration> {
+    match crate::arg_value(args, "--wait-online-timeout") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+            _ => Err(anyhow!("无效的 --wait-online-timeout: {}（正整数，单位秒）", v)),
+        },
+        None => Ok(DEFAULT_TIMEOUT),
+    }
+}
+
+/// 给出 --wait-online 时，等待默认路由和服务器域名解析就绪；servers 为要连接的 host:port
+pub async fn wait_from_args(args: &[String], servers: &[String]) -> Result<()> {
+    if !args.contains(&"--wait-online".to_string()) {
+        return Ok(());
+    }
+    let timeout = timeout_from_args(args)?;
+    println!("⏳ 等待网络就绪（最多 {} 秒）", timeout.as_secs());
+    let started = Instant::now();
+    match wait_until(timeout, POLL_INTERVAL, || check(servers)).await {
+        Ok(()) => println!("   ✅ 网络已就绪（等待 {} 秒）", started.elapsed().as_secs()),
+        Err(reason) => eprintln!("⚠️ {} 秒内网络没有就绪（{}），继续启动", timeout.as_secs(), reason),
+    }
+    Ok(())
+}
+
+/// 反复调用 check 直到成功或超时；超时时返回最后一次的原因。原因变化时打印
+async fn wait_until<F, Fut>(timeout: Duration, interval: Duration, mut check: F) -> Result<(), String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let deadline = Instant::now() + timeout;
+    let mut last: Option<String> = None;
+    loop {
+        let reason = match check().await {
+            Ok(()) => return Ok(()),
+            Err(reason) => reason,
+        };
+        if last.as_ref() != Some(&reason) {
+            println!("   … {}", reason);
+        }
+        if Instant::now() + interval > deadline {
+            return Err(reason);
+        }
+        last = Some(reason);
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// 检查一次：有默认路由，且每个以域名给出的服务器都能解析
+async fn check(servers: &[String]) -> Result<(), String> {
+    if netwatch::default_gateway().is_none() {
+        return Err("还没有默认路由".to_string());
+    }
+    for server in servers.iter().filter(|s| s.parse::<SocketAddr>().is_err()) {
+        match tokio::time::timeout(LOOKUP_TIMEOUT, endpoint::lookup(server)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(_) => return Err(format!("解析 {} 超时（DNS 还不可用）", server)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_until() {
+        // 第三次检查时就绪
+        let mut calls = 0;
+        let result = wait_until(Duration::from_secs(5), Duration::from_millis(1), || {
+            calls += 1;
+            let ready = calls >= 3;
+            async move { if ready { Ok(()) } else { Err("还没有默认路由".to_string()) } }
+        })
+        .await;
+        assert_eq!((result, calls), (Ok(()), 3));
+
+        // 超时返回最后一次的原因
+        let result = wait_until(Duration::from_millis(20), Duration::from_millis(5), || async { Err("解析超时".to_string()) }).await;
+        assert_eq!(result, Err("解析超时".to_string()));
+
+        let args = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(timeout_from_args(&args(&["vpn_client"])).unwrap(), DEFAULT_TIMEOUT);
+        assert_eq!(timeout_from_args(&args(&["vpn_client", "--wait-online-timeout", "30"])).unwrap().as_secs(), 30);
+        assert!(timeout_from_args(&args(&["vpn_client", "--wait-online-timeout", "0"])).is_err());
+    }
+}
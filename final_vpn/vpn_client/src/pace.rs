@@ -0,0 +1,94 @@
+// vpn_client/src/pace.rs
+// 上行发送节奏控制（--pace <速率>|auto），算法见 vpn_core::pacing
+//
+// 上行任务发送每个包之前调用 wait；自动模式下另起一个探测任务，每 PROBE_INTERVAL
+// 发一个带 PROBE_ID_FLAG 的 Echo，控制任务收到对应的 EchoReply 后交给 on_echo_reply。
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use tokio::net::UdpSocket;
+
+use vpn_core::control::{self, ControlMessage, KeyRing};
+use vpn_core::pacing::{self, MIN_SLEEP, PROBE_ID_FLAG, PROBE_INTERVAL, Pacer, PacingMode, RateController};
+
+use crate::endpoint::ServerEndpoint;
+
+pub struct Pacing {
+    mode: PacingMode,
+    pacer: Mutex<Pacer>,
+    /// 自动模式的速率估计（固定速率时为 None）
+    controller: Option<Mutex<RateController>>,
+}
+
+impl Pacing {
+    /// `--pace <速率>|auto`，未指定时返回 None
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(value) = crate::arg_value(args, "--pace") else { return Ok(None) };
+        let mode = PacingMode::parse(&value)?;
+        let rate = mode.initial_rate();
+        Ok(Some(Self {
+            mode,
+            pacer: Mutex::new(Pacer::new(rate)),
+            controller: (mode == PacingMode::Auto).then(|| Mutex::new(RateController::new(rate))),
+        }))
+    }
+
+    /// 等到 len 字节的包可以发出
+    pub async fn wait(&self, len: usize) {
+        let delay = self.pacer.lock().unwrap().schedule(len, Instant::now());
+        if delay >= MIN_SLEEP {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// 自动模式：启动探测任务，按探测结果调整速率
+    pub fn spawn(self: &Arc<Self>, socket: Arc<UdpSocket>, endpoint: Arc<ServerEndpoint>, keys: Arc<KeyRing>) {
+        if self.controller.is_none() {
+            return;
+        }
+        let pacing = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(PROBE_INTERVAL);
+            let mut last = Instant::now();
+            let mut next_id: u32 = 0;
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let sent = pacing.pacer.lock().unwrap().take_sent();
+                let id = next_id | PROBE_ID_FLAG;
+                next_id = (next_id + 1) & !PROBE_ID_FLAG;
+                if let Some(controller) = &pacing.controller {
+                    let mut controller = controller.lock().unwrap();
+                    let rate = controller.update(sent, now.duration_since(last), now);
+                    pacing.pacer.lock().unwrap().set_rate(rate);
+                    controller.on_probe_sent(id, now);
+                }
+                last = now;
+                let probe = ControlMessage::Echo { id, timestamp_us: control::monotonic_micros() };
+                crate::send_control(&socket, endpoint.addr(), &keys, &probe).await;
+            }
+        });
+    }
+
+    /// 控制任务收到 EchoReply：探测的回复交给速率估计，其他 Echo 的回复忽略
+    pub fn on_echo_reply(&self, id: u32, timestamp_us: u64) {
+        if let Some(controller) = self.controller.as_ref().filter(|_| pacing::is_probe(id)) {
+            let rtt = std::time::Duration::from_micros(control::monotonic_micros().saturating_sub(timestamp_us));
+            controller.lock().unwrap().on_probe_reply(id, rtt, Instant::now());
+        }
+    }
+
+    /// 状态输出：当前速率（自动模式附带最小 RTT）
+    pub fn summary(&self) -> String {
+        let rate = pacing::format_rate(self.pacer.lock().unwrap().rate());
+        match (&self.mode, &self.controller) {
+            (PacingMode::Auto, Some(controller)) => match controller.lock().unwrap().min_rtt() {
+                Some(min_rtt) => format!("{}（自动，最小 RTT {:.1} ms）", rate, min_rtt.as_secs_f64() * 1000.0),
+                None => format!("{}（自动）", rate),
+            },
+            _ => format!("{}（固定）", rate),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// vpn_client/src/profiles.rs
+// 命名的连接配置：把服务器、路由、DNS 和固定的服务端公钥存成配置文件，按名称连接
+//
+// 每个连接配置是一个普通的配置文件（格式见 vpn_core::config），保存在 <配置目录>/profiles/<名称>.toml，
+// 配置目录默认为 ~/.config/rust-vpn（与身份目录相同，命令行上给出 --identity-dir 时为该目录）。
+//
+//     vpn_client connect work [参数]   等价于 vpn_client [参数] --config <配置目录>/profiles/work.toml
+//     vpn_client profiles              列出已保存的连接配置
+//
+// 与运行档位（--profile gaming|bulk，见 vpn_core::profile）无关：运行档位只调性能参数，连接配置描述连到哪里。
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use vpn_core::asymmetric::{default_client_dir, key_fingerprint};
+use vpn_core::config::Config;
+use vpn_core::engine::Role;
+
+/// 连接配置所在的子目录
+pub const PROFILES_DIR: &str = "profiles";
+
+/// 已保存的连接配置
+#[derive(Debug)]
+pub struct SavedProfile {
+    pub name: String,
+    /// 配置有效时为其中的配置，否则为错误原因
+    pub config: Result<Config, String>,
+}
+
+/// 连接配置所在的目录
+pub fn dir(args: &[String]) -> Result<PathBuf> {
+    let base = match crate::arg_value(args, "--identity-dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => default_client_dir()?,
+    };
+    Ok(base.join(PROFILES_DIR))
+}
+
+/// 名称用作文件名：1~32 个字母、数字、- 或 _
+fn is_valid_name(name: &str) -> bool {
+    (1..=32).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// `connect <名称> [参数]` 展开为 `[参数] --config <文件>`；其他命令原样返回
+pub fn expand(args: Vec<String>) -> Result<Vec<String>> {
+    if args.get(1).map(String::as_str) != Some("connect") {
+        return Ok(args);
+    }
+    let expanded = expand_in(&args, &dir(&args)?)?;
+    println!("📇 连接配置: {}", args[2]);
+    Ok(expanded)
+}
+
+fn expand_in(args: &[String], dir: &Path) -> Result<Vec<String>> {
+    let name = args.get(2).filter(|name| !name.starts_with("--")).ok_or_else(|| anyhow!("用法: vpn_client connect <名称> [参数]"))?;
+    if !is_valid_name(name) {
+        return Err(anyhow!("无效的连接配置名称: {}（1~32 个字母、数字、- 或 _）", name));
+    }
+    if args.iter().any(|a| a == "--config") {
+        return Err(anyhow!("connect 已经指定了配置文件，不能再使用 --config"));
+    }
+    let path = dir.join(format!("{}.toml", name));
+    if !path.exists() {
+        let known: Vec<String> = list(dir)?.into_iter().map(|p| p.name).collect();
+        return Err(anyhow!(
+            "找不到连接配置 {}（{}）",
+            path.display(),
+            if known.is_empty() { "还没有保存任何连接配置".to_string() } else { format!("已有: {}", known.join(", ")) }
+        ));
+    }
+    let mut expanded = vec![args[0].clone()];
+    expanded.extend(args[3..].iter().cloned());
+    expanded.extend(["--config".to_string(), path.display().to_string()]);
+    Ok(expanded)
+}
+
+/// 目录下的全部连接配置（按名称排序，目录不存在时为空）
+pub fn list(dir: &Path) -> Result<Vec<SavedProfile>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("无法读取 {}: {}", dir.display(), e)),
+    };
+    let mut profiles = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".toml")) else { continue };
+        if !is_valid_name(name) {
+            continue;
+        }
+        let config = Config::load(&path).and_then(|config| config.validate(Role::Client).map(|_| config));
+        profiles.push(SavedProfile { name: name.to_string(), config: config.map_err(|e| e.to_string()) });
+    }
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(profiles)
+}
+
+/// `vpn_client profiles`：列出已保存的连接配置
+pub fn run_list(args: &[String]) -> Result<()> {
+    let dir = dir(args)?;
+    let profiles = list(&dir)?;
+    if profiles.is_empty() {
+        println!("没有连接配置。在 {} 下创建 <名称>.toml（格式与 --config 相同），之后用 vpn_client connect <名称> 连接", dir.display());
+        return Ok(());
+    }
+    println!("{:<16} {:<32} {:<18} 路由", "名称", "服务器", "服务端公钥");
+    for profile in profiles {
+        let config = match profile.config {
+            Ok(config) => config,
+            Err(e) => {
+                println!("{:<16} ⚠️ {}", profile.name, e);
+                continue;
+            }
+        };
+        let pinned = config.crypto.server_key.as_deref().and_then(|key| hex::decode(key.trim()).ok()).and_then(|key| <[u8; 32]>::try_from(key).ok());
+        let routes = match (config.network.full_tunnel, config.network.routes.is_empty()) {
+            (true, _) => "全隧道".to_string(),
+            (false, true) => "-".to_string(),
+            (false, false) => config.network.routes.join(","),
+        };
+        println!(
+            "{:<16} {:<32} {:<18} {}",
+            profile.name,
+            config.network.server.as_deref().unwrap_or("-"),
+            pinned.map(|key| key_fingerprint(&key)).unwrap_or_else(|| "keys/".to_string()),
+            routes
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand_and_list() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("work.toml"), "[network]\nserver = \"vpn.example.com:9000\"\nroutes = [\"10.20.0.0/16\"]\n").unwrap();
+        std::fs::write(dir.join("broken.toml"), "[network]\nserver = \"no-port\"\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+
+        // 其余参数保留，配置文件追加在最后（命令行上的值优先）
+        let expanded = expand_in(&strings(&["vpn_client", "connect", "work", "--full-tunnel"]), &dir).unwrap();
+        let path = dir.join("work.toml").display().to_string();
+        assert_eq!(expanded, strings(&["vpn_client", "--full-tunnel", "--config", &path]));
+
+        let missing = expand_in(&strings(&["vpn_client", "connect", "home"]), &dir).unwrap_err().to_string();
+        assert!(missing.contains("broken, work"), "{}", missing);
+        assert!(expand_in(&strings(&["vpn_client", "connect"]), &dir).is_err());
+        assert!(expand_in(&strings(&["vpn_client", "connect", "../work"]), &dir).is_err());
+        assert!(expand_in(&strings(&["vpn_client", "connect", "work", "--config", "other.toml"]), &dir).is_err());
+
+        let profiles = list(&dir).unwrap();
+        assert_eq!(profiles.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), ["broken", "work"]);
+        assert!(profiles[0].config.is_err());
+        assert_eq!(profiles[1].config.as_ref().unwrap().network.routes, ["10.20.0.0/16"]);
+        assert!(list(&dir.join("missing")).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
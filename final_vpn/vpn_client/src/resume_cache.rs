@@ -0,0 +1,67 @@
+// vpn_client/src/resume_cache.rs
+// 会话恢复缓存的维护
+//
+// 服务端下发票据后（ControlMessage::SessionTicket），把票据和当前会话密钥写入身份目录下的加密缓存；
+// 密钥轮换后、以及链路正常时每个状态周期重写一次（刷新有效期）。进程重启后 main 用缓存发送 Resume，
+// 成功则跳过完整握手，失败时删除缓存再完整握手。主动断开时删除缓存。协议见 vpn_core::resume。
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use vpn_core::handshake::AUTO_VIRTUAL_IP;
+use vpn_core::resume::{CachedSession, SessionCache, TicketId, unix_now};
+
+/// 会话恢复状态（控制任务、网络任务和退出处理共享）
+pub struct ResumeState {
+    cache: SessionCache,
+    /// 本次使用的虚拟 IP；--virtual-ip auto 时在握手或恢复后换成实际的地址
+    virtual_ip: Mutex<String>,
+    /// 当前会话的票据和有效期（秒）
+    ticket: Mutex<Option<(TicketId, u32)>>,
+}
+
+impl ResumeState {
+    pub fn new(cache: SessionCache, virtual_ip: String) -> Self {
+        Self { cache, virtual_ip: Mutex::new(virtual_ip), ticket: Mutex::new(None) }
+    }
+
+    /// 可用于本次连接的缓存：未过期，且服务端地址和虚拟 IP 与本次一致（auto 时接受上次分配的任何地址）
+    pub fn cached_for(&self, server: SocketAddr) -> Option<CachedSession> {
+        let virtual_ip = self.virtual_ip.lock().unwrap().clone();
+        self.cache
+            .load()
+            .filter(|c| c.server == server && (c.virtual_ip == virtual_ip || virtual_ip == AUTO_VIRTUAL_IP) && c.is_fresh(unix_now()))
+    }
+
+    /// 记录服务端分配的虚拟 IP（--virtual-ip auto）
+    pub fn set_virtual_ip(&self, virtual_ip: &str) {
+        *self.virtual_ip.lock().unwrap() = virtual_ip.to_string();
+    }
+
+    /// 记录服务端下发的票据
+    pub fn set_ticket(&self, id: TicketId, lifetime_secs: u32) {
+        *self.ticket.lock().unwrap() = Some((id, lifetime_secs));
+    }
+
+    /// 用当前会话密钥写入（或刷新）缓存；还没有票据时什么也不做
+    pub fn save(&self, server: SocketAddr, session_key: [u8; 32]) {
+        let Some((ticket, lifetime_secs)) = *self.ticket.lock().unwrap() else { return };
+        let session = CachedSession {
+            ticket,
+            session_key,
+            server,
+            virtual_ip: self.virtual_ip.lock().unwrap().clone(),
+            saved_at: unix_now(),
+            lifetime_secs,
+        };
+        if let Err(e) = self.cache.save(&session) {
+            eprintln!("⚠️ 会话恢复缓存写入失败: {}", e);
+        }
+    }
+
+    /// 删除缓存并忘记票据（主动断开、恢复失败或重新握手后）
+    pub fn clear(&self) {
+        *self.ticket.lock().unwrap() = None;
+        self.cache.clear();
+    }
+}
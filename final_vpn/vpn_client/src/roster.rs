@@ -0,0 +1,209 @@
+// vpn_client/src/roster.rs
+// 在线对端和服务查询：`vpn_client peers` / `vpn_client services`
+//
+// 运行中的客户端监听 /tmp/rust-vpn-<虚拟IP>.sock（权限 0600）。命令行连接该 socket 发送命令，
+// 客户端在控制通道里向服务端发 PeersRequest / ServicesRequest，服务端只回复按 --peer-acl 可以互访的客户端
+// （及其用 --advertise 登记的服务），结果以文本表格写回 socket。仅支持 Unix。
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+use vpn_core::control::{ControlMessage, KeyRing, PeerInfo, Service};
+
+use crate::endpoint::ServerEndpoint;
+#[cfg(unix)]
+use {
+    std::os::unix::fs::PermissionsExt,
+    std::path::Path,
+    std::sync::Arc,
+    tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    tokio::net::{UnixListener, UnixStream},
+};
+
+/// 等待服务端回复的时间
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 运行中的客户端监听的 socket 路径
+pub fn socket_path(virtual_ip: &str) -> String {
+    format!("/tmp/rust-vpn-{}.sock", virtual_ip)
+}
+
+/// 等待回复的查询，按请求 ID 匹配
+#[derive(Default)]
+pub struct Roster {
+    next_id: AtomicU32,
+    pending: std::sync::Mutex<HashMap<u32, oneshot::Sender<ControlMessage>>>,
+}
+
+impl Roster {
+    /// 控制任务收到 PeerList / ServiceList 时调用
+    pub fn complete(&self, id: u32, reply: ControlMessage) {
+        if let Some(tx) = self.pending.lock().unwrap().remove(&id) {
+            let _ = tx.send(reply);
+        }
+    }
+
+    /// 向服务端发一个查询并等待回复
+    async fn query(&self, request: impl FnOnce(u32) -> ControlMessage, socket: &UdpSocket, endpoint: &ServerEndpoint, keys: &KeyRing) -> Result<ControlMessage> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        crate::send_control(socket, endpoint.addr(), keys, &request(id)).await;
+        let result = tokio::time::timeout(QUERY_TIMEOUT, rx).await;
+        self.pending.lock().unwrap().remove(&id);
+        match result {
+            Ok(Ok(reply)) => Ok(reply),
+            _ => Err(anyhow!("服务端没有回复（旧版本服务端不支持这个查询）")),
+        }
+    }
+}
+
+/// 启动查询接口
+#[cfg(unix)]
+pub fn spawn(roster: Arc<Roster>, path: &str, socket: Arc<UdpSocket>, endpoint: Arc<ServerEndpoint>, keys: Arc<KeyRing>) -> Result<()> {
+    // 上次异常退出可能遗留 socket 文件
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            let (roster, socket, endpoint, keys) = (roster.clone(), socket.clone(), endpoint.clone(), keys.clone());
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &roster, &socket, &endpoint, &keys).await {
+                    eprintln!("⚠️  查询连接出错: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// 处理一个查询连接：读一行命令，写回文本结果后关闭
+#[cfg(unix)]
+async fn serve(stream: UnixStream, roster: &Roster, socket: &UdpSocket, endpoint: &ServerEndpoint, keys: &KeyRing) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let request: fn(u32) -> ControlMessage = match line.trim() {
+        "peers" => |id| ControlMessage::PeersRequest { id },
+        "services" => |id| ControlMessage::ServicesRequest { id },
+        other => {
+            writer.write_all(format!("❌ 未知命令: {}（可用: peers / services）\n", other).as_bytes()).await?;
+            return Ok(writer.shutdown().await?);
+        }
+    };
+    let response = match roster.query(request, socket, endpoint, keys).await {
+        Ok(ControlMessage::PeerList { peers, total, .. }) => report(&peers, total),
+        Ok(ControlMessage::ServiceList { services, total, .. }) => report_services(&services, total),
+        Ok(_) => "❌ 服务端的回复与查询不符\n".to_string(),
+        Err(e) => format!("❌ {}\n", e),
+    };
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// 在线对端表
+fn report(peers: &[PeerInfo], total: u32) -> String {
+    if peers.is_empty() {
+        return "（没有可以访问的在线对端）\n".to_string();
+    }
+    let mut out = format!("{:<16} {}\n", "虚拟 IP", "主机名");
+    for peer in peers {
+        out.push_str(&format!("{:<16} {}\n", peer.virtual_ip, peer.hostname.as_deref().unwrap_or("-")));
+    }
+    if total as usize > peers.len() {
+        out.push_str(&format!("…… 另有 {} 个对端未列出\n", total as usize - peers.len()));
+    }
+    out
+}
+
+/// 登记的服务表
+fn report_services(services: &[(Ipv4Addr, Service)], total: u32) -> String {
+    if services.is_empty() {
+        return "（可以访问的对端没有登记服务）\n".to_string();
+    }
+    let mut out = format!("{:<16} {:<16} {}\n", "虚拟 IP", "服务", "端口");
+    for (ip, service) in services {
+        out.push_str(&format!("{:<16} {:<16} {}\n", ip, service.name, service.rule()));
+    }
+    if total as usize > services.len() {
+        out.push_str(&format!("…… 另有 {} 条未列出\n", total as usize - services.len()));
+    }
+    out
+}
+
+/// 作为查询客户端运行：连接正在运行的客户端，打印在线对端或它们登记的服务
+///
+/// 用法: vpn_client peers|services [--virtual-ip <ip>]（本机只运行一个客户端时可以省略）
+#[cfg(unix)]
+pub async fn run_client(args: &[String]) -> Result<()> {
+    let path = match crate::arg_value(args, "--virtual-ip") {
+        Some(ip) => socket_path(&ip),
+        None => find_socket()?,
+    };
+    let mut stream = UnixStream::connect(&path).await
+        .map_err(|e| anyhow!("无法连接 {}（客户端是否在运行？）: {}", path, e))?;
+    stream.write_all(format!("{}\n", args[1]).as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    print!("{}", response);
+    Ok(())
+}
+
+/// 未指定虚拟 IP 时在 /tmp 下查找唯一的客户端 socket
+#[cfg(unix)]
+fn find_socket() -> Result<String> {
+    let mut found: Vec<String> = std::fs::read_dir("/tmp")?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| {
+            name.strip_prefix("rust-vpn-")
+                .and_then(|rest| rest.strip_suffix(".sock"))
+                .is_some_and(|ip| ip.parse::<Ipv4Addr>().is_ok())
+        })
+        .map(|name| format!("/tmp/{}", name))
+        .collect();
+    match found.len() {
+        0 => Err(anyhow!("没有找到运行中的客户端")),
+        1 => Ok(found.remove(0)),
+        _ => {
+            found.sort();
+            Err(anyhow!("本机运行着多个客户端，请用 --virtual-ip 指定: {}", found.join(" ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        assert_eq!(report(&[], 0), "（没有可以访问的在线对端）\n");
+        let peers = [
+            PeerInfo { virtual_ip: Ipv4Addr::new(10, 0, 0, 3), hostname: Some("nas".to_string()) },
+            PeerInfo { virtual_ip: Ipv4Addr::new(10, 0, 0, 4), hostname: None },
+        ];
+        let out = report(&peers, 5);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("10.0.0.3") && lines[1].ends_with("nas"));
+        assert!(lines[2].ends_with(" -"));
+        assert_eq!(lines[3], "…… 另有 3 个对端未列出");
+
+        let services = [(Ipv4Addr::new(10, 0, 0, 3), Service::parse("ssh=tcp:22").unwrap())];
+        let out = report_services(&services, 1);
+        assert!(out.lines().nth(1).unwrap().ends_with("tcp:22"));
+    }
+}
@@ -0,0 +1,149 @@
+// vpn_client/src/tunnels.rs
+// 多隧道模式的本地控制接口：Unix socket 上的文本命令，按名称启停单条隧道
+//
+// 多隧道客户端：监听 --control-socket（默认 /tmp/rust-vpn-client.sock，权限 0600）
+// 命令行：`vpn_client tunnel list` / `vpn_client tunnel up|down|restart <名称>` 连接该 socket 发送命令并打印结果
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::exits::{Lifecycle, TunnelHandle};
+
+pub const DEFAULT_CONTROL_SOCKET: &str = "/tmp/rust-vpn-client.sock";
+
+/// 控制命令
+#[derive(Debug, PartialEq)]
+pub enum TunnelCommand {
+    /// 列出所有隧道及其状态
+    List,
+    /// 对指定隧道执行 up / down / restart
+    Lifecycle(String, Lifecycle),
+}
+
+impl TunnelCommand {
+    /// 解析一行命令，例如 "down office"
+    pub fn parse(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["list"] | [] => Ok(TunnelCommand::List),
+            ["up", name] => Ok(TunnelCommand::Lifecycle(name.to_string(), Lifecycle::Up)),
+            ["down", name] => Ok(TunnelCommand::Lifecycle(name.to_string(), Lifecycle::Down)),
+            ["restart", name] => Ok(TunnelCommand::Lifecycle(name.to_string(), Lifecycle::Restart)),
+            _ => Err(anyhow!("未知命令: {}（可用: list / up <名称> / down <名称> / restart <名称>）", line.trim())),
+        }
+    }
+}
+
+/// 启动控制接口
+pub fn spawn(tunnels: Arc<Vec<TunnelHandle>>, path: &str) -> Result<()> {
+    // 上次异常退出可能遗留 socket 文件
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    println!("🛠️  控制接口: {}（vpn_client tunnel list|up|down|restart）", path);
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            let tunnels = tunnels.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &tunnels).await {
+                    eprintln!("⚠️  控制连接出错: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// 处理一个控制连接：读一行命令，写回文本结果后关闭
+async fn serve(stream: UnixStream, tunnels: &[TunnelHandle]) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let response = match TunnelCommand::parse(&line) {
+        Ok(TunnelCommand::List) => report(tunnels),
+        Ok(TunnelCommand::Lifecycle(name, command)) => match tunnels.iter().find(|t| t.name == name) {
+            Some(tunnel) if tunnel.send(command) => {
+                let action = match command {
+                    Lifecycle::Up => "启动",
+                    Lifecycle::Down => "停止",
+                    Lifecycle::Restart | Lifecycle::Shutdown => "重启",
+                };
+                format!("✅ 正在{}隧道 {}\n", action, name)
+            }
+            Some(_) => format!("❌ 隧道 {} 的监督任务已退出\n", name),
+            None => format!("❌ 没有名为 {} 的隧道\n", name),
+        },
+        Err(e) => format!("❌ {}\n", e),
+    };
+    writer.write_all(response.as_bytes()).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// 隧道状态表
+fn report(tunnels: &[TunnelHandle]) -> String {
+    let mut out = format!("{:<12} {:<10} {:<28} {:<8} {:>6} {:>6}\n", "名称", "设备", "服务器", "状态", "重启", "路由");
+    for tunnel in tunnels {
+        let status = tunnel.status();
+        out.push_str(&format!(
+            "{:<12} {:<10} {:<28} {:<8} {:>6} {:>6}\n",
+            tunnel.name, tunnel.dev_name, tunnel.server, status.state.label(), status.restarts, status.routes
+        ));
+    }
+    out
+}
+
+/// 作为控制客户端运行：把命令行上的命令发给正在运行的多隧道客户端并打印结果
+///
+/// 用法: vpn_client tunnel list|up|down|restart [<名称>] [--control-socket <path>]
+pub async fn run_client(args: &[String]) -> Result<()> {
+    let path = crate::arg_value(args, "--control-socket").unwrap_or_else(|| DEFAULT_CONTROL_SOCKET.to_string());
+
+    // 去掉程序名、子命令和 --control-socket 参数，剩下的就是命令
+    let mut command = Vec::new();
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if arg == "--control-socket" {
+            iter.next();
+        } else {
+            command.push(arg.as_str());
+        }
+    }
+    let command = command.join(" ");
+    TunnelCommand::parse(&command)?;
+
+    let mut stream = UnixStream::connect(&path).await
+        .map_err(|e| anyhow!("无法连接控制接口 {}（多隧道客户端是否在运行？）: {}", path, e))?;
+    stream.write_all(format!("{}\n", command).as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    print!("{}", response);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(TunnelCommand::parse("list\n").unwrap(), TunnelCommand::List);
+        assert_eq!(TunnelCommand::parse("").unwrap(), TunnelCommand::List);
+        assert_eq!(TunnelCommand::parse("down office").unwrap(), TunnelCommand::Lifecycle("office".to_string(), Lifecycle::Down));
+        assert_eq!(TunnelCommand::parse("restart exit0").unwrap(), TunnelCommand::Lifecycle("exit0".to_string(), Lifecycle::Restart));
+        assert!(TunnelCommand::parse("up").is_err());
+        assert!(TunnelCommand::parse("delete office").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+// vpn_core/src/agent.rs
+// 客户端密钥代理（类似 ssh-agent）
+//
+// 代理进程加载客户端身份私钥后监听一个 Unix socket（权限 600），隧道客户端和其他工具
+// 通过 socket 取得 client_id / 公钥并请求签名，私钥只存在于代理进程中，多个隧道实例可以共用同一个身份。
+//
+// 协议：每条消息为 4 字节大端长度 + wire 编码的 AgentRequest / AgentResponse。
+// 代理只对以 CLIENT_IDENTITY_DOMAIN 开头的消息签名，连上 socket 的程序也无法拿它签任意数据。
+
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::asymmetric::{ClientIdentity, SignerBackend};
+use crate::handshake::CLIENT_IDENTITY_DOMAIN;
+use crate::wire::{Fields, Writer};
+
+/// 指定代理 socket 的环境变量（相当于 SSH_AUTH_SOCK）
+pub const AGENT_SOCKET_ENV: &str = "RUST_VPN_AGENT_SOCK";
+
+const MAX_MESSAGE: usize = 64 * 1024;
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum AgentRequest {
+    /// 查询 client_id 和公钥
+    Identity,
+    /// 对消息签名
+    Sign { message: Vec<u8> },
+}
+
+#[derive(Debug)]
+enum AgentResponse {
+    Identity { client_id: String, public_key: [u8; 32] },
+    Signature { signature: Vec<u8> },
+    Error { message: String },
+}
+
+/// 代理消息的 wire 编码
+trait AgentMessage: Sized {
+    fn encode(&self) -> Result<Vec<u8>>;
+    fn decode(data: &[u8]) -> Result<Self>;
+}
+
+impl AgentMessage for AgentRequest {
+    fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            AgentRequest::Identity => Writer::new(&[], 1),
+            AgentRequest::Sign { message } => Writer::new(&[], 2).bytes(1, message),
+        }
+        .finish()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let f = Fields::parse(data)?;
+        match f.kind {
+            1 => Ok(AgentRequest::Identity),
+            2 => Ok(AgentRequest::Sign { message: f.vec(1)? }),
+            other => Err(anyhow!("未知的代理请求: {}", other)),
+        }
+    }
+}
+
+impl AgentMessage for AgentResponse {
+    fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            AgentResponse::Identity { client_id, public_key } => Writer::new(&[], 1).str(1, client_id).bytes(2, public_key),
+            AgentResponse::Signature { signature } => Writer::new(&[], 2).bytes(1, signature),
+            AgentResponse::Error { message } => Writer::new(&[], 3).str(1, message),
+        }
+        .finish()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let f = Fields::parse(data)?;
+        match f.kind {
+            1 => Ok(AgentResponse::Identity { client_id: f.string(1)?, public_key: f.array(2)? }),
+            2 => Ok(AgentResponse::Signature { signature: f.vec(1)? }),
+            3 => Ok(AgentResponse::Error { message: f.string(1)? }),
+            other => Err(anyhow!("未知的代理响应: {}", other)),
+        }
+    }
+}
+
+/// 默认 socket 路径：$RUST_VPN_AGENT_SOCK、$XDG_RUNTIME_DIR/rust-vpn-agent.sock 或 /tmp/rust-vpn-agent-<uid>.sock
+pub fn default_socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os(AGENT_SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("rust-vpn-agent.sock"),
+        None => std::env::temp_dir().join(format!("rust-vpn-agent-{}.sock", unsafe { libc::getuid() })),
+    }
+}
+
+/// 代理进程
+pub struct Agent {
+    identity: Arc<ClientIdentity>,
+    listener: UnixListener,
+}
+
+impl Agent {
+    /// 监听 socket；已有代理在运行时报错，残留的 socket 文件会被清理
+    pub fn bind(identity: ClientIdentity, path: &Path) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(anyhow!("{} 上已有密钥代理在运行", path.display()));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self { identity: Arc::new(identity), listener })
+    }
+
+    /// 处理连接（每个连接一个线程），不返回
+    pub fn run(self) -> Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("⚠️  密钥代理接受连接失败: {}", e);
+                    continue;
+                }
+            };
+            let identity = self.identity.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve_connection(&identity, stream) {
+                    eprintln!("⚠️  密钥代理连接出错: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve_connection(identity: &ClientIdentity, mut stream: UnixStream) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    // 一个连接上可以连续发多个请求，对端关闭时结束
+    while let Some(request) = read_message::<AgentRequest>(&mut stream)? {
+        let response = match request {
+            AgentRequest::Identity => AgentResponse::Identity {
+                client_id: identity.id().to_string(),
+                public_key: identity.public_key_bytes(),
+            },
+            AgentRequest::Sign { message } if !message.starts_with(CLIENT_IDENTITY_DOMAIN) => {
+                eprintln!("⚠️  拒绝签名：不是握手身份消息");
+                AgentResponse::Error { message: "只能签名握手身份消息".to_string() }
+            }
+            AgentRequest::Sign { message } => match identity.sign(&message) {
+                Ok(signature) => {
+                    println!("✍️  已为握手签名");
+                    AgentResponse::Signature { signature }
+                }
+                Err(e) => AgentResponse::Error { message: e.to_string() },
+            },
+        };
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+/// 连接代理，得到由代理签名的客户端身份
+pub fn connect(path: &Path) -> Result<ClientIdentity> {
+    let (client_id, public_key) = match request(path, &AgentRequest::Identity)? {
+        AgentResponse::Identity { client_id, public_key } => (client_id, public_key),
+        other => return Err(unexpected(other)),
+    };
+    ClientIdentity::with_signer(client_id, Box::new(AgentSigner { path: path.to_path_buf(), public_key }))
+}
+
+/// 通过代理签名
+struct AgentSigner {
+    path: PathBuf,
+    public_key: [u8; 32],
+}
+
+impl SignerBackend for AgentSigner {
+    fn name(&self) -> &'static str {
+        "agent"
+    }
+
+    fn public_key_bytes(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        match request(&self.path, &AgentRequest::Sign { message: message.to_vec() })? {
+            AgentResponse::Signature { signature } => Ok(signature),
+            other => Err(unexpected(other)),
+        }
+    }
+}
+
+fn request(path: &Path, request: &AgentRequest) -> Result<AgentResponse> {
+    let mut stream = UnixStream::connect(path)
+        .map_err(|e| anyhow!("无法连接密钥代理 {}: {}", path.display(), e))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    write_message(&mut stream, request)?;
+    read_message(&mut stream)?.ok_or_else(|| anyhow!("密钥代理关闭了连接"))
+}
+
+fn unexpected(response: AgentResponse) -> anyhow::Error {
+    match response {
+        AgentResponse::Error { message } => anyhow!("密钥代理拒绝请求: {}", message),
+        other => anyhow!("密钥代理返回了意外的响应: {:?}", other),
+    }
+}
+
+fn write_message<T: AgentMessage>(stream: &mut UnixStream, message: &T) -> Result<()> {
+    let data = message.encode()?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+/// 读一条消息；对端在消息边界关闭时返回 None
+fn read_message<T: AgentMessage>(stream: &mut UnixStream) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(anyhow!("密钥代理消息过长: {} 字节", len));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    Ok(Some(T::decode(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asymmetric::ClientVerifier;
+    use crate::handshake::client_identity_message;
+
+    #[test]
+    fn test_agent_signs_identity_messages_only() {
+        let path = std::env::temp_dir().join(format!("rust-vpn-agent-test-{}.sock", std::process::id()));
+        let identity = ClientIdentity::generate();
+        let (id, public_key) = (identity.id().to_string(), identity.public_key_bytes());
+        let agent = Agent::bind(identity, &path).unwrap();
+        assert!(Agent::bind(ClientIdentity::generate(), &path).is_err());
+        std::thread::spawn(move || agent.run());
+
+        let remote = connect(&path).unwrap();
+        assert_eq!(remote.id(), id);
+        assert_eq!(remote.public_key_bytes(), public_key);
+        assert_eq!(remote.backend_name(), "agent");
+
+        let message = client_identity_message(&[1u8; 32], &[2u8; 8], &id, "10.0.0.2");
+        let signature = remote.sign(&message).unwrap();
+        assert!(ClientVerifier::new(&public_key).unwrap().verify(&message, &signature).is_ok());
+        assert!(remote.sign(b"arbitrary data").is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
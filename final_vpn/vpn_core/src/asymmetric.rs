@@ -2,23 +2,102 @@
 // 非对称密钥管理和签名/验证功能
 
 use anyhow::{Result, anyhow};
-use ed25519_dalek::{Signer, Verifier, SigningKey, VerifyingKey, Signature};
+use ed25519_dalek::{Sha512, Verifier, SigningKey, VerifyingKey, Signature};
+use ed25519_dalek::hazmat::{ExpandedSecretKey, raw_sign};
 use rand::rngs::OsRng;
 use std::path::{Path, PathBuf};
 use std::fs;
 
+use crate::tpm::{self, KeyProtection};
+
 const SERVER_PRIVATE_KEY_FILE: &str = "server_private.key";
 const SERVER_PUBLIC_KEY_FILE: &str = "server_public.key";
+const CLIENT_PRIVATE_KEY_FILE: &str = "client_private.key";
+const CLIENT_ID_FILE: &str = "client_id";
+
+/// 服务端长期签名密钥的实现：密钥文件或 HSM / PKCS#11 token
+///
+/// 握手只需要公钥和“对消息签名”两个操作，使用 PKCS#11 时私钥不会出现在进程内存中。
+pub trait SignerBackend: Send + Sync {
+    /// 后端名称，启动时打印
+    fn name(&self) -> &'static str;
+
+    /// Ed25519 公钥
+    fn public_key_bytes(&self) -> [u8; 32];
+
+    /// Ed25519 签名（64 字节）
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
 
-/// 服务端密钥对管理
+/// 服务端身份：签名后端 + 公钥
 pub struct ServerIdentity {
+    signer: Box<dyn SignerBackend>,
+}
+
+impl ServerIdentity {
+    /// 从指定目录加载或生成密钥对（密钥文件后端）
+    pub fn load_or_generate(keys_dir: &Path, protection: KeyProtection) -> Result<Self> {
+        Ok(Self { signer: Box::new(FileSigner::load_or_generate(keys_dir, protection)?) })
+    }
+
+    /// 使用外部签名后端；公钥写入 keys_dir 供客户端分发，签名一次自检公钥和私钥是否匹配
+    pub fn with_signer(signer: Box<dyn SignerBackend>, keys_dir: &Path) -> Result<Self> {
+        let identity = Self { signer };
+        let verifier = ClientVerifier::new(&identity.public_key_bytes())?;
+        let probe = b"rust-vpn signer self-test";
+        verifier.verify(probe, &identity.sign(probe)?)
+            .map_err(|_| anyhow!("{} 签名后端的签名与公钥不匹配", identity.backend_name()))?;
+
+        fs::create_dir_all(keys_dir)?;
+        fs::write(keys_dir.join(SERVER_PUBLIC_KEY_FILE), identity.public_key_bytes())?;
+        Ok(identity)
+    }
+
+    /// 生成新的密钥对（不落盘）
+    #[cfg(test)]
+    fn generate() -> Self {
+        Self { signer: Box::new(FileSigner::generate()) }
+    }
+
+    /// 用给定的私钥创建（不落盘，互通测试的固定输入）
+    pub fn from_key_bytes(private_bytes: &[u8; 32]) -> Self {
+        Self { signer: Box::new(FileSigner::from_key_bytes(private_bytes)) }
+    }
+
+    /// 对消息进行签名
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.signer.sign(message)
+    }
+
+    /// 获取公钥字节数组
+    pub fn public_key_bytes(&self) -> [u8; 32] {
+        self.signer.public_key_bytes()
+    }
+
+    /// 签名后端名称
+    pub fn backend_name(&self) -> &'static str {
+        self.signer.name()
+    }
+
+    /// 打印公钥（供客户端使用）
+    pub fn print_public_key(&self) {
+        println!("🔑 服务端公钥（客户端需要此公钥，签名后端: {}）:", self.backend_name());
+        println!("   {}", hex::encode(self.public_key_bytes()));
+    }
+}
+
+/// 密钥文件后端：keys/server_private.key
+pub struct FileSigner {
     signing_key: SigningKey,
     verifying_key: VerifyingKey,
+    /// 展开后的私钥（SHA-512 后得到的标量和前缀）：SigningKey::sign 每次都会重新展开，
+    /// 服务端每个握手都要签名，这里在加载时算一次
+    expanded: ExpandedSecretKey,
 }
 
-impl ServerIdentity {
-    /// 从指定目录加载或生成密钥对
-    pub fn load_or_generate(keys_dir: &Path) -> Result<Self> {
+impl FileSigner {
+    /// 从指定目录加载或生成密钥对；protection 为 Tpm 时私钥密封到 TPM（已有的明文私钥会被迁移）
+    pub fn load_or_generate(keys_dir: &Path, protection: KeyProtection) -> Result<Self> {
         // 确保目录存在
         fs::create_dir_all(keys_dir)?;
         
@@ -26,88 +105,183 @@ impl ServerIdentity {
         let public_path = keys_dir.join(SERVER_PUBLIC_KEY_FILE);
         
         // 尝试加载已有密钥
-        if private_path.exists() {
+        if let Some(private_bytes) = read_private(&private_path, protection)? {
             println!("📂 从文件加载密钥对...");
-            return Self::load_from_file(&private_path);
+            return Self::from_private_bytes(&private_bytes);
         }
         
         // 生成新密钥
         println!("🔑 生成新的密钥对...");
-        let identity = Self::generate();
+        let signer = Self::generate();
         
         // 保存密钥
-        identity.save_to_file(keys_dir)?;
+        signer.save_to_file(keys_dir, protection)?;
         
         println!("✅ 密钥已保存到:");
         println!("   私钥: {}", private_path.display());
         println!("   公钥: {}", public_path.display());
         
-        Ok(identity)
+        Ok(signer)
     }
     
     /// 生成新的密钥对
     fn generate() -> Self {
         let mut csprng = OsRng;
-        let signing_key = SigningKey::generate(&mut csprng);
+        Self::from_signing_key(SigningKey::generate(&mut csprng))
+    }
+    
+    /// 用给定的私钥创建（不落盘）
+    pub fn from_key_bytes(private_bytes: &[u8; 32]) -> Self {
+        Self::from_signing_key(SigningKey::from_bytes(private_bytes))
+    }
+    
+    fn from_signing_key(signing_key: SigningKey) -> Self {
         let verifying_key = signing_key.verifying_key();
+        let expanded = ExpandedSecretKey::from(signing_key.as_bytes());
         
         Self {
             signing_key,
             verifying_key,
+            expanded,
         }
     }
     
-    /// 从文件加载密钥对
-    fn load_from_file(private_path: &Path) -> Result<Self> {
-        let private_bytes = fs::read(private_path)?;
-        
+    /// 从私钥字节恢复密钥对
+    fn from_private_bytes(private_bytes: &[u8]) -> Result<Self> {
         if private_bytes.len() != 32 {
             return Err(anyhow!("私钥文件格式错误：长度应为32字节，实际为{}字节", private_bytes.len()));
         }
         
         let mut key_bytes = [0u8; 32];
-        key_bytes.copy_from_slice(&private_bytes);
+        key_bytes.copy_from_slice(private_bytes);
         
-        let signing_key = SigningKey::from_bytes(&key_bytes);
-        let verifying_key = signing_key.verifying_key();
+        let signer = Self::from_signing_key(SigningKey::from_bytes(&key_bytes));
         
         println!("✅ 密钥加载成功");
         
-        Ok(Self {
-            signing_key,
-            verifying_key,
-        })
+        Ok(signer)
     }
     
     /// 保存密钥到文件
-    fn save_to_file(&self, keys_dir: &Path) -> Result<()> {
+    fn save_to_file(&self, keys_dir: &Path, protection: KeyProtection) -> Result<()> {
         let private_path = keys_dir.join(SERVER_PRIVATE_KEY_FILE);
         let public_path = keys_dir.join(SERVER_PUBLIC_KEY_FILE);
         
-        fs::write(&private_path, self.signing_key.to_bytes())?;
+        store_private(&private_path, &self.signing_key.to_bytes(), protection)?;
         fs::write(&public_path, self.verifying_key.to_bytes())?;
         
         Ok(())
     }
-    
-    /// 对消息进行签名
-    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
-        let signature = self.signing_key.sign(message);
-        signature.to_bytes().to_vec()
+}
+
+impl SignerBackend for FileSigner {
+    fn name(&self) -> &'static str {
+        "file"
     }
-    
-    /// 获取公钥字节数组
-    pub fn public_key_bytes(&self) -> [u8; 32] {
+
+    fn public_key_bytes(&self) -> [u8; 32] {
         self.verifying_key.to_bytes()
     }
-    
-    /// 打印公钥（供客户端使用）
-    pub fn print_public_key(&self) {
-        println!("🔑 服务端公钥（客户端需要此公钥）:");
-        println!("   {}", hex::encode(self.verifying_key.to_bytes()));
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        Ok(raw_sign::<Sha512>(&self.expanded, message, &self.verifying_key).to_bytes().to_vec())
+    }
+}
+
+/// PKCS#11 后端：通过 OpenSC 的 pkcs11-tool 调用 token 上的 Ed25519 密钥（CKM_EDDSA）
+///
+/// 需要 OpenSC 0.21 以上（PIN 通过 `--pin env:` 从环境变量读取，不出现在进程列表里）。
+/// 每次签名启动一次 pkcs11-tool，调用方应放在阻塞线程里执行。
+pub struct Pkcs11Signer {
+    /// PKCS#11 模块（如 /usr/lib/softhsm/libsofthsm2.so）
+    module: PathBuf,
+    /// token 标签，不指定时使用第一个 token
+    token_label: Option<String>,
+    /// 密钥对象的 CKA_ID（hex）
+    key_id: String,
+    /// 用户 PIN
+    pin: Option<String>,
+    public_key: [u8; 32],
+}
+
+const PKCS11_PIN_ENV: &str = "RUST_VPN_PKCS11_PIN";
+
+impl Pkcs11Signer {
+    /// 打开 token 上的密钥并读取公钥
+    pub fn open(module: PathBuf, token_label: Option<String>, key_id: String, pin: Option<String>) -> Result<Self> {
+        if key_id.is_empty() || hex::decode(&key_id).is_err() {
+            return Err(anyhow!("PKCS#11 密钥 ID 必须是 hex: {}", key_id));
+        }
+        let mut signer = Self { module, token_label, key_id, pin, public_key: [0u8; 32] };
+        let der = signer.run(&["--read-object", "--type", "pubkey"], None)?;
+        signer.public_key = parse_ed25519_public_key(&der)
+            .ok_or_else(|| anyhow!("PKCS#11 公钥对象不是 Ed25519 公钥（{} 字节）", der.len()))?;
+        Ok(signer)
+    }
+
+    /// 执行 pkcs11-tool，input 通过 stdin 传入，返回 stdout
+    fn run(&self, op: &[&str], input: Option<&[u8]>) -> Result<Vec<u8>> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let mut cmd = Command::new("pkcs11-tool");
+        cmd.arg("--module").arg(&self.module).args(["--id", &self.key_id]).args(op);
+        if let Some(label) = &self.token_label {
+            cmd.args(["--token-label", label]);
+        }
+        if let Some(pin) = &self.pin {
+            cmd.args(["--login", "--pin", &format!("env:{}", PKCS11_PIN_ENV)]).env(PKCS11_PIN_ENV, pin);
+        }
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| anyhow!("无法执行 pkcs11-tool: {}", e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input.unwrap_or_default())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("pkcs11-tool 失败: {}", stderr.trim()));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl SignerBackend for Pkcs11Signer {
+    fn name(&self) -> &'static str {
+        "pkcs11"
+    }
+
+    fn public_key_bytes(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let signature = self.run(&["--sign", "--mechanism", "EDDSA"], Some(message))?;
+        if signature.len() != 64 {
+            return Err(anyhow!("PKCS#11 签名长度错误: {} 字节", signature.len()));
+        }
+        Ok(signature)
     }
 }
 
+/// 从 token 导出的公钥对象中取出 Ed25519 公钥：
+/// SubjectPublicKeyInfo（DER）、CKA_EC_POINT 的 OCTET STRING，或 32 字节原始公钥
+fn parse_ed25519_public_key(der: &[u8]) -> Option<[u8; 32]> {
+    // SEQUENCE { SEQUENCE { OID 1.3.101.112 }, BIT STRING }
+    const SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+    let key = match der.len() {
+        32 => der,
+        34 if der[..2] == [0x04, 0x20] => &der[2..],
+        44 if der[..12] == SPKI_PREFIX => &der[12..],
+        _ => return None,
+    };
+    key.try_into().ok()
+}
+
 /// 客户端验证器
 pub struct ClientVerifier {
     server_public_key: VerifyingKey,
@@ -124,6 +298,13 @@ impl ClientVerifier {
         })
     }
     
+    /// 从 hex 编码的公钥创建（客户端用 --server-key 固定服务端公钥）
+    pub fn from_hex(public_key_hex: &str) -> Result<Self> {
+        let bytes = hex::decode(public_key_hex.trim()).map_err(|_| anyhow!("服务端公钥应为 hex 编码"))?;
+        let key: [u8; 32] = bytes.try_into().map_err(|b: Vec<u8>| anyhow!("服务端公钥长度应为 32 字节，实际为 {} 字节", b.len()))?;
+        Self::new(&key)
+    }
+    
     /// 从文件加载公钥
     pub fn load_from_file(public_key_path: &Path) -> Result<Self> {
         let public_bytes = fs::read(public_key_path)?;
@@ -156,6 +337,185 @@ impl ClientVerifier {
     }
 }
 
+/// 客户端身份：持久化的 UUID + Ed25519 密钥对
+///
+/// 以前 client_id 由虚拟 IP 拼出，换 IP 就换身份，不同机器用同一个 IP 时又会冲突。
+/// 现在首次运行时生成并保存在用户配置目录，之后握手、日志和服务端策略都使用这个 UUID，
+/// ClientHello 中附带对本次临时公钥的签名，证明持有对应的私钥。
+///
+/// 私钥可以在本进程内（FileSigner），也可以由密钥代理持有（见 agent 模块）。
+pub struct ClientIdentity {
+    id: String,
+    signer: Box<dyn SignerBackend>,
+}
+
+impl ClientIdentity {
+    /// 从指定目录加载或生成身份；protection 为 Tpm 时私钥密封到 TPM
+    pub fn load_or_generate(dir: &Path, protection: KeyProtection) -> Result<Self> {
+        let private_path = dir.join(CLIENT_PRIVATE_KEY_FILE);
+        let id_path = dir.join(CLIENT_ID_FILE);
+        
+        if id_path.exists() && let Some(private_bytes) = read_private(&private_path, protection)? {
+            let id = fs::read_to_string(&id_path)?.trim().to_string();
+            if !is_valid_client_id(&id) {
+                return Err(anyhow!("客户端 ID 文件格式错误: {}", id_path.display()));
+            }
+            let private_bytes: [u8; 32] = private_bytes
+                .try_into()
+                .map_err(|b: Vec<u8>| anyhow!("客户端私钥格式错误：长度应为32字节，实际为{}字节", b.len()))?;
+            let signer = FileSigner::from_signing_key(SigningKey::from_bytes(&private_bytes));
+            return Ok(Self { id, signer: Box::new(signer) });
+        }
+        
+        println!("🔑 生成新的客户端身份...");
+        fs::create_dir_all(dir)?;
+        let signing_key = SigningKey::generate(&mut OsRng);
+        store_private(&private_path, &signing_key.to_bytes(), protection)?;
+        let identity = Self { id: generate_client_id(), signer: Box::new(FileSigner::from_signing_key(signing_key)) };
+        fs::write(&id_path, format!("{}\n", identity.id))?;
+        println!("✅ 客户端身份已保存到: {}", dir.display());
+        Ok(identity)
+    }
+    
+    /// 生成新的身份（不保存）
+    pub fn generate() -> Self {
+        Self { id: generate_client_id(), signer: Box::new(FileSigner::generate()) }
+    }
+    
+    /// 使用外部签名后端（如密钥代理）
+    pub fn with_signer(id: String, signer: Box<dyn SignerBackend>) -> Result<Self> {
+        if !is_valid_client_id(&id) {
+            return Err(anyhow!("客户端 ID 格式错误: {}", id));
+        }
+        Ok(Self { id, signer })
+    }
+    
+    /// 客户端 UUID（握手中的 client_id）
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    
+    /// 身份公钥
+    pub fn public_key_bytes(&self) -> [u8; 32] {
+        self.signer.public_key_bytes()
+    }
+    
+    /// 签名后端名称（file / agent）
+    pub fn backend_name(&self) -> &'static str {
+        self.signer.name()
+    }
+    
+    /// 公钥指纹（前 8 字节的十六进制），用于日志
+    pub fn fingerprint(&self) -> String {
+        key_fingerprint(&self.public_key_bytes())
+    }
+    
+    /// 对消息进行签名
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        self.signer.sign(message)
+    }
+}
+
+/// 公钥指纹（前 8 字节的十六进制）
+pub fn key_fingerprint(public_key: &[u8; 32]) -> String {
+    hex::encode(&public_key[..8])
+}
+
+/// 生成随机的 UUID（版本 4）
+fn generate_client_id() -> String {
+    let mut b: [u8; 16] = rand::random();
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let h = hex::encode(b);
+    format!("{}-{}-{}-{}-{}", &h[0..8], &h[8..12], &h[12..16], &h[16..20], &h[20..32])
+}
+
+/// 是否为合法的客户端 ID（小写 UUID 格式，服务端据此拒绝伪造的字符串）
+pub fn is_valid_client_id(id: &str) -> bool {
+    id.len() == 36 && id.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_digit() || ('a'..='f').contains(&c),
+    })
+}
+
+/// 私钥文件只允许当前用户读写
+/// 读取私钥：已密封到 TPM 时解封，否则读明文文件（要求 TPM 保护时顺便迁移）；都不存在时返回 None
+pub(crate) fn read_private(path: &Path, protection: KeyProtection) -> Result<Option<Vec<u8>>> {
+    if tpm::is_sealed(path) {
+        return tpm::unseal(path).map(Some).map_err(|e| anyhow!(
+            "{} 已密封到 TPM，但解封失败: {}\n   TPM 被清除或不在原来的机器上时无法恢复；删除 {}.tpm.* 后会生成新的密钥",
+            path.display(), e, path.display()
+        ));
+    }
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if protection == KeyProtection::Tpm {
+        println!("🔒 把已有的明文私钥迁移到 TPM: {}", path.display());
+        store_private(path, &data, protection)?;
+    }
+    Ok(Some(data))
+}
+
+/// 保存私钥：要求 TPM 保护且密封成功时删除明文文件，否则回退为权限 600 的明文文件
+pub(crate) fn store_private(path: &Path, data: &[u8], protection: KeyProtection) -> Result<()> {
+    if protection == KeyProtection::Tpm {
+        match seal_verified(path, data) {
+            Ok(()) => {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                println!("🔒 私钥已密封到 TPM: {}.tpm.*", path.display());
+                return Ok(());
+            }
+            Err(e) => eprintln!("⚠️  无法密封到 TPM，私钥以明文文件保存: {}", e),
+        }
+    }
+    write_private(path, data)
+}
+
+/// 密封后立即解封核对，失败时清理密封文件，保证删除明文前 TPM 中的副本可用
+fn seal_verified(path: &Path, data: &[u8]) -> Result<()> {
+    if !tpm::available() {
+        return Err(anyhow!("没有可用的 TPM 2.0 设备或 tpm2-tools"));
+    }
+    let result = tpm::seal(path, data).and_then(|()| match tpm::unseal(path)? {
+        unsealed if unsealed == data => Ok(()),
+        _ => Err(anyhow!("解封结果与原私钥不一致")),
+    });
+    if result.is_err() {
+        let (public, private) = tpm::sealed_paths(path);
+        let _ = fs::remove_file(public);
+        let _ = fs::remove_file(private);
+    }
+    result
+}
+
+pub(crate) fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+        file.write_all(data)?;
+    }
+    #[cfg(not(unix))]
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// 客户端身份的默认目录：$XDG_CONFIG_HOME/rust-vpn、~/.config/rust-vpn 或 %APPDATA%\rust-vpn
+pub fn default_client_dir() -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .ok_or_else(|| anyhow!("无法确定用户配置目录，请使用 --identity-dir 指定"))?;
+    Ok(base.join("rust-vpn"))
+}
+
 /// 获取密钥存储目录（项目根目录下的 keys/）
 pub fn get_keys_dir() -> Result<PathBuf> {
     // 获取当前可执行文件路径
@@ -192,7 +552,7 @@ mod tests {
         let message = b"Test message";
         
         // 签名
-        let signature = identity.sign(message);
+        let signature = identity.sign(message).unwrap();
         
         // 验证
         let verifier = ClientVerifier::new(&identity.public_key_bytes()).unwrap();
@@ -202,4 +562,58 @@ mod tests {
         let wrong_message = b"Wrong message";
         assert!(verifier.verify(wrong_message, &signature).is_err());
     }
+
+    #[test]
+    fn test_cached_expanded_key_matches_signing_key() {
+        use ed25519_dalek::Signer;
+        
+        // Ed25519 签名是确定性的：缓存展开私钥前后应得到逐字节相同的签名
+        let signer = FileSigner::from_key_bytes(&[7u8; 32]);
+        let message = b"server hello";
+        assert_eq!(signer.sign(message).unwrap(), signer.signing_key.sign(message).to_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_parse_ed25519_public_key() {
+        let key = ServerIdentity::generate().public_key_bytes();
+        let mut spki = vec![0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+        spki.extend(key);
+        let mut point = vec![0x04, 0x20];
+        point.extend(key);
+
+        assert_eq!(parse_ed25519_public_key(&spki), Some(key));
+        assert_eq!(parse_ed25519_public_key(&point), Some(key));
+        assert_eq!(parse_ed25519_public_key(&key), Some(key));
+        // P-256 等其他曲线的公钥
+        spki[8] = 0x71;
+        assert_eq!(parse_ed25519_public_key(&spki), None);
+        assert_eq!(parse_ed25519_public_key(&[0x04; 65]), None);
+    }
+
+    #[test]
+    fn test_verifier_from_hex() {
+        let identity = ServerIdentity::generate();
+        let verifier = ClientVerifier::from_hex(&hex::encode(identity.public_key_bytes())).unwrap();
+        assert!(verifier.verify(b"pinned", &identity.sign(b"pinned").unwrap()).is_ok());
+        assert!(ClientVerifier::from_hex("abcd").is_err());
+        assert!(ClientVerifier::from_hex(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_client_identity_persisted() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-identity-{}", std::process::id()));
+        let first = ClientIdentity::load_or_generate(&dir, KeyProtection::File).unwrap();
+        // 没有 TPM 时回退为明文文件，有 TPM 时迁移后解封，两种情况下身份都不变
+        let second = ClientIdentity::load_or_generate(&dir, KeyProtection::Tpm).unwrap();
+        assert!(is_valid_client_id(first.id()));
+        assert_eq!(first.id(), second.id());
+        assert_eq!(first.public_key_bytes(), second.public_key_bytes());
+
+        let verifier = ClientVerifier::new(&first.public_key_bytes()).unwrap();
+        assert!(verifier.verify(b"hello", &second.sign(b"hello").unwrap()).is_ok());
+
+        assert!(!is_valid_client_id("client_10.0.0.2"));
+        assert!(!is_valid_client_id("6F9619FF-8B86-D011-B42D-00CF4FC964FF"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,370 @@
+// vpn_core/src/bench.rs
+// 性能自测（vpn_server bench / vpn_client bench）
+//
+// 不需要 root，不创建 TUN，也不连接服务器，在本机测两项：
+//   1. 加密 / 解密：单线程反复加解密同样大小的包，得到 AEAD 本身的上限
+//   2. 转发流水线：两个 TunnelEngine 通过 127.0.0.1 上的 UDP socket 相连，
+//      发送端的模拟 TUN 不停交付 IP 包，经加密、UDP、解密后写入接收端的模拟 TUN，
+//      按接收端实际收到的包计算吞吐（回环上的 socket 缓冲区溢出计为丢失）
+//
+// 结果反映本机 CPU 和协议栈的上限，用来估算部署规模、对比 --batch-size / --mtu 等调优前后的差别；
+// 实际链路还受带宽、丢包和对端的限制。
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+
+use crate::datapath_log::DataPathLog;
+use crate::engine::{PacketHandler, Role, TunnelEngine};
+use crate::local_tun::TunFrameCodec;
+use crate::pacing::format_rate;
+use crate::symmetric::{Cipher, KEY_SIZE};
+use crate::tuning::Tuning;
+
+/// 默认每项的测试时长
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(3);
+/// 最小的包（IPv4 头）
+const MIN_PACKET_SIZE: usize = 20;
+/// 回环 socket 的接收缓冲区，减少发送端过快时的丢包
+const RECV_BUFFER: usize = 4 << 20;
+
+/// 自测参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    /// 每项的测试时长
+    pub duration: Duration,
+    /// IP 包大小（加密前）
+    pub packet_size: usize,
+    /// 批处理深度和 MTU，与正常运行时相同
+    pub tuning: Tuning,
+}
+
+impl BenchOptions {
+    /// 从命令行参数读取
+    ///
+    /// * `--duration <秒>`：每项的时长，默认 3
+    /// * `--size <字节>`：IP 包大小，默认等于 MTU（满载的 TCP 段）
+    /// * `--batch-size` / `--mtu`：同正常运行时（见 tuning 模块）
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let tuning = Tuning::from_args(args)?;
+        let mut options = Self { duration: DEFAULT_DURATION, packet_size: tuning.tun_mtu() as usize, tuning };
+        if let Some(v) = arg_value(args, "--duration") {
+            let secs: u64 = v.parse().ok().filter(|s| *s > 0).ok_or_else(|| anyhow!("无效的 --duration: {}", v))?;
+            options.duration = Duration::from_secs(secs);
+        }
+        if let Some(v) = arg_value(args, "--size") {
+            let mtu = options.tuning.tun_mtu() as usize;
+            options.packet_size = v
+                .parse()
+                .ok()
+                .filter(|n| (MIN_PACKET_SIZE..=mtu).contains(n))
+                .ok_or_else(|| anyhow!("无效的 --size: {}（范围 {} ~ {}）", v, MIN_PACKET_SIZE, mtu))?;
+        }
+        Ok(options)
+    }
+}
+
+/// 一项测试的结果
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    pub packets: u64,
+    /// 加密前的字节数
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn packets_per_sec(&self) -> f64 {
+        self.packets as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn bits_per_sec(&self) -> u64 {
+        (self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(f64::EPSILON)) as u64
+    }
+
+    /// 例如 `  1234.5 Mbit/s   110234 包/秒`
+    pub fn summary(&self) -> String {
+        format!("{:>16} {:>10.0} 包/秒", format_rate(self.bits_per_sec()), self.packets_per_sec())
+    }
+}
+
+/// 流水线测试的结果
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PipelineResult {
+    /// 接收端写入模拟 TUN 的包
+    pub delivered: Throughput,
+    /// 发送端加密后发出的包数
+    pub sent: u64,
+}
+
+impl PipelineResult {
+    /// 发出但没有送达的比例（回环 socket 溢出）
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        self.sent.saturating_sub(self.delivered.packets) as f64 / self.sent as f64
+    }
+}
+
+/// 完整的自测结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BenchReport {
+    pub encrypt: Throughput,
+    pub decrypt: Throughput,
+    pub pipeline: PipelineResult,
+}
+
+impl BenchReport {
+    pub fn print(&self, options: &BenchOptions) {
+        println!(
+            "⏱️  性能自测（{}，IP 包 {} 字节，批处理 {}，每项 {} 秒）",
+            crate::crypto::backend_name(),
+            options.packet_size,
+            options.tuning.batch_size,
+            options.duration.as_secs()
+        );
+        println!("  加密（单线程）: {}", self.encrypt.summary());
+        println!("  解密（单线程）: {}", self.decrypt.summary());
+        println!(
+            "  转发流水线:     {}（发出 {}，送达 {}，丢失 {:.1}%）",
+            self.pipeline.delivered.summary(),
+            self.pipeline.sent,
+            self.pipeline.delivered.packets,
+            self.pipeline.loss() * 100.0
+        );
+    }
+}
+
+/// `vpn_server bench` / `vpn_client bench` 的入口：读取参数、运行并打印结果
+pub async fn run_command(args: &[String]) -> Result<()> {
+    let options = BenchOptions::from_args(args)?;
+    println!("⏱️  正在测试，大约需要 {} 秒...", options.duration.as_secs() * 3);
+    run(&options).await?.print(&options);
+    Ok(())
+}
+
+/// 依次运行各项测试
+pub async fn run(options: &BenchOptions) -> Result<BenchReport> {
+    let cipher = Cipher::new(&rand::random::<[u8; KEY_SIZE]>())?;
+    let packet = ip_packet(options.packet_size);
+    let encrypt = measure_encrypt(&cipher, &packet, options.duration)?;
+    let decrypt = measure_decrypt(&cipher, &packet, options.duration)?;
+    let pipeline = measure_pipeline(options, &packet).await?;
+    Ok(BenchReport { encrypt, decrypt, pipeline })
+}
+
+/// 填充了 IPv4 头的测试包（内容对转发没有影响，只要版本号正确）
+fn ip_packet(size: usize) -> Vec<u8> {
+    let mut packet = vec![0u8; size];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+    packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+    packet
+}
+
+/// 反复执行 f，直到用完 duration；每 64 次检查一次时间
+fn measure(duration: Duration, bytes_per_op: usize, mut f: impl FnMut() -> Result<()>) -> Result<Throughput> {
+    let start = Instant::now();
+    let mut packets = 0u64;
+    while start.elapsed() < duration {
+        for _ in 0..64 {
+            f()?;
+        }
+        packets += 64;
+    }
+    Ok(Throughput { packets, bytes: packets * bytes_per_op as u64, elapsed: start.elapsed() })
+}
+
+fn measure_encrypt(cipher: &Cipher, packet: &[u8], duration: Duration) -> Result<Throughput> {
+    measure(duration, packet.len(), || cipher.encrypt(packet).map(drop))
+}
+
+fn measure_decrypt(cipher: &Cipher, packet: &[u8], duration: Duration) -> Result<Throughput> {
+    let encrypted = cipher.encrypt(packet)?;
+    measure(duration, packet.len(), || cipher.decrypt(&encrypted).map(drop))
+}
+
+/// 两个引擎首尾相接：发送端 TUN -> 加密 -> UDP -> 解密 -> 接收端 TUN
+async fn measure_pipeline(options: &BenchOptions, packet: &[u8]) -> Result<PipelineResult> {
+    let key: [u8; KEY_SIZE] = rand::random();
+    let sender_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let receiver_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let receiver_tuning = Tuning { recv_buffer: Some(RECV_BUFFER), ..options.tuning.clone() };
+    // 缓冲区设置失败（没有权限调大上限等）不影响测试，只是丢包更多
+    let _ = receiver_tuning.apply_socket(&*receiver_socket);
+
+    let sent = Arc::new(AtomicU64::new(0));
+    let sink = Arc::new(Counters::default());
+    let sender = BenchHandler {
+        cipher: Cipher::new(&key)?,
+        socket: sender_socket.clone(),
+        peer: receiver_socket.local_addr()?,
+        sent: sent.clone(),
+    };
+    let receiver = BenchHandler {
+        cipher: Cipher::new(&key)?,
+        socket: receiver_socket.clone(),
+        peer: sender_socket.local_addr()?,
+        sent: Arc::new(AtomicU64::new(0)),
+    };
+
+    let datapath = Arc::new(DataPathLog::new());
+    let receiver_engine = TunnelEngine::new(Role::Server, Arc::new(receiver), datapath.clone(), &options.tuning);
+    let sender_engine = TunnelEngine::new(Role::Client, Arc::new(sender), datapath, &options.tuning);
+    let receiver_task = tokio::spawn(receiver_engine.run(Box::new(BenchTun::sink(sink.clone())), receiver_socket));
+
+    let start = Instant::now();
+    let frame = TunFrameCodec::platform().encode(packet.to_vec());
+    let sender_task = tokio::spawn(sender_engine.run(Box::new(BenchTun::source(frame, start + options.duration)), sender_socket));
+    tokio::time::sleep(options.duration).await;
+    let elapsed = start.elapsed();
+    let (packets, bytes) = sink.snapshot();
+    // 发送端的模拟 TUN 到期后关闭，上行循环自行结束；下行循环没有数据可收，直接丢弃
+    sender_task.abort();
+    receiver_task.abort();
+
+    Ok(PipelineResult {
+        delivered: Throughput { packets, bytes, elapsed },
+        sent: sent.load(Ordering::Relaxed),
+    })
+}
+
+/// 加密后发给 peer，收到的数据报解密后写回 TUN
+struct BenchHandler {
+    cipher: Cipher,
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    sent: Arc<AtomicU64>,
+}
+
+impl PacketHandler for BenchHandler {
+    async fn on_tun_packet(&self, ip_packet: &[u8]) {
+        let Ok(encrypted) = self.cipher.encrypt(ip_packet) else { return };
+        // 发送前计数：接收端可能在 send_to 返回之前就收到，先计数才能保证送达数不超过发出数
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        let _ = self.socket.send_to(&encrypted, self.peer).await;
+    }
+
+    async fn on_datagram(&self, data: &[u8], _src: SocketAddr) -> Option<Vec<u8>> {
+        self.cipher.decrypt(data).ok()
+    }
+}
+
+/// 模拟 TUN 写入的包数和字节数（去掉平台包头）
+#[derive(Debug, Default)]
+struct Counters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> (u64, u64) {
+        (self.packets.load(Ordering::Relaxed), self.bytes.load(Ordering::Relaxed))
+    }
+}
+
+/// 模拟 TUN：发送端在 deadline 之前每次读取都立即交付同一个帧，之后返回 EOF；
+/// 接收端永远没有包可读，只统计写入的帧
+struct BenchTun {
+    source: Option<(Vec<u8>, Instant)>,
+    sink: Option<Arc<Counters>>,
+}
+
+impl BenchTun {
+    fn source(frame: Vec<u8>, deadline: Instant) -> Self {
+        Self { source: Some((frame, deadline)), sink: None }
+    }
+
+    fn sink(counters: Arc<Counters>) -> Self {
+        Self { source: None, sink: Some(counters) }
+    }
+}
+
+impl AsyncRead for BenchTun {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &self.source {
+            Some((frame, deadline)) if Instant::now() < *deadline => {
+                buf.put_slice(&frame[..frame.len().min(buf.remaining())]);
+                Poll::Ready(Ok(()))
+            }
+            // 到期：读到 0 字节，上行循环结束
+            Some(_) => Poll::Ready(Ok(())),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for BenchTun {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(counters) = &self.sink {
+            let header = TunFrameCodec::platform().header_len();
+            counters.packets.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(buf.len().saturating_sub(header) as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_options_from_args() {
+        let options = BenchOptions::from_args(&strings(&["vpn_client", "bench"])).unwrap();
+        assert_eq!((options.duration, options.packet_size), (DEFAULT_DURATION, 1500));
+
+        let options = BenchOptions::from_args(&strings(&["bench", "--mtu", "1400", "--size", "64", "--duration", "1"])).unwrap();
+        assert_eq!((options.duration, options.packet_size, options.tuning.tun_mtu()), (Duration::from_secs(1), 64, 1400));
+
+        // 包不能超过 MTU，也不能短于 IPv4 头
+        for bad in [["--size", "1501"], ["--size", "10"], ["--duration", "0"]] {
+            assert!(BenchOptions::from_args(&strings(&bad)).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_run_reports_throughput() {
+        let mut options = BenchOptions::from_args(&[]).unwrap();
+        options.duration = Duration::from_millis(200);
+        options.packet_size = 200;
+        let report = run(&options).await.unwrap();
+
+        assert!(report.encrypt.packets > 0 && report.decrypt.packets > 0);
+        assert_eq!(report.encrypt.bytes, report.encrypt.packets * 200);
+        // 流水线送达的包经过了加密、UDP 和解密
+        let pipeline = report.pipeline;
+        assert!(pipeline.delivered.packets > 0);
+        assert!(pipeline.delivered.packets <= pipeline.sent);
+        assert_eq!(pipeline.delivered.bytes, pipeline.delivered.packets * 200);
+        assert!((0.0..1.0).contains(&pipeline.loss()));
+    }
+}
@@ -0,0 +1,308 @@
+// vpn_core/src/bin/vpn_handshake_tool.rs
+// 握手互通测试工具：用固定输入扮演握手的任意一端，打印握手消息和派生出的密钥
+//
+// 其他实现（移动端、嵌入式）用同样的输入应当得到逐字节相同的消息和会话密钥：
+//
+//   vpn_handshake_tool transcript                        两端都在本进程内，打印完整的测试向量
+//   vpn_handshake_tool client [--server-hello <hex>]     打印 ClientHello；给出对端的 ServerHello 时验证并派生会话密钥
+//   vpn_handshake_tool server --client-hello <hex>       验证对端的 ClientHello，打印 ServerHello 和会话密钥
+//
+// 固定输入（hex）省略时使用内置的测试向量，见 Inputs::default_vector。输出为 `名称 = 值`，每行一项，方便 diff。
+// 临时密钥固定后会话密钥也是固定的，这些输入只能用于测试。
+
+use std::net::SocketAddr;
+use std::process::ExitCode;
+
+use anyhow::{Context, Result, anyhow};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use vpn_core::asymmetric::{ClientIdentity, ClientVerifier, FileSigner, ServerIdentity};
+use vpn_core::handshake::{
+    ClientHandshake, HandshakeMessage, ServerHandshake, Transcript, deserialize_message, serialize_message,
+    verify_client_identity,
+};
+use vpn_core::kdf::DataKeys;
+use vpn_core::psk::DEFAULT_PSK;
+
+const USAGE: &str = "\
+用法: vpn_handshake_tool transcript|client|server [选项]
+
+  transcript                     两端都在本进程内，打印完整的测试向量
+  client [--server-hello <hex>]  打印 ClientHello；给出 ServerHello 时验证签名并派生会话密钥
+  server --client-hello <hex>    验证 ClientHello，打印签名后的 ServerHello 和会话密钥
+
+固定输入（hex，省略时使用内置测试向量）:
+  --psk <32 字节>                 预共享密钥（默认为 vpn_server / vpn_client 内置的 PSK）
+  --client-ephemeral <32 字节>    客户端 X25519 临时私钥
+  --client-mlkem-seed <64 字节>   客户端 ML-KEM-768 密钥对种子（draft-schwabe-cfrg-kyber）
+  --client-identity <32 字节>     客户端 Ed25519 身份私钥
+  --client-id <uuid>              客户端 UUID
+  --virtual-ip <ip>               ClientHello 中的虚拟 IP
+  --server-ephemeral <32 字节>    服务端 X25519 临时私钥
+  --server-mlkem-coins <32 字节>  服务端 ML-KEM 封装使用的随机数
+  --server-identity <32 字节>     服务端 Ed25519 身份私钥
+  --server-public-key <32 字节>   client 模式下用来验证 ServerHello 的公钥（默认由 --server-identity 推出）
+  --observed-addr <ip:port>       ServerHello 中服务端看到的客户端地址
+  --server-time <unix 秒>         ServerHello 中的服务端时间";
+
+/// 握手的全部固定输入
+struct Inputs {
+    psk: [u8; 32],
+    client_ephemeral: [u8; 32],
+    client_mlkem_seed: [u8; 64],
+    client_identity: [u8; 32],
+    client_id: String,
+    virtual_ip: String,
+    server_ephemeral: [u8; 32],
+    server_mlkem_coins: [u8; 32],
+    server_identity: [u8; 32],
+    server_public_key: Option<[u8; 32]>,
+    observed_addr: SocketAddr,
+    server_time: u64,
+}
+
+impl Inputs {
+    /// 内置测试向量：每项用不同的字节填充，一眼能看出是哪个输入
+    fn default_vector() -> Self {
+        Self {
+            psk: DEFAULT_PSK,
+            client_ephemeral: [0x11; 32],
+            client_mlkem_seed: [0x12; 64],
+            client_identity: [0x13; 32],
+            client_id: "00000000-0000-4000-8000-000000000001".to_string(),
+            virtual_ip: "10.0.0.2".to_string(),
+            server_ephemeral: [0x21; 32],
+            server_mlkem_coins: [0x22; 32],
+            server_identity: [0x23; 32],
+            server_public_key: None,
+            observed_addr: SocketAddr::from(([198, 51, 100, 7], 40000)),
+            server_time: 1_700_000_000,
+        }
+    }
+
+    fn from_args(args: &[String]) -> Result<Self> {
+        let mut inputs = Self::default_vector();
+        if let Some(v) = arg_value(args, "--psk") {
+            inputs.psk = parse_hex(v, "--psk")?;
+        }
+        if let Some(v) = arg_value(args, "--client-ephemeral") {
+            inputs.client_ephemeral = parse_hex(v, "--client-ephemeral")?;
+        }
+        if let Some(v) = arg_value(args, "--client-mlkem-seed") {
+            inputs.client_mlkem_seed = parse_hex(v, "--client-mlkem-seed")?;
+        }
+        if let Some(v) = arg_value(args, "--client-identity") {
+            inputs.client_identity = parse_hex(v, "--client-identity")?;
+        }
+        if let Some(v) = arg_value(args, "--client-id") {
+            inputs.client_id = v.clone();
+        }
+        if let Some(v) = arg_value(args, "--virtual-ip") {
+            inputs.virtual_ip = v.clone();
+        }
+        if let Some(v) = arg_value(args, "--server-ephemeral") {
+            inputs.server_ephemeral = parse_hex(v, "--server-ephemeral")?;
+        }
+        if let Some(v) = arg_value(args, "--server-mlkem-coins") {
+            inputs.server_mlkem_coins = parse_hex(v, "--server-mlkem-coins")?;
+        }
+        if let Some(v) = arg_value(args, "--server-identity") {
+            inputs.server_identity = parse_hex(v, "--server-identity")?;
+        }
+        if let Some(v) = arg_value(args, "--server-public-key") {
+            inputs.server_public_key = Some(parse_hex(v, "--server-public-key")?);
+        }
+        if let Some(v) = arg_value(args, "--observed-addr") {
+            inputs.observed_addr = v.parse().map_err(|_| anyhow!("无效的 --observed-addr: {}", v))?;
+        }
+        if let Some(v) = arg_value(args, "--server-time") {
+            inputs.server_time = v.parse().map_err(|_| anyhow!("无效的 --server-time: {}", v))?;
+        }
+        Ok(inputs)
+    }
+
+    fn print(&self) {
+        println!("# 输入");
+        field("psk", self.psk);
+        field("client.ephemeral_secret", self.client_ephemeral);
+        field("client.mlkem_seed", self.client_mlkem_seed);
+        field("client.identity_secret", self.client_identity);
+        println!("client.id = {}", self.client_id);
+        println!("client.virtual_ip = {}", self.virtual_ip);
+        field("server.ephemeral_secret", self.server_ephemeral);
+        field("server.mlkem_coins", self.server_mlkem_coins);
+        field("server.identity_secret", self.server_identity);
+        println!("server.observed_addr = {}", self.observed_addr);
+        println!("server.time = {}", self.server_time);
+    }
+
+    fn client(&self) -> Result<(ClientHandshake, HandshakeMessage)> {
+        let identity = ClientIdentity::with_signer(self.client_id.clone(), Box::new(FileSigner::from_key_bytes(&self.client_identity)))?;
+        let handshake = ClientHandshake::from_fixed(&self.psk, self.client_ephemeral, &self.client_mlkem_seed)?;
+        let hello = handshake.create_client_hello(&identity, self.virtual_ip.clone())?;
+        Ok((handshake, hello))
+    }
+
+    fn server_public_key(&self) -> [u8; 32] {
+        self.server_public_key.unwrap_or_else(|| ServerIdentity::from_key_bytes(&self.server_identity).public_key_bytes())
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("transcript") => Inputs::from_args(&args).and_then(|inputs| transcript(&inputs)),
+        Some("client") => Inputs::from_args(&args).and_then(|inputs| client(&inputs, arg_value(&args, "--server-hello"))),
+        Some("server") => match arg_value(&args, "--client-hello") {
+            Some(hello) => Inputs::from_args(&args).and_then(|inputs| server(&inputs, hello)),
+            None => Err(anyhow!("server 模式需要 --client-hello <hex>")),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("❌ {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// 两端都在本进程内完成握手，打印完整的测试向量
+fn transcript(inputs: &Inputs) -> Result<()> {
+    inputs.print();
+    let (client_handshake, client_hello) = inputs.client()?;
+    println!("\n# 客户端 -> 服务端");
+    print_client_hello(&client_hello)?;
+
+    println!("\n# 服务端 -> 客户端");
+    let (server_hello, server_key) = respond(inputs, &client_hello)?;
+
+    println!("\n# 客户端");
+    let client_key = finish(inputs, client_handshake, &client_hello, &server_hello)?;
+    if client_key != server_key {
+        return Err(anyhow!("两端的会话密钥不一致"));
+    }
+    println!("\n# 两端的会话密钥一致");
+    Ok(())
+}
+
+/// 客户端：打印 ClientHello；给出 ServerHello 时验证并派生会话密钥
+fn client(inputs: &Inputs, server_hello: Option<&String>) -> Result<()> {
+    inputs.print();
+    let (handshake, hello) = inputs.client()?;
+    println!("\n# 客户端 -> 服务端");
+    print_client_hello(&hello)?;
+    let Some(server_hello) = server_hello else { return Ok(()) };
+
+    let server_hello = deserialize_message(&parse_bytes(server_hello, "--server-hello")?).context("ServerHello 解码失败")?;
+    println!("\n# 客户端");
+    finish(inputs, handshake, &hello, &server_hello)?;
+    Ok(())
+}
+
+/// 服务端：验证对端的 ClientHello，打印 ServerHello 和会话密钥
+fn server(inputs: &Inputs, client_hello: &str) -> Result<()> {
+    inputs.print();
+    let hello = deserialize_message(&parse_bytes(client_hello, "--client-hello")?).context("ClientHello 解码失败")?;
+    println!("\n# 客户端 -> 服务端");
+    print_client_hello(&hello)?;
+    println!("\n# 服务端 -> 客户端");
+    respond(inputs, &hello)?;
+    Ok(())
+}
+
+fn print_client_hello(hello: &HandshakeMessage) -> Result<()> {
+    let HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, identity_key, .. } = hello else {
+        return Err(anyhow!("预期 ClientHello"));
+    };
+    field("client.x25519_public", client_pubkey);
+    field("client.mlkem_public", client_mlkem_pk);
+    field("client.identity_public", identity_key);
+    field("client_hello", serialize_message(hello)?);
+    Ok(())
+}
+
+/// 服务端处理 ClientHello：验证身份签名，封装、签名 ServerHello 并派生会话密钥
+fn respond(inputs: &Inputs, client_hello: &HandshakeMessage) -> Result<(HandshakeMessage, [u8; 32])> {
+    let HandshakeMessage::ClientHello { client_pubkey, .. } = client_hello else {
+        return Err(anyhow!("预期 ClientHello"));
+    };
+    verify_client_identity(client_hello).context("ClientHello 的身份签名无效")?;
+
+    let mut handshake = ServerHandshake::from_fixed(&inputs.psk, inputs.server_ephemeral);
+    let (mut server_hello, mlkem_shared) =
+        handshake.process_client_hello_fixed(client_hello, inputs.observed_addr, inputs.server_mlkem_coins, inputs.server_time)?;
+    let identity = ServerIdentity::from_key_bytes(&inputs.server_identity);
+    handshake.sign_server_hello(&mut server_hello, &identity)?;
+    if let HandshakeMessage::ServerHello { server_pubkey, .. } = server_hello {
+        field("server.x25519_public", server_pubkey);
+    }
+    field("server.identity_public", identity.public_key_bytes());
+    field("transcript_hash", transcript_hash(client_hello, &server_hello)?);
+    field("server_hello", serialize_message(&server_hello)?);
+    field("server.x25519_shared", ecdh(inputs.server_ephemeral, *client_pubkey));
+    field("server.mlkem_shared", mlkem_shared);
+
+    let session_key = handshake.compute_session_key(*client_pubkey, &mlkem_shared)?;
+    field("server.session_key", session_key);
+    data_keys("server", &session_key);
+    Ok((server_hello, session_key))
+}
+
+/// 客户端处理 ServerHello：验证对握手转录的签名后派生会话密钥
+fn finish(inputs: &Inputs, mut handshake: ClientHandshake, client_hello: &HandshakeMessage, server_hello: &HandshakeMessage) -> Result<[u8; 32]> {
+    let HandshakeMessage::ServerHello { server_pubkey, mlkem_ciphertext, .. } = server_hello else {
+        return Err(anyhow!("预期 ServerHello"));
+    };
+    handshake
+        .verify_server_hello(client_hello, server_hello, &ClientVerifier::new(&inputs.server_public_key())?)
+        .context("ServerHello 的签名无效")?;
+    println!("server_hello.signature = ok");
+    field("client.x25519_shared", ecdh(inputs.client_ephemeral, *server_pubkey));
+
+    let session_key = handshake.process_server_hello(*server_pubkey, mlkem_ciphertext)?;
+    field("client.session_key", session_key);
+    data_keys("client", &session_key);
+    Ok(session_key)
+}
+
+/// ServerHello 签名覆盖的握手转录哈希（见 vpn_core::handshake::Transcript）
+fn transcript_hash(client_hello: &HandshakeMessage, server_hello: &HandshakeMessage) -> Result<[u8; 32]> {
+    let mut transcript = Transcript::default();
+    transcript.absorb(client_hello)?;
+    transcript.absorb(server_hello)?;
+    Ok(transcript.hash())
+}
+
+/// X25519 共享密钥（会话密钥 KDF 的输入之一，便于对照中间值）
+fn ecdh(secret: [u8; 32], peer_public: [u8; 32]) -> [u8; 32] {
+    StaticSecret::from(secret).diffie_hellman(&PublicKey::from(peer_public)).to_bytes()
+}
+
+/// 会话密钥展开出的数据包密钥（见 vpn_core::kdf）
+fn data_keys(side: &str, session_key: &[u8; 32]) {
+    let keys = DataKeys::expand(session_key);
+    field(&format!("{}.key_to_server", side), keys.to_server);
+    field(&format!("{}.key_to_client", side), keys.to_client);
+    field(&format!("{}.header_key", side), keys.header);
+}
+
+fn field(name: &str, value: impl AsRef<[u8]>) {
+    println!("{} = {}", name, hex::encode(value));
+}
+
+fn parse_bytes(v: &str, name: &str) -> Result<Vec<u8>> {
+    hex::decode(v.trim()).map_err(|_| anyhow!("无效的 {}: 需要 hex", name))
+}
+
+fn parse_hex<const N: usize>(v: &str, name: &str) -> Result<[u8; N]> {
+    parse_bytes(v, name)?.try_into().map_err(|b: Vec<u8>| anyhow!("无效的 {}: 需要 {} 字节，实际 {} 字节", name, N, b.len()))
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
@@ -0,0 +1,76 @@
+// vpn_core/src/buffer_pool.rs
+// 收发包缓冲区池：转发循环按批读写时复用缓冲区，避免每个包都分配内存
+
+use std::sync::Mutex;
+
+/// 固定大小缓冲区的空闲列表
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    buf_size: usize,
+    max_free: usize,
+}
+
+impl BufferPool {
+    /// * `buf_size`: 每个缓冲区的长度（一般为 MTU + 头部余量）
+    /// * `max_free`: 最多缓存多少个空闲缓冲区，多余的直接释放
+    pub fn new(buf_size: usize, max_free: usize) -> Self {
+        Self {
+            free: Mutex::new(Vec::with_capacity(max_free)),
+            buf_size,
+            max_free,
+        }
+    }
+
+    /// 取一个长度为 buf_size 的缓冲区（内容未清零）
+    pub fn get(&self) -> Vec<u8> {
+        let recycled = self.free.lock().unwrap().pop();
+        match recycled {
+            Some(mut buf) => {
+                buf.resize(self.buf_size, 0);
+                buf
+            }
+            None => vec![0u8; self.buf_size],
+        }
+    }
+
+    /// 归还缓冲区
+    pub fn put(&self, buf: Vec<u8>) {
+        if buf.capacity() < self.buf_size {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free {
+            free.push(buf);
+        }
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_reuse() {
+        let pool = BufferPool::new(1500, 1);
+
+        let mut a = pool.get();
+        assert_eq!(a.len(), 1500);
+        a.truncate(10);
+        let ptr = a.as_ptr();
+        pool.put(a);
+
+        // 归还后再取到的是同一块内存，长度恢复
+        let b = pool.get();
+        assert_eq!(b.as_ptr(), ptr);
+        assert_eq!(b.len(), 1500);
+
+        // 超过 max_free 的缓冲区被丢弃
+        pool.put(b);
+        pool.put(vec![0u8; 1500]);
+        assert_eq!(pool.free.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,961 @@
+// vpn_core/src/config.rs
+// 客户端和服务端共用的配置文件格式（TOML）
+//
+// 参数越来越多以后，长命令行既难写也难检查。配置文件按用途分为五节：
+//
+//     [network]    地址、隧道设备和路由
+//     [crypto]     身份、密钥保护、密钥轮换和会话恢复
+//     [transport]  socket 缓冲区、握手超时、批处理、卸载
+//     [logging]    逐包日志、统计间隔、OTLP 导出
+//     [policy]     流量白名单、入站防火墙、重复连接策略
+//
+// 配置文件不引入新的解析路径：load_args 把它展开成等价的命令行参数，追加在实际命令行之后，
+// 各模块仍然通过 from_args 读取。同一个参数在命令行上也给出时以命令行为准（参数按首次出现取值）；
+// 可重复的参数（如 --allow、--push-route）两处的值合并。
+//
+// 只用于一端的字段在另一端被忽略，validate 会给出提示；未知字段直接报错，避免拼写错误被静默忽略。
+//
+// 容器部署时不方便为每个实例生成配置文件，每个字段都可以用环境变量覆盖：
+// `VPN__<节>__<字段>`（如 VPN__NETWORK__LISTEN），字段名在各节中唯一，也可以省略节名（VPN__LISTEN）。
+// 优先级：命令行 > 环境变量 > 配置文件 > 运行档位（transport.profile / --profile，见 profile 模块）。列表字段用逗号分隔，client_allow / peer_acl 写成 `ip=规则;ip=规则`，
+// 也可以直接写 TOML 字面量（[...] / {...}）；环境变量给出的列表替换而不是追加到文件中的列表。
+// network.exits / network.tunnels 是表格数组，只能写成字面量：VPN__EXITS='[{ virtual_ip = "...", server = "...", routes = [...] }]'。
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use serde::Deserialize;
+
+use crate::engine::Role;
+use crate::fec;
+use crate::logsink;
+use crate::profile::{self, Profile};
+use crate::firewall::Allowlist;
+use crate::pacing::PacingMode;
+use crate::tuning::{self, MAX_MTU, MIN_MTU};
+
+/// 完整的配置文件
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub crypto: CryptoConfig,
+    pub transport: TransportConfig,
+    pub logging: LoggingConfig,
+    pub policy: PolicyConfig,
+}
+
+/// [network]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// 客户端：虚拟 IP（代替第一个位置参数）
+    pub virtual_ip: Option<Ipv4Addr>,
+    /// 客户端：服务器地址 host:port（代替第二个位置参数）
+    pub server: Option<String>,
+    /// 客户端：全隧道
+    pub full_tunnel: bool,
+    /// 客户端：隧道 DNS
+    pub dns: Vec<Ipv4Addr>,
+    /// 客户端：额外经由隧道的 IPv4 网段
+    pub routes: Vec<String>,
+    /// 服务端：监听地址，默认 0.0.0.0:9000
+    pub listen: Option<SocketAddr>,
+    /// 服务端：网关模式（IP 转发 + NAT）
+    pub gateway: bool,
+    /// 服务端：下发给客户端的路由
+    pub push_routes: Vec<String>,
+    /// 客户端：上报给服务端的主机名（`<hostname>.vpn`）
+    pub hostname: Option<String>,
+    /// 客户端：向服务端上报主机名、操作系统和版本（默认不上报）
+    pub report_metadata: bool,
+    /// 客户端：握手前等待默认路由和服务器域名解析就绪
+    pub wait_online: bool,
+    /// 客户端：最多等待的时间（秒）
+    pub wait_online_timeout: Option<u64>,
+    /// 服务端：隧道内的 DNS 转发器
+    pub dns_forwarder: bool,
+    /// 服务端：DNS 转发器的上游（ip 或 ip:port）
+    pub dns_upstream: Option<String>,
+    /// 服务端：客户端主机名所在的域，默认 vpn
+    pub dns_domain: Option<String>,
+    /// 服务端：请求 auto 的客户端的虚拟 IP 分配策略
+    pub ipam: Option<IpamName>,
+    /// TUN 设备名
+    pub tun_name: Option<String>,
+    /// 隧道内 IPv6
+    pub ipv6: bool,
+    /// TUN MTU
+    pub mtu: Option<u16>,
+    /// 客户端：多出口，按目的地址选择服务器（[[network.exits]]）
+    pub exits: Vec<ExitConfig>,
+    /// 客户端：同时维护的多条独立隧道（[[network.tunnels]]）
+    pub tunnels: Vec<TunnelConfig>,
+}
+
+/// [[network.exits]] 中的一项，对应一个 --exit 参数
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExitConfig {
+    pub virtual_ip: Ipv4Addr,
+    /// host:port
+    pub server: String,
+    /// 经由这个出口的目的网段或域名；"default" 表示其余所有流量
+    pub routes: Vec<String>,
+}
+
+/// [[network.tunnels]] 中的一项，对应一个 --tunnel 参数
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TunnelConfig {
+    /// 控制命令（vpn_client tunnel up|down）中使用的名称
+    pub name: String,
+    pub virtual_ip: Ipv4Addr,
+    pub server: String,
+    /// 额外经由这条隧道的目的地址（虚拟网段本身总是路由到隧道）
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+/// [crypto]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CryptoConfig {
+    /// 客户端：身份目录
+    pub identity_dir: Option<PathBuf>,
+    /// 私钥密封到 TPM
+    pub tpm_seal: bool,
+    /// 客户端：密钥轮换间隔（秒）
+    pub rekey_interval: Option<u64>,
+    /// 会话恢复：服务端为 true 时启用，客户端为 false 时不使用
+    pub session_resume: Option<bool>,
+    /// 客户端：服务端签名连续验证失败多少次后停止
+    pub max_signature_failures: Option<u32>,
+    /// 客户端：停止的方式（exit / hold）
+    pub on_signature_failure: Option<String>,
+    /// 客户端：固定的服务端公钥（hex），代替 keys/server_public.key
+    pub server_key: Option<String>,
+    /// 预共享密钥文件（见 psk 模块），不设置时使用内置的 PSK
+    pub psk_file: Option<PathBuf>,
+    /// 服务端：轮换前的旧 PSK 文件，宽限期内仍然接受
+    pub previous_psk_file: Option<PathBuf>,
+    /// 服务端：旧 PSK 的宽限期（秒）
+    pub psk_grace: Option<u64>,
+}
+
+/// [transport]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportConfig {
+    /// socket 缓冲区，支持 k/m 后缀
+    pub recv_buffer: Option<String>,
+    pub send_buffer: Option<String>,
+    /// 握手超时（秒）
+    pub handshake_timeout: Option<u64>,
+    pub handshake_retries: Option<u32>,
+    pub batch_size: Option<usize>,
+    /// 转发任务卡住多久后重启（秒），0 表示关闭看门狗
+    pub watchdog: Option<u64>,
+    /// Linux TSO/GSO 卸载
+    pub tun_offload: bool,
+    /// 客户端：隧道内 PMTU 探测
+    pub pmtu_probe: bool,
+    /// 客户端：上行限速，速率（如 "20mbit"）或 "auto"
+    pub pace: Option<String>,
+    /// 客户端：请求的前向纠错分组大小
+    pub fec: Option<u8>,
+    /// 客户端：链路正常时的保活周期（秒）
+    pub keepalive: Option<u64>,
+    /// 运行档位：gaming、bulk 或 default
+    pub profile: Option<String>,
+    /// 服务端：挂载 eBPF 快速路径的外网接口
+    pub xdp: Option<String>,
+    /// 服务端：在快速路径的接口上启用 AF_XDP
+    pub af_xdp: bool,
+    /// 服务端：UDP GSO（Linux），为 false 时关闭
+    pub gso: Option<bool>,
+}
+
+/// [logging]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// 逐包日志
+    pub trace: bool,
+    /// 数据面统计的打印间隔（秒）
+    pub stats_interval: Option<u64>,
+    pub otlp_endpoint: Option<String>,
+    /// 日志输出：stdout / file:<路径> / journald / syslog / oslog
+    pub output: Option<String>,
+    /// 日志文件超过这个大小时轮转，支持 k/m 后缀
+    pub max_size: Option<String>,
+    /// 日志文件按时间轮转：hourly / daily
+    pub rotate: Option<String>,
+    /// 保留的旧日志文件数
+    pub keep: Option<usize>,
+}
+
+/// [policy]
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// 服务端：全局白名单规则
+    pub allow: Vec<String>,
+    /// 服务端：按虚拟 IP 单独配置的白名单
+    pub client_allow: BTreeMap<Ipv4Addr, String>,
+    /// 服务端：按虚拟 IP 配置允许互访的网段（逗号分隔）
+    pub peer_acl: BTreeMap<Ipv4Addr, String>,
+    /// 客户端：入站防火墙开放的服务
+    pub expose: Vec<String>,
+    /// 客户端：向服务端登记的服务（`ssh=tcp:22`）
+    pub advertise: Vec<String>,
+    /// 服务端：同一身份重复连接时的处理方式
+    pub duplicate_policy: Option<DuplicatePolicyName>,
+    /// 服务端：同时在线的客户端上限
+    pub max_clients: Option<usize>,
+    /// 服务端：拒绝握手时不回复原因
+    pub stealth: bool,
+    /// 服务端：接受的最低客户端功能版本
+    pub min_client_version: Option<u32>,
+    /// 服务端：拒绝旧版本客户端时显示给用户的升级说明
+    pub upgrade_message: Option<String>,
+}
+
+/// --duplicate-policy 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicyName {
+    Replace,
+    Reject,
+    Allow,
+}
+
+impl DuplicatePolicyName {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DuplicatePolicyName::Replace => "replace",
+            DuplicatePolicyName::Reject => "reject",
+            DuplicatePolicyName::Allow => "allow",
+        }
+    }
+}
+
+/// --ipam 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpamName {
+    Sequential,
+    Random,
+    Hashed,
+    Static,
+}
+
+impl IpamName {
+    fn as_str(&self) -> &'static str {
+        match self {
+            IpamName::Sequential => "sequential",
+            IpamName::Random => "random",
+            IpamName::Hashed => "hashed",
+            IpamName::Static => "static",
+        }
+    }
+}
+
+/// 环境变量前缀
+pub const ENV_PREFIX: &str = "VPN__";
+
+/// 字段的类型，决定环境变量的值如何转换
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    List,
+    Map,
+    /// 只接受 TOML 字面量（表格数组）
+    Literal,
+}
+
+/// 所有字段：(节, 字段, 类型)，与上面的结构体保持一致
+const KEYS: &[(&str, &str, Kind)] = &[
+    ("network", "virtual_ip", Kind::Str),
+    ("network", "server", Kind::Str),
+    ("network", "full_tunnel", Kind::Bool),
+    ("network", "dns", Kind::List),
+    ("network", "routes", Kind::List),
+    ("network", "listen", Kind::Str),
+    ("network", "gateway", Kind::Bool),
+    ("network", "push_routes", Kind::List),
+    ("network", "hostname", Kind::Str),
+    ("network", "report_metadata", Kind::Bool),
+    ("network", "wait_online", Kind::Bool),
+    ("network", "wait_online_timeout", Kind::Int),
+    ("network", "dns_forwarder", Kind::Bool),
+    ("network", "dns_upstream", Kind::Str),
+    ("network", "dns_domain", Kind::Str),
+    ("network", "ipam", Kind::Str),
+    ("network", "tun_name", Kind::Str),
+    ("network", "ipv6", Kind::Bool),
+    ("network", "mtu", Kind::Int),
+    ("network", "exits", Kind::Literal),
+    ("network", "tunnels", Kind::Literal),
+    ("crypto", "identity_dir", Kind::Str),
+    ("crypto", "tpm_seal", Kind::Bool),
+    ("crypto", "rekey_interval", Kind::Int),
+    ("crypto", "session_resume", Kind::Bool),
+    ("crypto", "max_signature_failures", Kind::Int),
+    ("crypto", "on_signature_failure", Kind::Str),
+    ("crypto", "server_key", Kind::Str),
+    ("crypto", "psk_file", Kind::Str),
+    ("crypto", "previous_psk_file", Kind::Str),
+    ("crypto", "psk_grace", Kind::Int),
+    ("transport", "recv_buffer", Kind::Str),
+    ("transport", "send_buffer", Kind::Str),
+    ("transport", "handshake_timeout", Kind::Int),
+    ("transport", "handshake_retries", Kind::Int),
+    ("transport", "batch_size", Kind::Int),
+    ("transport", "watchdog", Kind::Int),
+    ("transport", "tun_offload", Kind::Bool),
+    ("transport", "pmtu_probe", Kind::Bool),
+    ("transport", "pace", Kind::Str),
+    ("transport", "fec", Kind::Int),
+    ("transport", "keepalive", Kind::Int),
+    ("transport", "profile", Kind::Str),
+    ("transport", "xdp", Kind::Str),
+    ("transport", "af_xdp", Kind::Bool),
+    ("transport", "gso", Kind::Bool),
+    ("logging", "trace", Kind::Bool),
+    ("logging", "stats_interval", Kind::Int),
+    ("logging", "otlp_endpoint", Kind::Str),
+    ("logging", "output", Kind::Str),
+    ("logging", "max_size", Kind::Str),
+    ("logging", "rotate", Kind::Str),
+    ("logging", "keep", Kind::Int),
+    ("policy", "allow", Kind::List),
+    ("policy", "client_allow", Kind::Map),
+    ("policy", "peer_acl", Kind::Map),
+    ("policy", "expose", Kind::List),
+    ("policy", "advertise", Kind::List),
+    ("policy", "duplicate_policy", Kind::Str),
+    ("policy", "max_clients", Kind::Int),
+    ("policy", "stealth", Kind::Bool),
+    ("policy", "min_client_version", Kind::Int),
+    ("policy", "upgrade_message", Kind::Str),
+];
+
+/// 把一个 VPN__ 环境变量解析为 (节, 字段, 值)
+fn env_override(name: &str, raw: &str) -> Result<(&'static str, &'static str, toml::Value)> {
+    let path = name.strip_prefix(ENV_PREFIX).unwrap_or(name).to_ascii_lowercase();
+    let (section, key) = match path.split_once("__") {
+        Some((section, key)) => (Some(section), key),
+        None => (None, path.as_str()),
+    };
+    let &(section, key, kind) = KEYS
+        .iter()
+        .find(|(s, k, _)| *k == key && section.is_none_or(|section| section == *s))
+        .ok_or_else(|| anyhow!("未知的配置环境变量: {}", name))?;
+
+    let invalid = || anyhow!("环境变量 {} 的值无效: {}", name, raw);
+    let raw = raw.trim();
+    let literal = |raw: &str| -> Result<toml::Value> {
+        let table: toml::Table = toml::from_str(&format!("v = {}", raw)).map_err(|_| invalid())?;
+        table.get("v").cloned().ok_or_else(invalid)
+    };
+    let value = match kind {
+        Kind::Str => toml::Value::String(raw.to_string()),
+        Kind::Int => toml::Value::Integer(raw.parse().map_err(|_| invalid())?),
+        Kind::Bool => toml::Value::Boolean(match raw.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => true,
+            "0" | "false" | "no" | "off" | "" => false,
+            _ => return Err(invalid()),
+        }),
+        Kind::List if raw.starts_with('[') => literal(raw)?,
+        Kind::List => toml::Value::Array(
+            raw.split(',').map(str::trim).filter(|s| !s.is_empty()).map(|s| toml::Value::String(s.to_string())).collect(),
+        ),
+        Kind::Map if raw.starts_with('{') => literal(raw)?,
+        Kind::Map => {
+            let mut table = toml::Table::new();
+            for pair in raw.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+                let (k, v) = pair.split_once('=').ok_or_else(invalid)?;
+                table.insert(k.trim().to_string(), toml::Value::String(v.trim().to_string()));
+            }
+            toml::Value::Table(table)
+        }
+        Kind::Literal => literal(raw)?,
+    };
+    Ok((section, key, value))
+}
+
+impl Config {
+    /// 读取并解析配置文件
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::load_layered(Some(path), std::iter::empty())?.unwrap_or_default())
+    }
+
+    /// 按 配置文件 < 环境变量 的顺序合并；既没有文件也没有 VPN__ 环境变量时返回 None
+    ///
+    /// env 为全部环境变量（不以 VPN__ 开头的会被跳过），测试时可以传入固定的列表
+    pub fn load_layered(path: Option<&Path>, env: impl IntoIterator<Item = (String, String)>) -> Result<Option<Self>> {
+        let overrides = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .map(|(name, raw)| env_override(&name, &raw))
+            .collect::<Result<Vec<_>>>()?;
+        let mut table = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| anyhow!("无法读取配置文件 {}: {}", path.display(), e))?;
+                toml::from_str::<toml::Table>(&text).map_err(|e| anyhow!("配置文件 {} 有误: {}", path.display(), e))?
+            }
+            None if overrides.is_empty() => return Ok(None),
+            None => toml::Table::new(),
+        };
+        for (section, key, value) in overrides {
+            let section = table.entry(section).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            section
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("配置文件中的 {} 不是一节", key))?
+                .insert(key.to_string(), value);
+        }
+        let config = toml::Value::Table(table).try_into().map_err(|e| match path {
+            Some(path) => anyhow!("配置文件 {} 有误: {}", path.display(), e),
+            None => anyhow!("配置环境变量有误: {}", e),
+        })?;
+        Ok(Some(config))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// 检查取值是否合法；返回只用于另一端、会被忽略的字段
+    pub fn validate(&self, role: Role) -> Result<Vec<&'static str>> {
+        let n = &self.network;
+        let check_server = |name: &str, server: &str| {
+            let port = server.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(p)) if p != 0) {
+                return Err(anyhow!("{} 应为 host:port: {}", name, server));
+            }
+            Ok(())
+        };
+        if let Some(server) = &n.server {
+            check_server("network.server", server)?;
+        }
+        for exit in &n.exits {
+            check_server("network.exits.server", &exit.server)?;
+            if exit.routes.is_empty() {
+                return Err(anyhow!("network.exits 中 {} 的 routes 为空", exit.server));
+            }
+        }
+        for tunnel in &n.tunnels {
+            check_server("network.tunnels.server", &tunnel.server)?;
+        }
+        for route in &n.push_routes {
+            parse_cidr(route).ok_or_else(|| anyhow!("network.push_routes 中的网段无效: {}", route))?;
+        }
+        for route in &n.routes {
+            if !matches!(parse_cidr(route), Some((IpAddr::V4(_), _))) {
+                return Err(anyhow!("network.routes 中的网段无效: {}（只支持 IPv4）", route));
+            }
+        }
+        if let Some(name) = n.hostname.as_ref().filter(|name| !crate::control::is_valid_hostname(name)) {
+            return Err(anyhow!("network.hostname 无效: {}（1~63 个小写字母、数字或 -）", name));
+        }
+        if let Some(upstream) = n.dns_upstream.as_ref().filter(|u| u.parse::<SocketAddr>().is_err() && u.parse::<IpAddr>().is_err()) {
+            return Err(anyhow!("network.dns_upstream 应为 ip 或 ip:port: {}", upstream));
+        }
+        if let Some(mtu) = n.mtu.filter(|m| !(MIN_MTU..=MAX_MTU).contains(m)) {
+            return Err(anyhow!("network.mtu 超出范围: {}（{} ~ {}）", mtu, MIN_MTU, MAX_MTU));
+        }
+
+        if let Some(policy) = &self.crypto.on_signature_failure {
+            crate::sigguard::OnSignatureFailure::parse(policy)?;
+        }
+        if let Some(key) = &self.crypto.server_key {
+            crate::asymmetric::ClientVerifier::from_hex(key).map_err(|e| anyhow!("crypto.server_key 无效: {}", e))?;
+        }
+
+        let l = &self.logging;
+        if let Some(output) = &l.output {
+            logsink::Sink::parse(output)?;
+        }
+        if let Some(rotate) = &l.rotate {
+            logsink::RotateEvery::parse(rotate)?;
+        }
+        if (l.max_size.is_some() || l.rotate.is_some()) && !l.output.as_ref().is_some_and(|o| o.starts_with("file:")) {
+            return Err(anyhow!("logging.max_size / logging.rotate 只适用于 output = \"file:<路径>\""));
+        }
+
+        let t = &self.transport;
+        for size in [&t.recv_buffer, &t.send_buffer, &l.max_size].into_iter().flatten() {
+            tuning::parse_size(size)?;
+        }
+        if let Some(pace) = &t.pace {
+            PacingMode::parse(pace)?;
+        }
+        if let Some(k) = t.fec {
+            fec::parse_group_size(&k.to_string())?;
+        }
+        if let Some(profile) = &t.profile {
+            Profile::parse(profile)?;
+        }
+        if t.af_xdp && t.xdp.is_none() {
+            return Err(anyhow!("transport.af_xdp 需要同时设置 transport.xdp"));
+        }
+        if self.crypto.previous_psk_file.is_some() && self.crypto.psk_file.is_none() {
+            return Err(anyhow!("crypto.previous_psk_file 需要同时设置 crypto.psk_file"));
+        }
+        if self.crypto.psk_grace.is_some() && self.crypto.previous_psk_file.is_none() {
+            return Err(anyhow!("crypto.psk_grace 需要同时设置 crypto.previous_psk_file"));
+        }
+        if self.policy.upgrade_message.is_some() && self.policy.min_client_version.is_none() {
+            return Err(anyhow!("policy.upgrade_message 需要同时设置 policy.min_client_version"));
+        }
+        let zero = [
+            ("crypto.rekey_interval", self.crypto.rekey_interval.map(|v| v as usize)),
+            ("crypto.max_signature_failures", self.crypto.max_signature_failures.map(|v| v as usize)),
+            ("transport.handshake_timeout", t.handshake_timeout.map(|v| v as usize)),
+            ("network.wait_online_timeout", n.wait_online_timeout.map(|v| v as usize)),
+            ("transport.keepalive", t.keepalive.map(|v| v as usize)),
+            ("transport.batch_size", t.batch_size),
+            ("logging.stats_interval", self.logging.stats_interval.map(|v| v as usize)),
+            ("logging.keep", self.logging.keep),
+            ("policy.max_clients", self.policy.max_clients),
+        ];
+        if let Some((name, _)) = zero.iter().find(|(_, v)| *v == Some(0)) {
+            return Err(anyhow!("{} 不能为 0", name));
+        }
+
+        let p = &self.policy;
+        for rules in p.allow.iter().chain(&p.expose).chain(p.client_allow.values()) {
+            Allowlist::parse(rules)?;
+        }
+        for service in &p.advertise {
+            crate::control::Service::parse(service)?;
+        }
+        for net in p.peer_acl.values().flat_map(|nets| nets.split(',')).map(str::trim) {
+            if net.parse::<Ipv4Addr>().is_err() && !matches!(parse_cidr(net), Some((IpAddr::V4(_), _))) {
+                return Err(anyhow!("policy.peer_acl 中的网段无效: {}", net));
+            }
+        }
+
+        let client_only = [
+            ("network.virtual_ip", n.virtual_ip.is_some()),
+            ("network.server", n.server.is_some()),
+            ("network.full_tunnel", n.full_tunnel),
+            ("network.dns", !n.dns.is_empty()),
+            ("network.routes", !n.routes.is_empty()),
+            ("network.exits", !n.exits.is_empty()),
+            ("network.tunnels", !n.tunnels.is_empty()),
+            ("network.hostname", n.hostname.is_some()),
+            ("network.report_metadata", n.report_metadata),
+            ("network.wait_online", n.wait_online),
+            ("network.wait_online_timeout", n.wait_online_timeout.is_some()),
+            ("crypto.identity_dir", self.crypto.identity_dir.is_some()),
+            ("crypto.rekey_interval", self.crypto.rekey_interval.is_some()),
+            ("crypto.max_signature_failures", self.crypto.max_signature_failures.is_some()),
+            ("crypto.on_signature_failure", self.crypto.on_signature_failure.is_some()),
+            ("crypto.server_key", self.crypto.server_key.is_some()),
+            ("transport.pmtu_probe", t.pmtu_probe),
+            ("transport.pace", t.pace.is_some()),
+            ("transport.fec", t.fec.is_some()),
+            ("transport.keepalive", t.keepalive.is_some()),
+            ("policy.expose", !p.expose.is_empty()),
+            ("policy.advertise", !p.advertise.is_empty()),
+        ];
+        let server_only = [
+            ("network.listen", n.listen.is_some()),
+            ("network.gateway", n.gateway),
+            ("network.push_routes", !n.push_routes.is_empty()),
+            ("network.dns_forwarder", n.dns_forwarder),
+            ("network.dns_upstream", n.dns_upstream.is_some()),
+            ("network.dns_domain", n.dns_domain.is_some()),
+            ("network.ipam", n.ipam.is_some()),
+            ("policy.allow", !p.allow.is_empty()),
+            ("policy.client_allow", !p.client_allow.is_empty()),
+            ("policy.peer_acl", !p.peer_acl.is_empty()),
+            ("policy.duplicate_policy", p.duplicate_policy.is_some()),
+            ("policy.max_clients", p.max_clients.is_some()),
+            ("policy.stealth", p.stealth),
+            ("policy.min_client_version", p.min_client_version.is_some()),
+            ("policy.upgrade_message", p.upgrade_message.is_some()),
+            ("crypto.previous_psk_file", self.crypto.previous_psk_file.is_some()),
+            ("crypto.psk_grace", self.crypto.psk_grace.is_some()),
+            ("transport.xdp", t.xdp.is_some()),
+            ("transport.af_xdp", t.af_xdp),
+            ("transport.gso", t.gso.is_some()),
+        ];
+        let other_side: &[(&'static str, bool)] = if role == Role::Client { &server_only } else { &client_only };
+        Ok(other_side.iter().filter(|(_, set)| *set).map(|(name, _)| *name).collect())
+    }
+
+    /// 展开为等价的命令行参数（只包含本端使用的字段）
+    pub fn to_args(&self, role: Role) -> Vec<String> {
+        let mut args = ArgList::default();
+        let (n, c, t, l, p) = (&self.network, &self.crypto, &self.transport, &self.logging, &self.policy);
+        let client = role == Role::Client;
+
+        if client {
+            args.value("--virtual-ip", n.virtual_ip);
+            args.value("--server", n.server.as_ref());
+            args.flag("--full-tunnel", n.full_tunnel);
+            if !n.dns.is_empty() {
+                args.value("--dns", Some(n.dns.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(",")));
+            }
+            for route in &n.routes {
+                args.value("--route", Some(route));
+            }
+            for exit in &n.exits {
+                args.value("--exit", Some(format!("{}@{}={}", exit.virtual_ip, exit.server, exit.routes.join(","))));
+            }
+            for tunnel in &n.tunnels {
+                let routes = if tunnel.routes.is_empty() { String::new() } else { format!("={}", tunnel.routes.join(",")) };
+                args.value("--tunnel", Some(format!("{}:{}@{}{}", tunnel.name, tunnel.virtual_ip, tunnel.server, routes)));
+            }
+            args.value("--name", n.hostname.as_ref());
+            args.flag("--report-metadata", n.report_metadata);
+            args.flag("--wait-online", n.wait_online);
+            args.value("--wait-online-timeout", n.wait_online_timeout);
+            args.value("--identity-dir", c.identity_dir.as_ref().map(|d| d.display()));
+            args.value("--rekey-interval", c.rekey_interval);
+            args.flag("--no-session-resume", c.session_resume == Some(false));
+            args.value("--max-signature-failures", c.max_signature_failures);
+            args.value("--on-signature-failure", c.on_signature_failure.as_ref());
+            args.value("--server-key", c.server_key.as_ref());
+            args.flag("--pmtu-probe", t.pmtu_probe);
+            args.value("--pace", t.pace.as_ref());
+            args.value("--fec", t.fec);
+            args.value("--keepalive", t.keepalive);
+            for rules in &p.expose {
+                args.value("--expose", Some(rules));
+            }
+            for service in &p.advertise {
+                args.value("--advertise", Some(service));
+            }
+        } else {
+            args.value("--listen", n.listen);
+            args.flag("--gateway", n.gateway);
+            for route in &n.push_routes {
+                args.value("--push-route", Some(route));
+            }
+            args.flag("--dns-forwarder", n.dns_forwarder);
+            args.value("--dns-upstream", n.dns_upstream.as_ref());
+            args.value("--dns-domain", n.dns_domain.as_ref());
+            args.value("--ipam", n.ipam.map(|i| i.as_str()));
+            args.flag("--session-resume", c.session_resume == Some(true));
+            for rules in &p.allow {
+                args.value("--allow", Some(rules));
+            }
+            for (ip, rules) in &p.client_allow {
+                args.value("--client-allow", Some(format!("{}={}", ip, rules)));
+            }
+            for (ip, nets) in &p.peer_acl {
+                args.value("--peer-acl", Some(format!("{}={}", ip, nets)));
+            }
+            args.value("--duplicate-policy", p.duplicate_policy.map(|d| d.as_str()));
+            args.value("--max-clients", p.max_clients);
+            args.flag("--stealth", p.stealth);
+            args.value("--min-client-version", p.min_client_version);
+            args.value("--upgrade-message", p.upgrade_message.as_ref());
+            args.value("--previous-psk-file", c.previous_psk_file.as_ref().map(|f| f.display()));
+            args.value("--psk-grace", c.psk_grace);
+            args.value("--xdp", t.xdp.as_ref());
+            args.flag("--af-xdp", t.af_xdp);
+            args.flag("--no-gso", t.gso == Some(false));
+        }
+
+        args.value("--tun-name", n.tun_name.as_ref());
+        args.flag("--ipv6", n.ipv6);
+        args.value("--mtu", n.mtu);
+        args.flag("--tpm-seal", c.tpm_seal);
+        args.value("--psk-file", c.psk_file.as_ref().map(|f| f.display()));
+        args.value("--recv-buffer", t.recv_buffer.as_ref());
+        args.value("--send-buffer", t.send_buffer.as_ref());
+        args.value("--handshake-timeout", t.handshake_timeout);
+        args.value("--handshake-retries", t.handshake_retries);
+        args.value("--batch-size", t.batch_size);
+        args.value("--watchdog", t.watchdog);
+        args.flag("--tun-offload", t.tun_offload);
+        args.value("--profile", t.profile.as_ref());
+        args.flag("--trace", l.trace);
+        args.value("--stats-interval", l.stats_interval);
+        args.value("--otlp-endpoint", l.otlp_endpoint.as_ref());
+        args.value("--log", l.output.as_ref());
+        args.value("--log-max-size", l.max_size.as_ref());
+        args.value("--log-rotate", l.rotate.as_ref());
+        args.value("--log-keep", l.keep);
+        args.0
+    }
+}
+
+#[derive(Default)]
+struct ArgList(Vec<String>);
+
+impl ArgList {
+    fn flag(&mut self, name: &str, enabled: bool) {
+        if enabled {
+            self.0.push(name.to_string());
+        }
+    }
+
+    fn value(&mut self, name: &str, value: Option<impl std::fmt::Display>) {
+        if let Some(value) = value {
+            self.0.push(name.to_string());
+            self.0.push(value.to_string());
+        }
+    }
+}
+
+/// 解析 CIDR（IPv4 或 IPv6），返回 (地址, 前缀长度)
+pub fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (addr, len) = cidr.split_once('/')?;
+    let addr: IpAddr = addr.parse().ok()?;
+    let len: u8 = len.parse().ok()?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    (len <= max).then_some((addr, len))
+}
+
+/// 处理 `--config <文件>`、VPN__ 环境变量和运行档位：校验配置并把展开的参数依次追加在命令行之后；都没有时原样返回
+pub fn load_args(args: &[String], role: Role) -> Result<Vec<String>> {
+    load_args_with_env(args, role, std::env::vars())
+}
+
+fn load_args_with_env(args: &[String], role: Role, env: impl IntoIterator<Item = (String, String)>) -> Result<Vec<String>> {
+    let path = args.iter().position(|a| a == "--config").and_then(|i| args.get(i + 1));
+    let mut merged = args.to_vec();
+    if let Some(config) = Config::load_layered(path.map(Path::new), env)? {
+        for field in config.validate(role)? {
+            eprintln!("⚠️  配置项 {} 只用于{}，已忽略", field, if role == Role::Client { "服务端" } else { "客户端" });
+        }
+        merged.extend(config.to_args(role));
+    }
+    profile::expand(merged, role)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+[network]
+virtual_ip = "10.0.0.2"
+server = "vpn.example.com:9000"
+full_tunnel = true
+dns = ["1.1.1.1", "8.8.8.8"]
+routes = ["192.168.50.0/24"]
+listen = "0.0.0.0:9443"
+push_routes = ["192.168.10.0/24", "fd00:1::/64"]
+mtu = 1400
+
+[crypto]
+tpm_seal = true
+rekey_interval = 600
+session_resume = true
+
+[transport]
+recv_buffer = "4m"
+handshake_timeout = 10
+
+[logging]
+stats_interval = 5
+
+[policy]
+allow = ["udp:53", "tcp:443"]
+client_allow = { "10.0.0.5" = "tcp:22" }
+expose = ["tcp:22,icmp"]
+duplicate_policy = "reject"
+"#;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_and_expand() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.network.virtual_ip, Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(config.policy.duplicate_policy, Some(DuplicatePolicyName::Reject));
+
+        assert_eq!(
+            config.validate(Role::Client).unwrap(),
+            vec!["network.listen", "network.push_routes", "policy.allow", "policy.client_allow", "policy.duplicate_policy"]
+        );
+        assert_eq!(
+            config.to_args(Role::Client),
+            strings(&[
+                "--virtual-ip", "10.0.0.2", "--server", "vpn.example.com:9000", "--full-tunnel", "--dns", "1.1.1.1,8.8.8.8",
+                "--route", "192.168.50.0/24", "--rekey-interval", "600", "--expose", "tcp:22,icmp", "--mtu", "1400", "--tpm-seal", "--recv-buffer", "4m",
+                "--handshake-timeout", "10", "--stats-interval", "5",
+            ])
+        );
+        assert_eq!(
+            config.to_args(Role::Server),
+            strings(&[
+                "--listen", "0.0.0.0:9443", "--push-route", "192.168.10.0/24", "--push-route", "fd00:1::/64", "--session-resume",
+                "--allow", "udp:53", "--allow", "tcp:443", "--client-allow", "10.0.0.5=tcp:22", "--duplicate-policy", "reject",
+                "--mtu", "1400", "--tpm-seal", "--recv-buffer", "4m", "--handshake-timeout", "10", "--stats-interval", "5",
+            ])
+        );
+
+        // 展开的参数交给现有的解析逻辑
+        let tuning = tuning::Tuning::from_args(&config.to_args(Role::Server)).unwrap();
+        assert_eq!(tuning.mtu, Some(1400));
+        assert_eq!(tuning.recv_buffer, Some(4 << 20));
+
+        // 空文件等于全部默认值
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::default().to_args(Role::Client).is_empty());
+    }
+
+    #[test]
+    fn test_validation() {
+        // 未知字段、类型错误、非法取值
+        assert!(Config::parse("[network]\nvirtual_ipp = \"10.0.0.2\"").is_err());
+        assert!(Config::parse("[network]\nvirtual_ip = \"10.0.0.300\"").is_err());
+        assert!(Config::parse("[policy]\nduplicate_policy = \"kick\"").is_err());
+        assert!(Config::parse("[network]\nipam = \"dhcp\"").is_err());
+        assert!(Config::parse("[logging]\nlevel = \"debug\"").is_err());
+
+        let invalid = [
+            "[network]\nserver = \"vpn.example.com\"",
+            "[network]\npush_routes = [\"10.0.0.0/33\"]",
+            "[network]\nmtu = 100",
+            "[[network.exits]]\nvirtual_ip = \"10.1.0.2\"\nserver = \"us.example.com\"\nroutes = [\"default\"]",
+            "[[network.exits]]\nvirtual_ip = \"10.1.0.2\"\nserver = \"us.example.com:9000\"\nroutes = []",
+            "[transport]\nrecv_buffer = \"lots\"",
+            "[transport]\nbatch_size = 0",
+            "[transport]\npace = \"fast\"",
+            "[transport]\nfec = 1",
+            "[transport]\nprofile = \"turbo\"",
+            "[transport]\nkeepalive = 0",
+            "[transport]\naf_xdp = true",
+            "[policy]\nupgrade_message = \"请升级\"",
+            "[crypto]\nprevious_psk_file = \"/etc/rust-vpn/psk.previous\"",
+            "[crypto]\nrekey_interval = 0",
+            "[crypto]\nmax_signature_failures = 0",
+            "[logging]\noutput = \"kafka\"",
+            "[logging]\nrotate = \"daily\"",
+            "[logging]\noutput = \"file:/var/log/vpn.log\"\nrotate = \"weekly\"",
+            "[logging]\noutput = \"file:/var/log/vpn.log\"\nkeep = 0",
+            "[crypto]\non_signature_failure = \"retry\"",
+            "[crypto]\nserver_key = \"abcd\"",
+            "[network]\nroutes = [\"fd00:1::/64\"]",
+            "[network]\nwait_online = true\nwait_online_timeout = 0",
+            "[policy]\nexpose = [\"tcp:99999\"]",
+            "[policy]\nadvertise = [\"ssh\"]",
+            "[policy]\nmax_clients = 0",
+            "[network]\nhostname = \"My Laptop\"",
+            "[network]\ndns_upstream = \"dns.google\"",
+            "[policy]\npeer_acl = { \"10.0.0.2\" = \"10.0.0.3,fd00::/64\" }",
+        ];
+        for text in invalid {
+            let config = Config::parse(text).unwrap();
+            assert!(config.validate(Role::Client).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_load_args() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client.toml");
+        std::fs::write(&path, "[network]\nvirtual_ip = \"10.0.0.2\"\n[transport]\nhandshake_timeout = 10\n").unwrap();
+
+        // 命令行上的值优先
+        let args = strings(&["vpn_client", "--handshake-timeout", "5", "--config", path.to_str().unwrap()]);
+        let merged = load_args(&args, Role::Client).unwrap();
+        assert_eq!(&merged[..args.len()], &args[..]);
+        assert_eq!(tuning::Tuning::from_args(&merged).unwrap().handshake_timeout.as_secs(), 5);
+        assert!(merged.ends_with(&strings(&["--virtual-ip", "10.0.0.2", "--handshake-timeout", "10"])));
+
+        // 没有 --config 时原样返回；文件不存在时报错
+        assert_eq!(load_args(&args[..3], Role::Client).unwrap(), args[..3].to_vec());
+        assert!(load_args(&strings(&["vpn_client", "--config", "/nonexistent/vpn.toml"]), Role::Client).is_err());
+
+        // 环境变量覆盖文件，命令行仍然优先
+        let env = [("VPN__TRANSPORT__HANDSHAKE_TIMEOUT", "20"), ("VPN__TRANSPORT__BATCH_SIZE", "8"), ("HOME", "/root")];
+        let env = env.map(|(k, v)| (k.to_string(), v.to_string()));
+        let merged = load_args_with_env(&args, Role::Client, env.clone()).unwrap();
+        assert!(merged.ends_with(&strings(&["--virtual-ip", "10.0.0.2", "--handshake-timeout", "20", "--batch-size", "8"])));
+        let tuning = tuning::Tuning::from_args(&merged).unwrap();
+        assert_eq!((tuning.handshake_timeout.as_secs(), tuning.batch_size), (5, 8));
+        // 只有环境变量、没有配置文件
+        let merged = load_args_with_env(&args[..1], Role::Client, env).unwrap();
+        assert_eq!(merged, strings(&["vpn_client", "--handshake-timeout", "20", "--batch-size", "8"]));
+        // 运行档位展开的参数排在最后，优先级最低
+        let env = [("VPN__PROFILE", "bulk"), ("VPN__BATCH_SIZE", "8")].map(|(k, v)| (k.to_string(), v.to_string()));
+        let tuning = tuning::Tuning::from_args(&load_args_with_env(&args[..1], Role::Server, env).unwrap()).unwrap();
+        assert_eq!((tuning.batch_size, tuning.recv_buffer), (8, Some(4 << 20)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let env = |vars: &[(&str, &str)]| {
+            let vars: Vec<_> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            Config::load_layered(None, vars)
+        };
+        assert_eq!(env(&[("PATH", "/usr/bin")]).unwrap(), None);
+
+        let config = env(&[
+            ("VPN__NETWORK__LISTEN", "0.0.0.0:9443"),
+            ("VPN__MTU", "1400"),
+            ("VPN__network__ipv6", "yes"),
+            ("VPN__NETWORK__PUSH_ROUTES", "192.168.10.0/24, fd00:1::/64"),
+            ("VPN__DNS", "[\"1.1.1.1\"]"),
+            ("VPN__POLICY__CLIENT_ALLOW", "10.0.0.5=tcp:22; 10.0.0.6=all"),
+            ("VPN__DUPLICATE_POLICY", "reject"),
+            ("VPN__CRYPTO__SESSION_RESUME", "0"),
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.network.listen, Some("0.0.0.0:9443".parse().unwrap()));
+        assert_eq!(config.network.mtu, Some(1400));
+        assert!(config.network.ipv6);
+        assert_eq!(config.network.push_routes, vec!["192.168.10.0/24", "fd00:1::/64"]);
+        assert_eq!(config.network.dns, vec![Ipv4Addr::new(1, 1, 1, 1)]);
+        assert_eq!(config.policy.client_allow.get(&Ipv4Addr::new(10, 0, 0, 6)).map(String::as_str), Some("all"));
+        assert_eq!(config.policy.duplicate_policy, Some(DuplicatePolicyName::Reject));
+        assert_eq!(config.crypto.session_resume, Some(false));
+
+        let config = env(&[("VPN__EXITS", r#"[{ virtual_ip = "10.1.0.2", server = "us.example.com:9000", routes = ["default"] }]"#)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            config.to_args(Role::Client),
+            strings(&["--exit", "10.1.0.2@us.example.com:9000=default"])
+        );
+        assert!(env(&[("VPN__EXITS", "10.1.0.2@us.example.com:9000=default")]).is_err());
+        let config = env(&[("VPN__TUNNELS", r#"[{ name = "office", virtual_ip = "10.8.0.2", server = "office.example.com:9000" }]"#)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.to_args(Role::Client), strings(&["--tunnel", "office:10.8.0.2@office.example.com:9000"]));
+
+        // 未知的变量、节名与字段不符、值的类型不对
+        assert!(env(&[("VPN__LISTEN_ADDR", "0.0.0.0:9000")]).is_err());
+        assert!(env(&[("VPN__CRYPTO__LISTEN", "0.0.0.0:9000")]).is_err());
+        assert!(env(&[("VPN__MTU", "big")]).is_err());
+        assert!(env(&[("VPN__IPV6", "maybe")]).is_err());
+        assert!(env(&[("VPN__LISTEN", "not an address")]).is_err());
+
+        // 每个字段都能通过环境变量设置（KEYS 与结构体一致）
+        for (section, key, kind) in KEYS {
+            let value = match kind {
+                Kind::Str if *key == "duplicate_policy" => "allow",
+                Kind::Str if *key == "ipam" => "hashed",
+                Kind::Str if key.ends_with("_ip") => "10.0.0.2",
+                Kind::Str if *key == "listen" => "0.0.0.0:9000",
+                Kind::Str => "x",
+                Kind::Int => "1",
+                Kind::Bool => "true",
+                Kind::List => "",
+                Kind::Map => "",
+                Kind::Literal => "[]",
+            };
+            let name = format!("VPN__{}__{}", section.to_uppercase(), key.to_uppercase());
+            assert!(env(&[(&name, value)]).unwrap().is_some(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,749 @@
+// vpn_core/src/control.rs
+// 隧道内的控制通道
+//
+// 会话建立之后，密钥轮换、路由下发、保活、断开等控制消息都在加密隧道内传输，
+// 与 IP 包共用同一个会话密钥。解密后的明文按首字节区分类型：
+//
+// * 0x4_ / 0x6_ : IPv4 / IPv6 包
+// * 0x00        : PMTU 探测（见 pmtu 模块）
+// * 0x01        : 控制消息，后接 wire 编码的 ControlMessage
+// * 0x02        : 多路径 round-robin 模式下带序号的 IP 包（见 multipath 模块）
+// * 0x03 / 0x04 : 前向纠错的数据包 / 校验包（见 fec 模块）
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use rand::rngs::OsRng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::replay::ReplayWindow;
+use crate::symmetric::{Direction, PacketKeys};
+use crate::wire::{Fields, Writer};
+
+/// PMTU 探测的首字节
+pub const KIND_PMTU: u8 = 0x00;
+/// 控制消息的首字节
+pub const KIND_CONTROL: u8 = 0x01;
+
+/// 发送保活/Echo 的默认周期（客户端可用 --keepalive 调整）
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(25);
+/// 连续多少个 Echo 没有回复视为链路中断
+pub const MAX_MISSED_ECHOES: u32 = 4;
+/// 周期性打印链路状态的间隔
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(60);
+/// 默认的密钥轮换周期
+pub const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// PeerList 最多列出的对端数，保证回复装得进一个包
+pub const MAX_PEER_LIST: usize = 64;
+/// 每个客户端最多登记的服务数
+pub const MAX_SERVICES: usize = 16;
+/// ServiceList 最多列出的服务数
+pub const MAX_SERVICE_LIST: usize = 64;
+
+/// 密钥轮换的 KDF 上下文
+const REKEY_CONTEXT: &str = "rust-vpn 2024 rekey v1";
+
+/// 解密后明文的类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadKind {
+    Ip,
+    Pmtu,
+    Control,
+    Bonded,
+    Fec,
+    Unknown,
+}
+
+/// 按首字节判断明文类型
+pub fn classify(plaintext: &[u8]) -> PayloadKind {
+    match plaintext.first() {
+        Some(b) if b >> 4 == 4 || b >> 4 == 6 => PayloadKind::Ip,
+        Some(&KIND_PMTU) => PayloadKind::Pmtu,
+        Some(&KIND_CONTROL) => PayloadKind::Control,
+        Some(&crate::multipath::KIND_BONDED) => PayloadKind::Bonded,
+        Some(&crate::fec::KIND_FEC_DATA | &crate::fec::KIND_FEC_PARITY) => PayloadKind::Fec,
+        _ => PayloadKind::Unknown,
+    }
+}
+
+/// 控制消息
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlMessage {
+    /// 保活：对端原样回复（新版本改用 Echo，同时测量 RTT）
+    Keepalive,
+    /// 主动断开会话
+    Disconnect { reason: String },
+    /// 服务端下发给客户端的路由（CIDR 列表）
+    RoutePush { routes: Vec<String> },
+    /// 客户端发起密钥轮换，携带新的临时 X25519 公钥
+    RekeyRequest { public_key: [u8; 32] },
+    /// 服务端响应密钥轮换
+    RekeyResponse { public_key: [u8; 32] },
+    /// 延迟测量：timestamp_us 为发送方的单调时钟（微秒），对端原样带回
+    Echo { id: u32, timestamp_us: u64 },
+    /// Echo 的回复
+    EchoReply { id: u32, timestamp_us: u64 },
+    /// 服务端看到的客户端公网地址和端口（会话建立后下发，见 stun 模块）
+    ObservedAddr { addr: SocketAddr },
+    /// 服务端下发的会话恢复票据，客户端重启后可凭它恢复会话（见 resume 模块）
+    SessionTicket { id: [u8; 16], lifetime_secs: u32 },
+    /// 客户端的主机名（单个 DNS 标签），服务端的 DNS 转发器据此解析 `<name>.vpn`；每次（重新）握手后发送一次
+    Hostname { name: String },
+    /// 服务端通知：最近与本客户端通信过的对端下线（online=false）或重新上线，
+    /// 客户端据此清理流表，不必等超时
+    PeerStatus { peer: Ipv4Addr, online: bool },
+    /// 客户端查询当前在线、且按 ACL 可以互访的其他客户端
+    PeersRequest { id: u32 },
+    /// PeersRequest 的回复：最多 MAX_PEER_LIST 个对端，total 为实际数量
+    PeerList { id: u32, peers: Vec<PeerInfo>, total: u32 },
+    /// 客户端登记它对隧道开放的服务（整体替换之前的登记，空列表表示撤销）；每次（重新）握手后发送一次
+    ServiceRegister { services: Vec<Service> },
+    /// 客户端查询按 ACL 可以访问的对端登记的服务
+    ServicesRequest { id: u32 },
+    /// ServicesRequest 的回复：最多 MAX_SERVICE_LIST 条，total 为实际数量
+    ServiceList { id: u32, services: Vec<(Ipv4Addr, Service)>, total: u32 },
+    /// 客户端自愿上报的设备信息（--report-metadata），服务端只保存在会话中供管理接口显示；每次（重新）握手后发送一次
+    Metadata { client: ClientMetadata },
+    /// 服务端已更换 PSK，本次会话用的是旧 PSK：new_id 为新 PSK 的 ID，旧 PSK 的新握手再过 grace_secs 秒后被拒绝（见 psk 模块）
+    PskUpdated { new_id: crate::psk::PskId, grace_secs: u64 },
+}
+
+/// 客户端登记的服务：名称 + TCP/UDP 端口
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// 单个 DNS 标签（规则同主机名）
+    pub name: String,
+    /// IP 协议号（6 = TCP，17 = UDP）
+    pub protocol: u8,
+    pub port: u16,
+}
+
+impl Service {
+    /// 解析 `<名称>=<tcp|udp>:<端口>`，例如 `ssh=tcp:22`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let invalid = || anyhow!("无效的服务: {}（格式 ssh=tcp:22）", spec);
+        let (name, rule) = spec.split_once('=').ok_or_else(invalid)?;
+        let (proto, port) = rule.split_once(':').ok_or_else(invalid)?;
+        let protocol = match proto.to_ascii_lowercase().as_str() {
+            "tcp" => 6,
+            "udp" => 17,
+            _ => return Err(invalid()),
+        };
+        let service = Self { name: name.to_string(), protocol, port: port.parse().map_err(|_| invalid())? };
+        if !service.is_valid() {
+            return Err(invalid());
+        }
+        Ok(service)
+    }
+
+    /// 名称是合法的 DNS 标签、协议为 TCP/UDP 且端口非 0
+    pub fn is_valid(&self) -> bool {
+        is_valid_hostname(&self.name) && matches!(self.protocol, 6 | 17) && self.port != 0
+    }
+
+    /// 防火墙规则形式，例如 `tcp:22`
+    pub fn rule(&self) -> String {
+        format!("{}:{}", if self.protocol == 6 { "tcp" } else { "udp" }, self.port)
+    }
+
+    /// 编码为 [协议 u8][端口 u16][名称]
+    fn encode(&self) -> Vec<u8> {
+        let mut value = vec![self.protocol];
+        value.extend(self.port.to_be_bytes());
+        value.extend(self.name.as_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Self> {
+        let [protocol, hi, lo, name @ ..] = value else {
+            return Err(anyhow!("服务条目被截断"));
+        };
+        Ok(Self { name: String::from_utf8(name.to_vec())?, protocol: *protocol, port: u16::from_be_bytes([*hi, *lo]) })
+    }
+}
+
+impl std::fmt::Display for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.rule())
+    }
+}
+
+/// PeerList 中的一个对端
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub virtual_ip: Ipv4Addr,
+    /// 对端上报的主机名
+    pub hostname: Option<String>,
+}
+
+impl PeerInfo {
+    /// 编码为 [虚拟 IP 4 字节][主机名]（未上报主机名时为空）
+    fn encode(&self) -> Vec<u8> {
+        let mut value = self.virtual_ip.octets().to_vec();
+        value.extend(self.hostname.as_deref().unwrap_or("").as_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Self> {
+        let (ip, name) = value.split_at_checked(4).ok_or_else(|| anyhow!("对端条目被截断"))?;
+        let virtual_ip = Ipv4Addr::from(<[u8; 4]>::try_from(ip)?);
+        let hostname = (!name.is_empty()).then(|| String::from_utf8(name.to_vec())).transpose()?;
+        Ok(Self { virtual_ip, hostname })
+    }
+}
+
+/// 设备信息每一项的最大长度（字节）
+pub const MAX_METADATA_LEN: usize = 64;
+
+/// 客户端上报的设备信息
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientMetadata {
+    /// 系统主机名（原样，不要求是 DNS 标签）
+    pub hostname: String,
+    /// 操作系统和架构，例如 `linux x86_64 (Ubuntu 24.04 LTS)`
+    pub os: String,
+    /// 客户端版本
+    pub version: String,
+}
+
+impl ClientMetadata {
+    /// 每一项不超过 MAX_METADATA_LEN 字节且不含控制字符（服务端日志和管理接口逐行输出）
+    pub fn is_valid(&self) -> bool {
+        [&self.hostname, &self.os, &self.version]
+            .iter()
+            .all(|v| v.len() <= MAX_METADATA_LEN && !v.chars().any(char::is_control))
+    }
+}
+
+// 控制消息类型码（wire 编码，一经使用不再改变）
+const MSG_KEEPALIVE: u8 = 1;
+const MSG_DISCONNECT: u8 = 2;
+const MSG_ROUTE_PUSH: u8 = 3;
+const MSG_REKEY_REQUEST: u8 = 4;
+const MSG_REKEY_RESPONSE: u8 = 5;
+const MSG_ECHO: u8 = 6;
+const MSG_ECHO_REPLY: u8 = 7;
+const MSG_OBSERVED_ADDR: u8 = 8;
+const MSG_SESSION_TICKET: u8 = 9;
+const MSG_HOSTNAME: u8 = 10;
+const MSG_PEER_STATUS: u8 = 11;
+const MSG_PEERS_REQUEST: u8 = 12;
+const MSG_PEER_LIST: u8 = 13;
+const MSG_SERVICE_REGISTER: u8 = 14;
+const MSG_SERVICES_REQUEST: u8 = 15;
+const MSG_SERVICE_LIST: u8 = 16;
+const MSG_METADATA: u8 = 17;
+const MSG_PSK_UPDATED: u8 = 18;
+
+/// DNS 标签的最大长度
+const MAX_LABEL_LEN: usize = 63;
+
+impl ControlMessage {
+    /// 编码为隧道内明文：[KIND_CONTROL][wire 编码]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let prefix = [KIND_CONTROL];
+        let w = match self {
+            ControlMessage::Keepalive => Writer::new(&prefix, MSG_KEEPALIVE),
+            ControlMessage::Disconnect { reason } => Writer::new(&prefix, MSG_DISCONNECT).str(1, reason),
+            // 每条路由一个字段
+            ControlMessage::RoutePush { routes } => routes.iter()
+                .fold(Writer::new(&prefix, MSG_ROUTE_PUSH), |w, route| w.str(1, route)),
+            ControlMessage::RekeyRequest { public_key } => Writer::new(&prefix, MSG_REKEY_REQUEST).bytes(1, public_key),
+            ControlMessage::RekeyResponse { public_key } => Writer::new(&prefix, MSG_REKEY_RESPONSE).bytes(1, public_key),
+            ControlMessage::Echo { id, timestamp_us } => Writer::new(&prefix, MSG_ECHO).u32(1, *id).u64(2, *timestamp_us),
+            ControlMessage::EchoReply { id, timestamp_us } => Writer::new(&prefix, MSG_ECHO_REPLY).u32(1, *id).u64(2, *timestamp_us),
+            ControlMessage::ObservedAddr { addr } => Writer::new(&prefix, MSG_OBSERVED_ADDR).addr(1, *addr),
+            ControlMessage::SessionTicket { id, lifetime_secs } => {
+                Writer::new(&prefix, MSG_SESSION_TICKET).bytes(1, id).u32(2, *lifetime_secs)
+            }
+            ControlMessage::Hostname { name } => Writer::new(&prefix, MSG_HOSTNAME).str(1, name),
+            ControlMessage::PeerStatus { peer, online } => {
+                Writer::new(&prefix, MSG_PEER_STATUS).bytes(1, &peer.octets()).bool(2, *online)
+            }
+            ControlMessage::PeersRequest { id } => Writer::new(&prefix, MSG_PEERS_REQUEST).u32(1, *id),
+            ControlMessage::PeerList { id, peers, total } => peers
+                .iter()
+                .fold(Writer::new(&prefix, MSG_PEER_LIST).u32(1, *id).u32(3, *total), |w, peer| w.bytes(2, &peer.encode())),
+            ControlMessage::ServiceRegister { services } => services
+                .iter()
+                .fold(Writer::new(&prefix, MSG_SERVICE_REGISTER), |w, service| w.bytes(1, &service.encode())),
+            ControlMessage::ServicesRequest { id } => Writer::new(&prefix, MSG_SERVICES_REQUEST).u32(1, *id),
+            // 条目为 [虚拟 IP 4 字节][服务]
+            ControlMessage::ServiceList { id, services, total } => {
+                services.iter().fold(Writer::new(&prefix, MSG_SERVICE_LIST).u32(1, *id).u32(3, *total), |w, (ip, service)| {
+                    w.bytes(2, &[&ip.octets()[..], &service.encode()].concat())
+                })
+            }
+            ControlMessage::Metadata { client } => {
+                Writer::new(&prefix, MSG_METADATA).str(1, &client.hostname).str(2, &client.os).str(3, &client.version)
+            }
+            ControlMessage::PskUpdated { new_id, grace_secs } => Writer::new(&prefix, MSG_PSK_UPDATED).bytes(1, new_id).u64(2, *grace_secs),
+        };
+        w.finish()
+    }
+
+    pub fn decode(plaintext: &[u8]) -> Result<Self> {
+        let body = match plaintext.split_first() {
+            Some((&KIND_CONTROL, body)) => body,
+            _ => return Err(anyhow!("不是控制消息")),
+        };
+        let f = Fields::parse(body)?;
+        let msg = match f.kind {
+            MSG_KEEPALIVE => ControlMessage::Keepalive,
+            MSG_DISCONNECT => ControlMessage::Disconnect { reason: f.string(1)? },
+            MSG_ROUTE_PUSH => ControlMessage::RoutePush {
+                routes: f.all(1).map(|r| String::from_utf8(r.to_vec())).collect::<Result<_, _>>()?,
+            },
+            MSG_REKEY_REQUEST => ControlMessage::RekeyRequest { public_key: f.array(1)? },
+            MSG_REKEY_RESPONSE => ControlMessage::RekeyResponse { public_key: f.array(1)? },
+            MSG_ECHO => ControlMessage::Echo { id: f.u32(1)?, timestamp_us: f.u64(2)? },
+            MSG_ECHO_REPLY => ControlMessage::EchoReply { id: f.u32(1)?, timestamp_us: f.u64(2)? },
+            MSG_OBSERVED_ADDR => ControlMessage::ObservedAddr { addr: f.addr(1)? },
+            MSG_SESSION_TICKET => ControlMessage::SessionTicket { id: f.array(1)?, lifetime_secs: f.u32(2)? },
+            MSG_HOSTNAME => ControlMessage::Hostname { name: f.string(1)? },
+            MSG_PEER_STATUS => ControlMessage::PeerStatus { peer: Ipv4Addr::from(f.array::<4>(1)?), online: f.bool(2)? },
+            MSG_PEERS_REQUEST => ControlMessage::PeersRequest { id: f.u32(1)? },
+            MSG_PEER_LIST => ControlMessage::PeerList {
+                id: f.u32(1)?,
+                peers: f.all(2).map(PeerInfo::decode).collect::<Result<_>>()?,
+                total: f.u32(3)?,
+            },
+            MSG_SERVICE_REGISTER => ControlMessage::ServiceRegister { services: f.all(1).map(Service::decode).collect::<Result<_>>()? },
+            MSG_SERVICES_REQUEST => ControlMessage::ServicesRequest { id: f.u32(1)? },
+            MSG_SERVICE_LIST => ControlMessage::ServiceList {
+                id: f.u32(1)?,
+                services: f
+                    .all(2)
+                    .map(|value| {
+                        let (ip, service) = value.split_at_checked(4).ok_or_else(|| anyhow!("服务条目被截断"))?;
+                        Ok((Ipv4Addr::from(<[u8; 4]>::try_from(ip)?), Service::decode(service)?))
+                    })
+                    .collect::<Result<_>>()?,
+                total: f.u32(3)?,
+            },
+            MSG_METADATA => ControlMessage::Metadata {
+                client: ClientMetadata { hostname: f.string(1)?, os: f.string(2)?, version: f.string(3)? },
+            },
+            MSG_PSK_UPDATED => ControlMessage::PskUpdated { new_id: f.array(1)?, grace_secs: f.u64(2)? },
+            other => return Err(anyhow!("未知的控制消息类型: {}", other)),
+        };
+        Ok(msg)
+    }
+}
+
+/// 是否为合法的主机名：单个 DNS 标签，1~63 个小写字母、数字或 `-`，不以 `-` 开头或结尾
+pub fn is_valid_hostname(name: &str) -> bool {
+    (1..=MAX_LABEL_LEN).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// 把系统主机名转换为合法的主机名：取第一段、转小写，其他字符替换为 `-`；结果为空时返回 None
+pub fn normalize_hostname(raw: &str) -> Option<String> {
+    let label: String = raw
+        .trim()
+        .split('.')
+        .next()?
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .take(MAX_LABEL_LEN)
+        .collect();
+    let label = label.trim_matches('-');
+    is_valid_hostname(label).then(|| label.to_string())
+}
+
+/// 本进程的单调时钟（微秒），用于 Echo 时间戳
+pub fn monotonic_micros() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// 链路健康状态
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkHealth {
+    /// 最近的 Echo 都有回复
+    Up,
+    /// 有 Echo 未回复，正在加快探测
+    Degraded,
+    /// 连续 MAX_MISSED_ECHOES 个 Echo 未回复
+    Down,
+}
+
+/// 平滑 RTT 估计（RFC 6298 的 SRTT / RTTVAR）和 Echo 丢失计数
+#[derive(Debug, Clone, Default)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    /// 最近一次回复之后发出、尚未回复的 Echo 数
+    missed: u32,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次发出的 Echo
+    pub fn on_echo_sent(&mut self) {
+        self.missed = self.missed.saturating_add(1);
+    }
+
+    /// 收到 EchoReply：用带回的时间戳计算样本
+    pub fn on_echo_reply(&mut self, timestamp_us: u64) {
+        let sample = Duration::from_micros(monotonic_micros().saturating_sub(timestamp_us));
+        self.update(sample);
+        self.missed = 0;
+    }
+
+    /// 加入一个 RTT 样本
+    pub fn update(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// 重传超时：SRTT + max(4 * RTTVAR, 10ms)，限制在 [200ms, 10s]；没有样本时为 1s
+    pub fn rto(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt + (self.rttvar * 4).max(Duration::from_millis(10)))
+                .clamp(Duration::from_millis(200), Duration::from_secs(10)),
+            None => Duration::from_secs(1),
+        }
+    }
+
+    pub fn health(&self) -> LinkHealth {
+        match self.missed {
+            0 | 1 => LinkHealth::Up,
+            n if n < MAX_MISSED_ECHOES => LinkHealth::Degraded,
+            _ => LinkHealth::Down,
+        }
+    }
+
+    /// 下一次发送 Echo 的间隔：链路正常时按保活周期，有未回复的 Echo 时按 RTO 加快探测
+    pub fn next_echo_interval(&self, keepalive: Duration) -> Duration {
+        if self.missed == 0 {
+            keepalive
+        } else {
+            self.rto().min(keepalive)
+        }
+    }
+
+    /// 状态输出用的简短描述
+    pub fn summary(&self) -> String {
+        match self.srtt {
+            Some(srtt) => format!(
+                "{:?}, RTT {:.1} ms ± {:.1} ms",
+                self.health(),
+                srtt.as_secs_f64() * 1000.0,
+                self.rttvar.as_secs_f64() * 1000.0
+            ),
+            None => format!("{:?}, RTT 未知", self.health()),
+        }
+    }
+}
+
+/// 由旧会话密钥和新的 ECDH 共享密钥派生新会话密钥
+///
+/// 新密钥 = BLAKE3-KDF(旧密钥 || ECDH_shared)，即使单次 ECDH 被破解也无法脱离旧密钥推出新密钥
+pub fn derive_rekeyed_key(old_key: &[u8; 32], shared: &[u8; 32]) -> [u8; 32] {
+    let mut material = [0u8; 64];
+    material[..32].copy_from_slice(old_key);
+    material[32..].copy_from_slice(shared);
+    blake3::derive_key(REKEY_CONTEXT, &material)
+}
+
+/// 服务端处理 RekeyRequest：返回新会话密钥和要回复的 RekeyResponse
+pub fn respond_rekey(current_key: &[u8; 32], peer_public: [u8; 32]) -> ([u8; 32], ControlMessage) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public_key = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(peer_public));
+    let new_key = derive_rekeyed_key(current_key, shared.as_bytes());
+    (new_key, ControlMessage::RekeyResponse { public_key })
+}
+
+/// 一个会话密钥展开出的数据包密钥，及用它解密的包的接收窗口
+struct KeyEpoch {
+    keys: PacketKeys,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl KeyEpoch {
+    fn new(key: &[u8; 32]) -> Result<Arc<Self>> {
+        let keys = PacketKeys::from_session_key(key, Direction::ToServer)?;
+        Ok(Arc::new(Self { keys, replay: Mutex::new(ReplayWindow::new()) }))
+    }
+
+    /// 解密并检查序号；重复或太旧的包返回 Ok(None)
+    fn open(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (seq, plaintext) = self.keys.decrypt_packet(data)?;
+        Ok(self.replay.lock().unwrap().check(seq).then_some(plaintext))
+    }
+}
+
+/// 客户端的会话密钥环：当前密钥、轮换前的旧密钥（用于解密在途的包）、进行中的轮换和发送序号
+///
+/// 每个密钥有自己的接收窗口：重新握手后服务端的新会话从序号 0 开始发送，不能和旧会话的序号混在一个窗口里
+pub struct KeyRing {
+    current: RwLock<([u8; 32], Arc<KeyEpoch>)>,
+    previous: RwLock<Option<Arc<KeyEpoch>>>,
+    pending: Mutex<Option<StaticSecret>>,
+    /// 下一个发送序号（同时是 nonce 的一部分），密钥轮换和重新握手后继续递增
+    send_seq: AtomicU64,
+}
+
+impl KeyRing {
+    pub fn new(session_key: [u8; 32]) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new((session_key, KeyEpoch::new(&session_key)?)),
+            previous: RwLock::new(None),
+            pending: Mutex::new(None),
+            send_seq: AtomicU64::new(0),
+        })
+    }
+
+    /// 用当前密钥加密，返回带数据报头的 UDP 报文
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let epoch = self.current.read().unwrap().1.clone();
+        epoch.keys.encrypt_packet(self.send_seq.fetch_add(1, Ordering::Relaxed), plaintext)
+    }
+
+    /// 解密去掉数据报头后的 [nonce][密文]（wire::Datagram::Data）；先用当前密钥解密，失败时再尝试轮换前的旧密钥
+    ///
+    /// 通过认证但序号重复或落在接收窗口之前的包（链路复制或重放）返回 Ok(None)
+    pub fn decrypt(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let epoch = self.current.read().unwrap().1.clone();
+        match epoch.open(data) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(e) => match self.previous.read().unwrap().clone() {
+                Some(previous) => previous.open(data),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// 发起一次密钥轮换，返回要发送的 RekeyRequest
+    pub fn begin_rekey(&self) -> ControlMessage {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public_key = PublicKey::from(&secret).to_bytes();
+        *self.pending.lock().unwrap() = Some(secret);
+        ControlMessage::RekeyRequest { public_key }
+    }
+
+    /// 收到 RekeyResponse 后完成轮换
+    pub fn complete_rekey(&self, peer_public: [u8; 32]) -> Result<()> {
+        let secret = self.pending.lock().unwrap().take()
+            .ok_or_else(|| anyhow!("没有进行中的密钥轮换"))?;
+        let shared = secret.diffie_hellman(&PublicKey::from(peer_public));
+
+        let mut current = self.current.write().unwrap();
+        let new_key = derive_rekeyed_key(&current.0, shared.as_bytes());
+        let old_epoch = std::mem::replace(&mut *current, (new_key, KeyEpoch::new(&new_key)?)).1;
+        *self.previous.write().unwrap() = Some(old_epoch);
+        Ok(())
+    }
+
+    /// 当前的会话密钥（保存会话恢复缓存时使用）
+    pub fn current_key(&self) -> [u8; 32] {
+        self.current.read().unwrap().0
+    }
+
+    /// 重新握手后换成全新的会话密钥（旧密钥保留用于解密在途的包）
+    pub fn replace(&self, session_key: [u8; 32]) -> Result<()> {
+        let new_epoch = KeyEpoch::new(&session_key)?;
+        let old_epoch = std::mem::replace(&mut *self.current.write().unwrap(), (session_key, new_epoch)).1;
+        *self.previous.write().unwrap() = Some(old_epoch);
+        *self.pending.lock().unwrap() = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_and_roundtrip() {
+        assert_eq!(classify(&[0x45, 0, 0, 20]), PayloadKind::Ip);
+        assert_eq!(classify(&[0x60, 0, 0, 0]), PayloadKind::Ip);
+        assert_eq!(classify(&[KIND_PMTU, 1]), PayloadKind::Pmtu);
+        assert_eq!(classify(&[]), PayloadKind::Unknown);
+
+        let msg = ControlMessage::RoutePush { routes: vec!["192.168.10.0/24".to_string()] };
+        let data = msg.encode().unwrap();
+        assert_eq!(classify(&data), PayloadKind::Control);
+        assert_eq!(ControlMessage::decode(&data).unwrap(), msg);
+
+        let msg = ControlMessage::ObservedAddr { addr: "203.0.113.7:40123".parse().unwrap() };
+        assert_eq!(ControlMessage::decode(&msg.encode().unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_wire_compat() {
+        let messages = [
+            ControlMessage::Keepalive,
+            ControlMessage::Disconnect { reason: "replaced".to_string() },
+            ControlMessage::RoutePush { routes: vec![] },
+            ControlMessage::RoutePush { routes: vec!["10.1.0.0/16".to_string(), "fd00:1::/64".to_string()] },
+            ControlMessage::RekeyRequest { public_key: [1u8; 32] },
+            ControlMessage::RekeyResponse { public_key: [2u8; 32] },
+            ControlMessage::Echo { id: 7, timestamp_us: 1 << 40 },
+            ControlMessage::EchoReply { id: 7, timestamp_us: 1 << 40 },
+            ControlMessage::ObservedAddr { addr: "[2001:db8::1]:5000".parse().unwrap() },
+            ControlMessage::SessionTicket { id: [3u8; 16], lifetime_secs: 300 },
+            ControlMessage::Hostname { name: "laptop".to_string() },
+            ControlMessage::PeerStatus { peer: Ipv4Addr::new(10, 0, 0, 7), online: false },
+            ControlMessage::PeersRequest { id: 3 },
+            ControlMessage::PeerList { id: 3, peers: vec![], total: 0 },
+            ControlMessage::PeerList {
+                id: 4,
+                peers: vec![
+                    PeerInfo { virtual_ip: Ipv4Addr::new(10, 0, 0, 3), hostname: Some("nas".to_string()) },
+                    PeerInfo { virtual_ip: Ipv4Addr::new(10, 0, 0, 4), hostname: None },
+                ],
+                total: 70,
+            },
+            ControlMessage::ServiceRegister { services: vec![] },
+            ControlMessage::ServiceRegister { services: vec![Service::parse("ssh=tcp:22").unwrap(), Service::parse("game=udp:27015").unwrap()] },
+            ControlMessage::ServicesRequest { id: 5 },
+            ControlMessage::ServiceList { id: 5, services: vec![(Ipv4Addr::new(10, 0, 0, 3), Service::parse("web=tcp:8080").unwrap())], total: 1 },
+            ControlMessage::Metadata {
+                client: ClientMetadata { hostname: "Alice's MacBook".to_string(), os: "macos aarch64".to_string(), version: "0.1.0".to_string() },
+            },
+            ControlMessage::PskUpdated { new_id: [9u8; 8], grace_secs: 7 * 24 * 3600 },
+        ];
+        for msg in messages {
+            assert_eq!(ControlMessage::decode(&msg.encode().unwrap()).unwrap(), msg);
+        }
+
+        // v1 的编码固定不变
+        let echo = ControlMessage::Echo { id: 1, timestamp_us: 2 }.encode().unwrap();
+        assert_eq!(hex::encode(&echo), "010106010004000000010200080000000000000002");
+        assert_eq!(ControlMessage::Keepalive.encode().unwrap(), vec![KIND_CONTROL, 1, MSG_KEEPALIVE]);
+
+        // 新版本追加的字段被跳过；不认识的消息类型返回错误，由调用方忽略
+        let mut extended = echo.clone();
+        extended.extend([0x80, 0x00, 0x01, 0xff]);
+        assert_eq!(ControlMessage::decode(&extended).unwrap(), ControlMessage::Echo { id: 1, timestamp_us: 2 });
+        assert!(ControlMessage::decode(&[KIND_CONTROL, 1, 200]).is_err());
+        assert!(ControlMessage::decode(&echo[..echo.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_hostname() {
+        assert!(is_valid_hostname("nas"));
+        assert!(is_valid_hostname("build-01"));
+        for invalid in ["", "-nas", "nas-", "NAS", "nas.home", "n_a_s", &"a".repeat(64)] {
+            assert!(!is_valid_hostname(invalid), "{}", invalid);
+        }
+        assert_eq!(normalize_hostname("Alice's MacBook Pro.local").as_deref(), Some("alice-s-macbook-pro"));
+        assert_eq!(normalize_hostname("nas\n").as_deref(), Some("nas"));
+        assert_eq!(normalize_hostname("__").as_deref(), None);
+    }
+
+    #[test]
+    fn test_metadata_limits() {
+        let metadata = ClientMetadata { hostname: "build-01".to_string(), os: "linux x86_64".to_string(), version: "0.1.0".to_string() };
+        assert!(metadata.is_valid());
+        assert!(!ClientMetadata { hostname: "a".repeat(MAX_METADATA_LEN + 1), ..metadata.clone() }.is_valid());
+        assert!(!ClientMetadata { os: "linux\n📊 伪造的一行".to_string(), ..metadata }.is_valid());
+    }
+
+    #[test]
+    fn test_service() {
+        let ssh = Service::parse("ssh=tcp:22").unwrap();
+        assert_eq!((ssh.protocol, ssh.port), (6, 22));
+        assert_eq!(ssh.to_string(), "ssh=tcp:22");
+        assert_eq!(Service::parse("dns=UDP:53").unwrap().rule(), "udp:53");
+        for invalid in ["ssh", "ssh=22", "ssh=icmp:1", "ssh=tcp:0", "ssh=tcp:70000", "SSH=tcp:22", "=tcp:22"] {
+            assert!(Service::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_rtt_estimator() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), Duration::from_secs(1));
+
+        rtt.update(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(50));
+
+        rtt.update(Duration::from_millis(200));
+        // SRTT = 7/8 * 100 + 1/8 * 200 = 112.5ms
+        assert_eq!(rtt.srtt(), Some(Duration::from_micros(112_500)));
+
+        // 丢失的 Echo 推动健康状态变化并加快探测
+        assert_eq!(rtt.next_echo_interval(KEEPALIVE_INTERVAL), KEEPALIVE_INTERVAL);
+        rtt.on_echo_sent();
+        assert_eq!(rtt.health(), LinkHealth::Up);
+        assert!(rtt.next_echo_interval(KEEPALIVE_INTERVAL) < KEEPALIVE_INTERVAL);
+        assert_eq!(rtt.next_echo_interval(Duration::from_millis(100)), Duration::from_millis(100));
+        rtt.on_echo_sent();
+        assert_eq!(rtt.health(), LinkHealth::Degraded);
+        (0..2).for_each(|_| rtt.on_echo_sent());
+        assert_eq!(rtt.health(), LinkHealth::Down);
+
+        rtt.on_echo_reply(monotonic_micros());
+        assert_eq!(rtt.health(), LinkHealth::Up);
+    }
+
+    #[test]
+    fn test_rekey() {
+        let key = [7u8; 32];
+        let client = KeyRing::new(key).unwrap();
+
+        let ControlMessage::RekeyRequest { public_key } = client.begin_rekey() else { panic!() };
+        let (server_key, response) = respond_rekey(&key, public_key);
+        let ControlMessage::RekeyResponse { public_key } = response else { panic!() };
+
+        // 轮换前加密的包在轮换后仍能解密
+        let in_flight = PacketKeys::from_session_key(&key, Direction::ToClient).unwrap().encrypt_packet(7, b"old").unwrap();
+        let in_flight = &in_flight[crate::wire::PACKET_HEADER_LEN..];
+        client.complete_rekey(public_key).unwrap();
+        assert_ne!(server_key, key);
+
+        let server = PacketKeys::from_session_key(&server_key, Direction::ToClient).unwrap();
+        // encrypt 返回带数据报头的报文，序号依次递增
+        let datagram = client.encrypt(b"hello").unwrap();
+        let crate::wire::Datagram::Data(body) = crate::wire::classify(&datagram) else { panic!() };
+        assert_eq!(server.decrypt_packet(body).unwrap(), (0, b"hello".to_vec()));
+        assert_eq!(server.decrypt_packet(&client.encrypt(b"again").unwrap()[crate::wire::PACKET_HEADER_LEN..]).unwrap().0, 1);
+        assert_eq!(client.decrypt(in_flight).unwrap().as_deref(), Some(&b"old"[..]));
+        // 同一个包再次到达（链路复制或重放）时不再交付
+        assert_eq!(client.decrypt(in_flight).unwrap(), None);
+
+        // 没有进行中的轮换时拒绝响应
+        assert!(client.complete_rekey(public_key).is_err());
+
+        // 重新握手换成全新密钥，进行中的轮换作废
+        client.begin_rekey();
+        client.replace([9u8; 32]).unwrap();
+        assert!(client.complete_rekey(public_key).is_err());
+        let fresh = PacketKeys::from_session_key(&[9u8; 32], Direction::ToClient).unwrap();
+        assert_eq!(fresh.decrypt_packet(&client.encrypt(b"new").unwrap()[crate::wire::PACKET_HEADER_LEN..]).unwrap().1, b"new");
+        // 新会话的服务端从序号 0 开始发送，不受旧密钥接收窗口的影响
+        let reply = fresh.encrypt_packet(0, b"reply").unwrap();
+        assert_eq!(client.decrypt(&reply[crate::wire::PACKET_HEADER_LEN..]).unwrap().as_deref(), Some(&b"reply"[..]));
+    }
+}
@@ -0,0 +1,117 @@
+// vpn_core/src/crash.rs
+// 运行期间的任务失败：panic 或转发循环意外退出时走正常的退出清理，而不是留下半死的进程
+//
+// tokio 会吞掉 spawn 出来的任务里的 panic：以前某个任务 panic 后，其余任务照常运行，
+// TUN、路由、NAT 规则都还在，隧道却已经不通了。install 安装的 panic hook 记录第一次失败并唤醒 wait()，
+// 两个程序的退出任务（平时等待 Ctrl+C）收到后执行和 Ctrl+C 相同的清理，打印报告，以 CRASH_EXIT 退出。
+// 清理本身 panic 或超过 CLEANUP_TIMEOUT 时直接退出，不会卡住。
+//
+// 启动完成前的失败仍由启动阶段的撤销日志（见 undo 模块）处理。
+
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// 任务失败后的退出码（EX_SOFTWARE）
+pub const CRASH_EXIT: i32 = 70;
+
+/// 退出清理的最长时间
+pub const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+static REPORT: OnceLock<CrashReport> = OnceLock::new();
+static NOTIFY: Notify = Notify::const_new();
+
+/// 第一次失败的描述
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    /// panic 的消息或失败原因
+    pub what: String,
+    /// panic 的源码位置
+    pub location: Option<String>,
+    /// 发生失败的线程
+    pub thread: String,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "💥 运行期间出现致命错误，隧道无法继续工作")?;
+        writeln!(f, "   原因: {}", self.what)?;
+        if let Some(location) = &self.location {
+            writeln!(f, "   位置: {}", location)?;
+        }
+        writeln!(f, "   线程: {}", self.thread)?;
+        write!(f, "   正在恢复网络配置并退出（退出码 {}）；设置 RUST_BACKTRACE=1 重现时可以得到调用栈", CRASH_EXIT)
+    }
+}
+
+/// 安装 panic hook（保留原来的 hook，panic 的消息照常打印）
+pub fn install() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let what = info.payload().downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panic".to_string());
+        record(CrashReport { what: format!("panic: {}", what), location: info.location().map(|l| l.to_string()), thread: thread_name() });
+    }));
+}
+
+/// 报告一次不是 panic 的致命失败（例如转发循环退出）
+pub fn fail(what: impl Into<String>) {
+    record(CrashReport { what: what.into(), location: None, thread: thread_name() });
+}
+
+fn record(report: CrashReport) {
+    // 只保留第一次：之后的失败通常是它的连锁反应
+    if REPORT.set(report).is_ok() {
+        NOTIFY.notify_one();
+    }
+}
+
+fn thread_name() -> String {
+    std::thread::current().name().unwrap_or("未命名").to_string()
+}
+
+/// 等待第一次失败（已经失败过时立即返回）
+pub async fn wait() -> &'static CrashReport {
+    loop {
+        if let Some(report) = REPORT.get() {
+            return report;
+        }
+        NOTIFY.notified().await;
+    }
+}
+
+/// 等待退出清理完成后以 code 退出；清理 panic 或超时时同样退出
+///
+/// 清理任务自己调用 process::exit 时不会返回到这里
+pub async fn finish<T>(cleanup: JoinHandle<T>, code: i32) -> ! {
+    match tokio::time::timeout(CLEANUP_TIMEOUT, cleanup).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(_)) => eprintln!("⚠️  退出清理失败，部分网络配置可能需要手动恢复"),
+        Err(_) => eprintln!("⚠️  退出清理超过 {} 秒，部分网络配置可能需要手动恢复", CLEANUP_TIMEOUT.as_secs()),
+    }
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_first_failure_wakes_waiter() {
+        let waiter = tokio::spawn(wait());
+        tokio::task::yield_now().await;
+        fail("转发循环意外退出");
+        // 之后的失败不覆盖第一次
+        fail("second");
+
+        let report = waiter.await.unwrap();
+        assert_eq!(report.what, "转发循环意外退出");
+        assert_eq!(wait().await, report);
+        assert!(report.to_string().contains(&format!("退出码 {}", CRASH_EXIT)));
+    }
+}
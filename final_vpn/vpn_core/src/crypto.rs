@@ -0,0 +1,101 @@
+// vpn_core/src/crypto.rs
+// 加密后端：协议代码（symmetric::Cipher、控制通道、握手中的加密消息）只通过 AeadBackend 使用 AEAD，
+// 具体实现在编译时由 feature 选择，换用 FIPS 认证或有硬件加速的实现时不需要改协议代码。
+//
+// * rustcrypto（默认）：纯 Rust 的 chacha20poly1305
+//
+// 新增后端：实现 AeadBackend，加一个 feature 和对应的 `pub type Backend = ...`。
+// 后端只负责“给定 nonce 的加解密”，nonce 的生成和包格式仍由 symmetric 模块决定，两端可以使用不同的后端。
+
+use anyhow::Result;
+
+/// 密钥长度
+pub const KEY_SIZE: usize = 32;
+/// Nonce 长度（96 bits）
+pub const NONCE_SIZE: usize = 12;
+/// 认证标签长度
+pub const TAG_SIZE: usize = 16;
+
+/// ChaCha20-Poly1305 AEAD（RFC 8439），不使用附加数据
+pub trait AeadBackend: Send + Sync + Sized {
+    /// 后端名称，启动时打印
+    const NAME: &'static str;
+
+    fn new(key: &[u8; KEY_SIZE]) -> Self;
+
+    /// 加密，返回 密文 || 标签
+    fn seal(&self, nonce: &[u8; NONCE_SIZE], plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// 校验标签并解密
+    fn open(&self, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[cfg(not(feature = "rustcrypto"))]
+compile_error!("vpn_core 需要启用一个加密后端 feature（目前可选: rustcrypto）");
+
+/// 编译时选定的后端
+#[cfg(feature = "rustcrypto")]
+pub type Backend = rustcrypto::RustCrypto;
+
+/// 当前使用的后端名称
+pub fn backend_name() -> &'static str {
+    Backend::NAME
+}
+
+#[cfg(feature = "rustcrypto")]
+pub mod rustcrypto {
+    use anyhow::{Result, anyhow};
+    use chacha20poly1305::aead::{Aead, KeyInit};
+    use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+    use super::{AeadBackend, KEY_SIZE, NONCE_SIZE};
+
+    /// RustCrypto 的 chacha20poly1305
+    pub struct RustCrypto(ChaCha20Poly1305);
+
+    impl AeadBackend for RustCrypto {
+        const NAME: &'static str = "rustcrypto";
+
+        fn new(key: &[u8; KEY_SIZE]) -> Self {
+            Self(ChaCha20Poly1305::new(Key::from_slice(key)))
+        }
+
+        fn seal(&self, nonce: &[u8; NONCE_SIZE], plaintext: &[u8]) -> Result<Vec<u8>> {
+            self.0.encrypt(Nonce::from_slice(nonce), plaintext).map_err(|_| anyhow!("Encryption failed"))
+        }
+
+        fn open(&self, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8]) -> Result<Vec<u8>> {
+            self.0.decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|_| anyhow!("Decryption failed (invalid key or tampered data)"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 8439 附录 A.2 测试向量 #2 的前 64 字节（没有附加数据时密文部分与 ChaCha20 相同）
+    #[test]
+    fn test_backend_known_answer() {
+        let mut key = [0u8; KEY_SIZE];
+        key[31] = 1;
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[11] = 2;
+        let backend = Backend::new(&key);
+
+        let plaintext = b"Any submission to the IETF intended by the Contributor for publi";
+        let sealed = backend.seal(&nonce, plaintext).unwrap();
+        assert_eq!(sealed.len(), plaintext.len() + TAG_SIZE);
+        assert_eq!(
+            hex::encode(&sealed[..16]),
+            "a3fbf07df3fa2fde4f376ca23e827370"
+        );
+        assert_eq!(backend.open(&nonce, &sealed).unwrap(), plaintext);
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(backend.open(&nonce, &tampered).is_err());
+        assert!(Backend::new(&[0u8; KEY_SIZE]).open(&nonce, &sealed).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+// vpn_core/src/datapath_log.rs
+// 数据面日志：按方向汇总转发包数/字节数、按原因汇总丢包，定期打印一行
+//
+// 逐包日志只在 trace 级别输出（--trace 或 RUST_VPN_TRACE=1），
+// 默认情况下数据面不会逐包 println!，避免拖慢转发和刷屏。
+
+use std::collections::BTreeMap;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// 默认的汇总打印周期
+pub const DEFAULT_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 打开/关闭逐包日志
+pub fn set_trace(enabled: bool) {
+    TRACE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// 是否输出逐包日志
+pub fn trace_enabled() -> bool {
+    TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 根据命令行参数（--trace）和 RUST_VPN_TRACE 环境变量设置 trace 级别
+pub fn init_trace_from_args(args: &[String]) {
+    let from_env = std::env::var("RUST_VPN_TRACE").is_ok_and(|v| v != "0" && !v.is_empty());
+    set_trace(from_env || args.iter().any(|a| a == "--trace"));
+}
+
+/// 逐包日志：只有开启 trace 时才格式化和输出
+#[macro_export]
+macro_rules! trace_packet {
+    ($($arg:tt)*) => {
+        if $crate::datapath_log::trace_enabled() {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// 方向 -> (包数, 字节数)
+    forwarded: BTreeMap<&'static str, (u64, u64)>,
+    /// 丢包原因 -> 次数
+    dropped: BTreeMap<&'static str, u64>,
+}
+
+/// 数据面汇总计数器，可在多个任务间共享
+#[derive(Debug, Default)]
+pub struct DataPathLog {
+    counters: Mutex<Counters>,
+}
+
+impl DataPathLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一个成功转发的包
+    pub fn forwarded(&self, direction: &'static str, bytes: usize) {
+        let mut c = self.counters.lock().unwrap();
+        let entry = c.forwarded.entry(direction).or_default();
+        entry.0 += 1;
+        entry.1 += bytes as u64;
+    }
+
+    /// 记录一次丢包
+    pub fn dropped(&self, reason: &'static str) {
+        *self.counters.lock().unwrap().dropped.entry(reason).or_default() += 1;
+    }
+
+    /// 取出本周期的汇总并清零；这段时间没有任何流量时返回 None
+    pub fn take_summary(&self) -> Option<String> {
+        let c = std::mem::take(&mut *self.counters.lock().unwrap());
+        if c.forwarded.is_empty() && c.dropped.is_empty() {
+            return None;
+        }
+
+        let mut parts: Vec<String> = c.forwarded.iter()
+            .map(|(dir, (packets, bytes))| format!("{} {}包/{}B", dir, packets, bytes))
+            .collect();
+        if !c.dropped.is_empty() {
+            let drops: Vec<String> = c.dropped.iter()
+                .map(|(reason, n)| format!("{}×{}", reason, n))
+                .collect();
+            parts.push(format!("丢弃 {}", drops.join(" ")));
+        }
+        Some(parts.join(" | "))
+    }
+
+    /// 启动后台任务，每隔 interval 打印一次汇总（没有流量时不打印）
+    ///
+    /// 不使用 tokio 时可以在自己的定时器里调用 take_summary
+    #[cfg(feature = "tokio")]
+    pub fn spawn_reporter(self: &Arc<Self>, interval: Duration) {
+        let log = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Some(summary) = log.take_summary() {
+                    println!("📦 [{:?}] {}", interval, summary);
+                }
+            }
+        });
+    }
+}
+
+/// 从命令行读取汇总周期（--stats-interval <秒>），默认 1 秒
+pub fn report_interval_from_args(args: &[String]) -> Duration {
+    args.iter()
+        .position(|a| a == "--stats-interval")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_REPORT_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_aggregates_and_resets() {
+        let log = DataPathLog::new();
+        assert!(log.take_summary().is_none());
+
+        log.forwarded("uplink", 100);
+        log.forwarded("uplink", 50);
+        log.forwarded("downlink", 20);
+        log.dropped("decrypt_failed");
+        log.dropped("decrypt_failed");
+
+        let summary = log.take_summary().unwrap();
+        assert_eq!(summary, "downlink 1包/20B | uplink 2包/150B | 丢弃 decrypt_failed×2");
+        assert!(log.take_summary().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+// vpn_core/src/dryrun.rs
+// 试运行（--dry-run）：列出启动时会对系统做的修改，但不执行
+//
+// 各模块的 plan_* 函数与实际执行的函数共用同一份命令参数，打印出来的就是会执行的命令；
+// 只在 Linux 上给出完整命令，其他平台列出变更内容。试运行不创建 TUN 设备、不生成密钥、
+// 不连接服务端，只做只读查询（例如检测默认网卡），因此可以不用 sudo 运行，用来排查权限问题。
+
+/// 变更的类别（打印时按此顺序分组）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Tun,
+    Route,
+    Sysctl,
+    Firewall,
+    Dns,
+    /// 流量整形、端口映射等其他修改
+    Other,
+}
+
+impl Category {
+    fn label(self) -> &'static str {
+        match self {
+            Category::Tun => "TUN 设备",
+            Category::Route => "路由",
+            Category::Sysctl => "内核参数",
+            Category::Firewall => "防火墙",
+            Category::Dns => "DNS",
+            Category::Other => "其他",
+        }
+    }
+}
+
+/// 计划中的修改（按添加顺序保存）
+#[derive(Debug, Default)]
+pub struct Plan {
+    steps: Vec<(Category, String)>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 一条会执行的命令
+    pub fn command<S: AsRef<str>>(&mut self, category: Category, program: &str, args: &[S]) {
+        let mut line = program.to_string();
+        for arg in args {
+            line.push(' ');
+            line.push_str(arg.as_ref());
+        }
+        self.steps.push((category, line));
+    }
+
+    /// 无法用一条命令表示的修改（例如通过 ioctl 创建设备）
+    pub fn note(&mut self, category: Category, text: impl Into<String>) {
+        self.steps.push((category, format!("# {}", text.into())));
+    }
+
+    /// 某一类别下的全部条目
+    pub fn steps(&self, category: Category) -> Vec<&str> {
+        self.steps.iter().filter(|(c, _)| *c == category).map(|(_, s)| s.as_str()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!("🧪 试运行（--dry-run）：以下修改不会执行");
+        if self.is_empty() {
+            println!("   （不修改系统）");
+        }
+        let mut categories: Vec<Category> = self.steps.iter().map(|(c, _)| *c).collect();
+        categories.sort();
+        categories.dedup();
+        for category in categories {
+            println!("\n[{}]", category.label());
+            for step in self.steps(category) {
+                println!("   {}", step);
+            }
+        }
+    }
+}
+
+/// 命令行是否指定了 --dry-run
+pub fn requested(args: &[String]) -> bool {
+    args.iter().any(|a| a == "--dry-run")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let mut plan = Plan::new();
+        assert!(plan.is_empty());
+        plan.command(Category::Route, "ip", &["route", "add", "10.0.0.0/24", "dev", "tun0"]);
+        plan.note(Category::Tun, "创建 TUN 设备 tun0");
+        plan.command(Category::Route, "ip", &["-6".to_string(), "route".to_string()]);
+        assert_eq!(plan.steps(Category::Route), vec!["ip route add 10.0.0.0/24 dev tun0", "ip -6 route"]);
+        assert_eq!(plan.steps(Category::Tun), vec!["# 创建 TUN 设备 tun0"]);
+        assert!(plan.steps(Category::Firewall).is_empty());
+        assert!(requested(&["vpn_server".to_string(), "--dry-run".to_string()]));
+    }
+}
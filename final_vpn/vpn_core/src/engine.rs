@@ -0,0 +1,398 @@
+// vpn_core/src/engine.rs
+// 客户端和服务端共用的转发核心
+//
+//   TUN -> read_batch -> 截断检测 -> 去掉平台包头 -> handler.on_tun_packet（加密、选择对端、发送）
+//       -> 每批之后 handler.end_tun_batch（发出暂存的数据报，如 UDP GSO 合并发送）
+//   UDP -> recv_from + try_recv_from 批量收取 -> 截断检测 -> handler.on_datagram（解密、路由）
+//       -> 加上平台包头 -> write_batch 写入 TUN
+//   每批接收之后（以及 flush_interval 到期时）调用 handler.flush，取出暂存后放行的包一起写入
+//   两个方向各有一个 TaskProbe，看门狗发现某个方向卡住时中止并重新启动它（见 watchdog 模块）
+//
+// 会话表、路由和控制消息等与角色相关的逻辑都在 PacketHandler 里，
+// 批处理、缓冲区和帧格式等数据面优化在这里实现一次，两端同时受益。
+//
+// PacketHandler 只依赖标准库的 Future，不涉及具体运行时；TunnelEngine 基于 tokio
+// （tokio feature）。使用其他执行器时自行读写设备和 socket，用 TunFrameCodec
+// 处理帧头、tuning 推算缓冲区大小，再调用同一个 PacketHandler。
+
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+
+#[cfg(feature = "tokio")]
+use tokio::net::UdpSocket;
+
+#[cfg(feature = "tokio")]
+use crate::buffer_pool::BufferPool;
+#[cfg(feature = "tokio")]
+use crate::datapath_log::DataPathLog;
+#[cfg(feature = "tokio")]
+use crate::local_tun::{self, TunDevice, TunFrameCodec};
+#[cfg(feature = "tokio")]
+use crate::tuning::{self, Tuning};
+#[cfg(feature = "tokio")]
+use crate::watchdog::{self, Stage, TaskProbe};
+
+/// 引擎所在的一端，决定日志里的任务名
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[cfg(feature = "tokio")]
+impl Role {
+    fn tun_task(&self) -> &'static str {
+        match self {
+            Role::Client => "⬆️ 上行任务启动 (TUN -> UDP)",
+            Role::Server => "⬆️  TUN->UDP 任务启动",
+        }
+    }
+
+    fn udp_task(&self) -> &'static str {
+        match self {
+            Role::Client => "⬇️ 下行任务启动 (UDP -> TUN)",
+            Role::Server => "⬇️  UDP->TUN 任务启动",
+        }
+    }
+}
+
+/// 与角色相关的转发逻辑
+pub trait PacketHandler: Send + Sync + 'static {
+    /// 处理从 TUN 读到的 IP 包（已去掉平台包头）：选择对端、加密并发送
+    fn on_tun_packet(&self, ip_packet: &[u8]) -> impl Future<Output = ()> + Send;
+
+    /// 处理从 UDP 收到的数据报（握手、控制消息或隧道数据），返回需要写入 TUN 的 IP 包
+    fn on_datagram(&self, data: &[u8], src: SocketAddr) -> impl Future<Output = Option<Vec<u8>>> + Send;
+
+    /// 取出之前暂存、现在可以写入 TUN 的 IP 包（例如多路径重排缓冲区放行的包），默认没有
+    fn flush(&self) -> impl Future<Output = Vec<Vec<u8>>> + Send {
+        async { Vec::new() }
+    }
+
+    /// 没有新数据报时，最长隔多久调用一次 flush；None 表示只在收到数据报后调用
+    fn flush_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// 一批 TUN 包处理完之后调用，发出 on_tun_packet 暂存的数据报，默认什么也不做
+    fn end_tun_batch(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// 看门狗诊断时附带的队列深度（名称, 当前深度），默认没有
+    fn queue_depths(&self) -> Vec<(&'static str, usize)> {
+        Vec::new()
+    }
+
+    /// 看门狗重启了卡住的转发任务（task 为"上行"或"下行"），默认什么也不做；客户端借此重新握手
+    fn on_stall(&self, _task: &'static str) {}
+}
+
+/// 引擎接收数据报的 socket：tokio 的 UdpSocket，或者服务端自己的传输（如 AF_XDP）
+///
+/// 引擎只在接收循环里读；发送由 PacketHandler 负责，不经过这里
+#[cfg(feature = "tokio")]
+pub trait DatagramSocket: Send + Sync + 'static {
+    /// 等待并接收一个数据报
+    fn recv_from(&self, buf: &mut [u8]) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    /// 不等待地接收一个已经到达的数据报，没有时返回 WouldBlock
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+#[cfg(feature = "tokio")]
+impl DatagramSocket for UdpSocket {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::try_recv_from(self, buf)
+    }
+}
+
+/// 转发引擎：TUN 设备 + UDP 传输 + 角色相关的处理逻辑
+#[cfg(feature = "tokio")]
+pub struct TunnelEngine<H> {
+    role: Role,
+    handler: Arc<H>,
+    datapath: Arc<DataPathLog>,
+    codec: TunFrameCodec,
+    batch_size: usize,
+    tun_buffer_size: usize,
+    udp_buffer_size: usize,
+    watchdog: Option<Duration>,
+    uplink: TaskProbe,
+    downlink: TaskProbe,
+}
+
+#[cfg(feature = "tokio")]
+impl<H: PacketHandler> TunnelEngine<H> {
+    /// 缓冲区大小和批处理深度取自 tuning（--mtu / --batch-size）
+    pub fn new(role: Role, handler: Arc<H>, datapath: Arc<DataPathLog>, tuning: &Tuning) -> Self {
+        Self {
+            role,
+            handler,
+            datapath,
+            codec: TunFrameCodec::platform(),
+            batch_size: tuning.batch_size,
+            tun_buffer_size: tuning.tun_buffer_size(),
+            udp_buffer_size: tuning.udp_buffer_size(),
+            watchdog: tuning.watchdog,
+            uplink: TaskProbe::new("上行"),
+            downlink: TaskProbe::new("下行"),
+        }
+    }
+
+    /// 运行两个方向的转发循环，直到 TUN 设备或 socket 关闭
+    ///
+    /// 任一方向结束（或 panic）时停止另一方向并返回，不留下只有单向转发的隧道。
+    /// 设备的两半放在锁里：看门狗中止卡住的任务后，锁随任务释放，新任务接着使用
+    pub async fn run<S: DatagramSocket>(self, device: TunDevice, socket: Arc<S>) {
+        let (tun_reader, tun_writer) = tokio::io::split(device);
+        let (tun_reader, tun_writer) = (Arc::new(tokio::sync::Mutex::new(tun_reader)), Arc::new(tokio::sync::Mutex::new(tun_writer)));
+        let engine = Arc::new(self);
+        let mut uplink = tokio::spawn(engine.clone().tun_to_udp(tun_reader.clone()));
+        let mut downlink = tokio::spawn(engine.clone().udp_to_tun(socket.clone(), tun_writer.clone()));
+
+        let watching = engine.watchdog.is_some();
+        let timeout = engine.watchdog.unwrap_or(watchdog::DEFAULT_TIMEOUT);
+        // 检查间隔取超时的四分之一，卡住后最多再晚 25% 发现
+        let mut ticker = tokio::time::interval((timeout / 4).max(Duration::from_millis(10)));
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = &mut uplink => {
+                    downlink.abort();
+                    return;
+                }
+                _ = &mut downlink => {
+                    uplink.abort();
+                    return;
+                }
+                _ = ticker.tick(), if watching => {
+                    if engine.restart_if_stalled(&engine.uplink, &uplink, timeout) {
+                        uplink = tokio::spawn(engine.clone().tun_to_udp(tun_reader.clone()));
+                    }
+                    if engine.restart_if_stalled(&engine.downlink, &downlink, timeout) {
+                        downlink = tokio::spawn(engine.clone().udp_to_tun(socket.clone(), tun_writer.clone()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 任务卡住时打印诊断并中止它，返回 true 表示需要重新启动；连续重启无效时报告致命错误
+    fn restart_if_stalled(&self, probe: &TaskProbe, task: &tokio::task::JoinHandle<()>, timeout: Duration) -> bool {
+        let Some(stalled) = probe.stalled(timeout) else { return false };
+        eprintln!("{}", probe.report(stalled, &self.handler.queue_depths()));
+        let restarts = probe.note_restart();
+        if restarts > watchdog::MAX_RESTARTS {
+            crate::crash::fail(format!("{}任务连续重启 {} 次后仍然卡住", probe.name(), watchdog::MAX_RESTARTS));
+            return false;
+        }
+        eprintln!("🐕 重新启动{}任务（连续第 {}/{} 次）", probe.name(), restarts, watchdog::MAX_RESTARTS);
+        task.abort();
+        self.handler.on_stall(probe.name());
+        true
+    }
+
+    async fn tun_to_udp(self: Arc<Self>, reader: Arc<tokio::sync::Mutex<tokio::io::ReadHalf<TunDevice>>>) {
+        let mut reader = reader.lock().await;
+        let probe = &self.uplink;
+        let pool = BufferPool::new(self.tun_buffer_size, self.batch_size);
+        let mut batch = Vec::with_capacity(self.batch_size);
+        println!("{}", self.role.tun_task());
+
+        loop {
+            // 一次唤醒取走所有已就绪的包
+            probe.enter(Stage::Waiting, 0);
+            match local_tun::read_batch(&mut *reader, &pool, &mut batch, self.batch_size).await {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("❌ TUN 读取错误: {}", e);
+                    break;
+                }
+            }
+
+            probe.enter(Stage::Handling, batch.len());
+            for (buf, n) in batch.drain(..) {
+                if tuning::is_truncated(n, buf.len()) {
+                    tuning::warn_truncated("TUN", buf.len());
+                    self.datapath.dropped("tun_truncated");
+                } else if let Some(ip_packet) = self.codec.decode(&buf[..n]) {
+                    self.handler.on_tun_packet(ip_packet).await;
+                }
+                pool.put(buf);
+                probe.progress();
+            }
+            probe.enter(Stage::Flushing, 0);
+            self.handler.end_tun_batch().await;
+        }
+    }
+
+    async fn udp_to_tun<S: DatagramSocket>(self: Arc<Self>, socket: Arc<S>, writer: Arc<tokio::sync::Mutex<tokio::io::WriteHalf<TunDevice>>>) {
+        let mut writer = writer.lock().await;
+        let probe = &self.downlink;
+        let mut buf = vec![0u8; self.udp_buffer_size];
+        let mut packets = Vec::with_capacity(self.batch_size);
+        println!("{}", self.role.udp_task());
+
+        let flush_interval = self.handler.flush_interval();
+
+        loop {
+            probe.enter(Stage::Waiting, 0);
+            let received = match flush_interval {
+                Some(interval) => tokio::time::timeout(interval, socket.recv_from(&mut buf)).await.ok(),
+                None => Some(socket.recv_from(&mut buf).await),
+            };
+            match received {
+                Some(Ok((n, src))) => {
+                    probe.enter(Stage::Handling, 1);
+                    self.accept(&buf[..n], buf.len(), src, &mut packets).await;
+                    probe.progress();
+
+                    // 把 socket 里已经到达的包一起取出来，批量写入 TUN
+                    while packets.len() < self.batch_size {
+                        let Ok((n, src)) = socket.try_recv_from(&mut buf) else { break };
+                        self.accept(&buf[..n], buf.len(), src, &mut packets).await;
+                        probe.progress();
+                    }
+                }
+                Some(Err(e)) => {
+                    // Windows 上对端不可达的 ICMP 会以接收错误的形式返回，不能因此退出
+                    eprintln!("❌ UDP 接收错误: {}", e);
+                    continue;
+                }
+                // 等待超时，只处理暂存的包
+                None => {}
+            }
+            probe.enter(Stage::Flushing, 0);
+            for ip_packet in self.handler.flush().await {
+                packets.push(self.codec.encode(ip_packet));
+            }
+
+            if !packets.is_empty() {
+                probe.enter(Stage::Writing, packets.len());
+                if let Err(e) = local_tun::write_batch(&mut *writer, &packets).await {
+                    eprintln!("❌ TUN 写入错误: {}", e);
+                    self.datapath.dropped("tun_write_failed");
+                }
+                packets.clear();
+            }
+        }
+    }
+
+    async fn accept(&self, data: &[u8], buf_len: usize, src: SocketAddr, packets: &mut Vec<Vec<u8>>) {
+        // 超过缓冲区的数据报已被内核截断，解密必然失败
+        if tuning::is_truncated(data.len(), buf_len) {
+            tuning::warn_truncated("UDP", buf_len);
+            self.datapath.dropped("udp_truncated");
+            return;
+        }
+        if let Some(ip_packet) = self.handler.on_datagram(data, src).await {
+            packets.push(self.codec.encode(ip_packet));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// 上行原样发给 peer，下行原样写回 TUN
+    struct Loopback {
+        socket: Arc<UdpSocket>,
+        peer: SocketAddr,
+    }
+
+    impl PacketHandler for Loopback {
+        async fn on_tun_packet(&self, ip_packet: &[u8]) {
+            let _ = self.socket.send_to(ip_packet, self.peer).await;
+        }
+
+        async fn on_datagram(&self, data: &[u8], _src: SocketAddr) -> Option<Vec<u8>> {
+            Some(data.to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_engine_forwards_both_directions() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let handler = Arc::new(Loopback { socket: socket.clone(), peer: peer.local_addr().unwrap() });
+        let (device, mut tun) = tokio::io::duplex(64 * 1024);
+        let engine = TunnelEngine::new(Role::Client, handler, Arc::new(DataPathLog::new()), &Tuning::default());
+        let engine_addr = socket.local_addr().unwrap();
+        tokio::spawn(engine.run(Box::new(device), socket));
+
+        let codec = TunFrameCodec::platform();
+        let mut buf = [0u8; 64];
+
+        // TUN -> UDP：平台包头被去掉
+        tun.write_all(&codec.encode(vec![0x45, 1, 2, 3])).await.unwrap();
+        let (n, _) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], [0x45, 1, 2, 3]);
+
+        // UDP -> TUN：补上平台包头
+        peer.send_to(&[0x45, 9, 9], engine_addr).await.unwrap();
+        let n = tun.read(&mut buf).await.unwrap();
+        assert_eq!(codec.decode(&buf[..n]), Some(&[0x45, 9, 9][..]));
+    }
+
+    /// 第一个上行包永远处理不完，之后正常转发
+    struct StuckOnce {
+        inner: Loopback,
+        stuck: std::sync::atomic::AtomicBool,
+        stalls: std::sync::atomic::AtomicUsize,
+    }
+
+    impl PacketHandler for StuckOnce {
+        async fn on_tun_packet(&self, ip_packet: &[u8]) {
+            if !self.stuck.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                std::future::pending::<()>().await;
+            }
+            self.inner.on_tun_packet(ip_packet).await;
+        }
+
+        async fn on_datagram(&self, data: &[u8], src: SocketAddr) -> Option<Vec<u8>> {
+            self.inner.on_datagram(data, src).await
+        }
+
+        fn on_stall(&self, task: &'static str) {
+            assert_eq!(task, "上行");
+            self.stalls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watchdog_restarts_stuck_task() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let inner = Loopback { socket: socket.clone(), peer: peer.local_addr().unwrap() };
+        let handler = Arc::new(StuckOnce { inner, stuck: Default::default(), stalls: Default::default() });
+        let (device, mut tun) = tokio::io::duplex(64 * 1024);
+        let tuning = Tuning { watchdog: Some(Duration::from_millis(100)), ..Tuning::default() };
+        let engine = TunnelEngine::new(Role::Client, handler.clone(), Arc::new(DataPathLog::new()), &tuning);
+        tokio::spawn(engine.run(Box::new(device), socket));
+
+        let codec = TunFrameCodec::platform();
+        tun.write_all(&codec.encode(vec![0x45, 1])).await.unwrap();
+        // 让第一个包先卡住，再写第二个
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tun.write_all(&codec.encode(vec![0x45, 2])).await.unwrap();
+
+        // 看门狗重启上行任务后，第二个包照常发出（卡住的那一批已随旧任务丢弃）
+        let mut buf = [0u8; 64];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(5), peer.recv_from(&mut buf)).await.expect("上行任务没有被重启").unwrap();
+        assert_eq!(&buf[..n], [0x45, 2]);
+        assert_eq!(handler.stalls.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+}
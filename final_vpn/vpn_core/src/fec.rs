@@ -0,0 +1,313 @@
+// vpn_core/src/fec.rs
+// 前向纠错（--fec <分组大小>）：有损链路上不等重传就能恢复丢失的包
+//
+// 每 K 个 IP 包为一组，发送端在组内最后一个包之后多发一个校验包（组内各包按字节异或），
+// 接收端收到校验包时，如果组内恰好缺一个包，就用校验包和其余 K-1 个包把它还原出来。
+// 数据包照常立即交付，不增加延迟；代价是 1/K 的额外带宽（K=4 时 25%），
+// 能抵御每组一个包的随机丢包，适合游戏、语音等对重传延迟敏感的流量在 1~3% 丢包下的表现。
+//
+// 明文格式（与 IP 包、control 模块的消息靠首字节区分）：
+//
+//   数据包: [0x03][组号 u32][组内序号 u8][IP 包]
+//   校验包: [0x04][组号 u32][组内包数 u8][各包长度异或 u16][各包内容异或（按最长的包补零）]
+//
+// 分组大小在握手时协商：客户端在 ClientHello / Resume 中请求，服务端在 ServerHello / ResumeAck 中
+// 给出接受的值（服务端 --no-fec 时不接受），之后两个方向都按这个分组大小编码。
+// 校验包只在一组凑满时发出，流量停顿时最后一个未满的组不受保护。
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{Result, anyhow};
+
+/// FEC 数据包明文的首字节
+pub const KIND_FEC_DATA: u8 = 0x03;
+/// FEC 校验包明文的首字节
+pub const KIND_FEC_PARITY: u8 = 0x04;
+
+pub const DEFAULT_GROUP_SIZE: u8 = 4;
+pub const MIN_GROUP_SIZE: u8 = 2;
+pub const MAX_GROUP_SIZE: u8 = 16;
+
+/// 接收端保留最近多少组（更早的组不再能恢复）
+const WINDOW: u32 = 16;
+
+const DATA_HEADER: usize = 6;
+const PARITY_HEADER: usize = 8;
+
+/// 解析 --fec 的分组大小
+pub fn parse_group_size(value: &str) -> Result<u8> {
+    match value.parse::<u8>() {
+        Ok(k) if (MIN_GROUP_SIZE..=MAX_GROUP_SIZE).contains(&k) => Ok(k),
+        _ => Err(anyhow!("无效的 --fec: {}（分组大小 {} ~ {}）", value, MIN_GROUP_SIZE, MAX_GROUP_SIZE)),
+    }
+}
+
+/// 服务端决定接受的分组大小：未启用或客户端未请求时为 None，超出范围的请求调整到范围内
+pub fn negotiate(requested: Option<u8>, enabled: bool) -> Option<u8> {
+    requested.filter(|_| enabled).map(|k| k.clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE))
+}
+
+/// 编码一个 IP 包的结果：数据包，以及一组凑满时的校验包（都是明文，各自加密发送）
+#[derive(Debug, Clone, PartialEq)]
+pub struct FecFrames {
+    pub data: Vec<u8>,
+    pub parity: Option<Vec<u8>>,
+}
+
+/// 发送端
+#[derive(Debug)]
+pub struct FecEncoder {
+    group_size: u8,
+    group: u32,
+    index: u8,
+    parity: Vec<u8>,
+    len_xor: u16,
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u8) -> Self {
+        Self { group_size, group: 0, index: 0, parity: Vec::new(), len_xor: 0 }
+    }
+
+    pub fn encode(&mut self, ip_packet: &[u8]) -> FecFrames {
+        let mut data = Vec::with_capacity(DATA_HEADER + ip_packet.len());
+        data.push(KIND_FEC_DATA);
+        data.extend(self.group.to_be_bytes());
+        data.push(self.index);
+        data.extend(ip_packet);
+
+        xor_into(&mut self.parity, ip_packet);
+        self.len_xor ^= ip_packet.len() as u16;
+        self.index += 1;
+        if self.index < self.group_size {
+            return FecFrames { data, parity: None };
+        }
+
+        let mut parity = Vec::with_capacity(PARITY_HEADER + self.parity.len());
+        parity.push(KIND_FEC_PARITY);
+        parity.extend(self.group.to_be_bytes());
+        parity.push(self.index);
+        parity.extend(self.len_xor.to_be_bytes());
+        parity.append(&mut self.parity);
+        self.group = self.group.wrapping_add(1);
+        self.index = 0;
+        self.len_xor = 0;
+        FecFrames { data, parity: Some(parity) }
+    }
+}
+
+/// 接收端一组的状态
+#[derive(Debug, Default)]
+struct Group {
+    /// 已收到（或已恢复）的组内序号
+    seen: u32,
+    packets: Vec<Vec<u8>>,
+}
+
+/// 接收端
+#[derive(Debug, Default)]
+pub struct FecDecoder {
+    groups: HashMap<u32, Group>,
+    latest: Option<u32>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 处理一个 FEC 数据包或校验包的明文，返回需要交付的 IP 包
+    ///
+    /// 数据包总是立即交付（已经靠校验包恢复过的重复包除外）；校验包只在恰好缺一个包时返回恢复出的包
+    pub fn receive(&mut self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        let (&kind, rest) = plaintext.split_first()?;
+        let (group, rest) = rest.split_first_chunk::<4>()?;
+        let group = u32::from_be_bytes(*group);
+        let (&index, rest) = rest.split_first()?;
+        if (kind == KIND_FEC_DATA && index >= MAX_GROUP_SIZE) || index > MAX_GROUP_SIZE {
+            return None;
+        }
+
+        // 窗口之外的旧组：数据包直接交付，校验包丢弃
+        if let Some(latest) = self.latest
+            && latest.wrapping_sub(group) < u32::MAX / 2
+            && latest.wrapping_sub(group) >= WINDOW
+        {
+            return (kind == KIND_FEC_DATA).then(|| rest.to_vec());
+        }
+        if self.latest.is_none_or(|latest| group.wrapping_sub(latest).wrapping_sub(1) < u32::MAX / 2) {
+            self.latest = Some(group);
+            self.groups.retain(|g, _| group.wrapping_sub(*g) < WINDOW);
+        }
+        let entry = self.groups.entry(group).or_default();
+
+        match kind {
+            KIND_FEC_DATA => {
+                let bit = 1 << index;
+                if entry.seen & bit != 0 {
+                    return None;
+                }
+                entry.seen |= bit;
+                entry.packets.push(rest.to_vec());
+                Some(rest.to_vec())
+            }
+            KIND_FEC_PARITY => {
+                let count = index as u32;
+                let (len_xor, parity) = rest.split_first_chunk::<2>()?;
+                let missing: Vec<u32> = (0..count).filter(|i| entry.seen & (1 << i) == 0).collect();
+                let [missing] = missing[..] else { return None };
+
+                let mut packet = parity.to_vec();
+                let mut len = u16::from_be_bytes(*len_xor);
+                for received in &entry.packets {
+                    xor_into(&mut packet, received);
+                    len ^= received.len() as u16;
+                }
+                if len as usize > packet.len() {
+                    return None;
+                }
+                packet.truncate(len as usize);
+                entry.seen |= 1 << missing;
+                self.recovered += 1;
+                Some(packet)
+            }
+            _ => None,
+        }
+    }
+
+    /// 累计恢复的包数
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+}
+
+/// 一个会话两个方向的编解码状态
+#[derive(Debug)]
+pub struct Fec {
+    pub group_size: u8,
+    pub encoder: FecEncoder,
+    pub decoder: FecDecoder,
+}
+
+impl Fec {
+    pub fn new(group_size: u8) -> Self {
+        Self { group_size, encoder: FecEncoder::new(group_size), decoder: FecDecoder::new() }
+    }
+}
+
+/// 客户端使用的 FEC 状态：请求的分组大小，以及（每次握手后更新的）协商结果
+#[derive(Debug)]
+pub struct FecLink {
+    requested: Option<u8>,
+    active: Mutex<Option<Fec>>,
+}
+
+impl FecLink {
+    pub fn new(requested: Option<u8>) -> Self {
+        Self { requested, active: Mutex::new(None) }
+    }
+
+    pub fn requested(&self) -> Option<u8> {
+        self.requested
+    }
+
+    /// 握手完成后按服务端接受的分组大小重置编解码状态（None 表示不使用 FEC）
+    pub fn apply(&self, negotiated: Option<u8>) {
+        *self.active.lock().unwrap() = negotiated.map(Fec::new);
+    }
+
+    pub fn group_size(&self) -> Option<u8> {
+        self.active.lock().unwrap().as_ref().map(|fec| fec.group_size)
+    }
+
+    /// 未启用时返回 None，调用方直接发送原始 IP 包
+    pub fn encode(&self, ip_packet: &[u8]) -> Option<FecFrames> {
+        self.active.lock().unwrap().as_mut().map(|fec| fec.encoder.encode(ip_packet))
+    }
+
+    pub fn decode(&self, plaintext: &[u8]) -> Option<Vec<u8>> {
+        self.active.lock().unwrap().as_mut()?.decoder.receive(plaintext)
+    }
+
+    pub fn recovered(&self) -> u64 {
+        self.active.lock().unwrap().as_ref().map_or(0, |fec| fec.decoder.recovered())
+    }
+}
+
+/// dst ^= src，dst 比 src 短时先补零
+fn xor_into(dst: &mut Vec<u8>, src: &[u8]) {
+    if dst.len() < src.len() {
+        dst.resize(src.len(), 0);
+    }
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover_one_loss_per_group() {
+        assert_eq!(parse_group_size("4").unwrap(), 4);
+        assert!(parse_group_size("1").is_err());
+        assert_eq!(negotiate(Some(32), true), Some(MAX_GROUP_SIZE));
+        assert_eq!(negotiate(Some(4), false), None);
+
+        let packets: Vec<Vec<u8>> = vec![vec![0x45, 1, 2, 3, 4], vec![0x45, 9], vec![0x45, 7, 7, 7, 7, 7, 7], vec![0x45]];
+        let mut encoder = FecEncoder::new(4);
+        let frames: Vec<FecFrames> = packets.iter().map(|p| encoder.encode(p)).collect();
+        assert!(frames[..3].iter().all(|f| f.parity.is_none()));
+        let parity = frames[3].parity.clone().unwrap();
+
+        // 丢掉组内任意一个包，都能用校验包恢复，长度也正确
+        for lost in 0..4 {
+            let mut decoder = FecDecoder::new();
+            for (i, frame) in frames.iter().enumerate().filter(|(i, _)| *i != lost) {
+                assert_eq!(decoder.receive(&frame.data), Some(packets[i].clone()));
+            }
+            assert_eq!(decoder.receive(&parity), Some(packets[lost].clone()));
+            // 恢复之后才到的原包不再交付
+            assert_eq!(decoder.receive(&frames[lost].data), None);
+            assert_eq!(decoder.recovered(), 1);
+        }
+
+        // 没有丢包或丢了两个包时，校验包不产生输出
+        let mut decoder = FecDecoder::new();
+        for frame in &frames {
+            decoder.receive(&frame.data);
+        }
+        assert_eq!(decoder.receive(&parity), None);
+        let mut decoder = FecDecoder::new();
+        decoder.receive(&frames[0].data);
+        decoder.receive(&frames[1].data);
+        assert_eq!(decoder.receive(&parity), None);
+    }
+
+    #[test]
+    fn test_window_and_link() {
+        let mut encoder = FecEncoder::new(2);
+        let frames: Vec<FecFrames> = (0..40u8).map(|i| encoder.encode(&[0x45, i])).collect();
+        let mut decoder = FecDecoder::new();
+        for frame in &frames[2..] {
+            decoder.receive(&frame.data);
+        }
+        // 窗口之外的旧组：数据包直接交付，校验包无法使用
+        assert_eq!(decoder.receive(&frames[0].data), Some(vec![0x45, 0]));
+        assert_eq!(decoder.receive(frames[1].parity.as_ref().unwrap()), None);
+        assert!(decoder.groups.len() <= WINDOW as usize);
+
+        // 未协商时不编码；协商后按分组大小编码
+        let link = FecLink::new(Some(3));
+        assert_eq!(link.encode(&[0x45]), None);
+        link.apply(Some(3));
+        assert_eq!(link.group_size(), Some(3));
+        let frames = link.encode(&[0x45, 1]).unwrap();
+        assert_eq!(link.decode(&frames.data), Some(vec![0x45, 1]));
+        link.apply(None);
+        assert_eq!(link.decode(&frames.data), None);
+    }
+}
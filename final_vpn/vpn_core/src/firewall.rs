@@ -0,0 +1,479 @@
+// vpn_core/src/firewall.rs
+// 内层流量的协议/端口规则，以及客户端的入站防火墙
+//
+// * Allowlist：协议/端口白名单，服务端用它限制客户端能访问的服务（--allow），
+//   客户端用它声明愿意对隧道开放的本机服务（--expose）
+// * InboundFirewall：客户端的轻量级连接跟踪。隧道内的其他客户端和服务端都能直接访问客户端的虚拟 IP，
+//   默认只放行本机主动发起的连接的回包（established）和与之相关的 ICMP 差错报文（related），
+//   其余入站包除非命中 --expose 的规则，否则丢弃
+//
+// 规则格式与 gateway 的 --host-services 一致：`tcp:22`、`udp:5000-5100`、`8080`（省略协议为 tcp）、
+// `tcp`（整个协议）、`icmp`（同时匹配 ICMPv6），逗号分隔；`all` 表示不限制。
+//
+// 分片：之后的分片没有端口信息，Allowlist 只检查协议，防火墙直接放行
+// （首个分片已经过检查，缺少首个分片的包无法被重组）。
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_IPV6_FRAGMENT: u8 = 44;
+const PROTO_ICMPV6: u8 = 58;
+
+/// 连接跟踪表的最大条目数，满了以后淘汰最久未活动的连接
+pub const MAX_CONNECTIONS: usize = 16384;
+/// TCP 连接的空闲超时
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// UDP、ICMP 等其他协议的空闲超时
+pub const OTHER_IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// 单条规则：协议 + 可选的端口范围
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    protocol: u8,
+    ports: Option<RangeInclusive<u16>>,
+}
+
+impl Rule {
+    fn parse(spec: &str) -> Result<Self> {
+        let (proto, ports) = match spec.split_once(':') {
+            Some((proto, ports)) => (proto.to_ascii_lowercase(), Some(ports)),
+            None if spec.starts_with(|c: char| c.is_ascii_digit()) => ("tcp".to_string(), Some(spec)),
+            None => (spec.to_ascii_lowercase(), None),
+        };
+        let protocol = match proto.as_str() {
+            "tcp" => PROTO_TCP,
+            "udp" => PROTO_UDP,
+            "icmp" | "icmpv6" if ports.is_none() => PROTO_ICMP,
+            "icmp" | "icmpv6" => return Err(anyhow!("icmp 规则不能指定端口: {}", spec)),
+            _ => return Err(anyhow!("不支持的协议: {}（tcp、udp 或 icmp）", spec)),
+        };
+        let ports = match ports {
+            None => None,
+            Some(ports) => {
+                let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+                let start: u16 = start.parse().map_err(|_| anyhow!("无效的端口: {}", spec))?;
+                let end: u16 = end.parse().map_err(|_| anyhow!("无效的端口: {}", spec))?;
+                if start > end {
+                    return Err(anyhow!("端口范围起点大于终点: {}", spec));
+                }
+                Some(start..=end)
+            }
+        };
+        Ok(Self { protocol, ports })
+    }
+
+    fn matches_protocol(&self, protocol: u8) -> bool {
+        let protocol = if protocol == PROTO_ICMPV6 { PROTO_ICMP } else { protocol };
+        protocol == self.protocol
+    }
+
+    fn matches(&self, protocol: u8, port: Option<u16>) -> bool {
+        self.matches_protocol(protocol)
+            && match (&self.ports, port) {
+                (None, _) => true,
+                (Some(range), Some(port)) => range.contains(&port),
+                (Some(_), None) => false,
+            }
+    }
+}
+
+/// 一组规则；rules 为 None 表示不限制
+#[derive(Debug, Clone, PartialEq)]
+pub struct Allowlist {
+    rules: Option<Vec<Rule>>,
+}
+
+impl Allowlist {
+    /// 解析逗号分隔的规则列表，`all` 表示不限制
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec.trim().eq_ignore_ascii_case("all") {
+            return Ok(Self::unrestricted());
+        }
+        let rules = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Rule::parse)
+            .collect::<Result<Vec<_>>>()?;
+        if rules.is_empty() {
+            return Err(anyhow!("白名单为空: {}", spec));
+        }
+        Ok(Self { rules: Some(rules) })
+    }
+
+    /// 不限制任何流量
+    pub fn unrestricted() -> Self {
+        Self { rules: None }
+    }
+
+    /// 不放行任何流量
+    pub fn empty() -> Self {
+        Self { rules: Some(Vec::new()) }
+    }
+
+    pub fn is_unrestricted(&self) -> bool {
+        self.rules.is_none()
+    }
+
+    /// 包是否被放行（按目的端口匹配）；match_source 为 true 时源端口匹配也算放行（回包）
+    pub fn allows(&self, packet: &[u8], match_source: bool) -> bool {
+        let Some(rules) = &self.rules else {
+            return true;
+        };
+        let Some(info) = TransportInfo::parse(packet) else {
+            return false;
+        };
+        rules.iter().any(|rule| {
+            if info.fragment {
+                return rule.matches_protocol(info.protocol);
+            }
+            rule.matches(info.protocol, info.dst_port) || (match_source && rule.matches(info.protocol, info.src_port))
+        })
+    }
+}
+
+/// 过滤和连接跟踪需要的内层包信息
+struct TransportInfo<'a> {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+    /// 非首个分片（没有传输层头部）
+    fragment: bool,
+    /// 传输层头部及之后的数据
+    l4: &'a [u8],
+}
+
+impl<'a> TransportInfo<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (src, dst, mut protocol, mut fragment, mut l4) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let ihl = ((packet[0] & 0x0f) as usize) * 4;
+                let src = IpAddr::V4(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]));
+                let dst = IpAddr::V4(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]));
+                let fragment = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0;
+                (src, dst, packet[9], fragment, packet.get(ihl..)?)
+            }
+            6 if packet.len() >= 40 => {
+                let src = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?));
+                let dst = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?));
+                (src, dst, packet[6], false, &packet[40..])
+            }
+            _ => return None,
+        };
+        // IPv6 分片头：取出真正的上层协议，非首个分片没有端口
+        if src.is_ipv6() && protocol == PROTO_IPV6_FRAGMENT {
+            let header = l4.get(..8)?;
+            protocol = header[0];
+            fragment = u16::from_be_bytes([header[2], header[3]]) & 0xfff8 != 0;
+            l4 = &l4[8..];
+        }
+        let (src_port, dst_port) = match protocol {
+            PROTO_TCP | PROTO_UDP if !fragment && l4.len() >= 4 => (
+                Some(u16::from_be_bytes([l4[0], l4[1]])),
+                Some(u16::from_be_bytes([l4[2], l4[3]])),
+            ),
+            _ => (None, None),
+        };
+        Some(Self { src, dst, protocol, src_port, dst_port, fragment, l4 })
+    }
+
+    /// ICMP / ICMPv6 的类型
+    fn icmp_type(&self) -> Option<u8> {
+        matches!(self.protocol, PROTO_ICMP | PROTO_ICMPV6).then(|| self.l4.first().copied()).flatten()
+    }
+
+    /// ICMP 回显请求/应答的标识符
+    fn echo_id(&self) -> Option<u16> {
+        match (self.protocol, self.icmp_type()?) {
+            (PROTO_ICMP, 0 | 8) | (PROTO_ICMPV6, 128 | 129) => self.l4.get(4..6).map(|id| u16::from_be_bytes([id[0], id[1]])),
+            _ => None,
+        }
+    }
+
+    /// ICMP 差错报文（目的不可达、超时、参数问题等）中引用的原始包
+    fn quoted_packet(&self) -> Option<&'a [u8]> {
+        let is_error = match self.protocol {
+            PROTO_ICMP => matches!(self.icmp_type()?, 3 | 4 | 5 | 11 | 12),
+            PROTO_ICMPV6 => self.icmp_type()? < 128,
+            _ => false,
+        };
+        if is_error { self.l4.get(8..) } else { None }
+    }
+}
+
+/// 从本机视角标识一条连接
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ConnKey {
+    protocol: u8,
+    local: IpAddr,
+    local_port: u16,
+    remote: IpAddr,
+    remote_port: u16,
+}
+
+impl ConnKey {
+    /// 出站包（本机 -> 远端）对应的连接
+    fn outbound(info: &TransportInfo) -> Self {
+        let (local_port, remote_port) = match info.echo_id() {
+            Some(id) => (id, 0),
+            None => (info.src_port.unwrap_or(0), info.dst_port.unwrap_or(0)),
+        };
+        Self { protocol: info.protocol, local: info.src, local_port, remote: info.dst, remote_port }
+    }
+
+    /// 入站包（远端 -> 本机）对应的连接
+    fn inbound(info: &TransportInfo) -> Self {
+        let (local_port, remote_port) = match info.echo_id() {
+            Some(id) => (id, 0),
+            None => (info.dst_port.unwrap_or(0), info.src_port.unwrap_or(0)),
+        };
+        Self { protocol: info.protocol, local: info.dst, local_port, remote: info.src, remote_port }
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        if self.protocol == PROTO_TCP { TCP_IDLE_TIMEOUT } else { OTHER_IDLE_TIMEOUT }
+    }
+}
+
+/// 入站检查的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// 本机发起的连接的回包
+    Established,
+    /// 与本机发起的连接相关的 ICMP 差错报文
+    Related,
+    /// 命中 --expose 的规则
+    Exposed,
+    /// 不是任何已知连接的回包，丢弃
+    Unsolicited,
+}
+
+impl Verdict {
+    pub fn allowed(&self) -> bool {
+        *self != Verdict::Unsolicited
+    }
+}
+
+/// 客户端入站防火墙：出站包登记连接，入站包只放行已登记连接的回包和开放的服务
+pub struct InboundFirewall {
+    exposed: Allowlist,
+    connections: HashMap<ConnKey, Instant>,
+}
+
+impl InboundFirewall {
+    /// exposed 为愿意对隧道开放的本机服务
+    pub fn new(exposed: Allowlist) -> Self {
+        Self { exposed, connections: HashMap::new() }
+    }
+
+    /// 登记一个出站包
+    pub fn record_outbound(&mut self, packet: &[u8]) {
+        let Some(info) = TransportInfo::parse(packet) else { return };
+        // 非首个分片和本机发出的 ICMP 差错报文不建立连接
+        if info.fragment || info.quoted_packet().is_some() {
+            return;
+        }
+        let key = ConnKey::outbound(&info);
+        let now = Instant::now();
+        if self.connections.len() >= MAX_CONNECTIONS && !self.connections.contains_key(&key) {
+            self.evict(now);
+        }
+        self.connections.insert(key, now);
+    }
+
+    /// 检查一个入站包
+    pub fn check_inbound(&mut self, packet: &[u8]) -> Verdict {
+        let Some(info) = TransportInfo::parse(packet) else {
+            return Verdict::Unsolicited;
+        };
+        if info.fragment {
+            return Verdict::Established;
+        }
+        let now = Instant::now();
+
+        // ICMP 差错报文引用的是本机发出的包，按出站方向查找
+        if let Some(quoted) = info.quoted_packet() {
+            let related = TransportInfo::parse(quoted).is_some_and(|q| self.is_live(&ConnKey::outbound(&q), now));
+            return if related { Verdict::Related } else { self.exposed_verdict(packet) };
+        }
+
+        // 入站的回显请求不是回包，只能由 --expose icmp 放行
+        let echo_request = matches!((info.protocol, info.icmp_type()), (PROTO_ICMP, Some(8)) | (PROTO_ICMPV6, Some(128)));
+        let key = ConnKey::inbound(&info);
+        if !echo_request && self.is_live(&key, now) {
+            self.connections.insert(key, now);
+            return Verdict::Established;
+        }
+        self.exposed_verdict(packet)
+    }
+
+    /// 对端下线时清除与它的全部连接，返回清除的条数
+    pub fn forget_peer(&mut self, peer: IpAddr) -> usize {
+        let before = self.connections.len();
+        self.connections.retain(|key, _| key.remote != peer);
+        before - self.connections.len()
+    }
+
+    /// 当前跟踪的连接数
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    fn exposed_verdict(&self, packet: &[u8]) -> Verdict {
+        if self.exposed.allows(packet, false) { Verdict::Exposed } else { Verdict::Unsolicited }
+    }
+
+    fn is_live(&self, key: &ConnKey, now: Instant) -> bool {
+        self.connections.get(key).is_some_and(|last| now.duration_since(*last) < key.idle_timeout())
+    }
+
+    /// 表满时清理超时的连接，仍然满则淘汰最久未活动的一条
+    fn evict(&mut self, now: Instant) {
+        self.connections.retain(|key, last| now.duration_since(*last) < key.idle_timeout());
+        if self.connections.len() >= MAX_CONNECTIONS
+            && let Some(oldest) = self.connections.iter().min_by_key(|(_, last)| **last).map(|(key, _)| *key)
+        {
+            self.connections.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4(protocol: u8, src: [u8; 4], dst: [u8; 4], l4: &[u8]) -> Vec<u8> {
+        let mut p = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, protocol, 0, 0];
+        p.extend(src);
+        p.extend(dst);
+        p.extend(l4);
+        let len = p.len() as u16;
+        p[2..4].copy_from_slice(&len.to_be_bytes());
+        p
+    }
+
+    fn udp(src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16) -> Vec<u8> {
+        let mut l4 = src_port.to_be_bytes().to_vec();
+        l4.extend(dst_port.to_be_bytes());
+        l4.extend([0, 8, 0, 0]);
+        ipv4(PROTO_UDP, src, dst, &l4)
+    }
+
+    fn tcp(src: [u8; 4], src_port: u16, dst: [u8; 4], dst_port: u16) -> Vec<u8> {
+        let mut p = udp(src, src_port, dst, dst_port);
+        p[9] = PROTO_TCP;
+        p.extend([0u8; 12]);
+        p
+    }
+
+    fn echo(kind: u8, src: [u8; 4], dst: [u8; 4], id: u16) -> Vec<u8> {
+        let mut l4 = vec![kind, 0, 0, 0];
+        l4.extend(id.to_be_bytes());
+        l4.extend([0, 1]);
+        ipv4(PROTO_ICMP, src, dst, &l4)
+    }
+
+    const LOCAL: [u8; 4] = [10, 0, 0, 2];
+    const PEER: [u8; 4] = [10, 0, 0, 3];
+    const REMOTE: [u8; 4] = [1, 1, 1, 1];
+
+    #[test]
+    fn test_allowlist() {
+        let list = Allowlist::parse("udp:53, tcp:443, tcp:8000-8100, 8443, icmp").unwrap();
+        assert!(list.allows(&udp(LOCAL, 40000, REMOTE, 53), false));
+        assert!(list.allows(&tcp(LOCAL, 40000, REMOTE, 443), false));
+        assert!(list.allows(&tcp(LOCAL, 40000, REMOTE, 8050), false));
+        assert!(list.allows(&tcp(LOCAL, 40000, REMOTE, 8443), false));
+        assert!(list.allows(&echo(8, LOCAL, REMOTE, 1), false));
+        assert!(!list.allows(&tcp(LOCAL, 40000, REMOTE, 22), false));
+        assert!(!list.allows(&udp(LOCAL, 40000, REMOTE, 443), false));
+        assert!(!list.allows(&ipv4(47, LOCAL, REMOTE, &[0; 4]), false));
+        assert!(!list.allows(&[0u8; 4], false));
+
+        // 回包只在允许按源端口匹配时放行
+        assert!(!list.allows(&tcp(LOCAL, 443, PEER, 40000), false));
+        assert!(list.allows(&tcp(LOCAL, 443, PEER, 40000), true));
+
+        // 非首个分片按协议放行
+        let mut fragment = udp(LOCAL, 0x1234, REMOTE, 0x5678);
+        fragment[6..8].copy_from_slice(&0x0010u16.to_be_bytes());
+        assert!(list.allows(&fragment, false));
+        fragment[9] = 47;
+        assert!(!list.allows(&fragment, false));
+
+        assert!(Allowlist::parse("all").unwrap().allows(&ipv4(47, LOCAL, REMOTE, &[0; 4]), false));
+        assert!(!Allowlist::empty().allows(&udp(LOCAL, 40000, REMOTE, 53), false));
+        assert!(Allowlist::parse("icmp:1").is_err());
+        assert!(Allowlist::parse("tcp:443-80").is_err());
+        assert!(Allowlist::parse("tcp:99999").is_err());
+        assert!(Allowlist::parse("sctp").is_err());
+        assert!(Allowlist::parse("").is_err());
+    }
+
+    #[test]
+    fn test_inbound_firewall() {
+        let mut fw = InboundFirewall::new(Allowlist::parse("tcp:22").unwrap());
+
+        // 未发起连接时入站包被丢弃，开放的端口除外
+        assert_eq!(fw.check_inbound(&udp(REMOTE, 53, LOCAL, 40000)), Verdict::Unsolicited);
+        assert_eq!(fw.check_inbound(&tcp(PEER, 50000, LOCAL, 22)), Verdict::Exposed);
+        assert_eq!(fw.check_inbound(&tcp(PEER, 50000, LOCAL, 80)), Verdict::Unsolicited);
+
+        // 本机发起的连接的回包
+        fw.record_outbound(&udp(LOCAL, 40000, REMOTE, 53));
+        assert_eq!(fw.check_inbound(&udp(REMOTE, 53, LOCAL, 40000)), Verdict::Established);
+        assert_eq!(fw.check_inbound(&udp(REMOTE, 53, LOCAL, 40001)), Verdict::Unsolicited);
+        assert_eq!(fw.check_inbound(&udp(PEER, 53, LOCAL, 40000)), Verdict::Unsolicited);
+
+        // ping：回显应答放行，对端发来的回显请求不算回包
+        fw.record_outbound(&echo(8, LOCAL, PEER, 7));
+        assert_eq!(fw.check_inbound(&echo(0, PEER, LOCAL, 7)), Verdict::Established);
+        assert_eq!(fw.check_inbound(&echo(0, PEER, LOCAL, 8)), Verdict::Unsolicited);
+        assert_eq!(fw.check_inbound(&echo(8, PEER, LOCAL, 7)), Verdict::Unsolicited);
+
+        // 引用本机出站包的 ICMP 差错报文（例如服务端回复的 Time Exceeded）
+        let outbound = tcp(LOCAL, 41000, PEER, 443);
+        let mut error = vec![11, 0, 0, 0, 0, 0, 0, 0];
+        error.extend(&outbound[..28]);
+        let error = ipv4(PROTO_ICMP, [10, 0, 0, 1], LOCAL, &error);
+        assert_eq!(fw.check_inbound(&error), Verdict::Unsolicited);
+        fw.record_outbound(&outbound);
+        assert_eq!(fw.check_inbound(&error), Verdict::Related);
+        assert_eq!(fw.len(), 3);
+
+        // 对端下线后与它的连接一并清除
+        assert_eq!(fw.forget_peer(IpAddr::from(PEER)), 2);
+        assert_eq!(fw.check_inbound(&echo(0, PEER, LOCAL, 7)), Verdict::Unsolicited);
+        assert_eq!(fw.len(), 1);
+
+        // --expose all 不做过滤
+        let mut open = InboundFirewall::new(Allowlist::unrestricted());
+        assert!(open.check_inbound(&tcp(PEER, 50000, LOCAL, 80)).allowed());
+    }
+
+    #[test]
+    fn test_connection_table_eviction() {
+        let mut fw = InboundFirewall::new(Allowlist::empty());
+        for port in 0..MAX_CONNECTIONS as u16 {
+            fw.record_outbound(&udp(LOCAL, port, REMOTE, 53));
+        }
+        assert_eq!(fw.len(), MAX_CONNECTIONS);
+        // 满了以后淘汰最久未活动的连接，新连接仍然能建立
+        fw.record_outbound(&udp(LOCAL, 60000, REMOTE, 53));
+        assert_eq!(fw.len(), MAX_CONNECTIONS);
+        assert_eq!(fw.check_inbound(&udp(REMOTE, 53, LOCAL, 60000)), Verdict::Established);
+    }
+}
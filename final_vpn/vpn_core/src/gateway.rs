@@ -1,9 +1,16 @@
 // vpn_core/src/gateway.rs
 // 网关功能：IP转发 + NAT配置
 
+use std::net::IpAddr;
 use std::process::Command;
 use anyhow::Result;
 
+use crate::dryrun::{Category, Plan};
+
+/// Linux 上开启 IPv4 转发的命令（sh -c）
+#[cfg(target_os = "linux")]
+const IP_FORWARD_SCRIPT: &str = "echo 1 > /proc/sys/net/ipv4/ip_forward";
+
 /// 启用系统IP转发
 /// Linux: 修改 /proc/sys/net/ipv4/ip_forward
 /// macOS: 修改 sysctl net.inet.ip.forwarding
@@ -13,7 +20,7 @@ pub fn enable_ip_forwarding() -> Result<()> {
         println!("🔧 启用 Linux IP 转发...");
         Command::new("sh")
             .arg("-c")
-            .arg("echo 1 > /proc/sys/net/ipv4/ip_forward")
+            .arg(IP_FORWARD_SCRIPT)
             .status()?;
         
         // 验证
@@ -51,6 +58,74 @@ pub fn enable_ip_forwarding() -> Result<()> {
     }
 }
 
+/// 当前是否已开启 IPv4 / IPv6 转发（仅 Linux，其他系统或读取失败时返回 None）
+///
+/// 启动前记录下来，启动失败回滚时只关闭原本未开启的转发
+#[allow(unused_variables)]
+pub fn forwarding_enabled(ipv6: bool) -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = if ipv6 { "/proc/sys/net/ipv6/conf/all/forwarding" } else { "/proc/sys/net/ipv4/ip_forward" };
+        std::fs::read_to_string(path).ok().map(|v| v.trim() == "1")
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// 关闭 IPv4 / IPv6 转发（撤销 enable_ip_forwarding / enable_ipv6_forwarding，忽略错误）
+#[allow(unused_variables)]
+pub fn disable_forwarding(ipv6: bool) {
+    #[cfg(target_os = "linux")]
+    {
+        let key = if ipv6 { "net.ipv6.conf.all.forwarding=0" } else { "net.ipv4.ip_forward=0" };
+        let _ = Command::new("sysctl").args(["-q", "-w", key]).status();
+    }
+}
+
+/// 启用系统 IPv6 转发
+/// Linux: sysctl net.ipv6.conf.all.forwarding
+/// macOS: sysctl net.inet6.ip6.forwarding
+pub fn enable_ipv6_forwarding() -> Result<()> {
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    anyhow::bail!("不支持的操作系统");
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        println!("🔧 启用 IPv6 转发...");
+        let status = Command::new("sysctl").args(["-w", IPV6_FORWARD_SYSCTL]).status()?;
+        if status.success() {
+            println!("   ✅ IPv6 转发已启用");
+            Ok(())
+        } else {
+            anyhow::bail!("无法启用 IPv6 转发，请使用 sudo 运行")
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+const IPV6_FORWARD_SYSCTL: &str = "net.ipv6.conf.all.forwarding=1";
+#[cfg(target_os = "macos")]
+const IPV6_FORWARD_SYSCTL: &str = "net.inet6.ip6.forwarding=1";
+
+/// NAT 的三条规则（iptables 和 ip6tables 相同），op 为 -A 添加或 -D 删除
+#[cfg(target_os = "linux")]
+fn nat_rules(op: &str, tun_device: &str, external_interface: &str) -> Vec<Vec<String>> {
+    [
+        // 1. 允许从 TUN 转发到外网接口
+        vec![op, "FORWARD", "-i", tun_device, "-o", external_interface, "-j", "ACCEPT"],
+        // 2. 允许外网接口的响应包返回到 TUN
+        vec![op, "FORWARD", "-i", external_interface, "-o", tun_device, "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"],
+        // 3. 启用 MASQUERADE（源地址伪装）
+        vec!["-t", "nat", op, "POSTROUTING", "-o", external_interface, "-j", "MASQUERADE"],
+    ]
+    .iter()
+    .map(|args| args.iter().map(|s| s.to_string()).collect())
+    .collect()
+}
+
 /// 配置 NAT（网络地址转换）
 /// Linux: 使用 iptables MASQUERADE
 /// macOS: 使用 pfctl（较复杂，这里先提示）
@@ -64,28 +139,17 @@ pub fn setup_nat(tun_device: &str, external_interface: &str) -> Result<()> {
         println!("   VPN 接口: {}", tun_device);
         println!("   外网接口: {}", external_interface);
         
-        // 1. 允许从 TUN 转发到外网接口
-        let status1 = Command::new("iptables")
-            .args(&["-A", "FORWARD", "-i", tun_device, "-o", external_interface, "-j", "ACCEPT"])
-            .status()?;
-        
-        // 2. 允许外网接口的响应包返回到 TUN
-        let status2 = Command::new("iptables")
-            .args(&["-A", "FORWARD", "-i", external_interface, "-o", tun_device, 
-                    "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"])
-            .status()?;
-        
-        // 3. 启用 MASQUERADE（源地址伪装）
-        let status3 = Command::new("iptables")
-            .args(&["-t", "nat", "-A", "POSTROUTING", "-o", external_interface, "-j", "MASQUERADE"])
-            .status()?;
+        let mut success = true;
+        for args in nat_rules("-A", tun_device, external_interface) {
+            success &= Command::new("iptables").args(&args).status()?.success();
+        }
         
-        if status1.success() && status2.success() && status3.success() {
+        if success {
             println!("   ✅ NAT 配置成功");
             println!("   📝 清理命令:");
-            println!("      iptables -D FORWARD -i {} -o {} -j ACCEPT", tun_device, external_interface);
-            println!("      iptables -D FORWARD -i {} -o {} -m state --state RELATED,ESTABLISHED -j ACCEPT", external_interface, tun_device);
-            println!("      iptables -t nat -D POSTROUTING -o {} -j MASQUERADE", external_interface);
+            for args in nat_rules("-D", tun_device, external_interface) {
+                println!("      iptables {}", args.join(" "));
+            }
             Ok(())
         } else {
             anyhow::bail!("iptables 配置失败，请使用 sudo 运行")
@@ -116,18 +180,9 @@ pub fn cleanup_nat(tun_device: &str, external_interface: &str) -> Result<()> {
         println!("🧹 清理 NAT 规则...");
         
         // 使用 -D 删除规则（忽略错误，因为规则可能不存在）
-        let _ = Command::new("iptables")
-            .args(&["-D", "FORWARD", "-i", tun_device, "-o", external_interface, "-j", "ACCEPT"])
-            .status();
-        
-        let _ = Command::new("iptables")
-            .args(&["-D", "FORWARD", "-i", external_interface, "-o", tun_device, 
-                    "-m", "state", "--state", "RELATED,ESTABLISHED", "-j", "ACCEPT"])
-            .status();
-        
-        let _ = Command::new("iptables")
-            .args(&["-t", "nat", "-D", "POSTROUTING", "-o", external_interface, "-j", "MASQUERADE"])
-            .status();
+        for args in nat_rules("-D", tun_device, external_interface) {
+            let _ = Command::new("iptables").args(&args).status();
+        }
         
         println!("   ✅ 清理完成");
         Ok(())
@@ -139,25 +194,185 @@ pub fn cleanup_nat(tun_device: &str, external_interface: &str) -> Result<()> {
     }
 }
 
+/// 对经过 TUN 设备发出的 TCP SYN 钳制 MSS（仅 Linux，iptables mangle 表）
+///
+/// 规则按设备名唯一：先删除该设备上的旧规则再添加，MTU 变化时可重复调用
+#[allow(unused_variables)]
+pub fn set_mss_clamp(tun_device: &str, mss: u16) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        clear_mss_clamp(tun_device);
+        
+        for args in mss_clamp_rules(tun_device, mss) {
+            let status = Command::new("iptables").args(&args).status()?;
+            if !status.success() {
+                anyhow::bail!("iptables MSS 钳制配置失败，请使用 sudo 运行")
+            }
+        }
+        Ok(())
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    {
+        println!("⚠️  当前系统不支持自动 MSS 钳制，已忽略");
+        Ok(())
+    }
+}
+
+/// set_mss_clamp 添加的 iptables 规则
+fn mss_clamp_rules(tun_device: &str, mss: u16) -> Vec<Vec<String>> {
+    let mss = mss.to_string();
+    ["OUTPUT", "FORWARD"]
+        .iter()
+        .map(|chain| {
+            ["-t", "mangle", "-A", chain, "-o", tun_device, "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN", "-j", "TCPMSS", "--set-mss", &mss]
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .collect()
+}
+
+/// 试运行：列出 set_mss_clamp 的修改
+pub fn plan_mss_clamp(plan: &mut Plan, tun_device: &str, mss: u16) {
+    if cfg!(target_os = "linux") {
+        for args in mss_clamp_rules(tun_device, mss) {
+            plan.command(Category::Firewall, "iptables", &args);
+        }
+    }
+}
+
+/// 删除 TUN 设备上的 MSS 钳制规则（忽略错误，因为规则可能不存在）
+#[allow(unused_variables)]
+pub fn clear_mss_clamp(tun_device: &str) {
+    #[cfg(target_os = "linux")]
+    for chain in ["OUTPUT", "FORWARD"] {
+        // 规则中的 MSS 值未知，按 iptables -S 的输出逐条删除
+        let Ok(output) = Command::new("iptables").args(["-t", "mangle", "-S", chain]).output() else { continue };
+        let rules = String::from_utf8_lossy(&output.stdout);
+        let needle = format!("-o {} ", tun_device);
+        for rule in rules.lines().filter(|r| r.contains(&needle) && r.contains("TCPMSS")) {
+            let mut args = vec!["-t", "mangle", "-D"];
+            args.extend(rule.split_whitespace().skip(1));
+            let _ = Command::new("iptables").args(&args).status();
+        }
+    }
+}
+
+/// 配置 IPv6 NAT（仅 Linux，ip6tables MASQUERADE）
+///
+/// 隧道内使用 ULA 地址 fd00::/96，无法直接路由到公网，这里伪装成外网接口的全局地址。
+/// 开启转发后内核默认不再接受路由通告（RA），外网接口靠 SLAAC 获得默认路由时
+/// 会丢失 IPv6 出口，因此同时把该接口的 accept_ra 设为 2
+#[allow(unused_variables)]
+pub fn setup_nat6(tun_device: &str, external_interface: &str) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        println!("🔧 配置 IPv6 NAT (ip6tables)...");
+        let _ = Command::new("sysctl")
+            .args(["-w", &accept_ra_sysctl(external_interface)])
+            .output();
+
+        let mut success = true;
+        for args in nat_rules("-A", tun_device, external_interface) {
+            success &= Command::new("ip6tables").args(&args).status()?.success();
+        }
+
+        if success {
+            println!("   ✅ IPv6 NAT 配置成功");
+            Ok(())
+        } else {
+            anyhow::bail!("ip6tables 配置失败（需要 sudo 和 ip6table_nat 内核模块）")
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        anyhow::bail!("当前系统不支持自动配置 IPv6 NAT")
+    }
+}
+
+/// 清理 IPv6 NAT 规则（仅 Linux）
+#[allow(unused_variables)]
+pub fn cleanup_nat6(tun_device: &str, external_interface: &str) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    for args in nat_rules("-D", tun_device, external_interface) {
+        let _ = Command::new("ip6tables").args(&args).status();
+    }
+    Ok(())
+}
+
+/// 外网接口开启转发后仍接受路由通告
+#[cfg(target_os = "linux")]
+fn accept_ra_sysctl(external_interface: &str) -> String {
+    format!("net.ipv6.conf.{}.accept_ra=2", external_interface)
+}
+
+/// 试运行：列出网关模式的修改（IP 转发 + NAT，ipv6 时同时配置 IPv6 转发和 NAT）
+#[allow(unused_variables)]
+pub fn plan_gateway(plan: &mut Plan, tun_device: &str, external_interface: &str, ipv6: bool) {
+    #[cfg(target_os = "linux")]
+    {
+        plan.command(Category::Sysctl, "sh", &["-c", &format!("'{}'", IP_FORWARD_SCRIPT)]);
+        plan_nat(plan, tun_device, external_interface);
+        if ipv6 {
+            plan.command(Category::Sysctl, "sysctl", &["-w", IPV6_FORWARD_SYSCTL]);
+            plan.command(Category::Sysctl, "sysctl", &["-w", &accept_ra_sysctl(external_interface)]);
+            for args in nat_rules("-A", tun_device, external_interface) {
+                plan.command(Category::Firewall, "ip6tables", &args);
+            }
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        plan.command(Category::Sysctl, "sysctl", &["-w", "net.inet.ip.forwarding=1"]);
+        if ipv6 {
+            plan.command(Category::Sysctl, "sysctl", &["-w", IPV6_FORWARD_SYSCTL]);
+        }
+        plan.note(Category::Firewall, "macOS 不自动配置 NAT，需要手动配置 pfctl");
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    plan.note(Category::Sysctl, "当前系统不支持网关模式");
+}
+
+/// 试运行：列出 setup_nat 添加的规则
+#[cfg(target_os = "linux")]
+fn plan_nat(plan: &mut Plan, tun_device: &str, external_interface: &str) {
+    for args in nat_rules("-A", tun_device, external_interface) {
+        plan.command(Category::Firewall, "iptables", &args);
+    }
+}
+
+/// 外网接口变化后迁移 NAT 规则：删除旧接口上的规则，在新接口上重新配置
+pub fn move_nat(tun_device: &str, old_interface: &str, new_interface: &str, ipv6: bool) -> Result<()> {
+    cleanup_nat(tun_device, old_interface)?;
+    if ipv6 {
+        cleanup_nat6(tun_device, old_interface)?;
+        setup_nat6(tun_device, new_interface)?;
+    }
+    setup_nat(tun_device, new_interface)
+}
+
 /// 自动检测默认网关接口
 pub fn detect_default_interface() -> Result<String> {
     #[cfg(target_os = "linux")]
     {
         let output = Command::new("ip")
-            .args(&["route", "show", "default"])
+            .args(["route", "show", "default"])
             .output()?;
         
         let stdout = String::from_utf8_lossy(&output.stdout);
         // 输出格式: default via 192.168.1.1 dev eth0 proto dhcp metric 100
         for line in stdout.lines() {
-            if line.contains("default") {
-                if let Some(dev_pos) = line.find("dev ") {
+            if line.contains("default")
+                && let Some(dev_pos) = line.find("dev ") {
                     let rest = &line[dev_pos + 4..];
                     if let Some(interface) = rest.split_whitespace().next() {
                         return Ok(interface.to_string());
                     }
                 }
-            }
         }
         anyhow::bail!("无法检测默认网卡")
     }
@@ -185,3 +400,395 @@ pub fn detect_default_interface() -> Result<String> {
         anyhow::bail!("不支持的操作系统")
     }
 }
+
+/// 出口策略使用的路由表编号起点（每个出口接口一张表）
+pub const EGRESS_TABLE_BASE: u32 = 52000;
+/// 目的网段规则的 ip rule 优先级起点（优先于按客户端选择）
+const EGRESS_DEST_PRIORITY: u32 = 20000;
+/// 客户端规则的 ip rule 优先级起点
+const EGRESS_CLIENT_PRIORITY: u32 = 21000;
+
+/// 出口规则的匹配方式
+#[derive(Debug, Clone, PartialEq)]
+pub enum EgressMatch {
+    /// 按客户端虚拟 IP / 网段选择出口
+    Client(String),
+    /// 按目的网段选择出口
+    Destination(String),
+}
+
+/// 一条出口规则：匹配的流量从指定外网接口出去
+#[derive(Debug, Clone, PartialEq)]
+pub struct EgressRule {
+    pub matcher: EgressMatch,
+    pub interface: String,
+}
+
+impl EgressRule {
+    /// 解析 `<cidr>=<接口>`（例如 `10.0.0.8/29=wg0`，单个地址可省略前缀长度）
+    pub fn parse(spec: &str, destination: bool) -> Result<Self> {
+        let (cidr, interface) = spec
+            .split_once('=')
+            .filter(|(_, iface)| !iface.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("无效的出口规则: {}（格式 10.0.0.8/29=wg0）", spec))?;
+        let cidr = normalize_cidr(cidr)?;
+        let matcher = if destination { EgressMatch::Destination(cidr) } else { EgressMatch::Client(cidr) };
+        Ok(Self { matcher, interface: interface.to_string() })
+    }
+}
+
+/// 校验 IPv4 CIDR，单个地址补全为 /32
+fn normalize_cidr(s: &str) -> Result<String> {
+    let (ip, prefix) = s.split_once('/').unwrap_or((s, "32"));
+    let ip: std::net::Ipv4Addr = ip.parse().map_err(|_| anyhow::anyhow!("无效的地址: {}", s))?;
+    let prefix: u8 = prefix.parse().ok().filter(|p| *p <= 32).ok_or_else(|| anyhow::anyhow!("无效的前缀长度: {}", s))?;
+    Ok(format!("{}/{}", ip, prefix))
+}
+
+/// 出口接口及其路由表（按规则中首次出现的顺序编号）
+fn egress_tables(rules: &[EgressRule]) -> Vec<(&str, u32)> {
+    let mut tables: Vec<(&str, u32)> = Vec::new();
+    for rule in rules {
+        if !tables.iter().any(|(iface, _)| *iface == rule.interface) {
+            tables.push((&rule.interface, EGRESS_TABLE_BASE + tables.len() as u32));
+        }
+    }
+    tables
+}
+
+/// 每条规则对应的 `ip rule` 参数（不含 add/del），只匹配从 TUN 进来的流量
+fn egress_rule_args(tun_device: &str, rules: &[EgressRule]) -> Vec<Vec<String>> {
+    let tables = egress_tables(rules);
+    let (mut dest_index, mut client_index) = (0, 0);
+    rules
+        .iter()
+        .map(|rule| {
+            let table = tables.iter().find(|(iface, _)| *iface == rule.interface).map(|(_, t)| *t).unwrap_or(EGRESS_TABLE_BASE);
+            let (selector, cidr, priority) = match &rule.matcher {
+                EgressMatch::Destination(cidr) => {
+                    dest_index += 1;
+                    ("to", cidr, EGRESS_DEST_PRIORITY + dest_index)
+                }
+                EgressMatch::Client(cidr) => {
+                    client_index += 1;
+                    ("from", cidr, EGRESS_CLIENT_PRIORITY + client_index)
+                }
+            };
+            [selector, cidr, "iif", tun_device, "lookup", &table.to_string(), "priority", &priority.to_string()]
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        })
+        .collect()
+}
+
+/// 查询接口上的默认网关（点对点接口如 WireGuard 没有网关，返回 None）
+#[cfg(target_os = "linux")]
+fn interface_gateway(interface: &str) -> Option<String> {
+    let output = Command::new("ip").args(["route", "show", "default", "dev", interface]).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // 格式: default via 192.168.2.1 proto dhcp metric 100
+    let mut words = stdout.split_whitespace();
+    words.by_ref().find(|w| *w == "via")?;
+    words.next().map(|s| s.to_string())
+}
+
+/// 出口接口路由表中的默认路由（`ip` 参数）
+#[cfg(target_os = "linux")]
+fn egress_route_args(interface: &str, gateway: Option<&str>, table: u32) -> Vec<String> {
+    let mut args = vec!["route".to_string(), "replace".to_string(), "default".to_string()];
+    if let Some(gw) = gateway {
+        args.extend(["via".to_string(), gw.to_string()]);
+    }
+    args.extend(["dev".to_string(), interface.to_string(), "table".to_string(), table.to_string()]);
+    args
+}
+
+/// 试运行：列出 setup_egress 的修改
+#[allow(unused_variables)]
+pub fn plan_egress(plan: &mut Plan, tun_device: &str, rules: &[EgressRule]) {
+    #[cfg(target_os = "linux")]
+    {
+        for (interface, table) in egress_tables(rules) {
+            plan.command(Category::Route, "ip", &egress_route_args(interface, interface_gateway(interface).as_deref(), table));
+            plan_nat(plan, tun_device, interface);
+        }
+        for args in egress_rule_args(tun_device, rules) {
+            plan.command(Category::Route, "ip", &[&["rule".to_string(), "add".to_string()], args.as_slice()].concat());
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    plan.note(Category::Route, "出口策略仅支持 Linux，不会配置");
+}
+
+/// 按出口规则配置策略路由和各出口接口的 NAT（仅 Linux）
+///
+/// 每个出口接口一张路由表（默认路由指向该接口），`ip rule` 按客户端源地址或目的网段
+/// 把从 TUN 进来的流量导向对应的表；未匹配的流量仍走主路由表的默认出口
+#[allow(unused_variables)]
+pub fn setup_egress(tun_device: &str, rules: &[EgressRule]) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        println!("🔧 配置出口策略...");
+        for (interface, table) in egress_tables(rules) {
+            let gateway = interface_gateway(interface);
+            let status = Command::new("ip").args(egress_route_args(interface, gateway.as_deref(), table)).status()?;
+            if !status.success() {
+                anyhow::bail!("为 {} 配置路由表 {} 失败", interface, table)
+            }
+            setup_nat(tun_device, interface)?;
+            println!("   ✅ 出口 {} -> 路由表 {}{}", interface, table, gateway.map(|g| format!("（网关 {}）", g)).unwrap_or_default());
+        }
+
+        for args in egress_rule_args(tun_device, rules) {
+            let status = Command::new("ip").arg("rule").arg("add").args(&args).status()?;
+            if !status.success() {
+                anyhow::bail!("ip rule {} 配置失败", args.join(" "))
+            }
+            println!("   ✅ ip rule {}", args.join(" "));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        anyhow::bail!("出口策略仅支持 Linux")
+    }
+}
+
+/// 删除出口策略的 ip rule、路由表和 NAT 规则（忽略错误）
+#[allow(unused_variables)]
+pub fn cleanup_egress(tun_device: &str, rules: &[EgressRule]) {
+    #[cfg(target_os = "linux")]
+    {
+        for args in egress_rule_args(tun_device, rules) {
+            let _ = Command::new("ip").arg("rule").arg("del").args(&args).output();
+        }
+        for (interface, table) in egress_tables(rules) {
+            let _ = Command::new("ip").args(["route", "flush", "table", &table.to_string()]).output();
+            let _ = cleanup_nat(tun_device, interface);
+        }
+    }
+}
+
+/// 隧道访问本机服务的 iptables 链
+pub const HOST_SERVICES_CHAIN: &str = "RUSTVPN-HOST";
+
+/// 允许隧道访问的一个本机服务
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostService {
+    pub protocol: String,
+    pub port: u16,
+}
+
+/// 解析端口白名单，例如 `tcp:22,udp:53,8080`（省略协议时为 tcp）
+pub fn parse_host_services(spec: &str) -> Result<Vec<HostService>> {
+    spec.split(',')
+        .map(|item| {
+            let item = item.trim();
+            let (protocol, port) = item.split_once(':').unwrap_or(("tcp", item));
+            let protocol = protocol.to_ascii_lowercase();
+            if protocol != "tcp" && protocol != "udp" {
+                anyhow::bail!("不支持的协议: {}（只支持 tcp/udp）", item)
+            }
+            let port = port.parse().map_err(|_| anyhow::anyhow!("无效的端口: {}", item))?;
+            Ok(HostService { protocol, port })
+        })
+        .collect()
+}
+
+/// 本机服务白名单链中的规则（按顺序追加）
+fn host_service_rules(services: &[HostService]) -> Vec<Vec<String>> {
+    let mut rules = vec![
+        "-m state --state RELATED,ESTABLISHED -j ACCEPT".to_string(),
+        "-p icmp -j ACCEPT".to_string(),
+        "-p ipv6-icmp -j ACCEPT".to_string(),
+    ];
+    for service in services {
+        rules.push(format!("-p {} --dport {} -j ACCEPT", service.protocol, service.port));
+    }
+    rules.push("-j DROP".to_string());
+    rules.iter().map(|r| r.split_whitespace().map(String::from).collect()).collect()
+}
+
+/// setup_host_services 对 program（iptables / ip6tables）依次执行的参数
+fn host_service_commands(program: &str, tun_device: &str, services: &[HostService]) -> Vec<Vec<String>> {
+    let mut commands = vec![vec!["-N".to_string(), HOST_SERVICES_CHAIN.to_string()]];
+    for rule in host_service_rules(services) {
+        // ipv6-icmp 只对 ip6tables 有意义，icmp 只对 iptables 有意义
+        match (program, rule.get(1).map(|s| s.as_str())) {
+            ("iptables", Some("ipv6-icmp")) | ("ip6tables", Some("icmp")) => continue,
+            _ => commands.push([vec!["-A".to_string(), HOST_SERVICES_CHAIN.to_string()], rule].concat()),
+        }
+    }
+    commands.push(["-I", "INPUT", "-i", tun_device, "-j", HOST_SERVICES_CHAIN].iter().map(|s| s.to_string()).collect());
+    commands
+}
+
+/// 试运行：列出 setup_host_services 的修改
+pub fn plan_host_services(plan: &mut Plan, tun_device: &str, services: &[HostService]) {
+    if !cfg!(target_os = "linux") {
+        plan.note(Category::Firewall, "本机服务白名单仅支持 Linux，不会配置");
+        return;
+    }
+    for program in ["iptables", "ip6tables"] {
+        for args in host_service_commands(program, tun_device, services) {
+            plan.command(Category::Firewall, program, &args);
+        }
+    }
+}
+
+/// 只允许隧道访问白名单中的本机端口（仅 Linux）
+///
+/// INPUT 链中从 TUN 进来的包跳转到 RUSTVPN-HOST 链：放行已建立的连接、ICMP 和白名单端口，
+/// 其余丢弃。IPv4 用 iptables，IPv6 用 ip6tables（系统没有 ip6tables 时跳过）
+#[allow(unused_variables)]
+pub fn setup_host_services(tun_device: &str, services: &[HostService]) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        cleanup_host_services(tun_device);
+        println!("🔧 配置本机服务白名单...");
+        for program in ["iptables", "ip6tables"] {
+            let result = host_service_commands(program, tun_device, services).iter().try_for_each(|args| {
+                let status = Command::new(program).args(args).status()?;
+                if !status.success() {
+                    anyhow::bail!("{} {} 失败", program, args.join(" "))
+                }
+                Ok(())
+            });
+            match result {
+                Ok(_) => {}
+                Err(e) if program == "ip6tables" => println!("   ⚠️  IPv6 白名单未配置: {}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        let ports: Vec<String> = services.iter().map(|s| format!("{}/{}", s.port, s.protocol)).collect();
+        println!("   ✅ 隧道可访问的本机服务: {}", if ports.is_empty() { "（无）".to_string() } else { ports.join(", ") });
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        anyhow::bail!("本机服务白名单仅支持 Linux")
+    }
+}
+
+/// 删除本机服务白名单（忽略错误，因为规则可能不存在）
+#[allow(unused_variables)]
+pub fn cleanup_host_services(tun_device: &str) {
+    #[cfg(target_os = "linux")]
+    for program in ["iptables", "ip6tables"] {
+        let _ = Command::new(program).args(["-D", "INPUT", "-i", tun_device, "-j", HOST_SERVICES_CHAIN]).output();
+        let _ = Command::new(program).args(["-F", HOST_SERVICES_CHAIN]).output();
+        let _ = Command::new(program).args(["-X", HOST_SERVICES_CHAIN]).output();
+    }
+}
+
+/// 删除与某个地址相关的 conntrack 条目（仅 Linux，需要 conntrack 工具），返回删除的条数
+///
+/// 客户端断开后，它经 MASQUERADE 建立的连接仍留在 conntrack 表中直到超时；
+/// 虚拟 IP 分配给下一个客户端时，这些连接的回包会被送给新客户端。
+/// 同时按源地址和目的地址删除，覆盖客户端发起和发往客户端的连接
+#[allow(unused_variables)]
+pub fn flush_conntrack(ip: IpAddr) -> Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        let family = if ip.is_ipv6() { "ipv6" } else { "ipv4" };
+        let ip = ip.to_string();
+        let mut deleted = 0;
+        for direction in ["-s", "-d"] {
+            // 没有匹配条目时 conntrack 以非零状态退出，只看输出中的计数
+            let output = Command::new("conntrack")
+                .args(["-D", "-f", family, direction, &ip])
+                .output()?;
+            deleted += parse_conntrack_deleted(&String::from_utf8_lossy(&output.stderr));
+        }
+        Ok(deleted)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(0)
+    }
+}
+
+/// 解析 `conntrack -D` 的统计输出
+/// 格式: conntrack v1.4.6 (conntrack-tools): 3 flow entries have been deleted.
+fn parse_conntrack_deleted(output: &str) -> usize {
+    output
+        .lines()
+        .filter_map(|line| line.split("):").nth(1))
+        .filter_map(|rest| rest.split_whitespace().next()?.parse::<usize>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_egress_rules() {
+        let rules = vec![
+            EgressRule::parse("10.0.0.8/29=wg0", false).unwrap(),
+            EgressRule::parse("10.0.0.20=wan1", false).unwrap(),
+            EgressRule::parse("192.168.50.0/24=wg0", true).unwrap(),
+        ];
+        assert_eq!(rules[1].matcher, EgressMatch::Client("10.0.0.20/32".to_string()));
+        assert!(EgressRule::parse("10.0.0.8/33=wg0", false).is_err());
+        assert!(EgressRule::parse("10.0.0.8=", false).is_err());
+
+        assert_eq!(egress_tables(&rules), vec![("wg0", 52000), ("wan1", 52001)]);
+        let args: Vec<String> = egress_rule_args("tun0", &rules).iter().map(|a| a.join(" ")).collect();
+        assert_eq!(args[0], "from 10.0.0.8/29 iif tun0 lookup 52000 priority 21001");
+        assert_eq!(args[1], "from 10.0.0.20/32 iif tun0 lookup 52001 priority 21002");
+        assert_eq!(args[2], "to 192.168.50.0/24 iif tun0 lookup 52000 priority 20001");
+    }
+
+    #[test]
+    fn test_host_services() {
+        let services = parse_host_services("tcp:22, udp:53,8080").unwrap();
+        assert_eq!(services[1], HostService { protocol: "udp".to_string(), port: 53 });
+        assert_eq!(services[2].protocol, "tcp");
+        assert!(parse_host_services("sctp:1").is_err());
+        assert!(parse_host_services("tcp:99999").is_err());
+
+        let rules: Vec<String> = host_service_rules(&services).iter().map(|r| r.join(" ")).collect();
+        assert_eq!(rules[3], "-p tcp --dport 22 -j ACCEPT");
+        assert_eq!(rules.last().unwrap(), "-j DROP");
+
+        // iptables 跳过 ipv6-icmp，ip6tables 跳过 icmp
+        let v4: Vec<String> = host_service_commands("iptables", "tun0", &services).iter().map(|r| r.join(" ")).collect();
+        let v6: Vec<String> = host_service_commands("ip6tables", "tun0", &services).iter().map(|r| r.join(" ")).collect();
+        assert_eq!(v4.first().unwrap(), "-N RUSTVPN-HOST");
+        assert_eq!(v4.last().unwrap(), "-I INPUT -i tun0 -j RUSTVPN-HOST");
+        assert!(v4.contains(&"-A RUSTVPN-HOST -p icmp -j ACCEPT".to_string()) && !v4.iter().any(|r| r.contains("ipv6-icmp")));
+        assert!(v6.contains(&"-A RUSTVPN-HOST -p ipv6-icmp -j ACCEPT".to_string()) && !v6.iter().any(|r| r.contains("-p icmp ")));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_plan_gateway() {
+        let mut plan = Plan::new();
+        plan_gateway(&mut plan, "tun0", "eth0", true);
+        plan_mss_clamp(&mut plan, "tun0", 1240);
+        assert_eq!(
+            plan.steps(Category::Sysctl),
+            vec!["sh -c 'echo 1 > /proc/sys/net/ipv4/ip_forward'", "sysctl -w net.ipv6.conf.all.forwarding=1", "sysctl -w net.ipv6.conf.eth0.accept_ra=2"]
+        );
+        let firewall = plan.steps(Category::Firewall);
+        assert_eq!(firewall[0], "iptables -A FORWARD -i tun0 -o eth0 -j ACCEPT");
+        assert_eq!(firewall[2], "iptables -t nat -A POSTROUTING -o eth0 -j MASQUERADE");
+        assert_eq!(firewall[5], "ip6tables -t nat -A POSTROUTING -o eth0 -j MASQUERADE");
+        assert_eq!(firewall[6], "iptables -t mangle -A OUTPUT -o tun0 -p tcp --tcp-flags SYN,RST SYN -j TCPMSS --set-mss 1240");
+        // 清理命令与添加的规则一一对应
+        assert_eq!(nat_rules("-D", "tun0", "eth0")[1].join(" "), "-D FORWARD -i eth0 -o tun0 -m state --state RELATED,ESTABLISHED -j ACCEPT");
+    }
+
+    #[test]
+    fn test_parse_conntrack_deleted() {
+        assert_eq!(parse_conntrack_deleted("conntrack v1.4.6 (conntrack-tools): 3 flow entries have been deleted.\n"), 3);
+        assert_eq!(parse_conntrack_deleted("conntrack v1.4.6 (conntrack-tools): 0 flow entries have been deleted.\n"), 0);
+        assert_eq!(parse_conntrack_deleted(""), 0);
+    }
+}
@@ -0,0 +1,255 @@
+// vpn_core/src/gso.rs
+// Linux UDP GSO（UDP_SEGMENT）：发送时的分段卸载
+//
+// 大流量下行时，一批 TUN 包里往往有多个发往同一客户端、加密后长度相同的数据报（满 MSS 的 TCP 段）。
+// GsoBatch 把它们按目的端点拼成一个大缓冲区，send 用一次 sendmsg 交给内核，附带 UDP_SEGMENT 指定每段长度，
+// 由内核（或支持 UDP 分段卸载的网卡）切回独立的数据报。接收端看到的与逐个发送完全一样。
+//
+// 内核的要求：同一组里除最后一段外长度都相同，最后一段可以更短；段数不超过 MAX_SEGMENTS，
+// 总长度不超过一个 UDP 数据报。GsoBatch 保证这些条件，并保持发往同一端点的数据报的先后顺序。
+
+use std::collections::HashMap;
+#[cfg(feature = "tokio")]
+use std::io;
+use std::net::SocketAddr;
+
+#[cfg(feature = "tokio")]
+use tokio::net::UdpSocket;
+
+/// 一次 sendmsg 最多的段数（内核的 UDP_MAX_SEGMENTS）
+pub const MAX_SEGMENTS: usize = 64;
+/// 一组的总长度上限（IPv4 UDP 载荷的最大长度）
+pub const MAX_GSO_BYTES: usize = 65507;
+
+/// 一组发往同一端点、可以一次发出的数据报
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segments {
+    pub addr: SocketAddr,
+    /// 首尾相接的数据报
+    pub buf: Vec<u8>,
+    /// 每段的长度（最后一段可以更短）
+    pub segment_size: usize,
+    /// 最后一段比 segment_size 短，之后不能再追加
+    closed: bool,
+}
+
+impl Segments {
+    /// 段数
+    pub fn count(&self) -> usize {
+        self.buf.len().div_ceil(self.segment_size)
+    }
+
+    /// 逐个取出数据报（不支持 GSO 时逐个发送）
+    pub fn datagrams(&self) -> impl Iterator<Item = &[u8]> {
+        self.buf.chunks(self.segment_size)
+    }
+
+    fn try_push(&mut self, datagram: &[u8]) -> bool {
+        if self.closed
+            || datagram.len() > self.segment_size
+            || self.count() >= MAX_SEGMENTS
+            || self.buf.len() + datagram.len() > MAX_GSO_BYTES
+        {
+            return false;
+        }
+        self.buf.extend_from_slice(datagram);
+        self.closed = datagram.len() < self.segment_size;
+        true
+    }
+}
+
+/// 一批待发送的数据报，按目的端点合并成组
+#[derive(Debug, Default)]
+pub struct GsoBatch {
+    groups: Vec<Segments>,
+    /// 每个端点最新的一组在 groups 中的位置
+    latest: HashMap<SocketAddr, usize>,
+}
+
+impl GsoBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一个数据报：能接在该端点最新的一组后面就接上，否则开始新的一组
+    pub fn push(&mut self, addr: SocketAddr, datagram: &[u8]) {
+        if datagram.is_empty() {
+            return;
+        }
+        if let Some(&index) = self.latest.get(&addr)
+            && self.groups[index].try_push(datagram)
+        {
+            return;
+        }
+        self.latest.insert(addr, self.groups.len());
+        self.groups.push(Segments { addr, buf: datagram.to_vec(), segment_size: datagram.len(), closed: false });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// 取出所有组（同一端点的组按追加顺序排列），批次清空
+    pub fn take(&mut self) -> Vec<Segments> {
+        self.latest.clear();
+        std::mem::take(&mut self.groups)
+    }
+}
+
+/// 当前平台是否支持 UDP GSO
+pub fn supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// 用一次 sendmsg 发出一组数据报
+///
+/// 只有一段时等同于普通发送。内核或网卡不支持、段长超过路径 MTU 等情况返回错误，由调用方改为逐个发送
+#[cfg(feature = "tokio")]
+pub async fn send(socket: &UdpSocket, segments: &Segments) -> io::Result<()> {
+    if segments.count() == 1 {
+        return socket.send_to(&segments.buf, segments.addr).await.map(|_| ());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        let v6_socket = socket.local_addr()?.is_ipv6();
+        socket
+            .async_io(tokio::io::Interest::WRITABLE, || sys::send_segments(socket.as_raw_fd(), segments, v6_socket))
+            .await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+#[cfg(all(feature = "tokio", target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::{IpAddr, SocketAddr};
+    use std::os::fd::RawFd;
+
+    use super::Segments;
+
+    /// 写入目的地址，返回地址长度；IPv6 socket 上的 IPv4 地址写成映射地址
+    fn sockaddr(addr: SocketAddr, v6_socket: bool, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        let ip = match (addr.ip(), v6_socket) {
+            (IpAddr::V4(ip), true) => IpAddr::V6(ip.to_ipv6_mapped()),
+            (ip, _) => ip,
+        };
+        match ip {
+            IpAddr::V4(ip) => {
+                // SAFETY: sockaddr_storage 足够大且对齐，可以容纳 sockaddr_in
+                let sin = unsafe { &mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(ip).to_be();
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            IpAddr::V6(ip) => {
+                // SAFETY: 同上，可以容纳 sockaddr_in6
+                let sin6 = unsafe { &mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = ip.octets();
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+
+    pub fn send_segments(fd: RawFd, segments: &Segments, v6_socket: bool) -> io::Result<()> {
+        // SAFETY: 全零是 sockaddr_storage / msghdr 的合法值
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let name_len = sockaddr(segments.addr, v6_socket, &mut storage);
+        let mut iov = libc::iovec { iov_base: segments.buf.as_ptr() as *mut libc::c_void, iov_len: segments.buf.len() };
+        // 按 u64 对齐的控制消息缓冲区，容纳一个 u16
+        let mut control = [0u64; 4];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = (&mut storage as *mut libc::sockaddr_storage).cast();
+        msg.msg_namelen = name_len;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        // SAFETY: CMSG_* 只做长度计算和指针运算，控制消息缓冲区足够容纳一条 u16 控制消息
+        unsafe {
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<u16>(), segments.segment_size as u16);
+        }
+        // SAFETY: msg 指向的地址、数据和控制消息在调用期间有效
+        if unsafe { libc::sendmsg(fd, &msg, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([198, 51, 100, 7], port))
+    }
+
+    #[test]
+    fn test_batch_groups() {
+        let mut batch = GsoBatch::new();
+        batch.push(addr(1), &[1; 100]);
+        batch.push(addr(2), &[2; 100]);
+        batch.push(addr(1), &[1; 100]);
+        // 更短的一段结束这一组
+        batch.push(addr(1), &[1; 40]);
+        batch.push(addr(1), &[1; 100]);
+        // 更长的一段开始新的一组
+        batch.push(addr(2), &[2; 200]);
+        batch.push(addr(2), &[]);
+
+        let groups = batch.take();
+        assert!(batch.is_empty());
+        let summary: Vec<_> = groups.iter().map(|g| (g.addr.port(), g.segment_size, g.count(), g.buf.len())).collect();
+        assert_eq!(summary, [(1, 100, 3, 240), (2, 100, 1, 100), (1, 100, 1, 100), (2, 200, 1, 200)]);
+        let lens: Vec<_> = groups[0].datagrams().map(<[u8]>::len).collect();
+        assert_eq!(lens, [100, 100, 40]);
+    }
+
+    #[test]
+    fn test_batch_limits() {
+        let mut batch = GsoBatch::new();
+        for _ in 0..MAX_SEGMENTS + 1 {
+            batch.push(addr(1), &[0; 10]);
+        }
+        for _ in 0..50 {
+            batch.push(addr(2), &[0; 1400]);
+        }
+        let counts: Vec<_> = batch.take().iter().map(|g| (g.addr.port(), g.count())).collect();
+        // 段数和总长度都不超过上限
+        assert_eq!(counts, [(1, MAX_SEGMENTS), (1, 1), (2, MAX_GSO_BYTES / 1400), (2, 50 - MAX_GSO_BYTES / 1400)]);
+    }
+
+    #[cfg(all(feature = "tokio", target_os = "linux"))]
+    #[tokio::test]
+    async fn test_send_segments() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut batch = GsoBatch::new();
+        for (i, len) in [300, 300, 300, 120].into_iter().enumerate() {
+            batch.push(receiver.local_addr().unwrap(), &vec![i as u8; len]);
+        }
+        let groups = batch.take();
+        assert_eq!(groups.len(), 1);
+        send(&sender, &groups[0]).await.unwrap();
+
+        // 接收端看到的是独立的数据报
+        let mut buf = [0u8; 2048];
+        for (i, len) in [300, 300, 300, 120].into_iter().enumerate() {
+            let (n, _) = receiver.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, len);
+            assert!(buf[..n].iter().all(|b| *b == i as u8));
+        }
+    }
+}
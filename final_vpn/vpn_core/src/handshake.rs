@@ -2,60 +2,317 @@
 
 use anyhow::{Result, anyhow};
 use rand::rngs::OsRng;
-use x25519_dalek::{EphemeralSecret, PublicKey};
-use serde::{Serialize, Deserialize};
+use rand::{CryptoRng, RngCore};
+use x25519_dalek::{PublicKey, StaticSecret};
 use blake3::Hasher;
 use pqc_kyber::*;
+use subtle::ConstantTimeEq;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-/// 握手消息类型
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::asymmetric::{ClientIdentity, ClientVerifier, ServerIdentity};
+use crate::kdf;
+use crate::psk::{self, PskId};
+use crate::symmetric::Cipher;
+use crate::wire::{self, Fields, PacketType, Writer};
+
+/// ClientFinish 中加密的确认值
+const CLIENT_FINISH_CONFIRM: &[u8] = b"CLIENT_FINISH_CONFIRM";
+/// ServerFinish 中加密的确认值（与客户端的不同，反射回去的 ClientFinish 不能当作服务端的确认）
+const SERVER_FINISH_CONFIRM: &[u8] = b"SERVER_FINISH_CONFIRM";
+
+/// ClientHello 中请求服务端分配虚拟 IP 时填写的值，分配结果在 ServerHello 的 assigned_ip 中
+pub const AUTO_VIRTUAL_IP: &str = "auto";
+
+/// 客户端的功能版本，随 ClientHello 上报（不带的旧版本客户端视为 0）
+///
+/// 与 WIRE_VERSION（编码格式）不同：编码仍然兼容、但服务端需要能拒绝更旧的客户端的变化（安全修复、行为变化）时加 1，
+/// 运营方用服务端的 --min-client-version 要求客户端升级
+pub const FEATURE_VERSION: u32 = 2;
+
+/// 功能标志：握手时双方各自声明支持（并已启用）的功能，按位与得到本次会话可以使用的功能
+///
+/// 新功能分配新的位，一经使用不再改变含义。对端没有声明（旧版本）时取不到交集，
+/// 依赖功能标志的行为在这种情况下保持原来的做法，不影响与旧版本互通
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// 控制通道内的密钥轮换（RekeyRequest）
+    pub const REKEY: Self = Self(1 << 0);
+    /// 会话恢复：地址变化或重启后凭票据快速重连（Resume）
+    pub const ROAMING: Self = Self(1 << 1);
+    /// 隧道内压缩（保留，当前版本不声明）
+    pub const COMPRESSION: Self = Self(1 << 2);
+    /// 隧道内 IPv6
+    pub const IPV6: Self = Self(1 << 3);
+    /// 前向纠错
+    pub const FEC: Self = Self(1 << 4);
+    /// 多路径绑定（PathJoin）
+    pub const BONDING: Self = Self(1 << 5);
+
+    const NAMES: [(Self, &'static str); 6] = [
+        (Self::REKEY, "rekey"),
+        (Self::ROAMING, "roaming"),
+        (Self::COMPRESSION, "compression"),
+        (Self::IPV6, "ipv6"),
+        (Self::FEC, "fec"),
+        (Self::BONDING, "bonding"),
+    ];
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// enabled 为 true 时加上 flag
+    pub fn with(self, flag: Self, enabled: bool) -> Self {
+        if enabled { Self(self.0 | flag.0) } else { self }
+    }
+
+    /// 双方都支持的功能
+    pub fn intersect(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// 逗号分隔的功能名称，不认识的位（更新版本的对端）显示为 bitN
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = (0..32)
+            .filter(|bit| self.0 & (1 << bit) != 0)
+            .map(|bit| match Self::NAMES.iter().find(|(flag, _)| flag.0 == 1 << bit) {
+                Some((_, name)) => name.to_string(),
+                None => format!("bit{}", bit),
+            })
+            .collect();
+        if names.is_empty() { f.write_str("（无）") } else { f.write_str(&names.join(",")) }
+    }
+}
+
+/// 握手消息类型（编码见 serialize_message）
+#[derive(Debug, Clone, PartialEq)]
 pub enum HandshakeMessage {
     /// 客户端发起握手：携带客户端的临时公钥（X25519 + ML-KEM）
     ClientHello {
         client_pubkey: [u8; 32],        // X25519 公钥
         client_mlkem_pk: Vec<u8>,       // ML-KEM-768 公钥
-        client_id: String,              // 客户端标识（可选，用于日志）
-        virtual_ip: String,             // 客户端的虚拟 IP 地址
+        client_id: String,              // 客户端 UUID（持久化的客户端身份）
+        virtual_ip: String,             // 客户端的虚拟 IP 地址，或 AUTO_VIRTUAL_IP 请求服务端分配
+        identity_key: [u8; 32],         // 客户端身份公钥（Ed25519）
+        identity_signature: Vec<u8>,    // 身份私钥对本次握手的签名，见 client_identity_message
+        cookie: Vec<u8>,                // 服务端下发的地址 cookie（首次为空，见 CookieJar）
+        fec: Option<u8>,                // 请求的 FEC 分组大小（见 fec 模块，不使用时不编码）
+        capabilities: Option<Capabilities>, // 客户端的功能标志（旧版本客户端不带）
+        feature_version: Option<u32>,   // 客户端的 FEATURE_VERSION（旧版本客户端不带）
+        psk_id: Option<PskId>,          // 客户端使用的 PSK 的 ID，服务端据此在轮换期间选择 PSK（见 psk 模块，旧版本客户端不带）
     },
     
     /// 服务端响应：携带服务端的临时公钥和封装的ML-KEM密文
     ServerHello {
         server_pubkey: [u8; 32],        // X25519 公钥
         mlkem_ciphertext: Vec<u8>,      // ML-KEM 密文（封装的共享密钥）
-        signature: Vec<u8>,             // 服务端对握手消息的签名
+        observed_addr: SocketAddr,      // 服务端看到的客户端地址
+        signature: Vec<u8>,             // 服务端对握手转录的签名（覆盖 ClientHello 和本消息的其余全部字段），见 server_hello_message
+        fec: Option<u8>,                // 服务端接受的 FEC 分组大小
+        server_time: Option<u64>,       // 服务端的 Unix 时间（秒），客户端据此校正时钟偏差，见 resume::record_server_time
+        assigned_ip: Option<Ipv4Addr>,  // 客户端请求 AUTO_VIRTUAL_IP 时服务端分配的虚拟 IP（不分配时不编码）
+        capabilities: Option<Capabilities>, // 服务端的功能标志，只回复给声明了功能标志的客户端
     },
     
-    /// 客户端确认：用会话密钥加密的确认消息
+    /// 客户端确认：证明持有会话密钥，服务端收到之前不接受这个会话的数据包（见 client_finish）
     ClientFinish {
-        encrypted_confirm: Vec<u8>,  // 用会话密钥加密的随机数
+        encrypted_confirm: Vec<u8>,  // 用会话密钥加密的确认值
     },
     
-    /// 服务端最终确认
+    /// 服务端最终确认：回复 ClientFinish（带 encrypted_confirm），也用于认证结果和拒绝
     ServerFinish {
         success: bool,
+        encrypted_confirm: Vec<u8>,     // 确认 ClientFinish 时为用会话密钥加密的确认值（见 server_finish），其他情况为空
     },
+    
+    /// 客户端认证扩展：用会话密钥加密的 AuthCredential（服务端启用外部认证时需要）
+    ClientAuth {
+        encrypted_credential: Vec<u8>,
+    },
+    
+    /// 服务端要求客户端带上 cookie 重发 ClientHello，证明来源地址可达
+    Cookie {
+        cookie: Vec<u8>,
+    },
+
+    /// 客户端用上次会话的票据恢复会话，跳过完整握手（见 resume 模块）
+    Resume {
+        ticket: [u8; 16],               // 服务端下发的票据 ID
+        proof: Vec<u8>,                 // 用票据对应的会话密钥加密的时间戳，见 resume::resume_proof
+        fec: Option<u8>,                // 同 ClientHello
+    },
+
+    /// 服务端接受会话恢复（拒绝时回复 ServerFinish { success: false }）
+    ResumeAck {
+        proof: Vec<u8>,                 // 用会话密钥加密的确认值，见 resume::resume_ack
+        fec: Option<u8>,                // 同 ServerHello
+    },
+
+    /// 客户端从第二条链路加入已有会话（见 multipath 模块）
+    PathJoin {
+        path_id: [u8; 16],              // 由会话密钥派生的路径 ID
+        proof: Vec<u8>,                 // 用会话密钥加密的时间戳，见 multipath::join_proof
+    },
+
+    /// 服务端接受第二条链路（发往该链路的地址）
+    PathAck {
+        proof: Vec<u8>,                 // 见 multipath::join_ack
+    },
+
+    /// 服务端拒绝握手的原因（服务端 --stealth 时不发送，见 HandshakeErrorCode）
+    HandshakeError {
+        code: u8,                       // HandshakeErrorCode，保留原始值以便识别新版本增加的错误码
+        detail: String,                 // 补充说明（可为空）
+        observed_addr: SocketAddr,      // 服务端看到的客户端地址（纳入签名）
+        signature: Vec<u8>,             // 见 handshake_error_message；无法验证来源地址时为空
+    },
+}
+
+/// HandshakeError 的错误码（wire 编码，一经使用不再改变）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeErrorCode {
+    /// 握手消息的协议版本与服务端不兼容
+    BadVersion = 1,
+    /// 客户端身份签名无效，或 client_id 已登记了另一把公钥
+    Unauthorized = 2,
+    /// 服务端已达到 --max-clients
+    ServerFull = 3,
+    /// 虚拟 IP 正被其他客户端使用，或与身份绑定的地址不一致
+    IpConflict = 4,
+    /// 客户端的 FEATURE_VERSION 低于服务端的 --min-client-version，detail 为运营方给出的升级说明
+    UpgradeRequired = 5,
+    /// ClientHello 中的 ML-KEM 公钥无法用于封装（客户端实现有误，或消息在途中被改动）
+    BadKeyShare = 6,
+    /// 同一身份已在其他地址在线，服务端按 --duplicate-policy reject 拒绝新连接
+    DuplicateSession = 7,
+}
+
+impl HandshakeErrorCode {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(HandshakeErrorCode::BadVersion),
+            2 => Some(HandshakeErrorCode::Unauthorized),
+            3 => Some(HandshakeErrorCode::ServerFull),
+            4 => Some(HandshakeErrorCode::IpConflict),
+            5 => Some(HandshakeErrorCode::UpgradeRequired),
+            6 => Some(HandshakeErrorCode::BadKeyShare),
+            7 => Some(HandshakeErrorCode::DuplicateSession),
+            _ => None,
+        }
+    }
+
+    /// 面向用户的说明
+    pub fn describe(&self) -> &'static str {
+        match self {
+            HandshakeErrorCode::BadVersion => "协议版本不兼容，请将客户端和服务端升级到同一版本",
+            HandshakeErrorCode::Unauthorized => "客户端身份未获授权（签名无效、该客户端 ID 已登记了另一把公钥，或使用的 PSK 已失效）",
+            HandshakeErrorCode::ServerFull => "服务端已达到最大客户端数",
+            HandshakeErrorCode::IpConflict => "虚拟 IP 冲突（已被其他客户端使用，或与身份绑定的地址不一致）",
+            HandshakeErrorCode::UpgradeRequired => "客户端版本过旧，服务端要求升级后再连接",
+            HandshakeErrorCode::BadKeyShare => "ClientHello 中的 ML-KEM 公钥无效，服务端无法完成密钥交换",
+            HandshakeErrorCode::DuplicateSession => "该客户端身份已在其他地方连接，服务端拒绝重复连接",
+        }
+    }
+}
+
+/// HandshakeError 的可读描述：已知错误码的说明加上服务端给出的补充说明
+pub fn describe_handshake_error(code: u8, detail: &str) -> String {
+    let reason = match HandshakeErrorCode::from_u8(code) {
+        Some(code) => code.describe().to_string(),
+        None => format!("未知错误码 {}", code),
+    };
+    if detail.is_empty() { reason } else { format!("{}：{}", reason, detail) }
+}
+
+/// HandshakeError 签名覆盖的内容：域分隔前缀 || 错误码 || client_pubkey || 服务端看到的客户端地址 || detail
+///
+/// client_pubkey 每次握手都不同，截获的错误响应不能在之后的握手中重放
+pub fn handshake_error_message(code: u8, detail: &str, client_pubkey: &[u8; 32], observed_addr: SocketAddr) -> Vec<u8> {
+    let mut message = b"RV-HANDSHAKE-ERROR".to_vec();
+    message.push(code);
+    message.extend_from_slice(client_pubkey);
+    message.extend_from_slice(observed_addr.to_string().as_bytes());
+    message.push(0);
+    message.extend_from_slice(detail.as_bytes());
+    message
+}
+
+/// 客户端认证凭据，交给服务端的认证后端校验
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthCredential {
+    /// OIDC access token（通常由设备授权流程获取）
+    OidcToken { access_token: String },
+    /// LDAP 简单绑定
+    LdapBind { username: String, password: String },
+}
+
+impl AuthCredential {
+    /// 用会话密钥加密凭据，生成 ClientAuth 消息
+    pub fn seal(&self, session_key: &[u8; 32]) -> Result<HandshakeMessage> {
+        
+        let plaintext = self.encode()?;
+        let encrypted_credential = Cipher::new(session_key)?.encrypt(&plaintext)?;
+        
+        Ok(HandshakeMessage::ClientAuth { encrypted_credential })
+    }
+    
+    /// 解密 ClientAuth 中的凭据
+    pub fn open(encrypted_credential: &[u8], session_key: &[u8; 32]) -> Result<Self> {
+        
+        let plaintext = Cipher::new(session_key)?.decrypt(encrypted_credential)?;
+        Self::decode(&plaintext)
+            .map_err(|e| anyhow!("Failed to deserialize credential: {}", e))
+    }
+    
+    fn encode(&self) -> Result<Vec<u8>> {
+        match self {
+            AuthCredential::OidcToken { access_token } => Writer::new(&[], CREDENTIAL_OIDC_TOKEN)
+                .str(1, access_token),
+            AuthCredential::LdapBind { username, password } => Writer::new(&[], CREDENTIAL_LDAP_BIND)
+                .str(1, username)
+                .str(2, password),
+        }
+        .finish()
+    }
+    
+    fn decode(data: &[u8]) -> Result<Self> {
+        let f = Fields::parse(data)?;
+        match f.kind {
+            CREDENTIAL_OIDC_TOKEN => Ok(AuthCredential::OidcToken { access_token: f.string(1)? }),
+            CREDENTIAL_LDAP_BIND => Ok(AuthCredential::LdapBind { username: f.string(1)?, password: f.string(2)? }),
+            other => Err(anyhow!("未知的凭据类型: {}", other)),
+        }
+    }
 }
 
 /// 握手状态机 - 客户端
 pub struct ClientHandshake {
-    client_secret: EphemeralSecret,
+    client_secret: StaticSecret,
     client_pubkey: PublicKey,
     mlkem_keypair: Keypair,         // ML-KEM-768 密钥对
     psk: [u8; 32],                  // 预共享密钥（用于认证）
+    transcript: Transcript,         // 握手转录（见 verify_server_hello）
 }
 
 /// 握手状态机 - 服务端
 pub struct ServerHandshake {
-    server_secret: EphemeralSecret,
+    server_secret: StaticSecret,
     server_pubkey: PublicKey,
     psk: [u8; 32],
+    transcript: Transcript,         // 握手转录（见 sign_server_hello）
 }
 
 impl ClientHandshake {
     /// 创建新的客户端握手实例（混合：X25519 + ML-KEM-768）
     pub fn new(psk: &[u8; 32]) -> Self {
         // X25519 密钥对
-        let client_secret = EphemeralSecret::random_from_rng(OsRng);
+        let client_secret = StaticSecret::random_from_rng(OsRng);
         let client_pubkey = PublicKey::from(&client_secret);
         
         // ML-KEM-768 密钥对
@@ -67,17 +324,60 @@ impl ClientHandshake {
             client_pubkey,
             mlkem_keypair,
             psk: *psk,
+            transcript: Transcript::default(),
         }
     }
     
-    /// 生成 ClientHello 消息（包含X25519和ML-KEM公钥）
-    pub fn create_client_hello(&self, client_id: String, virtual_ip: String) -> HandshakeMessage {
-        HandshakeMessage::ClientHello {
-            client_pubkey: self.client_pubkey.to_bytes(),
-            client_mlkem_pk: self.mlkem_keypair.public.to_vec(),
+    /// 用固定的临时密钥创建（互通测试，见 vpn_handshake_tool）
+    ///
+    /// mlkem_seed 为 64 字节，按 draft-schwabe-cfrg-kyber 确定性地派生 ML-KEM 密钥对。
+    /// 正常握手不能使用：临时密钥固定后会话密钥也是固定的
+    pub fn from_fixed(psk: &[u8; 32], x25519_secret: [u8; 32], mlkem_seed: &[u8; 64]) -> Result<Self> {
+        let client_secret = StaticSecret::from(x25519_secret);
+        let mlkem_keypair = derive(mlkem_seed).map_err(|e| anyhow!("ML-KEM keypair derivation failed: {:?}", e))?;
+        Ok(Self {
+            client_pubkey: PublicKey::from(&client_secret),
+            client_secret,
+            mlkem_keypair,
+            psk: *psk,
+            transcript: Transcript::default(),
+        })
+    }
+    
+    /// 生成 ClientHello 消息（包含X25519和ML-KEM公钥，并用客户端身份签名）
+    pub fn create_client_hello(&self, identity: &ClientIdentity, virtual_ip: String) -> Result<HandshakeMessage> {
+        let client_pubkey = self.client_pubkey.to_bytes();
+        let client_mlkem_pk = self.mlkem_keypair.public.to_vec();
+        let client_id = identity.id().to_string();
+        let message = client_identity_message(&client_pubkey, &client_mlkem_pk, &client_id, &virtual_ip);
+        Ok(HandshakeMessage::ClientHello {
+            client_pubkey,
+            client_mlkem_pk,
+            identity_signature: identity.sign(&message)?,
+            identity_key: identity.public_key_bytes(),
             client_id,
             virtual_ip,
+            cookie: Vec::new(),
+            fec: None,
+            capabilities: None,
+            feature_version: Some(FEATURE_VERSION),
+            psk_id: Some(psk::psk_id(&self.psk)),
+        })
+    }
+    
+    /// 验证 ServerHello 的签名：client_hello 为最后发出的 ClientHello（调用方填好 fec 等字段之后的版本）
+    ///
+    /// 签名覆盖两条消息的转录哈希，任何字段被改动都会验证失败
+    pub fn verify_server_hello(&mut self, client_hello: &HandshakeMessage, server_hello: &HandshakeMessage, verifier: &ClientVerifier) -> Result<()> {
+        let HandshakeMessage::ServerHello { signature, .. } = server_hello else {
+            return Err(anyhow!("不是 ServerHello"));
+        };
+        if !matches!(client_hello, HandshakeMessage::ClientHello { .. }) {
+            return Err(anyhow!("不是 ClientHello"));
         }
+        self.transcript.absorb(client_hello)?;
+        self.transcript.absorb(server_hello)?;
+        verifier.verify(&server_hello_message(&self.transcript.hash()), signature)
     }
     
     /// 处理 ServerHello，计算会话密钥（混合：X25519 + ML-KEM，消耗self）
@@ -91,8 +391,8 @@ impl ClientHandshake {
         let mlkem_shared = decapsulate(mlkem_ciphertext, &self.mlkem_keypair.secret)
             .map_err(|e| anyhow!("ML-KEM decapsulation failed: {:?}", e))?;
         
-        // 3. 使用 BLAKE3 派生会话密钥，组合两个共享密钥和 PSK
-        // 会话密钥 = KDF(ECDH_shared || ML-KEM_shared || PSK)
+        // 3. 派生会话密钥，组合两个共享密钥和 PSK
+        // 会话密钥 = HKDF-Expand(HKDF-Extract(PSK, ECDH_shared || ML-KEM_shared), "session")
         let session_key = derive_hybrid_session_key(
             ecdh_shared.as_bytes(),
             mlkem_shared.as_ref(),
@@ -101,53 +401,78 @@ impl ClientHandshake {
         
         Ok(session_key)
     }
-    
-    /// 创建 ClientFinish 消息（用会话密钥加密确认）
-    pub fn create_client_finish(&self, session_key: &[u8; 32]) -> Result<HandshakeMessage> {
-        use crate::symmetric::Cipher;
-        
-        // 生成一个随机确认消息
-        let confirm_data = b"CLIENT_FINISH_CONFIRM";
-        
-        let cipher = Cipher::new(session_key)?;
-        let encrypted_confirm = cipher.encrypt(confirm_data)?;
-        
-        Ok(HandshakeMessage::ClientFinish {
-            encrypted_confirm,
-        })
-    }
 }
 
 impl ServerHandshake {
     /// 创建新的服务端握手实例
     pub fn new(psk: &[u8; 32]) -> Self {
-        let server_secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_secret = StaticSecret::random_from_rng(OsRng);
         let server_pubkey = PublicKey::from(&server_secret);
         
         Self {
             server_secret,
             server_pubkey,
             psk: *psk,
+            transcript: Transcript::default(),
+        }
+    }
+    
+    /// 用固定的临时密钥创建（互通测试，见 vpn_handshake_tool）
+    pub fn from_fixed(psk: &[u8; 32], x25519_secret: [u8; 32]) -> Self {
+        let server_secret = StaticSecret::from(x25519_secret);
+        Self {
+            server_pubkey: PublicKey::from(&server_secret),
+            server_secret,
+            psk: *psk,
+            transcript: Transcript::default(),
         }
     }
     
-    /// 处理 ClientHello，生成 ServerHello（使用ML-KEM封装，不包含签名）
-    pub fn process_client_hello(&self, _client_pubkey: [u8; 32], client_mlkem_pk: &[u8]) -> Result<(HandshakeMessage, SharedSecret)> {
+    /// 处理 ClientHello（计入握手转录），生成 ServerHello（使用ML-KEM封装，签名见 sign_server_hello）
+    pub fn process_client_hello(&mut self, client_hello: &HandshakeMessage, observed_addr: SocketAddr) -> Result<(HandshakeMessage, SharedSecret)> {
+        self.server_hello(client_hello, observed_addr, &mut OsRng, crate::resume::unix_now())
+    }
+    
+    /// 同 process_client_hello，但 ML-KEM 封装使用固定的 32 字节随机数、ServerHello 使用给定的时间（互通测试）
+    pub fn process_client_hello_fixed(&mut self, client_hello: &HandshakeMessage, observed_addr: SocketAddr, coins: [u8; 32], server_time: u64) -> Result<(HandshakeMessage, SharedSecret)> {
+        self.server_hello(client_hello, observed_addr, &mut FixedCoins(coins), server_time)
+    }
+    
+    fn server_hello<R: RngCore + CryptoRng>(&mut self, client_hello: &HandshakeMessage, observed_addr: SocketAddr, rng: &mut R, server_time: u64) -> Result<(HandshakeMessage, SharedSecret)> {
+        let HandshakeMessage::ClientHello { client_mlkem_pk, .. } = client_hello else {
+            return Err(anyhow!("不是 ClientHello"));
+        };
+        self.transcript.absorb(client_hello)?;
+        
         // 使用客户端的ML-KEM公钥进行封装，生成共享密钥和密文
-        let mut rng = OsRng;
-        let (mlkem_ciphertext, mlkem_shared) = encapsulate(client_mlkem_pk, &mut rng)
+        let (mlkem_ciphertext, mlkem_shared) = encapsulate(client_mlkem_pk, rng)
             .map_err(|e| anyhow!("ML-KEM encapsulation failed: {:?}", e))?;
         
-        // 注意：signature 应该在外部由 ServerIdentity 添加
+        // fec、assigned_ip 等由调用方按需填写，之后再用 sign_server_hello 签名
         let server_hello = HandshakeMessage::ServerHello {
             server_pubkey: self.server_pubkey.to_bytes(),
             mlkem_ciphertext: mlkem_ciphertext.to_vec(),
-            signature: vec![], // 占位符，实际使用时应由外部填充
+            observed_addr,
+            signature: vec![], // 占位符，见 sign_server_hello
+            fec: None,
+            server_time: Some(server_time),
+            assigned_ip: None,
+            capabilities: None,
         };
         
         Ok((server_hello, mlkem_shared))
     }
     
+    /// 对填好全部字段的 ServerHello 签名：ServerHello 计入握手转录，签名覆盖转录哈希
+    pub fn sign_server_hello(&mut self, server_hello: &mut HandshakeMessage, identity: &ServerIdentity) -> Result<()> {
+        self.transcript.absorb(server_hello)?;
+        let HandshakeMessage::ServerHello { signature, .. } = server_hello else {
+            return Err(anyhow!("不是 ServerHello"));
+        };
+        *signature = identity.sign(&server_hello_message(&self.transcript.hash()))?;
+        Ok(())
+    }
+    
     /// 计算会话密钥（混合：X25519 + ML-KEM，与客户端计算相同，消耗self）
     pub fn compute_session_key(self, client_pubkey: [u8; 32], mlkem_shared: &SharedSecret) -> Result<[u8; 32]> {
         let client_pk = PublicKey::from(client_pubkey);
@@ -164,41 +489,42 @@ impl ServerHandshake {
         
         Ok(session_key)
     }
-    
-    /// 验证 ClientFinish 消息
-    pub fn verify_client_finish(&self, encrypted_confirm: &[u8], session_key: &[u8; 32]) -> Result<()> {
-        use crate::symmetric::Cipher;
-        
-        let cipher = Cipher::new(session_key)?;
-        let decrypted = cipher.decrypt(encrypted_confirm)?;
-        
-        // 验证确认消息
-        if decrypted == b"CLIENT_FINISH_CONFIRM" {
-            Ok(())
-        } else {
-            Err(anyhow!("ClientFinish verification failed"))
+}
+
+/// 固定的“随机数”：ML-KEM 封装只取 32 字节，用它得到可复现的密文（仅用于互通测试）
+struct FixedCoins([u8; 32]);
+
+impl RngCore for FixedCoins {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(self.0.len()) {
+            chunk.copy_from_slice(&self.0[..chunk.len()]);
         }
     }
-    
-    /// 创建 ServerFinish 消息
-    pub fn create_server_finish(&self, success: bool) -> HandshakeMessage {
-        HandshakeMessage::ServerFinish { success }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
     }
 }
 
+impl CryptoRng for FixedCoins {}
+
 /// 密钥派生函数（KDF）- 混合模式
-/// 使用 BLAKE3 从 X25519 共享密钥、ML-KEM 共享密钥和 PSK 派生会话密钥
+/// 从 X25519 共享密钥、ML-KEM 共享密钥和 PSK 派生会话密钥（HKDF 结构，见 kdf 模块）
 fn derive_hybrid_session_key(ecdh_shared: &[u8], mlkem_shared: &[u8], psk: &[u8; 32]) -> [u8; 32] {
-    let mut hasher = Hasher::new();
-    hasher.update(b"VPN_HYBRID_SESSION_KEY_V2"); // 域分隔符（版本2表示混合模式）
-    hasher.update(ecdh_shared);                  // X25519 共享密钥
-    hasher.update(mlkem_shared);                 // ML-KEM 共享密钥
-    hasher.update(psk);                          // 预共享密钥
-    
-    let hash = hasher.finalize();
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&hash.as_bytes()[..32]);
-    key
+    kdf::session_key(ecdh_shared, mlkem_shared, psk)
 }
 
 /// 旧版密钥派生函数（保留用于向后兼容）
@@ -215,16 +541,399 @@ fn derive_session_key(ecdh_shared: &[u8], psk: &[u8; 32]) -> [u8; 32] {
     key
 }
 
-/// 序列化握手消息（用于网络传输）
+/// ServerHello 签名覆盖的内容：域分隔前缀 || 握手转录哈希（见 Transcript）
+///
+/// 转录包含 ClientHello 和 ServerHello 的全部字段，签名同时认证了双方的临时公钥、ML-KEM 密文、
+/// client_id、虚拟 IP、服务端看到的客户端地址、分配的虚拟 IP 和协商的功能
+pub fn server_hello_message(transcript_hash: &[u8; 32]) -> Vec<u8> {
+    let mut message = b"RV-SERVER-HELLO".to_vec();
+    message.extend_from_slice(transcript_hash);
+    message
+}
+
+/// 握手转录的域分隔符
+const TRANSCRIPT_DOMAIN: &[u8] = b"rust-vpn handshake transcript v1";
+
+/// 握手转录：按顺序累积 ClientHello、ServerHello 的字段，ServerHello 的签名覆盖它的哈希
+///
+/// 不计入的只有 ClientHello 的 cookie（重发时会变化，只用于验证来源地址）和 ServerHello 的签名本身。
+/// 每个字段带长度前缀，可选字段带存在标志，不同的字段组合不会得到相同的编码。
+/// 握手消息增加字段时要同时加入转录，否则新字段不受签名保护
+#[derive(Clone)]
+pub struct Transcript(Hasher);
+
+impl Default for Transcript {
+    fn default() -> Self {
+        let mut hasher = Hasher::new();
+        hasher.update(TRANSCRIPT_DOMAIN);
+        Self(hasher)
+    }
+}
+
+impl Transcript {
+    /// 加入一条握手消息（只接受 ClientHello 和 ServerHello）
+    pub fn absorb(&mut self, message: &HandshakeMessage) -> Result<()> {
+        match message {
+            HandshakeMessage::ClientHello {
+                client_pubkey, client_mlkem_pk, client_id, virtual_ip, identity_key, identity_signature,
+                cookie: _, fec, capabilities, feature_version, psk_id,
+            } => {
+                self.field(&[MSG_CLIENT_HELLO]);
+                self.field(client_pubkey);
+                self.field(client_mlkem_pk);
+                self.field(client_id.as_bytes());
+                self.field(virtual_ip.as_bytes());
+                self.field(identity_key);
+                self.field(identity_signature);
+                self.optional(fec.map(|group| [group]));
+                self.optional(capabilities.map(|c| c.0.to_be_bytes()));
+                self.optional(feature_version.map(u32::to_be_bytes));
+                self.optional(*psk_id);
+            }
+            HandshakeMessage::ServerHello {
+                server_pubkey, mlkem_ciphertext, observed_addr, signature: _, fec, server_time, assigned_ip, capabilities,
+            } => {
+                self.field(&[MSG_SERVER_HELLO]);
+                self.field(server_pubkey);
+                self.field(mlkem_ciphertext);
+                self.field(observed_addr.to_string().as_bytes());
+                self.optional(fec.map(|group| [group]));
+                self.optional(server_time.map(u64::to_be_bytes));
+                self.optional(assigned_ip.map(|ip| ip.octets()));
+                self.optional(capabilities.map(|c| c.0.to_be_bytes()));
+            }
+            _ => return Err(anyhow!("握手转录只包含 ClientHello 和 ServerHello")),
+        }
+        Ok(())
+    }
+
+    /// 当前的转录哈希
+    pub fn hash(&self) -> [u8; 32] {
+        *self.0.finalize().as_bytes()
+    }
+
+    fn field(&mut self, value: &[u8]) {
+        self.0.update(&(value.len() as u32).to_be_bytes());
+        self.0.update(value);
+    }
+
+    fn optional(&mut self, value: Option<impl AsRef<[u8]>>) {
+        match value {
+            Some(value) => {
+                self.0.update(&[1]);
+                self.field(value.as_ref());
+            }
+            None => {
+                self.0.update(&[0]);
+            }
+        }
+    }
+}
+
+/// cookie 密钥的轮换周期（cookie 在 1 ~ 2 个周期内有效）
+const COOKIE_ROTATE: Duration = Duration::from_secs(120);
+/// cookie 长度
+pub const COOKIE_LEN: usize = 16;
+
+/// 无状态的地址 cookie（类似 DTLS HelloVerifyRequest / WireGuard cookie reply）
+///
+/// ClientHello 没有带上有效 cookie 时，服务端只回复一个很小的 Cookie 消息，
+/// 不做 ML-KEM 封装、不回复 1 KB 以上的 ServerHello：伪造来源地址的请求只能让服务端
+/// 向受害者发送比请求小得多的包，服务端也不会为它们消耗 CPU。
+/// cookie = BLAKE3-keyed(密钥, 来源地址 || client_pubkey)，密钥定期轮换，不需要保存任何状态。
+pub struct CookieJar {
+    secrets: Mutex<CookieSecrets>,
+}
+
+struct CookieSecrets {
+    current: [u8; 32],
+    previous: [u8; 32],
+    rotated_at: Instant,
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self {
+            secrets: Mutex::new(CookieSecrets { current: rand::random(), previous: rand::random(), rotated_at: Instant::now() }),
+        }
+    }
+    
+    /// 为该地址和临时公钥生成 cookie
+    pub fn issue(&self, addr: SocketAddr, client_pubkey: &[u8; 32]) -> Vec<u8> {
+        let secrets = self.rotate();
+        cookie_mac(&secrets.0, addr, client_pubkey).to_vec()
+    }
+    
+    /// 校验 ClientHello 带回的 cookie（当前或上一个密钥生成的都有效）
+    pub fn verify(&self, addr: SocketAddr, client_pubkey: &[u8; 32], cookie: &[u8]) -> bool {
+        let (current, previous) = self.rotate();
+        constant_time_eq(cookie, &cookie_mac(&current, addr, client_pubkey))
+            | constant_time_eq(cookie, &cookie_mac(&previous, addr, client_pubkey))
+    }
+    
+    /// 到期时轮换密钥，返回 (当前, 上一个)
+    fn rotate(&self) -> ([u8; 32], [u8; 32]) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() >= COOKIE_ROTATE {
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+            secrets.rotated_at = Instant::now();
+        }
+        (secrets.current, secrets.previous)
+    }
+    
+    #[cfg(test)]
+    fn force_rotate(&self) {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.previous = secrets.current;
+        secrets.current = rand::random();
+    }
+}
+
+fn cookie_mac(secret: &[u8; 32], addr: SocketAddr, client_pubkey: &[u8; 32]) -> [u8; COOKIE_LEN] {
+    let mut hasher = Hasher::new_keyed(secret);
+    hasher.update(addr.to_string().as_bytes());
+    hasher.update(client_pubkey);
+    let mut cookie = [0u8; COOKIE_LEN];
+    cookie.copy_from_slice(&hasher.finalize().as_bytes()[..COOKIE_LEN]);
+    cookie
+}
+
+/// 生成 ClientFinish：用会话密钥加密确认值，证明客户端算出了同一个会话密钥
+pub fn client_finish(session_key: &[u8; 32]) -> Result<HandshakeMessage> {
+    Ok(HandshakeMessage::ClientFinish { encrypted_confirm: Cipher::new(session_key)?.encrypt(CLIENT_FINISH_CONFIRM)? })
+}
+
+/// 服务端验证 ClientFinish
+pub fn verify_client_finish(encrypted_confirm: &[u8], session_key: &[u8; 32]) -> Result<()> {
+    verify_confirm(encrypted_confirm, session_key, CLIENT_FINISH_CONFIRM).map_err(|_| anyhow!("ClientFinish verification failed"))
+}
+
+/// 生成确认 ClientFinish 的 ServerFinish：服务端同样证明持有会话密钥
+pub fn server_finish(session_key: &[u8; 32]) -> Result<HandshakeMessage> {
+    Ok(HandshakeMessage::ServerFinish { success: true, encrypted_confirm: Cipher::new(session_key)?.encrypt(SERVER_FINISH_CONFIRM)? })
+}
+
+/// 客户端验证 ServerFinish 中的确认值
+pub fn verify_server_finish(encrypted_confirm: &[u8], session_key: &[u8; 32]) -> Result<()> {
+    verify_confirm(encrypted_confirm, session_key, SERVER_FINISH_CONFIRM).map_err(|_| anyhow!("ServerFinish verification failed"))
+}
+
+/// 解密失败和确认值不符返回同一个错误，确认值用常数时间比较
+fn verify_confirm(encrypted_confirm: &[u8], session_key: &[u8; 32], expected: &[u8]) -> Result<()> {
+    let decrypted = Cipher::new(session_key)?.decrypt(encrypted_confirm).unwrap_or_default();
+    if constant_time_eq(&decrypted, expected) {
+        Ok(())
+    } else {
+        Err(anyhow!("confirm mismatch"))
+    }
+}
+
+/// 客户端身份签名的域分隔符（密钥代理只签以它开头的消息）
+pub const CLIENT_IDENTITY_DOMAIN: &[u8] = b"rust-vpn client identity v1";
+
+/// ClientHello 中身份签名覆盖的内容：域分隔符 || 临时公钥 || ML-KEM 公钥 || client_id || 虚拟 IP
+///
+/// 签名绑定本次握手的临时公钥，重放别人的 ClientHello 拿不到会话密钥
+pub fn client_identity_message(client_pubkey: &[u8; 32], client_mlkem_pk: &[u8], client_id: &str, virtual_ip: &str) -> Vec<u8> {
+    let mut message = CLIENT_IDENTITY_DOMAIN.to_vec();
+    message.extend_from_slice(client_pubkey);
+    message.extend_from_slice(client_mlkem_pk);
+    message.extend_from_slice(client_id.as_bytes());
+    message.push(0);
+    message.extend_from_slice(virtual_ip.as_bytes());
+    message
+}
+
+/// 服务端校验 ClientHello 中的客户端身份：client_id 格式合法且签名有效
+pub fn verify_client_identity(hello: &HandshakeMessage) -> Result<()> {
+    let HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, client_id, virtual_ip, identity_key, identity_signature, .. } = hello else {
+        return Err(anyhow!("不是 ClientHello"));
+    };
+    if !crate::asymmetric::is_valid_client_id(client_id) {
+        return Err(anyhow!("客户端 ID 格式无效"));
+    }
+    let message = client_identity_message(client_pubkey, client_mlkem_pk, client_id, virtual_ip);
+    ClientVerifier::new(identity_key)?.verify(&message, identity_signature)
+}
+
+/// 常数时间比较两段密钥材料（MAC、确认值等），耗时不随第一个不同字节的位置变化
+///
+/// 长度不同时直接返回 false（长度本身不是秘密）
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// 握手报文的前缀：类型为握手的数据报头（见 wire 模块）
+pub const HANDSHAKE_MAGIC: [u8; wire::PACKET_HEADER_LEN] = wire::packet_header(PacketType::Handshake);
+
+// 握手消息类型码（wire 编码，一经使用不再改变）
+const MSG_CLIENT_HELLO: u8 = 1;
+const MSG_SERVER_HELLO: u8 = 2;
+const MSG_CLIENT_FINISH: u8 = 3;
+const MSG_SERVER_FINISH: u8 = 4;
+const MSG_CLIENT_AUTH: u8 = 5;
+const MSG_COOKIE: u8 = 6;
+const MSG_RESUME: u8 = 7;
+const MSG_RESUME_ACK: u8 = 8;
+const MSG_PATH_JOIN: u8 = 9;
+const MSG_PATH_ACK: u8 = 10;
+const MSG_HANDSHAKE_ERROR: u8 = 11;
+
+// 认证凭据类型码
+const CREDENTIAL_OIDC_TOKEN: u8 = 1;
+const CREDENTIAL_LDAP_BIND: u8 = 2;
+
+/// 以 HANDSHAKE_MAGIC 开头的报文的 wire 版本（用于识别无法解码的旧/新版本握手消息）
+pub fn handshake_version(data: &[u8]) -> Option<u8> {
+    data.strip_prefix(&HANDSHAKE_MAGIC[..])?.first().copied()
+}
+
+/// 序列化握手消息（用于网络传输）：HANDSHAKE_MAGIC + wire 编码，字段标签见各分支
 pub fn serialize_message(msg: &HandshakeMessage) -> Result<Vec<u8>> {
-    bincode::serialize(msg)
-        .map_err(|e| anyhow!("Failed to serialize message: {}", e))
+    let w = match msg {
+        HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, client_id, virtual_ip, identity_key, identity_signature, cookie, fec, capabilities, feature_version, psk_id } => {
+            let w = Writer::new(&HANDSHAKE_MAGIC, MSG_CLIENT_HELLO)
+                .bytes(1, client_pubkey)
+                .bytes(2, client_mlkem_pk)
+                .str(3, client_id)
+                .str(4, virtual_ip)
+                .bytes(5, identity_key)
+                .bytes(6, identity_signature);
+            // cookie 可选：首次 ClientHello 不带
+            let w = if cookie.is_empty() { w } else { w.bytes(7, cookie) };
+            let w = opt_capabilities(opt_u8(w, 8, *fec), 9, *capabilities);
+            let w = match feature_version {
+                Some(v) => w.u32(10, *v),
+                None => w,
+            };
+            match psk_id {
+                Some(id) => w.bytes(11, id),
+                None => w,
+            }
+        }
+        HandshakeMessage::ServerHello { server_pubkey, mlkem_ciphertext, observed_addr, signature, fec, server_time, assigned_ip, capabilities } => {
+            let w = Writer::new(&HANDSHAKE_MAGIC, MSG_SERVER_HELLO)
+                .bytes(1, server_pubkey)
+                .bytes(2, mlkem_ciphertext)
+                .addr(3, *observed_addr)
+                .bytes(4, signature);
+            let w = opt_u8(w, 5, *fec);
+            let w = match server_time {
+                Some(t) => w.u64(6, *t),
+                None => w,
+            };
+            let w = match assigned_ip {
+                Some(ip) => w.bytes(7, &ip.octets()),
+                None => w,
+            };
+            opt_capabilities(w, 8, *capabilities)
+        }
+        HandshakeMessage::ClientFinish { encrypted_confirm } => {
+            Writer::new(&HANDSHAKE_MAGIC, MSG_CLIENT_FINISH).bytes(1, encrypted_confirm)
+        }
+        HandshakeMessage::ServerFinish { success, encrypted_confirm } => {
+            let w = Writer::new(&HANDSHAKE_MAGIC, MSG_SERVER_FINISH).bool(1, *success);
+            // 确认值可选：认证结果和拒绝不带，编码与之前相同
+            if encrypted_confirm.is_empty() { w } else { w.bytes(2, encrypted_confirm) }
+        }
+        HandshakeMessage::ClientAuth { encrypted_credential } => {
+            Writer::new(&HANDSHAKE_MAGIC, MSG_CLIENT_AUTH).bytes(1, encrypted_credential)
+        }
+        HandshakeMessage::Cookie { cookie } => {
+            Writer::new(&HANDSHAKE_MAGIC, MSG_COOKIE).bytes(1, cookie)
+        }
+        HandshakeMessage::Resume { ticket, proof, fec } => {
+            opt_u8(Writer::new(&HANDSHAKE_MAGIC, MSG_RESUME).bytes(1, ticket).bytes(2, proof), 3, *fec)
+        }
+        HandshakeMessage::ResumeAck { proof, fec } => {
+            opt_u8(Writer::new(&HANDSHAKE_MAGIC, MSG_RESUME_ACK).bytes(1, proof), 2, *fec)
+        }
+        HandshakeMessage::PathJoin { path_id, proof } => {
+            Writer::new(&HANDSHAKE_MAGIC, MSG_PATH_JOIN).bytes(1, path_id).bytes(2, proof)
+        }
+        HandshakeMessage::PathAck { proof } => {
+            Writer::new(&HANDSHAKE_MAGIC, MSG_PATH_ACK).bytes(1, proof)
+        }
+        HandshakeMessage::HandshakeError { code, detail, observed_addr, signature } => {
+            let w = Writer::new(&HANDSHAKE_MAGIC, MSG_HANDSHAKE_ERROR)
+                .bytes(1, &[*code])
+                .str(2, detail)
+                .addr(3, *observed_addr);
+            // 签名可选：无法验证来源地址时不签名
+            if signature.is_empty() { w } else { w.bytes(4, signature) }
+        }
+    };
+    w.finish()
+}
+
+/// 可选的单字节字段：None 时不编码，旧版本对端看到的消息与之前完全相同
+fn opt_u8(w: Writer, tag: u8, value: Option<u8>) -> Writer {
+    match value {
+        Some(v) => w.bytes(tag, &[v]),
+        None => w,
+    }
+}
+
+/// 可选的功能标志：None 时不编码
+fn opt_capabilities(w: Writer, tag: u8, value: Option<Capabilities>) -> Writer {
+    match value {
+        Some(c) => w.u32(tag, c.0),
+        None => w,
+    }
 }
 
 /// 反序列化握手消息
 pub fn deserialize_message(data: &[u8]) -> Result<HandshakeMessage> {
-    bincode::deserialize(data)
-        .map_err(|e| anyhow!("Failed to deserialize message: {}", e))
+    let body = data.strip_prefix(&HANDSHAKE_MAGIC[..]).ok_or_else(|| anyhow!("不是握手消息"))?;
+    let f = Fields::parse(body).map_err(|e| anyhow!("Failed to deserialize message: {}", e))?;
+    let msg = match f.kind {
+        MSG_CLIENT_HELLO => HandshakeMessage::ClientHello {
+            client_pubkey: f.array(1)?,
+            client_mlkem_pk: f.vec(2)?,
+            client_id: f.string(3)?,
+            virtual_ip: f.string(4)?,
+            identity_key: f.array(5)?,
+            identity_signature: f.vec(6)?,
+            cookie: f.opt(7).unwrap_or_default().to_vec(),
+            fec: f.opt(8).and_then(|v| v.first().copied()),
+            capabilities: f.u32(9).ok().map(Capabilities),
+            feature_version: f.u32(10).ok(),
+            psk_id: f.array(11).ok(),
+        },
+        MSG_SERVER_HELLO => HandshakeMessage::ServerHello {
+            server_pubkey: f.array(1)?,
+            mlkem_ciphertext: f.vec(2)?,
+            observed_addr: f.addr(3)?,
+            signature: f.vec(4)?,
+            fec: f.opt(5).and_then(|v| v.first().copied()),
+            server_time: f.u64(6).ok(),
+            assigned_ip: f.opt(7).and_then(|v| <[u8; 4]>::try_from(v).ok()).map(Ipv4Addr::from),
+            capabilities: f.u32(8).ok().map(Capabilities),
+        },
+        MSG_CLIENT_FINISH => HandshakeMessage::ClientFinish { encrypted_confirm: f.vec(1)? },
+        MSG_SERVER_FINISH => HandshakeMessage::ServerFinish { success: f.bool(1)?, encrypted_confirm: f.opt(2).unwrap_or_default().to_vec() },
+        MSG_CLIENT_AUTH => HandshakeMessage::ClientAuth { encrypted_credential: f.vec(1)? },
+        MSG_COOKIE => HandshakeMessage::Cookie { cookie: f.vec(1)? },
+        MSG_RESUME => HandshakeMessage::Resume { ticket: f.array(1)?, proof: f.vec(2)?, fec: f.opt(3).and_then(|v| v.first().copied()) },
+        MSG_RESUME_ACK => HandshakeMessage::ResumeAck { proof: f.vec(1)?, fec: f.opt(2).and_then(|v| v.first().copied()) },
+        MSG_PATH_JOIN => HandshakeMessage::PathJoin { path_id: f.array(1)?, proof: f.vec(2)? },
+        MSG_PATH_ACK => HandshakeMessage::PathAck { proof: f.vec(1)? },
+        MSG_HANDSHAKE_ERROR => HandshakeMessage::HandshakeError {
+            code: f.array::<1>(1)?[0],
+            detail: f.string(2)?,
+            observed_addr: f.addr(3)?,
+            signature: f.opt(4).unwrap_or_default().to_vec(),
+        },
+        other => return Err(anyhow!("未知的握手消息类型: {}", other)),
+    };
+    Ok(msg)
 }
 
 #[cfg(test)]
@@ -242,18 +951,31 @@ mod tests {
         psk_32[..28].copy_from_slice(psk);
         
         // 1. 客户端和服务端初始化
-        let client = ClientHandshake::new(&psk_32);
-        let server = ServerHandshake::new(&psk_32);
+        let mut client = ClientHandshake::new(&psk_32);
+        let mut server = ServerHandshake::new(&psk_32);
         
         // 2. ClientHello（包含X25519和ML-KEM公钥）
-        let client_hello = client.create_client_hello("test_client".to_string(), "10.0.0.2".to_string());
-        let (client_pubkey, client_mlkem_pk) = match &client_hello {
-            HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, .. } => (*client_pubkey, client_mlkem_pk.clone()),
+        let identity = ClientIdentity::generate();
+        let mut client_hello = client.create_client_hello(&identity, "10.0.0.2".to_string()).unwrap();
+        assert!(verify_client_identity(&client_hello).is_ok());
+        
+        // 篡改虚拟 IP 后签名失效
+        if let HandshakeMessage::ClientHello { virtual_ip, .. } = &mut client_hello {
+            *virtual_ip = "10.0.0.3".to_string();
+        }
+        assert!(verify_client_identity(&client_hello).is_err());
+        let client_pubkey = match &client_hello {
+            HandshakeMessage::ClientHello { client_pubkey, .. } => *client_pubkey,
             _ => panic!("Wrong message type"),
         };
         
-        // 3. ServerHello（使用ML-KEM封装）
-        let (server_hello, mlkem_shared) = server.process_client_hello(client_pubkey, &client_mlkem_pk).unwrap();
+        // 3. ServerHello（使用ML-KEM封装，服务端对握手转录签名）
+        let observed: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let (mut server_hello, mlkem_shared) = server.process_client_hello(&client_hello, observed).unwrap();
+        let server_identity = ServerIdentity::from_key_bytes(&[6u8; 32]);
+        server.sign_server_hello(&mut server_hello, &server_identity).unwrap();
+        let verifier = ClientVerifier::new(&server_identity.public_key_bytes()).unwrap();
+        client.verify_server_hello(&client_hello, &server_hello, &verifier).unwrap();
         let (server_pubkey, mlkem_ciphertext) = match &server_hello {
             HandshakeMessage::ServerHello { server_pubkey, mlkem_ciphertext, .. } => (*server_pubkey, mlkem_ciphertext.clone()),
             _ => panic!("Wrong message type"),
@@ -266,10 +988,93 @@ mod tests {
         // 5. 验证双方计算出相同的会话密钥
         assert_eq!(client_session_key, server_session_key);
         
+        // 6. ClientFinish / ServerFinish：双方证明持有会话密钥
+        let HandshakeMessage::ClientFinish { encrypted_confirm } = client_finish(&client_session_key).unwrap() else { panic!() };
+        assert!(verify_client_finish(&encrypted_confirm, &server_session_key).is_ok());
+        let HandshakeMessage::ServerFinish { encrypted_confirm, .. } = server_finish(&server_session_key).unwrap() else { panic!() };
+        assert!(verify_server_finish(&encrypted_confirm, &client_session_key).is_ok());
+        
         println!("✅ 混合密钥交换测试通过！");
         println!("   - X25519 ECDH: ✓");
         println!("   - ML-KEM-768: ✓");
         println!("   - 会话密钥一致: ✓");
+        println!("   - 密钥确认: ✓");
+    }
+
+    #[test]
+    fn test_fixed_handshake_is_reproducible() {
+        // 固定输入时两端得到相同且可复现的会话密钥（vpn_handshake_tool 的测试向量依赖这一点）
+        let psk = [3u8; 32];
+        let observed: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let run = || {
+            let client = ClientHandshake::from_fixed(&psk, [1u8; 32], &[2u8; 64]).unwrap();
+            let mut server = ServerHandshake::from_fixed(&psk, [4u8; 32]);
+            let client_pubkey = client.client_pubkey.to_bytes();
+            let client_hello = client.create_client_hello(&ClientIdentity::generate(), "10.0.0.2".to_string()).unwrap();
+            let (hello, shared) = server.process_client_hello_fixed(&client_hello, observed, [5u8; 32], 1_700_000_000).unwrap();
+            let HandshakeMessage::ServerHello { server_pubkey, ref mlkem_ciphertext, server_time, .. } = hello else { panic!() };
+            assert_eq!(server_time, Some(1_700_000_000));
+            let client_key = client.process_server_hello(server_pubkey, mlkem_ciphertext).unwrap();
+            let server_key = server.compute_session_key(client_pubkey, &shared).unwrap();
+            assert_eq!(client_key, server_key);
+            (serialize_message(&hello).unwrap(), client_key)
+        };
+        assert_eq!(run(), run());
+        // 不同的封装随机数得到不同的密钥
+        let mut server = ServerHandshake::from_fixed(&psk, [4u8; 32]);
+        let client = ClientHandshake::from_fixed(&psk, [1u8; 32], &[2u8; 64]).unwrap();
+        let client_hello = client.create_client_hello(&ClientIdentity::generate(), "10.0.0.2".to_string()).unwrap();
+        let (_, a) = server.process_client_hello_fixed(&client_hello, observed, [5u8; 32], 0).unwrap();
+        let (_, b) = server.process_client_hello_fixed(&client_hello, observed, [6u8; 32], 0).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        let jar = CookieJar::new();
+        let addr: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let pubkey = [9u8; 32];
+        let cookie = jar.issue(addr, &pubkey);
+        assert_eq!(cookie.len(), COOKIE_LEN);
+        assert!(jar.verify(addr, &pubkey, &cookie));
+        
+        // 换了来源地址或临时公钥都不能复用
+        assert!(!jar.verify("198.51.100.1:40001".parse().unwrap(), &pubkey, &cookie));
+        assert!(!jar.verify(addr, &[8u8; 32], &cookie));
+        assert!(!jar.verify(addr, &pubkey, &[]));
+        
+        // 轮换一次后仍然有效，两次后失效
+        jar.force_rotate();
+        assert!(jar.verify(addr, &pubkey, &cookie));
+        jar.force_rotate();
+        assert!(!jar.verify(addr, &pubkey, &cookie));
+    }
+    
+    #[test]
+    fn test_verify_finish() {
+        let key = [1u8; 32];
+        let confirm = match client_finish(&key).unwrap() {
+            HandshakeMessage::ClientFinish { encrypted_confirm } => encrypted_confirm,
+            _ => panic!("Wrong message type"),
+        };
+        assert!(verify_client_finish(&confirm, &key).is_ok());
+
+        // 密钥错误和内容被篡改返回同一个错误
+        let wrong_key = verify_client_finish(&confirm, &[2u8; 32]).unwrap_err();
+        let mut tampered = confirm.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let bad_mac = verify_client_finish(&tampered, &key).unwrap_err();
+        assert_eq!(wrong_key.to_string(), bad_mac.to_string());
+
+        // 两个方向的确认值不能互换
+        let HandshakeMessage::ServerFinish { success: true, encrypted_confirm: server_confirm } = server_finish(&key).unwrap() else { panic!() };
+        assert!(verify_server_finish(&server_confirm, &key).is_ok());
+        assert!(verify_server_finish(&confirm, &key).is_err());
+        assert!(verify_client_finish(&server_confirm, &key).is_err());
+
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
     }
     
     #[test]
@@ -279,19 +1084,203 @@ mod tests {
             client_mlkem_pk: vec![2u8; 1184], // ML-KEM-768 公钥大小
             client_id: "test".to_string(),
             virtual_ip: "10.0.0.2".to_string(),
+            identity_key: [3u8; 32],
+            identity_signature: vec![4u8; 64],
+            cookie: vec![5u8; COOKIE_LEN],
+            fec: Some(4),
+            capabilities: Some(Capabilities::REKEY.with(Capabilities::IPV6, true)),
+            feature_version: Some(FEATURE_VERSION),
+            psk_id: Some([6u8; 8]),
         };
         
         let serialized = serialize_message(&msg).unwrap();
         let deserialized = deserialize_message(&serialized).unwrap();
         
         match deserialized {
-            HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, client_id, virtual_ip } => {
+            HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, client_id, virtual_ip, identity_key, identity_signature, cookie, fec, capabilities, feature_version, psk_id } => {
+                assert_eq!(fec, Some(4));
+                assert_eq!(feature_version, Some(FEATURE_VERSION));
+                assert_eq!(psk_id, Some([6u8; 8]));
+                assert_eq!(capabilities, Some(Capabilities::REKEY.with(Capabilities::IPV6, true)));
                 assert_eq!(client_pubkey, [1u8; 32]);
                 assert_eq!(client_mlkem_pk, vec![2u8; 1184]);
                 assert_eq!(client_id, "test");
                 assert_eq!(virtual_ip, "10.0.0.2");
+                assert_eq!(identity_key, [3u8; 32]);
+                assert_eq!(identity_signature, vec![4u8; 64]);
+                assert_eq!(cookie, vec![5u8; COOKIE_LEN]);
             }
             _ => panic!("Wrong message type"),
         }
     }
+    
+    #[test]
+    fn test_wire_compat() {
+        let observed: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let messages = [
+            HandshakeMessage::ServerHello { server_pubkey: [1u8; 32], mlkem_ciphertext: vec![2u8; 1088], observed_addr: observed, signature: vec![3u8; 64], fec: None, server_time: None, assigned_ip: None, capabilities: None },
+            HandshakeMessage::ServerHello { server_pubkey: [1u8; 32], mlkem_ciphertext: vec![2u8; 1088], observed_addr: observed, signature: vec![3u8; 64], fec: Some(4), server_time: Some(1_700_000_000), assigned_ip: Some(Ipv4Addr::new(10, 0, 0, 7)), capabilities: Some(Capabilities(0x8000_0003)) },
+            HandshakeMessage::ClientFinish { encrypted_confirm: vec![4u8; 49] },
+            HandshakeMessage::ServerFinish { success: false, encrypted_confirm: Vec::new() },
+            HandshakeMessage::ServerFinish { success: true, encrypted_confirm: vec![14u8; 45] },
+            HandshakeMessage::ClientAuth { encrypted_credential: vec![5u8; 40] },
+            HandshakeMessage::Cookie { cookie: vec![6u8; COOKIE_LEN] },
+            HandshakeMessage::Resume { ticket: [7u8; 16], proof: vec![8u8; 36], fec: Some(8) },
+            HandshakeMessage::ResumeAck { proof: vec![9u8; 40], fec: None },
+            HandshakeMessage::PathJoin { path_id: [10u8; 16], proof: vec![11u8; 65] },
+            HandshakeMessage::PathAck { proof: vec![12u8; 64] },
+            HandshakeMessage::HandshakeError { code: 3, detail: String::new(), observed_addr: observed, signature: vec![13u8; 64] },
+            HandshakeMessage::HandshakeError { code: 1, detail: "服务端协议版本 1".to_string(), observed_addr: observed, signature: Vec::new() },
+            HandshakeMessage::HandshakeError { code: 5, detail: "请从 https://vpn.example.com 下载新版本".to_string(), observed_addr: observed, signature: vec![13u8; 64] },
+        ];
+        for msg in messages {
+            assert_eq!(deserialize_message(&serialize_message(&msg).unwrap()).unwrap(), msg);
+        }
+
+        // v1 的编码固定不变，改动布局会导致新旧版本无法互通
+        let finish = serialize_message(&HandshakeMessage::ServerFinish { success: true, encrypted_confirm: Vec::new() }).unwrap();
+        assert_eq!(hex::encode(&finish), "b70101010401000101");
+        let cookie = serialize_message(&HandshakeMessage::Cookie { cookie: vec![0xaa, 0xbb] }).unwrap();
+        assert_eq!(hex::encode(&cookie), "b701010106010002aabb");
+        let ack = serialize_message(&HandshakeMessage::ResumeAck { proof: vec![0xcc], fec: None }).unwrap();
+        assert_eq!(hex::encode(&ack), "b701010108010001cc");
+
+        // 首次 ClientHello 不带 cookie；新版本追加的未知字段被跳过
+        let hello = HandshakeMessage::ClientHello {
+            client_pubkey: [1u8; 32],
+            client_mlkem_pk: vec![2u8; 1184],
+            client_id: "0f8fad5b-d9cb-469f-a165-70867728950e".to_string(),
+            virtual_ip: "10.0.0.2".to_string(),
+            identity_key: [3u8; 32],
+            identity_signature: vec![4u8; 64],
+            cookie: Vec::new(),
+            fec: None,
+            capabilities: None,
+            feature_version: None,
+            psk_id: None,
+        };
+        let mut data = serialize_message(&hello).unwrap();
+        data.extend([0xf0, 0x00, 0x02, 0x12, 0x34]);
+        assert_eq!(deserialize_message(&data).unwrap(), hello);
+
+        // 缺少必需字段、未知消息类型、不认识的版本、加密的数据包
+        let framed = |body: &[u8]| [&HANDSHAKE_MAGIC[..], body].concat();
+        assert!(deserialize_message(&finish[..5]).is_err());
+        assert!(deserialize_message(&framed(&[1, 99])).is_err());
+        assert!(deserialize_message(&framed(&[2, MSG_SERVER_FINISH, 1, 0, 1, 1])).is_err());
+        assert!(deserialize_message(&[0x5a; 64]).is_err());
+        assert_eq!(handshake_version(&framed(&[2, MSG_SERVER_FINISH])), Some(2));
+        assert_eq!(handshake_version(&[0x5a; 64]), None);
+        // 旧版本的 "RV" 前缀
+        assert!(deserialize_message(b"RV\x01\x04\x01\x00\x01\x01").is_err());
+
+        let credential = AuthCredential::LdapBind { username: "alice".to_string(), password: "secret".to_string() };
+        assert_eq!(AuthCredential::decode(&credential.encode().unwrap()).unwrap(), credential);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let client = Capabilities::REKEY.with(Capabilities::IPV6, true).with(Capabilities::FEC, false);
+        let server = Capabilities::REKEY.with(Capabilities::ROAMING, true).with(Capabilities::IPV6, true);
+        let both = client.intersect(server);
+        assert!(both.contains(Capabilities::IPV6) && !both.contains(Capabilities::ROAMING));
+        assert_eq!(both.to_string(), "rekey,ipv6");
+        // 更新版本的对端声明的未知功能
+        assert_eq!(Capabilities(1 << 31 | 1).to_string(), "rekey,bit31");
+        assert_eq!(Capabilities::default().to_string(), "（无）");
+    }
+
+    #[test]
+    fn test_handshake_error() {
+        let addr: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        assert_eq!(HandshakeErrorCode::from_u8(HandshakeErrorCode::IpConflict as u8), Some(HandshakeErrorCode::IpConflict));
+        assert_eq!(HandshakeErrorCode::from_u8(5), Some(HandshakeErrorCode::UpgradeRequired));
+        assert_eq!(HandshakeErrorCode::from_u8(HandshakeErrorCode::DuplicateSession as u8), Some(HandshakeErrorCode::DuplicateSession));
+        assert!(describe_handshake_error(99, "").contains("99"));
+        assert!(describe_handshake_error(3, "上限 2").ends_with("上限 2"));
+
+        // 签名内容绑定本次握手的 client_pubkey、客户端地址和错误内容
+        let message = handshake_error_message(2, "", &[1u8; 32], addr);
+        assert_ne!(message, handshake_error_message(2, "", &[2u8; 32], addr));
+        assert_ne!(message, handshake_error_message(2, "", &[1u8; 32], "203.0.113.7:40124".parse().unwrap()));
+        assert_ne!(message, handshake_error_message(4, "", &[1u8; 32], addr));
+        assert!(!server_hello_message(&[1u8; 32]).starts_with(b"RV-HANDSHAKE-ERROR"));
+    }
+
+    #[test]
+    fn test_transcript_signature() {
+        let psk = [3u8; 32];
+        let observed: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let server_identity = ServerIdentity::from_key_bytes(&[6u8; 32]);
+        let verifier = ClientVerifier::new(&server_identity.public_key_bytes()).unwrap();
+        let client_identity = ClientIdentity::generate();
+        let mut client_hello = ClientHandshake::new(&psk).create_client_hello(&client_identity, AUTO_VIRTUAL_IP.to_string()).unwrap();
+        if let HandshakeMessage::ClientHello { capabilities, .. } = &mut client_hello {
+            *capabilities = Some(Capabilities::REKEY.with(Capabilities::FEC, true));
+        }
+        let mut server = ServerHandshake::new(&psk);
+        let (mut server_hello, _) = server.process_client_hello(&client_hello, observed).unwrap();
+        if let HandshakeMessage::ServerHello { assigned_ip, fec, capabilities, .. } = &mut server_hello {
+            *assigned_ip = Some(Ipv4Addr::new(10, 0, 0, 7));
+            *fec = Some(4);
+            *capabilities = Some(Capabilities::REKEY);
+        }
+        server.sign_server_hello(&mut server_hello, &server_identity).unwrap();
+        let verify = |client_hello: &HandshakeMessage, server_hello: &HandshakeMessage| {
+            ClientHandshake::new(&psk).verify_server_hello(client_hello, server_hello, &verifier).is_ok()
+        };
+        assert!(verify(&client_hello, &server_hello));
+
+        // cookie 不计入转录：带 cookie 重发的 ClientHello 仍能验证
+        let mut resent = client_hello.clone();
+        if let HandshakeMessage::ClientHello { cookie, .. } = &mut resent {
+            *cookie = vec![1; COOKIE_LEN];
+        }
+        assert!(verify(&resent, &server_hello));
+
+        // ServerHello 的任一字段被改动，签名都失效
+        let tamper_server: [fn(&mut HandshakeMessage); 6] = [
+            |m| if let HandshakeMessage::ServerHello { mlkem_ciphertext, .. } = m { mlkem_ciphertext[0] ^= 1 },
+            |m| if let HandshakeMessage::ServerHello { assigned_ip, .. } = m { *assigned_ip = Some(Ipv4Addr::new(10, 0, 0, 8)) },
+            |m| if let HandshakeMessage::ServerHello { fec, .. } = m { *fec = None },
+            |m| if let HandshakeMessage::ServerHello { capabilities, .. } = m { *capabilities = Some(Capabilities::default()) },
+            |m| if let HandshakeMessage::ServerHello { server_time, .. } = m { *server_time = Some(0) },
+            |m| if let HandshakeMessage::ServerHello { observed_addr, .. } = m { observed_addr.set_port(1) },
+        ];
+        for tamper in tamper_server {
+            let mut hello = server_hello.clone();
+            tamper(&mut hello);
+            assert!(!verify(&client_hello, &hello));
+        }
+        // ClientHello 的字段同样被认证：ServerHello 不能拿来回复另一个 ClientHello
+        let tamper_client: [fn(&mut HandshakeMessage); 3] = [
+            |m| if let HandshakeMessage::ClientHello { virtual_ip, .. } = m { *virtual_ip = "10.0.0.9".to_string() },
+            |m| if let HandshakeMessage::ClientHello { client_id, .. } = m { client_id.push('0') },
+            |m| if let HandshakeMessage::ClientHello { capabilities, .. } = m { *capabilities = None },
+        ];
+        for tamper in tamper_client {
+            let mut hello = client_hello.clone();
+            tamper(&mut hello);
+            assert!(!verify(&hello, &server_hello));
+        }
+        let other = ClientHandshake::new(&psk).create_client_hello(&client_identity, AUTO_VIRTUAL_IP.to_string()).unwrap();
+        assert!(!verify(&other, &server_hello));
+    }
+
+    #[test]
+    fn test_auth_credential_seal_open() {
+        let session_key = [7u8; 32];
+        let cred = AuthCredential::LdapBind {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        };
+        
+        let encrypted = match cred.seal(&session_key).unwrap() {
+            HandshakeMessage::ClientAuth { encrypted_credential } => encrypted_credential,
+            _ => panic!("Wrong message type"),
+        };
+        
+        assert_eq!(AuthCredential::open(&encrypted, &session_key).unwrap(), cred);
+        assert!(AuthCredential::open(&encrypted, &[8u8; 32]).is_err());
+    }
 }
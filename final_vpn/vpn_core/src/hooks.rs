@@ -0,0 +1,183 @@
+// vpn_core/src/hooks.rs
+// 转发路径上的包处理钩子
+//
+// 服务端转发每个内层 IP 包之前依次调用已注册的钩子，钩子可以检查、改写或丢弃包，
+// 用来实现自定义的过滤、改写和计费，而不必改动转发循环：
+//
+//     struct DropTelnet;
+//     impl PacketHook for DropTelnet {
+//         fn name(&self) -> &str { "drop-telnet" }
+//         fn on_packet(&self, _ctx: &PacketContext, packet: &mut Vec<u8>) -> HookVerdict {
+//             if is_telnet(packet) { HookVerdict::Drop("telnet") } else { HookVerdict::Continue }
+//         }
+//     }
+//     chain.register(Arc::new(DropTelnet))?;
+//
+// * 钩子按注册顺序调用：Continue 交给下一个钩子，Accept 跳过其余钩子，Drop 丢弃并按原因计数
+// * 改写包的钩子自己负责更新校验和（见 packet 模块）
+// * 钩子在转发路径上同步执行，不能阻塞；运行时可以注册和注销，没有钩子时不产生额外开销
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{Result, anyhow};
+
+/// 包的方向（以服务端为视角）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 客户端发进隧道的包（包括发往其他客户端的包）
+    FromClient,
+    /// 从 TUN 读出、发往客户端的包
+    ToClient,
+}
+
+/// 钩子可以看到的包信息
+#[derive(Debug, Clone)]
+pub struct PacketContext {
+    pub direction: Direction,
+    /// 包所属客户端的虚拟 IP（FromClient 为发送方，ToClient 为接收方）
+    pub client: Option<Ipv4Addr>,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+}
+
+/// 钩子的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookVerdict {
+    /// 交给下一个钩子
+    Continue,
+    /// 放行，跳过其余钩子
+    Accept,
+    /// 丢弃，参数为计入丢包统计的原因
+    Drop(&'static str),
+}
+
+/// 包处理钩子
+pub trait PacketHook: Send + Sync {
+    /// 钩子名称，在一条钩子链中唯一
+    fn name(&self) -> &str;
+
+    /// 处理一个包，可以原地改写
+    fn on_packet(&self, ctx: &PacketContext, packet: &mut Vec<u8>) -> HookVerdict;
+}
+
+/// 已注册的钩子，按注册顺序调用
+#[derive(Default)]
+pub struct HookChain {
+    hooks: RwLock<Vec<Arc<dyn PacketHook>>>,
+    /// 是否有钩子（转发路径上先检查它，避免没有钩子时也去拿锁）
+    active: AtomicBool,
+}
+
+impl HookChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 在链尾注册一个钩子，名称重复时报错
+    pub fn register(&self, hook: Arc<dyn PacketHook>) -> Result<()> {
+        let mut hooks = self.hooks.write().unwrap();
+        if hooks.iter().any(|h| h.name() == hook.name()) {
+            return Err(anyhow!("钩子 {} 已注册", hook.name()));
+        }
+        hooks.push(hook);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// 按名称注销钩子，返回是否找到
+    pub fn unregister(&self, name: &str) -> bool {
+        let mut hooks = self.hooks.write().unwrap();
+        let before = hooks.len();
+        hooks.retain(|h| h.name() != name);
+        self.active.store(!hooks.is_empty(), Ordering::Release);
+        hooks.len() != before
+    }
+
+    /// 已注册的钩子名称（按调用顺序）
+    pub fn names(&self) -> Vec<String> {
+        self.hooks.read().unwrap().iter().map(|h| h.name().to_string()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.active.load(Ordering::Acquire)
+    }
+
+    /// 依次调用钩子，返回 Accept 或 Drop
+    pub fn run(&self, ctx: &PacketContext, packet: &mut Vec<u8>) -> HookVerdict {
+        if self.is_empty() {
+            return HookVerdict::Accept;
+        }
+        // 复制一份列表再调用，钩子执行期间可以注册/注销钩子
+        let hooks = self.hooks.read().unwrap().clone();
+        for hook in hooks {
+            match hook.on_packet(ctx, packet) {
+                HookVerdict::Continue => continue,
+                verdict => return verdict,
+            }
+        }
+        HookVerdict::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 把 TTL 改成固定值
+    struct SetTtl(u8);
+
+    impl PacketHook for SetTtl {
+        fn name(&self) -> &str {
+            "set-ttl"
+        }
+
+        fn on_packet(&self, _ctx: &PacketContext, packet: &mut Vec<u8>) -> HookVerdict {
+            packet[8] = self.0;
+            HookVerdict::Continue
+        }
+    }
+
+    /// 丢弃来自指定客户端的包
+    struct Block(Ipv4Addr);
+
+    impl PacketHook for Block {
+        fn name(&self) -> &str {
+            "block"
+        }
+
+        fn on_packet(&self, ctx: &PacketContext, _packet: &mut Vec<u8>) -> HookVerdict {
+            if ctx.client == Some(self.0) { HookVerdict::Drop("blocked") } else { HookVerdict::Continue }
+        }
+    }
+
+    #[test]
+    fn test_hook_chain() {
+        let chain = HookChain::new();
+        let ctx = |last| PacketContext {
+            direction: Direction::FromClient,
+            client: Some(Ipv4Addr::new(10, 0, 0, last)),
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)),
+            dst: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        };
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2, 1, 1, 1, 1];
+        assert!(chain.is_empty());
+        assert_eq!(chain.run(&ctx(2), &mut packet), HookVerdict::Accept);
+
+        chain.register(Arc::new(SetTtl(9))).unwrap();
+        chain.register(Arc::new(Block(Ipv4Addr::new(10, 0, 0, 3)))).unwrap();
+        assert!(chain.register(Arc::new(SetTtl(1))).is_err());
+        assert_eq!(chain.names(), ["set-ttl", "block"]);
+
+        assert_eq!(chain.run(&ctx(2), &mut packet), HookVerdict::Accept);
+        assert_eq!(packet[8], 9);
+        assert_eq!(chain.run(&ctx(3), &mut packet), HookVerdict::Drop("blocked"));
+
+        assert!(chain.unregister("block"));
+        assert!(!chain.unregister("block"));
+        assert_eq!(chain.run(&ctx(3), &mut packet), HookVerdict::Accept);
+        assert!(chain.unregister("set-ttl"));
+        assert!(chain.is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+// vpn_core/src/icmp.rs
+// 转发时的 TTL / Hop Limit 处理和 ICMP 超时报文
+//
+// 服务端在客户端之间直接转发内层包（不经过内核路由），需要像路由器一样递减 TTL，
+// 否则路由配置错误（例如两个客户端都把对方网段指向隧道）时包会在客户端之间无限循环。
+// TTL 耗尽的包被丢弃，并向源地址回复 ICMP Time Exceeded（traceroute 也因此能看到服务端这一跳）。
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::packet::{checksum, fold, pseudo_header_sum, recompute_ipv4_header_checksum, set_ipv4_word, sum_words};
+
+/// 生成的 ICMP 报文的 TTL / Hop Limit
+const ICMP_TTL: u8 = 64;
+/// ICMPv4 差错报文的总长度上限（RFC 1812）
+const ICMPV4_MAX_LEN: usize = 576;
+/// ICMPv6 差错报文的总长度上限（IPv6 最小 MTU，RFC 4443）
+const ICMPV6_MAX_LEN: usize = 1280;
+
+const PROTO_ICMP: u8 = 1;
+const PROTO_ICMPV6: u8 = 58;
+
+/// 递减的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopLimit {
+    /// 已递减，可以转发
+    Forward,
+    /// TTL / Hop Limit 耗尽，应丢弃（包未被修改）
+    Expired,
+}
+
+/// 转发前递减 TTL（IPv4，增量更新头校验和）或 Hop Limit（IPv6）；不是 IP 包时返回 None
+pub fn decrement_hop_limit(packet: &mut [u8]) -> Option<HopLimit> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let ttl = packet[8];
+            if ttl <= 1 {
+                return Some(HopLimit::Expired);
+            }
+            // TTL 和协议号在同一个 16 位字中
+            set_ipv4_word(packet, 8, u16::from_be_bytes([ttl - 1, packet[9]]));
+            Some(HopLimit::Forward)
+        }
+        6 if packet.len() >= 40 => {
+            if packet[7] <= 1 {
+                return Some(HopLimit::Expired);
+            }
+            packet[7] -= 1;
+            Some(HopLimit::Forward)
+        }
+        _ => None,
+    }
+}
+
+/// 为 TTL 耗尽的包生成 ICMP Time Exceeded（发往原包的源地址）
+///
+/// 按 RFC 1812 / RFC 4443 不为以下包生成：ICMP 差错报文本身、IPv4 非首个分片、
+/// 源地址为未指定/组播/广播地址的包、发往组播或广播地址的包。
+pub fn time_exceeded(packet: &[u8], source_v4: Ipv4Addr, source_v6: Ipv6Addr) -> Option<Vec<u8>> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => time_exceeded_v4(packet, source_v4),
+        6 if packet.len() >= 40 => time_exceeded_v6(packet, source_v6),
+        _ => None,
+    }
+}
+
+fn time_exceeded_v4(packet: &[u8], source: Ipv4Addr) -> Option<Vec<u8>> {
+    let ihl = (packet[0] & 0x0f) as usize * 4;
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+    if fragment_offset != 0
+        || src.is_unspecified() || src.is_multicast() || src.is_broadcast()
+        || dst.is_multicast() || dst.is_broadcast()
+    {
+        return None;
+    }
+    // 差错报文（目的不可达、源抑制、重定向、超时、参数问题）不再回复差错
+    if packet[9] == PROTO_ICMP && matches!(packet.get(ihl), Some(3 | 4 | 5 | 11 | 12)) {
+        return None;
+    }
+
+    let quote = &packet[..packet.len().min(ICMPV4_MAX_LEN - 28)];
+    let total_len = 28 + quote.len();
+    let mut out = Vec::with_capacity(total_len);
+    out.extend([0x45, 0]);
+    out.extend((total_len as u16).to_be_bytes());
+    out.extend([0, 0, 0, 0, ICMP_TTL, PROTO_ICMP, 0, 0]);
+    out.extend(source.octets());
+    out.extend(src.octets());
+    recompute_ipv4_header_checksum(&mut out);
+
+    // 类型 11（Time Exceeded），代码 0（传输中 TTL 耗尽）
+    out.extend([11, 0, 0, 0, 0, 0, 0, 0]);
+    out.extend(quote);
+    let icmp_checksum = checksum(&out[20..]);
+    out[22..24].copy_from_slice(&icmp_checksum.to_be_bytes());
+    Some(out)
+}
+
+fn time_exceeded_v6(packet: &[u8], source: Ipv6Addr) -> Option<Vec<u8>> {
+    let src = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+    let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+    if src.is_unspecified() || src.is_multicast() || dst.is_multicast() {
+        return None;
+    }
+    // ICMPv6 差错报文的类型小于 128
+    if packet[6] == PROTO_ICMPV6 && packet.get(40).is_some_and(|t| *t < 128) {
+        return None;
+    }
+
+    let quote = &packet[..packet.len().min(ICMPV6_MAX_LEN - 48)];
+    let payload_len = 8 + quote.len();
+    let mut out = Vec::with_capacity(40 + payload_len);
+    out.extend([0x60, 0, 0, 0]);
+    out.extend((payload_len as u16).to_be_bytes());
+    out.extend([PROTO_ICMPV6, ICMP_TTL]);
+    out.extend(source.octets());
+    out.extend(src.octets());
+
+    // 类型 3（Time Exceeded），代码 0（传输中 Hop Limit 耗尽）
+    out.extend([3, 0, 0, 0, 0, 0, 0, 0]);
+    out.extend(quote);
+    // 校验和覆盖伪首部：源地址、目的地址、上层长度、下一个头部
+    let pseudo = pseudo_header_sum(&out, PROTO_ICMPV6, payload_len);
+    let icmp_checksum = !fold(sum_words(&out[40..], pseudo));
+    out[42..44].copy_from_slice(&icmp_checksum.to_be_bytes());
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::ipv4_header_checksum_valid;
+
+    fn udp4(ttl: u8) -> Vec<u8> {
+        let mut p = vec![0x45, 0, 0, 32, 0x12, 0x34, 0x40, 0, ttl, 17, 0, 0, 10, 0, 0, 2, 10, 0, 0, 3];
+        recompute_ipv4_header_checksum(&mut p);
+        p.extend([0x30, 0x39, 0x00, 0x35, 0, 12, 0, 0, b'p', b'i', b'n', b'g']);
+        p
+    }
+
+    #[test]
+    fn test_decrement_ttl() {
+        let mut p = udp4(64);
+        assert_eq!(decrement_hop_limit(&mut p), Some(HopLimit::Forward));
+        assert_eq!(p[8], 63);
+        // 增量更新后的校验和与重新计算的一致
+        assert!(ipv4_header_checksum_valid(&p));
+
+        let mut last_hop = udp4(1);
+        assert_eq!(decrement_hop_limit(&mut last_hop), Some(HopLimit::Expired));
+        assert_eq!(last_hop, udp4(1));
+
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 0, 17, 2];
+        v6.resize(40, 0);
+        assert_eq!(decrement_hop_limit(&mut v6), Some(HopLimit::Forward));
+        assert_eq!(decrement_hop_limit(&mut v6), Some(HopLimit::Expired));
+        assert_eq!(decrement_hop_limit(&mut [0u8; 4]), None);
+    }
+
+    #[test]
+    fn test_time_exceeded() {
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let server6: Ipv6Addr = "fd00::a00:1".parse().unwrap();
+        let original = udp4(1);
+        let reply = time_exceeded(&original, server, server6).unwrap();
+        assert!(ipv4_header_checksum_valid(&reply));
+        assert_eq!(fold(sum_words(&reply[20..], 0)), 0xffff);
+        assert_eq!(&reply[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&reply[16..20], &[10, 0, 0, 2]);
+        assert_eq!(reply[20], 11);
+        assert_eq!(&reply[28..], &original[..]);
+        // 不对差错报文回复差错
+        assert!(time_exceeded(&reply, server, server6).is_none());
+
+        let mut v6 = vec![0x60, 0, 0, 0, 0, 8, 17, 1];
+        v6.extend("fd00::a00:2".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend("fd00::a00:3".parse::<Ipv6Addr>().unwrap().octets());
+        v6.extend([0u8; 8]);
+        let reply = time_exceeded(&v6, server, server6).unwrap();
+        assert_eq!(reply[40], 3);
+        assert_eq!(&reply[24..40], &v6[8..24]);
+        let pseudo = pseudo_header_sum(&reply, PROTO_ICMPV6, reply.len() - 40);
+        assert_eq!(fold(sum_words(&reply[40..], pseudo)), 0xffff);
+        assert!(time_exceeded(&reply, server, server6).is_none());
+    }
+}
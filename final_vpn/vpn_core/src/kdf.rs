@@ -0,0 +1,94 @@
+// vpn_core/src/kdf.rs
+// HKDF 形式的密钥派生（RFC 5869 的 extract-then-expand 结构，PRF 用 BLAKE3 keyed hash 代替 HMAC）
+//
+//   PRK   = extract(salt, IKM)            = BLAKE3-keyed(salt, IKM)
+//   OKM_i = expand(PRK, info_i)           = BLAKE3-keyed(PRK, info_i || 0x01)
+//
+// 每个输出都是 32 字节，只需要 HKDF-Expand 的第一块；不同用途的密钥用不同的 info 区分，互相独立。
+//
+// 握手：PRK = extract(PSK, 标签 || X25519 共享密钥 || ML-KEM 共享密钥)，会话密钥 = expand(PRK, "session")。
+// 会话密钥用于握手确认、会话恢复、多路径证明和密钥轮换，不直接加密数据包；
+// 数据包使用它展开出的 DataKeys：两个方向各一个 AEAD 密钥，加上混淆报文中序号的密钥。
+// 密钥轮换和会话恢复得到新的会话密钥后，DataKeys 随之重新展开。
+
+/// 握手 extract 的标签（版本 3：HKDF 结构，方向分离的数据包密钥）
+pub const HANDSHAKE_LABEL: &[u8] = b"rust-vpn hybrid handshake v3";
+
+const INFO_SESSION: &[u8] = b"rust-vpn session key";
+const INFO_TO_SERVER: &[u8] = b"rust-vpn data client->server";
+const INFO_TO_CLIENT: &[u8] = b"rust-vpn data server->client";
+const INFO_HEADER: &[u8] = b"rust-vpn header protection";
+
+/// HKDF-Extract：把输入密钥材料压缩成伪随机密钥
+pub fn extract(salt: &[u8; 32], ikm: &[u8]) -> [u8; 32] {
+    *blake3::keyed_hash(salt, ikm).as_bytes()
+}
+
+/// HKDF-Expand 的第一块（32 字节）
+pub fn expand(prk: &[u8; 32], info: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_keyed(prk);
+    hasher.update(info);
+    hasher.update(&[1]);
+    *hasher.finalize().as_bytes()
+}
+
+/// 握手的会话密钥：由 X25519、ML-KEM 共享密钥和 PSK 派生
+pub fn session_key(ecdh_shared: &[u8], mlkem_shared: &[u8], psk: &[u8; 32]) -> [u8; 32] {
+    let ikm = [HANDSHAKE_LABEL, ecdh_shared, mlkem_shared].concat();
+    expand(&extract(psk, &ikm), INFO_SESSION)
+}
+
+/// 数据包密钥：由会话密钥展开
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct DataKeys {
+    /// 客户端 -> 服务端的 AEAD 密钥
+    pub to_server: [u8; 32],
+    /// 服务端 -> 客户端的 AEAD 密钥
+    pub to_client: [u8; 32],
+    /// 序号混淆密钥（两个方向共用，见 symmetric::PacketKeys）
+    pub header: [u8; 32],
+}
+
+impl DataKeys {
+    pub fn expand(session_key: &[u8; 32]) -> Self {
+        Self {
+            to_server: expand(session_key, INFO_TO_SERVER),
+            to_client: expand(session_key, INFO_TO_CLIENT),
+            header: expand(session_key, INFO_HEADER),
+        }
+    }
+}
+
+// 不打印密钥
+impl std::fmt::Debug for DataKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DataKeys { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let keys = DataKeys::expand(&[7u8; 32]);
+        assert_eq!(keys, DataKeys::expand(&[7u8; 32]));
+        // 各用途的密钥互不相同，也不等于会话密钥本身
+        assert_ne!(keys.to_server, keys.to_client);
+        assert_ne!(keys.to_server, keys.header);
+        assert_ne!(keys.to_client, [7u8; 32]);
+        assert_ne!(DataKeys::expand(&[8u8; 32]).to_server, keys.to_server);
+
+        // PSK、任一共享密钥不同都得到不同的会话密钥
+        let key = session_key(&[1u8; 32], &[2u8; 32], &[3u8; 32]);
+        assert_ne!(key, session_key(&[1u8; 32], &[2u8; 32], &[4u8; 32]));
+        assert_ne!(key, session_key(&[9u8; 32], &[2u8; 32], &[3u8; 32]));
+        assert_ne!(key, session_key(&[1u8; 32], &[9u8; 32], &[3u8; 32]));
+
+        // expand 是 HKDF-Expand 的第一块：T(1) = PRF(PRK, info || 0x01)
+        let mut input = INFO_HEADER.to_vec();
+        input.push(1);
+        assert_eq!(expand(&[7u8; 32], INFO_HEADER), *blake3::keyed_hash(&[7u8; 32], &input).as_bytes());
+    }
+}
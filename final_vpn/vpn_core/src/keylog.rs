@@ -0,0 +1,111 @@
+// vpn_core/src/keylog.rs
+// 调试用的会话密钥导出（--debug-key-log <路径>，类似 TLS 的 SSLKEYLOGFILE）
+//
+// 开启后，每次握手、会话恢复和密钥轮换得到的会话密钥都追加一行到指定文件，
+// 开发者可以用它在 Wireshark 里解密自己抓到的隧道流量。每行的格式：
+//
+//   <事件> <unix 秒> <对端 UDP 端点> <会话密钥 hex>
+//   RUSTVPN_HANDSHAKE 1760550000 203.0.113.5:51820 9f86d0...
+//
+// 数据包的两个方向各用一个由会话密钥展开的密钥，序号另有混淆（见 kdf::DataKeys 和 symmetric::PacketKeys），
+// 同一端点按时间取最近的一行密钥解密。
+// 拿到这个文件就能解密对应的全部流量，所以默认关闭，只能用命令行参数显式开启
+// （配置文件和环境变量都不行），开启时和每次写入时都会打印醒目的警告。
+
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// 开启密钥导出的命令行参数
+pub const FLAG: &str = "--debug-key-log";
+
+static KEY_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// 得到新会话密钥的事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// 完整握手
+    Handshake,
+    /// 会话恢复（0-RTT）
+    Resume,
+    /// 控制通道上的密钥轮换
+    Rekey,
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyEvent::Handshake => "RUSTVPN_HANDSHAKE",
+            KeyEvent::Resume => "RUSTVPN_RESUME",
+            KeyEvent::Rekey => "RUSTVPN_REKEY",
+        })
+    }
+}
+
+/// 根据命令行参数（--debug-key-log <路径>）打开密钥文件（追加写入，权限 0600）
+pub fn init_from_args(args: &[String]) -> Result<()> {
+    let Some(path) = args.iter().position(|a| a == FLAG).and_then(|i| args.get(i + 1)) else {
+        return Ok(());
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path).with_context(|| format!("无法打开 {} 指定的文件 {}", FLAG, path))?;
+    let _ = KEY_LOG.set(Mutex::new(file));
+
+    eprintln!("⚠️⚠️⚠️  调试模式：会话密钥将写入 {}", path);
+    eprintln!("⚠️⚠️⚠️  拿到这个文件的人可以解密全部隧道流量，只在协议调试时使用，用完删除该文件");
+    Ok(())
+}
+
+/// 是否开启了密钥导出
+pub fn enabled() -> bool {
+    KEY_LOG.get().is_some()
+}
+
+/// 记录一个新的会话密钥；没有开启时什么也不做
+pub fn record(event: KeyEvent, peer: SocketAddr, key: &[u8; 32]) {
+    let Some(file) = KEY_LOG.get() else { return };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut file = file.lock().unwrap();
+    match writeln!(file, "{}", format_line(event, now, peer, key)) {
+        Ok(()) => eprintln!("🔓 [{}] 已导出 {} 的会话密钥（{}）", FLAG, peer, event),
+        Err(e) => eprintln!("⚠️  会话密钥写入失败: {}", e),
+    }
+}
+
+/// 密钥文件中的一行（不含换行）
+pub fn format_line(event: KeyEvent, unix_secs: u64, peer: SocketAddr, key: &[u8; 32]) -> String {
+    format!("{} {} {} {}", event, unix_secs, peer, hex::encode(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        let peer: SocketAddr = "203.0.113.5:51820".parse().unwrap();
+        let mut key = [0u8; 32];
+        key[0] = 0x9f;
+        key[31] = 0x01;
+        let line = format_line(KeyEvent::Rekey, 1_760_550_000, peer, &key);
+        assert_eq!(line, format!("RUSTVPN_REKEY 1760550000 203.0.113.5:51820 9f{}01", "00".repeat(30)));
+        assert_eq!(format_line(KeyEvent::Handshake, 0, "[2001:db8::1]:9000".parse().unwrap(), &key).split(' ').nth(2), Some("[2001:db8::1]:9000"));
+        assert_eq!(KeyEvent::Resume.to_string(), "RUSTVPN_RESUME");
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        // 没有参数时不打开任何文件，record 不做任何事
+        init_from_args(&["vpn_server".to_string()]).unwrap();
+        assert!(!enabled());
+        record(KeyEvent::Handshake, "127.0.0.1:9000".parse().unwrap(), &[0; 32]);
+    }
+}
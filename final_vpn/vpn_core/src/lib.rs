@@ -1,8 +1,55 @@
 pub mod symmetric;
+pub mod kdf;
+pub mod crypto;
 pub mod local_tun;
 pub mod handshake;
 pub mod asymmetric;
+pub mod tpm;
+#[cfg(unix)]
+pub mod agent;
 pub mod gateway;
+#[cfg(feature = "tokio")]
+pub mod telemetry;
+pub mod offload;
+pub mod gso;
+pub mod buffer_pool;
+pub mod pmtu;
+pub mod control;
+pub mod datapath_log;
+pub mod keylog;
+pub mod sigguard;
+pub mod logsink;
+#[cfg(feature = "tokio")]
+pub mod crash;
+pub mod watchdog;
+#[cfg(feature = "tokio")]
+pub mod netwatch;
+#[cfg(feature = "tokio")]
+pub mod mdns;
+pub mod tuning;
+pub mod replay;
+pub mod psk;
+pub mod packet;
+pub mod firewall;
+pub mod resume;
+pub mod dryrun;
+pub mod preflight;
+pub mod undo;
+pub mod multipath;
+pub mod pacing;
+pub mod fec;
+pub mod profile;
+pub mod recursion;
+pub mod hooks;
+#[cfg(feature = "netstack")]
+pub mod netstack;
+pub mod config;
+pub mod icmp;
+pub mod stun;
+pub mod wire;
+pub mod engine;
+#[cfg(feature = "tokio")]
+pub mod bench;
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
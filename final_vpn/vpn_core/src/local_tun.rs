@@ -1,12 +1,88 @@
 // src/tun.rs
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::process::Command; // 引入 Command
 use std::str::FromStr;
-use tun::{Configuration, AsyncDevice}; 
+#[cfg(feature = "tokio")]
+use tun::{Configuration, AsyncDevice};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt, ReadBuf};
 use anyhow::Result;
+#[cfg(feature = "tokio")]
+use crate::buffer_pool::BufferPool;
+use crate::dryrun::{Category, Plan};
 
+/// 统一的 TUN 读写接口（普通 tun 设备或开启卸载的设备）
+#[cfg(feature = "tokio")]
+pub trait TunIo: AsyncRead + AsyncWrite + Send + Unpin {}
+#[cfg(feature = "tokio")]
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunIo for T {}
+
+#[cfg(feature = "tokio")]
+pub type TunDevice = Box<dyn TunIo>;
+
+/// 创建 TUN 设备时的可选参数
+#[derive(Debug, Clone, Default)]
+pub struct DeviceOptions {
+    /// 指定设备名（例如 "vpn1"；macOS 上必须是 "utunN"），不指定则由系统分配
+    pub name: Option<String>,
+    /// 复用预先创建的持久化设备（`ip tuntap add dev <name> mode tun user <uid> persist`）
+    ///
+    /// 此时不再设置地址/掩码/up，这些由创建设备的一方（例如 systemd unit）负责，
+    /// 进程本身只需要对该设备有访问权限，无需 CAP_NET_ADMIN
+    pub reuse_existing: bool,
+    /// 开启 IFF_VNET_HDR + TSO/GSO 卸载（仅 Linux），内核可一次交付最大 64KB 的 TCP 段，
+    /// 读端在加密前切分成普通 IP 包
+    pub offload: bool,
+}
+
+/// 路由的可选参数，用于同一主机上多个实例互不干扰
+#[derive(Debug, Clone, Default)]
+pub struct RouteOptions {
+    /// 路由优先级（Linux/Windows: metric；macOS: 不支持，忽略）
+    pub metric: Option<u32>,
+    /// 写入的路由表（仅 Linux，配合 ip rule 使用）
+    pub table: Option<u32>,
+}
+
+#[cfg(feature = "tokio")]
 pub fn create_device(address: &str, netmask: &str) -> Result<AsyncDevice> {
+    create_device_with(address, netmask, &DeviceOptions::default())
+}
+
+/// 按指定参数打开 TUN 设备，返回设备和设备名（需要 tokio feature）
+///
+/// `offload` 打开失败（旧内核、非 Linux）时回退到普通设备
+#[cfg(feature = "tokio")]
+pub fn open_device(address: &str, netmask: &str, options: &DeviceOptions) -> Result<(TunDevice, String)> {
+    #[cfg(target_os = "linux")]
+    if options.offload && !options.reuse_existing {
+        match crate::offload::OffloadDevice::create(options.name.as_deref(), address, netmask) {
+            Ok(dev) => {
+                let name = dev.name().to_string();
+                println!("   ⚡ 已开启 TUN 卸载 (TSO/GSO)");
+                return Ok((Box::new(dev), name));
+            }
+            Err(e) => println!("   ⚠️  无法开启 TUN 卸载，回退到普通模式: {}", e),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if options.offload {
+        println!("   ⚠️  TUN 卸载仅支持 Linux，已忽略");
+    }
+
+    let dev = create_device_with(address, netmask, options)?;
+    let name = tun::Device::name(dev.get_ref())?;
+    Ok((Box::new(dev), name))
+}
+
+/// 按指定参数创建 TUN 设备
+#[cfg(feature = "tokio")]
+pub fn create_device_with(address: &str, netmask: &str, options: &DeviceOptions) -> Result<AsyncDevice> {
+    if options.reuse_existing {
+        return attach_existing_device(address, options.name.as_deref());
+    }
+    
     let ip = Ipv4Addr::from_str(address)?;
     let mask = Ipv4Addr::from_str(netmask)?;
     
@@ -16,6 +92,10 @@ pub fn create_device(address: &str, netmask: &str) -> Result<AsyncDevice> {
         .netmask(mask)
         .destination(ip) // 添加 destination，对于点对点接口很重要
         .up();
+    
+    if let Some(name) = &options.name {
+        config.name(name);
+    }
 
     #[cfg(target_os = "linux")]
     config.platform(|config| { config.packet_information(false); });
@@ -29,44 +109,149 @@ pub fn create_device(address: &str, netmask: &str) -> Result<AsyncDevice> {
     Ok(dev)
 }
 
+/// 连接到已存在的持久化 TUN 设备（仅 Linux）
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+fn attach_existing_device(address: &str, name: Option<&str>) -> Result<AsyncDevice> {
+    use tun::Device;
+    
+    let name = name.ok_or_else(|| anyhow::anyhow!("复用已有设备时必须指定设备名"))?;
+    if !device_exists(name) {
+        anyhow::bail!(
+            "设备 {} 不存在，请先创建: sudo ip tuntap add dev {} mode tun user $(id -u) persist",
+            name, name
+        );
+    }
+    
+    // 只指定名称：TUNSETIFF 会挂接到同名的持久化设备上
+    let mut config = Configuration::default();
+    config.name(name);
+    config.platform(|config| { config.packet_information(false); });
+    
+    let dev = tun::create_as_async(&config)
+        .map_err(|e| anyhow::anyhow!("无法挂接设备 {}（检查设备属主是否为当前用户）: {}", name, e))?;
+    
+    // 地址由外部配置，这里只核对一下，不一致时提醒
+    let expected = Ipv4Addr::from_str(address)?;
+    match dev.get_ref().address() {
+        Ok(actual) if actual == expected => {}
+        Ok(actual) => println!("⚠️  设备 {} 的地址为 {}，与配置的 {} 不一致", name, actual, expected),
+        Err(_) => println!("⚠️  设备 {} 尚未配置地址，请执行: sudo ip addr add {}/24 dev {}", name, expected, name),
+    }
+    
+    Ok(dev)
+}
+
+#[cfg(all(not(target_os = "linux"), feature = "tokio"))]
+fn attach_existing_device(_address: &str, _name: Option<&str>) -> Result<AsyncDevice> {
+    anyhow::bail!("复用预先创建的 TUN 设备仅支持 Linux")
+}
+
+/// 指定名称的网络接口是否已存在
+pub fn device_exists(name: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        std::path::Path::new("/sys/class/net").join(name).exists()
+    }
+    
+    #[cfg(not(target_os = "linux"))]
+    {
+        Command::new("ifconfig")
+            .arg(name)
+            .output()
+            .map(|o| o.status.success())
+            .unwrap_or(false)
+    }
+}
+
+/// 试运行：列出 open_device 会创建的设备
+pub fn plan_device(plan: &mut Plan, address: &str, netmask: &str, options: &DeviceOptions) {
+    let name = options.name.as_ref().map(|n| format!(" {}", n)).unwrap_or_else(|| "（名称由系统分配，下文记为 <tun>）".to_string());
+    if options.reuse_existing {
+        plan.note(Category::Tun, format!("挂接已有的持久化设备{}，不修改地址", name));
+        return;
+    }
+    let prefix = netmask.parse::<Ipv4Addr>().map(|m| u32::from(m).count_ones()).unwrap_or(32);
+    plan.note(Category::Tun, format!("创建 TUN 设备{}，地址 {}/{}，并设为 up", name, address, prefix));
+    if options.offload {
+        plan.note(Category::Tun, "开启 IFF_VNET_HDR 和 TSO/GSO 卸载（仅 Linux，失败时回退到普通设备）");
+    }
+}
+
 /// 配置系统路由
 /// 
 /// * `dev_name`: 设备名 (例如 "utun6")
 /// * `cidr`: 网段 CIDR (例如 "10.0.0.0/24" 或 "0.0.0.0/0" 表示默认路由)
 pub fn configure_route(dev_name: &str, cidr: &str) -> Result<()> {
-    println!("正在为设备 {} 配置路由 {} ...", dev_name, cidr);
+    configure_route_with(dev_name, cidr, &RouteOptions::default())
+}
 
+/// 按指定的 metric / 路由表配置系统路由
+#[cfg_attr(target_os = "macos", allow(unused_variables))]
+pub fn configure_route_with(dev_name: &str, cidr: &str, options: &RouteOptions) -> Result<()> {
+    println!("正在为设备 {} 配置路由 {} ...", dev_name, cidr);
+    
     #[cfg(target_os = "macos")]
+    if options.metric.is_some() || options.table.is_some() {
+        println!("   ⚠️  macOS 不支持路由 metric/路由表，已忽略");
+    }
+
+    #[cfg(target_os = "windows")]
     {
-        // macOS 对默认路由（0.0.0.0/0）需要特殊处理
-        let status = if cidr == "0.0.0.0/0" {
-            // 先删除旧的默认路由（忽略错误）
-            println!("   🔄 删除旧的默认路由...");
-            let _ = Command::new("route")
-                .args(&["-n", "delete", "default"])
-                .status();
-            
-            // 添加新的默认路由，指向 VPN 网关 10.0.0.1
-            println!("   ➕ 添加新的默认路由 -> 10.0.0.1");
-            Command::new("route")
-                .args(&["-n", "add", "default", "10.0.0.1"])
-                .status()?
+        if options.table.is_some() {
+            println!("   ⚠️  Windows 不支持路由表，已忽略");
+        }
+        let index = windows_interface_index(dev_name)?;
+        
+        // 全隧道：用 0.0.0.0/1 + 128.0.0.0/1 覆盖默认路由，原默认路由保持不动，
+        // 退出时 TUN 接口消失，这两条路由也随之失效
+        let prefixes = if cidr == "0.0.0.0/0" {
+            // 让 TUN 接口的 DNS 优先于物理网卡，避免 DNS 泄漏
+            let _ = netsh(&["interface", "ipv4", "set", "interface", &index.to_string(), "metric=1"]);
+            vec!["0.0.0.0/1", "128.0.0.0/1"]
         } else {
-            // 普通路由，直接指向接口
-            Command::new("route")
-                .args(&["-n", "add", "-net", cidr, "-interface", dev_name])
-                .status()?
+            vec![cidr]
         };
         
-        if !status.success() {
-            anyhow::bail!("路由配置失败 (exit code: {:?})", status.code())
+        for prefix in prefixes {
+            let mut args = vec![
+                "interface".to_string(), "ipv4".to_string(), "add".to_string(), "route".to_string(),
+                prefix.to_string(), format!("interface={}", index), "store=active".to_string(),
+            ];
+            if let Some(metric) = options.metric {
+                args.push(format!("metric={}", metric));
+            }
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            netsh(&args)?;
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        // macOS 的默认路由由网络服务顺序（SystemConfiguration）管理，直接替换 default 会在
+        // 切换网络或休眠唤醒后被系统改回。这里不动原默认路由，而是用两条 /1 路由覆盖它
+        let prefixes = if cidr == "0.0.0.0/0" { vec!["0.0.0.0/1", "128.0.0.0/1"] } else { vec![cidr] };
+        for prefix in prefixes {
+            let status = Command::new("route")
+                .args(["-n", "add", "-net", prefix, "-interface", dev_name])
+                .status()?;
+            if !status.success() {
+                anyhow::bail!("路由配置失败 (exit code: {:?})", status.code())
+            }
+        }
+        
+        // 再加一条限定在 TUN 接口上的 scoped 默认路由，供绑定到该接口（IP_BOUND_IF）的套接字使用
+        if cidr == "0.0.0.0/0" {
+            println!("   ➕ 添加 scoped 默认路由 (-ifscope {})", dev_name);
+            let _ = Command::new("route")
+                .args(["-n", "add", "-ifscope", dev_name, "default", "-interface", dev_name])
+                .status();
         }
     }
 
     #[cfg(target_os = "linux")]
     {
         let status = Command::new("ip")
-            .args(&["route", "add", cidr, "dev", dev_name])
+            .args(route_add_args(dev_name, cidr, options))
             .status()?;
         
         if !status.success() {
@@ -75,4 +260,792 @@ pub fn configure_route(dev_name: &str, cidr: &str) -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// configure_route_with 在 Linux 上执行的 `ip` 参数
+#[cfg(target_os = "linux")]
+fn route_add_args(dev_name: &str, cidr: &str, options: &RouteOptions) -> Vec<String> {
+    let mut args = vec!["route".to_string(), "add".to_string(), cidr.to_string(), "dev".to_string(), dev_name.to_string()];
+    if let Some(metric) = options.metric {
+        args.extend(["metric".to_string(), metric.to_string()]);
+    }
+    if let Some(table) = options.table {
+        args.extend(["table".to_string(), table.to_string()]);
+    }
+    args
+}
+
+/// 试运行：列出 configure_route_with 会添加的路由
+pub fn plan_route(plan: &mut Plan, dev_name: &str, cidr: &str, options: &RouteOptions) {
+    #[cfg(target_os = "linux")]
+    plan.command(Category::Route, "ip", &route_add_args(dev_name, cidr, options));
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = options;
+        if cidr == "0.0.0.0/0" {
+            plan.note(Category::Route, format!("添加 0.0.0.0/1 和 128.0.0.0/1 -> {}（覆盖默认路由，原默认路由不变）", dev_name));
+        } else {
+            plan.note(Category::Route, format!("添加路由 {} -> {}", cidr, dev_name));
+        }
+    }
+}
+
+/// 删除 configure_route 添加的路由（退出清理用，失败只返回错误不影响其他清理）
+pub fn remove_route(dev_name: &str, cidr: &str) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    {
+        let index = windows_interface_index(dev_name)?;
+        let prefixes = if cidr == "0.0.0.0/0" { vec!["0.0.0.0/1", "128.0.0.0/1"] } else { vec![cidr] };
+        for prefix in prefixes {
+            netsh(&["interface", "ipv4", "delete", "route", prefix, &format!("interface={}", index)])?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        #[cfg(target_os = "linux")]
+        let status = Command::new("ip").args(["route", "del", cidr, "dev", dev_name]).status()?;
+        
+        #[cfg(not(target_os = "linux"))]
+        let status = if cidr == "0.0.0.0/0" {
+            let _ = Command::new("route").args(["-n", "delete", "-ifscope", dev_name, "default"]).status();
+            let _ = Command::new("route").args(["-n", "delete", "-net", "0.0.0.0/1", "-interface", dev_name]).status();
+            Command::new("route").args(["-n", "delete", "-net", "128.0.0.0/1", "-interface", dev_name]).status()?
+        } else {
+            Command::new("route").args(["-n", "delete", "-net", cidr, "-interface", dev_name]).status()?
+        };
+        
+        if !status.success() {
+            anyhow::bail!("删除路由失败 (exit code: {:?})", status.code())
+        }
+        Ok(())
+    }
+}
+
+/// 为 TUN 接口设置 DNS 服务器
+///
+/// * Windows: netsh 按接口索引设置静态 DNS（接口 metric 越小越优先）
+/// * Linux: systemd-resolved 的 `resolvectl dns`
+#[cfg_attr(not(any(target_os = "linux", target_os = "windows")), allow(unused_variables))]
+pub fn set_dns(dev_name: &str, servers: &[Ipv4Addr]) -> Result<()> {
+    if servers.is_empty() {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let name = format!("name={}", windows_interface_index(dev_name)?);
+        for (i, server) in servers.iter().enumerate() {
+            let address = format!("address={}", server);
+            if i == 0 {
+                netsh(&["interface", "ipv4", "set", "dnsservers", &name, "source=static", &address, "register=none", "validate=no"])?;
+            } else {
+                let index = format!("index={}", i + 1);
+                netsh(&["interface", "ipv4", "add", "dnsservers", &name, &address, &index, "validate=no"])?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let status = Command::new("resolvectl").args(dns_args(dev_name, servers)).status()?;
+        if !status.success() {
+            anyhow::bail!("设置 DNS 失败（需要 systemd-resolved），exit code: {:?}", status.code())
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        anyhow::bail!("当前平台暂不支持按接口设置 DNS")
+    }
+}
+
+/// set_dns 在 Linux 上执行的 `resolvectl` 参数
+#[cfg(target_os = "linux")]
+fn dns_args(dev_name: &str, servers: &[Ipv4Addr]) -> Vec<String> {
+    let mut args = vec!["dns".to_string(), dev_name.to_string()];
+    args.extend(servers.iter().map(|s| s.to_string()));
+    args
+}
+
+/// 试运行：列出 set_dns 的修改
+pub fn plan_dns(plan: &mut Plan, dev_name: &str, servers: &[Ipv4Addr]) {
+    if servers.is_empty() {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    plan.command(Category::Dns, "resolvectl", &dns_args(dev_name, servers));
+
+    #[cfg(not(target_os = "linux"))]
+    plan.note(Category::Dns, format!("为 {} 设置 DNS 服务器 {:?}", dev_name, servers));
+}
+
+/// 撤销 set_dns 的设置
+#[cfg_attr(not(any(target_os = "linux", target_os = "windows")), allow(unused_variables))]
+pub fn clear_dns(dev_name: &str) -> Result<()> {
+    #[cfg(target_os = "windows")]
+    netsh(&["interface", "ipv4", "set", "dnsservers", &format!("name={}", windows_interface_index(dev_name)?), "source=dhcp"])?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let _ = Command::new("resolvectl").args(["revert", dev_name]).status()?;
+    }
+
+    Ok(())
+}
+
+/// 执行一条 netsh 命令
+#[cfg(target_os = "windows")]
+fn netsh(args: &[&str]) -> Result<()> {
+    let output = Command::new("netsh").args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "netsh {} 失败: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+    }
+    Ok(())
+}
+
+/// 按接口名查找 Windows 接口索引（netsh 的 name= 参数用索引可以避开名称中的空格和本地化问题）
+#[cfg(target_os = "windows")]
+pub fn windows_interface_index(dev_name: &str) -> Result<u32> {
+    let output = Command::new("netsh").args(["interface", "ipv4", "show", "interfaces"]).output()?;
+    parse_netsh_interfaces(&String::from_utf8_lossy(&output.stdout), dev_name)
+        .ok_or_else(|| anyhow::anyhow!("找不到接口 {} 的索引", dev_name))
+}
+
+/// 解析 `netsh interface ipv4 show interfaces` 的输出，返回指定接口的索引
+///
+/// 格式:
+/// ```text
+/// Idx     Met         MTU          State                Name
+/// ---  ----------  ----------  ------------  ---------------------------
+///  12          25        1500  connected     Wi-Fi
+/// ```
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn parse_netsh_interfaces(output: &str, dev_name: &str) -> Option<u32> {
+    output.lines().find_map(|line| {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let index = parts.first()?.parse().ok()?;
+        // 名称可能包含空格，取第 5 列之后的全部内容
+        (parts.get(4..)?.join(" ") == dev_name).then_some(index)
+    })
+}
+
+/// 解析 `route print -4 0.0.0.0` 的输出，返回默认网关
+///
+/// 格式: `          0.0.0.0          0.0.0.0      192.168.1.1    192.168.1.100     25`
+pub fn parse_windows_default_gateway(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            ["0.0.0.0", "0.0.0.0", gateway, ..] if gateway.parse::<Ipv4Addr>().is_ok() => Some(gateway.to_string()),
+            _ => None,
+        }
+    })
+}
+
+/// Linux 策略路由（WireGuard 风格）的默认 fwmark 和路由表
+///
+/// 与 wg-quick 的 51820 区分开，两者可以同时运行
+pub const DEFAULT_FWMARK: u32 = 0xca6d;
+pub const DEFAULT_POLICY_TABLE: u32 = 51821;
+
+/// 全隧道的策略路由参数：隧道自身的 UDP 包打上 fwmark，其余流量查 table（默认路由指向 TUN）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PolicyRouting {
+    pub fwmark: u32,
+    pub table: u32,
+}
+
+impl Default for PolicyRouting {
+    fn default() -> Self {
+        Self { fwmark: DEFAULT_FWMARK, table: DEFAULT_POLICY_TABLE }
+    }
+}
+
+/// 解析 fwmark，支持十进制和 0x 开头的十六进制
+pub fn parse_fwmark(s: &str) -> Result<u32> {
+    let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    value.map_err(|_| anyhow::anyhow!("无效的 fwmark: {}", s))
+}
+
+/// 给套接字设置 SO_MARK（需要 CAP_NET_ADMIN），策略路由据此让隧道自身的包走主路由表
+#[cfg(target_os = "linux")]
+pub fn set_fwmark<S: std::os::unix::io::AsRawFd>(socket: &S, mark: u32) -> Result<()> {
+    // SAFETY: fd 有效，mark 的生命周期覆盖调用
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// 启用全隧道策略路由，不修改主路由表的默认路由：
+///
+/// ```text
+/// ip route replace default dev <dev> table <table>
+/// ip rule add not fwmark <mark> table <table>
+/// ip rule add table main suppress_prefixlength 0
+/// ```
+///
+/// 第二条让未打标记的流量查隧道路由表；第三条先查主表但忽略其中的默认路由，
+/// 这样本地网段等更具体的路由仍然有效。隧道自身的 UDP 包带有 fwmark，走主表的默认路由，
+/// 因此不需要到服务器的例外路由，也不会和其他 VPN 争抢默认路由
+#[cfg(target_os = "linux")]
+pub fn enable_policy_routing(dev_name: &str, policy: &PolicyRouting) -> Result<()> {
+    // 清理上次异常退出残留的规则
+    disable_policy_routing(policy);
+    
+    for args in policy_routing_args(dev_name, policy) {
+        let status = Command::new("ip").args(&args).status()?;
+        if !status.success() {
+            disable_policy_routing(policy);
+            anyhow::bail!("ip {} 失败 (exit code: {:?})", args.join(" "), status.code())
+        }
+    }
+    
+    // 带 fwmark 的回包需要通过反向路径过滤
+    let _ = Command::new("sysctl").args(["-q", "-w", SRC_VALID_MARK_SYSCTL]).status();
+    Ok(())
+}
+
+/// 策略路由需要打开的内核参数
+#[cfg(target_os = "linux")]
+const SRC_VALID_MARK_SYSCTL: &str = "net.ipv4.conf.all.src_valid_mark=1";
+
+/// enable_policy_routing 依次执行的 `ip` 参数
+#[cfg(target_os = "linux")]
+fn policy_routing_args(dev_name: &str, policy: &PolicyRouting) -> Vec<Vec<String>> {
+    let table = policy.table.to_string();
+    let mark = format!("{:#x}", policy.fwmark);
+    [
+        vec!["route", "replace", "default", "dev", dev_name, "table", &table],
+        vec!["rule", "add", "not", "fwmark", &mark, "table", &table],
+        vec!["rule", "add", "table", "main", "suppress_prefixlength", "0"],
+    ]
+    .iter()
+    .map(|args| args.iter().map(|s| s.to_string()).collect())
+    .collect()
+}
+
+/// 试运行：列出 enable_policy_routing 的修改
+#[cfg(target_os = "linux")]
+pub fn plan_policy_routing(plan: &mut Plan, dev_name: &str, policy: &PolicyRouting) {
+    plan.note(Category::Route, format!("隧道的 UDP socket 打上 fwmark {:#x}（SO_MARK）", policy.fwmark));
+    for args in policy_routing_args(dev_name, policy) {
+        plan.command(Category::Route, "ip", &args);
+    }
+    plan.command(Category::Sysctl, "sysctl", &["-w", SRC_VALID_MARK_SYSCTL]);
+}
+
+/// 撤销 enable_policy_routing 添加的规则和路由（忽略不存在的条目）
+#[cfg(target_os = "linux")]
+pub fn disable_policy_routing(policy: &PolicyRouting) {
+    let table = policy.table.to_string();
+    let mark = format!("{:#x}", policy.fwmark);
+    let _ = Command::new("ip").args(["rule", "del", "not", "fwmark", &mark, "table", &table]).output();
+    let _ = Command::new("ip").args(["rule", "del", "table", "main", "suppress_prefixlength", "0"]).output();
+    let _ = Command::new("ip").args(["route", "flush", "table", &table]).output();
+}
+
+/// 修改设备 MTU
+pub fn set_mtu(dev_name: &str, mtu: u16) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let status = Command::new("ip")
+        .args(["link", "set", "dev", dev_name, "mtu", &mtu.to_string()])
+        .status()?;
+
+    #[cfg(target_os = "windows")]
+    let status = Command::new("netsh")
+        .args(["interface", "ipv4", "set", "subinterface", dev_name, &format!("mtu={}", mtu), "store=active"])
+        .status()?;
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    let status = Command::new("ifconfig")
+        .args([dev_name, "mtu", &mtu.to_string()])
+        .status()?;
+
+    if !status.success() {
+        anyhow::bail!("设置 MTU 失败 (exit code: {:?})", status.code())
+    }
+    Ok(())
+}
+
+/// 试运行：列出 set_mtu 的修改
+pub fn plan_mtu(plan: &mut Plan, dev_name: &str, mtu: u16) {
+    #[cfg(target_os = "linux")]
+    plan.command(Category::Tun, "ip", &["link", "set", "dev", dev_name, "mtu", &mtu.to_string()]);
+
+    #[cfg(not(target_os = "linux"))]
+    plan.note(Category::Tun, format!("将 {} 的 MTU 设为 {}", dev_name, mtu));
+}
+
+/// 隧道内 IPv6 地址段 fd00::/96：低 32 位嵌入虚拟 IPv4 地址（10.0.0.2 -> fd00::a00:2），
+/// 这样不需要额外分配，服务端仍按 IPv4 虚拟地址查找客户端
+pub const TUNNEL_IPV6_PREFIX_LEN: u8 = 96;
+const TUNNEL_IPV6_PREFIX: u128 = 0xfd00 << 112;
+
+/// 虚拟 IPv4 地址对应的隧道 IPv6 地址
+pub fn tunnel_ipv6(v4: Ipv4Addr) -> Ipv6Addr {
+    Ipv6Addr::from(TUNNEL_IPV6_PREFIX | u32::from(v4) as u128)
+}
+
+/// 隧道 IPv6 地址中嵌入的虚拟 IPv4 地址；不在 fd00::/96 内时返回 None
+pub fn tunnel_ipv4(v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    let bits = u128::from(v6);
+    (bits >> 32 == TUNNEL_IPV6_PREFIX >> 32).then(|| Ipv4Addr::from(bits as u32))
+}
+
+/// 隧道 IPv6 网段（CIDR 形式）
+pub fn tunnel_ipv6_network() -> String {
+    format!("{}/{}", Ipv6Addr::from(TUNNEL_IPV6_PREFIX), TUNNEL_IPV6_PREFIX_LEN)
+}
+
+/// 为 TUN 接口添加隧道 IPv6 地址（同时生成 fd00::/96 的接口路由）
+pub fn add_ipv6_address(dev_name: &str, address: Ipv6Addr) -> Result<()> {
+    let cidr = format!("{}/{}", address, TUNNEL_IPV6_PREFIX_LEN);
+    println!("正在为设备 {} 配置 IPv6 地址 {} ...", dev_name, cidr);
+
+    #[cfg(target_os = "linux")]
+    let status = Command::new("ip").args(["-6", "addr", "add", &cidr, "dev", dev_name]).status()?;
+
+    #[cfg(target_os = "windows")]
+    let status = {
+        let index = windows_interface_index(dev_name)?.to_string();
+        netsh(&["interface", "ipv6", "add", "route", &tunnel_ipv6_network(), &format!("interface={}", index), "store=active"])?;
+        Command::new("netsh")
+            .args(["interface", "ipv6", "add", "address", &format!("interface={}", index), &address.to_string(), "store=active"])
+            .status()?
+    };
+
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    let status = Command::new("ifconfig")
+        .args([dev_name, "inet6", &address.to_string(), "prefixlen", &TUNNEL_IPV6_PREFIX_LEN.to_string()])
+        .status()?;
+
+    if !status.success() {
+        anyhow::bail!("IPv6 地址配置失败（系统可能禁用了 IPv6）(exit code: {:?})", status.code())
+    }
+    Ok(())
+}
+
+/// 试运行：列出 add_ipv6_address 的修改
+pub fn plan_ipv6_address(plan: &mut Plan, dev_name: &str, address: Ipv6Addr) {
+    let cidr = format!("{}/{}", address, TUNNEL_IPV6_PREFIX_LEN);
+    #[cfg(target_os = "linux")]
+    plan.command(Category::Tun, "ip", &["-6", "addr", "add", &cidr, "dev", dev_name]);
+
+    #[cfg(not(target_os = "linux"))]
+    plan.note(Category::Tun, format!("为 {} 添加 IPv6 地址 {}", dev_name, cidr));
+}
+
+/// 覆盖 IPv6 默认路由的两条 /1 路由
+const IPV6_FULL_TUNNEL_PREFIXES: [&str; 2] = ["::/1", "8000::/1"];
+
+/// IPv6 全隧道：与 IPv4 一样用 ::/1 + 8000::/1 覆盖默认路由，原默认路由保持不动
+///
+/// 隧道本身走 IPv4，这两条路由不会把加密后的 UDP 包再引回 TUN，Linux 上无需策略路由
+pub fn configure_ipv6_full_tunnel(dev_name: &str) -> Result<()> {
+    println!("正在为设备 {} 配置 IPv6 默认路由 ...", dev_name);
+    for prefix in IPV6_FULL_TUNNEL_PREFIXES {
+        #[cfg(target_os = "linux")]
+        let status = Command::new("ip").args(["-6", "route", "add", prefix, "dev", dev_name]).status()?;
+
+        #[cfg(target_os = "windows")]
+        let status = Command::new("netsh")
+            .args(["interface", "ipv6", "add", "route", prefix, &format!("interface={}", windows_interface_index(dev_name)?), "store=active"])
+            .status()?;
+
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let status = Command::new("route").args(["-n", "add", "-inet6", prefix, "-interface", dev_name]).status()?;
+
+        if !status.success() {
+            anyhow::bail!("IPv6 路由配置失败 (exit code: {:?})", status.code())
+        }
+    }
+    Ok(())
+}
+
+/// 试运行：列出 configure_ipv6_full_tunnel 添加的路由
+pub fn plan_ipv6_full_tunnel(plan: &mut Plan, dev_name: &str) {
+    for prefix in IPV6_FULL_TUNNEL_PREFIXES {
+        #[cfg(target_os = "linux")]
+        plan.command(Category::Route, "ip", &["-6", "route", "add", prefix, "dev", dev_name]);
+
+        #[cfg(not(target_os = "linux"))]
+        plan.note(Category::Route, format!("添加 IPv6 路由 {} -> {}", prefix, dev_name));
+    }
+}
+
+/// 删除 configure_ipv6_full_tunnel 添加的路由（忽略错误，接口消失时路由已随之删除）
+pub fn remove_ipv6_full_tunnel(dev_name: &str) {
+    for prefix in IPV6_FULL_TUNNEL_PREFIXES {
+        #[cfg(target_os = "linux")]
+        let _ = Command::new("ip").args(["-6", "route", "del", prefix, "dev", dev_name]).status();
+
+        #[cfg(target_os = "windows")]
+        if let Ok(index) = windows_interface_index(dev_name) {
+            let _ = netsh(&["interface", "ipv6", "delete", "route", prefix, &format!("interface={}", index)]);
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        let _ = Command::new("route").args(["-n", "delete", "-inet6", prefix, "-interface", dev_name]).status();
+    }
+}
+
+/// macOS 的协议族编号（与 Linux 不同，AF_INET6 为 30）
+const UTUN_AF_INET: u32 = 2;
+const UTUN_AF_INET6: u32 = 30;
+
+/// TUN 帧编解码：处理平台相关的包头
+///
+/// macOS utun 每个包前有 4 字节协议族头（网络字节序），读出时需要去掉，写入时需要按包的
+/// IP 版本补上；Linux 配置了 no_pi，Windows (wintun) 也没有包头，帧就是 IP 包本身
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunFrameCodec {
+    af_header: bool,
+}
+
+impl TunFrameCodec {
+    /// 协议族头的长度
+    pub const AF_HEADER_LEN: usize = 4;
+
+    /// 当前平台的编解码器
+    pub const fn platform() -> Self {
+        Self { af_header: cfg!(target_os = "macos") }
+    }
+
+    /// 指定是否带协议族头（用于测试或跨平台处理抓包数据）
+    pub const fn with_af_header(af_header: bool) -> Self {
+        Self { af_header }
+    }
+
+    /// 帧头长度
+    pub const fn header_len(&self) -> usize {
+        if self.af_header { Self::AF_HEADER_LEN } else { 0 }
+    }
+
+    /// 从读到的帧中取出 IP 包；空包、协议族头与 IP 版本不符（或非 IP 协议族）时返回 None
+    pub fn decode<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        if !self.af_header {
+            return (!frame.is_empty()).then_some(frame);
+        }
+        let (header, packet) = frame.split_at_checked(Self::AF_HEADER_LEN)?;
+        let family = u32::from_be_bytes(header.try_into().ok()?);
+        match (family, packet.first()? >> 4) {
+            (UTUN_AF_INET, 4) | (UTUN_AF_INET6, 6) => Some(packet),
+            _ => None,
+        }
+    }
+
+    /// 把 IP 包编码为可以写入 TUN 的帧（无帧头时直接返回原包，不复制）
+    pub fn encode(&self, ip_packet: Vec<u8>) -> Vec<u8> {
+        if !self.af_header {
+            return ip_packet;
+        }
+        let family = match ip_packet.first().map(|b| b >> 4) {
+            Some(6) => UTUN_AF_INET6,
+            _ => UTUN_AF_INET,
+        };
+        let mut frame = Vec::with_capacity(Self::AF_HEADER_LEN + ip_packet.len());
+        frame.extend_from_slice(&family.to_be_bytes());
+        frame.extend_from_slice(&ip_packet);
+        frame
+    }
+}
+
+/// 检查本机已有接口是否占用了与 `address/netmask` 重叠的网段
+///
+/// 返回冲突的 (接口名, 地址/前缀)，用于在创建 TUN 之前给出明确的错误。
+/// `ignore_iface` 用于排除将要复用的设备本身
+pub fn find_subnet_conflict(address: &str, netmask: &str, ignore_iface: Option<&str>) -> Result<Option<(String, String)>> {
+    let ip = Ipv4Addr::from_str(address)?;
+    let prefix = u32::from(Ipv4Addr::from_str(netmask)?).count_ones() as u8;
+    
+    #[cfg(target_os = "linux")]
+    let output = Command::new("ip").args(["-o", "-4", "addr", "show"]).output()?;
+    
+    #[cfg(target_os = "macos")]
+    let output = Command::new("ifconfig").output()?;
+    
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    
+    #[cfg(target_os = "linux")]
+    let addrs = parse_ip_addr_output(&stdout);
+    
+    #[cfg(target_os = "macos")]
+    let addrs = parse_ifconfig_output(&stdout);
+    
+    Ok(addrs.into_iter()
+        .filter(|(iface, _, _)| Some(iface.as_str()) != ignore_iface)
+        .find(|(_, other_ip, other_prefix)| subnets_overlap(ip, prefix, *other_ip, *other_prefix))
+        .map(|(iface, other_ip, other_prefix)| (iface, format!("{}/{}", other_ip, other_prefix))))
+}
+
+/// 由地址和掩码得到网段 CIDR，例如 ("10.0.0.2", "255.255.255.0") -> "10.0.0.0/24"
+pub fn network_cidr(address: &str, netmask: &str) -> Result<String> {
+    let ip = u32::from(Ipv4Addr::from_str(address)?);
+    let mask = u32::from(Ipv4Addr::from_str(netmask)?);
+    Ok(format!("{}/{}", Ipv4Addr::from(ip & mask), mask.count_ones()))
+}
+
+/// 两个网段是否重叠（按较短的前缀比较）
+pub fn subnets_overlap(a: Ipv4Addr, a_prefix: u8, b: Ipv4Addr, b_prefix: u8) -> bool {
+    let prefix = a_prefix.min(b_prefix).min(32);
+    if prefix == 0 {
+        return true;
+    }
+    let mask = u32::MAX << (32 - prefix as u32);
+    (u32::from(a) & mask) == (u32::from(b) & mask)
+}
+
+/// 解析 `ip -o -4 addr show` 的输出
+/// 格式: `3: tun0    inet 10.0.0.2/24 scope global tun0 ...`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_ip_addr_output(output: &str) -> Vec<(String, Ipv4Addr, u8)> {
+    output.lines().filter_map(|line| {
+        let mut parts = line.split_whitespace();
+        let iface = parts.nth(1)?.trim_end_matches(':').to_string();
+        let cidr = parts.skip_while(|p| *p != "inet").nth(1)?;
+        let (ip, prefix) = cidr.split_once('/')?;
+        Some((iface, ip.parse().ok()?, prefix.parse().ok()?))
+    })
+    .filter(|(_, ip, _): &(String, Ipv4Addr, u8)| !ip.is_loopback())
+    .collect()
+}
+
+/// 解析 macOS `ifconfig` 的输出
+/// 格式: `utun3: flags=...` 之后跟 `\tinet 10.0.0.2 --> 10.0.0.2 netmask 0xffffff00`
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_ifconfig_output(output: &str) -> Vec<(String, Ipv4Addr, u8)> {
+    let mut result = Vec::new();
+    let mut iface = String::new();
+    
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            if let Some((name, _)) = line.split_once(':') {
+                iface = name.to_string();
+            }
+            continue;
+        }
+        
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.first() != Some(&"inet") {
+            continue;
+        }
+        let Some(ip) = parts.get(1).and_then(|p| p.parse::<Ipv4Addr>().ok()) else { continue };
+        let prefix = parts.iter()
+            .position(|p| *p == "netmask")
+            .and_then(|i| parts.get(i + 1))
+            .and_then(|m| u32::from_str_radix(m.trim_start_matches("0x"), 16).ok())
+            .map(|m| m.count_ones() as u8)
+            .unwrap_or(32);
+        
+        if !ip.is_loopback() {
+            result.push((iface.clone(), ip, prefix));
+        }
+    }
+    
+    result
+}
+
+/// 每批最多处理的包数
+pub const MAX_BATCH: usize = 32;
+
+/// 批量读取 TUN 包：等待第一个包，然后把已经就绪的包一并取走（最多 max 个）
+///
+/// TUN 的 read()/readv() 每次只交付一个包，这里通过不阻塞地继续读取，
+/// 让一次唤醒处理多个包。每项为 (缓冲区, 有效长度)，用完后应归还到 pool。
+/// 返回 Ok(false) 表示设备已关闭。
+#[cfg(feature = "tokio")]
+pub async fn read_batch<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    pool: &BufferPool,
+    batch: &mut Vec<(Vec<u8>, usize)>,
+    max: usize,
+) -> std::io::Result<bool> {
+    let mut buf = pool.get();
+    let n = reader.read(&mut buf).await?;
+    if n == 0 {
+        pool.put(buf);
+        return Ok(false);
+    }
+    batch.push((buf, n));
+
+    let mut cx = std::task::Context::from_waker(std::task::Waker::noop());
+    while batch.len() < max {
+        let mut buf = pool.get();
+        let mut read_buf = ReadBuf::new(&mut buf);
+        match std::pin::Pin::new(&mut *reader).poll_read(&mut cx, &mut read_buf) {
+            std::task::Poll::Ready(Ok(())) if !read_buf.filled().is_empty() => {
+                let n = read_buf.filled().len();
+                batch.push((buf, n));
+            }
+            // 没有更多就绪的包（或出错/关闭，留给下一次 read_batch 处理）
+            _ => {
+                pool.put(buf);
+                break;
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+/// 批量写入 TUN 包：逐个写入（TUN 按一次 write 一个包划分边界），最后统一 flush
+#[cfg(feature = "tokio")]
+pub async fn write_batch<W: AsyncWrite + Unpin, P: AsRef<[u8]>>(writer: &mut W, packets: &[P]) -> std::io::Result<()> {
+    for packet in packets {
+        writer.write_all(packet.as_ref()).await?;
+    }
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn test_subnets_overlap() {
+        let a = Ipv4Addr::new(10, 0, 0, 2);
+        assert!(subnets_overlap(a, 24, Ipv4Addr::new(10, 0, 0, 3), 24));
+        assert!(!subnets_overlap(a, 24, Ipv4Addr::new(10, 0, 1, 3), 24));
+        assert!(subnets_overlap(a, 24, Ipv4Addr::new(10, 0, 1, 3), 16));
+        assert!(subnets_overlap(a, 0, Ipv4Addr::new(192, 168, 1, 1), 24));
+        
+        assert_eq!(network_cidr("10.0.1.7", "255.255.255.0").unwrap(), "10.0.1.0/24");
+    }
+    
+    #[test]
+    fn test_tunnel_ipv6_mapping() {
+        let v4 = Ipv4Addr::new(10, 0, 0, 2);
+        let v6 = tunnel_ipv6(v4);
+        assert_eq!(v6, "fd00::a00:2".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(tunnel_ipv4(v6), Some(v4));
+        assert_eq!(tunnel_ipv4("2001:db8::a00:2".parse().unwrap()), None);
+        assert_eq!(tunnel_ipv6_network(), "fd00::/96");
+    }
+
+    #[test]
+    fn test_tun_frame_codec() {
+        let utun = TunFrameCodec::with_af_header(true);
+        let v4 = vec![0x45, 0, 0, 20];
+        let v6 = vec![0x60, 0, 0, 0];
+        assert_eq!(utun.encode(v4.clone()), [0, 0, 0, 0x02, 0x45, 0, 0, 20]);
+        assert_eq!(utun.encode(v6.clone()), [0, 0, 0, 0x1e, 0x60, 0, 0, 0]);
+        assert_eq!(utun.decode(&utun.encode(v6.clone())), Some(&v6[..]));
+        // 协议族头与 IP 版本不符、过短的帧都丢弃
+        assert_eq!(utun.decode(&[0, 0, 0, 0x02, 0x60, 0, 0, 0]), None);
+        assert_eq!(utun.decode(&[0, 0, 0, 0x02]), None);
+
+        let plain = TunFrameCodec::with_af_header(false);
+        assert_eq!(plain.header_len(), 0);
+        assert_eq!(plain.encode(v4.clone()), v4);
+        assert_eq!(plain.decode(&v4), Some(&v4[..]));
+        assert_eq!(plain.decode(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_fwmark() {
+        assert_eq!(parse_fwmark("0xca6d").unwrap(), 0xca6d);
+        assert_eq!(parse_fwmark("51820").unwrap(), 51820);
+        assert!(parse_fwmark("mark").is_err());
+    }
+    
+    #[test]
+    fn test_parse_windows_outputs() {
+        let interfaces = "\
+Idx     Met         MTU          State                Name
+---  ----------  ----------  ------------  ---------------------------
+  1          75  4294967295  connected     Loopback Pseudo-Interface 1
+ 12          25        1500  connected     Wi-Fi
+ 27           5        1420  connected     rust-vpn
+";
+        assert_eq!(parse_netsh_interfaces(interfaces, "rust-vpn"), Some(27));
+        assert_eq!(parse_netsh_interfaces(interfaces, "Loopback Pseudo-Interface 1"), Some(1));
+        assert_eq!(parse_netsh_interfaces(interfaces, "Ethernet"), None);
+        
+        let routes = "\
+IPv4 Route Table
+===========================================================================
+Active Routes:
+Network Destination        Netmask          Gateway       Interface  Metric
+          0.0.0.0          0.0.0.0      192.168.1.1    192.168.1.100     25
+===========================================================================
+";
+        assert_eq!(parse_windows_default_gateway(routes).as_deref(), Some("192.168.1.1"));
+    }
+    
+    #[test]
+    fn test_parse_ip_addr_output() {
+        let output = "1: lo    inet 127.0.0.1/8 scope host lo\n\
+                      2: eth0    inet 192.168.1.10/24 brd 192.168.1.255 scope global eth0\n\
+                      5: tun0    inet 10.0.0.2/24 scope global tun0\n";
+        let addrs = parse_ip_addr_output(output);
+        assert_eq!(addrs, vec![
+            ("eth0".to_string(), Ipv4Addr::new(192, 168, 1, 10), 24),
+            ("tun0".to_string(), Ipv4Addr::new(10, 0, 0, 2), 24),
+        ]);
+    }
+    
+    #[test]
+    fn test_parse_ifconfig_output() {
+        let output = "lo0: flags=8049<UP,LOOPBACK,RUNNING,MULTICAST> mtu 16384\n\
+                      \tinet 127.0.0.1 netmask 0xff000000\n\
+                      utun3: flags=8051<UP,POINTOPOINT,RUNNING,MULTICAST> mtu 1500\n\
+                      \tinet 10.0.0.2 --> 10.0.0.2 netmask 0xffffff00\n";
+        let addrs = parse_ifconfig_output(output);
+        assert_eq!(addrs, vec![("utun3".to_string(), Ipv4Addr::new(10, 0, 0, 2), 24)]);
+    }
+    
+    /// 按包交付的模拟 TUN：每次 poll_read 返回一个包，队列空时 Pending
+    #[cfg(feature = "tokio")]
+    struct MockTun(std::collections::VecDeque<Vec<u8>>);
+    
+    #[cfg(feature = "tokio")]
+    impl AsyncRead for MockTun {
+        fn poll_read(mut self: std::pin::Pin<&mut Self>, _cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> std::task::Poll<std::io::Result<()>> {
+            match self.0.pop_front() {
+                Some(p) => {
+                    buf.put_slice(&p);
+                    std::task::Poll::Ready(Ok(()))
+                }
+                None => std::task::Poll::Pending,
+            }
+        }
+    }
+    
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_read_batch() {
+        let pool = BufferPool::new(1500, 8);
+        let mut tun = MockTun((1..=5u8).map(|i| vec![i; i as usize * 10]).collect());
+        let mut batch = Vec::new();
+        
+        assert!(read_batch(&mut tun, &pool, &mut batch, 3).await.unwrap());
+        assert_eq!(batch.iter().map(|(_, n)| *n).collect::<Vec<_>>(), vec![10, 20, 30]);
+        assert_eq!(batch[1].0[..20], [2u8; 20]);
+        
+        batch.drain(..).for_each(|(buf, _)| pool.put(buf));
+        assert!(read_batch(&mut tun, &pool, &mut batch, 3).await.unwrap());
+        assert_eq!(batch.len(), 2);
+    }
+}
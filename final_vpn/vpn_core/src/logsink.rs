@@ -0,0 +1,427 @@
+// vpn_core/src/logsink.rs
+// 日志输出目标（--log stdout|file:<路径>|journald|syslog）
+//
+// 服务端和客户端的日志都是直接 println! / eprintln!。作为守护进程长期运行时需要写到文件或系统日志：
+// init_from_args 把进程的 stdout / stderr（fd 1 / 2）换成管道，后台线程按行读出后写到选定的目标。
+// 已有的打印和启动的子进程（ip、iptables 等）的输出都会进入日志，stderr 的行按 warning 级别记录。
+//
+// * file:<路径>：每行前加 UTC 时间；--log-max-size <大小> 按大小、--log-rotate hourly|daily 按时间轮转，
+//   旧文件依次改名为 <路径>.1、<路径>.2 ...，保留 --log-keep 个（默认 5）
+// * journald（Linux）：通过 /run/systemd/journal/socket 写入，带 SYSLOG_IDENTIFIER 和 PRIORITY
+// * syslog：syslog(3)，facility 为 daemon；macOS 上 syslog(3) 的消息进入统一日志系统（os_log），
+//   用 `log stream --predicate 'process == "vpn_server"'` 查看，oslog 是它的别名
+//
+// 进程退出时（std::process::exit 或 main 返回）最多等待 1 秒，把管道里剩下的行写完。
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+
+use crate::tuning;
+
+/// 默认保留的轮转文件数
+pub const DEFAULT_KEEP: usize = 5;
+
+/// 日志写到哪里
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Sink {
+    /// 不重定向（默认）
+    #[default]
+    Stdout,
+    File(PathBuf),
+    Journald,
+    Syslog,
+}
+
+impl Sink {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "stdout" => Ok(Sink::Stdout),
+            "journald" => Ok(Sink::Journald),
+            "syslog" | "oslog" => Ok(Sink::Syslog),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Sink::File(PathBuf::from(path))),
+                _ => Err(anyhow!("无效的日志输出: {}（可用: stdout / file:<路径> / journald / syslog / oslog）", s)),
+            },
+        }
+    }
+}
+
+/// 按时间轮转的周期
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotateEvery {
+    Hourly,
+    Daily,
+}
+
+impl RotateEvery {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "hourly" => Ok(RotateEvery::Hourly),
+            "daily" => Ok(RotateEvery::Daily),
+            _ => Err(anyhow!("无效的 --log-rotate: {}（可用: hourly / daily）", s)),
+        }
+    }
+
+    fn secs(&self) -> u64 {
+        match self {
+            RotateEvery::Hourly => 3600,
+            RotateEvery::Daily => 86400,
+        }
+    }
+}
+
+/// 日志文件的轮转策略
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rotation {
+    /// 当前文件超过这个大小（字节）时轮转
+    pub max_size: Option<u64>,
+    /// 跨过整点 / UTC 零点时轮转
+    pub every: Option<RotateEvery>,
+    /// 保留的旧文件数
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self { max_size: None, every: None, keep: DEFAULT_KEEP }
+    }
+}
+
+/// 日志配置
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct LogOptions {
+    pub sink: Sink,
+    pub rotation: Rotation,
+}
+
+impl LogOptions {
+    /// 从命令行参数读取（--log / --log-max-size / --log-rotate / --log-keep）
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let value = |name: &str| args.iter().position(|a| a == name).and_then(|i| args.get(i + 1));
+        let sink = value("--log").map(|s| Sink::parse(s)).transpose()?.unwrap_or_default();
+        let rotation = Rotation {
+            max_size: value("--log-max-size").map(|s| tuning::parse_size(s)).transpose()?.map(|n| n as u64),
+            every: value("--log-rotate").map(|s| RotateEvery::parse(s)).transpose()?,
+            keep: match value("--log-keep") {
+                Some(n) => n.parse().ok().filter(|n| *n > 0).ok_or_else(|| anyhow!("无效的 --log-keep: {}", n))?,
+                None => DEFAULT_KEEP,
+            },
+        };
+        if !matches!(sink, Sink::File(_)) && (rotation.max_size.is_some() || rotation.every.is_some()) {
+            return Err(anyhow!("--log-max-size / --log-rotate 只适用于 --log file:<路径>"));
+        }
+        Ok(Self { sink, rotation })
+    }
+}
+
+/// 日志行的级别（来自 stdout 的为 Info，来自 stderr 的为 Warning）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warning,
+}
+
+impl Level {
+    /// syslog / journald 的优先级
+    fn priority(&self) -> i32 {
+        match self {
+            Level::Info => 6,
+            Level::Warning => 4,
+        }
+    }
+}
+
+/// 按大小 / 时间轮转的日志文件
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 当前文件所属的轮转周期（unix 秒 / 周期长度）
+    period: u64,
+    rotation: Rotation,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, rotation: Rotation) -> Result<Self> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        let period = rotation.every.map(|e| unix_now() / e.secs()).unwrap_or(0);
+        Ok(Self { path: path.to_path_buf(), file, size, period, rotation })
+    }
+
+    /// 写一行（unix_secs 用于时间戳和按时间轮转）
+    pub fn write_line(&mut self, line: &str, unix_secs: u64) -> io::Result<()> {
+        let record = format!("{} {}\n", format_utc(unix_secs), line);
+        let period = self.rotation.every.map(|e| unix_secs / e.secs()).unwrap_or(0);
+        let oversized = self.rotation.max_size.is_some_and(|max| self.size > 0 && self.size + record.len() as u64 > max);
+        if oversized || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        self.file.write_all(record.as_bytes())?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    /// <路径>.keep-1 -> <路径>.keep ... <路径> -> <路径>.1，然后重新创建 <路径>
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.rotation.keep).rev() {
+            let _ = fs::rename(numbered(&self.path, i), numbered(&self.path, i + 1));
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+        self.file = open_append(&self.path).map_err(io::Error::other)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+    options.open(path).map_err(|e| anyhow!("无法打开日志文件 {}: {}", path.display(), e))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// RFC 3339 格式的 UTC 时间（2026-10-15T20:30:20Z）
+pub fn format_utc(unix_secs: u64) -> String {
+    let (days, secs) = (unix_secs / 86400, unix_secs % 86400);
+    // 公历日期（Howard Hinnant 的 civil_from_days）
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// 根据命令行参数重定向 stdout / stderr；ident 为 syslog / journald 中的程序名
+pub fn init_from_args(args: &[String], ident: &str) -> Result<()> {
+    let options = LogOptions::from_args(args)?;
+    if options.sink == Sink::Stdout {
+        return Ok(());
+    }
+    imp::install(options, ident)
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use super::*;
+
+    pub fn install(_options: LogOptions, _ident: &str) -> Result<()> {
+        Err(anyhow!("--log 目前只支持 Linux / macOS"))
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::*;
+    use std::ffi::CString;
+    use std::os::fd::{FromRawFd, RawFd};
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::mpsc;
+    use std::sync::{Mutex, OnceLock};
+
+    /// 退出时等待剩余日志写完的最长时间
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// 写入线程写完全部日志（两个管道都已关闭）后发出通知
+    static DRAINED: OnceLock<Mutex<mpsc::Receiver<()>>> = OnceLock::new();
+
+    /// 实际写日志的一端（在写入线程里使用）
+    enum Writer {
+        File(RotatingFile),
+        Journald { socket: UnixDatagram, ident: String },
+        Syslog,
+    }
+
+    impl Writer {
+        fn open(options: LogOptions, ident: &str) -> Result<Self> {
+            match options.sink {
+                Sink::Stdout => unreachable!(),
+                Sink::File(path) => Ok(Writer::File(RotatingFile::open(&path, options.rotation)?)),
+                Sink::Journald => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect("/run/systemd/journal/socket").map_err(|e| anyhow!("无法连接 journald: {}", e))?;
+                    Ok(Writer::Journald { socket, ident: ident.to_string() })
+                }
+                Sink::Syslog => {
+                    // openlog 保存指针而不复制，ident 需要在整个进程期间有效
+                    let ident: &'static CString = Box::leak(Box::new(CString::new(ident)?));
+                    unsafe { libc::openlog(ident.as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+                    Ok(Writer::Syslog)
+                }
+            }
+        }
+
+        /// 写一行；写失败时没有地方报告（stderr 本身就是这个管道），直接丢弃
+        fn write(&mut self, level: Level, line: &str) {
+            match self {
+                Writer::File(file) => {
+                    let _ = file.write_line(line, unix_now());
+                }
+                Writer::Journald { socket, ident } => {
+                    let entry = format!("PRIORITY={}\nSYSLOG_IDENTIFIER={}\nMESSAGE={}\n", level.priority(), ident, line);
+                    let _ = socket.send(entry.as_bytes());
+                }
+                Writer::Syslog => {
+                    let Ok(message) = CString::new(line.replace('\0', " ")) else { return };
+                    unsafe { libc::syslog(libc::LOG_DAEMON | level.priority(), c"%s".as_ptr(), message.as_ptr()) };
+                }
+            }
+        }
+    }
+
+    pub fn install(options: LogOptions, ident: &str) -> Result<()> {
+        // 先打开目标，失败时日志还在终端上
+        let mut writer = Writer::open(options, ident)?;
+        io::stdout().flush()?;
+
+        let (tx, rx) = mpsc::channel::<(Level, String)>();
+        for (fd, level) in [(libc::STDOUT_FILENO, Level::Info), (libc::STDERR_FILENO, Level::Warning)] {
+            let pipe = redirect(fd)?;
+            let tx = tx.clone();
+            std::thread::spawn(move || {
+                let mut reader = BufReader::new(pipe);
+                let mut buf = Vec::new();
+                while reader.read_until(b'\n', &mut buf).is_ok_and(|n| n > 0) {
+                    let line = String::from_utf8_lossy(&buf);
+                    if tx.send((level, line.trim_end_matches(['\n', '\r']).to_string())).is_err() {
+                        break;
+                    }
+                    buf.clear();
+                }
+            });
+        }
+        drop(tx);
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let _ = DRAINED.set(Mutex::new(done_rx));
+        std::thread::spawn(move || {
+            for (level, line) in rx {
+                writer.write(level, &line);
+            }
+            let _ = done_tx.send(());
+        });
+        unsafe { libc::atexit(drain) };
+        Ok(())
+    }
+
+    /// 把 fd 换成一个管道的写端，返回读端（读端不会被子进程继承）
+    fn redirect(fd: RawFd) -> Result<File> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let [read, write] = fds;
+        unsafe {
+            libc::fcntl(read, libc::F_SETFD, libc::FD_CLOEXEC);
+            if libc::dup2(write, fd) < 0 {
+                let e = io::Error::last_os_error();
+                libc::close(read);
+                libc::close(write);
+                return Err(e.into());
+            }
+            libc::close(write);
+            Ok(File::from_raw_fd(read))
+        }
+    }
+
+    /// 进程退出时：关闭 stdout / stderr 的写端，等写入线程把剩下的行写完
+    extern "C" fn drain() {
+        let _ = io::stdout().flush();
+        unsafe {
+            let null = libc::open(c"/dev/null".as_ptr(), libc::O_WRONLY);
+            if null >= 0 {
+                libc::dup2(null, libc::STDOUT_FILENO);
+                libc::dup2(null, libc::STDERR_FILENO);
+                libc::close(null);
+            }
+        }
+        // 仍在运行的子进程持有写端时读不到 EOF，最多等 DRAIN_TIMEOUT
+        if let Some(done) = DRAINED.get()
+            && let Ok(done) = done.lock()
+        {
+            let _ = done.recv_timeout(DRAIN_TIMEOUT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(LogOptions::from_args(&[]).unwrap(), LogOptions::default());
+        let options = LogOptions::from_args(&strings(&["--log", "file:/var/log/vpn.log", "--log-max-size", "10m", "--log-rotate", "daily", "--log-keep", "3"])).unwrap();
+        assert_eq!(options.sink, Sink::File(PathBuf::from("/var/log/vpn.log")));
+        assert_eq!(options.rotation, Rotation { max_size: Some(10 << 20), every: Some(RotateEvery::Daily), keep: 3 });
+        assert_eq!(Sink::parse("oslog").unwrap(), Sink::Syslog);
+
+        assert!(Sink::parse("file:").is_err());
+        assert!(Sink::parse("kafka").is_err());
+        assert!(LogOptions::from_args(&strings(&["--log", "journald", "--log-rotate", "daily"])).is_err());
+        assert!(LogOptions::from_args(&strings(&["--log", "file:x", "--log-keep", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(1_792_096_220), "2026-10-15T20:30:20Z");
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-log-{}-{}", std::process::id(), rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vpn.log");
+
+        // 按大小：每行 "<时间戳> line-N\n" 共 28 字节，每个文件放两行
+        let rotation = Rotation { max_size: Some(60), every: None, keep: 2 };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        for i in 0..7 {
+            file.write_line(&format!("line-{}", i), 0).unwrap();
+        }
+        let read = |p: PathBuf| fs::read_to_string(p).unwrap().lines().map(|l| l.split(' ').nth(1).unwrap().to_string()).collect::<Vec<_>>();
+        assert_eq!(read(path.clone()), ["line-6"]);
+        assert_eq!(read(numbered(&path, 1)), ["line-4", "line-5"]);
+        assert_eq!(read(numbered(&path, 2)), ["line-2", "line-3"]);
+        assert!(!numbered(&path, 3).exists());
+
+        // 按时间：跨过 UTC 零点时轮转
+        let path = dir.join("daily.log");
+        let rotation = Rotation { max_size: None, every: Some(RotateEvery::Daily), keep: 1 };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        file.period = 0;
+        file.write_line("day-0", 86399).unwrap();
+        file.write_line("day-1", 86400).unwrap();
+        assert_eq!(read(path.clone()), ["day-1"]);
+        assert_eq!(read(numbered(&path, 1)), ["day-0"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
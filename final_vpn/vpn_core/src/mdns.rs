@@ -0,0 +1,432 @@
+// vpn_core/src/mdns.rs
+// 局域网服务发现：mDNS（RFC 6762）+ DNS-SD（RFC 6763），服务类型 `_rustvpn._udp.local`
+//
+// * 服务端：在 224.0.0.251:5353 上响应对服务类型（PTR）和实例名（SRV/TXT）的查询，
+//   启动时主动广播两次
+// * 客户端：从临时端口发送一次查询（legacy unicast，响应直接发回该端口），收集一段时间内的响应
+//
+// 只实现所需的 PTR / SRV / TXT / A 记录，不做冲突检测和缓存。
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+
+/// 服务类型
+pub const SERVICE_TYPE: &str = "_rustvpn._udp.local";
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// 唯一记录（SRV/TXT/A）的 cache-flush 位
+const CACHE_FLUSH: u16 = 0x8000;
+/// 记录的 TTL（秒）
+const RECORD_TTL: u32 = 120;
+
+/// 要广播的服务
+#[derive(Debug, Clone)]
+pub struct ServiceInfo {
+    /// 实例名（例如主机名），完整名称为 `<instance>._rustvpn._udp.local`
+    pub instance: String,
+    pub port: u16,
+    /// TXT 记录中的 key=value 项
+    pub txt: Vec<String>,
+}
+
+impl ServiceInfo {
+    fn full_name(&self) -> String {
+        format!("{}.{}", self.instance, SERVICE_TYPE)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.instance.replace(' ', "-"))
+    }
+}
+
+/// 发现到的服务端
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveredServer {
+    pub instance: String,
+    pub addr: SocketAddr,
+    pub txt: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RecordData {
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+/// 解析后的 DNS 报文（只保留需要的部分）
+#[derive(Debug, Default)]
+struct Message {
+    id: u16,
+    is_response: bool,
+    /// (名称, 类型)
+    questions: Vec<(String, u16)>,
+    /// answer + authority + additional
+    records: Vec<Record>,
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn put_record(out: &mut Vec<u8>, name: &str, rtype: u16, class: u16, rdata: &[u8]) {
+    put_name(out, name);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+fn put_header(out: &mut Vec<u8>, id: u16, flags: u16, counts: [u16; 4]) {
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        out.extend_from_slice(&count.to_be_bytes());
+    }
+}
+
+/// 查询服务类型的 PTR 记录
+fn encode_query() -> Vec<u8> {
+    let mut out = Vec::new();
+    put_header(&mut out, 0, 0, [1, 0, 0, 0]);
+    put_name(&mut out, SERVICE_TYPE);
+    out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out
+}
+
+/// 生成响应：PTR 作为 answer，SRV/TXT/A 放在 additional 中，客户端一次即可拿到地址
+///
+/// `question` 为 Some 时是 legacy unicast 响应，需要带回查询 ID 和问题
+fn encode_response(info: &ServiceInfo, host_ip: Ipv4Addr, question: Option<u16>) -> Vec<u8> {
+    let full_name = info.full_name();
+    let host_name = info.host_name();
+    let mut out = Vec::new();
+    put_header(&mut out, question.unwrap_or(0), 0x8400, [question.map_or(0, |_| 1), 1, 0, 3]);
+    if question.is_some() {
+        put_name(&mut out, SERVICE_TYPE);
+        out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+
+    let mut ptr = Vec::new();
+    put_name(&mut ptr, &full_name);
+    put_record(&mut out, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &ptr);
+
+    let mut srv = vec![0, 0, 0, 0];
+    srv.extend_from_slice(&info.port.to_be_bytes());
+    put_name(&mut srv, &host_name);
+    put_record(&mut out, &full_name, TYPE_SRV, CLASS_IN | CACHE_FLUSH, &srv);
+
+    let mut txt = Vec::new();
+    for item in &info.txt {
+        let item = &item.as_bytes()[..item.len().min(255)];
+        txt.push(item.len() as u8);
+        txt.extend_from_slice(item);
+    }
+    if txt.is_empty() {
+        txt.push(0);
+    }
+    put_record(&mut out, &full_name, TYPE_TXT, CLASS_IN | CACHE_FLUSH, &txt);
+    put_record(&mut out, &host_name, TYPE_A, CLASS_IN | CACHE_FLUSH, &host_ip.octets());
+    out
+}
+
+/// 读取一个（可能带压缩指针的）名称，返回名称和紧随其后的偏移
+fn read_name(packet: &[u8], mut offset: usize) -> Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..64 {
+        let len = *packet.get(offset).ok_or_else(|| anyhow!("名称越界"))? as usize;
+        match len {
+            0 => {
+                return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            l if l & 0xc0 == 0xc0 => {
+                let low = *packet.get(offset + 1).ok_or_else(|| anyhow!("压缩指针越界"))? as usize;
+                end.get_or_insert(offset + 2);
+                offset = ((l & 0x3f) << 8) | low;
+            }
+            l => {
+                let label = packet.get(offset + 1..offset + 1 + l).ok_or_else(|| anyhow!("标签越界"))?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                offset += 1 + l;
+            }
+        }
+    }
+    Err(anyhow!("名称压缩指针过多"))
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Result<u16> {
+    packet.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(|| anyhow!("报文被截断"))
+}
+
+fn decode_message(packet: &[u8]) -> Result<Message> {
+    if packet.len() < 12 {
+        return Err(anyhow!("报文太短"));
+    }
+    let mut msg = Message {
+        id: read_u16(packet, 0)?,
+        is_response: packet[2] & 0x80 != 0,
+        ..Default::default()
+    };
+    let qdcount = read_u16(packet, 4)?;
+    let rrcount = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        let (name, next) = read_name(packet, offset)?;
+        msg.questions.push((name, read_u16(packet, next)?));
+        offset = next + 4;
+    }
+    for _ in 0..rrcount {
+        let (name, next) = read_name(packet, offset)?;
+        let rtype = read_u16(packet, next)?;
+        let rdlen = read_u16(packet, next + 8)? as usize;
+        let start = next + 10;
+        let rdata = packet.get(start..start + rdlen).ok_or_else(|| anyhow!("记录被截断"))?;
+        let data = match rtype {
+            TYPE_PTR => RecordData::Ptr(read_name(packet, start)?.0),
+            TYPE_SRV if rdlen >= 7 => RecordData::Srv {
+                port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                target: read_name(packet, start + 6)?.0,
+            },
+            TYPE_TXT => {
+                let mut items = Vec::new();
+                let mut i = 0;
+                while i < rdata.len() {
+                    let len = rdata[i] as usize;
+                    if let Some(item) = rdata.get(i + 1..i + 1 + len).filter(|s| !s.is_empty()) {
+                        items.push(String::from_utf8_lossy(item).to_string());
+                    }
+                    i += 1 + len;
+                }
+                RecordData::Txt(items)
+            }
+            TYPE_A if rdlen == 4 => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            _ => RecordData::Other,
+        };
+        msg.records.push(Record { name, data });
+        offset = start + rdlen;
+    }
+    Ok(msg)
+}
+
+/// 从一条响应中提取服务端（没有 A 记录时使用响应的来源地址）
+fn servers_from_response(msg: &Message, source: Ipv4Addr) -> Vec<DiscoveredServer> {
+    let suffix = format!(".{}", SERVICE_TYPE);
+    msg.records
+        .iter()
+        .filter_map(|r| match &r.data {
+            RecordData::Ptr(instance) if r.name.eq_ignore_ascii_case(SERVICE_TYPE) => Some(instance),
+            _ => None,
+        })
+        .filter_map(|full_name| {
+            let (port, target) = msg.records.iter().find_map(|r| match &r.data {
+                RecordData::Srv { port, target } if r.name.eq_ignore_ascii_case(full_name) => Some((*port, target)),
+                _ => None,
+            })?;
+            let ip = msg.records.iter().find_map(|r| match r.data {
+                RecordData::A(ip) if r.name.eq_ignore_ascii_case(target) => Some(ip),
+                _ => None,
+            }).unwrap_or(source);
+            let txt = msg.records.iter().find_map(|r| match &r.data {
+                RecordData::Txt(items) if r.name.eq_ignore_ascii_case(full_name) => Some(items.clone()),
+                _ => None,
+            }).unwrap_or_default();
+            let instance = full_name.strip_suffix(&suffix).unwrap_or(full_name).to_string();
+            Some(DiscoveredServer { instance, addr: SocketAddr::from((ip, port)), txt })
+        })
+        .collect()
+}
+
+/// 本机在局域网上的 IPv4 地址（UDP connect 只做路由选择，不发送数据）
+fn local_ipv4() -> Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        _ => Err(anyhow!("无法确定局域网地址")),
+    }
+}
+
+/// 绑定 5353 端口并加入组播组（允许与系统的 mDNS 守护进程共用端口）
+#[cfg(unix)]
+fn bind_multicast() -> Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: 只传入常量参数
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: fd 是刚创建的有效套接字，交给 UdpSocket 负责关闭
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+
+    // 端口复用必须在 bind 之前设置，std 没有提供对应接口
+    let one: libc::c_int = 1;
+    for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // SAFETY: fd 有效，one 的生命周期覆盖调用
+        unsafe {
+            libc::setsockopt(fd, libc::SOL_SOCKET, opt, &one as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t);
+        }
+    }
+
+    let sockaddr = libc::sockaddr_in {
+        #[cfg(target_os = "macos")]
+        sin_len: std::mem::size_of::<libc::sockaddr_in>() as u8,
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: sockaddr 是完整初始化的 sockaddr_in，长度与之匹配
+    let ret = unsafe {
+        libc::bind(fd, &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+#[cfg(not(unix))]
+fn bind_multicast() -> Result<UdpSocket> {
+    Err(anyhow!("当前系统不支持 mDNS 广播"))
+}
+
+/// 启动后台任务广播服务，直到进程退出
+pub fn advertise(info: ServiceInfo) -> Result<()> {
+    let socket = bind_multicast()?;
+    let host_ip = local_ipv4()?;
+    let full_name = info.full_name();
+    let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+    println!("📢 mDNS 广播: {} -> {}:{}", full_name, host_ip, info.port);
+
+    tokio::spawn(async move {
+        // 启动时主动通告两次（RFC 6762 第 8.3 节）
+        let announcement = encode_response(&info, host_ip, None);
+        for _ in 0..2 {
+            let _ = socket.send_to(&announcement, group).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        let mut buf = [0u8; 1500];
+        loop {
+            let Ok((n, source)) = socket.recv_from(&mut buf).await else { continue };
+            let Ok(msg) = decode_message(&buf[..n]) else { continue };
+            if msg.is_response {
+                continue;
+            }
+            let asked = msg.questions.iter().any(|(name, qtype)| {
+                (name.eq_ignore_ascii_case(SERVICE_TYPE) && matches!(*qtype, TYPE_PTR | TYPE_ANY))
+                    || name.eq_ignore_ascii_case(&full_name)
+            });
+            if !asked {
+                continue;
+            }
+            // 来源端口不是 5353 的是 legacy unicast 查询，直接回复给查询方
+            let (response, target) = if source.port() != MDNS_PORT {
+                (encode_response(&info, host_ip, Some(msg.id)), source)
+            } else {
+                (encode_response(&info, host_ip, None), group)
+            };
+            let _ = socket.send_to(&response, target).await;
+        }
+    });
+    Ok(())
+}
+
+/// 在局域网上查找服务端，收集 wait 时间内的所有响应
+pub async fn discover(wait: Duration) -> Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.send_to(&encode_query(), (MDNS_ADDR, MDNS_PORT)).await?;
+
+    let mut found: HashMap<String, DiscoveredServer> = HashMap::new();
+    let deadline = tokio::time::Instant::now() + wait;
+    let mut buf = [0u8; 1500];
+    while let Ok(Ok((n, source))) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (Ok(msg), std::net::IpAddr::V4(source)) = (decode_message(&buf[..n]), source.ip()) else { continue };
+        if !msg.is_response {
+            continue;
+        }
+        for server in servers_from_response(&msg, source) {
+            found.entry(server.instance.clone()).or_insert(server);
+        }
+    }
+
+    let mut servers: Vec<DiscoveredServer> = found.into_values().collect();
+    servers.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_roundtrip() {
+        let info = ServiceInfo { instance: "lab-server".to_string(), port: 9000, txt: vec!["v=1".to_string(), "pk=00112233".to_string()] };
+        let ip = Ipv4Addr::new(192, 168, 1, 20);
+
+        let query = decode_message(&encode_query()).unwrap();
+        assert!(!query.is_response);
+        assert_eq!(query.questions, vec![(SERVICE_TYPE.to_string(), TYPE_PTR)]);
+
+        let response = decode_message(&encode_response(&info, ip, Some(7))).unwrap();
+        assert!(response.is_response);
+        assert_eq!(response.id, 7);
+        let servers = servers_from_response(&response, Ipv4Addr::new(192, 168, 1, 99));
+        assert_eq!(servers, vec![DiscoveredServer {
+            instance: "lab-server".to_string(),
+            addr: "192.168.1.20:9000".parse().unwrap(),
+            txt: info.txt.clone(),
+        }]);
+    }
+
+    #[test]
+    fn test_read_compressed_name() {
+        // 偏移 12: "local"，偏移 19: "_rustvpn" + 指向 12 的指针
+        let mut packet = vec![0u8; 12];
+        put_name(&mut packet, "local");
+        packet.extend_from_slice(&[8]);
+        packet.extend_from_slice(b"_rustvpn");
+        packet.extend_from_slice(&[0xc0, 12]);
+        let (name, next) = read_name(&packet, 19).unwrap();
+        assert_eq!(name, "_rustvpn.local");
+        assert_eq!(next, packet.len());
+        // 指针自环
+        assert!(read_name(&[0xc0, 0], 0).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// vpn_core/src/multipath.rs
+// 多路径绑定（实验性）：客户端同时经两条上行链路（如 LTE + Wi-Fi）发送隧道数据
+//
+// 客户端在会话建立后从第二个 socket（绑定到另一块网卡）发送 PathJoin，证明持有会话密钥；
+// 服务端把这个来源地址登记为原会话的别名并回复 PathAck，此后两条路径上的包都按原会话处理。
+// 下行和控制消息仍只走主路径。
+//
+// * duplicate：每个包加密一次，同一份密文从两条路径各发一次；服务端按序号丢弃重复的一份（见 replay），
+//   任一路径丢包都不影响，代价是上行流量翻倍
+// * round-robin：两条路径轮流发送，带宽叠加；明文前加上序号（KIND_BONDED），
+//   服务端用 ReorderBuffer 按序号放行，缺失的包最多等待 REORDER_HOLD
+//
+// 路径 ID 由会话密钥派生，不额外下发；证明是用会话密钥加密的时间戳，新鲜度要求与会话恢复相同。
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+use crate::resume::{MAX_CLOCK_SKEW, server_now, unix_now};
+use crate::symmetric::Cipher;
+
+/// round-robin 模式下带序号的明文的首字节（0x4_/0x6_ 为 IP 包，0x00/0x01 见 control 模块）
+pub const KIND_BONDED: u8 = 0x02;
+
+/// 缺失的包最多等待多久，之后跳过空洞继续放行
+pub const REORDER_HOLD: Duration = Duration::from_millis(30);
+
+/// 每个会话最多暂存的包数，超过时立即跳过最早的空洞
+pub const REORDER_CAPACITY: usize = 128;
+
+const PATH_ID_CONTEXT: &str = "rust-vpn 2024 multipath id";
+const JOIN_DOMAIN: &[u8] = b"rust-vpn path join v1";
+const JOIN_ACK_DOMAIN: &[u8] = b"rust-vpn path ack v1";
+
+/// 路径 ID：服务端据此找到会话
+pub type PathId = [u8; 16];
+
+/// 多条路径的使用方式（--bond-mode）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BondMode {
+    Duplicate,
+    RoundRobin,
+}
+
+impl BondMode {
+    pub fn parse(value: Option<&str>) -> Result<Self> {
+        match value {
+            None | Some("duplicate") => Ok(BondMode::Duplicate),
+            Some("round-robin") => Ok(BondMode::RoundRobin),
+            Some(other) => Err(anyhow!("无效的 --bond-mode: {}（可选 duplicate / round-robin）", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BondMode::Duplicate => "duplicate",
+            BondMode::RoundRobin => "round-robin",
+        }
+    }
+}
+
+/// 由会话密钥派生的路径 ID
+pub fn path_id(session_key: &[u8; 32]) -> PathId {
+    let key = blake3::derive_key(PATH_ID_CONTEXT, session_key);
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&key[..16]);
+    id
+}
+
+/// 客户端生成 PathJoin 中的证明：用会话密钥加密 域分隔符 || 路径 ID || 时间戳
+pub fn join_proof(session_key: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut plaintext = [JOIN_DOMAIN, &path_id(session_key)].concat();
+    plaintext.extend(server_now().to_be_bytes());
+    Cipher::new(session_key)?.encrypt(&plaintext)
+}
+
+/// 服务端校验 PathJoin 的证明（密钥、格式和时间戳）
+pub fn verify_join_proof(session_key: &[u8; 32], proof: &[u8]) -> Result<()> {
+    let plaintext = Cipher::new(session_key)?.decrypt(proof)?;
+    let timestamp = plaintext
+        .strip_prefix(JOIN_DOMAIN)
+        .and_then(|rest| rest.strip_prefix(&path_id(session_key)[..]))
+        .and_then(|rest| <[u8; 8]>::try_from(rest).ok())
+        .map(u64::from_be_bytes)
+        .ok_or_else(|| anyhow!("路径加入证明格式错误"))?;
+    if unix_now().abs_diff(timestamp) > MAX_CLOCK_SKEW {
+        return Err(anyhow!("路径加入证明已过期"));
+    }
+    Ok(())
+}
+
+/// 服务端生成 PathAck 中的确认值
+pub fn join_ack(session_key: &[u8; 32]) -> Result<Vec<u8>> {
+    Cipher::new(session_key)?.encrypt(&[JOIN_ACK_DOMAIN, &path_id(session_key)].concat())
+}
+
+/// 客户端校验 PathAck
+pub fn verify_join_ack(session_key: &[u8; 32], proof: &[u8]) -> Result<()> {
+    let plaintext = Cipher::new(session_key)?.decrypt(proof)?;
+    if plaintext != [JOIN_ACK_DOMAIN, &path_id(session_key)].concat() {
+        return Err(anyhow!("路径确认值不匹配"));
+    }
+    Ok(())
+}
+
+/// round-robin：给 IP 包加上序号
+pub fn wrap(seq: u64, ip_packet: &[u8]) -> Vec<u8> {
+    let mut plaintext = Vec::with_capacity(9 + ip_packet.len());
+    plaintext.push(KIND_BONDED);
+    plaintext.extend(seq.to_be_bytes());
+    plaintext.extend(ip_packet);
+    plaintext
+}
+
+/// 拆出序号和 IP 包
+pub fn unwrap(plaintext: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = plaintext.strip_prefix(&[KIND_BONDED])?;
+    let (seq, ip_packet) = rest.split_first_chunk::<8>()?;
+    Some((u64::from_be_bytes(*seq), ip_packet))
+}
+
+/// 按序号放行的重排缓冲区（每个会话一个）
+#[derive(Debug, Default)]
+pub struct ReorderBuffer {
+    /// 下一个应放行的序号（收到第一个包时确定）
+    next: Option<u64>,
+    held: BTreeMap<u64, (Vec<u8>, Instant)>,
+}
+
+impl ReorderBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 收到一个包，返回现在可以按序放行的包
+    pub fn push(&mut self, seq: u64, packet: Vec<u8>, now: Instant) -> Vec<Vec<u8>> {
+        let next = *self.next.get_or_insert(seq);
+        // 已经跳过的序号（等待超时后才到达）直接放行，交给上层协议处理乱序
+        if seq < next {
+            return vec![packet];
+        }
+        self.held.insert(seq, (packet, now));
+        if self.held.len() > REORDER_CAPACITY {
+            self.skip_gap();
+        }
+        self.drain()
+    }
+
+    /// 等待超过 REORDER_HOLD 的空洞被跳过，返回因此放行的包
+    pub fn expire(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut released = Vec::new();
+        while self.held.values().any(|(_, at)| now.duration_since(*at) >= REORDER_HOLD) {
+            self.skip_gap();
+            released.extend(self.drain());
+        }
+        released
+    }
+
+    /// 有暂存的包时，最晚应在什么时候调用 expire
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held.values().map(|(_, at)| *at + REORDER_HOLD).min()
+    }
+
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    /// 放弃等待缺失的包：从暂存的最小序号继续
+    fn skip_gap(&mut self) {
+        if let Some(&first) = self.held.keys().next() {
+            self.next = Some(first);
+        }
+    }
+
+    fn drain(&mut self) -> Vec<Vec<u8>> {
+        let mut released = Vec::new();
+        while let Some(next) = self.next
+            && let Some((packet, _)) = self.held.remove(&next)
+        {
+            released.push(packet);
+            self.next = Some(next.wrapping_add(1));
+        }
+        released
+    }
+}
+
+/// 把 UDP socket 绑定到指定网卡（SO_BINDTODEVICE），第二条路径经这块网卡发出
+#[cfg(target_os = "linux")]
+pub fn bind_to_device<S: std::os::fd::AsRawFd>(socket: &S, interface: &str) -> Result<()> {
+    let name = std::ffi::CString::new(interface)?;
+    // SAFETY: fd 有效，name 是以 NUL 结尾的字符串，长度参数包含结尾的 NUL
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.as_bytes_with_nul().len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!("无法绑定到网卡 {}: {}", interface, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_proof() {
+        let key = [7u8; 32];
+        assert_ne!(path_id(&key), path_id(&[8u8; 32]));
+        let proof = join_proof(&key).unwrap();
+        assert!(verify_join_proof(&key, &proof).is_ok());
+        assert!(verify_join_proof(&[8u8; 32], &proof).is_err());
+        let ack = join_ack(&key).unwrap();
+        assert!(verify_join_ack(&key, &ack).is_ok());
+        assert!(verify_join_ack(&key, &proof).is_err());
+        assert!(verify_join_proof(&key, &ack).is_err());
+
+        assert_eq!(unwrap(&wrap(42, &[0x45, 1, 2])), Some((42, &[0x45, 1, 2][..])));
+        assert_eq!(unwrap(&[0x45, 1, 2]), None);
+        assert_eq!(BondMode::parse(Some("round-robin")).unwrap(), BondMode::RoundRobin);
+        assert!(BondMode::parse(Some("striped")).is_err());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let now = Instant::now();
+        let p = |n: u8| vec![n];
+        let mut buffer = ReorderBuffer::new();
+
+        // 按序到达直接放行；乱序时等缺失的包到达后一起放行
+        assert_eq!(buffer.push(10, p(10), now), vec![p(10)]);
+        assert!(buffer.push(12, p(12), now).is_empty());
+        assert!(buffer.push(13, p(13), now).is_empty());
+        assert_eq!(buffer.deadline(), Some(now + REORDER_HOLD));
+        assert_eq!(buffer.push(11, p(11), now), vec![p(11), p(12), p(13)]);
+        assert_eq!(buffer.held(), 0);
+
+        // 缺失的包超时后跳过空洞；之后才到达的包直接放行
+        assert!(buffer.push(16, p(16), now).is_empty());
+        assert!(buffer.expire(now + Duration::from_millis(10)).is_empty());
+        assert_eq!(buffer.expire(now + REORDER_HOLD), vec![p(16)]);
+        assert_eq!(buffer.push(14, p(14), now), vec![p(14)]);
+        assert_eq!(buffer.push(17, p(17), now), vec![p(17)]);
+
+        // 暂存超过上限时立即跳过
+        let mut buffer = ReorderBuffer::new();
+        buffer.push(0, p(0), now);
+        for seq in 2..(REORDER_CAPACITY as u64 + 2) {
+            assert!(buffer.push(seq, p(seq as u8), now).is_empty());
+        }
+        assert_eq!(buffer.push(REORDER_CAPACITY as u64 + 2, p(0), now).len(), REORDER_CAPACITY + 1);
+    }
+}
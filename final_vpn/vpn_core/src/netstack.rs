@@ -0,0 +1,507 @@
+// vpn_core/src/netstack.rs
+// 进程内的虚拟网络：不创建 TUN 设备，直接通过隧道打开 TCP/UDP socket（需要 netstack feature）
+//
+//   应用 -> TcpStream / UdpSocket -> smoltcp 协议栈 -> IP 包 -> NetstackDevice -> TunnelEngine -> 加密 -> UDP
+//
+// Netstack::new 返回的 TunDevice 代替真实的 TUN 交给 TunnelEngine::run，引擎读到的是协议栈发出的 IP 包，
+// 写入的包交给协议栈处理。整个过程不需要 root、不修改路由表，隧道只对这个进程里用
+// Netstack 打开的连接生效，适合爬虫、机器人等只需要 VPN 出口的程序以库的形式嵌入。
+//
+// 协议栈由一个后台任务驱动：收到 IP 包、应用读写或定时器到期时调用一次 poll，
+// socket 的读写通过 smoltcp 的 waker 唤醒等待中的任务。
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpCidr, IpEndpoint};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::local_tun::{self, TunDevice, TunFrameCodec};
+
+/// 每个 TCP 连接的收发缓冲区
+const TCP_BUFFER_SIZE: usize = 256 * 1024;
+/// 每个 UDP socket 最多缓存的数据报数和总字节数
+const UDP_PACKETS: usize = 64;
+const UDP_BUFFER_SIZE: usize = 256 * 1024;
+/// 两个方向上等待处理的 IP 包上限，超出时丢弃（与内核 TUN 队列满时的行为一致）
+const DEVICE_QUEUE_DEPTH: usize = 1024;
+/// 协议栈没有定时器时，最长隔多久检查一次
+const IDLE_POLL: Duration = Duration::from_secs(1);
+/// 本地临时端口范围
+const EPHEMERAL_PORTS: std::ops::Range<u16> = 49152..65535;
+
+/// 进程内的虚拟网络（可以 clone，所有副本共享同一个协议栈）
+#[derive(Clone)]
+pub struct Netstack {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    stack: Mutex<Stack>,
+    /// 通知后台任务重新 poll
+    poll: Notify,
+    next_port: AtomicU16,
+    codec: TunFrameCodec,
+}
+
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    device: QueueDevice,
+    /// 已经关闭、等待挥手完成后回收的 TCP 连接
+    closing: Vec<SocketHandle>,
+    /// 等待协议栈发出 IP 包的引擎读端
+    reader: Option<Waker>,
+}
+
+impl Netstack {
+    /// 创建虚拟网络，使用握手分配到的虚拟地址；返回的设备交给 TunnelEngine::run 代替 TUN
+    ///
+    /// 同时配置对应的隧道 IPv6 地址（fd00::/96），两个协议族的默认路由都指向隧道。
+    /// 需要在 tokio 运行时中调用：驱动协议栈的后台任务在所有 Netstack 和 socket 释放后退出
+    pub fn new(address: Ipv4Addr, prefix_len: u8, mtu: usize) -> (Self, TunDevice) {
+        let mut device = QueueDevice { inbound: VecDeque::new(), outbound: VecDeque::new(), mtu };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::random();
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+
+        let ipv6 = local_tun::tunnel_ipv6(address);
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(address.into(), prefix_len));
+            let _ = addrs.push(IpCidr::new(ipv6.into(), local_tun::TUNNEL_IPV6_PREFIX_LEN));
+        });
+        // 点对点链路上没有邻居解析，网关只用于选路，取网段内的第一个地址（服务端的惯例）
+        let gateway = gateway(address, prefix_len);
+        let _ = iface.routes_mut().add_default_ipv4_route(gateway);
+        let _ = iface.routes_mut().add_default_ipv6_route(local_tun::tunnel_ipv6(gateway));
+
+        let stack = Stack { iface, sockets: SocketSet::new(Vec::new()), device, closing: Vec::new(), reader: None };
+        let span = EPHEMERAL_PORTS.end - EPHEMERAL_PORTS.start;
+        let shared = Arc::new(Shared {
+            stack: Mutex::new(stack),
+            poll: Notify::new(),
+            next_port: AtomicU16::new(rand::random::<u16>() % span),
+            codec: TunFrameCodec::platform(),
+        });
+        tokio::spawn(drive(Arc::downgrade(&shared)));
+
+        let device = NetstackDevice { shared: shared.clone() };
+        (Self { shared }, Box::new(device))
+    }
+
+    /// 通过隧道建立 TCP 连接（连接超时由调用方用 tokio::time::timeout 控制）
+    pub async fn tcp_connect(&self, remote: SocketAddr) -> io::Result<TcpStream> {
+        let handle = {
+            let mut stack = self.shared.lock();
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            );
+            let local_port = self.shared.ephemeral_port();
+            let Stack { iface, sockets, .. } = &mut *stack;
+            socket
+                .connect(iface.context(), remote, local_port)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("无法连接 {}: {}", remote, e)))?;
+            sockets.add(socket)
+        };
+        self.shared.poll.notify_one();
+        let stream = TcpStream { shared: self.shared.clone(), handle };
+
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+            match socket.state() {
+                tcp::State::Established | tcp::State::CloseWait => Poll::Ready(Ok(())),
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("{} 拒绝连接", remote)))),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// 绑定 UDP socket；port 为 0 时分配临时端口
+    pub fn udp_bind(&self, port: u16) -> io::Result<UdpSocket> {
+        let port = if port == 0 { self.shared.ephemeral_port() } else { port };
+        let mut socket = udp::Socket::new(
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER_SIZE]),
+            udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER_SIZE]),
+        );
+        socket
+            .bind(port)
+            .map_err(|e| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("无法绑定端口 {}: {}", port, e)))?;
+        let handle = self.shared.lock().sockets.add(socket);
+        Ok(UdpSocket { shared: self.shared.clone(), handle, port })
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Stack> {
+        self.stack.lock().unwrap()
+    }
+
+    fn ephemeral_port(&self) -> u16 {
+        let span = EPHEMERAL_PORTS.end - EPHEMERAL_PORTS.start;
+        EPHEMERAL_PORTS.start + self.next_port.fetch_add(1, Ordering::Relaxed) % span
+    }
+}
+
+/// 网段内的第一个地址
+fn gateway(address: Ipv4Addr, prefix_len: u8) -> Ipv4Addr {
+    let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len.min(32))).unwrap_or(0);
+    Ipv4Addr::from((u32::from(address) & mask) | 1)
+}
+
+/// 后台任务：有事件或定时器到期时 poll 协议栈，回收挥手完成的连接
+async fn drive(shared: Weak<Shared>) {
+    loop {
+        let Some(shared) = shared.upgrade() else { break };
+        let delay = {
+            let mut stack = shared.lock();
+            let Stack { iface, sockets, device, closing, reader } = &mut *stack;
+            let now = Instant::now();
+            iface.poll(now, device, sockets);
+            closing.retain(|&handle| {
+                let done = matches!(sockets.get::<tcp::Socket>(handle).state(), tcp::State::Closed | tcp::State::TimeWait);
+                if done {
+                    sockets.remove(handle);
+                }
+                !done
+            });
+            if !device.outbound.is_empty()
+                && let Some(waker) = reader.take()
+            {
+                waker.wake();
+            }
+            iface.poll_delay(now, sockets).map_or(IDLE_POLL, |d| Duration::from_micros(d.total_micros()))
+        };
+        // 每轮重新取强引用：所有 Netstack、socket 和设备释放后，最迟下一次定时器到期时退出
+        tokio::select! {
+            _ = shared.poll.notified() => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+}
+
+/// 交给 TunnelEngine 的虚拟 TUN：读出协议栈发出的 IP 包，写入的 IP 包交给协议栈
+struct NetstackDevice {
+    shared: Arc<Shared>,
+}
+
+impl AsyncRead for NetstackDevice {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut stack = self.shared.lock();
+        let Some(ip_packet) = stack.device.outbound.pop_front() else {
+            stack.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+        let frame = self.shared.codec.encode(ip_packet);
+        // 与 TUN 一样，缓冲区放不下的部分被截断，由引擎的截断检测计数
+        let n = frame.len().min(buf.remaining());
+        buf.put_slice(&frame[..n]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for NetstackDevice {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, frame: &[u8]) -> Poll<io::Result<usize>> {
+        if let Some(ip_packet) = self.shared.codec.decode(frame) {
+            let mut stack = self.shared.lock();
+            if stack.device.inbound.len() < DEVICE_QUEUE_DEPTH {
+                stack.device.inbound.push_back(ip_packet.to_vec());
+            }
+        }
+        self.shared.poll.notify_one();
+        Poll::Ready(Ok(frame.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 隧道中的 TCP 连接
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// 对端地址
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.shared.lock().sockets.get::<tcp::Socket>(self.handle).remote_endpoint().map(socket_addr)
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if socket.can_recv() {
+            let n = socket.recv_slice(buf.initialize_unfilled()).map_err(|e| io::Error::other(e.to_string()))?;
+            buf.advance(n);
+            drop(stack);
+            // 接收窗口变大，需要通告给对端
+            self.shared.poll.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        if !socket.may_recv() {
+            // 对端已关闭（或连接被重置），返回 EOF
+            return Poll::Ready(Ok(()));
+        }
+        socket.register_recv_waker(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut stack = self.shared.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        if !socket.may_send() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !socket.can_send() {
+            socket.register_send_waker(cx.waker());
+            return Poll::Pending;
+        }
+        let n = socket.send_slice(data).map_err(|e| io::Error::other(e.to_string()))?;
+        drop(stack);
+        self.shared.poll.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().sockets.get_mut::<tcp::Socket>(self.handle).close();
+        self.shared.poll.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // 发完缓冲区里的数据并完成挥手后由后台任务回收
+        let mut stack = self.shared.lock();
+        stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        stack.closing.push(self.handle);
+        drop(stack);
+        self.shared.poll.notify_one();
+    }
+}
+
+/// 隧道中的 UDP socket
+pub struct UdpSocket {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// 绑定的本地端口
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// 发送一个数据报；发送缓冲区满时等待
+    pub async fn send_to(&self, data: &[u8], target: SocketAddr) -> io::Result<usize> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            match socket.send_slice(data, IpEndpoint::from(target)) {
+                Ok(()) => Poll::Ready(Ok(data.len())),
+                Err(udp::SendError::BufferFull) => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidInput, format!("无法发往 {}: {}", target, e)))),
+            }
+        })
+        .await
+        .inspect(|_| self.shared.poll.notify_one())
+    }
+
+    /// 接收一个数据报，返回长度和来源；buf 放不下时丢弃该数据报并返回错误
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        poll_fn(|cx| {
+            let mut stack = self.shared.lock();
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            match socket.recv_slice(buf) {
+                Ok((n, meta)) => Poll::Ready(Ok((n, socket_addr(meta.endpoint)))),
+                Err(udp::RecvError::Exhausted) => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))),
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.shared.lock().sockets.remove(self.handle);
+    }
+}
+
+fn socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(IpAddr::from(endpoint.addr), endpoint.port)
+}
+
+/// smoltcp 的设备：两个方向的 IP 包队列
+struct QueueDevice {
+    /// 从隧道收到、等待协议栈处理的包
+    inbound: VecDeque<Vec<u8>>,
+    /// 协议栈发出、等待引擎读取的包
+    outbound: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.inbound.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.outbound)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        (self.outbound.len() < DEVICE_QUEUE_DEPTH).then_some(TxToken(&mut self.outbound))
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// 把两个虚拟设备背靠背连起来（代替两端的隧道）
+    fn link(a: TunDevice, b: TunDevice) {
+        let (mut a_read, mut a_write) = tokio::io::split(a);
+        let (mut b_read, mut b_write) = tokio::io::split(b);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok(n @ 1..) = a_read.read(&mut buf).await {
+                let _ = b_write.write_all(&buf[..n]).await;
+            }
+        });
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok(n @ 1..) = b_read.read(&mut buf).await {
+                let _ = a_write.write_all(&buf[..n]).await;
+            }
+        });
+    }
+
+    #[test]
+    fn test_gateway() {
+        assert_eq!(gateway(Ipv4Addr::new(10, 0, 0, 2), 24), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(gateway(Ipv4Addr::new(10, 8, 3, 7), 16), Ipv4Addr::new(10, 8, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_and_udp_through_netstack() {
+        let (client, client_device) = Netstack::new(Ipv4Addr::new(10, 0, 0, 2), 24, 1400);
+        let (server, server_device) = Netstack::new(Ipv4Addr::new(10, 0, 0, 3), 24, 1400);
+        link(client_device, server_device);
+
+        // UDP：两个方向各一个数据报
+        let a = client.udp_bind(0).unwrap();
+        let b = server.udp_bind(5353).unwrap();
+        a.send_to(b"ping", "10.0.0.3:5353".parse().unwrap()).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, from) = b.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from), (&b"ping"[..], SocketAddr::new("10.0.0.2".parse().unwrap(), a.local_port())));
+        b.send_to(b"pong", from).await.unwrap();
+        let (n, _) = a.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"pong");
+
+        // TCP：没有监听的端口被拒绝
+        let refused = client.tcp_connect("10.0.0.3:81".parse().unwrap()).await;
+        assert_eq!(refused.err().map(|e| e.kind()), Some(io::ErrorKind::ConnectionRefused));
+
+        // TCP：在对端协议栈上直接放一个监听 socket，回显收到的数据
+        let handle = {
+            let mut socket = tcp::Socket::new(tcp::SocketBuffer::new(vec![0; 4096]), tcp::SocketBuffer::new(vec![0; 4096]));
+            socket.listen(80).unwrap();
+            server.shared.lock().sockets.add(socket)
+        };
+        let mut accepted = TcpStream { shared: server.shared.clone(), handle };
+        tokio::spawn(async move {
+            // 监听状态下还不能读写，等连接建立
+            poll_fn(|cx| {
+                let mut stack = accepted.shared.lock();
+                let socket = stack.sockets.get_mut::<tcp::Socket>(handle);
+                if socket.may_recv() {
+                    return Poll::Ready(());
+                }
+                socket.register_recv_waker(cx.waker());
+                Poll::Pending
+            })
+            .await;
+            let mut buf = [0u8; 1024];
+            while let Ok(n @ 1..) = accepted.read(&mut buf).await {
+                accepted.write_all(&buf[..n]).await.unwrap();
+            }
+            accepted.shutdown().await.unwrap();
+        });
+
+        let mut stream = client.tcp_connect("10.0.0.3:80".parse().unwrap()).await.unwrap();
+        assert_eq!(stream.peer_addr(), Some("10.0.0.3:80".parse().unwrap()));
+        let payload: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+        stream.write_all(&payload).await.unwrap();
+        let mut echoed = vec![0u8; payload.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, payload);
+    }
+}
@@ -0,0 +1,189 @@
+// vpn_core/src/netwatch.rs
+// 网络变化感知：系统休眠唤醒、默认路由（物理网络）切换
+//
+// * 唤醒：单调时钟在休眠期间不前进，而墙上时钟会前进，两者差值明显变大即视为刚从休眠中恢复
+// * 路由变化：订阅路由套接字消息（macOS `route -n monitor`，Linux `ip monitor route`），
+//   消息到达后重新查询默认网关，只有网关真正变化时才上报
+// * 出口网卡变化：同样基于路由消息，重新查询默认路由所在的网卡（服务端网关模式用来迁移 NAT 规则）
+
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// 休眠检测的采样周期
+const WAKE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// 墙上时钟比单调时钟多走超过该值时视为发生过休眠
+const WAKE_THRESHOLD: Duration = Duration::from_secs(10);
+/// 一次网络切换会产生一串路由消息，等消息平息后再检查
+const ROUTE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkEvent {
+    /// 从休眠中唤醒（大约休眠了多久）
+    Wake { slept: Duration },
+    /// 默认网关发生变化（切换 Wi-Fi、插拔网线等）
+    DefaultRouteChanged { gateway: Option<String> },
+    /// 默认路由的出口网卡发生变化（eth0 -> eth1 等）
+    DefaultInterfaceChanged { interface: Option<String> },
+}
+
+/// 根据同一时段内墙上时钟和单调时钟的流逝判断是否休眠过
+pub fn detect_sleep(wall_elapsed: Duration, mono_elapsed: Duration) -> Option<Duration> {
+    let slept = wall_elapsed.saturating_sub(mono_elapsed);
+    (slept >= WAKE_THRESHOLD).then_some(slept)
+}
+
+/// 查询当前默认网关
+pub fn default_gateway() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        let output = Command::new("route")
+            .args(["-n", "get", "default"])
+            .output()
+            .ok()?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        for line in stdout.lines() {
+            if line.trim().starts_with("gateway:")
+                && let Some(gateway) = line.split(':').nth(1).map(|s| s.trim())
+            {
+                return Some(gateway.to_string());
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let output = Command::new("ip")
+            .args(["route", "show", "default"])
+            .output()
+            .ok()?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // 格式: default via 192.168.1.1 dev eth0
+        if let Some(gateway) = stdout.split_whitespace().nth(2) {
+            return Some(gateway.to_string());
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let output = Command::new("route")
+            .args(["print", "-4", "0.0.0.0"])
+            .output()
+            .ok()?;
+
+        // 格式: 0.0.0.0  0.0.0.0  192.168.1.1  192.168.1.100  25
+        if let Some(gateway) = crate::local_tun::parse_windows_default_gateway(&String::from_utf8_lossy(&output.stdout)) {
+            return Some(gateway);
+        }
+    }
+
+    None
+}
+
+/// 启动后台监听任务，返回事件接收端
+pub fn spawn_watcher() -> mpsc::UnboundedReceiver<NetworkEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(watch_sleep(tx.clone()));
+    tokio::spawn(watch_routes(tx, default_gateway, |gateway| NetworkEvent::DefaultRouteChanged { gateway }));
+    rx
+}
+
+/// 启动后台任务，只监听默认路由出口网卡的变化
+pub fn spawn_interface_watcher() -> mpsc::UnboundedReceiver<NetworkEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(watch_routes(
+        tx,
+        || crate::gateway::detect_default_interface().ok(),
+        |interface| NetworkEvent::DefaultInterfaceChanged { interface },
+    ));
+    rx
+}
+
+async fn watch_sleep(tx: mpsc::UnboundedSender<NetworkEvent>) {
+    let mut wall = SystemTime::now();
+    let mut mono = Instant::now();
+    loop {
+        tokio::time::sleep(WAKE_CHECK_INTERVAL).await;
+        let (now_wall, now_mono) = (SystemTime::now(), Instant::now());
+        let wall_elapsed = now_wall.duration_since(wall).unwrap_or_default();
+        if let Some(slept) = detect_sleep(wall_elapsed, now_mono.duration_since(mono))
+            && tx.send(NetworkEvent::Wake { slept }).is_err()
+        {
+            return;
+        }
+        wall = now_wall;
+        mono = now_mono;
+    }
+}
+
+/// 路由监听命令（Windows 上没有等价的命令行工具，只依赖唤醒检测）
+fn route_monitor_command() -> Option<tokio::process::Command> {
+    #[cfg(target_os = "macos")]
+    let (program, args) = ("route", ["-n", "monitor"]);
+    #[cfg(target_os = "linux")]
+    let (program, args) = ("ip", ["monitor", "route"]);
+    #[cfg(not(any(target_os = "macos", target_os = "linux")))]
+    return None;
+
+    #[cfg(any(target_os = "macos", target_os = "linux"))]
+    {
+        let mut cmd = tokio::process::Command::new(program);
+        cmd.args(args).stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::null()).kill_on_drop(true);
+        Some(cmd)
+    }
+}
+
+/// 每批路由消息平息后调用 probe 重新查询，结果变化时用 event 构造事件上报
+async fn watch_routes(
+    tx: mpsc::UnboundedSender<NetworkEvent>,
+    probe: fn() -> Option<String>,
+    event: fn(Option<String>) -> NetworkEvent,
+) {
+    let Some(mut cmd) = route_monitor_command() else { return };
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("⚠️ 无法监听路由变化: {}", e);
+            return;
+        }
+    };
+    let Some(stdout) = child.stdout.take() else { return };
+    let mut lines = BufReader::new(stdout).lines();
+    let mut last = probe();
+
+    while let Ok(Some(_)) = lines.next_line().await {
+        tokio::time::sleep(ROUTE_DEBOUNCE).await;
+        // 丢弃防抖期间积压的消息（next_line 可以安全取消）
+        while let Ok(Ok(Some(_))) = tokio::time::timeout(Duration::ZERO, lines.next_line()).await {}
+
+        let current = probe();
+        if current != last {
+            last = current.clone();
+            if tx.send(event(current)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_sleep() {
+        assert_eq!(detect_sleep(Duration::from_secs(5), Duration::from_secs(5)), None);
+        // 调度抖动不算休眠
+        assert_eq!(detect_sleep(Duration::from_secs(7), Duration::from_secs(5)), None);
+        assert_eq!(
+            detect_sleep(Duration::from_secs(605), Duration::from_secs(5)),
+            Some(Duration::from_secs(600))
+        );
+        // 墙上时钟回拨
+        assert_eq!(detect_sleep(Duration::ZERO, Duration::from_secs(5)), None);
+    }
+}
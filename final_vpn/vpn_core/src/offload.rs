@@ -0,0 +1,391 @@
+// vpn_core/src/offload.rs
+// Linux TUN 卸载：IFF_VNET_HDR + TSO/GSO
+//
+// 开启后内核可以一次交给我们最大 64KB 的合并 TCP 段（附带 virtio_net_hdr），
+// 读端在加密前按 gso_size 切分成普通 IP 包；写端给每个包加一个全零头部（GSO_NONE）。
+
+use anyhow::{Result, anyhow};
+
+use crate::packet::{fold, pseudo_header_sum, recompute_ipv4_header_checksum, sum_words};
+
+/// virtio_net_hdr 长度（不含 num_buffers）
+pub const VNET_HDR_LEN: usize = 10;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+
+/// virtio_net_hdr（字段为主机字节序）
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() < VNET_HDR_LEN {
+            return Err(anyhow!("virtio_net_hdr 太短"));
+        }
+        let u16_at = |i: usize| u16::from_ne_bytes([buf[i], buf[i + 1]]);
+        Ok(Self {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; VNET_HDR_LEN] {
+        let mut out = [0u8; VNET_HDR_LEN];
+        out[0] = self.flags;
+        out[1] = self.gso_type;
+        out[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        out[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        out[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        out[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        out
+    }
+}
+
+/// 把从 TUN 读到的 [virtio_net_hdr][IP 包] 还原成一个或多个普通 IP 包
+pub fn split_frame(frame: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let hdr = VirtioNetHdr::decode(frame)?;
+    let packet = &frame[VNET_HDR_LEN..];
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let mut packet = packet.to_vec();
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                complete_partial_checksum(&mut packet, hdr.csum_start as usize, hdr.csum_offset as usize)?;
+            }
+            Ok(vec![packet])
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => segment_tcp(packet, hdr.gso_size as usize),
+        other => Err(anyhow!("不支持的 GSO 类型: {}", other)),
+    }
+}
+
+/// 补全 NEEDS_CSUM 包的校验和：校验和字段里已是伪首部部分和，
+/// 对 csum_start 之后的数据求和取反后写回即可
+fn complete_partial_checksum(packet: &mut [u8], csum_start: usize, csum_offset: usize) -> Result<()> {
+    let field = csum_start + csum_offset;
+    if field + 2 > packet.len() {
+        return Err(anyhow!("校验和偏移越界"));
+    }
+    let sum = fold(sum_words(&packet[csum_start..], 0));
+    packet[field..field + 2].copy_from_slice(&(!sum).to_be_bytes());
+    Ok(())
+}
+
+/// 按 gso_size 切分合并的 TCP 段，修正长度、IP ID、序列号、标志位和校验和
+fn segment_tcp(packet: &[u8], gso_size: usize) -> Result<Vec<Vec<u8>>> {
+    if packet.is_empty() || gso_size == 0 {
+        return Err(anyhow!("无效的 GSO 包"));
+    }
+
+    let is_v6 = packet[0] >> 4 == 6;
+    let ip_hdr_len = if is_v6 { 40 } else { ((packet[0] & 0x0f) as usize) * 4 };
+    if packet.len() < ip_hdr_len + 20 {
+        return Err(anyhow!("GSO 包太短"));
+    }
+    let tcp_hdr_len = ((packet[ip_hdr_len + 12] >> 4) as usize) * 4;
+    let hdr_len = ip_hdr_len + tcp_hdr_len;
+    if packet.len() < hdr_len {
+        return Err(anyhow!("GSO 包头部不完整"));
+    }
+
+    let payload = &packet[hdr_len..];
+    let base_seq = u32::from_be_bytes(packet[ip_hdr_len + 4..ip_hdr_len + 8].try_into()?);
+    let base_id = u16::from_be_bytes([packet[4], packet[5]]);
+    let chunks: Vec<&[u8]> = payload.chunks(gso_size).collect();
+    let count = chunks.len().max(1);
+
+    let mut segments = Vec::with_capacity(count);
+    for (i, chunk) in chunks.iter().enumerate() {
+        let mut seg = Vec::with_capacity(hdr_len + chunk.len());
+        seg.extend_from_slice(&packet[..hdr_len]);
+        seg.extend_from_slice(chunk);
+
+        // IP 头：长度 / ID / 校验和
+        if is_v6 {
+            let payload_len = (tcp_hdr_len + chunk.len()) as u16;
+            seg[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        } else {
+            let total_len = seg.len() as u16;
+            seg[2..4].copy_from_slice(&total_len.to_be_bytes());
+            seg[4..6].copy_from_slice(&base_id.wrapping_add(i as u16).to_be_bytes());
+            recompute_ipv4_header_checksum(&mut seg);
+        }
+
+        // TCP 头：序列号，除最后一段外清掉 FIN/PSH
+        let seq = base_seq.wrapping_add((i * gso_size) as u32);
+        seg[ip_hdr_len + 4..ip_hdr_len + 8].copy_from_slice(&seq.to_be_bytes());
+        if i + 1 < count {
+            seg[ip_hdr_len + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+
+        // TCP 校验和（含伪首部）
+        seg[ip_hdr_len + 16..ip_hdr_len + 18].copy_from_slice(&[0, 0]);
+        let tcp_len = seg.len() - ip_hdr_len;
+        let pseudo = pseudo_header_sum(&seg, 6, tcp_len);
+        let tcp_sum = !fold(sum_words(&seg[ip_hdr_len..], pseudo));
+        seg[ip_hdr_len + 16..ip_hdr_len + 18].copy_from_slice(&tcp_sum.to_be_bytes());
+
+        segments.push(seg);
+    }
+
+    Ok(segments)
+}
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+pub use linux::OffloadDevice;
+
+#[cfg(all(target_os = "linux", feature = "tokio"))]
+mod linux {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::pin::Pin;
+    use std::process::Command;
+    use std::task::{Context, Poll, ready};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    const IFF_TUN: libc::c_short = 0x0001;
+    const IFF_NO_PI: libc::c_short = 0x1000;
+    const IFF_VNET_HDR: libc::c_short = 0x4000;
+    const TUNSETIFF: libc::c_ulong = 0x400454ca;
+    const TUNSETOFFLOAD: libc::c_ulong = 0x400454d0;
+    const TUNSETVNETHDRSZ: libc::c_ulong = 0x400454d8;
+    const TUN_F_CSUM: libc::c_uint = 0x01;
+    const TUN_F_TSO4: libc::c_uint = 0x02;
+    const TUN_F_TSO6: libc::c_uint = 0x04;
+    const TUN_F_TSO_ECN: libc::c_uint = 0x08;
+
+    /// 单个 GSO 帧的最大长度
+    const MAX_FRAME: usize = VNET_HDR_LEN + 65535;
+
+    #[repr(C)]
+    struct IfReq {
+        name: [libc::c_char; libc::IFNAMSIZ],
+        flags: libc::c_short,
+        _pad: [u8; 22],
+    }
+
+    /// 开启了 IFF_VNET_HDR 和 TSO 的 TUN 设备
+    ///
+    /// 对外表现为普通的“一次读/写一个 IP 包”的设备，GSO 帧在内部切分
+    pub struct OffloadDevice {
+        fd: AsyncFd<File>,
+        name: String,
+        pending: VecDeque<Vec<u8>>,
+        frame: Vec<u8>,
+    }
+
+    impl OffloadDevice {
+        /// 创建设备并配置地址（使用 ip 命令，需要 CAP_NET_ADMIN）
+        pub fn create(name: Option<&str>, address: &str, netmask: &str) -> Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/net/tun")?;
+
+            let mut req = IfReq { name: [0; libc::IFNAMSIZ], flags: IFF_TUN | IFF_NO_PI | IFF_VNET_HDR, _pad: [0; 22] };
+            let requested = name.unwrap_or("tun%d");
+            if requested.len() >= libc::IFNAMSIZ {
+                return Err(anyhow!("设备名过长: {}", requested));
+            }
+            for (dst, src) in req.name.iter_mut().zip(requested.bytes()) {
+                *dst = src as libc::c_char;
+            }
+
+            let fd = file.as_raw_fd();
+            let hdr_size: libc::c_int = VNET_HDR_LEN as libc::c_int;
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_TSO_ECN;
+            // SAFETY: fd 有效，参数布局与内核 ioctl 约定一致
+            unsafe {
+                if libc::ioctl(fd, TUNSETIFF as _, &mut req) < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                if libc::ioctl(fd, TUNSETVNETHDRSZ as _, &hdr_size) < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                if libc::ioctl(fd, TUNSETOFFLOAD as _, offloads as libc::c_ulong) < 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+            }
+
+            let name: String = req.name.iter()
+                .take_while(|c| **c != 0)
+                .map(|c| *c as u8 as char)
+                .collect();
+
+            let prefix = u32::from(netmask.parse::<std::net::Ipv4Addr>()?).count_ones();
+            let cidr = format!("{}/{}", address, prefix);
+            for args in [
+                vec!["addr", "add", cidr.as_str(), "dev", name.as_str()],
+                vec!["link", "set", name.as_str(), "up"],
+            ] {
+                let status = Command::new("ip").args(&args).status()?;
+                if !status.success() {
+                    return Err(anyhow!("ip {} 失败 (exit code: {:?})", args.join(" "), status.code()));
+                }
+            }
+
+            Ok(Self {
+                fd: AsyncFd::new(file)?,
+                name,
+                pending: VecDeque::new(),
+                frame: vec![0u8; MAX_FRAME],
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl AsyncRead for OffloadDevice {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            let this = &mut *self;
+            loop {
+                if let Some(packet) = this.pending.pop_front() {
+                    let n = packet.len().min(buf.remaining());
+                    buf.put_slice(&packet[..n]);
+                    return Poll::Ready(Ok(()));
+                }
+
+                let mut guard = ready!(this.fd.poll_read_ready(cx))?;
+                let frame = &mut this.frame;
+                match guard.try_io(|inner| inner.get_ref().read(frame)) {
+                    Ok(Ok(n)) => match split_frame(&this.frame[..n]) {
+                        Ok(packets) => this.pending.extend(packets),
+                        Err(e) => eprintln!("⚠️  丢弃无法解析的 GSO 帧: {}", e),
+                    },
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for OffloadDevice {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let mut frame = Vec::with_capacity(VNET_HDR_LEN + buf.len());
+            frame.extend_from_slice(&VirtioNetHdr::default().encode());
+            frame.extend_from_slice(buf);
+
+            loop {
+                let mut guard = ready!(self.fd.poll_write_ready(cx))?;
+                match guard.try_io(|inner| inner.get_ref().write(&frame)) {
+                    Ok(result) => return Poll::Ready(result.map(|_| buf.len())),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ipv4_header_checksum_valid, transport_checksum_valid};
+
+    /// 构造一个 IPv4/TCP 包（校验和字段留空）
+    fn tcp4_packet(payload_len: usize, flags: u8) -> Vec<u8> {
+        let total = 20 + 20 + payload_len;
+        let mut p = vec![0u8; total];
+        p[0] = 0x45;
+        p[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        p[4..6].copy_from_slice(&100u16.to_be_bytes());
+        p[8] = 64;
+        p[9] = 6;
+        p[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        p[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        p[24..28].copy_from_slice(&1000u32.to_be_bytes());
+        p[32] = 5 << 4;
+        p[33] = flags;
+        for (i, b) in p[40..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        p
+    }
+
+    fn checksum_ok(seg: &[u8]) -> bool {
+        ipv4_header_checksum_valid(seg) && transport_checksum_valid(seg) == Some(true)
+    }
+
+    #[test]
+    fn test_vnet_hdr_roundtrip() {
+        let hdr = VirtioNetHdr { flags: 1, gso_type: 1, hdr_len: 40, gso_size: 1400, csum_start: 20, csum_offset: 16 };
+        assert_eq!(VirtioNetHdr::decode(&hdr.encode()).unwrap(), hdr);
+    }
+
+    #[test]
+    fn test_segment_tcp4() {
+        let packet = tcp4_packet(3000, TCP_FLAG_PSH | TCP_FLAG_FIN | 0x10);
+        let hdr = VirtioNetHdr { gso_type: VIRTIO_NET_HDR_GSO_TCPV4, gso_size: 1400, hdr_len: 40, ..Default::default() };
+        let mut frame = hdr.encode().to_vec();
+        frame.extend_from_slice(&packet);
+
+        let segments = split_frame(&frame).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments.iter().map(|s| s.len()).collect::<Vec<_>>(), vec![1440, 1440, 240]);
+
+        for (i, seg) in segments.iter().enumerate() {
+            assert!(checksum_ok(seg), "segment {} checksum", i);
+            assert_eq!(u16::from_be_bytes([seg[2], seg[3]]) as usize, seg.len());
+            assert_eq!(u16::from_be_bytes([seg[4], seg[5]]), 100 + i as u16);
+            assert_eq!(u32::from_be_bytes(seg[24..28].try_into().unwrap()), 1000 + (i as u32) * 1400);
+        }
+
+        // 只有最后一段保留 FIN/PSH
+        assert_eq!(segments[0][33] & (TCP_FLAG_FIN | TCP_FLAG_PSH), 0);
+        assert_eq!(segments[2][33] & (TCP_FLAG_FIN | TCP_FLAG_PSH), TCP_FLAG_FIN | TCP_FLAG_PSH);
+        // 负载按顺序拼回去与原始一致
+        let joined: Vec<u8> = segments.iter().flat_map(|s| s[40..].to_vec()).collect();
+        assert_eq!(joined, packet[40..].to_vec());
+    }
+
+    #[test]
+    fn test_needs_csum() {
+        let mut packet = tcp4_packet(100, 0x10);
+        // 按内核约定，校验和字段预先填入伪首部部分和
+        let pseudo = fold(sum_words(&packet[12..20], 0) + 6 + 120);
+        packet[36..38].copy_from_slice(&pseudo.to_be_bytes());
+        // IP 校验和由内核填好
+        recompute_ipv4_header_checksum(&mut packet);
+
+        let hdr = VirtioNetHdr { flags: VIRTIO_NET_HDR_F_NEEDS_CSUM, csum_start: 20, csum_offset: 16, ..Default::default() };
+        let mut frame = hdr.encode().to_vec();
+        frame.extend_from_slice(&packet);
+
+        let out = split_frame(&frame).unwrap();
+        assert_eq!(out.len(), 1);
+        assert!(checksum_ok(&out[0]));
+    }
+}
@@ -0,0 +1,252 @@
+// vpn_core/src/pacing.rs
+// 上行发送节奏控制（--pace）
+//
+// 不加控制时，客户端把从 TUN 读到的包以线速发出。上行链路拥塞时（家用宽带、蜂窝网络），
+// 链路上的缓冲区被隧道流量填满，用户的其他流量延迟飙升（bufferbloat）并开始丢包。
+// 启用后上行包按目标速率匀速发出，队列留在本机（TUN 发送队列），而不是链路上：
+//
+// * 固定速率：`--pace 20mbit`，一般设为实际上行带宽的 90% 左右
+// * 自动：`--pace auto`，客户端每 PROBE_INTERVAL 在控制通道上发一个探测 Echo，
+//   RTT 明显高于最小 RTT（链路上的队列在堆积）或探测丢失时降速，
+//   发送确实受限于当前速率且延迟正常时逐步提速（类似 BBR / Vegas 的延迟反馈）
+//
+// Pacer 记录下一个包最早的发送时间，允许 BURST 的突发；等待不足 MIN_SLEEP 的包直接发出，
+// 欠下的时间累积到后面的包上，平均速率仍然准确。
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+use crate::tuning::parse_rate;
+
+/// 允许的突发（按当前速率折算成时间）
+pub const BURST: Duration = Duration::from_millis(5);
+
+/// 短于这个时间的等待不睡眠（定时器精度约 1ms）
+pub const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+/// 自动模式的探测间隔
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 探测超过这个时间没有回复视为丢失
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 最小 RTT 的有效期，过期后用新的样本重新确定（路由变化后基准也会变化）
+pub const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+
+/// 自动模式的起始速率和范围（bit/s）
+pub const AUTO_INITIAL_RATE: u64 = 10_000_000;
+pub const AUTO_MIN_RATE: u64 = 256_000;
+pub const AUTO_MAX_RATE: u64 = 1_000_000_000;
+
+/// 探测 Echo 的 ID 带上这个标志，与控制任务自己的保活 Echo 区分
+pub const PROBE_ID_FLAG: u32 = 0x8000_0000;
+
+/// --pace 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacingMode {
+    /// 固定速率（bit/s）
+    Fixed(u64),
+    /// 根据 RTT 和探测丢失自动调整
+    Auto,
+}
+
+impl PacingMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(PacingMode::Auto),
+            rate => parse_rate(rate)
+                .map(PacingMode::Fixed)
+                .map_err(|_| anyhow!("无效的 --pace: {}（auto 或速率，如 20mbit）", value)),
+        }
+    }
+
+    pub fn initial_rate(&self) -> u64 {
+        match self {
+            PacingMode::Fixed(rate) => *rate,
+            PacingMode::Auto => AUTO_INITIAL_RATE,
+        }
+    }
+}
+
+pub fn is_probe(id: u32) -> bool {
+    id & PROBE_ID_FLAG != 0
+}
+
+/// 按速率排定每个包的发送时间
+#[derive(Debug)]
+pub struct Pacer {
+    /// bit/s
+    rate: u64,
+    next_send: Option<Instant>,
+    /// 上次 take_sent 之后排定的字节数
+    sent_bytes: u64,
+}
+
+impl Pacer {
+    pub fn new(rate: u64) -> Self {
+        Self { rate: rate.max(1), next_send: None, sent_bytes: 0 }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate.max(1);
+    }
+
+    /// 排定一个 len 字节的包，返回还需要等待多久才能发出
+    pub fn schedule(&mut self, len: usize, now: Instant) -> Duration {
+        // 空闲之后最多积累 BURST 的额度
+        let earliest = now.checked_sub(BURST).unwrap_or(now);
+        let start = self.next_send.map_or(earliest, |t| t.max(earliest));
+        self.next_send = Some(start + Duration::from_secs_f64(len as f64 * 8.0 / self.rate as f64));
+        self.sent_bytes += len as u64;
+        start.saturating_duration_since(now)
+    }
+
+    /// 取出并清零发送字节数
+    pub fn take_sent(&mut self) -> u64 {
+        std::mem::take(&mut self.sent_bytes)
+    }
+}
+
+/// 自动模式的速率估计：根据探测 Echo 的 RTT 和丢失调整速率
+#[derive(Debug)]
+pub struct RateController {
+    rate: u64,
+    /// (最小 RTT, 记录时间)
+    min_rtt: Option<(Duration, Instant)>,
+    /// 本轮收到的最小 RTT 样本（取最小值排除单个样本的抖动）
+    round_rtt: Option<Duration>,
+    /// 已发出、尚未回复的探测
+    outstanding: HashMap<u32, Instant>,
+}
+
+impl RateController {
+    pub fn new(rate: u64) -> Self {
+        Self { rate, min_rtt: None, round_rtt: None, outstanding: HashMap::new() }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt.map(|(rtt, _)| rtt)
+    }
+
+    pub fn on_probe_sent(&mut self, id: u32, now: Instant) {
+        self.outstanding.insert(id, now);
+    }
+
+    /// 收到探测的回复；不是本控制器发出的（或已判定丢失的）探测被忽略
+    pub fn on_probe_reply(&mut self, id: u32, rtt: Duration, now: Instant) {
+        if self.outstanding.remove(&id).is_none() {
+            return;
+        }
+        let expired = self.min_rtt.is_none_or(|(min, at)| rtt < min || now.duration_since(at) > MIN_RTT_WINDOW);
+        if expired {
+            self.min_rtt = Some((rtt, now));
+        }
+        self.round_rtt = Some(self.round_rtt.map_or(rtt, |r| r.min(rtt)));
+    }
+
+    /// 每个探测周期调用一次：sent_bytes 为这一周期内实际发送的字节数，返回新的速率
+    pub fn update(&mut self, sent_bytes: u64, elapsed: Duration, now: Instant) -> u64 {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|_, sent| now.duration_since(*sent) < PROBE_TIMEOUT);
+        let lost = before - self.outstanding.len();
+
+        let queueing = match (self.round_rtt.take(), self.min_rtt()) {
+            // 排队延迟超过最小 RTT 的 1/4（至少 5ms）时认为链路缓冲区在堆积
+            (Some(rtt), Some(min)) => rtt > min + (min / 4).max(Duration::from_millis(5)),
+            _ => false,
+        };
+        let sent_rate = (sent_bytes as f64 * 8.0 / elapsed.as_secs_f64().max(1e-3)) as u64;
+
+        self.rate = if lost > 0 {
+            self.rate * 7 / 10
+        } else if queueing {
+            self.rate * 85 / 100
+        } else if sent_rate >= self.rate * 8 / 10 {
+            // 只在发送确实受限于当前速率时提速，流量很小时速率不会无限上涨
+            self.rate * 110 / 100
+        } else {
+            self.rate
+        }
+        .clamp(AUTO_MIN_RATE, AUTO_MAX_RATE);
+        self.rate
+    }
+}
+
+/// 状态输出用的速率格式（kbit/s 或 Mbit/s）
+pub fn format_rate(rate: u64) -> String {
+    if rate >= 1_000_000 {
+        format!("{:.1} Mbit/s", rate as f64 / 1e6)
+    } else {
+        format!("{} kbit/s", rate / 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer_schedule() {
+        assert_eq!(PacingMode::parse("20mbit").unwrap(), PacingMode::Fixed(20_000_000));
+        assert_eq!(PacingMode::parse("auto").unwrap(), PacingMode::Auto);
+        assert!(PacingMode::parse("fast").is_err());
+
+        // 8 Mbit/s：1000 字节的包间隔 1ms，BURST（5ms）以内的包不用等
+        let now = Instant::now();
+        let mut pacer = Pacer::new(8_000_000);
+        for _ in 0..5 {
+            assert_eq!(pacer.schedule(1000, now), Duration::ZERO);
+        }
+        assert_eq!(pacer.schedule(1000, now), Duration::ZERO);
+        assert_eq!(pacer.schedule(1000, now), Duration::from_millis(1));
+        assert_eq!(pacer.schedule(1000, now), Duration::from_millis(2));
+        assert_eq!(pacer.take_sent(), 8000);
+        assert_eq!(pacer.take_sent(), 0);
+
+        // 空闲之后额度不会无限积累
+        let later = now + Duration::from_secs(1);
+        for _ in 0..6 {
+            assert_eq!(pacer.schedule(1000, later), Duration::ZERO);
+        }
+        assert!(pacer.schedule(1000, later) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_controller() {
+        let start = Instant::now();
+        let mut controller = RateController::new(AUTO_INITIAL_RATE);
+        let round = |controller: &mut RateController, n: u32, rtt_ms: u64, sent: u64| {
+            let now = start + PROBE_INTERVAL * n;
+            let id = n | PROBE_ID_FLAG;
+            controller.on_probe_sent(id, now);
+            controller.on_probe_reply(id, Duration::from_millis(rtt_ms), now);
+            controller.update(sent, PROBE_INTERVAL, now)
+        };
+
+        // 发送量跟不上速率时不提速；受限于速率且延迟正常时提速
+        assert_eq!(round(&mut controller, 1, 20, 1000), AUTO_INITIAL_RATE);
+        assert_eq!(round(&mut controller, 2, 20, 250_000), 11_000_000);
+        // 延迟明显增加时降速
+        assert_eq!(round(&mut controller, 3, 40, 275_000), 9_350_000);
+        assert_eq!(controller.min_rtt(), Some(Duration::from_millis(20)));
+
+        // 探测丢失时大幅降速
+        let now = start + PROBE_INTERVAL * 4;
+        controller.on_probe_sent(PROBE_ID_FLAG | 4, now);
+        assert_eq!(controller.update(0, PROBE_INTERVAL, now + PROBE_TIMEOUT), 9_350_000 * 7 / 10);
+        // 判定丢失后才到的回复被忽略
+        controller.on_probe_reply(PROBE_ID_FLAG | 4, Duration::from_secs(2), now + PROBE_TIMEOUT);
+        assert_eq!(controller.min_rtt(), Some(Duration::from_millis(20)));
+        assert!(is_probe(PROBE_ID_FLAG | 4) && !is_probe(4));
+    }
+}
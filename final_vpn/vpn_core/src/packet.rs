@@ -0,0 +1,240 @@
+// vpn_core/src/packet.rs
+// IP 包的校验和工具
+//
+// 转发路径上改写包头（TTL 递减、MSS 钳制、地址改写）后必须更新校验和，否则对端协议栈会静默丢包。
+// 这里集中提供 Internet 校验和（RFC 1071）的计算与校验、IPv4 头校验和的重新计算，
+// 以及只改动个别 16 位字时的增量更新（RFC 1624），offload、icmp 等模块都基于这些函数。
+
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+
+/// 16 位反码求和（未折叠），奇数长度时末字节补零
+pub fn sum_words(data: &[u8], initial: u32) -> u32 {
+    let mut sum = initial;
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+/// 把进位折叠回低 16 位
+pub fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Internet 校验和：反码和取反
+pub fn checksum(data: &[u8]) -> u16 {
+    !fold(sum_words(data, 0))
+}
+
+/// 增量更新校验和（RFC 1624 式 3：HC' = ~(~HC + ~m + m')），old / new 为被改动的 16 位字
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold((!checksum) as u32 + (!old) as u32 + new as u32)
+}
+
+/// IPv4 头长度（IHL * 4）；不是完整的 IPv4 头时返回 None
+pub fn ipv4_header_len(packet: &[u8]) -> Option<usize> {
+    let first = *packet.first()?;
+    let len = (first & 0x0f) as usize * 4;
+    (first >> 4 == 4 && len >= 20 && packet.len() >= len).then_some(len)
+}
+
+/// IPv4 头校验和是否正确（含选项）
+pub fn ipv4_header_checksum_valid(packet: &[u8]) -> bool {
+    ipv4_header_len(packet).is_some_and(|len| fold(sum_words(&packet[..len], 0)) == 0xffff)
+}
+
+/// 重新计算并写入 IPv4 头校验和；不是 IPv4 包时返回 false
+pub fn recompute_ipv4_header_checksum(packet: &mut [u8]) -> bool {
+    let Some(len) = ipv4_header_len(packet) else {
+        return false;
+    };
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let sum = checksum(&packet[..len]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    true
+}
+
+/// 改写 IPv4 头中偏移 offset（偶数）处的 16 位字，并增量更新头校验和
+pub fn set_ipv4_word(packet: &mut [u8], offset: usize, value: u16) {
+    debug_assert!(offset.is_multiple_of(2) && offset != 10);
+    let old = u16::from_be_bytes([packet[offset], packet[offset + 1]]);
+    packet[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    let sum = update_checksum(u16::from_be_bytes([packet[10], packet[11]]), old, value);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+}
+
+/// 上层协议校验和的伪首部部分和（IPv4：源/目的地址、协议号、长度；IPv6：源/目的地址、长度、下一个头部）
+pub fn pseudo_header_sum(packet: &[u8], protocol: u8, upper_len: usize) -> u32 {
+    let addrs = if packet[0] >> 4 == 6 { &packet[8..40] } else { &packet[12..20] };
+    sum_words(addrs, 0) + protocol as u32 + upper_len as u32
+}
+
+/// TCP / UDP 校验和是否正确；其他协议、分片和带扩展头的 IPv6 包返回 None
+///
+/// UDP over IPv4 的校验和为 0 表示未计算，视为正确。
+pub fn transport_checksum_valid(packet: &[u8]) -> Option<bool> {
+    let (header_len, protocol) = match packet.first()? >> 4 {
+        4 => {
+            let len = ipv4_header_len(packet)?;
+            let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+            if fragmented {
+                return None;
+            }
+            (len, packet[9])
+        }
+        6 if packet.len() >= 40 => (40, packet[6]),
+        _ => return None,
+    };
+    let segment = &packet[header_len..];
+    match protocol {
+        PROTO_TCP if segment.len() >= 20 => {}
+        PROTO_UDP if segment.len() >= 8 => {
+            if header_len != 40 && segment[6..8] == [0, 0] {
+                return Some(true);
+            }
+        }
+        _ => return None,
+    }
+    let pseudo = pseudo_header_sum(packet, protocol, segment.len());
+    Some(fold(sum_words(segment, pseudo)) == 0xffff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 以下报文由独立工具计算校验和，作为已知正确的样本
+    /// IPv4 TCP SYN（MSS 1460）
+    const TCP_SYN: &str = "450000301c464000400626f5c0a8010a5db8d822d431005012345678000000006002faf063730000020405b401010402";
+    /// IPv4 UDP DNS 查询
+    const UDP_DNS: &str = "45000039beef4000401161b30a00000208080808cf08003500259b19abcd01000001000000000000076578616d706c6503636f6d0000010001";
+    /// 带 Router Alert 选项（IHL = 6）的 IGMP 报告
+    const IGMP_WITH_OPTIONS: &str = "46000020000040000102f9da0a000002e00000fb9404000016000904e00000fb";
+    /// IPv6 UDP
+    const UDP6: &str = "60000000000c1140fd000000000000000000000000000002fd0000000000000000000000000000039c4014e9000c75d570696e67";
+
+    fn packet(hex: &str) -> Vec<u8> {
+        hex::decode(hex).unwrap()
+    }
+
+    #[test]
+    fn test_checksum_known_values() {
+        // RFC 1071 第 3 节的例子：反码和为 0xddf2
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(fold(sum_words(&data, 0)), 0xddf2);
+        assert_eq!(checksum(&data), !0xddf2);
+        // 奇数长度末字节补零
+        assert_eq!(checksum(&[0x12, 0x34, 0x56]), checksum(&[0x12, 0x34, 0x56, 0x00]));
+        assert_eq!(checksum(&[]), 0xffff);
+
+        // 常见的 IPv4 头示例，校验和为 0xb861
+        let mut header = packet("450000730000400040110000c0a80001c0a800c7");
+        assert!(recompute_ipv4_header_checksum(&mut header));
+        assert_eq!(&header[10..12], &[0xb8, 0x61]);
+        assert!(ipv4_header_checksum_valid(&header));
+    }
+
+    #[test]
+    fn test_ipv4_header_checksum() {
+        for hex in [TCP_SYN, UDP_DNS, IGMP_WITH_OPTIONS] {
+            let original = packet(hex);
+            assert!(ipv4_header_checksum_valid(&original), "{}", hex);
+
+            // 清零后重新计算得到原值
+            let mut p = original.clone();
+            p[10..12].copy_from_slice(&[0, 0]);
+            assert!(!ipv4_header_checksum_valid(&p));
+            assert!(recompute_ipv4_header_checksum(&mut p));
+            assert_eq!(p, original);
+
+            // 头部（含选项）任意一位翻转都能被发现
+            let header_len = ipv4_header_len(&original).unwrap();
+            for bit in 0..header_len * 8 {
+                let mut p = original.clone();
+                p[bit / 8] ^= 0x80 >> (bit % 8);
+                if ipv4_header_len(&p) == Some(header_len) {
+                    assert!(!ipv4_header_checksum_valid(&p), "{} 第 {} 位", hex, bit);
+                }
+            }
+        }
+
+        let mut v6 = packet(UDP6);
+        assert!(!ipv4_header_checksum_valid(&v6));
+        assert!(!recompute_ipv4_header_checksum(&mut v6));
+        assert_eq!(v6, packet(UDP6));
+        // 截断或 IHL 小于 5
+        assert!(!ipv4_header_checksum_valid(&packet(TCP_SYN)[..19]));
+        assert!(!ipv4_header_checksum_valid(&packet(IGMP_WITH_OPTIONS)[..22]));
+        let mut bad_ihl = packet(TCP_SYN);
+        bad_ihl[0] = 0x44;
+        assert!(!recompute_ipv4_header_checksum(&mut bad_ihl));
+    }
+
+    #[test]
+    fn test_incremental_update_matches_recompute() {
+        // 遍历所有 TTL 和协议号组合，增量更新的结果都与完整重算一致
+        let original = packet(UDP_DNS);
+        for ttl in 0..=255u8 {
+            for protocol in 0..=255u8 {
+                let mut incremental = original.clone();
+                set_ipv4_word(&mut incremental, 8, u16::from_be_bytes([ttl, protocol]));
+                let mut full = incremental.clone();
+                recompute_ipv4_header_checksum(&mut full);
+                assert_eq!(incremental, full, "ttl {} proto {}", ttl, protocol);
+            }
+        }
+
+        // 改写地址（两个 16 位字）
+        let mut p = packet(TCP_SYN);
+        set_ipv4_word(&mut p, 16, 0x0a00);
+        set_ipv4_word(&mut p, 18, 0x0001);
+        assert_eq!(&p[16..20], &[10, 0, 0, 1]);
+        assert!(ipv4_header_checksum_valid(&p));
+
+        // 改回原值后校验和也回到原值
+        let mut p = packet(IGMP_WITH_OPTIONS);
+        set_ipv4_word(&mut p, 4, 0xffff);
+        set_ipv4_word(&mut p, 4, 0x0000);
+        assert_eq!(p, packet(IGMP_WITH_OPTIONS));
+
+        // RFC 1624 第 4 节的例子：式 3 得到 0x0000，而式 2 会错误地得到 0xffff
+        assert_eq!(update_checksum(0xdd2f, 0x5555, 0x3285), 0x0000);
+    }
+
+    #[test]
+    fn test_transport_checksum() {
+        for hex in [TCP_SYN, UDP_DNS, UDP6] {
+            let original = packet(hex);
+            assert_eq!(transport_checksum_valid(&original), Some(true), "{}", hex);
+            let mut corrupted = original.clone();
+            *corrupted.last_mut().unwrap() ^= 0x01;
+            assert_eq!(transport_checksum_valid(&corrupted), Some(false), "{}", hex);
+        }
+
+        // 把 MSS 从 1460 钳制到 1360：TCP 校验和按 RFC 1624 增量更新
+        let mut syn = packet(TCP_SYN);
+        let tcp_sum = u16::from_be_bytes([syn[36], syn[37]]);
+        syn[42..44].copy_from_slice(&1360u16.to_be_bytes());
+        syn[36..38].copy_from_slice(&update_checksum(tcp_sum, 1460, 1360).to_be_bytes());
+        assert_eq!(transport_checksum_valid(&syn), Some(true));
+
+        // IPv4 UDP 校验和为 0 表示未计算
+        let mut udp = packet(UDP_DNS);
+        udp[26..28].copy_from_slice(&[0, 0]);
+        assert_eq!(transport_checksum_valid(&udp), Some(true));
+        // 其他协议和分片无法校验
+        assert_eq!(transport_checksum_valid(&packet(IGMP_WITH_OPTIONS)), None);
+        let mut fragment = packet(UDP_DNS);
+        set_ipv4_word(&mut fragment, 6, 0x2000);
+        assert_eq!(transport_checksum_valid(&fragment), None);
+        assert_eq!(transport_checksum_valid(&[]), None);
+    }
+}
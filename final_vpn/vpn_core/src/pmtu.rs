@@ -0,0 +1,242 @@
+// vpn_core/src/pmtu.rs
+// 隧道内的路径 MTU 探测
+//
+// 客户端周期性地在隧道内发送填充到指定大小的探测包（外层 UDP 设置 DF），
+// 服务端收到后回一个很小的确认包；用二分查找收敛到能通过的最大尺寸，
+// 然后据此调整 TUN MTU 和 TCP MSS。
+//
+// 探测包和 IP 包共用加密通道，用明文首字节区分（见 control 模块），
+// 探测包首字节固定为 control::KIND_PMTU。
+
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+
+/// 探测消息的首字节标记
+pub const PROBE_MARKER: u8 = crate::control::KIND_PMTU;
+const TYPE_PROBE: u8 = 1;
+const TYPE_ACK: u8 = 2;
+const HEADER_LEN: usize = 8;
+
+/// 外层开销：IPv4 头 20 + UDP 头 8 + 数据报头 3 + 序号 8 + Poly1305 Tag 16
+pub const TUNNEL_OVERHEAD: u16 = 55;
+/// 探测的下限（IPv4 保证可达的最小 MTU）和上限
+pub const MIN_MTU: u16 = 576;
+pub const MAX_MTU: u16 = 1500;
+/// 启用探测时在收敛前使用的保守 MTU
+pub const INITIAL_MTU: u16 = 1400;
+/// 二分查找收敛精度
+const PRECISION: u16 = 8;
+/// 单个尺寸的最大重试次数，全部超时视为该尺寸无法通过
+const MAX_ATTEMPTS: u8 = 3;
+
+/// 单个探测的等待时间
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// 收敛后重新探测的周期（用于发现路径变化）
+pub const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PmtuMessage {
+    /// 探测包：明文总长度为 size
+    Probe { id: u32, size: u16 },
+    /// 确认：对端收到了该尺寸的探测包
+    Ack { id: u32, size: u16 },
+}
+
+impl PmtuMessage {
+    /// 编码；Probe 会用 0 填充到 size 字节
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, id, size) = match *self {
+            PmtuMessage::Probe { id, size } => (TYPE_PROBE, id, size),
+            PmtuMessage::Ack { id, size } => (TYPE_ACK, id, size),
+        };
+        let mut out = Vec::with_capacity(HEADER_LEN.max(size as usize));
+        out.push(PROBE_MARKER);
+        out.push(kind);
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&size.to_be_bytes());
+        if kind == TYPE_PROBE {
+            out.resize(HEADER_LEN.max(size as usize), 0);
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < HEADER_LEN || data[0] != PROBE_MARKER {
+            return Err(anyhow!("不是 PMTU 探测消息"));
+        }
+        let id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let size = u16::from_be_bytes([data[6], data[7]]);
+        match data[1] {
+            TYPE_PROBE if data.len() == size as usize => Ok(PmtuMessage::Probe { id, size }),
+            TYPE_PROBE => Err(anyhow!("探测包长度与声明不符")),
+            TYPE_ACK => Ok(PmtuMessage::Ack { id, size }),
+            other => Err(anyhow!("未知的 PMTU 消息类型: {}", other)),
+        }
+    }
+}
+
+/// 判断解密后的明文是否为探测消息（而不是 IP 包）
+pub fn is_pmtu_message(plaintext: &[u8]) -> bool {
+    plaintext.first() == Some(&PROBE_MARKER)
+}
+
+/// 二分查找探测状态机（不涉及 IO，便于测试）
+///
+/// `low` 为已确认可通过的尺寸，`high` 为尚未排除的最大尺寸
+#[derive(Debug)]
+pub struct PmtuProber {
+    low: u16,
+    high: u16,
+    next_id: u32,
+    in_flight: Option<(u32, u16, u8)>,
+}
+
+impl Default for PmtuProber {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PmtuProber {
+    pub fn new() -> Self {
+        Self { low: MIN_MTU, high: MAX_MTU, next_id: 0, in_flight: None }
+    }
+
+    /// 重新开始一轮完整的探测
+    pub fn restart(&mut self) {
+        self.low = MIN_MTU;
+        self.high = MAX_MTU;
+        self.in_flight = None;
+    }
+
+    pub fn converged(&self) -> bool {
+        self.high - self.low < PRECISION
+    }
+
+    /// 当前确认可用的最大尺寸
+    pub fn result(&self) -> u16 {
+        self.low
+    }
+
+    /// 下一个要发送的探测；已收敛时返回 None。
+    /// 上一个探测超时重试时会复用同一尺寸
+    pub fn next_probe(&mut self) -> Option<PmtuMessage> {
+        if self.converged() {
+            return None;
+        }
+        let (size, attempts) = match self.in_flight {
+            Some((_, size, attempts)) => (size, attempts),
+            None => ((self.low + self.high).div_ceil(2), 0),
+        };
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.in_flight = Some((id, size, attempts));
+        Some(PmtuMessage::Probe { id, size })
+    }
+
+    /// 收到确认；返回是否与当前探测匹配
+    pub fn on_ack(&mut self, id: u32, size: u16) -> bool {
+        match self.in_flight {
+            Some((expected, probe_size, _)) if expected == id && probe_size == size => {
+                self.low = size;
+                self.in_flight = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 当前探测超时
+    pub fn on_timeout(&mut self) {
+        if let Some((id, size, attempts)) = self.in_flight {
+            if attempts + 1 >= MAX_ATTEMPTS {
+                self.high = size - 1;
+                self.in_flight = None;
+            } else {
+                self.in_flight = Some((id, size, attempts + 1));
+            }
+        }
+    }
+}
+
+/// 在 UDP socket 上设置 DF，使超过路径 MTU 的探测包被丢弃而不是分片
+///
+/// Linux 使用 IP_PMTUDISC_PROBE：设置 DF 但不受内核缓存的 PMTU 影响
+#[cfg(unix)]
+pub fn set_dont_fragment<S: std::os::unix::io::AsRawFd>(socket: &S) -> Result<()> {
+    let fd = socket.as_raw_fd();
+
+    #[cfg(target_os = "linux")]
+    let (level, name, value) = (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE);
+    #[cfg(target_os = "macos")]
+    let (level, name, value) = (libc::IPPROTO_IP, libc::IP_DONTFRAG, 1);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return Err(anyhow!("当前平台不支持设置 DF"));
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        let value: libc::c_int = value;
+        // SAFETY: fd 有效，value 的生命周期覆盖调用
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let probe = PmtuMessage::Probe { id: 7, size: 1200 };
+        let data = probe.encode();
+        assert_eq!(data.len(), 1200);
+        assert!(is_pmtu_message(&data));
+        assert_eq!(PmtuMessage::decode(&data).unwrap(), probe);
+
+        let ack = PmtuMessage::Ack { id: 7, size: 1200 };
+        assert_eq!(PmtuMessage::decode(&ack.encode()).unwrap(), ack);
+
+        // IPv4 包不会被误认为探测消息
+        assert!(!is_pmtu_message(&[0x45, 0, 0, 20]));
+        // 被截断的探测包
+        assert!(PmtuMessage::decode(&data[..1000]).is_err());
+    }
+
+    #[test]
+    fn test_prober_converges() {
+        // 模拟一条路径 MTU（隧道内）为 1372 的链路
+        let path_limit = 1372;
+        let mut prober = PmtuProber::new();
+
+        let mut rounds = 0;
+        while let Some(PmtuMessage::Probe { id, size }) = prober.next_probe() {
+            if size <= path_limit {
+                assert!(prober.on_ack(id, size));
+            } else {
+                prober.on_timeout();
+            }
+            rounds += 1;
+            assert!(rounds < 100);
+        }
+
+        assert!(prober.result() <= path_limit);
+        assert!(path_limit - prober.result() < PRECISION);
+
+        // 过期的确认被忽略
+        prober.restart();
+        let Some(PmtuMessage::Probe { id, size }) = prober.next_probe() else { panic!() };
+        assert!(!prober.on_ack(id.wrapping_add(1), size));
+    }
+}
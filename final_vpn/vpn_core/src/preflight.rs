@@ -0,0 +1,238 @@
+// vpn_core/src/preflight.rs
+// 启动前的权限和环境检查
+//
+// 以前缺少权限或工具时，要等到配置到一半 `ip` / `iptables` 返回非零状态码才失败，只留下一个 exit code，
+// 而且前面的步骤已经改动了系统。现在在修改任何状态之前统一检查：
+//
+// * root 或 CAP_NET_ADMIN（Linux 读取 /proc/self/status 的 CapEff）
+// * /dev/net/tun 是否存在、能否读写（只打开不 ioctl，不会创建设备）
+// * 本次参数会用到的外部命令是否在 PATH（以及 /sbin、/usr/sbin）中
+//
+// 所有问题一次列出，每条附带修复方法；`--skip-preflight` 跳过检查。
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+/// CAP_NET_ADMIN 在能力位图中的编号
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// PATH 之外额外查找的目录（普通用户的 PATH 通常不含 sbin）
+const EXTRA_DIRS: [&str; 4] = ["/sbin", "/usr/sbin", "/usr/local/sbin", "/bin"];
+
+/// 检查发现的一个问题
+#[derive(Debug, Clone, PartialEq)]
+pub struct Issue {
+    /// 为 false 时只提示，不阻止启动
+    pub fatal: bool,
+    pub problem: String,
+    pub fix: String,
+}
+
+/// 需要用到的外部命令
+#[derive(Debug, Clone)]
+struct Tool {
+    program: &'static str,
+    purpose: &'static str,
+    required: bool,
+}
+
+/// 本次启动的检查项
+#[derive(Debug, Clone)]
+pub struct Preflight {
+    /// 挂接预先创建的持久化设备（--tun-reuse）时不要求 root，只给出提示
+    reuse_existing: bool,
+    tools: Vec<Tool>,
+}
+
+impl Preflight {
+    pub fn new(reuse_existing: bool) -> Self {
+        Self { reuse_existing, tools: Vec::new() }
+    }
+
+    /// 缺少时无法启动的命令（只在 Linux 上检查，其他平台使用系统自带的 route / ifconfig）
+    pub fn require(mut self, program: &'static str, purpose: &'static str) -> Self {
+        self.tools.push(Tool { program, purpose, required: true });
+        self
+    }
+
+    /// 缺少时相关功能降级的命令
+    pub fn recommend(mut self, program: &'static str, purpose: &'static str) -> Self {
+        self.tools.push(Tool { program, purpose, required: false });
+        self
+    }
+
+    /// 执行全部检查，返回发现的问题
+    pub fn check(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        self.check_privileges(&mut issues);
+        #[cfg(target_os = "linux")]
+        {
+            check_tun_device(&mut issues);
+            let path = std::env::var("PATH").unwrap_or_default();
+            for tool in &self.tools {
+                if find_program(tool.program, &path).is_none() {
+                    issues.push(Issue {
+                        fatal: tool.required,
+                        problem: format!("找不到命令 {}（{}）", tool.program, tool.purpose),
+                        fix: install_hint(tool.program),
+                    });
+                }
+            }
+        }
+        issues
+    }
+
+    /// 检查并打印结果；有致命问题时返回错误，此时系统尚未被修改
+    pub fn run(&self) -> Result<()> {
+        let issues = self.check();
+        if issues.is_empty() {
+            return Ok(());
+        }
+        print_issues(&issues);
+        let fatal = issues.iter().filter(|i| i.fatal).count();
+        if fatal > 0 {
+            anyhow::bail!("启动前检查发现 {} 个问题，系统未做任何修改（确认无误后可用 --skip-preflight 跳过检查）", fatal)
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn check_privileges(&self, issues: &mut Vec<Issue>) {
+        // SAFETY: geteuid 没有前置条件
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        if std::fs::read_to_string("/proc/self/status").is_ok_and(|status| has_capability(&status, CAP_NET_ADMIN)) {
+            return;
+        }
+        let exe = std::env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| "<程序路径>".to_string());
+        let fix = if cfg!(target_os = "linux") {
+            format!("使用 sudo 运行，或授予能力: sudo setcap cap_net_admin+ep {}", exe)
+        } else {
+            "使用 sudo 运行".to_string()
+        };
+        issues.push(Issue {
+            // 挂接已有设备本身不需要特权，但路由、防火墙等配置仍然需要
+            fatal: !self.reuse_existing,
+            problem: "当前进程既不是 root 也没有 CAP_NET_ADMIN".to_string(),
+            fix,
+        });
+    }
+
+    #[cfg(not(unix))]
+    fn check_privileges(&self, _issues: &mut Vec<Issue>) {}
+}
+
+/// 打印问题列表
+pub fn print_issues(issues: &[Issue]) {
+    println!("🩺 启动前检查:");
+    for issue in issues {
+        println!("   {} {}", if issue.fatal { "❌" } else { "⚠️ " }, issue.problem);
+        println!("      👉 {}", issue.fix);
+    }
+}
+
+/// 命令行是否指定了 --skip-preflight
+pub fn skipped(args: &[String]) -> bool {
+    args.iter().any(|a| a == "--skip-preflight")
+}
+
+#[cfg(target_os = "linux")]
+fn check_tun_device(issues: &mut Vec<Issue>) {
+    let path = Path::new("/dev/net/tun");
+    if !path.exists() {
+        issues.push(Issue {
+            fatal: true,
+            problem: "/dev/net/tun 不存在".to_string(),
+            fix: "加载内核模块: sudo modprobe tun；容器中需要 --device /dev/net/tun".to_string(),
+        });
+        return;
+    }
+    if let Err(e) = std::fs::OpenOptions::new().read(true).write(true).open(path) {
+        issues.push(Issue {
+            fatal: true,
+            problem: format!("无法打开 /dev/net/tun: {}", e),
+            fix: "使用 sudo 运行；容器中还需要 --cap-add NET_ADMIN 和 --device /dev/net/tun".to_string(),
+        });
+    }
+}
+
+/// /proc/self/status 中的 CapEff 是否包含指定能力
+#[cfg(target_os = "linux")]
+fn has_capability(status: &str, cap: u32) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << cap) != 0)
+}
+
+/// 在 PATH 和常见的 sbin 目录中查找可执行文件
+fn find_program(program: &str, path: &str) -> Option<PathBuf> {
+    std::env::split_paths(path)
+        .chain(EXTRA_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// 缺少命令时的安装提示
+fn install_hint(program: &str) -> String {
+    let package = match program {
+        "ip" | "tc" => "iproute2",
+        "iptables" | "ip6tables" => "iptables（使用 nftables 的系统安装 iptables-nft）",
+        "sysctl" => "procps",
+        "resolvectl" => "systemd-resolved",
+        "conntrack" => "conntrack（conntrack-tools）",
+        "upnpc" => "miniupnpc",
+        "clang" => "clang（XDP 程序还需要 libbpf-dev 提供的头文件）",
+        "bpftool" => "bpftool",
+        "curl" => "curl",
+        _ => return format!("安装提供 {} 的软件包", program),
+    };
+    format!("安装 {}，例如: sudo apt install {}", package, package.split('（').next().unwrap_or(package))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_has_capability() {
+        let status = "Name:\tvpn_server\nCapInh:\t0000000000000000\nCapEff:\t0000000000001000\n";
+        assert!(has_capability(status, CAP_NET_ADMIN));
+        assert!(!has_capability(status, 21));
+        assert!(!has_capability("CapEff:\t0000000000000000\n", CAP_NET_ADMIN));
+        assert!(has_capability("CapEff:\t000001ffffffffff\n", CAP_NET_ADMIN));
+        assert!(!has_capability("", CAP_NET_ADMIN));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_program() {
+        assert!(find_program("sh", "").is_some());
+        assert!(find_program("rust-vpn-no-such-tool", "/usr/bin").is_none());
+        assert!(install_hint("iptables").contains("sudo apt install iptables"));
+        assert!(install_hint("tpm2_unseal").contains("tpm2_unseal"));
+
+        let issues = Preflight::new(true).require("rust-vpn-no-such-tool", "测试").recommend("rust-vpn-optional", "测试").check();
+        if cfg!(target_os = "linux") {
+            let missing: Vec<bool> = issues.iter().filter(|i| i.problem.contains("rust-vpn-")).map(|i| i.fatal).collect();
+            assert_eq!(missing, vec![true, false]);
+        }
+    }
+}
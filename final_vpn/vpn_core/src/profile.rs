@@ -0,0 +1,113 @@
+// vpn_core/src/profile.rs
+// 运行档位（--profile gaming|bulk|default）：按"延迟优先"或"吞吐优先"一次调好一组相关参数
+//
+// 批处理深度、FEC、保活周期、上行限速和 socket 缓冲区彼此牵连，单独调整容易顾此失彼。
+// 档位不引入新的解析路径：expand 把它展开成等价的命令行参数，追加在命令行和配置文件展开的参数之后，
+// 所以显式给出的参数总是优先（参数按首次出现取值），档位只填补没有指定的项。
+//
+//   gaming   延迟优先：小批次（4 个包）、FEC 分组 4、保活 10 秒（更快发现断线）、上行自动限速（避免 bufferbloat）
+//   bulk     吞吐优先：大批次（64 个包）、4 MB socket 缓冲区，不使用 FEC 和限速
+//   default  不修改任何参数
+//
+// FEC、保活和限速只在客户端生效，服务端只展开两端共用的参数。
+
+use anyhow::{Result, anyhow};
+
+use crate::engine::Role;
+
+/// --profile 的取值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Default,
+    Gaming,
+    Bulk,
+}
+
+impl Profile {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "default" => Ok(Profile::Default),
+            "gaming" => Ok(Profile::Gaming),
+            "bulk" => Ok(Profile::Bulk),
+            _ => Err(anyhow!("无效的 --profile: {}（gaming、bulk 或 default）", value)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Default => "default",
+            Profile::Gaming => "gaming",
+            Profile::Bulk => "bulk",
+        }
+    }
+
+    /// 档位对应的 (参数, 值, 是否只用于客户端)
+    fn settings(&self) -> &'static [(&'static str, &'static str, bool)] {
+        match self {
+            Profile::Default => &[],
+            Profile::Gaming => &[
+                ("--batch-size", "4", false),
+                ("--fec", "4", true),
+                ("--keepalive", "10", true),
+                ("--pace", "auto", true),
+            ],
+            Profile::Bulk => &[
+                ("--batch-size", "64", false),
+                ("--recv-buffer", "4m", false),
+                ("--send-buffer", "4m", false),
+            ],
+        }
+    }
+
+    /// 展开为命令行参数（只包含本端使用的参数）
+    pub fn to_args(&self, role: Role) -> Vec<String> {
+        self.settings()
+            .iter()
+            .filter(|(_, _, client_only)| role == Role::Client || !client_only)
+            .flat_map(|(name, value, _)| [name.to_string(), value.to_string()])
+            .collect()
+    }
+}
+
+/// 处理 `--profile`：把档位展开的参数追加在最后；没有指定时原样返回
+pub fn expand(mut args: Vec<String>, role: Role) -> Result<Vec<String>> {
+    let Some(value) = args.iter().position(|a| a == "--profile").and_then(|i| args.get(i + 1)) else {
+        return Ok(args);
+    };
+    let profile = Profile::parse(value)?;
+    args.extend(profile.to_args(role));
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert!(Profile::parse("turbo").is_err());
+        assert_eq!(Profile::parse("bulk").unwrap().as_str(), "bulk");
+
+        // 显式给出的参数排在前面，按首次出现取值时优先于档位
+        let args = expand(strings(&["vpn_client", "--batch-size", "8", "--profile", "gaming"]), Role::Client).unwrap();
+        assert_eq!(
+            args,
+            strings(&[
+                "vpn_client", "--batch-size", "8", "--profile", "gaming",
+                "--batch-size", "4", "--fec", "4", "--keepalive", "10", "--pace", "auto",
+            ])
+        );
+
+        // 服务端只展开两端共用的参数
+        let args = expand(strings(&["vpn_server", "--profile", "gaming"]), Role::Server).unwrap();
+        assert_eq!(args, strings(&["vpn_server", "--profile", "gaming", "--batch-size", "4"]));
+
+        let args = strings(&["vpn_client", "--profile", "default"]);
+        assert_eq!(expand(args.clone(), Role::Client).unwrap(), args);
+        assert!(expand(strings(&["vpn_client", "--profile", "fast"]), Role::Client).is_err());
+    }
+}
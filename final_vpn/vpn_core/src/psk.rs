@@ -0,0 +1,261 @@
+// vpn_core/src/psk.rs
+// 预共享密钥（PSK）：从文件加载，以及轮换期间服务端同时接受新旧两个 PSK
+//
+// PSK 与 X25519、ML-KEM 的共享密钥一起派生会话密钥（见 handshake::derive_hybrid_session_key），
+// 两端不一致时握手在 ClientFinish 处失败。没有指定 --psk-file 时两端都使用内置的 DEFAULT_PSK。
+//
+// 文件格式：一行 64 个十六进制字符。客户端在 ClientHello 中带上 PSK ID（PSK 的哈希前 8 字节，不泄露 PSK 本身），
+// 服务端据此选择用哪个 PSK 派生会话密钥。轮换步骤：
+//   1. `vpn_server psk rotate <文件>`：原文件改名为 <文件>.previous，生成新的 PSK 写入 <文件>
+//   2. 服务端以 --psk-file <文件> --previous-psk-file <文件>.previous 重启，宽限期（--psk-grace）内两个 PSK 都接受，
+//      用旧 PSK 连上的客户端会收到 PskUpdated 控制消息
+//   3. 把新文件分发到各客户端的 --psk-file 位置；客户端每次握手时重新读取，不需要重启
+//   4. 宽限期过后旧 PSK 的新握手被拒绝（已建立的会话不受影响）
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// 没有指定 --psk-file 时使用的 PSK（与旧版本内置的相同）
+pub const DEFAULT_PSK: [u8; 32] = *b"0123456789abcdef0123456789abcdef";
+
+/// 旧 PSK 的默认宽限期：7 天
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// PSK ID：随 ClientHello 发送，服务端据此选择 PSK
+pub type PskId = [u8; 8];
+
+/// PSK 的 ID（BLAKE3 派生，不能反推出 PSK）
+pub fn psk_id(psk: &[u8; 32]) -> PskId {
+    let key = blake3::derive_key("rust-vpn 2026 psk id", psk);
+    key[..8].try_into().unwrap()
+}
+
+/// 生成随机的 PSK
+pub fn generate() -> [u8; 32] {
+    let mut psk = [0u8; 32];
+    OsRng.fill_bytes(&mut psk);
+    psk
+}
+
+/// 解析文件内容：64 个十六进制字符，忽略首尾空白
+pub fn parse(text: &str) -> Result<[u8; 32]> {
+    let bytes = hex::decode(text.trim()).map_err(|_| anyhow!("PSK 应为 64 个十六进制字符"))?;
+    bytes.try_into().map_err(|_| anyhow!("PSK 应为 32 字节（64 个十六进制字符）"))
+}
+
+/// 从文件加载 PSK
+pub fn load(path: &Path) -> Result<[u8; 32]> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("无法读取 PSK 文件 {}", path.display()))?;
+    parse(&text).with_context(|| format!("PSK 文件 {} 无效", path.display()))
+}
+
+/// --psk-file 指定的 PSK，没有指定时为内置的 DEFAULT_PSK
+pub fn from_args(args: &[String]) -> Result<[u8; 32]> {
+    match arg_value(args, "--psk-file") {
+        Some(path) => load(Path::new(path)),
+        None => Ok(DEFAULT_PSK),
+    }
+}
+
+/// 写入新的 PSK 文件（权限 0600），文件已存在时报错
+pub fn save(path: &Path, psk: &[u8; 32]) -> Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("无法创建 PSK 文件 {}", path.display()))?;
+    writeln!(file, "{}", hex::encode(psk))?;
+    Ok(())
+}
+
+/// 轮换时旧 PSK 文件的位置：<文件>.previous
+pub fn previous_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".previous");
+    PathBuf::from(name)
+}
+
+/// 服务端接受的 PSK：当前的，以及宽限期内的旧 PSK
+#[derive(Debug, Clone)]
+pub struct PskSet {
+    current: [u8; 32],
+    /// 旧 PSK 和宽限期的截止时间
+    previous: Option<([u8; 32], SystemTime)>,
+}
+
+/// 为一次握手选中的 PSK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectedPsk {
+    pub psk: [u8; 32],
+    /// 是宽限期内的旧 PSK
+    pub previous: bool,
+}
+
+impl PskSet {
+    pub fn new(current: [u8; 32]) -> Self {
+        Self { current, previous: None }
+    }
+
+    /// 旧 PSK 在 until 之前仍然接受
+    pub fn with_previous(self, previous: [u8; 32], until: SystemTime) -> Self {
+        Self { previous: Some((previous, until)), ..self }
+    }
+
+    /// `--psk-file <文件>`、`--previous-psk-file <文件>`、`--psk-grace <秒>`
+    ///
+    /// 宽限期从 --psk-file 的修改时间（即 `psk rotate` 的时间）算起，服务端重启不会延长宽限期
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let current = from_args(args)?;
+        let set = Self::new(current);
+        let Some(previous_file) = arg_value(args, "--previous-psk-file") else {
+            if arg_value(args, "--psk-grace").is_some() {
+                return Err(anyhow!("--psk-grace 需要同时指定 --previous-psk-file"));
+            }
+            return Ok(set);
+        };
+        let Some(current_file) = arg_value(args, "--psk-file") else {
+            return Err(anyhow!("--previous-psk-file 需要同时指定 --psk-file"));
+        };
+        let grace = match arg_value(args, "--psk-grace") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| anyhow!("无效的 --psk-grace: {}（秒）", secs))?),
+            None => DEFAULT_GRACE,
+        };
+        let previous = load(Path::new(previous_file))?;
+        if previous == current {
+            return Err(anyhow!("--previous-psk-file 与 --psk-file 的 PSK 相同"));
+        }
+        let rotated = std::fs::metadata(current_file)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("无法读取 {} 的修改时间", current_file))?;
+        Ok(set.with_previous(previous, rotated + grace))
+    }
+
+    pub fn current_id(&self) -> PskId {
+        psk_id(&self.current)
+    }
+
+    /// 旧 PSK 宽限期的剩余时间；没有旧 PSK 或已过期时为 None
+    pub fn grace_remaining(&self, now: SystemTime) -> Option<Duration> {
+        let (_, until) = self.previous?;
+        until.duration_since(now).ok().filter(|d| !d.is_zero())
+    }
+
+    /// 按 ClientHello 中的 PSK ID 选择 PSK；不带 ID 的旧客户端使用当前 PSK。
+    /// ID 不认识，或者旧 PSK 已过宽限期时返回 None
+    pub fn select(&self, id: Option<PskId>, now: SystemTime) -> Option<SelectedPsk> {
+        let Some(id) = id else {
+            return Some(SelectedPsk { psk: self.current, previous: false });
+        };
+        if id == psk_id(&self.current) {
+            return Some(SelectedPsk { psk: self.current, previous: false });
+        }
+        match self.previous {
+            Some((psk, _)) if id == psk_id(&psk) && self.grace_remaining(now).is_some() => Some(SelectedPsk { psk, previous: true }),
+            _ => None,
+        }
+    }
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
+
+/// `vpn_server psk <generate|rotate|show> <文件>`：生成、轮换和查看 PSK 文件
+pub fn run_command(args: &[String]) -> Result<()> {
+    const USAGE: &str = "用法: vpn_server psk generate <文件> | rotate <文件> | show <文件>";
+    let (Some(action), Some(path)) = (args.get(2), args.get(3)) else {
+        return Err(anyhow!(USAGE));
+    };
+    let path = Path::new(path);
+    match action.as_str() {
+        "generate" => {
+            let psk = generate();
+            save(path, &psk)?;
+            println!("🔑 已生成 PSK: {}（ID {}）", path.display(), hex::encode(psk_id(&psk)));
+            println!("   服务端和客户端都以 --psk-file {} 使用", path.display());
+        }
+        "rotate" => {
+            let old = load(path)?;
+            let previous = previous_path(path);
+            std::fs::rename(path, &previous).with_context(|| format!("无法把 {} 改名为 {}", path.display(), previous.display()))?;
+            let psk = generate();
+            save(path, &psk)?;
+            println!("🔁 已轮换 PSK: {}", path.display());
+            println!("   旧 PSK: {}（ID {}）", previous.display(), hex::encode(psk_id(&old)));
+            println!("   新 PSK: {}（ID {}）", path.display(), hex::encode(psk_id(&psk)));
+            println!("   1. 以 --psk-file {} --previous-psk-file {} 重启服务端，宽限期内新旧 PSK 都接受", path.display(), previous.display());
+            println!("   2. 把 {} 复制到各客户端的 --psk-file 位置（权限 0600），客户端下次握手时使用", path.display());
+        }
+        "show" => {
+            let psk = load(path)?;
+            println!("ID:  {}", hex::encode(psk_id(&psk)));
+            println!("PSK: {}", hex::encode(psk));
+        }
+        _ => return Err(anyhow!(USAGE)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let now = SystemTime::now();
+        let (old, new) = ([1u8; 32], [2u8; 32]);
+        let set = PskSet::new(new).with_previous(old, now + Duration::from_secs(60));
+
+        assert_eq!(set.select(Some(psk_id(&new)), now), Some(SelectedPsk { psk: new, previous: false }));
+        assert_eq!(set.select(Some(psk_id(&old)), now), Some(SelectedPsk { psk: old, previous: true }));
+        // 不带 ID 的旧客户端用当前 PSK
+        assert_eq!(set.select(None, now), Some(SelectedPsk { psk: new, previous: false }));
+        assert_eq!(set.select(Some(psk_id(&[3u8; 32])), now), None);
+        assert!(set.grace_remaining(now).is_some());
+
+        // 宽限期过后只接受新 PSK
+        let later = now + Duration::from_secs(61);
+        assert_eq!(set.select(Some(psk_id(&old)), later), None);
+        assert_eq!(set.select(Some(psk_id(&new)), later).map(|s| s.previous), Some(false));
+        assert_eq!(set.grace_remaining(later), None);
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-psk-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("psk");
+        let psk = generate();
+        save(&path, &psk).unwrap();
+        assert_eq!(load(&path).unwrap(), psk);
+        // 不覆盖已有的文件
+        assert!(save(&path, &generate()).is_err());
+        assert_eq!(previous_path(&path), dir.join("psk.previous"));
+
+        let args = |extra: &[&str]| {
+            let mut args = vec!["--psk-file".to_string(), path.display().to_string()];
+            args.extend(extra.iter().map(|s| s.to_string()));
+            args
+        };
+        assert_eq!(from_args(&args(&[])).unwrap(), psk);
+        assert_eq!(from_args(&[]).unwrap(), DEFAULT_PSK);
+
+        let previous = previous_path(&path);
+        save(&previous, &DEFAULT_PSK).unwrap();
+        let set = PskSet::from_args(&args(&["--previous-psk-file", &previous.display().to_string()])).unwrap();
+        assert!(set.select(Some(psk_id(&DEFAULT_PSK)), SystemTime::now()).is_some_and(|s| s.previous));
+        let set = PskSet::from_args(&args(&["--previous-psk-file", &previous.display().to_string(), "--psk-grace", "0"])).unwrap();
+        assert_eq!(set.select(Some(psk_id(&DEFAULT_PSK)), SystemTime::now()), None);
+        assert!(PskSet::from_args(&args(&["--psk-grace", "60"])).is_err());
+        assert!(PskSet::from_args(&["--previous-psk-file".to_string(), previous.display().to_string()]).is_err());
+
+        std::fs::write(&path, "not hex\n").unwrap();
+        assert!(load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+// vpn_core/src/recursion.rs
+// 隧道递归检测：发往 VPN 端点自身的内层包
+//
+// 客户端：服务器地址的路由例外缺失或被更具体的路由盖过时（手动加了路由、服务器地址变化后例外还没更新），
+// 隧道自己发往服务器的 UDP 包会被路由进 TUN，加密后再发出、再被路由进 TUN……每一轮多一层封装，
+// 直到超过 MTU，期间把上行带宽和 CPU 耗尽。
+// 服务端：客户端把发往服务端监听端口的流量放进隧道（在隧道里再跑一个客户端）时，内层包写入 TUN 后
+// 又回到服务端的 socket，成为隧道里的隧道。
+//
+// 这些包不可能是有用的流量，直接丢弃并计数（recursive_tunnel），不进入加密和转发。
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Command;
+
+use anyhow::Result;
+
+const PROTO_UDP: u8 = 17;
+
+/// 丢包原因（datapath 计数中使用）
+pub const DROP_REASON: &str = "recursive_tunnel";
+
+/// UDP 包的目的地址和端口（IPv6 只识别没有扩展头的包；分片的后续片没有端口，不识别）
+pub fn udp_destination(ip_packet: &[u8]) -> Option<SocketAddr> {
+    match ip_packet.first()? >> 4 {
+        4 => {
+            let ihl = crate::packet::ipv4_header_len(ip_packet)?;
+            let fragment_offset = u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x1fff;
+            if ip_packet[9] != PROTO_UDP || fragment_offset != 0 || ip_packet.len() < ihl + 4 {
+                return None;
+            }
+            let dst = Ipv4Addr::new(ip_packet[16], ip_packet[17], ip_packet[18], ip_packet[19]);
+            Some(SocketAddr::new(dst.into(), u16::from_be_bytes([ip_packet[ihl + 2], ip_packet[ihl + 3]])))
+        }
+        6 => {
+            if ip_packet.len() < 44 || ip_packet[6] != PROTO_UDP {
+                return None;
+            }
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&ip_packet[24..40]).ok()?);
+            Some(SocketAddr::new(dst.into(), u16::from_be_bytes([ip_packet[42], ip_packet[43]])))
+        }
+        _ => None,
+    }
+}
+
+/// 客户端：内层包是否发往服务器端点（隧道自己的包被路由进了 TUN）
+pub fn targets_endpoint(ip_packet: &[u8], endpoint: SocketAddr) -> bool {
+    udp_destination(ip_packet).is_some_and(|dst| dst.port() == endpoint.port() && same_ip(dst.ip(), endpoint.ip()))
+}
+
+/// IPv4 与 IPv4 映射的 IPv6 地址视为同一地址
+fn same_ip(a: IpAddr, b: IpAddr) -> bool {
+    a.to_canonical() == b.to_canonical()
+}
+
+/// 服务端：本机监听的所有端点（监听地址为 0.0.0.0 / :: 时，本机的每个地址加上监听端口）
+#[derive(Debug, Clone)]
+pub struct LocalEndpoints {
+    port: u16,
+    addrs: Vec<IpAddr>,
+}
+
+impl LocalEndpoints {
+    /// listen 为具体地址时只有它自己；否则列出本机接口地址（启动时读取一次），extra 为隧道地址等额外的本机地址
+    pub fn new(listen: SocketAddr, extra: &[IpAddr]) -> Result<Self> {
+        let mut addrs = if listen.ip().is_unspecified() { local_addresses()? } else { vec![listen.ip()] };
+        addrs.extend(extra);
+        Ok(Self::with_addrs(listen.port(), addrs))
+    }
+
+    /// 指定端口和地址（读取本机地址失败时，至少覆盖隧道地址）
+    pub fn with_addrs(port: u16, mut addrs: Vec<IpAddr>) -> Self {
+        addrs.sort();
+        addrs.dedup();
+        Self { port, addrs }
+    }
+
+    /// 内层包是否发往本机的监听端点
+    pub fn matches(&self, ip_packet: &[u8]) -> bool {
+        udp_destination(ip_packet).is_some_and(|dst| dst.port() == self.port && self.addrs.iter().any(|a| same_ip(*a, dst.ip())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+}
+
+/// 本机接口上的所有地址（`ip -o addr show`）
+fn local_addresses() -> Result<Vec<IpAddr>> {
+    let output = Command::new("ip").args(["-o", "addr", "show"]).output()?;
+    Ok(parse_addresses(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// 解析 `ip -o addr show` 的输出：每行 `<序号>: <接口> inet[6] <地址>/<前缀> ...`
+fn parse_addresses(output: &str) -> Vec<IpAddr> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip_while(|f| *f != "inet" && *f != "inet6").skip(1);
+            fields.next()?.split('/').next()?.parse().ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 最小的 IPv4 UDP 包：目的地址 dst，目的端口 port
+    fn udp_v4(dst: [u8; 4], port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[9] = PROTO_UDP;
+        packet[16..20].copy_from_slice(&dst);
+        packet[22..24].copy_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_detect_recursive_packets() {
+        let endpoint: SocketAddr = "203.0.113.10:9000".parse().unwrap();
+        let packet = udp_v4([203, 0, 113, 10], 9000);
+        assert_eq!(udp_destination(&packet), Some(endpoint));
+        assert!(targets_endpoint(&packet, endpoint));
+        assert!(targets_endpoint(&packet, "[::ffff:203.0.113.10]:9000".parse().unwrap()));
+        // 同一服务器上的其他服务、其他协议、分片的后续片都不算
+        assert!(!targets_endpoint(&udp_v4([203, 0, 113, 10], 443), endpoint));
+        let mut tcp = packet.clone();
+        tcp[9] = 6;
+        assert!(!targets_endpoint(&tcp, endpoint));
+        let mut fragment = packet.clone();
+        fragment[7] = 1;
+        assert!(!targets_endpoint(&fragment, endpoint));
+        assert!(udp_destination(&packet[..22]).is_none());
+
+        let local = LocalEndpoints::with_addrs(9000, vec!["10.0.0.1".parse().unwrap(), "198.51.100.2".parse().unwrap()]);
+        assert!(local.matches(&udp_v4([10, 0, 0, 1], 9000)));
+        assert!(local.matches(&udp_v4([198, 51, 100, 2], 9000)));
+        assert!(!local.matches(&udp_v4([203, 0, 113, 10], 9000)));
+    }
+
+    #[test]
+    fn test_parse_addresses() {
+        let output = "\
+1: lo    inet 127.0.0.1/8 scope host lo\\       valid_lft forever preferred_lft forever
+2: eth0    inet 198.51.100.2/24 brd 198.51.100.255 scope global eth0\\       valid_lft forever
+2: eth0    inet6 2001:db8::2/64 scope global \\       valid_lft forever preferred_lft forever
+";
+        let addrs = parse_addresses(output);
+        assert_eq!(addrs, vec![
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            "198.51.100.2".parse().unwrap(),
+            "2001:db8::2".parse().unwrap(),
+        ]);
+    }
+}
@@ -0,0 +1,96 @@
+// vpn_core/src/replay.rs
+// 重放保护：每个会话的 64 位发送序号和滑动接收窗口（RFC 6479 的位图窗口，与 WireGuard 相同）
+//
+// 每个数据包带着发送端的序号，序号和发送方向一起构成 nonce（见 symmetric::Cipher::encrypt_packet），
+// 改动序号会导致解密失败。接收端记住窗口内已收到的序号：
+// * 比收到过的序号都大：接受，窗口前移
+// * 在窗口内且没收到过（链路乱序）：接受
+// * 在窗口内已收到过（链路复制或重放），或落在窗口之前（太旧，无法判断是否收到过）：丢弃
+// 检查必须放在解密（认证）成功之后，伪造的包无法推动窗口。
+// 序号同时是 nonce，被接受过的序号再次出现说明对端（或攻击者）在重复使用 nonce，一律丢弃。
+
+/// 位图的块数（每块 64 位）
+const BLOCKS: usize = 32;
+const BLOCK_BITS: u64 = u64::BITS as u64;
+
+/// 能接受的乱序距离：比最大序号小这么多以内的包仍可接受（保留一个块用于窗口前移时清零）
+pub const WINDOW_SIZE: u64 = (BLOCKS as u64 - 1) * BLOCK_BITS;
+
+/// 接收窗口
+#[derive(Debug, Clone, Default)]
+pub struct ReplayWindow {
+    /// 收到过的最大序号 + 1（0 表示还没有收到过包）
+    highest: u64,
+    bitmap: [u64; BLOCKS],
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一个已通过认证的包的序号；重复或太旧时返回 false
+    pub fn check(&mut self, seq: u64) -> bool {
+        // 内部按 seq + 1 计数，使 highest = 0 可以表示空窗口
+        let Some(seq) = seq.checked_add(1) else {
+            return false;
+        };
+        if seq.saturating_add(WINDOW_SIZE) < self.highest {
+            return false;
+        }
+
+        let index = seq / BLOCK_BITS;
+        if seq > self.highest {
+            // 窗口前移：清空新覆盖的块（最多整个位图）
+            let current = self.highest / BLOCK_BITS;
+            let advance = (index - current).min(BLOCKS as u64);
+            for i in 1..=advance {
+                self.bitmap[((current + i) % BLOCKS as u64) as usize] = 0;
+            }
+            self.highest = seq;
+        }
+
+        let block = &mut self.bitmap[(index % BLOCKS as u64) as usize];
+        let bit = 1u64 << (seq % BLOCK_BITS);
+        let fresh = *block & bit == 0;
+        *block |= bit;
+        fresh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(window.check(0));
+        assert!(!window.check(0));
+
+        // 乱序到达的包在窗口内仍可接受，但只接受一次
+        assert!(window.check(10));
+        assert!(window.check(5));
+        assert!(!window.check(5));
+        assert!(!window.check(10));
+
+        // 大幅前移后，窗口之前的包一律丢弃，边界上的包仍可接受
+        let top = 10 + 3 * WINDOW_SIZE;
+        assert!(window.check(top));
+        assert!(!window.check(11));
+        assert!(window.check(top - WINDOW_SIZE));
+        assert!(!window.check(top - WINDOW_SIZE - 1));
+        assert!(window.check(top - 1));
+        assert!(!window.check(top));
+
+        // 前移时清空的块不会误判为已收到
+        let mut window = ReplayWindow::new();
+        for seq in 0..200 {
+            assert!(window.check(seq));
+        }
+        assert!(window.check(200 + BLOCKS as u64 * BLOCK_BITS));
+        assert!(window.check(200 + BLOCKS as u64 * BLOCK_BITS - 1));
+
+        assert!(!ReplayWindow::new().check(u64::MAX));
+    }
+}
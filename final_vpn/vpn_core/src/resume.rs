@@ -0,0 +1,273 @@
+// vpn_core/src/resume.rs
+// 会话恢复：客户端进程重启（崩溃、升级）后跳过完整握手
+//
+// 服务端按源地址区分会话，客户端重启后换了源端口，以前只能重新完整握手（外部认证时还要重新登录）。
+// 启用 --session-resume 的服务端在会话建立后通过控制通道下发票据（SessionTicket），客户端把票据、
+// 当前会话密钥和服务端地址加密保存在身份目录下；重启后先发 Resume，证明仍持有会话密钥，
+// 服务端把原会话（虚拟 IP、身份、认证结果）迁移到新的源地址并回复 ResumeAck。
+//
+// * 票据只是服务端会话表的索引，本身不含密钥；服务端在会话存活期间和会话结束后 RESUME_TTL 内接受恢复
+// * 证明是用会话密钥加密的时间戳，服务端要求它在 MAX_CLOCK_SKEW 内且严格递增，抓包重放无效
+// * 恢复后双方用 resumed_key 派生新的会话密钥，旧会话的数据包不能重放到新会话
+// * 缓存文件用单独的密钥（session_cache.key）加密，--tpm-seal 时该密钥密封到 TPM
+// * 本机时钟不准时，客户端按 ServerHello 中的服务端时间校正发出的时间戳（server_now），
+//   否则偏差超过 MAX_CLOCK_SKEW 的设备上恢复和多路径加入都会失败
+// * 主动断开（Ctrl+C、服务端 Disconnect）时删除缓存，服务端同时作废票据
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+
+use crate::asymmetric::{read_private, store_private, write_private};
+use crate::symmetric::Cipher;
+use crate::tpm::KeyProtection;
+use crate::wire::{Fields, Writer};
+
+/// 会话结束后票据仍然有效的时间（客户端缓存的有效期也取这个值）
+pub const RESUME_TTL: Duration = Duration::from_secs(300);
+
+/// Resume 证明中的时间戳与服务端时钟允许的最大偏差（秒）
+pub const MAX_CLOCK_SKEW: u64 = 60;
+
+/// 本机时钟与服务端的偏差超过这个值（秒）时警告：再偏一些，未校正的时间戳就会被服务端拒绝
+pub const SKEW_WARN_THRESHOLD: u64 = MAX_CLOCK_SKEW / 2;
+
+/// 本进程记录的时钟偏差：服务端时间 - 本机时间（秒），最近一次握手时更新
+static CLOCK_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// 票据 ID
+pub type TicketId = [u8; 16];
+
+const PROOF_DOMAIN: &[u8] = b"rust-vpn resume proof v1";
+const ACK_DOMAIN: &[u8] = b"rust-vpn resume ack v1";
+const RESUMED_KEY_CONTEXT: &str = "rust-vpn 2024 session resume";
+
+const CACHE_FILE: &str = "session.cache";
+const CACHE_KEY_FILE: &str = "session_cache.key";
+const CACHE_RECORD: u8 = 1;
+
+/// 当前的 Unix 时间（秒）
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// 记录 ServerHello 中的服务端时间，返回本机时钟的偏差（秒，正值表示本机慢于服务端）
+///
+/// 精度为秒，握手往返的耗时（远小于 MAX_CLOCK_SKEW）不做扣除
+pub fn record_server_time(server_time: u64) -> i64 {
+    let offset = clock_offset(server_time, unix_now());
+    CLOCK_OFFSET.store(offset, Ordering::Relaxed);
+    offset
+}
+
+fn clock_offset(server_time: u64, local_time: u64) -> i64 {
+    (server_time as i128 - local_time as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// 按服务端时钟校正后的当前时间：发给服务端校验新鲜度的时间戳（Resume、PathJoin 证明）使用它
+pub fn server_now() -> u64 {
+    unix_now().saturating_add_signed(CLOCK_OFFSET.load(Ordering::Relaxed))
+}
+
+/// 客户端生成 Resume 中的证明：用会话密钥加密 域分隔符 || 票据 || 时间戳
+pub fn resume_proof(session_key: &[u8; 32], ticket: &TicketId, timestamp: u64) -> Result<Vec<u8>> {
+    let mut plaintext = [PROOF_DOMAIN, ticket].concat();
+    plaintext.extend(timestamp.to_be_bytes());
+    Cipher::new(session_key)?.encrypt(&plaintext)
+}
+
+/// 服务端解开 Resume 中的证明，返回其中的时间戳（新鲜度由调用方检查）
+pub fn open_resume_proof(session_key: &[u8; 32], ticket: &TicketId, proof: &[u8]) -> Result<u64> {
+    let plaintext = Cipher::new(session_key)?.decrypt(proof)?;
+    let timestamp = plaintext
+        .strip_prefix(PROOF_DOMAIN)
+        .and_then(|rest| rest.strip_prefix(&ticket[..]))
+        .and_then(|rest| <[u8; 8]>::try_from(rest).ok())
+        .ok_or_else(|| anyhow!("会话恢复证明格式错误"))?;
+    Ok(u64::from_be_bytes(timestamp))
+}
+
+/// 服务端生成 ResumeAck 中的确认值
+pub fn resume_ack(session_key: &[u8; 32], ticket: &TicketId) -> Result<Vec<u8>> {
+    Cipher::new(session_key)?.encrypt(&[ACK_DOMAIN, ticket].concat())
+}
+
+/// 客户端校验 ResumeAck，确认对端确实是持有会话密钥的服务端
+pub fn verify_resume_ack(session_key: &[u8; 32], ticket: &TicketId, proof: &[u8]) -> Result<()> {
+    let plaintext = Cipher::new(session_key)?.decrypt(proof)?;
+    if plaintext != [ACK_DOMAIN, ticket].concat() {
+        return Err(anyhow!("会话恢复确认值不匹配"));
+    }
+    Ok(())
+}
+
+/// 恢复后的会话密钥：由旧密钥和本次 Resume 的证明（含随机 nonce）派生，双方各自计算
+pub fn resumed_key(session_key: &[u8; 32], proof: &[u8]) -> [u8; 32] {
+    let mut material = session_key.to_vec();
+    material.extend_from_slice(proof);
+    blake3::derive_key(RESUMED_KEY_CONTEXT, &material)
+}
+
+/// 客户端缓存的会话
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedSession {
+    pub ticket: TicketId,
+    pub session_key: [u8; 32],
+    /// 会话所在的服务端地址，和本次要连接的地址不同时不使用缓存
+    pub server: SocketAddr,
+    pub virtual_ip: String,
+    /// 保存时间（Unix 秒），会话存活期间定期刷新
+    pub saved_at: u64,
+    pub lifetime_secs: u32,
+}
+
+impl CachedSession {
+    /// 缓存是否仍在有效期内
+    pub fn is_fresh(&self, now: u64) -> bool {
+        now >= self.saved_at && now < self.saved_at + self.lifetime_secs as u64
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        Writer::new(&[], CACHE_RECORD)
+            .bytes(1, &self.ticket)
+            .bytes(2, &self.session_key)
+            .addr(3, self.server)
+            .str(4, &self.virtual_ip)
+            .u64(5, self.saved_at)
+            .u32(6, self.lifetime_secs)
+            .finish()
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let f = Fields::parse(data)?;
+        if f.kind != CACHE_RECORD {
+            return Err(anyhow!("未知的会话缓存格式: {}", f.kind));
+        }
+        Ok(Self {
+            ticket: f.array(1)?,
+            session_key: f.array(2)?,
+            server: f.addr(3)?,
+            virtual_ip: f.string(4)?,
+            saved_at: f.u64(5)?,
+            lifetime_secs: f.u32(6)?,
+        })
+    }
+}
+
+/// 客户端的会话恢复缓存文件（加密保存，权限 600）
+pub struct SessionCache {
+    path: PathBuf,
+    cipher: Cipher,
+}
+
+impl SessionCache {
+    /// 打开身份目录下的缓存；缓存密钥不存在时生成，protection 为 Tpm 时密封到 TPM
+    pub fn open(dir: &Path, protection: KeyProtection) -> Result<Self> {
+        let key_path = dir.join(CACHE_KEY_FILE);
+        let key: [u8; 32] = match read_private(&key_path, protection)? {
+            Some(key) => key
+                .try_into()
+                .map_err(|k: Vec<u8>| anyhow!("会话缓存密钥格式错误：长度应为32字节，实际为{}字节", k.len()))?,
+            None => {
+                fs::create_dir_all(dir)?;
+                let key: [u8; 32] = rand::random();
+                store_private(&key_path, &key, protection)?;
+                key
+            }
+        };
+        Ok(Self { path: dir.join(CACHE_FILE), cipher: Cipher::new(&key)? })
+    }
+
+    /// 读取缓存；不存在、无法解密或格式错误时返回 None（有效期由调用方检查）
+    pub fn load(&self) -> Option<CachedSession> {
+        let data = fs::read(&self.path).ok()?;
+        let plaintext = self.cipher.decrypt(&data).ok()?;
+        CachedSession::decode(&plaintext).ok()
+    }
+
+    pub fn save(&self, session: &CachedSession) -> Result<()> {
+        write_private(&self.path, &self.cipher.encrypt(&session.encode()?)?)
+    }
+
+    /// 删除缓存（主动断开或恢复失败后）
+    pub fn clear(&self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_proof() {
+        let key = [7u8; 32];
+        let ticket = [1u8; 16];
+        let proof = resume_proof(&key, &ticket, 1_700_000_000).unwrap();
+        assert_eq!(open_resume_proof(&key, &ticket, &proof).unwrap(), 1_700_000_000);
+        // 错误的密钥、票据或被篡改的证明都无法通过
+        assert!(open_resume_proof(&[8u8; 32], &ticket, &proof).is_err());
+        assert!(open_resume_proof(&key, &[2u8; 16], &proof).is_err());
+        let mut tampered = proof.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(open_resume_proof(&key, &ticket, &tampered).is_err());
+        // 证明和确认值不能互换
+        let ack = resume_ack(&key, &ticket).unwrap();
+        assert!(verify_resume_ack(&key, &ticket, &ack).is_ok());
+        assert!(verify_resume_ack(&key, &ticket, &proof).is_err());
+        assert!(open_resume_proof(&key, &ticket, &ack).is_err());
+
+        // 每次的证明带随机 nonce，派生出的新密钥也不同
+        let again = resume_proof(&key, &ticket, 1_700_000_000).unwrap();
+        assert_ne!(resumed_key(&key, &proof), resumed_key(&key, &again));
+        assert_ne!(resumed_key(&key, &proof), key);
+    }
+
+    #[test]
+    fn test_clock_offset() {
+        // 不改动进程内记录的偏差（其他测试会生成 PathJoin 证明），只测换算
+        assert_eq!(clock_offset(1_700_000_090, 1_700_000_000), 90);
+        assert_eq!(clock_offset(1_700_000_000, 1_700_000_090), -90);
+        assert_eq!(clock_offset(0, u64::MAX), i64::MIN);
+    }
+
+    #[test]
+    fn test_session_cache() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-resume-{}-{}", std::process::id(), rand::random::<u32>()));
+        let cache = SessionCache::open(&dir, KeyProtection::File).unwrap();
+        assert_eq!(cache.load(), None);
+
+        let session = CachedSession {
+            ticket: [3u8; 16],
+            session_key: [4u8; 32],
+            server: "203.0.113.1:51820".parse().unwrap(),
+            virtual_ip: "10.0.0.2".to_string(),
+            saved_at: 1_700_000_000,
+            lifetime_secs: RESUME_TTL.as_secs() as u32,
+        };
+        cache.save(&session).unwrap();
+        // 重新打开（相当于进程重启）后读到同样的内容，文件中没有明文密钥
+        let reopened = SessionCache::open(&dir, KeyProtection::File).unwrap();
+        assert_eq!(reopened.load(), Some(session.clone()));
+        let raw = fs::read(dir.join(CACHE_FILE)).unwrap();
+        assert!(!raw.windows(32).any(|w| w == session.session_key));
+
+        assert!(session.is_fresh(1_700_000_000));
+        assert!(session.is_fresh(1_700_000_299));
+        assert!(!session.is_fresh(1_700_000_300));
+        assert!(!session.is_fresh(1_699_999_999));
+
+        // 缓存密钥变了（例如被复制到其他机器）时无法解密
+        fs::remove_file(dir.join(CACHE_KEY_FILE)).unwrap();
+        let other = SessionCache::open(&dir, KeyProtection::File).unwrap();
+        assert_eq!(other.load(), None);
+
+        other.clear();
+        assert!(!dir.join(CACHE_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
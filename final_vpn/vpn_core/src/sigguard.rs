@@ -0,0 +1,174 @@
+// vpn_core/src/sigguard.rs
+// 客户端：服务端签名验证失败的限制和告警（--max-signature-failures / --on-signature-failure）
+//
+// ServerHello 的签名验证失败，说明回复不是持有服务端私钥的一方发出的：路径上有中间人，
+// 或者本地的 server_public.key 不属于这台服务端。这两种情况都不会自己消失，
+// 反复重新握手只会不断把 ClientHello 交给对方。SignatureGuard 统计连续的失败次数，
+// 达到上限后打印醒目的安全警告，由客户端按策略停止重试：
+//
+// * exit（默认）：恢复网络配置后以 SIGNATURE_FAILURE_EXIT 退出；多隧道的监督进程看到这个退出码不再重启
+// * hold：保留 TUN 和路由，不再重新握手，直到手动重启。全隧道时流量仍然被导入（已经不通的）隧道，
+//   不会绕过 VPN 明文发出
+//
+// 任何一次握手成功后计数清零。启动时的第一次握手不重试：还没有配置隧道，签名验证失败时两种策略都直接退出。
+
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{Result, anyhow};
+
+/// 默认的连续失败上限
+pub const DEFAULT_MAX_FAILURES: u32 = 3;
+
+/// 因签名验证失败而停止时的退出码（EX_CONFIG：需要人工检查配置或网络）
+pub const SIGNATURE_FAILURE_EXIT: i32 = 78;
+
+/// 达到上限后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnSignatureFailure {
+    /// 恢复网络配置后退出
+    #[default]
+    Exit,
+    /// 保留隧道配置，停止重新握手
+    Hold,
+}
+
+impl OnSignatureFailure {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "exit" => Ok(Self::Exit),
+            "hold" => Ok(Self::Hold),
+            _ => Err(anyhow!("无效的签名验证失败策略: {}（可用: exit / hold）", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Exit => "exit",
+            Self::Hold => "hold",
+        }
+    }
+}
+
+/// ServerHello 签名验证失败（与超时等普通握手错误区分）
+#[derive(Debug)]
+pub struct SignatureError {
+    pub detail: String,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "服务端签名验证失败: {}", self.detail)
+    }
+}
+
+impl Error for SignatureError {}
+
+/// 连续的签名验证失败计数
+#[derive(Debug)]
+pub struct SignatureGuard {
+    max_failures: u32,
+    policy: OnSignatureFailure,
+    failures: AtomicU32,
+}
+
+impl SignatureGuard {
+    pub fn new(max_failures: u32, policy: OnSignatureFailure) -> Self {
+        Self { max_failures: max_failures.max(1), policy, failures: AtomicU32::new(0) }
+    }
+
+    /// 从命令行参数读取
+    ///
+    /// * `--max-signature-failures <次数>`：连续失败多少次后停止，默认 3
+    /// * `--on-signature-failure exit|hold`：停止的方式，默认 exit
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let max_failures = match arg_value(args, "--max-signature-failures") {
+            Some(v) => v.parse().ok().filter(|n| *n > 0).ok_or_else(|| anyhow!("无效的 --max-signature-failures: {}", v))?,
+            None => DEFAULT_MAX_FAILURES,
+        };
+        let policy = match arg_value(args, "--on-signature-failure") {
+            Some(v) => OnSignatureFailure::parse(v)?,
+            None => OnSignatureFailure::default(),
+        };
+        Ok(Self::new(max_failures, policy))
+    }
+
+    pub fn policy(&self) -> OnSignatureFailure {
+        self.policy
+    }
+
+    /// 握手成功，计数清零
+    pub fn reset(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// 记录一次失败并打印警告；达到上限时返回 true，调用方应停止重试
+    pub fn record_failure(&self, error: &SignatureError) -> bool {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!("🚨 {}（连续 {}/{} 次）", error, failures, self.max_failures);
+        failures >= self.max_failures
+    }
+
+    /// 是否已经达到上限
+    pub fn tripped(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) >= self.max_failures
+    }
+
+    /// 停止前打印的安全警告
+    pub fn alert(&self, server: &str) {
+        let action = match self.policy {
+            OnSignatureFailure::Exit => "客户端将恢复网络配置并退出，不会自动重连",
+            OnSignatureFailure::Hold => "隧道保持配置但不再重新握手，流量不会绕过 VPN 发出；检查后请手动重启客户端",
+        };
+        eprintln!();
+        eprintln!("🚨🚨🚨 安全警告：服务器 {} 的 ServerHello 签名连续 {} 次验证失败", server, self.failures.load(Ordering::Relaxed));
+        eprintln!("🚨🚨🚨 可能有人在冒充服务端（中间人攻击），或者本地保存的服务端公钥（server_public.key）不是这台服务端的");
+        eprintln!("🚨🚨🚨 {}", action);
+        eprintln!("🚨🚨🚨 确认服务端公钥的指纹（vpn_server 启动时打印）与本地一致后再连接");
+        eprintln!();
+    }
+}
+
+impl Default for SignatureGuard {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FAILURES, OnSignatureFailure::default())
+    }
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_guard_trips_after_consecutive_failures() {
+        let guard = SignatureGuard::from_args(&strings(&["--max-signature-failures", "2", "--on-signature-failure", "hold"])).unwrap();
+        assert_eq!(guard.policy(), OnSignatureFailure::Hold);
+        let error = SignatureError { detail: "Signature verification failed".into() };
+
+        assert!(!guard.record_failure(&error));
+        // 成功的握手清零，之后重新计数
+        guard.reset();
+        assert!(!guard.record_failure(&error));
+        assert!(!guard.tripped());
+        assert!(guard.record_failure(&error));
+        assert!(guard.tripped());
+    }
+
+    #[test]
+    fn test_from_args() {
+        let guard = SignatureGuard::from_args(&[]).unwrap();
+        assert_eq!((guard.max_failures, guard.policy()), (DEFAULT_MAX_FAILURES, OnSignatureFailure::Exit));
+        assert!(SignatureGuard::from_args(&strings(&["--max-signature-failures", "0"])).is_err());
+        assert!(SignatureGuard::from_args(&strings(&["--on-signature-failure", "retry"])).is_err());
+        assert_eq!(OnSignatureFailure::parse(OnSignatureFailure::Hold.as_str()).unwrap(), OnSignatureFailure::Hold);
+    }
+}
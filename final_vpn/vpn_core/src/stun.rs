@@ -0,0 +1,197 @@
+// vpn_core/src/stun.rs
+// 公网映射地址（server reflexive address）和 NAT 映射行为
+//
+// 会话建立后服务端通过 ControlMessage::ObservedAddr 告诉客户端它看到的来源地址和端口。
+// 只有一个观察点时只能判断有没有 NAT、端口是否保持；客户端可以再用同一个 socket
+// 向公共 STUN 服务器（RFC 5389 Binding）查询一次，两个目的地看到的映射不同即为对称型 NAT
+// （映射依赖目的地址，之后的 UDP 打洞基本无法成功）。
+//
+// 这里只实现 Binding 请求/响应中用到的部分，不支持认证和 FINGERPRINT。
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// STUN 魔数（RFC 5389）
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+/// 事务 ID
+pub type TransactionId = [u8; 12];
+
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+/// 生成一个 Binding 请求，返回事务 ID 和报文
+pub fn binding_request() -> (TransactionId, Vec<u8>) {
+    let id: TransactionId = rand::random();
+    (id, header(BINDING_REQUEST, 0, &id))
+}
+
+/// 生成 Binding 成功响应（XOR-MAPPED-ADDRESS），用于测试和之后的打洞协调
+pub fn binding_response(id: &TransactionId, mapped: SocketAddr) -> Vec<u8> {
+    let mut value = vec![0, 0];
+    value.extend((mapped.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+    match mapped.ip() {
+        IpAddr::V4(ip) => {
+            value[1] = FAMILY_IPV4;
+            value.extend((u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value[1] = FAMILY_IPV6;
+            value.extend(ip.octets().iter().zip(xor_key(id)).map(|(b, k)| b ^ k));
+        }
+    }
+    let mut out = header(BINDING_SUCCESS, 4 + value.len() as u16, id);
+    out.extend(ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+    out.extend((value.len() as u16).to_be_bytes());
+    out.extend(value);
+    out
+}
+
+/// 是否像一个 STUN 报文（前两位为 0 且带魔数），用于和隧道数据区分
+pub fn is_stun(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data[0] & 0xC0 == 0 && data[4..8] == MAGIC_COOKIE.to_be_bytes()
+}
+
+/// 解析 Binding 成功响应，返回事务 ID 和映射地址（优先 XOR-MAPPED-ADDRESS）
+pub fn parse_binding_response(data: &[u8]) -> Option<(TransactionId, SocketAddr)> {
+    if !is_stun(data) || u16::from_be_bytes([data[0], data[1]]) != BINDING_SUCCESS {
+        return None;
+    }
+    let id: TransactionId = data[8..HEADER_LEN].try_into().ok()?;
+    let body_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let mut attrs = data.get(HEADER_LEN..HEADER_LEN + body_len)?;
+
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs.get(4..4 + len)?;
+        match kind {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&id)).map(|addr| (id, addr)),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // 属性按 4 字节对齐
+        attrs = attrs.get((4 + len).next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped.map(|addr| (id, addr))
+}
+
+/// NAT 的映射行为
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMapping {
+    /// 看到的就是本机地址，没有 NAT
+    None,
+    /// 端口保持不变（通常是锥型 NAT，打洞容易成功）
+    PortPreserving,
+    /// 端口被改写，但不同目的地看到同一个映射（或只有一个观察点）
+    PortTranslated,
+    /// 不同目的地看到不同的映射：对称型 NAT
+    Symmetric,
+}
+
+impl NatMapping {
+    /// 由本机地址、服务端看到的地址和（可选的）STUN 服务器看到的地址判断映射行为
+    pub fn classify(local: SocketAddr, observed: SocketAddr, second: Option<SocketAddr>) -> Self {
+        if second.is_some_and(|s| s != observed) {
+            NatMapping::Symmetric
+        } else if local == observed {
+            NatMapping::None
+        } else if local.port() == observed.port() {
+            NatMapping::PortPreserving
+        } else {
+            NatMapping::PortTranslated
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            NatMapping::None => "无 NAT（公网地址）",
+            NatMapping::PortPreserving => "NAT，端口保持",
+            NatMapping::PortTranslated => "NAT，端口改写",
+            NatMapping::Symmetric => "对称型 NAT（映射随目的地址变化，难以打洞）",
+        }
+    }
+}
+
+fn header(kind: u16, len: u16, id: &TransactionId) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + len as usize);
+    out.extend(kind.to_be_bytes());
+    out.extend(len.to_be_bytes());
+    out.extend(MAGIC_COOKIE.to_be_bytes());
+    out.extend(id);
+    out
+}
+
+/// IPv6 地址的异或密钥：魔数 || 事务 ID
+fn xor_key(id: &TransactionId) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    key[4..].copy_from_slice(id);
+    key
+}
+
+/// 解析 (XOR-)MAPPED-ADDRESS 的值；xor 为 Some 时按 XOR-MAPPED-ADDRESS 还原
+fn parse_address(value: &[u8], xor: Option<&TransactionId>) -> Option<SocketAddr> {
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    if xor.is_some() {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+    }
+    let ip = match value[1] {
+        FAMILY_IPV4 => {
+            let mut ip = u32::from_be_bytes(value.get(4..8)?.try_into().ok()?);
+            if xor.is_some() {
+                ip ^= MAGIC_COOKIE;
+            }
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        FAMILY_IPV6 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            if let Some(id) = xor {
+                octets.iter_mut().zip(xor_key(id)).for_each(|(b, k)| *b ^= k);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binding_roundtrip() {
+        let (id, request) = binding_request();
+        assert_eq!(request.len(), HEADER_LEN);
+        assert!(is_stun(&request));
+        // 隧道数据（随机 nonce 开头）和握手消息不会被误认
+        assert!(!is_stun(&[0x45; 40]));
+        assert!(parse_binding_response(&request).is_none());
+
+        for mapped in ["203.0.113.7:40123", "[2001:db8::1]:5000"] {
+            let mapped: SocketAddr = mapped.parse().unwrap();
+            assert_eq!(parse_binding_response(&binding_response(&id, mapped)), Some((id, mapped)));
+        }
+
+        // 只有 MAPPED-ADDRESS 的旧式响应（RFC 3489）
+        let mut legacy = header(BINDING_SUCCESS, 12, &id);
+        legacy.extend([0, 1, 0, 8, 0, FAMILY_IPV4, 0x1F, 0x90, 198, 51, 100, 2]);
+        assert_eq!(parse_binding_response(&legacy), Some((id, "198.51.100.2:8080".parse().unwrap())));
+    }
+
+    #[test]
+    fn test_nat_mapping() {
+        let local: SocketAddr = "192.168.1.10:50000".parse().unwrap();
+        let public: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let translated: SocketAddr = "203.0.113.7:61000".parse().unwrap();
+        assert_eq!(NatMapping::classify(local, local, None), NatMapping::None);
+        assert_eq!(NatMapping::classify(local, public, None), NatMapping::PortPreserving);
+        assert_eq!(NatMapping::classify(local, translated, Some(translated)), NatMapping::PortTranslated);
+        assert_eq!(NatMapping::classify(local, translated, Some(public)), NatMapping::Symmetric);
+    }
+}
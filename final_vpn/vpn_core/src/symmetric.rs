@@ -1,19 +1,52 @@
 // src/symmetric.rs
 
-use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng},
-    ChaCha20Poly1305, Nonce
-};
 use anyhow::{Result, anyhow};
 
+use crate::crypto::{AeadBackend, Backend, NONCE_SIZE, TAG_SIZE};
+use crate::kdf::DataKeys;
+use crate::wire::{PacketType, packet_header};
+
+const DATA_HEADER: [u8; crate::wire::PACKET_HEADER_LEN] = packet_header(PacketType::Data);
+
 // 定义密钥长度为 32 字节
-pub const KEY_SIZE: usize = 32;
-// ChaCha20Poly1305 的 Nonce 长度通常是 12 字节 (96 bits)
-const NONCE_SIZE: usize = 12;
+pub const KEY_SIZE: usize = crate::crypto::KEY_SIZE;
+// 数据包中发送序号的长度（见 replay 模块），序号同时是 nonce 的后 8 字节
+pub const SEQ_SIZE: usize = 8;
+// 加密后每个数据包增加的字节数：数据报头 (3 bytes) + 序号 (8 bytes) + Poly1305 Tag (16 bytes)
+pub const OVERHEAD: usize = crate::wire::PACKET_HEADER_LEN + SEQ_SIZE + TAG_SIZE;
+
+/// 数据包的发送方向，构成 nonce 的前 4 字节
+///
+/// 两个方向各有自己的 AEAD 密钥（见 PacketKeys），各自的序号都从 0 开始；nonce 中的方向是额外的一层隔离
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 客户端发往服务端
+    ToServer = 1,
+    /// 服务端发往客户端
+    ToClient = 2,
+}
+
+impl Direction {
+    /// 数据包的 nonce：[方向 (4 bytes)] + [序号 (8 bytes)]，都是大端序
+    fn nonce(self, seq: u64) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&(self as u32).to_be_bytes());
+        nonce[4..].copy_from_slice(&seq.to_be_bytes());
+        nonce
+    }
+
+    /// 对端的发送方向
+    fn reverse(self) -> Self {
+        match self {
+            Direction::ToServer => Direction::ToClient,
+            Direction::ToClient => Direction::ToServer,
+        }
+    }
+}
 
 pub struct Cipher {
-    // 内部保存加密算法的实例
-    inner: ChaCha20Poly1305,
+    // 内部保存加密算法的实例（编译时选定的后端，见 crypto 模块）
+    inner: Backend,
 }
 
 impl Cipher {
@@ -25,24 +58,23 @@ impl Cipher {
         }
         
         // 初始化 ChaCha20Poly1305
-        let key = chacha20poly1305::Key::from_slice(key_bytes);
-        let inner = ChaCha20Poly1305::new(key);
+        let key: &[u8; KEY_SIZE] = key_bytes.try_into()?;
+        let inner = Backend::new(key);
 
         Ok(Self { inner })
     }
 
-    /// 加密数据
+    /// 加密数据（握手消息、会话恢复证明、本地缓存等一次性的消息，数据包用 encrypt_packet）
     /// 返回格式: [Nonce (12 bytes)] + [Ciphertext (data + tag)]
     pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
         // 1. 生成一个随机的 Nonce
         // 注意：对于同一个 Key，Nonce 绝对不能重复，否则密钥会被攻破。
-        // 这里我们对每个包使用随机生成的 Nonce。
-        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // 这类消息很少，随机 Nonce 与数据包的计数器 Nonce（前 4 字节为方向）重复的概率可以忽略。
+        let nonce: [u8; NONCE_SIZE] = rand::random();
 
         // 2. 执行加密
-        // encrypt 函数会返回 Vec<u8>，包含加密后的数据和 Poly1305 MAC Tag
-        let ciphertext = self.inner.encrypt(&nonce, plaintext)
-            .map_err(|_| anyhow!("Encryption failed"))?;
+        // seal 返回 Vec<u8>，包含加密后的数据和 Poly1305 MAC Tag
+        let ciphertext = self.inner.seal(&nonce, plaintext)?;
 
         // 3. 拼接结果：Nonce 在前，密文在后
         // 接收端需要先读取 Nonce 才能解密
@@ -53,6 +85,25 @@ impl Cipher {
         Ok(packet)
     }
 
+    /// 加密一个直接发到 UDP 上的数据包，seq 为本方向的发送序号，nonce 由方向和序号组成
+    /// 返回格式: [数据报头 (3 bytes)] + [序号 (8 bytes)] + [Ciphertext (data + tag)]，
+    /// 接收端用 wire::classify 去掉报头后再 decrypt_packet
+    ///
+    /// 同一个密钥、同一个方向上的序号绝对不能重复，调用方保证序号单调递增
+    pub fn encrypt_packet(&self, direction: Direction, seq: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        // u64::MAX 留给接收窗口表示溢出，序号用尽时只能重新握手换密钥
+        if seq == u64::MAX {
+            return Err(anyhow!("Sequence number exhausted"));
+        }
+        let ciphertext = self.inner.seal(&direction.nonce(seq), plaintext)?;
+
+        let mut packet = Vec::with_capacity(DATA_HEADER.len() + SEQ_SIZE + ciphertext.len());
+        packet.extend_from_slice(&DATA_HEADER);
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&ciphertext);
+        Ok(packet)
+    }
+
     /// 解密数据
     /// 输入格式必须是: [Nonce (12 bytes)] + [Ciphertext]
     pub fn decrypt(&self, encrypted_data: &[u8]) -> Result<Vec<u8>> {
@@ -61,16 +112,150 @@ impl Cipher {
         }
 
         // 1. 提取 Nonce (前 12 字节)
-        let nonce_bytes = &encrypted_data[..NONCE_SIZE];
-        let nonce = Nonce::from_slice(nonce_bytes);
+        let (nonce, ciphertext) = encrypted_data.split_at(NONCE_SIZE);
+        let nonce: &[u8; NONCE_SIZE] = nonce.try_into()?;
 
-        // 2. 提取真正的密文部分
-        let ciphertext = &encrypted_data[NONCE_SIZE..];
-
-        // 3. 执行解密
-        let plaintext = self.inner.decrypt(nonce, ciphertext)
-            .map_err(|_| anyhow!("Decryption failed (invalid key or tampered data)"))?;
+        // 2. 执行解密
+        let plaintext = self.inner.open(nonce, ciphertext)?;
 
         Ok(plaintext)
     }
-}
\ No newline at end of file
+
+    /// 解密对端按 direction 方向用 encrypt_packet 生成的数据包（已去掉数据报头），返回发送序号和明文
+    /// 序号是 nonce 的一部分，被改动时解密失败；是否重复要由调用方用 replay::ReplayWindow 检查
+    pub fn decrypt_packet(&self, direction: Direction, body: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let Some((seq, ciphertext)) = body.split_first_chunk::<SEQ_SIZE>() else {
+            return Err(anyhow!("Data too short"));
+        };
+        let seq = u64::from_be_bytes(*seq);
+        let plaintext = self.inner.open(&direction.nonce(seq), ciphertext)?;
+        Ok((seq, plaintext))
+    }
+}
+
+/// 一个会话一端的数据包密钥（由会话密钥展开，见 kdf::DataKeys）：发送和接收各用一个方向的 AEAD 密钥，
+/// 报文中的序号用混淆密钥遮盖
+///
+/// 序号混淆与 QUIC 的报头保护相同：掩码 = BLAKE3-keyed(混淆密钥, 密文的前 16 字节) 的前 8 字节，与序号异或。
+/// 链路上的观察者看不到序号，无法据此统计包量或关联漫游前后的同一会话；改动遮盖后的序号仍会导致解密失败。
+pub struct PacketKeys {
+    tx: Cipher,
+    rx: Cipher,
+    header: [u8; KEY_SIZE],
+    /// 本端的发送方向
+    direction: Direction,
+}
+
+impl PacketKeys {
+    /// direction 为本端的发送方向：客户端为 ToServer，服务端为 ToClient
+    pub fn new(keys: &DataKeys, direction: Direction) -> Result<Self> {
+        let (tx, rx) = match direction {
+            Direction::ToServer => (&keys.to_server, &keys.to_client),
+            Direction::ToClient => (&keys.to_client, &keys.to_server),
+        };
+        Ok(Self { tx: Cipher::new(tx)?, rx: Cipher::new(rx)?, header: keys.header, direction })
+    }
+
+    /// 由会话密钥展开
+    pub fn from_session_key(session_key: &[u8; KEY_SIZE], direction: Direction) -> Result<Self> {
+        Self::new(&DataKeys::expand(session_key), direction)
+    }
+
+    /// 加密一个发往对端的数据包，格式同 Cipher::encrypt_packet，序号被遮盖
+    pub fn encrypt_packet(&self, seq: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut packet = self.tx.encrypt_packet(self.direction, seq, plaintext)?;
+        let (seq_bytes, ciphertext) = packet[crate::wire::PACKET_HEADER_LEN..].split_at_mut(SEQ_SIZE);
+        xor(seq_bytes, &self.mask(ciphertext));
+        Ok(packet)
+    }
+
+    /// 解密对端发来的、去掉数据报头后的 [遮盖的序号][密文]，返回序号和明文
+    pub fn decrypt_packet(&self, body: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let Some((masked, ciphertext)) = body.split_first_chunk::<SEQ_SIZE>() else {
+            return Err(anyhow!("Data too short"));
+        };
+        if ciphertext.len() < TAG_SIZE {
+            return Err(anyhow!("Data too short"));
+        }
+        let mut seq = *masked;
+        xor(&mut seq, &self.mask(ciphertext));
+        let seq = u64::from_be_bytes(seq);
+        let plaintext = self.rx.inner.open(&self.direction.reverse().nonce(seq), ciphertext)?;
+        Ok((seq, plaintext))
+    }
+
+    /// 序号的掩码，取自密文开头（至少有 16 字节的 tag）
+    fn mask(&self, ciphertext: &[u8]) -> [u8; SEQ_SIZE] {
+        let hash = blake3::keyed_hash(&self.header, &ciphertext[..TAG_SIZE]);
+        hash.as_bytes()[..SEQ_SIZE].try_into().unwrap()
+    }
+}
+
+fn xor(target: &mut [u8], mask: &[u8; SEQ_SIZE]) {
+    for (byte, m) in target.iter_mut().zip(mask) {
+        *byte ^= m;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_nonce() {
+        let cipher = Cipher::new(&[3u8; KEY_SIZE]).unwrap();
+        let packet = cipher.encrypt_packet(Direction::ToServer, 42, b"payload").unwrap();
+        assert_eq!(packet.len(), OVERHEAD + b"payload".len());
+        let body = &packet[crate::wire::PACKET_HEADER_LEN..];
+        assert_eq!(&body[..SEQ_SIZE], &42u64.to_be_bytes());
+        assert_eq!(cipher.decrypt_packet(Direction::ToServer, body).unwrap(), (42, b"payload".to_vec()));
+
+        // 两个方向上相同的序号得到不同的 nonce，互相不能解密
+        let reverse = cipher.encrypt_packet(Direction::ToClient, 42, b"payload").unwrap();
+        assert_ne!(reverse, packet);
+        assert!(cipher.decrypt_packet(Direction::ToClient, body).is_err());
+
+        // 改动序号等于改动 nonce，解密失败
+        let mut tampered = body.to_vec();
+        tampered[SEQ_SIZE - 1] ^= 1;
+        assert!(cipher.decrypt_packet(Direction::ToServer, &tampered).is_err());
+        assert!(cipher.decrypt_packet(Direction::ToServer, &body[..4]).is_err());
+
+        assert!(cipher.encrypt_packet(Direction::ToServer, u64::MAX, b"payload").is_err());
+    }
+
+    #[test]
+    fn test_packet_keys() {
+        let session_key = [5u8; KEY_SIZE];
+        let client = PacketKeys::from_session_key(&session_key, Direction::ToServer).unwrap();
+        let server = PacketKeys::from_session_key(&session_key, Direction::ToClient).unwrap();
+
+        let packet = client.encrypt_packet(42, b"payload").unwrap();
+        assert_eq!(packet.len(), OVERHEAD + b"payload".len());
+        let body = &packet[crate::wire::PACKET_HEADER_LEN..];
+        // 链路上看不到序号
+        assert_ne!(&body[..SEQ_SIZE], &42u64.to_be_bytes());
+        assert_eq!(server.decrypt_packet(body).unwrap(), (42, b"payload".to_vec()));
+        // 自己发出的包不能用自己的接收密钥解开（两个方向的密钥不同）
+        assert!(client.decrypt_packet(body).is_err());
+
+        let reply = server.encrypt_packet(42, b"payload").unwrap();
+        assert_ne!(reply, packet);
+        assert_eq!(client.decrypt_packet(&reply[crate::wire::PACKET_HEADER_LEN..]).unwrap(), (42, b"payload".to_vec()));
+
+        // 发送方向的密钥就是展开出的方向密钥
+        let keys = DataKeys::expand(&session_key);
+        let raw = Cipher::new(&keys.to_server).unwrap();
+        let mut unmasked = body.to_vec();
+        xor(&mut unmasked[..SEQ_SIZE], &client.mask(&body[SEQ_SIZE..]));
+        assert_eq!(raw.decrypt_packet(Direction::ToServer, &unmasked).unwrap().1, b"payload");
+
+        // 改动遮盖后的序号或密文都解密失败
+        for index in [0, SEQ_SIZE, body.len() - 1] {
+            let mut tampered = body.to_vec();
+            tampered[index] ^= 1;
+            assert!(server.decrypt_packet(&tampered).is_err());
+        }
+        assert!(server.decrypt_packet(&body[..SEQ_SIZE + TAG_SIZE - 1]).is_err());
+    }
+}
@@ -0,0 +1,531 @@
+// vpn_core/src/telemetry.rs
+// 可选的 OpenTelemetry 导出（OTLP/HTTP JSON）：握手阶段 span、数据面采样、计数器
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// 待导出 span 的队列深度（满了直接丢弃，不阻塞数据面）
+const SPAN_QUEUE_DEPTH: usize = 4096;
+/// 导出周期
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// 默认的数据面采样率：每 N 个包采样一个
+pub const DEFAULT_SAMPLE_EVERY: u64 = 1000;
+
+/// 已结束的 span，等待导出
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub name: String,
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub start_unix_nanos: u64,
+    pub end_unix_nanos: u64,
+    pub attributes: Vec<(String, String)>,
+    pub error: Option<String>,
+}
+
+/// 进程级计数器（无论是否启用导出都会累计）
+#[derive(Default)]
+pub struct Metrics {
+    pub handshakes_started: AtomicU64,
+    pub handshakes_completed: AtomicU64,
+    pub handshakes_failed: AtomicU64,
+    pub packets_forwarded: AtomicU64,
+    pub bytes_forwarded: AtomicU64,
+    pub packets_dropped: AtomicU64,
+    /// 握手/认证/会话校验阶段被拒绝的请求（原因见服务端 denials 报告）
+    pub denials: AtomicU64,
+    /// 有界队列（待处理的认证、计费上报、span 导出等）已满而丢弃的条目
+    pub queue_drops: AtomicU64,
+    /// 服务端处理一次 ClientHello 的耗时（收到请求到发出 ServerHello）
+    pub handshake_latency: LatencyHistogram,
+}
+
+impl Metrics {
+    /// 计数器 +1
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 导出用的快照：(指标名, 当前值)
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("vpn.handshakes.started", self.handshakes_started.load(Ordering::Relaxed)),
+            ("vpn.handshakes.completed", self.handshakes_completed.load(Ordering::Relaxed)),
+            ("vpn.handshakes.failed", self.handshakes_failed.load(Ordering::Relaxed)),
+            ("vpn.packets.forwarded", self.packets_forwarded.load(Ordering::Relaxed)),
+            ("vpn.bytes.forwarded", self.bytes_forwarded.load(Ordering::Relaxed)),
+            ("vpn.packets.dropped", self.packets_dropped.load(Ordering::Relaxed)),
+            ("vpn.denials", self.denials.load(Ordering::Relaxed)),
+            ("vpn.queue.dropped", self.queue_drops.load(Ordering::Relaxed)),
+        ]
+    }
+
+    /// 导出用的瞬时值：(指标名, 当前值)，握手延迟的分位数以微秒为单位
+    pub fn gauges(&self) -> Vec<(&'static str, u64)> {
+        [("vpn.handshake.latency.p50_us", 0.5), ("vpn.handshake.latency.p90_us", 0.9), ("vpn.handshake.latency.p99_us", 0.99)]
+            .into_iter()
+            .filter_map(|(name, q)| self.handshake_latency.percentile(q).map(|d| (name, d.as_micros() as u64)))
+            .collect()
+    }
+}
+
+/// 延迟直方图的桶数：上界按 2^(i/4) 微秒增长，最后一个桶约 16 秒，之后的都计入最后一个桶
+const LATENCY_BUCKETS: usize = 97;
+
+/// 无锁的延迟直方图（进程启动以来累计）
+///
+/// 相邻桶的上界相差约 19%，分位数取所在桶的上界，精度足够比较优化前后的差异
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self { buckets: std::array::from_fn(|_| AtomicU64::new(0)) }
+    }
+}
+
+impl LatencyHistogram {
+    /// 第 i 个桶的上界（微秒）
+    fn bound(i: usize) -> u64 {
+        2f64.powf(i as f64 / 4.0).round() as u64
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let i = (0..LATENCY_BUCKETS).find(|&i| micros <= Self::bound(i)).unwrap_or(LATENCY_BUCKETS - 1);
+        Metrics::incr(&self.buckets[i]);
+    }
+
+    /// 已记录的次数
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    /// 分位数（q 取 0~1）；还没有记录时返回 None
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        let counts: Vec<u64> = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        let i = counts.iter().position(|c| {
+            seen += c;
+            seen >= rank
+        })?;
+        Some(Duration::from_micros(Self::bound(i)))
+    }
+}
+
+/// 遥测句柄，可以廉价克隆到各个任务中
+#[derive(Clone)]
+pub struct Telemetry {
+    metrics: Arc<Metrics>,
+    exporter: Option<mpsc::Sender<SpanData>>,
+    sample_every: u64,
+    sample_counter: Arc<AtomicU64>,
+}
+
+impl Telemetry {
+    /// 不导出任何数据，只保留本地计数器
+    pub fn disabled() -> Self {
+        Self {
+            metrics: Arc::new(Metrics::default()),
+            exporter: None,
+            sample_every: DEFAULT_SAMPLE_EVERY,
+            sample_counter: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// 启用 OTLP 导出并启动后台导出任务（必须在 tokio 运行时中调用）
+    ///
+    /// * `endpoint`: collector 的 OTLP/HTTP 地址，例如 "http://127.0.0.1:4318"
+    /// * `service_name`: 上报的 service.name（"vpn_server" / "vpn_client"）
+    pub fn with_otlp(endpoint: &str, service_name: &str, sample_every: u64) -> Result<Self> {
+        let endpoint = HttpEndpoint::parse(endpoint)?;
+        let (tx, rx) = mpsc::channel(SPAN_QUEUE_DEPTH);
+        let metrics = Arc::new(Metrics::default());
+
+        tokio::spawn(run_exporter(endpoint, service_name.to_string(), rx, metrics.clone()));
+
+        Ok(Self {
+            metrics,
+            exporter: Some(tx),
+            sample_every: sample_every.max(1),
+            sample_counter: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// 根据命令行参数和 OTEL_EXPORTER_OTLP_ENDPOINT 环境变量决定是否启用导出
+    pub fn from_env_or_arg(arg: Option<&str>, service_name: &str) -> Self {
+        let endpoint = arg
+            .map(|s| s.to_string())
+            .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok());
+
+        match endpoint {
+            Some(ep) => match Self::with_otlp(&ep, service_name, DEFAULT_SAMPLE_EVERY) {
+                Ok(t) => {
+                    println!("📈 OTLP 导出已启用: {}", ep);
+                    t
+                }
+                Err(e) => {
+                    eprintln!("⚠️  OTLP 导出配置无效，已禁用: {}", e);
+                    Self::disabled()
+                }
+            },
+            None => Self::disabled(),
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn is_exporting(&self) -> bool {
+        self.exporter.is_some()
+    }
+
+    /// 开启一个新的根 span
+    pub fn start_span(&self, name: &str) -> Span {
+        if self.exporter.is_none() {
+            return Span { telemetry: self.clone(), data: None };
+        }
+
+        let mut trace_id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut trace_id);
+        self.new_span(name, trace_id, None)
+    }
+
+    /// 数据面采样：每 sample_every 个包返回一次 true
+    pub fn should_sample(&self) -> bool {
+        if self.exporter.is_none() {
+            return false;
+        }
+        self.sample_counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sample_every)
+    }
+
+    fn new_span(&self, name: &str, trace_id: [u8; 16], parent: Option<[u8; 8]>) -> Span {
+        let mut span_id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut span_id);
+
+        Span {
+            telemetry: self.clone(),
+            data: Some(SpanData {
+                name: name.to_string(),
+                trace_id,
+                span_id,
+                parent_span_id: parent,
+                start_unix_nanos: unix_nanos(),
+                end_unix_nanos: 0,
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+}
+
+/// 进行中的 span，Drop 时自动结束并提交导出
+pub struct Span {
+    telemetry: Telemetry,
+    data: Option<SpanData>,
+}
+
+impl Span {
+    /// 在同一条 trace 下开启子 span
+    pub fn child(&self, name: &str) -> Span {
+        match &self.data {
+            Some(d) => self.telemetry.new_span(name, d.trace_id, Some(d.span_id)),
+            None => Span { telemetry: self.telemetry.clone(), data: None },
+        }
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl ToString) {
+        if let Some(d) = &mut self.data {
+            d.attributes.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    /// 标记 span 失败（OTLP status = ERROR）
+    pub fn set_error(&mut self, message: impl ToString) {
+        if let Some(d) = &mut self.data {
+            d.error = Some(message.to_string());
+        }
+    }
+
+    /// 显式结束 span
+    pub fn end(self) {}
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut d), Some(tx)) = (self.data.take(), &self.telemetry.exporter) {
+            d.end_unix_nanos = unix_nanos();
+            // 队列满时丢弃，遥测不能拖慢转发
+            if let Err(mpsc::error::TrySendError::Full(_)) = tx.try_send(d) {
+                Metrics::incr(&self.telemetry.metrics.queue_drops);
+            }
+        }
+    }
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// 后台导出任务：周期性批量发送 span 和计数器快照
+async fn run_exporter(
+    endpoint: HttpEndpoint,
+    service_name: String,
+    mut rx: mpsc::Receiver<SpanData>,
+    metrics: Arc<Metrics>,
+) {
+    let start = unix_nanos();
+    let mut ticker = tokio::time::interval(EXPORT_INTERVAL);
+    let mut last_error_reported = false;
+
+    loop {
+        ticker.tick().await;
+
+        let mut batch = Vec::new();
+        while let Ok(span) = rx.try_recv() {
+            batch.push(span);
+        }
+
+        let mut result = Ok(());
+        if !batch.is_empty() {
+            let body = encode_traces(&service_name, &batch);
+            result = endpoint.post_json("/v1/traces", &body).await;
+        }
+        if result.is_ok() {
+            let body = encode_metrics(&service_name, start, unix_nanos(), &metrics.snapshot(), &metrics.gauges());
+            result = endpoint.post_json("/v1/metrics", &body).await;
+        }
+
+        // 只在状态变化时打印，避免 collector 不可用时刷屏
+        match result {
+            Ok(()) => last_error_reported = false,
+            Err(e) if !last_error_reported => {
+                eprintln!("⚠️  OTLP 导出失败: {}", e);
+                last_error_reported = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn resource(service_name: &str) -> Value {
+    json!({
+        "attributes": [
+            { "key": "service.name", "value": { "stringValue": service_name } }
+        ]
+    })
+}
+
+/// 按 OTLP/JSON 格式编码 span 批次
+pub fn encode_traces(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(|s| {
+        let mut span = json!({
+            "traceId": hex::encode(s.trace_id),
+            "spanId": hex::encode(s.span_id),
+            "name": s.name,
+            "kind": 1,
+            "startTimeUnixNano": s.start_unix_nanos.to_string(),
+            "endTimeUnixNano": s.end_unix_nanos.to_string(),
+            "attributes": s.attributes.iter().map(|(k, v)| json!({
+                "key": k,
+                "value": { "stringValue": v }
+            })).collect::<Vec<_>>(),
+        });
+        if let Some(parent) = s.parent_span_id {
+            span["parentSpanId"] = json!(hex::encode(parent));
+        }
+        if let Some(err) = &s.error {
+            span["status"] = json!({ "code": 2, "message": err });
+        }
+        span
+    }).collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": resource(service_name),
+            "scopeSpans": [{ "scope": { "name": "vpn_core" }, "spans": spans }]
+        }]
+    })
+}
+
+/// 按 OTLP/JSON 格式编码累计计数器和瞬时值
+pub fn encode_metrics(service_name: &str, start: u64, now: u64, counters: &[(&str, u64)], gauges: &[(&str, u64)]) -> Value {
+    let mut metrics: Vec<Value> = counters.iter().map(|(name, value)| json!({
+        "name": name,
+        "sum": {
+            "dataPoints": [{
+                "asInt": value.to_string(),
+                "startTimeUnixNano": start.to_string(),
+                "timeUnixNano": now.to_string(),
+            }],
+            "aggregationTemporality": 2,
+            "isMonotonic": true,
+        }
+    })).collect();
+    metrics.extend(gauges.iter().map(|(name, value)| json!({
+        "name": name,
+        "gauge": {
+            "dataPoints": [{ "asInt": value.to_string(), "timeUnixNano": now.to_string() }],
+        }
+    })));
+
+    json!({
+        "resourceMetrics": [{
+            "resource": resource(service_name),
+            "scopeMetrics": [{ "scope": { "name": "vpn_core" }, "metrics": metrics }]
+        }]
+    })
+}
+
+/// 极简的 HTTP/1.1 端点（只支持 http://，TLS 交给本地 collector/sidecar）
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub base_path: String,
+}
+
+impl HttpEndpoint {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| anyhow!("只支持 http:// 地址: {}", url))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse::<u16>().map_err(|_| anyhow!("无效端口: {}", p))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("缺少主机名: {}", url));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            base_path: path.to_string(),
+        })
+    }
+
+    /// POST 一个 JSON body，非 2xx 视为失败
+    pub async fn post_json(&self, path: &str, body: &Value) -> Result<()> {
+        let body = body.to_string();
+        let request = format!(
+            "POST {}{} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.base_path, path, self.host, self.port, body.len(), body
+        );
+
+        let mut stream = tokio::time::timeout(
+            Duration::from_secs(3),
+            TcpStream::connect((self.host.as_str(), self.port)),
+        ).await??;
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(3), stream.read_to_end(&mut response)).await??;
+
+        let status_line = String::from_utf8_lossy(&response);
+        let status = status_line.split_whitespace().nth(1).unwrap_or("");
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(anyhow!("collector 返回状态 {}", status))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let ep = HttpEndpoint::parse("http://collector:4318/otel/").unwrap();
+        assert_eq!(ep.host, "collector");
+        assert_eq!(ep.port, 4318);
+        assert_eq!(ep.base_path, "/otel");
+
+        let ep = HttpEndpoint::parse("http://127.0.0.1").unwrap();
+        assert_eq!(ep.port, 80);
+        assert!(HttpEndpoint::parse("https://collector:4318").is_err());
+    }
+
+    #[test]
+    fn test_encode_traces() {
+        let span = SpanData {
+            name: "handshake".to_string(),
+            trace_id: [1u8; 16],
+            span_id: [2u8; 8],
+            parent_span_id: Some([3u8; 8]),
+            start_unix_nanos: 10,
+            end_unix_nanos: 20,
+            attributes: vec![("client_id".to_string(), "laptop".to_string())],
+            error: Some("bad signature".to_string()),
+        };
+
+        let json = encode_traces("vpn_server", &[span]);
+        let s = &json["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(s["traceId"], "01010101010101010101010101010101");
+        assert_eq!(s["parentSpanId"], "0303030303030303");
+        assert_eq!(s["startTimeUnixNano"], "10");
+        assert_eq!(s["status"]["code"], 2);
+        assert_eq!(s["attributes"][0]["value"]["stringValue"], "laptop");
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(0.5), None);
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.count(), 101);
+
+        // 分位数取桶的上界：不小于真实值，误差在一个桶（约 19%）以内
+        let p50 = histogram.percentile(0.5).unwrap();
+        assert!(p50 >= Duration::from_millis(51) && p50 <= Duration::from_millis(61), "{:?}", p50);
+        let p90 = histogram.percentile(0.9).unwrap();
+        assert!(p90 >= Duration::from_millis(91) && p90 <= Duration::from_millis(109), "{:?}", p90);
+        // 超出范围的计入最后一个桶
+        assert_eq!(histogram.percentile(1.0), Some(Duration::from_micros(LatencyHistogram::bound(LATENCY_BUCKETS - 1))));
+
+        let json = encode_metrics("vpn_server", 0, 1, &[], &[("vpn.handshake.latency.p50_us", p50.as_micros() as u64)]);
+        assert_eq!(json["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0]["gauge"]["dataPoints"][0]["asInt"], p50.as_micros().to_string());
+    }
+
+    #[test]
+    fn test_disabled_is_noop() {
+        let telemetry = Telemetry::disabled();
+        assert!(!telemetry.should_sample());
+
+        let mut span = telemetry.start_span("handshake");
+        span.set_attribute("k", "v");
+        assert!(span.data.is_none());
+
+        Metrics::incr(&telemetry.metrics().handshakes_started);
+        assert_eq!(telemetry.metrics().snapshot()[0].1, 1);
+    }
+}
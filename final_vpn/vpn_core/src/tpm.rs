@@ -0,0 +1,130 @@
+// vpn_core/src/tpm.rs
+// 把私钥密封（seal）到本机 TPM 2.0，密钥文件被复制到其他机器上也无法使用
+//
+// 通过 tpm2-tools 完成：在 owner 层级下创建主密钥（同一 TPM 上模板相同则每次得到同一个主密钥，不需要持久化），
+// 私钥作为 sealed data object 保存为 `<密钥文件>.tpm.pub` / `<密钥文件>.tpm.priv` 两个文件，
+// 只有创建它们的那块 TPM 能够解封。TPM 被清除（或更换主板）后密封的私钥无法恢复。
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Result, anyhow};
+
+/// 私钥的保存方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyProtection {
+    /// 明文文件（权限 600）
+    File,
+    /// 密封到 TPM；TPM 不可用时回退为明文文件并给出警告
+    Tpm,
+}
+
+/// 本机是否有可用的 TPM 2.0（设备节点和 tpm2-tools 都存在）
+pub fn available() -> bool {
+    let device = ["/dev/tpmrm0", "/dev/tpm0"].iter().any(|d| Path::new(d).exists());
+    device && Command::new("tpm2_unseal").arg("--version")
+        .stdout(Stdio::null()).stderr(Stdio::null())
+        .status().is_ok_and(|s| s.success())
+}
+
+/// 密钥文件对应的密封对象文件（公开部分、加密的私有部分）
+pub fn sealed_paths(key_path: &Path) -> (PathBuf, PathBuf) {
+    let with_suffix = |suffix: &str| {
+        let mut name = key_path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    (with_suffix(".tpm.pub"), with_suffix(".tpm.priv"))
+}
+
+/// 密钥文件是否已有密封的副本
+pub fn is_sealed(key_path: &Path) -> bool {
+    let (public, private) = sealed_paths(key_path);
+    public.exists() && private.exists()
+}
+
+/// 把 data 密封到 TPM，写入 key_path 对应的 .tpm.pub / .tpm.priv
+pub fn seal(key_path: &Path, data: &[u8]) -> Result<()> {
+    let (public, private) = sealed_paths(key_path);
+    let work = WorkDir::new()?;
+    create_primary(&work)?;
+    // 私钥通过 stdin 传入，不落盘
+    run(
+        Command::new("tpm2_create")
+            .args(["-Q", "-C"]).arg(work.primary())
+            .args(["-g", "sha256", "-i", "-", "-u"]).arg(&public)
+            .arg("-r").arg(&private),
+        Some(data),
+    )?;
+    Ok(())
+}
+
+/// 解封 key_path 对应的密封对象
+pub fn unseal(key_path: &Path) -> Result<Vec<u8>> {
+    let (public, private) = sealed_paths(key_path);
+    let work = WorkDir::new()?;
+    create_primary(&work)?;
+    let object = work.0.join("key.ctx");
+    run(
+        Command::new("tpm2_load")
+            .args(["-Q", "-C"]).arg(work.primary())
+            .arg("-u").arg(&public)
+            .arg("-r").arg(&private)
+            .arg("-c").arg(&object),
+        None,
+    )?;
+    run(Command::new("tpm2_unseal").arg("-c").arg(&object), None)
+}
+
+/// 在 owner 层级下创建主密钥（ECC P-256，模板固定）
+fn create_primary(work: &WorkDir) -> Result<()> {
+    run(
+        Command::new("tpm2_createprimary")
+            .args(["-Q", "-C", "o", "-g", "sha256", "-G", "ecc", "-c"])
+            .arg(work.primary()),
+        None,
+    )?;
+    Ok(())
+}
+
+/// 执行 tpm2-tools 命令，返回 stdout
+fn run(cmd: &mut Command, input: Option<&[u8]>) -> Result<Vec<u8>> {
+    use std::io::Write;
+
+    let name = cmd.get_program().to_string_lossy().into_owned();
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("无法执行 {}: {}", name, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.unwrap_or_default())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!("{} 失败: {}", name, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(output.stdout)
+}
+
+/// 存放 TPM 对象上下文的临时目录（只含句柄信息，不含明文私钥），用完删除
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    fn new() -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-tpm-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir(&dir)?;
+        Ok(Self(dir))
+    }
+
+    fn primary(&self) -> PathBuf {
+        self.0.join("primary.ctx")
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
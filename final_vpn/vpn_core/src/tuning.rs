@@ -0,0 +1,280 @@
+// vpn_core/src/tuning.rs
+// 性能和超时参数：UDP socket 缓冲区、握手超时和重试次数、批处理深度、TUN MTU、看门狗
+//
+// 默认值与之前写死的常量一致，只在需要时通过命令行覆盖。
+// 收包缓冲区按 MTU 推算并多留 1 字节：读满缓冲区说明包超过了预期大小、已被截断，直接丢弃。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::{local_tun, watchdog};
+
+/// 默认握手超时（等待 ServerHello / ServerFinish）
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 默认重新握手次数
+pub const DEFAULT_HANDSHAKE_RETRIES: u32 = 5;
+/// 默认 TUN MTU
+pub const DEFAULT_MTU: u16 = 1500;
+/// 允许的 MTU 范围（上限为 9000 字节的巨型帧）
+pub const MIN_MTU: u16 = 576;
+pub const MAX_MTU: u16 = 9000;
+/// TUN 包前的协议头余量（macOS utun 4 字节）
+const TUN_HEADROOM: usize = local_tun::TunFrameCodec::AF_HEADER_LEN;
+/// UDP 收包缓冲区下限：握手消息（ServerHello 约 1200 字节）不受 MTU 影响
+const MIN_UDP_BUFFER: usize = 4096;
+
+/// 可调参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    /// UDP 接收缓冲区（SO_RCVBUF），None 时使用系统默认值
+    pub recv_buffer: Option<usize>,
+    /// UDP 发送缓冲区（SO_SNDBUF）
+    pub send_buffer: Option<usize>,
+    /// 等待握手响应的超时
+    pub handshake_timeout: Duration,
+    /// 网络变化后重新握手的最大次数
+    pub handshake_retries: u32,
+    /// 每批读写的最大包数，也是缓冲池的容量
+    pub batch_size: usize,
+    /// TUN MTU（--mtu），None 时不修改设备，按默认 1500 分配缓冲区
+    pub mtu: Option<u16>,
+    /// 转发任务卡住多久后重启（--watchdog），None 表示关闭看门狗
+    pub watchdog: Option<Duration>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            recv_buffer: None,
+            send_buffer: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_retries: DEFAULT_HANDSHAKE_RETRIES,
+            batch_size: local_tun::MAX_BATCH,
+            mtu: None,
+            watchdog: Some(watchdog::DEFAULT_TIMEOUT),
+        }
+    }
+}
+
+impl Tuning {
+    /// 从命令行参数读取，未指定的项使用默认值
+    ///
+    /// * `--recv-buffer <大小>` `--send-buffer <大小>`：socket 缓冲区，支持 k/m 后缀（如 4m）
+    /// * `--handshake-timeout <秒>`：等待握手响应的超时，默认 30
+    /// * `--handshake-retries <次数>`：重新握手的最大次数，默认 5
+    /// * `--batch-size <包数>`：批处理深度，默认 32
+    /// * `--mtu <字节>`：TUN MTU（576 ~ 9000），两端需要一致
+    /// * `--watchdog <秒>`：转发任务卡住多久后重启，默认 15，0 表示关闭
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut tuning = Self::default();
+        if let Some(v) = arg_value(args, "--recv-buffer") {
+            tuning.recv_buffer = Some(parse_size(v)?);
+        }
+        if let Some(v) = arg_value(args, "--send-buffer") {
+            tuning.send_buffer = Some(parse_size(v)?);
+        }
+        if let Some(v) = arg_value(args, "--handshake-timeout") {
+            let secs: u64 = v.parse().map_err(|_| anyhow!("无效的 --handshake-timeout: {}", v))?;
+            if secs == 0 {
+                return Err(anyhow!("--handshake-timeout 不能为 0"));
+            }
+            tuning.handshake_timeout = Duration::from_secs(secs);
+        }
+        if let Some(v) = arg_value(args, "--handshake-retries") {
+            tuning.handshake_retries = v.parse().map_err(|_| anyhow!("无效的 --handshake-retries: {}", v))?;
+        }
+        if let Some(v) = arg_value(args, "--batch-size") {
+            tuning.batch_size = v.parse().ok().filter(|n| *n > 0).ok_or_else(|| anyhow!("无效的 --batch-size: {}", v))?;
+        }
+        if let Some(v) = arg_value(args, "--mtu") {
+            let mtu = v.parse().ok().filter(|m| (MIN_MTU..=MAX_MTU).contains(m));
+            tuning.mtu = Some(mtu.ok_or_else(|| anyhow!("无效的 --mtu: {}（范围 {} ~ {}）", v, MIN_MTU, MAX_MTU))?);
+        }
+        if let Some(v) = arg_value(args, "--watchdog") {
+            let secs: u64 = v.parse().map_err(|_| anyhow!("无效的 --watchdog: {}", v))?;
+            tuning.watchdog = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+        Ok(tuning)
+    }
+
+    /// 生效的 TUN MTU
+    pub fn tun_mtu(&self) -> u16 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    /// TUN 读缓冲区大小：MTU + 协议头余量 + 1 字节截断检测
+    pub fn tun_buffer_size(&self) -> usize {
+        self.tun_mtu() as usize + TUN_HEADROOM + 1
+    }
+
+    /// UDP 收包缓冲区大小：MTU + 加密开销 + 1 字节截断检测
+    pub fn udp_buffer_size(&self) -> usize {
+        (self.tun_mtu() as usize + crate::symmetric::OVERHEAD + 1).max(MIN_UDP_BUFFER)
+    }
+
+    /// 按配置设置 socket 缓冲区，返回内核实际生效的 (接收, 发送) 大小
+    pub fn apply_socket<S: std::os::unix::io::AsRawFd>(&self, socket: &S) -> Result<(usize, usize)> {
+        let fd = socket.as_raw_fd();
+        if let Some(size) = self.recv_buffer {
+            set_buffer(fd, libc::SO_RCVBUF, force_option(libc::SO_RCVBUF), size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_buffer(fd, libc::SO_SNDBUF, force_option(libc::SO_SNDBUF), size)?;
+        }
+        Ok((get_buffer(fd, libc::SO_RCVBUF)?, get_buffer(fd, libc::SO_SNDBUF)?))
+    }
+}
+
+/// 读到的长度占满了缓冲区（缓冲区比最大合法包多 1 字节），说明包被截断
+pub fn is_truncated(n: usize, buf_len: usize) -> bool {
+    n >= buf_len
+}
+
+static TRUNCATION_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// 第一次丢弃截断的包时提示检查 MTU 配置（之后只计入数据面统计）
+pub fn warn_truncated(source: &str, buf_len: usize) {
+    if !TRUNCATION_WARNED.swap(true, Ordering::Relaxed) {
+        eprintln!("⚠️  {} 收到超过 {} 字节的包，已丢弃（请检查两端的 --mtu 是否一致，或是否关闭了网卡 GRO）", source, buf_len - 1);
+    }
+}
+
+fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+}
+
+/// 解析字节数（212992、512k、4m）
+pub fn parse_size(s: &str) -> Result<usize> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (digits, multiplier) = if let Some(n) = lower.strip_suffix('m') {
+        (n, 1 << 20)
+    } else if let Some(n) = lower.strip_suffix('k') {
+        (n, 1 << 10)
+    } else {
+        (lower.as_str(), 1)
+    };
+    match digits.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n * multiplier),
+        _ => Err(anyhow!("无效的大小: {}（示例: 4m, 512k）", s)),
+    }
+}
+
+/// 解析 tc 风格的速率（100mbit、512kbit、1gbit、8000bit），返回 bit/s
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let s = s.trim().to_ascii_lowercase();
+    let (digits, multiplier) = if let Some(n) = s.strip_suffix("gbit") {
+        (n, 1_000_000_000)
+    } else if let Some(n) = s.strip_suffix("mbit") {
+        (n, 1_000_000)
+    } else if let Some(n) = s.strip_suffix("kbit") {
+        (n, 1_000)
+    } else if let Some(n) = s.strip_suffix("bit") {
+        (n, 1)
+    } else {
+        return Err(anyhow!("无效的速率: {}（示例: 100mbit, 512kbit）", s));
+    };
+    let value: u64 = digits.parse().map_err(|_| anyhow!("无效的速率: {}", s))?;
+    if value == 0 {
+        return Err(anyhow!("速率不能为 0: {}", s));
+    }
+    Ok(value * multiplier)
+}
+
+/// Linux 上 SO_RCVBUFFORCE / SO_SNDBUFFORCE 可以突破 net.core.rmem_max / wmem_max（需要 CAP_NET_ADMIN）
+#[cfg(target_os = "linux")]
+fn force_option(option: libc::c_int) -> Option<libc::c_int> {
+    match option {
+        libc::SO_RCVBUF => Some(libc::SO_RCVBUFFORCE),
+        libc::SO_SNDBUF => Some(libc::SO_SNDBUFFORCE),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn force_option(_option: libc::c_int) -> Option<libc::c_int> {
+    None
+}
+
+/// 先尝试强制设置，没有权限时退回普通选项（会被系统上限截断）
+fn set_buffer(fd: libc::c_int, option: libc::c_int, force: Option<libc::c_int>, size: usize) -> Result<()> {
+    let value = libc::c_int::try_from(size).map_err(|_| anyhow!("缓冲区过大: {}", size))?;
+    for name in force.into_iter().chain([option]) {
+        // SAFETY: fd 有效，value 的生命周期覆盖调用
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+    }
+    Err(std::io::Error::last_os_error().into())
+}
+
+fn get_buffer(fd: libc::c_int, option: libc::c_int) -> Result<usize> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: fd 有效，value 和 len 指向足够大的栈变量
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(value as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(parse_size("4m").unwrap(), 4 << 20);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("212992").unwrap(), 212992);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("4mb").is_err());
+
+        assert_eq!(Tuning::from_args(&[]).unwrap(), Tuning::default());
+        let args: Vec<String> = ["--recv-buffer", "2m", "--handshake-timeout", "10", "--handshake-retries", "0", "--batch-size", "64"]
+            .iter().map(|s| s.to_string()).collect();
+        let tuning = Tuning::from_args(&args).unwrap();
+        assert_eq!(tuning.recv_buffer, Some(2 << 20));
+        assert_eq!(tuning.send_buffer, None);
+        assert_eq!(tuning.handshake_timeout, Duration::from_secs(10));
+        assert_eq!(tuning.handshake_retries, 0);
+        assert_eq!(tuning.batch_size, 64);
+        assert!(Tuning::from_args(&["--batch-size".to_string(), "0".to_string()]).is_err());
+        assert_eq!(Tuning::from_args(&["--watchdog".to_string(), "0".to_string()]).unwrap().watchdog, None);
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let default = Tuning::default();
+        assert_eq!(default.tun_mtu(), 1500);
+        assert_eq!(default.udp_buffer_size(), 4096);
+
+        let jumbo = Tuning::from_args(&["--mtu".to_string(), "9000".to_string()]).unwrap();
+        assert_eq!(jumbo.tun_buffer_size(), 9005);
+        assert!(jumbo.udp_buffer_size() > 9000 + crate::symmetric::OVERHEAD);
+        assert!(!is_truncated(9000, jumbo.tun_buffer_size()));
+        assert!(is_truncated(jumbo.tun_buffer_size(), jumbo.tun_buffer_size()));
+        assert!(Tuning::from_args(&["--mtu".to_string(), "65535".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_apply_socket() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tuning = Tuning { recv_buffer: Some(256 << 10), ..Tuning::default() };
+        let (recv, send) = tuning.apply_socket(&socket).unwrap();
+        assert!(recv > 0 && send > 0);
+    }
+}
@@ -0,0 +1,115 @@
+// vpn_core/src/undo.rs
+// 启动阶段的撤销日志
+//
+// 启动时依次修改系统（路由、iptables、内核参数、tc……），中途某一步失败或 panic 时，
+// 以前进程直接退出，前面已经生效的修改留在系统里。现在每完成一项修改就记录对应的撤销操作，
+// 日志被丢弃时（`?` 提前返回、panic 展开）按相反顺序全部撤销；启动完成后调用 commit，
+// 之后的清理仍由正常的退出流程负责。
+
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+struct UndoStep {
+    description: String,
+    undo: Box<dyn FnOnce() + Send>,
+}
+
+/// 撤销日志（Drop 时自动回滚，commit 后失效）
+pub struct UndoJournal {
+    steps: Vec<UndoStep>,
+    armed: bool,
+}
+
+impl UndoJournal {
+    pub fn new() -> Self {
+        Self { steps: Vec::new(), armed: true }
+    }
+
+    /// 记录一项已应用（或部分应用）的修改及其撤销操作，撤销操作应忽略"不存在"之类的错误
+    pub fn record(&mut self, description: impl Into<String>, undo: impl FnOnce() + Send + 'static) {
+        self.steps.push(UndoStep { description: description.into(), undo: Box::new(undo) });
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// 按相反顺序执行全部撤销操作；单个撤销操作 panic 不影响其余的
+    pub fn rollback(&mut self) {
+        if self.steps.is_empty() {
+            return;
+        }
+        println!("↩️  启动未完成，撤销已应用的 {} 项配置...", self.steps.len());
+        while let Some(step) = self.steps.pop() {
+            println!("   ↩️  {}", step.description);
+            if catch_unwind(AssertUnwindSafe(step.undo)).is_err() {
+                eprintln!("   ⚠️  撤销失败: {}", step.description);
+            }
+        }
+    }
+
+    /// 启动完成：丢弃日志，不再自动回滚
+    pub fn commit(mut self) {
+        self.armed = false;
+        self.steps.clear();
+    }
+}
+
+impl Default for UndoJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for UndoJournal {
+    fn drop(&mut self) {
+        if self.armed {
+            self.rollback();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn journal_with(log: &Arc<Mutex<Vec<u32>>>, n: u32) -> UndoJournal {
+        let mut journal = UndoJournal::new();
+        for i in 1..=n {
+            let log = log.clone();
+            journal.record(format!("step {}", i), move || log.lock().unwrap().push(i));
+        }
+        journal
+    }
+
+    #[test]
+    fn test_rollback_order() {
+        // 提前返回：按相反顺序撤销
+        let log = Arc::new(Mutex::new(Vec::new()));
+        drop(journal_with(&log, 3));
+        assert_eq!(*log.lock().unwrap(), vec![3, 2, 1]);
+
+        // 启动完成后不撤销
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let journal = journal_with(&log, 2);
+        assert_eq!(journal.len(), 2);
+        journal.commit();
+        assert!(log.lock().unwrap().is_empty());
+
+        // panic 展开时撤销，某一步撤销 panic 不影响其余步骤
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let mut journal = journal_with(&log, 2);
+            journal.record("broken", || panic!("undo failed"));
+            let log = log.clone();
+            journal.record("step 4", move || log.lock().unwrap().push(4));
+            panic!("setup failed");
+        }));
+        assert!(result.is_err());
+        assert_eq!(*log.lock().unwrap(), vec![4, 2, 1]);
+    }
+}
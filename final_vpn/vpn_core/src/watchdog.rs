@@ -0,0 +1,184 @@
+// vpn_core/src/watchdog.rs
+// 转发任务的看门狗：发现卡住的上行 / 下行任务，打印诊断后重启，反复卡住时按致命错误退出
+//
+// 转发循环平时停在等待输入的地方（读 TUN、收 UDP），没有流量时停多久都正常。
+// 卡住是指拿到一批包之后迟迟回不到等待输入：处理、发送或写 TUN 的某一步不返回
+// （发送缓冲区一直满、某个锁没有释放……）。这时进程和对端都还活着，包却不再转发。
+//
+// 每个任务用 TaskProbe 记录所在阶段和最近一次进展的时间：回到等待输入就是空闲标记，
+// 其余阶段超过超时没有处理完一个包即判定卡住。引擎定期检查，卡住时打印任务状态和队列深度，
+// 中止并重新启动该任务，再通知 PacketHandler（客户端借此重新握手）。
+// 同一任务连续 MAX_RESTARTS 次重启后仍然没有进展，交给 crash 模块走退出清理。
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// 默认的卡住判定时间
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// 连续重启多少次仍无进展后放弃
+pub const MAX_RESTARTS: u32 = 3;
+
+/// 转发任务所处的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// 等待输入（读 TUN / 收 UDP），停留多久都不算卡住
+    Waiting,
+    /// 逐包处理（加密发送 / 解密路由）
+    Handling,
+    /// 发出或取出暂存的包（end_tun_batch / flush）
+    Flushing,
+    /// 写入 TUN
+    Writing,
+    /// 已被看门狗中止，等待新任务接管设备
+    Restarting,
+}
+
+impl Stage {
+    const ALL: [Stage; 5] = [Stage::Waiting, Stage::Handling, Stage::Flushing, Stage::Writing, Stage::Restarting];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Waiting => "等待输入",
+            Stage::Handling => "处理数据包",
+            Stage::Flushing => "发送暂存的包",
+            Stage::Writing => "写入 TUN",
+            Stage::Restarting => "重启中",
+        }
+    }
+}
+
+/// 一个转发任务的进展记录，由任务自己更新、看门狗读取
+#[derive(Debug)]
+pub struct TaskProbe {
+    name: &'static str,
+    epoch: Instant,
+    stage: AtomicU8,
+    /// 进入当前阶段或最近一次进展的时间（相对 epoch 的毫秒数）
+    since: AtomicU64,
+    /// 当前这批还没处理完的包数
+    pending: AtomicUsize,
+    packets: AtomicU64,
+    restarts: AtomicU32,
+    /// 上次重启时的 packets，用来判断重启后是否有进展
+    packets_at_restart: AtomicU64,
+}
+
+impl TaskProbe {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            epoch: Instant::now(),
+            stage: AtomicU8::new(Stage::Waiting as u8),
+            since: AtomicU64::new(0),
+            pending: AtomicUsize::new(0),
+            packets: AtomicU64::new(0),
+            restarts: AtomicU32::new(0),
+            packets_at_restart: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// 进入新阶段，pending 为这批待处理的包数
+    pub fn enter(&self, stage: Stage, pending: usize) {
+        self.pending.store(pending, Ordering::Relaxed);
+        self.since.store(self.now(), Ordering::Relaxed);
+        self.stage.store(stage as u8, Ordering::Relaxed);
+    }
+
+    /// 处理完一个包
+    pub fn progress(&self) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        let _ = self.pending.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+        self.since.store(self.now(), Ordering::Relaxed);
+    }
+
+    pub fn stage(&self) -> Stage {
+        Stage::ALL[self.stage.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn packets(&self) -> u64 {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    /// 不在等待输入、且超过 timeout 没有进展时返回已经停留的时间
+    pub fn stalled(&self, timeout: Duration) -> Option<Duration> {
+        if self.stage() == Stage::Waiting {
+            return None;
+        }
+        let idle = Duration::from_millis(self.now().saturating_sub(self.since.load(Ordering::Relaxed)));
+        (idle >= timeout).then_some(idle)
+    }
+
+    /// 记一次重启，返回连续无进展的重启次数（上次重启之后处理过包则从 1 重新计数）
+    pub fn note_restart(&self) -> u32 {
+        let packets = self.packets();
+        let restarts = if packets > self.packets_at_restart.swap(packets, Ordering::Relaxed) {
+            self.restarts.store(1, Ordering::Relaxed);
+            1
+        } else {
+            self.restarts.fetch_add(1, Ordering::Relaxed) + 1
+        };
+        self.enter(Stage::Restarting, 0);
+        restarts
+    }
+
+    /// 卡住时打印的诊断：任务状态和 PacketHandler 报告的队列深度
+    pub fn report(&self, stalled: Duration, queues: &[(&'static str, usize)]) -> String {
+        let mut report = format!(
+            "🐕 看门狗: {}任务卡在「{}」已 {:.1} 秒没有进展（本批还剩 {} 个包，累计处理 {} 个）",
+            self.name,
+            self.stage().as_str(),
+            stalled.as_secs_f64(),
+            self.pending.load(Ordering::Relaxed),
+            self.packets(),
+        );
+        if !queues.is_empty() {
+            report.push_str("\n   队列:");
+            for (name, depth) in queues {
+                let _ = write!(report, " {}={}", name, depth);
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waiting_never_stalls() {
+        let probe = TaskProbe::new("上行");
+        assert_eq!(probe.stalled(Duration::ZERO), None);
+
+        probe.enter(Stage::Handling, 2);
+        assert!(probe.stalled(Duration::ZERO).is_some());
+        assert_eq!(probe.stalled(Duration::from_secs(60)), None);
+        probe.progress();
+        let report = probe.report(Duration::from_secs(20), &[("control", 3)]);
+        assert!(report.contains("处理数据包") && report.contains("还剩 1 个包") && report.contains("control=3"), "{}", report);
+
+        probe.enter(Stage::Waiting, 0);
+        assert_eq!(probe.stalled(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_restarts_count_until_progress() {
+        let probe = TaskProbe::new("下行");
+        assert_eq!(probe.note_restart(), 1);
+        assert_eq!(probe.note_restart(), 2);
+        assert_eq!(probe.stage(), Stage::Restarting);
+        // 重启后处理过包，重新计数
+        probe.progress();
+        assert_eq!(probe.note_restart(), 1);
+    }
+}
@@ -0,0 +1,294 @@
+// vpn_core/src/wire.rs
+// 显式、带版本的消息编码（TLV）
+//
+// 以前握手消息、控制消息等直接用 bincode 序列化 enum：布局由字段顺序和变体下标隐式决定，
+// 加一个字段或调整变体顺序，新旧版本之间就会静默地解析出错误的数据。现在每条消息编码为：
+//
+//     [版本 u8][消息类型 u8] { [标签 u8][长度 u16 大端][值] }*
+//
+// 兼容规则：
+// * 消息类型码和字段标签一经使用不再改变含义，删除的标签不再复用
+// * 新增字段必须是可选的（旧版本不认识的标签直接跳过），这类改动不需要提升版本号
+// * 只有无法兼容的改动才提升 WIRE_VERSION，解码时拒绝不认识的版本
+// * 同一标签出现多次表示列表（如 RoutePush 的路由）
+// * 单个字段的值最长 65535 字节；超长的字段不会被截断写出，finish() 返回错误
+//
+// 各消息的类型码和标签定义在消息所在的模块（handshake、control、agent），
+// 对应的测试里保存了 v1 编码的固定字节，防止布局被无意改动。
+//
+// UDP 上的每个报文（STUN 除外）另有 3 字节的数据报头，收到后先按报头分派：
+//
+//     [魔数 0xB7][报头版本 u8][报文类型 u8] 内容
+//
+// 报文类型：1 = 握手消息（内容为上面的 TLV 编码），2 = 加密数据包（内容为 [序号][密文]，见 symmetric::Cipher::encrypt_packet）。
+// 以前服务端把每个数据报先试着当作握手消息解码，失败再当作密文，随机 nonce 碰巧像握手消息时会走错分支。
+// 魔数最高两位不为 0，和 STUN（首字节最高两位为 0）区分。
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Result, anyhow};
+
+/// 当前的编码版本
+pub const WIRE_VERSION: u8 = 1;
+
+/// 数据报头的魔数
+pub const PACKET_MAGIC: u8 = 0xB7;
+/// 数据报头的版本：报头或报文类型的含义无法兼容时才提升
+pub const PACKET_VERSION: u8 = 1;
+pub const PACKET_HEADER_LEN: usize = 3;
+
+/// 数据报头中的报文类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Handshake = 1,
+    Data = 2,
+}
+
+/// 某类报文的数据报头
+pub const fn packet_header(kind: PacketType) -> [u8; PACKET_HEADER_LEN] {
+    [PACKET_MAGIC, PACKET_VERSION, kind as u8]
+}
+
+/// 按数据报头分派的结果
+#[derive(Debug, PartialEq)]
+pub enum Datagram<'a> {
+    /// 握手消息（handshake::deserialize_message 解码整个报文）
+    Handshake,
+    /// 加密数据包，值为去掉报头后的 [序号][密文]
+    Data(&'a [u8]),
+    /// STUN 报文（客户端的 NAT 检测）
+    Stun,
+    /// 无法识别的报文，值为丢弃原因（用于丢包计数）
+    Invalid(&'static str),
+}
+
+/// 按数据报头判断报文类型，不做任何解码
+pub fn classify(data: &[u8]) -> Datagram<'_> {
+    match data {
+        [PACKET_MAGIC, PACKET_VERSION, kind, body @ ..] => match *kind {
+            k if k == PacketType::Handshake as u8 => Datagram::Handshake,
+            k if k == PacketType::Data as u8 => Datagram::Data(body),
+            _ => Datagram::Invalid("unknown_packet_type"),
+        },
+        [PACKET_MAGIC, _, _, ..] => Datagram::Invalid("bad_packet_version"),
+        _ if crate::stun::is_stun(data) => Datagram::Stun,
+        _ => Datagram::Invalid("bad_packet_header"),
+    }
+}
+
+/// 编码一条消息
+pub struct Writer {
+    buf: Vec<u8>,
+    /// 第一个超长字段的标签和长度，由 finish() 报告
+    oversize: Option<(u8, usize)>,
+}
+
+impl Writer {
+    /// prefix 为消息前的固定字节（如控制消息的 KIND_CONTROL），之后写入版本和类型
+    pub fn new(prefix: &[u8], kind: u8) -> Self {
+        let mut buf = prefix.to_vec();
+        buf.extend([WIRE_VERSION, kind]);
+        Self { buf, oversize: None }
+    }
+
+    /// 长度放不进 u16 的值不写入，记下来由 finish() 返回错误
+    pub fn bytes(mut self, tag: u8, value: &[u8]) -> Self {
+        let Ok(len) = u16::try_from(value.len()) else {
+            self.oversize.get_or_insert((tag, value.len()));
+            return self;
+        };
+        self.buf.push(tag);
+        self.buf.extend(len.to_be_bytes());
+        self.buf.extend(value);
+        self
+    }
+
+    pub fn str(self, tag: u8, value: &str) -> Self {
+        self.bytes(tag, value.as_bytes())
+    }
+
+    pub fn bool(self, tag: u8, value: bool) -> Self {
+        self.bytes(tag, &[value as u8])
+    }
+
+    pub fn u32(self, tag: u8, value: u32) -> Self {
+        self.bytes(tag, &value.to_be_bytes())
+    }
+
+    pub fn u64(self, tag: u8, value: u64) -> Self {
+        self.bytes(tag, &value.to_be_bytes())
+    }
+
+    /// 地址：[4 或 6][IP][端口 u16]
+    pub fn addr(self, tag: u8, addr: SocketAddr) -> Self {
+        let mut value = Vec::with_capacity(19);
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(4);
+                value.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                value.push(6);
+                value.extend(ip.octets());
+            }
+        }
+        value.extend(addr.port().to_be_bytes());
+        self.bytes(tag, &value)
+    }
+
+    /// 编码结果；有字段超长时返回错误（而不是写出长度被截断、解码错位的消息）
+    pub fn finish(self) -> Result<Vec<u8>> {
+        match self.oversize {
+            Some((tag, len)) => Err(anyhow!("字段 {} 长度 {} 字节，超过上限 {} 字节", tag, len, u16::MAX)),
+            None => Ok(self.buf),
+        }
+    }
+}
+
+/// 解码后的消息：类型和字段列表
+pub struct Fields<'a> {
+    pub kind: u8,
+    fields: Vec<(u8, &'a [u8])>,
+}
+
+impl<'a> Fields<'a> {
+    /// 解析 [版本][类型][TLV...]，拒绝不认识的版本和截断的字段
+    pub fn parse(data: &'a [u8]) -> Result<Self> {
+        let (&version, rest) = data.split_first().ok_or_else(|| anyhow!("消息为空"))?;
+        if version != WIRE_VERSION {
+            return Err(anyhow!("不支持的消息版本: {}（本端为 {}）", version, WIRE_VERSION));
+        }
+        let (&kind, mut rest) = rest.split_first().ok_or_else(|| anyhow!("缺少消息类型"))?;
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            let [tag, hi, lo, ..] = *rest else {
+                return Err(anyhow!("字段头被截断"));
+            };
+            let len = u16::from_be_bytes([hi, lo]) as usize;
+            let value = rest.get(3..3 + len).ok_or_else(|| anyhow!("字段 {} 被截断", tag))?;
+            fields.push((tag, value));
+            rest = &rest[3 + len..];
+        }
+        Ok(Self { kind, fields })
+    }
+
+    /// 可选字段（出现多次时取第一个）
+    pub fn opt(&self, tag: u8) -> Option<&'a [u8]> {
+        self.fields.iter().find(|(t, _)| *t == tag).map(|(_, v)| *v)
+    }
+
+    /// 同一标签的所有值（列表字段）
+    pub fn all(&self, tag: u8) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.fields.iter().filter(move |(t, _)| *t == tag).map(|(_, v)| *v)
+    }
+
+    pub fn bytes(&self, tag: u8) -> Result<&'a [u8]> {
+        self.opt(tag).ok_or_else(|| anyhow!("消息类型 {} 缺少字段 {}", self.kind, tag))
+    }
+
+    pub fn vec(&self, tag: u8) -> Result<Vec<u8>> {
+        self.bytes(tag).map(<[u8]>::to_vec)
+    }
+
+    pub fn array<const N: usize>(&self, tag: u8) -> Result<[u8; N]> {
+        self.bytes(tag)?.try_into().map_err(|_| anyhow!("字段 {} 长度应为 {} 字节", tag, N))
+    }
+
+    pub fn string(&self, tag: u8) -> Result<String> {
+        Ok(String::from_utf8(self.vec(tag)?)?)
+    }
+
+    pub fn bool(&self, tag: u8) -> Result<bool> {
+        match self.bytes(tag)? {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(anyhow!("字段 {} 不是布尔值", tag)),
+        }
+    }
+
+    pub fn u32(&self, tag: u8) -> Result<u32> {
+        self.array(tag).map(u32::from_be_bytes)
+    }
+
+    pub fn u64(&self, tag: u8) -> Result<u64> {
+        self.array(tag).map(u64::from_be_bytes)
+    }
+
+    pub fn addr(&self, tag: u8) -> Result<SocketAddr> {
+        let value = self.bytes(tag)?;
+        let ip = match value.split_first() {
+            Some((4, rest)) if rest.len() == 6 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&rest[..4])?)),
+            Some((6, rest)) if rest.len() == 18 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&rest[..16])?)),
+            _ => return Err(anyhow!("字段 {} 不是地址", tag)),
+        };
+        let port = u16::from_be_bytes([value[value.len() - 2], value[value.len() - 1]]);
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let data = [&packet_header(PacketType::Data)[..], &[1, 2, 3]].concat();
+        assert_eq!(classify(&data), Datagram::Data(&[1, 2, 3]));
+        assert_eq!(classify(&packet_header(PacketType::Handshake)), Datagram::Handshake);
+        assert_eq!(classify(&crate::stun::binding_request().1), Datagram::Stun);
+
+        // 旧版本的握手消息（"RV" 开头）、随机 nonce 开头的旧数据包、截断的报头
+        assert_eq!(classify(b"RV\x01\x01"), Datagram::Invalid("bad_packet_header"));
+        assert_eq!(classify(&[PACKET_MAGIC, PACKET_VERSION]), Datagram::Invalid("bad_packet_header"));
+        assert_eq!(classify(&[PACKET_MAGIC, PACKET_VERSION + 1, 1]), Datagram::Invalid("bad_packet_version"));
+        assert_eq!(classify(&[PACKET_MAGIC, PACKET_VERSION, 9]), Datagram::Invalid("unknown_packet_type"));
+    }
+
+    #[test]
+    fn test_fields_roundtrip_and_compat() {
+        let v4: SocketAddr = "203.0.113.7:40123".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        let data = Writer::new(&[], 9)
+            .str(1, "hello")
+            .u64(2, 42)
+            .addr(3, v4)
+            .addr(4, v6)
+            .str(5, "a")
+            .str(5, "b")
+            // 新版本增加的字段：旧版本跳过
+            .bytes(200, &[0xff; 3])
+            .finish()
+            .unwrap();
+        assert_eq!(&data[..2], &[WIRE_VERSION, 9]);
+
+        let fields = Fields::parse(&data).unwrap();
+        assert_eq!(fields.kind, 9);
+        assert_eq!(fields.string(1).unwrap(), "hello");
+        assert_eq!(fields.u64(2).unwrap(), 42);
+        assert_eq!(fields.addr(3).unwrap(), v4);
+        assert_eq!(fields.addr(4).unwrap(), v6);
+        assert_eq!(fields.all(5).collect::<Vec<_>>(), vec![b"a", b"b"]);
+        assert!(fields.u32(2).is_err());
+        assert!(fields.bytes(6).is_err());
+        assert!(fields.opt(6).is_none());
+
+        // 截断、未知版本
+        assert!(Fields::parse(&data[..data.len() - 1]).is_err());
+        assert!(Fields::parse(&data[..3]).is_err());
+        let mut future = data.clone();
+        future[0] = WIRE_VERSION + 1;
+        assert!(Fields::parse(&future).is_err());
+        assert!(Fields::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn test_oversize_field_is_error() {
+        let max = vec![0u8; u16::MAX as usize];
+        let data = Writer::new(&[], 9).bytes(1, &max).finish().unwrap();
+        assert_eq!(Fields::parse(&data).unwrap().bytes(1).unwrap().len(), max.len());
+
+        // 超长一个字节：不截断写出，finish 报错，即使后面还有正常字段
+        let err = Writer::new(&[], 9).bytes(1, &[0u8; 65536]).str(2, "ok").finish().unwrap_err();
+        assert!(err.to_string().contains("字段 1"), "{}", err);
+    }
+}
@@ -0,0 +1,266 @@
+// vpn_server/src/accounting.rs
+// RADIUS 计费（RFC 2866）：每个会话上报 Accounting-Start / Interim-Update / Stop
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use md5::{Digest, Md5};
+use tokio::net::UdpSocket;
+
+const CODE_ACCOUNTING_REQUEST: u8 = 4;
+const CODE_ACCOUNTING_RESPONSE: u8 = 5;
+
+// RADIUS 属性类型
+const ATTR_USER_NAME: u8 = 1;
+const ATTR_FRAMED_IP_ADDRESS: u8 = 8;
+const ATTR_CALLING_STATION_ID: u8 = 31;
+const ATTR_NAS_IDENTIFIER: u8 = 32;
+const ATTR_ACCT_STATUS_TYPE: u8 = 40;
+const ATTR_ACCT_INPUT_OCTETS: u8 = 42;
+const ATTR_ACCT_OUTPUT_OCTETS: u8 = 43;
+const ATTR_ACCT_SESSION_ID: u8 = 44;
+const ATTR_ACCT_SESSION_TIME: u8 = 46;
+const ATTR_ACCT_INPUT_PACKETS: u8 = 47;
+const ATTR_ACCT_OUTPUT_PACKETS: u8 = 48;
+const ATTR_ACCT_TERMINATE_CAUSE: u8 = 49;
+const ATTR_ACCT_INPUT_GIGAWORDS: u8 = 52;
+const ATTR_ACCT_OUTPUT_GIGAWORDS: u8 = 53;
+
+/// 单次请求的重试次数和超时
+const MAX_ATTEMPTS: usize = 3;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(3);
+/// 默认的 Interim-Update 周期
+pub const DEFAULT_INTERIM_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Acct-Status-Type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AcctStatus {
+    Start = 1,
+    Stop = 2,
+    Interim = 3,
+}
+
+/// Acct-Terminate-Cause（只列出会用到的）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TerminateCause {
+    UserRequest = 1,
+    LostCarrier = 2,
+    AdminReset = 6,
+    NasRequest = 10,
+}
+
+/// 一条计费记录（方向以 NAS 为视角：input = 来自客户端）
+#[derive(Debug, Clone)]
+pub struct AcctRecord {
+    pub session_id: String,
+    pub user_name: String,
+    pub framed_ip: Option<Ipv4Addr>,
+    pub calling_station: SocketAddr,
+    pub input_octets: u64,
+    pub output_octets: u64,
+    pub input_packets: u64,
+    pub output_packets: u64,
+    pub session_time: Duration,
+    pub terminate_cause: Option<TerminateCause>,
+}
+
+/// RADIUS 计费客户端
+pub struct Accounting {
+    server: SocketAddr,
+    secret: Vec<u8>,
+    nas_identifier: String,
+    next_id: AtomicU8,
+    pub interim_interval: Duration,
+}
+
+impl Accounting {
+    /// 从命令行参数构建，未指定 --radius-acct 时返回 None
+    ///
+    /// * `--radius-acct <host:port>`：计费服务器（通常是 1813 端口）
+    /// * `--radius-interim <秒>`：Interim-Update 周期，默认 300
+    /// * 共享密钥取自环境变量 VPN_RADIUS_SECRET
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(server) = crate::arg_value(args, "--radius-acct") else {
+            return Ok(None);
+        };
+        let server = std::net::ToSocketAddrs::to_socket_addrs(&server)?
+            .next()
+            .ok_or_else(|| anyhow!("无法解析 RADIUS 服务器地址: {}", server))?;
+
+        let secret = std::env::var("VPN_RADIUS_SECRET")
+            .map_err(|_| anyhow!("RADIUS 计费需要环境变量 VPN_RADIUS_SECRET"))?;
+
+        let interim_interval = match crate::arg_value(args, "--radius-interim") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| anyhow!("无效的 --radius-interim: {}", secs))?),
+            None => DEFAULT_INTERIM_INTERVAL,
+        };
+
+        Ok(Some(Self {
+            server,
+            secret: secret.into_bytes(),
+            nas_identifier: "rust-vpn".to_string(),
+            next_id: AtomicU8::new(0),
+            interim_interval,
+        }))
+    }
+
+    /// 发送一条计费记录并等待 Accounting-Response（带重试）
+    pub async fn send(&self, status: AcctStatus, record: &AcctRecord) -> Result<()> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = encode_request(id, &self.secret, &self.nas_identifier, status, record);
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(self.server).await?;
+
+        let mut buf = [0u8; 4096];
+        for _ in 0..MAX_ATTEMPTS {
+            socket.send(&request).await?;
+
+            if let Ok(Ok(n)) = tokio::time::timeout(RESPONSE_TIMEOUT, socket.recv(&mut buf)).await
+                && verify_response(&buf[..n], &request, &self.secret)
+            {
+                return Ok(());
+            }
+        }
+
+        Err(anyhow!("RADIUS 计费服务器 {} 无响应", self.server))
+    }
+}
+
+/// 编码 Accounting-Request
+///
+/// Request Authenticator = MD5(Code + Identifier + Length + 16 字节 0 + Attributes + Secret)
+pub fn encode_request(id: u8, secret: &[u8], nas_identifier: &str, status: AcctStatus, record: &AcctRecord) -> Vec<u8> {
+    let mut attrs = Vec::new();
+    push_u32(&mut attrs, ATTR_ACCT_STATUS_TYPE, status as u32);
+    push_attr(&mut attrs, ATTR_ACCT_SESSION_ID, record.session_id.as_bytes());
+    push_attr(&mut attrs, ATTR_USER_NAME, record.user_name.as_bytes());
+    push_attr(&mut attrs, ATTR_NAS_IDENTIFIER, nas_identifier.as_bytes());
+    push_attr(&mut attrs, ATTR_CALLING_STATION_ID, record.calling_station.to_string().as_bytes());
+    if let Some(ip) = record.framed_ip {
+        push_attr(&mut attrs, ATTR_FRAMED_IP_ADDRESS, &ip.octets());
+    }
+
+    if status != AcctStatus::Start {
+        // 32 位计数器溢出的部分放到 Gigawords 属性中
+        push_u32(&mut attrs, ATTR_ACCT_INPUT_OCTETS, record.input_octets as u32);
+        push_u32(&mut attrs, ATTR_ACCT_INPUT_GIGAWORDS, (record.input_octets >> 32) as u32);
+        push_u32(&mut attrs, ATTR_ACCT_OUTPUT_OCTETS, record.output_octets as u32);
+        push_u32(&mut attrs, ATTR_ACCT_OUTPUT_GIGAWORDS, (record.output_octets >> 32) as u32);
+        push_u32(&mut attrs, ATTR_ACCT_INPUT_PACKETS, record.input_packets.min(u32::MAX as u64) as u32);
+        push_u32(&mut attrs, ATTR_ACCT_OUTPUT_PACKETS, record.output_packets.min(u32::MAX as u64) as u32);
+        push_u32(&mut attrs, ATTR_ACCT_SESSION_TIME, record.session_time.as_secs().min(u32::MAX as u64) as u32);
+    }
+    if let (AcctStatus::Stop, Some(cause)) = (status, record.terminate_cause) {
+        push_u32(&mut attrs, ATTR_ACCT_TERMINATE_CAUSE, cause as u32);
+    }
+
+    let length = (20 + attrs.len()) as u16;
+    let mut packet = Vec::with_capacity(length as usize);
+    packet.push(CODE_ACCOUNTING_REQUEST);
+    packet.push(id);
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(&[0u8; 16]);
+    packet.extend_from_slice(&attrs);
+
+    let mut hasher = Md5::new();
+    hasher.update(&packet);
+    hasher.update(secret);
+    let authenticator = hasher.finalize();
+    packet[4..20].copy_from_slice(&authenticator);
+
+    packet
+}
+
+/// 校验 Accounting-Response
+///
+/// Response Authenticator = MD5(Code + Identifier + Length + Request Authenticator + Attributes + Secret)
+pub fn verify_response(response: &[u8], request: &[u8], secret: &[u8]) -> bool {
+    if response.len() < 20 || response[0] != CODE_ACCOUNTING_RESPONSE || response[1] != request[1] {
+        return false;
+    }
+    let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+    if length < 20 || length > response.len() {
+        return false;
+    }
+
+    let mut hasher = Md5::new();
+    hasher.update(&response[..4]);
+    hasher.update(&request[4..20]);
+    hasher.update(&response[20..length]);
+    hasher.update(secret);
+
+    hasher.finalize().as_slice() == &response[4..20]
+}
+
+fn push_attr(buf: &mut Vec<u8>, attr_type: u8, value: &[u8]) {
+    // 单个属性最多 253 字节
+    let value = &value[..value.len().min(253)];
+    buf.push(attr_type);
+    buf.push((value.len() + 2) as u8);
+    buf.extend_from_slice(value);
+}
+
+fn push_u32(buf: &mut Vec<u8>, attr_type: u8, value: u32) {
+    push_attr(buf, attr_type, &value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AcctRecord {
+        AcctRecord {
+            session_id: "0011223344556677".to_string(),
+            user_name: "alice".to_string(),
+            framed_ip: Some(Ipv4Addr::new(10, 0, 0, 2)),
+            calling_station: "203.0.113.7:40000".parse().unwrap(),
+            input_octets: (1u64 << 32) + 5,
+            output_octets: 42,
+            input_packets: 3,
+            output_packets: 4,
+            session_time: Duration::from_secs(60),
+            terminate_cause: Some(TerminateCause::LostCarrier),
+        }
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let packet = encode_request(7, b"secret", "rust-vpn", AcctStatus::Stop, &record());
+
+        assert_eq!(packet[0], CODE_ACCOUNTING_REQUEST);
+        assert_eq!(packet[1], 7);
+        assert_eq!(u16::from_be_bytes([packet[2], packet[3]]) as usize, packet.len());
+
+        // 第一个属性是 Acct-Status-Type = Stop
+        assert_eq!(&packet[20..26], &[ATTR_ACCT_STATUS_TYPE, 6, 0, 0, 0, 2]);
+
+        // 重新计算 Request Authenticator
+        let mut zeroed = packet.clone();
+        zeroed[4..20].copy_from_slice(&[0u8; 16]);
+        let mut hasher = Md5::new();
+        hasher.update(&zeroed);
+        hasher.update(b"secret");
+        assert_eq!(hasher.finalize().as_slice(), &packet[4..20]);
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let request = encode_request(9, b"secret", "rust-vpn", AcctStatus::Start, &record());
+
+        let mut response = vec![CODE_ACCOUNTING_RESPONSE, 9, 0, 20];
+        let mut hasher = Md5::new();
+        hasher.update(&response);
+        hasher.update(&request[4..20]);
+        hasher.update(b"secret");
+        response.extend_from_slice(&hasher.finalize());
+
+        assert!(verify_response(&response, &request, b"secret"));
+        assert!(!verify_response(&response, &request, b"wrong"));
+
+        response[1] = 10;
+        assert!(!verify_response(&response, &request, b"secret"));
+    }
+}
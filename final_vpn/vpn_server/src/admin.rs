@@ -0,0 +1,620 @@
+// vpn_server/src/admin.rs
+// 本地管理接口：Unix socket 上的文本命令，供运维查看运行状态
+//
+// 服务端：监听 --admin-socket（默认 /tmp/rust-vpn-admin.sock，权限 0600）
+// 客户端：`vpn_server flows --top 20` / `vpn_server denials` / `vpn_server hooks` / `vpn_server fastpath` / `vpn_server handshakes` / `vpn_server clients`
+//         / `vpn_server kick <客户端>` / `vpn_server audit` 连接该 socket 发送命令并打印结果
+//
+// 多人运维时用 --admin-tokens <文件> 给每个人发一个令牌，每行 `<名称> <角色> <令牌>`：
+// * admin：可以执行全部命令
+// * observer：只读，可以查看会话、计数、流、拒绝统计和审计记录，不能踢下线客户端
+// 指定了令牌文件时 socket 权限放宽为 0666，每个连接都必须先发送 `token <令牌>` 一行；客户端从环境变量 VPN_ADMIN_TOKEN 读取令牌。
+// 此时默认路径改为 root 所有的 /run/rust-vpn/admin.sock，不放在所有人可写的 /tmp 下；其他目录必须是 root 所有且只有 root 可写。
+// 任何本地用户都能连上 0666 的 socket，所以每行最多 MAX_LINE 字节、必须在 READ_TIMEOUT 内发完，
+// 同时处理的连接不超过 MAX_CONNECTIONS，多余的连接直接关闭。
+// 没有令牌文件时与以前相同：只有能打开 0600 socket 的用户（服务端的属主）可以连接，拥有 admin 角色。
+// 每条命令（包括被拒绝的）都记入审计记录，最近 AUDIT_CAPACITY 条可以用 `vpn_server audit` 查看。
+
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Result, anyhow};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Semaphore;
+
+use vpn_core::control::ControlMessage;
+use vpn_core::telemetry::Metrics;
+
+use crate::ServerState;
+use crate::accounting::TerminateCause;
+
+pub const DEFAULT_ADMIN_SOCKET: &str = "/tmp/rust-vpn-admin.sock";
+/// 指定了令牌文件时的默认路径（socket 为 0666，放在 root 所有的目录下）
+pub const TOKEN_ADMIN_SOCKET: &str = "/run/rust-vpn/admin.sock";
+
+/// 默认的 flows / denials 条数
+const DEFAULT_TOP: usize = 20;
+/// 保留的审计记录条数
+const AUDIT_CAPACITY: usize = 256;
+/// 令牌的最短长度
+const MIN_TOKEN_LEN: usize = 16;
+/// 管理客户端读取令牌的环境变量
+pub const TOKEN_ENV: &str = "VPN_ADMIN_TOKEN";
+/// 一行（令牌或命令）的最大字节数
+const MAX_LINE: usize = 4096;
+/// 连接后发完令牌和命令的时限
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// 写回结果的时限（对端不读时不一直占着连接）
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 同时处理的管理连接数
+const MAX_CONNECTIONS: usize = 16;
+
+/// 管理 socket 的路径：--admin-socket，否则按是否使用令牌取默认路径
+///
+/// 服务端按是否指定了 --admin-tokens，管理客户端按是否设置了 VPN_ADMIN_TOKEN
+pub fn socket_path(args: &[String], with_tokens: bool) -> String {
+    crate::arg_value(args, "--admin-socket").unwrap_or_else(|| {
+        if with_tokens { TOKEN_ADMIN_SOCKET } else { DEFAULT_ADMIN_SOCKET }.to_string()
+    })
+}
+
+/// 管理命令
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    /// 按字节数列出前 N 条活跃流
+    Flows { top: usize },
+    /// 按原因汇总的拒绝统计，以及拒绝次数最多的 N 个来源
+    Denials { top: usize },
+    /// 转发路径上已注册的钩子
+    Hooks,
+    /// eBPF 快速路径的计数，以及按字节数排序的前 N 个来源端点
+    FastPath { top: usize },
+    /// 握手计数和延迟分位数
+    Handshakes,
+    /// 在线客户端，以及它们上报的主机名、操作系统和版本（--report-metadata）
+    Clients,
+    /// 断开客户端（虚拟 IP、UDP 地址或 client_id），作废其会话恢复票据
+    Kick { target: String },
+    /// 最近的 N 条审计记录
+    Audit { top: usize },
+}
+
+impl AdminCommand {
+    /// 解析一行命令，例如 "flows --top 20"
+    pub fn parse(line: &str) -> Result<Self> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let parse_top = |n: &str| n.parse().map_err(|_| anyhow!("无效的 --top: {}", n));
+        match words.as_slice() {
+            ["flows"] => Ok(AdminCommand::Flows { top: DEFAULT_TOP }),
+            ["flows", "--top", n] => Ok(AdminCommand::Flows { top: parse_top(n)? }),
+            ["denials"] => Ok(AdminCommand::Denials { top: DEFAULT_TOP }),
+            ["denials", "--top", n] => Ok(AdminCommand::Denials { top: parse_top(n)? }),
+            ["hooks"] => Ok(AdminCommand::Hooks),
+            ["fastpath"] => Ok(AdminCommand::FastPath { top: DEFAULT_TOP }),
+            ["fastpath", "--top", n] => Ok(AdminCommand::FastPath { top: parse_top(n)? }),
+            ["handshakes"] => Ok(AdminCommand::Handshakes),
+            ["clients"] => Ok(AdminCommand::Clients),
+            ["kick", target] => Ok(AdminCommand::Kick { target: target.to_string() }),
+            ["audit"] => Ok(AdminCommand::Audit { top: DEFAULT_TOP }),
+            ["audit", "--top", n] => Ok(AdminCommand::Audit { top: parse_top(n)? }),
+            [] => Err(anyhow!("空命令")),
+            _ => Err(anyhow!(
+                "未知命令: {}（可用: flows [--top N] / denials [--top N] / hooks / fastpath [--top N] / handshakes / clients / kick <客户端> / audit [--top N]）",
+                line.trim()
+            )),
+        }
+    }
+
+    /// 命令行第一个参数是否为管理子命令
+    pub fn is_subcommand(name: &str) -> bool {
+        matches!(name, "flows" | "denials" | "hooks" | "fastpath" | "handshakes" | "clients" | "kick" | "audit")
+    }
+
+    /// observer 角色能否执行
+    pub fn is_read_only(&self) -> bool {
+        !matches!(self, AdminCommand::Kick { .. })
+    }
+}
+
+/// 管理令牌的角色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminRole {
+    /// 全部命令
+    Admin,
+    /// 只读命令
+    Observer,
+}
+
+impl AdminRole {
+    fn parse(s: &str) -> Result<Self> {
+        match s {
+            "admin" => Ok(AdminRole::Admin),
+            "observer" => Ok(AdminRole::Observer),
+            _ => Err(anyhow!("未知的角色: {}（可用: admin / observer）", s)),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            AdminRole::Admin => "admin",
+            AdminRole::Observer => "observer",
+        }
+    }
+}
+
+/// 令牌文件（--admin-tokens）：名称、角色和令牌的哈希
+#[derive(Debug, Default)]
+pub struct AdminTokens {
+    entries: Vec<(String, AdminRole, blake3::Hash)>,
+}
+
+impl AdminTokens {
+    /// `--admin-tokens <文件>`；没有指定时返回 None
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(path) = crate::arg_value(args, "--admin-tokens") else {
+            return Ok(None);
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| anyhow!("无法读取令牌文件 {}: {}", path, e))?;
+        Self::parse(&text).map(Some).map_err(|e| anyhow!("令牌文件 {} 有误: {}", path, e))
+    }
+
+    /// 每行 `<名称> <角色> <令牌>`，# 开头为注释
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut tokens = Self::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let [name, role, token] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+                return Err(anyhow!("第 {} 行应为 `<名称> <角色> <令牌>`", number + 1));
+            };
+            let role = AdminRole::parse(role).map_err(|e| anyhow!("第 {} 行: {}", number + 1, e))?;
+            if token.len() < MIN_TOKEN_LEN {
+                return Err(anyhow!("第 {} 行: 令牌至少 {} 个字符", number + 1, MIN_TOKEN_LEN));
+            }
+            let hash = blake3::hash(token.as_bytes());
+            if tokens.entries.iter().any(|(n, _, h)| n == name || *h == hash) {
+                return Err(anyhow!("第 {} 行: 名称或令牌与前面的行重复", number + 1));
+            }
+            tokens.entries.push((name.to_string(), role, hash));
+        }
+        if tokens.entries.is_empty() {
+            return Err(anyhow!("没有任何令牌"));
+        }
+        Ok(tokens)
+    }
+
+    /// 按令牌查找名称和角色（比较哈希，常数时间）
+    pub fn lookup(&self, token: &str) -> Option<(&str, AdminRole)> {
+        let hash = blake3::hash(token.as_bytes());
+        self.entries.iter().find(|(_, _, h)| *h == hash).map(|(name, role, _)| (name.as_str(), *role))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// 一条审计记录
+struct AuditEntry {
+    time: SystemTime,
+    /// 令牌名称（没有令牌文件时为 "local"，令牌无效时为 "-"）
+    who: String,
+    command: String,
+    outcome: String,
+}
+
+/// 最近的管理命令记录
+#[derive(Default)]
+struct AuditLog {
+    entries: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    fn record(&mut self, who: &str, command: &str, outcome: &str) {
+        if self.entries.len() == AUDIT_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(AuditEntry {
+            time: SystemTime::now(),
+            who: who.to_string(),
+            command: command.to_string(),
+            outcome: outcome.to_string(),
+        });
+    }
+
+    /// 最近的 top 条，新的在前
+    fn report(&self, top: usize) -> String {
+        if self.entries.is_empty() {
+            return "（没有审计记录）\n".to_string();
+        }
+        let now = SystemTime::now();
+        self.entries
+            .iter()
+            .rev()
+            .take(top)
+            .map(|e| {
+                let ago = now.duration_since(e.time).unwrap_or_default().as_secs();
+                format!("{:>6}s 前  {:<12} {:<24} {}\n", ago, e.who, e.command, e.outcome)
+            })
+            .collect()
+    }
+}
+
+/// 令牌模式下 socket 对所有人开放，所在目录必须只有 root 能写，别人无法抢先占用或替换这个路径
+///
+/// 只创建默认目录（/run/rust-vpn，不存在时以 0755 创建）；其他目录（--admin-socket 指定的）从不修改权限，
+/// 不是 root 所有或组、其他用户可写（例如 /tmp）时拒绝启动
+fn prepare_socket_dir(path: &Path) -> Result<()> {
+    let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) else {
+        return Err(anyhow!("管理 socket 路径 {} 应为绝对路径", path.display()));
+    };
+    let default_dir = Path::new(TOKEN_ADMIN_SOCKET).parent();
+    if Some(dir) == default_dir && !dir.exists() {
+        std::fs::create_dir_all(dir)?;
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o755))?;
+    }
+    let metadata = std::fs::metadata(dir).map_err(|e| anyhow!("无法读取 {}: {}", dir.display(), e))?;
+    check_socket_dir(metadata.uid(), metadata.mode()).map_err(|e| anyhow!("{} {}，使用令牌时不能把管理 socket 放在这里", dir.display(), e))
+}
+
+/// 目录的属主和权限是否可以放 0666 的管理 socket
+fn check_socket_dir(uid: u32, mode: u32) -> Result<()> {
+    if uid != 0 {
+        return Err(anyhow!("不是 root 所有"));
+    }
+    if mode & 0o022 != 0 {
+        return Err(anyhow!("对组或其他用户可写"));
+    }
+    Ok(())
+}
+
+/// 启动管理接口；指定了令牌文件时每个连接按令牌的角色授权
+pub fn spawn(state: Arc<ServerState>, path: &str, tokens: Option<AdminTokens>) -> Result<()> {
+    if tokens.is_some() {
+        prepare_socket_dir(Path::new(path))?;
+    }
+    // 上次异常退出可能遗留 socket 文件
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+
+    // 有令牌时由令牌控制访问，其他用户（多个运维人员）也可以连接
+    let mode = if tokens.is_some() { 0o666 } else { 0o600 };
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    match &tokens {
+        Some(tokens) => println!("🛠️  管理接口: {}（{} 个令牌，按角色授权）", path, tokens.len()),
+        None => println!("🛠️  管理接口: {}", path),
+    }
+
+    let tokens = Arc::new(tokens);
+    let audit = Arc::new(Mutex::new(AuditLog::default()));
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else { continue };
+            // 连接数已满：直接关闭，不为它分配任务
+            let Ok(permit) = connections.clone().try_acquire_owned() else { continue };
+            let state = state.clone();
+            let tokens = tokens.clone();
+            let audit = audit.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &state, tokens.as_ref().as_ref(), &audit).await {
+                    eprintln!("⚠️  管理连接出错: {}", e);
+                }
+                drop(permit);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// 处理一个管理连接：读一行命令（有令牌文件时先读 `token <令牌>` 一行），写回文本结果后关闭
+async fn serve(stream: UnixStream, state: &ServerState, tokens: Option<&AdminTokens>, audit: &Mutex<AuditLog>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let (token, line) = tokio::time::timeout(READ_TIMEOUT, read_request(&mut reader))
+        .await
+        .map_err(|_| anyhow!("{} 秒内没有收到完整的命令", READ_TIMEOUT.as_secs()))??;
+    let command = line.trim();
+
+    let (who, role) = match (tokens, token) {
+        (None, _) => ("local".to_string(), AdminRole::Admin),
+        (Some(tokens), Some(token)) if let Some((name, role)) = tokens.lookup(&token) => (name.to_string(), role),
+        (Some(_), _) => {
+            audit.lock().unwrap().record("-", command, "拒绝: 令牌无效");
+            let response = format!("❌ 需要有效的管理令牌（环境变量 {}）\n", TOKEN_ENV);
+            return respond(&mut writer, &response).await;
+        }
+    };
+
+    let response = match AdminCommand::parse(command) {
+        Ok(cmd) if !cmd.is_read_only() && role != AdminRole::Admin => {
+            audit.lock().unwrap().record(&who, command, &format!("拒绝: {} 角色只读", role.as_str()));
+            format!("❌ {} 的角色是 {}，只能执行只读命令\n", who, role.as_str())
+        }
+        Ok(AdminCommand::Kick { target }) => {
+            let response = kick(state, &target).await;
+            let outcome = response.lines().next().unwrap_or_default().to_string();
+            println!("🛠️  管理命令 [{}] {}: {}", who, command, outcome);
+            audit.lock().unwrap().record(&who, command, &outcome);
+            response
+        }
+        Ok(AdminCommand::Audit { top }) => {
+            let report = audit.lock().unwrap().report(top);
+            audit.lock().unwrap().record(&who, command, "ok");
+            report
+        }
+        parsed => {
+            let response = read_only(state, parsed).await;
+            let outcome = if response.starts_with('❌') { "失败" } else { "ok" };
+            audit.lock().unwrap().record(&who, command, outcome);
+            response
+        }
+    };
+    respond(&mut writer, &response).await
+}
+
+/// 读取请求：可选的 `token <令牌>` 一行，然后是命令一行；每行最多 MAX_LINE 字节
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<(Option<String>, String)> {
+    let line = read_line_bounded(reader).await?;
+    match line.strip_prefix("token ") {
+        Some(token) => Ok((Some(token.trim().to_string()), read_line_bounded(reader).await?)),
+        None => Ok((None, line)),
+    }
+}
+
+async fn read_line_bounded<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut line = String::new();
+    let n = reader.take(MAX_LINE as u64).read_line(&mut line).await?;
+    if n == MAX_LINE && !line.ends_with('\n') {
+        return Err(anyhow!("命令超过 {} 字节", MAX_LINE));
+    }
+    Ok(line)
+}
+
+/// 写回结果后关闭连接
+async fn respond<W: AsyncWrite + Unpin>(writer: &mut W, response: &str) -> Result<()> {
+    tokio::time::timeout(WRITE_TIMEOUT, async {
+        writer.write_all(response.as_bytes()).await?;
+        writer.shutdown().await
+    })
+    .await
+    .map_err(|_| anyhow!("{} 秒内没有写完结果", WRITE_TIMEOUT.as_secs()))??;
+    Ok(())
+}
+
+/// 只读命令的结果
+async fn read_only(state: &ServerState, command: Result<AdminCommand>) -> String {
+    match command {
+        Ok(AdminCommand::Flows { top }) => state.flows.lock().unwrap().report(top),
+        Ok(AdminCommand::Denials { top }) => state.denials.lock().unwrap().report(top),
+        Ok(AdminCommand::Hooks) => match state.hooks.names() {
+            names if names.is_empty() => "（没有注册钩子）\n".to_string(),
+            names => names.iter().enumerate().map(|(i, name)| format!("{:>2}. {}\n", i + 1, name)).collect(),
+        },
+        Ok(AdminCommand::FastPath { top }) => match &state.fastpath {
+            Some(fastpath) => match fastpath.stats() {
+                Ok(stats) => stats.report(top) + &state.socket.xdp_counters().map(|c| c.report()).unwrap_or_default(),
+                Err(e) => format!("❌ 无法读取 XDP 计数: {}\n", e),
+            },
+            None => "（没有启用 eBPF 快速路径，见 --xdp）\n".to_string(),
+        },
+        Ok(AdminCommand::Handshakes) => handshake_report(state.telemetry.metrics()),
+        Ok(AdminCommand::Clients) => client_report(state).await,
+        Ok(AdminCommand::Kick { .. } | AdminCommand::Audit { .. }) => unreachable!("在 serve 中处理"),
+        Err(e) => format!("❌ {}\n", e),
+    }
+}
+
+/// 断开 target 对应的全部会话：虚拟 IP、客户端的 UDP 地址或 client_id
+async fn kick(state: &ServerState, target: &str) -> String {
+    let addrs: Vec<SocketAddr> = {
+        let sessions = state.sessions.lock().await;
+        sessions
+            .iter()
+            .filter(|(addr, s)| match (target.parse::<Ipv4Addr>(), target.parse::<SocketAddr>()) {
+                (Ok(vip), _) => s.virtual_ip == Some(vip),
+                (_, Ok(target_addr)) => **addr == target_addr,
+                _ => s.client_id == target,
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    };
+    if addrs.is_empty() {
+        return format!("❌ 没有找到客户端: {}\n", target);
+    }
+    for addr in &addrs {
+        if let Some(session_key) = state.sessions.lock().await.get(addr).map(|s| s.session_key) {
+            let reason = "disconnected by administrator".to_string();
+            crate::send_control(state, *addr, &session_key, &ControlMessage::Disconnect { reason }).await;
+        }
+        crate::remove_session(state, *addr, TerminateCause::AdminReset).await;
+    }
+    let list: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    format!("✅ 已断开 {} 个会话: {}\n", addrs.len(), list.join(", "))
+}
+
+/// 握手计数，以及启动以来收到 ClientHello 到发出 ServerHello 的耗时分位数
+fn handshake_report(metrics: &Metrics) -> String {
+    let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+    let mut report = format!(
+        "握手: 开始 {}，完成 {}，失败 {}\n",
+        count(&metrics.handshakes_started),
+        count(&metrics.handshakes_completed),
+        count(&metrics.handshakes_failed),
+    );
+    let latency = &metrics.handshake_latency;
+    match [0.5, 0.9, 0.99].map(|q| latency.percentile(q)) {
+        [Some(p50), Some(p90), Some(p99)] => report += &format!(
+            "延迟（{} 次）: p50 {:.2} ms  p90 {:.2} ms  p99 {:.2} ms\n",
+            latency.count(),
+            p50.as_secs_f64() * 1000.0,
+            p90.as_secs_f64() * 1000.0,
+            p99.as_secs_f64() * 1000.0,
+        ),
+        _ => report += "延迟: （还没有完成的握手）\n",
+    }
+    report
+}
+
+/// 已认证的会话按虚拟 IP 排序，每行一个；没有上报设备信息的客户端显示"未上报"
+async fn client_report(state: &ServerState) -> String {
+    let sessions = state.sessions.lock().await;
+    let mut clients: Vec<_> = sessions.iter().filter(|(_, s)| s.authenticated).collect();
+    if clients.is_empty() {
+        return "（没有在线客户端）\n".to_string();
+    }
+    clients.sort_by_key(|(addr, s)| (s.virtual_ip, **addr));
+    clients
+        .iter()
+        .map(|(addr, s)| {
+            let vip = s.virtual_ip.map_or("-".to_string(), |ip| ip.to_string());
+            let metadata = match &s.metadata {
+                Some(m) => format!("{} / {} / v{}", m.hostname, m.os, m.version),
+                None => "未上报".to_string(),
+            };
+            let capabilities = s.capabilities.map_or("未声明".to_string(), |c| c.to_string());
+            format!(
+                "{:<15} {} {} 在线 {}s  {}  功能: {}\n",
+                vip,
+                s.client_id,
+                addr,
+                s.started_at.elapsed().as_secs(),
+                metadata,
+                capabilities,
+            )
+        })
+        .collect()
+}
+
+/// 作为管理客户端运行：把命令行上的命令发给正在运行的服务端并打印结果
+///
+/// 用法: vpn_server flows|denials|fastpath|audit [--top N] | hooks | handshakes | clients | kick <客户端> [--admin-socket <path>]
+///
+/// 服务端启用了令牌（--admin-tokens）时从环境变量 VPN_ADMIN_TOKEN 读取令牌
+pub async fn run_client(args: &[String]) -> Result<()> {
+    let token = std::env::var(TOKEN_ENV).ok();
+    let path = socket_path(args, token.is_some());
+
+    // 去掉程序名和 --admin-socket 参数，剩下的就是命令
+    let mut command = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--admin-socket" {
+            iter.next();
+        } else {
+            command.push(arg.as_str());
+        }
+    }
+    let command = command.join(" ");
+    AdminCommand::parse(&command)?;
+
+    let mut stream = UnixStream::connect(&path).await
+        .map_err(|e| anyhow!("无法连接管理接口 {}（服务端是否在运行？）: {}", path, e))?;
+    if let Some(token) = token {
+        stream.write_all(format!("token {}\n", token).as_bytes()).await?;
+    }
+    stream.write_all(format!("{}\n", command).as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    print!("{}", response);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(AdminCommand::parse("flows\n").unwrap(), AdminCommand::Flows { top: DEFAULT_TOP });
+        assert_eq!(AdminCommand::parse("flows --top 5").unwrap(), AdminCommand::Flows { top: 5 });
+        assert!(AdminCommand::parse("flows --top x").is_err());
+        assert_eq!(AdminCommand::parse("denials").unwrap(), AdminCommand::Denials { top: DEFAULT_TOP });
+        assert_eq!(AdminCommand::parse("hooks").unwrap(), AdminCommand::Hooks);
+        assert_eq!(AdminCommand::parse("fastpath --top 3").unwrap(), AdminCommand::FastPath { top: 3 });
+        assert_eq!(AdminCommand::parse("handshakes").unwrap(), AdminCommand::Handshakes);
+        assert_eq!(AdminCommand::parse("clients").unwrap(), AdminCommand::Clients);
+        assert_eq!(AdminCommand::parse("kick 10.0.0.5").unwrap(), AdminCommand::Kick { target: "10.0.0.5".to_string() });
+        assert!(AdminCommand::parse("kick").is_err());
+        assert_eq!(AdminCommand::parse("audit --top 5").unwrap(), AdminCommand::Audit { top: 5 });
+        assert!(AdminCommand::parse("reboot").is_err());
+
+        assert!(AdminCommand::parse("clients").unwrap().is_read_only());
+        assert!(AdminCommand::parse("audit").unwrap().is_read_only());
+        assert!(!AdminCommand::parse("kick laptop").unwrap().is_read_only());
+    }
+
+    #[test]
+    fn test_tokens() {
+        let tokens = AdminTokens::parse("# 运维令牌\nalice admin 0123456789abcdef0123\n\nbob observer fedcba9876543210fedc\n").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.lookup("0123456789abcdef0123"), Some(("alice", AdminRole::Admin)));
+        assert_eq!(tokens.lookup("fedcba9876543210fedc"), Some(("bob", AdminRole::Observer)));
+        assert_eq!(tokens.lookup("fedcba9876543210fed"), None);
+
+        assert!(AdminTokens::parse("").is_err());
+        assert!(AdminTokens::parse("alice root 0123456789abcdef0123").is_err());
+        assert!(AdminTokens::parse("alice admin short").is_err());
+        assert!(AdminTokens::parse("alice admin").is_err());
+        // 名称或令牌重复
+        assert!(AdminTokens::parse("a admin 0123456789abcdef0123\nb observer 0123456789abcdef0123").is_err());
+        assert!(AdminTokens::parse("a admin 0123456789abcdef0123\na observer fedcba9876543210fedc").is_err());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut audit = AuditLog::default();
+        assert!(audit.report(5).contains("没有审计记录"));
+        for i in 0..AUDIT_CAPACITY + 3 {
+            audit.record("bob", &format!("flows --top {}", i), "ok");
+        }
+        assert_eq!(audit.entries.len(), AUDIT_CAPACITY);
+        // 新的在前，超出容量时丢弃最旧的
+        let report = audit.report(2);
+        assert_eq!(report.lines().count(), 2);
+        assert!(report.lines().next().unwrap().contains(&format!("flows --top {}", AUDIT_CAPACITY + 2)));
+        assert_eq!(audit.entries.front().unwrap().command, "flows --top 3");
+    }
+
+    #[tokio::test]
+    async fn test_read_request_bounded() {
+        let mut request: &[u8] = b"token 0123456789abcdef0123\nclients\n";
+        assert_eq!(read_request(&mut request).await.unwrap(), (Some("0123456789abcdef0123".to_string()), "clients\n".to_string()));
+        let mut request: &[u8] = b"clients\n";
+        assert_eq!(read_request(&mut request).await.unwrap(), (None, "clients\n".to_string()));
+
+        // 不带换行的超长输入在 MAX_LINE 字节处停止读取并报错
+        let long = vec![b'a'; MAX_LINE * 4];
+        assert!(read_request(&mut long.as_slice()).await.is_err());
+        let long = [b"token ".as_slice(), &vec![b'a'; MAX_LINE * 4]].concat();
+        assert!(read_request(&mut long.as_slice()).await.is_err());
+
+        // 连上之后什么都不发的连接在 READ_TIMEOUT 后放弃（这里用更短的时限）
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let mut reader = BufReader::new(server);
+        assert!(tokio::time::timeout(Duration::from_millis(50), read_request(&mut reader)).await.is_err());
+        client.write_all(b"clients\n").await.unwrap();
+        assert_eq!(read_request(&mut reader).await.unwrap().1, "clients\n");
+
+        assert_eq!(socket_path(&[], false), DEFAULT_ADMIN_SOCKET);
+        // /tmp（1777）、其他用户的目录都不能放令牌模式的 socket，也不会被改权限
+        assert!(check_socket_dir(0, 0o40755).is_ok());
+        assert!(check_socket_dir(0, 0o41777).is_err());
+        assert!(check_socket_dir(0, 0o40775).is_err());
+        assert!(check_socket_dir(1000, 0o40755).is_err());
+        assert!(prepare_socket_dir(Path::new("admin.sock")).is_err());
+        assert_eq!(socket_path(&[], true), TOKEN_ADMIN_SOCKET);
+        assert_eq!(socket_path(&["vpn_server".to_string(), "--admin-socket".to_string(), "/x.sock".to_string()], true), "/x.sock");
+    }
+}
@@ -0,0 +1,298 @@
+// vpn_server/src/auth.rs
+// 可插拔的客户端认证后端：把准入决策委托给 LDAP / OIDC 等外部系统
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::pin::Pin;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use vpn_core::handshake::AuthCredential;
+
+/// 认证通过后的外部身份
+#[derive(Debug, Clone)]
+pub struct AuthIdentity {
+    /// 外部系统中的唯一标识（LDAP 用户名 / OIDC sub）
+    pub subject: String,
+}
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<AuthIdentity>> + Send + 'a>>;
+
+/// 认证后端接口
+pub trait AuthBackend: Send + Sync {
+    /// 后端名称（用于日志）
+    fn name(&self) -> &'static str;
+
+    /// 校验客户端提交的凭据
+    fn authenticate<'a>(&'a self, credential: &'a AuthCredential) -> AuthFuture<'a>;
+}
+
+/// LDAP 简单绑定认证（调用 OpenLDAP 的 ldapwhoami）
+///
+/// * `url`: 例如 "ldaps://ldap.example.com"
+/// * `dn_template`: 例如 "uid={username},ou=people,dc=example,dc=com"
+pub struct LdapBackend {
+    pub url: String,
+    pub dn_template: String,
+}
+
+impl AuthBackend for LdapBackend {
+    fn name(&self) -> &'static str {
+        "ldap"
+    }
+
+    fn authenticate<'a>(&'a self, credential: &'a AuthCredential) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let (username, password) = match credential {
+                AuthCredential::LdapBind { username, password } => (username.clone(), password.clone()),
+                _ => return Err(anyhow!("LDAP 后端只接受 LdapBind 凭据")),
+            };
+
+            // 用户名会拼进 DN，拒绝可能改写 DN 结构的字符
+            if username.is_empty() || username.contains([',', '=', '+', '<', '>', '#', ';', '\\', '"']) {
+                return Err(anyhow!("非法的 LDAP 用户名"));
+            }
+            // DN 加空密码是“未认证绑定”（RFC 4513 5.1.2），允许它的服务器（Active Directory、
+            // 开启 bind_anon_cred 的 OpenLDAP）会返回成功，等于不需要密码就能以任何用户名登录
+            if password.is_empty() {
+                return Err(anyhow!("LDAP 密码不能为空"));
+            }
+            let dn = self.dn_template.replace("{username}", &username);
+            let url = self.url.clone();
+
+            tokio::task::spawn_blocking(move || {
+                // 密码通过 stdin 传入（-y /dev/stdin），避免出现在进程列表里
+                let mut child = Command::new("ldapwhoami")
+                    .args(["-x", "-H", &url, "-D", &dn, "-y", "/dev/stdin"])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .map_err(|e| anyhow!("无法执行 ldapwhoami: {}", e))?;
+
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(password.as_bytes())?;
+                }
+
+                if child.wait()?.success() {
+                    Ok(AuthIdentity { subject: username })
+                } else {
+                    Err(anyhow!("LDAP 绑定失败"))
+                }
+            }).await?
+        })
+    }
+}
+
+/// OIDC access token 认证（RFC 7662 token introspection）
+pub struct OidcBackend {
+    pub introspection_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl AuthBackend for OidcBackend {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authenticate<'a>(&'a self, credential: &'a AuthCredential) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let token = match credential {
+                AuthCredential::OidcToken { access_token } => access_token.clone(),
+                _ => return Err(anyhow!("OIDC 后端只接受 OidcToken 凭据")),
+            };
+
+            // 这些值会写进 curl 配置文件的引号里，拒绝能跳出引号的字符
+            let fields = [&self.client_id, &self.client_secret, &token, &self.introspection_url];
+            if fields.iter().any(|f| f.contains(['"', '\\', '\n', '\r'])) {
+                return Err(anyhow!("凭据中包含非法字符"));
+            }
+
+            // 参数通过 curl --config 从 stdin 传入，client_secret 和 token 不出现在进程列表里
+            let curl_config = format!(
+                "silent\nfail\nuser = \"{}:{}\"\ndata-urlencode = \"token={}\"\nurl = \"{}\"\n",
+                self.client_id, self.client_secret, token, self.introspection_url
+            );
+
+            let output = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+                let mut child = Command::new("curl")
+                    .args(["--config", "-"])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn()
+                    .map_err(|e| anyhow!("无法执行 curl: {}", e))?;
+
+                if let Some(mut stdin) = child.stdin.take() {
+                    stdin.write_all(curl_config.as_bytes())?;
+                }
+
+                let output = child.wait_with_output()?;
+                if !output.status.success() {
+                    return Err(anyhow!("introspection 请求失败 (exit code: {:?})", output.status.code()));
+                }
+                Ok(output.stdout)
+            }).await??;
+
+            parse_introspection(&output)
+        })
+    }
+}
+
+/// 解析 introspection 响应：要求 active=true，并取 sub（或 username）作为身份
+fn parse_introspection(body: &[u8]) -> Result<AuthIdentity> {
+    let json: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| anyhow!("introspection 响应不是合法 JSON: {}", e))?;
+
+    if json["active"] != serde_json::Value::Bool(true) {
+        return Err(anyhow!("token 无效或已过期"));
+    }
+
+    let subject = json["sub"].as_str()
+        .or_else(|| json["username"].as_str())
+        .ok_or_else(|| anyhow!("introspection 响应缺少 sub"))?;
+
+    Ok(AuthIdentity { subject: subject.to_string() })
+}
+
+/// 外部身份 -> 虚拟 IP 的绑定表
+///
+/// 文件格式：每行 `<subject> <虚拟IP>`，`#` 开头为注释
+#[derive(Debug, Default)]
+pub struct IdentityIpMap {
+    entries: HashMap<String, Ipv4Addr>,
+}
+
+impl IdentityIpMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut entries = HashMap::new();
+
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (Some(subject), Some(ip), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(anyhow!("第 {} 行格式错误: {}", lineno + 1, line));
+            };
+            let ip = ip.parse::<Ipv4Addr>()
+                .map_err(|_| anyhow!("第 {} 行 IP 无效: {}", lineno + 1, ip))?;
+            entries.insert(subject.to_string(), ip);
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// 绑定给该身份的虚拟 IP
+    pub fn get(&self, subject: &str) -> Option<Ipv4Addr> {
+        self.entries.get(subject).copied()
+    }
+
+    /// 表中绑定的全部虚拟 IP
+    pub fn addresses(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        self.entries.values().copied()
+    }
+
+    /// 检查身份是否允许使用该虚拟 IP
+    ///
+    /// 表中有绑定时必须完全一致；没有绑定的身份可以使用表外的 IP，但不能使用绑定给其他身份的 IP
+    pub fn check(&self, subject: &str, requested: Ipv4Addr) -> Result<()> {
+        match self.entries.get(subject) {
+            Some(ip) if *ip != requested => Err(anyhow!(
+                "身份 {} 绑定的虚拟 IP 为 {}，但请求了 {}", subject, ip, requested
+            )),
+            Some(_) => Ok(()),
+            None => match self.entries.iter().find(|(_, ip)| **ip == requested) {
+                Some((owner, _)) => Err(anyhow!("虚拟 IP {} 已绑定给身份 {}，{} 不能使用", requested, owner, subject)),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// 服务端认证配置
+pub struct AuthConfig {
+    pub backend: Box<dyn AuthBackend>,
+    pub ip_map: IdentityIpMap,
+}
+
+/// 从命令行参数构建认证配置，未指定 --auth 时返回 None（保持原有的仅 PSK 模式）
+///
+/// * `--auth ldap --ldap-url <url> --ldap-dn-template <tpl>`
+/// * `--auth oidc --oidc-introspect-url <url> --oidc-client-id <id>`（secret 取自 VPN_OIDC_CLIENT_SECRET）
+/// * `--auth-ip-map <file>`：可选的身份 -> 虚拟 IP 绑定表
+pub fn from_args(args: &[String]) -> Result<Option<Arc<AuthConfig>>> {
+    let value = |name: &str| -> Result<String> {
+        crate::arg_value(args, name).ok_or_else(|| anyhow!("缺少参数 {}", name))
+    };
+
+    let backend: Box<dyn AuthBackend> = match crate::arg_value(args, "--auth").as_deref() {
+        None => return Ok(None),
+        Some("ldap") => Box::new(LdapBackend {
+            url: value("--ldap-url")?,
+            dn_template: value("--ldap-dn-template")?,
+        }),
+        Some("oidc") => Box::new(OidcBackend {
+            introspection_url: value("--oidc-introspect-url")?,
+            client_id: value("--oidc-client-id")?,
+            client_secret: std::env::var("VPN_OIDC_CLIENT_SECRET")
+                .map_err(|_| anyhow!("缺少环境变量 VPN_OIDC_CLIENT_SECRET"))?,
+        }),
+        Some(other) => return Err(anyhow!("未知的认证后端: {}", other)),
+    };
+
+    let ip_map = match crate::arg_value(args, "--auth-ip-map") {
+        Some(path) => IdentityIpMap::load(Path::new(&path))?,
+        None => IdentityIpMap::default(),
+    };
+
+    Ok(Some(Arc::new(AuthConfig { backend, ip_map })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_ip_map() {
+        let map = IdentityIpMap::parse("# 绑定表\nalice 10.0.0.5\n\nbob   10.0.0.6\n").unwrap();
+
+        assert!(map.check("alice", "10.0.0.5".parse().unwrap()).is_ok());
+        assert!(map.check("alice", "10.0.0.6".parse().unwrap()).is_err());
+        assert!(map.check("carol", "10.0.0.9".parse().unwrap()).is_ok());
+        // 没有绑定的身份不能使用绑定给别人的 IP
+        assert!(map.check("carol", "10.0.0.5".parse().unwrap()).is_err());
+        assert!(map.check("bob", "10.0.0.5".parse().unwrap()).is_err());
+
+        assert!(IdentityIpMap::parse("alice").is_err());
+        assert!(IdentityIpMap::parse("alice not-an-ip").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ldap_rejects_empty_password() {
+        let backend = LdapBackend { url: "ldap://127.0.0.1:1".to_string(), dn_template: "uid={username},dc=example".to_string() };
+        let credential = AuthCredential::LdapBind { username: "alice".to_string(), password: String::new() };
+        let e = backend.authenticate(&credential).await.unwrap_err();
+        assert!(e.to_string().contains("密码不能为空"), "{}", e);
+    }
+
+    #[test]
+    fn test_parse_introspection() {
+        let identity = parse_introspection(br#"{"active":true,"sub":"user-123"}"#).unwrap();
+        assert_eq!(identity.subject, "user-123");
+
+        assert!(parse_introspection(br#"{"active":false,"sub":"user-123"}"#).is_err());
+        assert!(parse_introspection(br#"{"active":true}"#).is_err());
+    }
+}
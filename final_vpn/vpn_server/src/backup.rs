@@ -0,0 +1,479 @@
+// vpn_server/src/backup.rs
+// 服务端状态的备份和恢复：`vpn_server export-state` / `import-state`
+//
+// 迁移主机或灾难恢复时需要带走的状态分散在几个地方：keys 目录（服务端密钥对、known_clients 登记表），
+// 以及参数或配置文件引用的文件（配置文件本身、--client-ip-map / --auth-ip-map 地址绑定表、PSK 文件、WASM 策略模块、管理令牌）。
+// export-state 把它们打成一个加密的归档，import-state 在新主机上按原来的路径写回（keys 目录下的文件写到新主机的 keys 目录）。
+//
+// 归档格式：MAGIC + 版本（1 字节）+ Cipher::encrypt(明文)，明文为：
+//   [创建时间 u64][条目数 u16]，每个条目 [类型 u8][名字长度 u16][名字][权限 u32][数据长度 u32][数据]（整数均为大端）
+// 类型 KIND_KEYS 的名字是 keys 目录下的文件名，KIND_FILE 的名字是导出时的绝对路径。
+//
+// 归档密钥是导出时随机生成的 32 字节，以 64 个十六进制字符写入单独的密钥文件（默认 <归档>.key，权限 0600）。
+// 归档和密钥文件应分开保存和传输：只拿到归档无法得到服务端私钥和 PSK。
+//
+// 密封到 TPM 的私钥（<私钥>.tpm.*）只能在原来的机器上解封，默认不导出；指定 --unseal-keys 时在导出机上解封，
+// 以明文私钥放进归档（归档本身是加密的），新主机上以 --tpm-seal 启动时会重新密封。
+// IPAM 的动态租约只在内存中，不在归档里；需要在迁移后保持不变的地址应写进 --client-ip-map。
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use vpn_core::asymmetric::get_keys_dir;
+use vpn_core::config;
+use vpn_core::engine::Role;
+use vpn_core::symmetric::Cipher;
+use vpn_core::tpm;
+
+const MAGIC: &[u8] = b"RVPNSTATE";
+const VERSION: u8 = 1;
+
+const KIND_KEYS: u8 = 1;
+const KIND_FILE: u8 = 2;
+
+/// 引用状态文件的参数（可重复的参数每次出现都导出）
+const FILE_ARGS: &[&str] = &["--config", "--client-ip-map", "--auth-ip-map", "--psk-file", "--previous-psk-file", "--wasm-policy", "--admin-tokens"];
+
+const USAGE: &str = "用法: vpn_server export-state <归档> [--config <文件>] [服务端参数...] [--key-file <文件>] [--unseal-keys]\n      vpn_server import-state <归档> [--key-file <文件>] [--list] [--force]";
+
+/// 归档中的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub target: Target,
+    /// Unix 权限位
+    pub mode: u32,
+    pub data: Vec<u8>,
+}
+
+/// 文件恢复到哪里
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// keys 目录下的文件名
+    Keys(String),
+    /// 导出时的绝对路径
+    File(PathBuf),
+}
+
+impl Target {
+    fn path(&self, keys_dir: &Path) -> PathBuf {
+        match self {
+            Target::Keys(name) => keys_dir.join(name),
+            Target::File(path) => path.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Keys(name) => write!(f, "<keys>/{}", name),
+            Target::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// 解开的归档
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Archive {
+    pub created: SystemTime,
+    pub entries: Vec<Entry>,
+}
+
+impl Archive {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let created = self.created.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let count = u16::try_from(self.entries.len()).map_err(|_| anyhow!("文件太多"))?;
+        let mut out = Vec::new();
+        out.extend_from_slice(&created.to_be_bytes());
+        out.extend_from_slice(&count.to_be_bytes());
+        for entry in &self.entries {
+            let (kind, name) = match &entry.target {
+                Target::Keys(name) => (KIND_KEYS, name.clone()),
+                Target::File(path) => (KIND_FILE, path.to_str().ok_or_else(|| anyhow!("路径不是 UTF-8: {}", path.display()))?.to_string()),
+            };
+            let name_len = u16::try_from(name.len()).map_err(|_| anyhow!("路径太长: {}", name))?;
+            let data_len = u32::try_from(entry.data.len()).map_err(|_| anyhow!("文件太大: {}", name))?;
+            out.push(kind);
+            out.extend_from_slice(&name_len.to_be_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&entry.mode.to_be_bytes());
+            out.extend_from_slice(&data_len.to_be_bytes());
+            out.extend_from_slice(&entry.data);
+        }
+        Ok(out)
+    }
+
+    fn decode(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let created = SystemTime::UNIX_EPOCH + Duration::from_secs(u64::from_be_bytes(reader.array()?));
+        let count = u16::from_be_bytes(reader.array()?);
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let [kind] = reader.array()?;
+            let name_len = u16::from_be_bytes(reader.array()?) as usize;
+            let name = std::str::from_utf8(reader.take(name_len)?).map_err(|_| anyhow!("归档中的路径不是 UTF-8"))?;
+            let target = match kind {
+                KIND_KEYS if is_plain_name(name) => Target::Keys(name.to_string()),
+                KIND_FILE if Path::new(name).is_absolute() => Target::File(PathBuf::from(name)),
+                _ => return Err(anyhow!("归档中的条目无效: {}", name)),
+            };
+            let mode = u32::from_be_bytes(reader.array()?);
+            let data_len = u32::from_be_bytes(reader.array()?) as usize;
+            let data = reader.take(data_len)?.to_vec();
+            entries.push(Entry { target, mode, data });
+        }
+        if !reader.0.is_empty() {
+            return Err(anyhow!("归档末尾有多余的数据"));
+        }
+        Ok(Self { created, entries })
+    }
+
+    /// 加密：MAGIC + 版本 + 密文
+    pub fn seal(&self, key: &[u8; 32]) -> Result<Vec<u8>> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(Cipher::new(key)?.encrypt(&self.encode()?)?);
+        Ok(out)
+    }
+
+    /// 解密并解析；密钥不对或归档被改动时报错
+    pub fn open(data: &[u8], key: &[u8; 32]) -> Result<Self> {
+        let body = data.strip_prefix(MAGIC).ok_or_else(|| anyhow!("不是 rust-vpn 状态归档"))?;
+        let (&version, ciphertext) = body.split_first().ok_or_else(|| anyhow!("归档不完整"))?;
+        if version != VERSION {
+            return Err(anyhow!("不支持的归档版本 {}（本程序支持 {}）", version, VERSION));
+        }
+        let plaintext = Cipher::new(key)?.decrypt(ciphertext).map_err(|_| anyhow!("解密失败：密钥文件与归档不匹配，或归档已损坏"))?;
+        Self::decode(&plaintext)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(anyhow!("归档不完整"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+/// keys 目录下的文件名不能带路径分隔符，防止恢复时写到目录外
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
+/// 收集要导出的文件：keys 目录下的普通文件（隐藏文件除外），加上 FILE_ARGS 引用的文件
+///
+/// unseal 为 false 时跳过密封到 TPM 的私钥（返回的警告中列出），为 true 时解封后以明文导出
+pub fn collect(args: &[String], keys_dir: &Path, unseal: bool) -> Result<(Vec<Entry>, Vec<String>)> {
+    let mut entries = Vec::new();
+    let mut warnings = Vec::new();
+
+    let mut names = match std::fs::read_dir(keys_dir) {
+        Ok(dir) => dir
+            .map(|entry| entry.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<Vec<_>>>()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(anyhow!("无法读取 keys 目录 {}: {}", keys_dir.display(), e)),
+    };
+    names.sort();
+    for name in &names {
+        let path = keys_dir.join(name);
+        if let Some(key) = name.strip_suffix(".tpm.pub") {
+            let key_path = keys_dir.join(key);
+            if !tpm::is_sealed(&key_path) {
+                continue;
+            }
+            if !unseal {
+                warnings.push(format!("{} 已密封到 TPM，未导出（需要时以 --unseal-keys 在本机解封后导出）", key_path.display()));
+                continue;
+            }
+            let data = tpm::unseal(&key_path).with_context(|| format!("无法解封 {}", key_path.display()))?;
+            entries.push(Entry { target: Target::Keys(key.to_string()), mode: 0o600, data });
+            continue;
+        }
+        if name.starts_with('.') || name.ends_with(".tpm.priv") || !path.is_file() {
+            continue;
+        }
+        entries.push(read_entry(Target::Keys(name.clone()), &path)?);
+    }
+
+    for name in FILE_ARGS {
+        for file in crate::arg_values(args, name) {
+            let path = std::path::absolute(&file).with_context(|| format!("无效的路径 {}", file))?;
+            let target = Target::File(path.clone());
+            if entries.iter().any(|e| e.target == target) {
+                continue;
+            }
+            entries.push(read_entry(target, &path).with_context(|| format!("{} 引用的文件", name))?);
+        }
+    }
+    Ok((entries, warnings))
+}
+
+fn read_entry(target: Target, path: &Path) -> Result<Entry> {
+    let data = std::fs::read(path).with_context(|| format!("无法读取 {}", path.display()))?;
+    #[cfg(unix)]
+    let mode = std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(path)?.permissions()) & 0o7777;
+    #[cfg(not(unix))]
+    let mode = 0o600;
+    Ok(Entry { target, mode, data })
+}
+
+/// 把归档中的文件写回；目标已存在（包括已密封到 TPM 的私钥）且没有 force 时不写任何文件
+pub fn restore(archive: &Archive, keys_dir: &Path, force: bool) -> Result<Vec<PathBuf>> {
+    if !force {
+        let existing: Vec<String> = archive.entries.iter()
+            .map(|e| e.target.path(keys_dir))
+            .filter(|path| path.exists() || tpm::is_sealed(path))
+            .map(|path| path.display().to_string())
+            .collect();
+        if !existing.is_empty() {
+            return Err(anyhow!("以下文件已存在，未做任何改动（确认覆盖时加 --force）:\n   {}", existing.join("\n   ")));
+        }
+    }
+
+    let mut written = Vec::new();
+    for entry in &archive.entries {
+        let path = entry.target.path(keys_dir);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("无法创建目录 {}", parent.display()))?;
+        }
+        write_file(&path, &entry.data, entry.mode).with_context(|| format!("无法写入 {}", path.display()))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn write_file(path: &Path, data: &[u8], mode: u32) -> Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    options.open(path)?.write_all(data)?;
+    // 已有的文件不会按 OpenOptions 的 mode 修改权限
+    #[cfg(unix)]
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+/// 归档密钥文件：一行 64 个十六进制字符，权限 0600，不覆盖已有的文件
+fn save_key(path: &Path, key: &[u8; 32]) -> Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).with_context(|| format!("无法创建密钥文件 {}", path.display()))?;
+    if let Err(e) = writeln!(file, "{}", hex::encode(key)) {
+        let _ = std::fs::remove_file(path);
+        return Err(anyhow!("无法写入密钥文件 {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+/// 写出归档和密钥文件：先写归档，再写密钥文件（create_new）；任何一步失败都删掉已写的文件，
+/// 重新导出不会因为残留的密钥文件失败
+fn write_export(archive_path: &Path, sealed: &[u8], key_path: &Path, key: &[u8; 32]) -> Result<()> {
+    if let Err(e) = std::fs::write(archive_path, sealed) {
+        let _ = std::fs::remove_file(archive_path);
+        return Err(anyhow!("无法写入 {}: {}", archive_path.display(), e));
+    }
+    if let Err(e) = save_key(key_path, key) {
+        let _ = std::fs::remove_file(archive_path);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn load_key(path: &Path) -> Result<[u8; 32]> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("无法读取密钥文件 {}", path.display()))?;
+    hex::decode(text.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("密钥文件 {} 无效：应为 64 个十六进制字符", path.display()))
+}
+
+/// 默认的密钥文件位置：<归档>.key
+fn default_key_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(".key");
+    PathBuf::from(name)
+}
+
+/// `vpn_server export-state <归档> ...`
+pub fn run_export(args: &[String]) -> Result<()> {
+    let archive_path = PathBuf::from(args.get(2).filter(|a| !a.starts_with("--")).ok_or_else(|| anyhow!(USAGE))?);
+    let key_path = crate::arg_value(args, "--key-file").map(PathBuf::from).unwrap_or_else(|| default_key_path(&archive_path));
+    let unseal = args.contains(&"--unseal-keys".to_string());
+    // 配置文件中的 psk_file 等也要展开，才能找到它们引用的文件
+    let args = config::load_args(args, Role::Server)?;
+    let keys_dir = get_keys_dir()?;
+
+    let (entries, warnings) = collect(&args, &keys_dir, unseal)?;
+    if entries.is_empty() {
+        return Err(anyhow!("没有可导出的状态（keys 目录 {} 为空，也没有引用任何文件）", keys_dir.display()));
+    }
+    if archive_path.exists() {
+        return Err(anyhow!("{} 已存在", archive_path.display()));
+    }
+    let key: [u8; 32] = rand::random();
+    let sealed = Archive { created: SystemTime::now(), entries }.seal(&key)?;
+    write_export(&archive_path, &sealed, &key_path, &key)?;
+
+    let archive = Archive::open(&std::fs::read(&archive_path)?, &key)?;
+    println!("📦 已导出服务端状态: {}（{} 个文件）", archive_path.display(), archive.entries.len());
+    for entry in &archive.entries {
+        println!("   {}（{} 字节）", entry.target, entry.data.len());
+    }
+    for warning in warnings {
+        println!("⚠️  {}", warning);
+    }
+    println!("🔑 归档密钥: {}（权限 0600）", key_path.display());
+    println!("   归档包含服务端私钥和 PSK，请与密钥文件分开保存和传输");
+    println!("   恢复: vpn_server import-state {} --key-file <密钥文件>", archive_path.display());
+    println!("   IPAM 的动态租约只在运行中的服务端内存里，不在归档中；迁移后需要保持不变的地址请写进 --client-ip-map");
+    Ok(())
+}
+
+/// `vpn_server import-state <归档> ...`
+pub fn run_import(args: &[String]) -> Result<()> {
+    let archive_path = PathBuf::from(args.get(2).filter(|a| !a.starts_with("--")).ok_or_else(|| anyhow!(USAGE))?);
+    let key_path = crate::arg_value(args, "--key-file").map(PathBuf::from).unwrap_or_else(|| default_key_path(&archive_path));
+    let data = std::fs::read(&archive_path).with_context(|| format!("无法读取 {}", archive_path.display()))?;
+    let archive = Archive::open(&data, &load_key(&key_path)?)?;
+    let keys_dir = get_keys_dir()?;
+
+    let age = SystemTime::now().duration_since(archive.created).unwrap_or_default().as_secs();
+    println!("📦 {}（{} 小时前导出，{} 个文件）", archive_path.display(), age / 3600, archive.entries.len());
+    if args.contains(&"--list".to_string()) {
+        for entry in &archive.entries {
+            println!("   {}（{} 字节，权限 {:o}）", entry.target, entry.data.len(), entry.mode);
+        }
+        return Ok(());
+    }
+
+    let written = restore(&archive, &keys_dir, args.contains(&"--force".to_string()))?;
+    for path in &written {
+        println!("   ✅ {}", path.display());
+    }
+    println!("✅ 已恢复 {} 个文件，keys 目录: {}", written.len(), keys_dir.display());
+    println!("   以导出时的参数（或 --config）启动服务端；IPAM 的动态租约不在归档中，重启后重新分配");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-backup-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_archive_roundtrip() {
+        let archive = Archive {
+            created: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            entries: vec![
+                Entry { target: Target::Keys("server_private.key".into()), mode: 0o600, data: vec![7; 32] },
+                Entry { target: Target::File("/etc/rust-vpn/client-ip-map".into()), mode: 0o644, data: b"alice 10.0.0.5\n".to_vec() },
+                Entry { target: Target::File("/etc/rust-vpn/empty".into()), mode: 0o644, data: Vec::new() },
+            ],
+        };
+        let key = [9u8; 32];
+        let sealed = archive.seal(&key).unwrap();
+        assert_eq!(Archive::open(&sealed, &key).unwrap(), archive);
+
+        // 密钥不对、被改动或截断都无法解开
+        assert!(Archive::open(&sealed, &[8u8; 32]).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(Archive::open(&tampered, &key).is_err());
+        assert!(Archive::open(&sealed[..sealed.len() - 1], &key).is_err());
+        assert!(Archive::open(b"not an archive", &key).is_err());
+
+        // keys 目录的条目不能带路径，其他条目必须是绝对路径
+        for target in [Target::Keys("../escape".into()), Target::File("relative/path".into())] {
+            let bad = Archive { created: archive.created, entries: vec![Entry { target, mode: 0o600, data: Vec::new() }] };
+            assert!(Archive::open(&bad.seal(&key).unwrap(), &key).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_export_leaves_nothing_on_failure() {
+        let dir = temp_dir("export");
+        let archive = dir.join("vpn.rvpn");
+        let key = dir.join("vpn.rvpn.key");
+
+        // 归档写不进去：不创建密钥文件
+        assert!(write_export(&dir.join("missing").join("vpn.rvpn"), b"sealed", &key, &[1u8; 32]).is_err());
+        assert!(!key.exists());
+
+        // 密钥文件写不进去：删掉已写的归档
+        assert!(write_export(&archive, b"sealed", &dir.join("missing").join("vpn.rvpn.key"), &[1u8; 32]).is_err());
+        assert!(!archive.exists());
+
+        // 之后可以正常导出
+        write_export(&archive, b"sealed", &key, &[1u8; 32]).unwrap();
+        assert_eq!(load_key(&key).unwrap(), [1u8; 32]);
+        assert_eq!(std::fs::read(&archive).unwrap(), b"sealed");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect_and_restore() {
+        let dir = temp_dir("collect");
+        let keys_dir = dir.join("keys");
+        std::fs::create_dir_all(&keys_dir).unwrap();
+        std::fs::write(keys_dir.join("server_public.key"), [1u8; 32]).unwrap();
+        std::fs::write(keys_dir.join("known_clients"), "laptop 00\n").unwrap();
+        let ip_map = dir.join("client-ip-map");
+        std::fs::write(&ip_map, "laptop 10.0.0.5\n").unwrap();
+        let args = vec!["--client-ip-map".to_string(), ip_map.display().to_string()];
+
+        let (entries, warnings) = collect(&args, &keys_dir, false).unwrap();
+        assert!(warnings.is_empty());
+        let targets: Vec<String> = entries.iter().map(|e| e.target.to_string()).collect();
+        assert_eq!(targets, ["<keys>/known_clients", "<keys>/server_public.key", &ip_map.display().to_string()]);
+        // 引用的文件不存在时报错，而不是导出一个不完整的归档
+        assert!(collect(&["--psk-file".to_string(), dir.join("missing").display().to_string()], &keys_dir, false).is_err());
+
+        // 恢复到新主机：keys 目录不同，其他文件按原路径写回
+        let archive = Archive { created: SystemTime::now(), entries };
+        let new_keys = dir.join("new-keys");
+        assert!(restore(&archive, &new_keys, false).is_err());
+        std::fs::remove_file(&ip_map).unwrap();
+        let written = restore(&archive, &new_keys, false).unwrap();
+        assert_eq!(written.len(), 3);
+        assert_eq!(std::fs::read(new_keys.join("server_public.key")).unwrap(), [1u8; 32]);
+        assert_eq!(std::fs::read_to_string(&ip_map).unwrap(), "laptop 10.0.0.5\n");
+
+        // 再次恢复需要 --force
+        assert!(restore(&archive, &new_keys, false).is_err());
+        std::fs::write(&ip_map, "changed\n").unwrap();
+        restore(&archive, &new_keys, true).unwrap();
+        assert_eq!(std::fs::read_to_string(&ip_map).unwrap(), "laptop 10.0.0.5\n");
+
+        let key_path = default_key_path(&dir.join("state.rvpn"));
+        assert_eq!(key_path, dir.join("state.rvpn.key"));
+        save_key(&key_path, &[5u8; 32]).unwrap();
+        assert_eq!(load_key(&key_path).unwrap(), [5u8; 32]);
+        assert!(save_key(&key_path, &[6u8; 32]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
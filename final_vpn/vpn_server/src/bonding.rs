@@ -0,0 +1,109 @@
+// vpn_server/src/bonding.rs
+// 多路径绑定（--bonding，实验性）：一个会话可以从两个来源地址发送上行数据
+//
+// 客户端的第二条链路用 PathJoin 加入已有会话后，它的来源地址被登记为主地址的别名，
+// 之后来自别名的数据包按主地址的会话解密、去重和转发；下行和控制消息仍只发往主地址。
+// round-robin 模式的包带有序号，按会话放进重排缓冲区，按序放行后才进入转发流程。
+// 协议细节见 vpn_core::multipath。
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use vpn_core::multipath::{REORDER_HOLD, ReorderBuffer};
+
+/// 别名表和按会话的重排缓冲区（只在同步代码中访问，使用 std Mutex）
+#[derive(Debug, Default)]
+pub struct Bonding {
+    /// 第二条链路的来源地址 -> 会话的主地址
+    aliases: Mutex<HashMap<SocketAddr, SocketAddr>>,
+    reorder: Mutex<HashMap<SocketAddr, ReorderBuffer>>,
+    /// 一次放行多个包时，第一个随当前数据报返回，其余的等引擎 flush 时取走
+    ready: Mutex<Vec<(SocketAddr, Vec<u8>)>>,
+}
+
+impl Bonding {
+    /// `--bonding`：允许客户端用第二条链路加入会话，未指定时返回 None
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        args.contains(&"--bonding".to_string()).then(Self::default)
+    }
+
+    /// 来源地址对应的会话主地址（不是别名时原样返回）
+    pub fn primary(&self, addr: SocketAddr) -> SocketAddr {
+        self.aliases.lock().unwrap().get(&addr).copied().unwrap_or(addr)
+    }
+
+    /// 登记第二条链路，返回 true 表示是新加入的（而不是重复的 PathJoin）
+    pub fn join(&self, secondary: SocketAddr, primary: SocketAddr) -> bool {
+        self.aliases.lock().unwrap().insert(secondary, primary) != Some(primary)
+    }
+
+    /// 会话结束：删除它的别名和重排缓冲区
+    pub fn forget(&self, primary: SocketAddr) {
+        self.aliases.lock().unwrap().retain(|alias, p| *p != primary && *alias != primary);
+        self.reorder.lock().unwrap().remove(&primary);
+        self.ready.lock().unwrap().retain(|(addr, _)| *addr != primary);
+    }
+
+    /// 一个带序号的包进入重排缓冲区，返回现在可以转发的第一个包，其余的暂存到 ready
+    pub fn push(&self, primary: SocketAddr, seq: u64, ip_packet: Vec<u8>) -> Option<Vec<u8>> {
+        let released = self.reorder.lock().unwrap().entry(primary).or_default().push(seq, ip_packet, Instant::now());
+        let mut released = released.into_iter();
+        let first = released.next();
+        self.ready.lock().unwrap().extend(released.map(|p| (primary, p)));
+        first
+    }
+
+    /// 取走暂存的包，以及等待超时（REORDER_HOLD）后放行的包
+    pub fn take_ready(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut ready = std::mem::take(&mut *self.ready.lock().unwrap());
+        let now = Instant::now();
+        for (primary, buffer) in self.reorder.lock().unwrap().iter_mut() {
+            ready.extend(buffer.expire(now).into_iter().map(|p| (*primary, p)));
+        }
+        ready
+    }
+
+    /// 引擎在没有新数据报时多久检查一次超时
+    pub fn flush_interval(&self) -> std::time::Duration {
+        REORDER_HOLD / 2
+    }
+
+    /// 所有第二条链路的来源地址
+    pub fn secondaries(&self) -> Vec<SocketAddr> {
+        self.aliases.lock().unwrap().keys().copied().collect()
+    }
+
+    pub fn aliases(&self) -> usize {
+        self.aliases.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aliases_and_reorder() {
+        let primary: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let secondary: SocketAddr = "203.0.113.7:50000".parse().unwrap();
+        let bonding = Bonding::default();
+
+        assert!(bonding.join(secondary, primary));
+        assert!(!bonding.join(secondary, primary));
+        assert_eq!(bonding.primary(secondary), primary);
+        assert_eq!(bonding.primary(primary), primary);
+
+        // 乱序到达：补齐后第一个随当前包返回，其余的由 take_ready 取走
+        assert_eq!(bonding.push(primary, 1, vec![1]), Some(vec![1]));
+        assert_eq!(bonding.push(primary, 3, vec![3]), None);
+        assert_eq!(bonding.push(primary, 2, vec![2]), Some(vec![2]));
+        assert_eq!(bonding.take_ready(), vec![(primary, vec![3])]);
+        assert!(bonding.take_ready().is_empty());
+
+        bonding.forget(primary);
+        assert_eq!(bonding.primary(secondary), secondary);
+        assert_eq!(bonding.aliases(), 0);
+    }
+}
@@ -0,0 +1,193 @@
+// vpn_server/src/clients.rs
+// 客户端身份登记：client_id（UUID）-> 身份公钥，首次出现时记录（TOFU），之后必须使用同一把公钥
+//
+// ClientHello 的签名只证明客户端持有它声明的公钥；把 UUID 固定到第一次见到的公钥上，
+// 其他人即使知道某个 UUID 也无法冒用它（以及绑定到它的虚拟 IP）。
+// 登记表保存在 keys/known_clients，每行 `<client_id> <公钥hex>`；客户端重新生成身份后需要删除对应行。
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Result, anyhow};
+use vpn_core::asymmetric::{is_valid_client_id, key_fingerprint};
+
+use crate::auth::IdentityIpMap;
+use crate::denials::DenyReason;
+
+const KNOWN_CLIENTS_FILE: &str = "known_clients";
+
+/// 登记表的容量：新身份可以随意生成，登记满后只接受已登记的身份，避免登记表（和文件）无限增长
+pub const MAX_KNOWN_CLIENTS: usize = 65536;
+
+/// 同一身份从另一个地址再次连接时的处理方式（--duplicate-policy）
+///
+/// 典型场景：笔记本休眠唤醒后换了出口地址重新握手，旧会话还没有因保活超时被清理。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// 新连接替换旧会话，并通知旧会话断开（默认）
+    Replace,
+    /// 已有会话时拒绝新连接
+    Reject,
+    /// 两个会话同时保留；虚拟 IP 相同时无法同时路由，按 Replace 处理
+    Allow,
+}
+
+/// 对新连接的处理结果
+#[derive(Debug, PartialEq)]
+pub enum DuplicateDecision {
+    /// 接受新连接，并移除这些旧会话
+    Accept { replace: Vec<SocketAddr> },
+    /// 拒绝新连接
+    Reject,
+}
+
+impl DuplicatePolicy {
+    /// `--duplicate-policy replace|reject|allow`，默认 replace
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        match crate::arg_value(args, "--duplicate-policy").as_deref() {
+            None | Some("replace") => Ok(DuplicatePolicy::Replace),
+            Some("reject") => Ok(DuplicatePolicy::Reject),
+            Some("allow") => Ok(DuplicatePolicy::Allow),
+            Some(other) => Err(anyhow!("无效的 --duplicate-policy: {}（可选 replace、reject、allow）", other)),
+        }
+    }
+
+    /// existing：同一身份在其他地址上的会话及其虚拟 IP
+    pub fn decide(&self, requested: Option<Ipv4Addr>, existing: &[(SocketAddr, Option<Ipv4Addr>)]) -> DuplicateDecision {
+        let replace = match self {
+            DuplicatePolicy::Reject if !existing.is_empty() => return DuplicateDecision::Reject,
+            DuplicatePolicy::Replace | DuplicatePolicy::Reject => existing.iter().map(|(addr, _)| *addr).collect(),
+            DuplicatePolicy::Allow => existing.iter()
+                .filter(|(_, vip)| vip.is_some() && *vip == requested)
+                .map(|(addr, _)| *addr)
+                .collect(),
+        };
+        DuplicateDecision::Accept { replace }
+    }
+}
+
+/// 客户端身份登记表和按身份绑定的虚拟 IP
+pub struct ClientRegistry {
+    path: PathBuf,
+    keys: Mutex<HashMap<String, [u8; 32]>>,
+    /// client_id -> 虚拟 IP（--client-ip-map），没有绑定的身份不限制 IP
+    ip_map: IdentityIpMap,
+}
+
+impl ClientRegistry {
+    /// 加载 keys_dir 下的登记表；`--client-ip-map <file>` 指定按 client_id 绑定的虚拟 IP（格式同 --auth-ip-map）
+    pub fn from_args(args: &[String], keys_dir: &Path) -> Result<Self> {
+        let path = keys_dir.join(KNOWN_CLIENTS_FILE);
+        let keys = match std::fs::read_to_string(&path) {
+            Ok(content) => parse_known_clients(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let ip_map = match crate::arg_value(args, "--client-ip-map") {
+            Some(file) => IdentityIpMap::load(Path::new(&file))?,
+            None => IdentityIpMap::default(),
+        };
+        Ok(Self { path, keys: Mutex::new(keys), ip_map })
+    }
+
+    /// 已登记的客户端数
+    pub fn known_count(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    /// 校验已通过签名验证的身份：公钥与登记的一致（新身份在此登记），虚拟 IP 符合绑定
+    pub fn check(&self, client_id: &str, public_key: &[u8; 32], virtual_ip: Option<Ipv4Addr>) -> Result<(), DenyReason> {
+        {
+            let mut keys = self.keys.lock().unwrap();
+            match keys.get(client_id) {
+                Some(known) if known != public_key => return Err(DenyReason::IdentityKeyMismatch),
+                Some(_) => {}
+                None if keys.len() >= MAX_KNOWN_CLIENTS => return Err(DenyReason::RegistryFull),
+                None => {
+                    if let Err(e) = self.append(client_id, public_key) {
+                        eprintln!("⚠️  客户端登记表写入失败: {}", e);
+                    }
+                    println!("   🆕 新客户端身份: {} (公钥 {})", client_id, key_fingerprint(public_key));
+                    keys.insert(client_id.to_string(), *public_key);
+                }
+            }
+        }
+        match virtual_ip {
+            Some(ip) => self.ip_map.check(client_id, ip).map_err(|_| DenyReason::IdentityIpMismatch),
+            None => Ok(()),
+        }
+    }
+
+    fn append(&self, client_id: &str, public_key: &[u8; 32]) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} {}", client_id, hex::encode(public_key))?;
+        Ok(())
+    }
+}
+
+fn parse_known_clients(content: &str) -> Result<HashMap<String, [u8; 32]>> {
+    let mut keys = HashMap::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(id), Some(key), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(anyhow!("known_clients 第 {} 行格式错误: {}", lineno + 1, line));
+        };
+        let key: [u8; 32] = hex::decode(key).ok()
+            .and_then(|k| k.try_into().ok())
+            .filter(|_| is_valid_client_id(id))
+            .ok_or_else(|| anyhow!("known_clients 第 {} 行无效: {}", lineno + 1, line))?;
+        keys.insert(id.to_string(), key);
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_pins_first_key() {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-clients-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let map = dir.join("client_ip_map");
+        let id = "0f8fad5b-d9cb-469f-a165-70867728950e";
+        std::fs::write(&map, format!("{} 10.0.0.5\n", id)).unwrap();
+        let args = vec!["--client-ip-map".to_string(), map.display().to_string()];
+
+        let registry = ClientRegistry::from_args(&args, &dir).unwrap();
+        assert_eq!(registry.check(id, &[1u8; 32], Some(Ipv4Addr::new(10, 0, 0, 5))), Ok(()));
+        assert_eq!(registry.check(id, &[2u8; 32], None), Err(DenyReason::IdentityKeyMismatch));
+        assert_eq!(registry.check(id, &[1u8; 32], Some(Ipv4Addr::new(10, 0, 0, 6))), Err(DenyReason::IdentityIpMismatch));
+
+        // 重启后登记表仍然有效
+        let reloaded = ClientRegistry::from_args(&[], &dir).unwrap();
+        assert_eq!(reloaded.known_count(), 1);
+        assert_eq!(reloaded.check(id, &[2u8; 32], None), Err(DenyReason::IdentityKeyMismatch));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let old: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let other: SocketAddr = "198.51.100.2:40000".parse().unwrap();
+        let ip5 = Some(Ipv4Addr::new(10, 0, 0, 5));
+        let existing = [(old, ip5), (other, Some(Ipv4Addr::new(10, 0, 0, 6)))];
+
+        assert_eq!(DuplicatePolicy::from_args(&[]).unwrap(), DuplicatePolicy::Replace);
+        assert!(DuplicatePolicy::from_args(&["--duplicate-policy".to_string(), "kick".to_string()]).is_err());
+
+        assert_eq!(DuplicatePolicy::Replace.decide(ip5, &existing), DuplicateDecision::Accept { replace: vec![old, other] });
+        assert_eq!(DuplicatePolicy::Reject.decide(ip5, &existing), DuplicateDecision::Reject);
+        assert_eq!(DuplicatePolicy::Reject.decide(ip5, &[]), DuplicateDecision::Accept { replace: vec![] });
+        // allow 只替换占用了同一虚拟 IP 的会话
+        assert_eq!(DuplicatePolicy::Allow.decide(ip5, &existing), DuplicateDecision::Accept { replace: vec![old] });
+        assert_eq!(DuplicatePolicy::Allow.decide(Some(Ipv4Addr::new(10, 0, 0, 7)), &existing), DuplicateDecision::Accept { replace: vec![] });
+    }
+}
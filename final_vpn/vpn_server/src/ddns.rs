@@ -0,0 +1,239 @@
+// vpn_server/src/ddns.rs
+// 动态 DNS：定期检查服务端的公网 IP，变化后更新到 DDNS 服务商
+//
+// 公网 IP 优先取端口映射（--port-map）得到的外部地址，否则请求一个“查询本机 IP”的 HTTP 服务。
+// HTTPS 请求交给 curl，参数通过 --config 从 stdin 传入，token 不出现在进程列表里。
+
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+
+use crate::portmap::PortMapper;
+
+/// 默认检查周期
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(300);
+/// 默认的公网 IP 查询服务（返回纯文本 IP）
+const DEFAULT_IP_URL: &str = "https://api.ipify.org";
+
+/// DDNS 服务商
+#[derive(Debug, Clone, PartialEq)]
+pub enum DdnsProvider {
+    /// DuckDNS：`<domain>.duckdns.org`
+    DuckDns { domain: String },
+    /// Cloudflare DNS API：更新指定 zone 下的一条 A 记录
+    Cloudflare { zone_id: String, record_id: String, name: String },
+    /// 通用 HTTP API：GET 模板 URL，其中的 `{ip}` 替换为当前 IP
+    Generic { url: String },
+}
+
+impl DdnsProvider {
+    fn name(&self) -> &'static str {
+        match self {
+            DdnsProvider::DuckDns { .. } => "duckdns",
+            DdnsProvider::Cloudflare { .. } => "cloudflare",
+            DdnsProvider::Generic { .. } => "generic",
+        }
+    }
+}
+
+/// DDNS 更新器
+pub struct DdnsUpdater {
+    provider: DdnsProvider,
+    token: String,
+    interval: Duration,
+    ip_url: String,
+}
+
+impl DdnsUpdater {
+    /// 从命令行参数构建，未指定 --ddns 时返回 None
+    ///
+    /// * `--ddns duckdns|cloudflare|generic`
+    /// * `--ddns-domain <name>`：DuckDNS 子域名 / Cloudflare 记录名
+    /// * `--ddns-zone <id>` `--ddns-record <id>`：Cloudflare 的 zone 和 DNS 记录 ID
+    /// * `--ddns-url <url>`：通用 API 的 URL 模板，例如 `https://dyn.example.com/update?ip={ip}`
+    /// * `--ddns-interval <秒>`：检查周期，默认 300
+    /// * `--ddns-ip-url <url>`：公网 IP 查询服务，默认 https://api.ipify.org
+    /// * token 取自环境变量 VPN_DDNS_TOKEN（通用 API 可不设置）
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(kind) = crate::arg_value(args, "--ddns") else {
+            return Ok(None);
+        };
+        let require = |name: &str| crate::arg_value(args, name).ok_or_else(|| anyhow!("--ddns {} 需要参数 {}", kind, name));
+
+        let provider = match kind.as_str() {
+            "duckdns" => DdnsProvider::DuckDns { domain: require("--ddns-domain")? },
+            "cloudflare" => DdnsProvider::Cloudflare {
+                zone_id: require("--ddns-zone")?,
+                record_id: require("--ddns-record")?,
+                name: require("--ddns-domain")?,
+            },
+            "generic" => DdnsProvider::Generic { url: require("--ddns-url")? },
+            other => return Err(anyhow!("未知的 DDNS 服务商: {}（可选 duckdns / cloudflare / generic）", other)),
+        };
+
+        let token = std::env::var("VPN_DDNS_TOKEN").unwrap_or_default();
+        if token.is_empty() && !matches!(provider, DdnsProvider::Generic { .. }) {
+            return Err(anyhow!("{} 需要环境变量 VPN_DDNS_TOKEN", provider.name()));
+        }
+
+        let interval = match crate::arg_value(args, "--ddns-interval") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| anyhow!("无效的 --ddns-interval: {}", secs))?),
+            None => DEFAULT_INTERVAL,
+        };
+
+        Ok(Some(Self {
+            provider,
+            token,
+            interval,
+            ip_url: crate::arg_value(args, "--ddns-ip-url").unwrap_or_else(|| DEFAULT_IP_URL.to_string()),
+        }))
+    }
+
+    /// 启动后台任务：每个周期检查一次公网 IP，变化时更新记录（失败下个周期重试）
+    pub fn spawn(self, port_mapper: Option<Arc<PortMapper>>) {
+        println!("🌍 DDNS 已启用（{}，每 {} 秒检查一次）", self.provider.name(), self.interval.as_secs());
+        tokio::spawn(async move {
+            let mut published: Option<Ipv4Addr> = None;
+            let mut ticker = tokio::time::interval(self.interval);
+            loop {
+                ticker.tick().await;
+                let ip = match port_mapper.as_ref().and_then(|m| m.external()) {
+                    Some(addr) => match addr.ip() {
+                        std::net::IpAddr::V4(ip) => Ok(ip),
+                        std::net::IpAddr::V6(_) => continue,
+                    },
+                    None => self.lookup_public_ip().await,
+                };
+                let ip = match ip {
+                    Ok(ip) => ip,
+                    Err(e) => {
+                        eprintln!("⚠️  DDNS 获取公网 IP 失败: {}", e);
+                        continue;
+                    }
+                };
+                if published == Some(ip) {
+                    continue;
+                }
+                match self.update(ip).await {
+                    Ok(_) => {
+                        println!("🌍 DDNS 已更新: {}", ip);
+                        published = Some(ip);
+                    }
+                    Err(e) => eprintln!("⚠️  DDNS 更新失败: {}", e),
+                }
+            }
+        });
+    }
+
+    async fn lookup_public_ip(&self) -> Result<Ipv4Addr> {
+        let body = curl(format!("silent\nfail\nmax-time = 10\nurl = \"{}\"\n", curl_escape(&self.ip_url)?)).await?;
+        body.trim().parse().map_err(|_| anyhow!("公网 IP 服务返回了无效内容: {}", body.trim()))
+    }
+
+    async fn update(&self, ip: Ipv4Addr) -> Result<()> {
+        let body = curl(request_config(&self.provider, &self.token, ip)?).await?;
+        check_response(&self.provider, &body)
+    }
+}
+
+/// 转义 curl 配置文件中带引号的值；拒绝换行，避免注入额外的配置项
+fn curl_escape(value: &str) -> Result<String> {
+    if value.contains(['\n', '\r']) {
+        return Err(anyhow!("参数中包含换行符"));
+    }
+    Ok(value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// 生成更新请求的 curl 配置
+fn request_config(provider: &DdnsProvider, token: &str, ip: Ipv4Addr) -> Result<String> {
+    let mut config = String::from("silent\nfail\nmax-time = 10\n");
+    match provider {
+        DdnsProvider::DuckDns { domain } => {
+            config.push_str(&format!(
+                "url = \"https://www.duckdns.org/update?domains={}&token={}&ip={}\"\n",
+                curl_escape(domain)?, curl_escape(token)?, ip
+            ));
+        }
+        DdnsProvider::Cloudflare { zone_id, record_id, name } => {
+            let body = serde_json::json!({ "type": "A", "name": name, "content": ip.to_string(), "ttl": 60 });
+            config.push_str(&format!(
+                "request = \"PUT\"\nheader = \"Authorization: Bearer {}\"\nheader = \"Content-Type: application/json\"\ndata = \"{}\"\nurl = \"https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}\"\n",
+                curl_escape(token)?, curl_escape(&body.to_string())?, curl_escape(zone_id)?, curl_escape(record_id)?
+            ));
+        }
+        DdnsProvider::Generic { url } => {
+            if !token.is_empty() {
+                config.push_str(&format!("header = \"Authorization: Bearer {}\"\n", curl_escape(token)?));
+            }
+            config.push_str(&format!("url = \"{}\"\n", curl_escape(&url.replace("{ip}", &ip.to_string()))?));
+        }
+    }
+    Ok(config)
+}
+
+/// 检查服务商的响应体（HTTP 状态已由 curl --fail 检查）
+fn check_response(provider: &DdnsProvider, body: &str) -> Result<()> {
+    match provider {
+        DdnsProvider::DuckDns { .. } if body.trim() != "OK" => Err(anyhow!("DuckDNS 返回 {}", body.trim())),
+        DdnsProvider::Cloudflare { .. } => {
+            let json: serde_json::Value = serde_json::from_str(body).map_err(|e| anyhow!("Cloudflare 响应不是合法 JSON: {}", e))?;
+            if json["success"] == serde_json::Value::Bool(true) {
+                Ok(())
+            } else {
+                Err(anyhow!("Cloudflare 返回错误: {}", json["errors"]))
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn curl(config: String) -> Result<String> {
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut child = Command::new("curl")
+            .args(["--config", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("无法执行 curl: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(config.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(anyhow!("HTTP 请求失败 (exit code: {:?})", output.status.code()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_config() {
+        let ip = Ipv4Addr::new(203, 0, 113, 7);
+        let duck = DdnsProvider::DuckDns { domain: "myvpn".to_string() };
+        let config = request_config(&duck, "secret", ip).unwrap();
+        assert!(config.contains("url = \"https://www.duckdns.org/update?domains=myvpn&token=secret&ip=203.0.113.7\""));
+        assert!(check_response(&duck, "OK").is_ok());
+        assert!(check_response(&duck, "KO").is_err());
+
+        let cf = DdnsProvider::Cloudflare { zone_id: "z".to_string(), record_id: "r".to_string(), name: "vpn.example.com".to_string() };
+        let config = request_config(&cf, "tok", ip).unwrap();
+        assert!(config.contains("data = \"{\\\"content\\\":\\\"203.0.113.7\\\""));
+        assert!(config.contains("zones/z/dns_records/r"));
+        assert!(check_response(&cf, r#"{"success":true}"#).is_ok());
+
+        let generic = DdnsProvider::Generic { url: "https://dyn.example.com/update?ip={ip}".to_string() };
+        assert!(request_config(&generic, "", ip).unwrap().contains("update?ip=203.0.113.7"));
+        assert!(request_config(&duck, "a\nurl = \"http://evil\"", ip).is_err());
+    }
+}
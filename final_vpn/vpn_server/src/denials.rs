@@ -0,0 +1,247 @@
+// vpn_server/src/denials.rs
+// 拒绝原因统计：握手/认证/数据面上被静默丢弃的请求，按来源 IP 和原因计数
+//
+// 排查“客户端连不上”时，用 `vpn_server denials` 查看某个地址被拒绝的原因，
+// 不需要再临时加打印。
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use vpn_core::handshake::HandshakeErrorCode;
+
+/// 最多跟踪的来源地址数，超过后淘汰最久未出现的来源
+pub const MAX_SOURCES: usize = 4096;
+
+/// 拒绝原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DenyReason {
+    /// 数据包来自未握手的地址
+    UnknownSession,
+    /// 数据报头无法识别（不是本协议的报文，或旧版本的客户端）
+    BadPacket,
+    /// 会话已协商密钥但尚未通过认证
+    Unauthenticated,
+    /// 会话已协商密钥但客户端还没有发送 ClientFinish
+    Unconfirmed,
+    /// ClientFinish 无法用会话密钥验证
+    BadFinish,
+    /// 数据包解密失败（密钥不匹配或被篡改）
+    DecryptFailed,
+    /// 服务端 ML-KEM 封装失败（ClientHello 中的 ML-KEM 公钥无效）
+    KeyExchangeFailed,
+    /// 服务端对握手签名或派生会话密钥失败（服务端自身的问题，例如签名后端不可用）
+    ServerError,
+    /// 收到不支持的握手消息类型
+    UnexpectedHandshake,
+    /// 服务端未启用认证时收到 ClientAuth
+    AuthNotEnabled,
+    /// 收到 ClientAuth 但该地址没有已确认（ClientFinish）的握手
+    AuthWithoutSession,
+    /// 认证凭据无法解密（会话密钥不一致）
+    BadCredential,
+    /// 认证后端拒绝，或身份与虚拟 IP 不匹配
+    AuthRejected,
+    /// ClientHello 的客户端身份签名无效或 client_id 格式错误
+    BadIdentity,
+    /// 客户端 ID 已登记了另一把公钥（疑似冒用）
+    IdentityKeyMismatch,
+    /// 客户端 ID 绑定了其他虚拟 IP（--client-ip-map）
+    IdentityIpMismatch,
+    /// 同一身份已有会话，按 --duplicate-policy reject 拒绝新连接
+    DuplicateIdentity,
+    /// 会话恢复被拒绝（票据不存在或已过期、证明无效或被重放、未启用 --session-resume）
+    ResumeRejected,
+    /// 第二条链路加入会话被拒绝（路径 ID 不存在、证明无效或未启用 --bonding）
+    PathJoinRejected,
+    /// 握手消息的协议版本与服务端不兼容
+    BadVersion,
+    /// 请求的虚拟 IP 正被另一个身份的会话使用
+    VirtualIpInUse,
+    /// 请求自动分配虚拟 IP，但没有可用的地址（地址用完，或 --ipam static 下身份没有绑定）
+    NoFreeAddress,
+    /// 已达到 --max-clients
+    ServerFull,
+    /// 等待认证的会话已达上限
+    Overloaded,
+    /// 客户端登记表已满，不再接受新身份
+    RegistryFull,
+    /// 要接替同一身份旧会话（其他地址上的，或同一地址上已确认的）的新连接超时没有发送有效的 ClientFinish（疑似重放的 ClientHello）
+    TakeoverUnconfirmed,
+    /// 客户端版本低于 --min-client-version
+    OutdatedClient,
+    /// ClientHello 中的 PSK ID 不是当前的 PSK，也不是宽限期内的旧 PSK（--psk-file / --previous-psk-file）
+    UnknownPsk,
+}
+
+impl DenyReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenyReason::UnknownSession => "unknown_session",
+            DenyReason::BadPacket => "bad_packet",
+            DenyReason::Unauthenticated => "unauthenticated",
+            DenyReason::Unconfirmed => "unconfirmed",
+            DenyReason::BadFinish => "bad_finish",
+            DenyReason::DecryptFailed => "decrypt_failed",
+            DenyReason::KeyExchangeFailed => "key_exchange_failed",
+            DenyReason::ServerError => "server_error",
+            DenyReason::UnexpectedHandshake => "unexpected_handshake",
+            DenyReason::AuthNotEnabled => "auth_not_enabled",
+            DenyReason::AuthWithoutSession => "auth_without_session",
+            DenyReason::BadCredential => "bad_credential",
+            DenyReason::AuthRejected => "auth_rejected",
+            DenyReason::BadIdentity => "bad_identity",
+            DenyReason::IdentityKeyMismatch => "identity_key_mismatch",
+            DenyReason::IdentityIpMismatch => "identity_ip_mismatch",
+            DenyReason::DuplicateIdentity => "duplicate_identity",
+            DenyReason::ResumeRejected => "resume_rejected",
+            DenyReason::PathJoinRejected => "path_join_rejected",
+            DenyReason::BadVersion => "bad_version",
+            DenyReason::VirtualIpInUse => "virtual_ip_in_use",
+            DenyReason::NoFreeAddress => "no_free_address",
+            DenyReason::ServerFull => "server_full",
+            DenyReason::Overloaded => "overloaded",
+            DenyReason::RegistryFull => "registry_full",
+            DenyReason::TakeoverUnconfirmed => "takeover_unconfirmed",
+            DenyReason::OutdatedClient => "outdated_client",
+            DenyReason::UnknownPsk => "unknown_psk",
+        }
+    }
+
+    /// 可以告诉客户端的握手拒绝原因（HandshakeError）；其他原因只记录，不回复
+    pub fn error_code(&self) -> Option<HandshakeErrorCode> {
+        match self {
+            DenyReason::BadVersion => Some(HandshakeErrorCode::BadVersion),
+            DenyReason::BadIdentity | DenyReason::IdentityKeyMismatch | DenyReason::UnknownPsk => Some(HandshakeErrorCode::Unauthorized),
+            DenyReason::ServerFull | DenyReason::Overloaded | DenyReason::RegistryFull => Some(HandshakeErrorCode::ServerFull),
+            DenyReason::IdentityIpMismatch | DenyReason::VirtualIpInUse | DenyReason::NoFreeAddress => Some(HandshakeErrorCode::IpConflict),
+            DenyReason::OutdatedClient => Some(HandshakeErrorCode::UpgradeRequired),
+            DenyReason::KeyExchangeFailed => Some(HandshakeErrorCode::BadKeyShare),
+            DenyReason::DuplicateIdentity => Some(HandshakeErrorCode::DuplicateSession),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DenyReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 单个来源的拒绝记录
+#[derive(Debug, Clone)]
+pub struct SourceDenials {
+    pub counts: BTreeMap<DenyReason, u64>,
+    pub last_reason: DenyReason,
+    pub last_seen: Instant,
+}
+
+impl SourceDenials {
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+/// 拒绝统计表
+#[derive(Debug, Default)]
+pub struct DenialTable {
+    sources: HashMap<IpAddr, SourceDenials>,
+    /// 按原因的累计值（不受来源淘汰影响）
+    totals: BTreeMap<DenyReason, u64>,
+}
+
+impl DenialTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次拒绝
+    pub fn record(&mut self, source: IpAddr, reason: DenyReason) {
+        *self.totals.entry(reason).or_default() += 1;
+
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_SOURCES
+            && let Some(oldest) = self.sources.iter().min_by_key(|(_, d)| d.last_seen).map(|(ip, _)| *ip)
+        {
+            self.sources.remove(&oldest);
+        }
+
+        let now = Instant::now();
+        let entry = self.sources.entry(source).or_insert_with(|| SourceDenials {
+            counts: BTreeMap::new(),
+            last_reason: reason,
+            last_seen: now,
+        });
+        *entry.counts.entry(reason).or_default() += 1;
+        entry.last_reason = reason;
+        entry.last_seen = now;
+    }
+
+    /// 生成 `denials` 的文本报告：按原因汇总 + 拒绝次数最多的前 n 个来源
+    pub fn report(&self, n: usize) -> String {
+        let now = Instant::now();
+        let mut out = String::from("拒绝原因汇总:\n");
+        if self.totals.is_empty() {
+            out.push_str("   （无）\n");
+            return out;
+        }
+        for (reason, count) in &self.totals {
+            out.push_str(&format!("{:>10}  {}\n", count, reason));
+        }
+
+        let mut sources: Vec<(&IpAddr, &SourceDenials)> = self.sources.iter().collect();
+        sources.sort_by_key(|(_, d)| std::cmp::Reverse(d.total()));
+        sources.truncate(n);
+
+        out.push_str(&format!("\n来源（按拒绝次数前 {} 个）:\n", sources.len()));
+        out.push_str(&format!("{:>10} {:>8}  {:<40} {}\n", "TOTAL", "AGO(s)", "SOURCE", "REASONS"));
+        for (ip, d) in sources {
+            let reasons: Vec<String> = d.counts.iter().map(|(r, c)| format!("{}×{}", r, c)).collect();
+            out.push_str(&format!(
+                "{:>10} {:>8}  {:<40} {}（最近: {}）\n",
+                d.total(),
+                now.duration_since(d.last_seen).as_secs(),
+                ip,
+                reasons.join(" "),
+                d.last_reason
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_report() {
+        let mut table = DenialTable::new();
+        let a: IpAddr = "203.0.113.5".parse().unwrap();
+        let b: IpAddr = "198.51.100.7".parse().unwrap();
+
+        table.record(a, DenyReason::DecryptFailed);
+        table.record(a, DenyReason::DecryptFailed);
+        table.record(a, DenyReason::AuthRejected);
+        table.record(b, DenyReason::UnknownSession);
+
+        let d = &table.sources[&a];
+        assert_eq!(d.total(), 3);
+        assert_eq!(d.counts[&DenyReason::DecryptFailed], 2);
+        assert_eq!(d.last_reason, DenyReason::AuthRejected);
+        assert_eq!(table.totals[&DenyReason::UnknownSession], 1);
+
+        let report = table.report(1);
+        assert!(report.contains("203.0.113.5"));
+        assert!(!report.contains("198.51.100.7"));
+        assert!(report.contains("decrypt_failed×2"));
+
+        // 只有可以告诉客户端的原因才回复 HandshakeError
+        assert_eq!(DenyReason::IdentityIpMismatch.error_code(), Some(HandshakeErrorCode::IpConflict));
+        assert_eq!(DenyReason::Overloaded.error_code(), Some(HandshakeErrorCode::ServerFull));
+        assert_eq!(DenyReason::KeyExchangeFailed.error_code(), Some(HandshakeErrorCode::BadKeyShare));
+        assert_eq!(DenyReason::ServerError.error_code(), None);
+        assert_eq!(DenyReason::DecryptFailed.error_code(), None);
+    }
+}
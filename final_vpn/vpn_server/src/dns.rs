@@ -0,0 +1,282 @@
+// vpn_server/src/dns.rs
+// 隧道内的 DNS 转发器：按主机名解析在线的客户端，其余查询转发给上游
+//
+// 客户端每次（重新）握手后在控制通道里上报主机名（ControlMessage::Hostname），
+// `<主机名>.<域>`（默认 `.vpn`）在查询时从会话表中取当前的虚拟 IP，客户端上线/下线后立即生效：
+//
+//   laptop.vpn  A     -> 10.0.0.2
+//   laptop.vpn  AAAA  -> fd00::a00:2（开启 --ipv6 时）
+//   其他名字          -> 转发给上游（--dns-upstream，默认 /etc/resolv.conf 中的第一个 nameserver）
+//
+// 转发器监听在服务端的隧道地址 10.0.0.1:53，客户端用 `--dns 10.0.0.1` 使用它。
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+use vpn_core::local_tun;
+
+use crate::ServerState;
+
+/// 默认的域
+const DEFAULT_DOMAIN: &str = "vpn";
+/// 记录的 TTL（秒）：客户端的地址随上下线变化，不宜缓存太久
+const RECORD_TTL: u32 = 30;
+/// 同时等待上游响应的查询数，超出时丢弃（queue.dropped）
+const MAX_FORWARDS: usize = 256;
+/// 等待上游响应的时间
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_MESSAGE: usize = 4096;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// DNS 转发器的配置
+#[derive(Debug, Clone)]
+pub struct DnsForwarder {
+    domain: String,
+    upstream: SocketAddr,
+    /// 是否回答 AAAA（--ipv6）
+    ipv6: bool,
+}
+
+impl DnsForwarder {
+    /// 从命令行参数构建，未指定 --dns-forwarder 时返回 None
+    ///
+    /// * `--dns-domain <域>`：客户端主机名所在的域，默认 vpn
+    /// * `--dns-upstream <ip[:port]>`：其他查询的上游，默认取 /etc/resolv.conf
+    pub fn from_args(args: &[String], ipv6: bool) -> Result<Option<Self>> {
+        if !args.contains(&"--dns-forwarder".to_string()) {
+            return Ok(None);
+        }
+        let domain = crate::arg_value(args, "--dns-domain")
+            .map(|d| d.trim_matches('.').to_ascii_lowercase())
+            .unwrap_or_else(|| DEFAULT_DOMAIN.to_string());
+        if domain.is_empty() || !domain.split('.').all(vpn_core::control::is_valid_hostname) {
+            return Err(anyhow!("无效的 --dns-domain: {}", domain));
+        }
+        let upstream = match crate::arg_value(args, "--dns-upstream") {
+            Some(spec) => parse_upstream(&spec).ok_or_else(|| anyhow!("无效的 --dns-upstream: {}（应为 ip 或 ip:port）", spec))?,
+            None => {
+                let text = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+                let ip = first_nameserver(&text).ok_or_else(|| anyhow!("/etc/resolv.conf 中没有 nameserver，请用 --dns-upstream 指定上游"))?;
+                SocketAddr::new(ip, 53)
+            }
+        };
+        Ok(Some(Self { domain, upstream, ipv6 }))
+    }
+
+    /// 查询名在本域内时返回主机名（`laptop.vpn` -> `laptop`）；域本身和多级子域返回空串（没有记录）
+    fn local_label<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if name == self.domain {
+            return Some("");
+        }
+        let label = name.strip_suffix(self.domain.as_str())?.strip_suffix('.')?;
+        Some(if label.contains('.') { "" } else { label })
+    }
+
+    /// 在隧道地址上启动转发器（需要 root 绑定 53 端口）
+    pub async fn spawn(self, state: Arc<ServerState>, listen: SocketAddr) -> Result<()> {
+        let socket = Arc::new(UdpSocket::bind(listen).await.map_err(|e| anyhow!("无法监听 {}: {}", listen, e))?);
+        println!("📒 DNS 转发器已启用: {}（*.{} 解析为在线客户端，其余转发给 {}）", listen, self.domain, self.upstream);
+        let forwards = Arc::new(Semaphore::new(MAX_FORWARDS));
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_MESSAGE];
+            loop {
+                let Ok((n, src)) = socket.recv_from(&mut buf).await else { continue };
+                let packet = &buf[..n];
+                let Some(query) = parse_query(packet) else { continue };
+                match self.local_label(&query.name) {
+                    Some(label) => {
+                        let vip = crate::lookup_hostname(&state, label).await;
+                        let response = answer(packet, &query, vip, self.ipv6);
+                        let _ = socket.send_to(&response, src).await;
+                    }
+                    None => {
+                        let Ok(permit) = forwards.clone().try_acquire_owned() else {
+                            crate::record_overflow(&state, "dns_forward_full");
+                            continue;
+                        };
+                        let (socket, packet, upstream) = (socket.clone(), packet.to_vec(), self.upstream);
+                        tokio::spawn(async move {
+                            if let Ok(response) = forward(&packet, upstream).await {
+                                let _ = socket.send_to(&response, src).await;
+                            }
+                            drop(permit);
+                        });
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// 把查询原样发给上游，等待 ID 相同的响应
+async fn forward(packet: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(upstream).await?;
+    socket.send(packet).await?;
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    tokio::time::timeout(FORWARD_TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            if n >= HEADER_LEN && buf[..2] == packet[..2] {
+                return Ok(buf[..n].to_vec());
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow!("上游 {} 无响应", upstream))?
+}
+
+/// `ip` 或 `ip:port`（IPv6 带端口时写作 `[ip]:port`）
+fn parse_upstream(spec: &str) -> Option<SocketAddr> {
+    spec.parse().ok().or_else(|| spec.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 53)))
+}
+
+/// resolv.conf 中的第一个 nameserver
+fn first_nameserver(text: &str) -> Option<IpAddr> {
+    text.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|rest| rest.trim().parse().ok())
+}
+
+/// 查询报文中的唯一问题
+#[derive(Debug, PartialEq)]
+struct Query {
+    /// 小写、以点分隔、不带末尾的点
+    name: String,
+    qtype: u16,
+    /// 问题部分结束的位置（回复时原样复制问题）
+    end: usize,
+}
+
+/// 解析标准查询（QR=0、OPCODE=0、恰好一个问题）；其他报文返回 None
+fn parse_query(packet: &[u8]) -> Option<Query> {
+    let header = packet.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if flags & 0xf800 != 0 || u16::from_be_bytes([header[4], header[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LEN;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // 问题中的名字不应使用压缩指针
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(packet.get(offset..offset + len)?).ok()?.to_ascii_lowercase());
+        offset += len;
+    }
+    let fixed = packet.get(offset..offset + 4)?;
+    if u16::from_be_bytes([fixed[2], fixed[3]]) != CLASS_IN {
+        return None;
+    }
+    Some(Query { name: labels.join("."), qtype: u16::from_be_bytes([fixed[0], fixed[1]]), end: offset + 4 })
+}
+
+/// 本域内查询的权威回答：有地址时回答 A（开启 IPv6 时还有 AAAA），名字不存在时返回 NXDOMAIN
+fn answer(packet: &[u8], query: &Query, vip: Option<Ipv4Addr>, ipv6: bool) -> Vec<u8> {
+    let rdata = match (vip, query.qtype) {
+        (Some(ip), TYPE_A) => Some(ip.octets().to_vec()),
+        (Some(ip), TYPE_AAAA) if ipv6 => Some(local_tun::tunnel_ipv6(ip).octets().to_vec()),
+        _ => None,
+    };
+    // QR=1、AA=1，保留 RD，RA=1
+    let recursion_desired = u16::from_be_bytes([packet[2], packet[3]]) & 0x0100;
+    let rcode = if vip.is_none() { RCODE_NXDOMAIN } else { 0 };
+    let flags = 0x8400 | recursion_desired | 0x0080 | rcode;
+
+    let mut out = Vec::with_capacity(query.end + 28);
+    out.extend_from_slice(&packet[..2]);
+    out.extend_from_slice(&flags.to_be_bytes());
+    for count in [1u16, u16::from(rdata.is_some()), 0, 0] {
+        out.extend_from_slice(&count.to_be_bytes());
+    }
+    out.extend_from_slice(&packet[HEADER_LEN..query.end]);
+    if let Some(rdata) = rdata {
+        // 名字用指向问题的压缩指针
+        out.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        out.extend_from_slice(&query.qtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_answer_local_names() {
+        let forwarder = DnsForwarder { domain: "vpn".to_string(), upstream: "1.1.1.1:53".parse().unwrap(), ipv6: false };
+        assert_eq!(forwarder.local_label("laptop.vpn"), Some("laptop"));
+        assert_eq!(forwarder.local_label("a.b.vpn"), Some(""));
+        assert_eq!(forwarder.local_label("vpn"), Some(""));
+        assert_eq!(forwarder.local_label("example.com"), None);
+        assert_eq!(forwarder.local_label("myvpn"), None);
+
+        let packet = query(0x1234, "Laptop.VPN", TYPE_A);
+        let q = parse_query(&packet).unwrap();
+        assert_eq!((q.name.as_str(), q.qtype, q.end), ("laptop.vpn", TYPE_A, packet.len()));
+
+        let response = answer(&packet, &q, Some(Ipv4Addr::new(10, 0, 0, 2)), false);
+        assert_eq!(&response[..4], [0x12, 0x34, 0x85, 0x80]);
+        assert_eq!(&response[6..8], [0, 1]);
+        assert_eq!(&response[response.len() - 4..], [10, 0, 0, 2]);
+
+        // 不存在的名字：NXDOMAIN；没有开启 IPv6 时 AAAA 没有记录
+        let missing = answer(&packet, &q, None, false);
+        assert_eq!((missing[3] & 0x0f, &missing[6..8]), (3, &[0u8, 0][..]));
+        let packet = query(1, "laptop.vpn", TYPE_AAAA);
+        let q = parse_query(&packet).unwrap();
+        assert_eq!(&answer(&packet, &q, Some(Ipv4Addr::new(10, 0, 0, 2)), false)[6..8], [0, 0]);
+        let response = answer(&packet, &q, Some(Ipv4Addr::new(10, 0, 0, 2)), true);
+        assert_eq!(response[response.len() - 16..], local_tun::tunnel_ipv6(Ipv4Addr::new(10, 0, 0, 2)).octets());
+
+        // 响应、多个问题、截断的报文不处理
+        let mut response = packet.clone();
+        response[2] |= 0x80;
+        assert!(parse_query(&response).is_none());
+        assert!(parse_query(&packet[..packet.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_upstream() {
+        assert_eq!(parse_upstream("9.9.9.9"), Some("9.9.9.9:53".parse().unwrap()));
+        assert_eq!(parse_upstream("[2620:fe::fe]:5353"), Some("[2620:fe::fe]:5353".parse().unwrap()));
+        assert_eq!(parse_upstream("dns.google"), None);
+        let resolv = "# generated\nsearch lan\nnameserver 127.0.0.53\nnameserver 1.1.1.1\n";
+        assert_eq!(first_nameserver(resolv), Some("127.0.0.53".parse().unwrap()));
+        assert_eq!(first_nameserver("search lan\n"), None);
+    }
+}
@@ -0,0 +1,145 @@
+// vpn_server/src/fastpath.bpf.c
+// eBPF 快速路径（XDP）：由 vpn_server 在启动时编译、加载并挂到外网接口上，见 fastpath.rs
+//
+// 发往监听端口（VPN_PORT，编译时定义）的 UDP 数据报：
+//   * 来源端点在 sessions 表中：累加该会话的包数和字节数，交给用户态
+//   * 数据报头为握手消息（[PACKET_MAGIC][版本][PACKET_HANDSHAKE]）：交给用户态处理握手
+//   * 其他：直接丢弃。用户态同样会按 unknown_session 或 bad_packet 丢弃它们，这里省去协议栈、socket 队列和查表的开销
+// "交给用户态"时，接收队列在 xsks 表中有 AF_XDP socket（--af-xdp）就重定向过去，绕过内核 UDP 协议栈；
+// 否则交给协议栈（XDP_PASS），到达普通的 UDP socket。
+// 其余流量、IP 分片和带扩展头的 IPv6 包原样放行。sessions / xsks 表由用户态维护。
+
+#include <linux/bpf.h>
+#include <linux/if_ether.h>
+#include <linux/in.h>
+#include <linux/ip.h>
+#include <linux/ipv6.h>
+#include <linux/udp.h>
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_endian.h>
+
+// 数据报头（与 vpn_core::wire 一致）
+#define PACKET_MAGIC 0xB7
+#define PACKET_HANDSHAKE 1
+
+#ifndef VPN_PORT
+#define VPN_PORT 51820
+#endif
+#ifndef MAX_SESSIONS
+#define MAX_SESSIONS 65536
+#endif
+#ifndef MAX_QUEUES
+#define MAX_QUEUES 64
+#endif
+
+/* 来源端点：IPv4 地址以 IPv4 映射的 IPv6 地址（::ffff:a.b.c.d）表示，端口为网络字节序 */
+struct endpoint {
+    __u8 addr[16];
+    __be16 port;
+    __u16 pad;
+};
+
+struct session_stats {
+    __u64 packets;
+    /* UDP 载荷字节数 */
+    __u64 bytes;
+};
+
+enum counter {
+    COUNTER_PASSED,
+    COUNTER_HANDSHAKE,
+    COUNTER_DROPPED,
+    COUNTER_MAX,
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, MAX_SESSIONS);
+    __type(key, struct endpoint);
+    __type(value, struct session_stats);
+} sessions SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, COUNTER_MAX);
+    __type(key, __u32);
+    __type(value, __u64);
+} counters SEC(".maps");
+
+/* 接收队列号 -> AF_XDP socket */
+struct {
+    __uint(type, BPF_MAP_TYPE_XSKMAP);
+    __uint(max_entries, MAX_QUEUES);
+    __type(key, __u32);
+    __type(value, __u32);
+} xsks SEC(".maps");
+
+static __always_inline void count(__u32 index)
+{
+    __u64 *value = bpf_map_lookup_elem(&counters, &index);
+    if (value)
+        __sync_fetch_and_add(value, 1);
+}
+
+/* 交给用户态：有 AF_XDP socket 时重定向，否则走协议栈 */
+static __always_inline int to_userspace(struct xdp_md *ctx)
+{
+    return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS);
+}
+
+SEC("xdp")
+int vpn_fastpath(struct xdp_md *ctx)
+{
+    void *data = (void *)(long)ctx->data;
+    void *data_end = (void *)(long)ctx->data_end;
+    struct ethhdr *eth = data;
+    struct endpoint key = {};
+    struct udphdr *udp;
+
+    if ((void *)(eth + 1) > data_end)
+        return XDP_PASS;
+
+    if (eth->h_proto == bpf_htons(ETH_P_IP)) {
+        struct iphdr *ip = (void *)(eth + 1);
+        if ((void *)(ip + 1) > data_end || ip->protocol != IPPROTO_UDP || ip->ihl < 5)
+            return XDP_PASS;
+        /* 分片交给协议栈重组 */
+        if (ip->frag_off & bpf_htons(0x3fff))
+            return XDP_PASS;
+        udp = (void *)ip + ip->ihl * 4;
+        key.addr[10] = 0xff;
+        key.addr[11] = 0xff;
+        __builtin_memcpy(&key.addr[12], &ip->saddr, 4);
+    } else if (eth->h_proto == bpf_htons(ETH_P_IPV6)) {
+        struct ipv6hdr *ip6 = (void *)(eth + 1);
+        if ((void *)(ip6 + 1) > data_end || ip6->nexthdr != IPPROTO_UDP)
+            return XDP_PASS;
+        udp = (void *)(ip6 + 1);
+        __builtin_memcpy(key.addr, &ip6->saddr, 16);
+    } else {
+        return XDP_PASS;
+    }
+
+    if ((void *)(udp + 1) > data_end || udp->dest != bpf_htons(VPN_PORT))
+        return XDP_PASS;
+    key.port = udp->source;
+
+    struct session_stats *stats = bpf_map_lookup_elem(&sessions, &key);
+    if (stats) {
+        __sync_fetch_and_add(&stats->packets, 1);
+        __sync_fetch_and_add(&stats->bytes, bpf_ntohs(udp->len) - sizeof(*udp));
+        count(COUNTER_PASSED);
+        return to_userspace(ctx);
+    }
+
+    __u8 *payload = (void *)(udp + 1);
+    if ((void *)(payload + 3) <= data_end && payload[0] == PACKET_MAGIC && payload[2] == PACKET_HANDSHAKE) {
+        count(COUNTER_HANDSHAKE);
+        return to_userspace(ctx);
+    }
+
+    count(COUNTER_DROPPED);
+    return XDP_DROP;
+}
+
+char _license[] SEC("license") = "GPL";
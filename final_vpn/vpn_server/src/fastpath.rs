@@ -0,0 +1,435 @@
+// vpn_server/src/fastpath.rs
+// eBPF 快速路径（--xdp <外网接口>，Linux，实验性）
+//
+// 启动时用 clang 编译 fastpath.bpf.c（监听端口作为编译参数），用 bpftool 加载、固定（pin）到
+// PIN_DIR 并挂到外网接口的 XDP 上。内核里只做分流、过滤和计数，解密/加密和转发仍在用户态：
+//
+// * 已建立会话的来源端点：计数后交给协议栈，照常到达监听 socket
+// * 握手消息（数据报头类型为握手，见 vpn_core::wire）：交给用户态
+// * 其他发往监听端口的数据报：在网卡驱动里直接丢弃，不再占用 socket 队列和接收循环
+//
+// 会话表的来源端点（包括 --bonding 的第二条链路）每 SYNC_INTERVAL 同步到 sessions 表，
+// 新会话在握手完成后一个同步周期内生效。统计通过 `vpn_server fastpath` 查看。
+// 加载失败时打印警告，服务端照常运行（所有包走协议栈）。
+//
+// --af-xdp 时在每个接收队列上再打开一个 AF_XDP socket（见 transport 模块），登记到 xsks 表，
+// 交给用户态的数据报直接重定向到这些 socket，不再经过内核 UDP 协议栈。
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use vpn_core::dryrun::{Category, Plan};
+
+use crate::ServerState;
+
+/// XDP 程序源码
+const PROGRAM: &str = include_str!("fastpath.bpf.c");
+/// 程序和表在 bpffs 上的固定位置
+pub const PIN_DIR: &str = "/sys/fs/bpf/rust-vpn";
+/// 会话表同步到内核的间隔
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+/// sessions 表的容量（与 fastpath.bpf.c 中的 MAX_SESSIONS 一致）
+pub const MAX_SESSIONS: usize = 65536;
+/// sessions 表的键长：16 字节地址 + 2 字节端口 + 2 字节填充
+const KEY_LEN: usize = 20;
+/// xsks 表的容量，即最多使用的接收队列数（与 fastpath.bpf.c 中的 MAX_QUEUES 一致）
+pub const MAX_QUEUES: u32 = 64;
+
+/// XDP 的挂载模式（--xdp-mode）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XdpMode {
+    /// 由内核选择：驱动支持时用原生模式，否则用通用模式
+    Auto,
+    /// 原生（驱动）模式，驱动不支持时加载失败
+    Native,
+    /// 通用模式，任何网卡都可以用，但性能提升有限
+    Generic,
+}
+
+impl XdpMode {
+    /// bpftool net attach 的挂载类型
+    fn attach_type(self) -> &'static str {
+        match self {
+            XdpMode::Auto => "xdp",
+            XdpMode::Native => "xdpdrv",
+            XdpMode::Generic => "xdpgeneric",
+        }
+    }
+}
+
+/// 快速路径配置
+#[derive(Debug, Clone)]
+pub struct FastPathConfig {
+    pub interface: String,
+    pub mode: XdpMode,
+    /// 用 AF_XDP socket 接收和发送（--af-xdp）
+    pub af_xdp: bool,
+}
+
+impl FastPathConfig {
+    /// 从命令行参数构建，未指定 --xdp 时返回 None
+    ///
+    /// * `--xdp <接口>`：在外网接口上挂载快速路径
+    /// * `--xdp-mode auto|native|generic`：挂载模式，默认 auto
+    /// * `--af-xdp`：同时用 AF_XDP socket 绕过内核 UDP 协议栈，失败时自动回退到普通 UDP socket
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let af_xdp = args.contains(&"--af-xdp".to_string());
+        let Some(interface) = crate::arg_value(args, "--xdp") else {
+            if af_xdp {
+                return Err(anyhow!("--af-xdp 需要用 --xdp <接口> 指定外网接口"));
+            }
+            return Ok(None);
+        };
+        let mode = match crate::arg_value(args, "--xdp-mode").as_deref() {
+            None | Some("auto") => XdpMode::Auto,
+            Some("native") => XdpMode::Native,
+            Some("generic") => XdpMode::Generic,
+            Some(other) => return Err(anyhow!("无效的 --xdp-mode: {}（可用: auto / native / generic）", other)),
+        };
+        Ok(Some(Self { interface, mode, af_xdp }))
+    }
+}
+
+/// 内核里的计数
+#[derive(Debug, Default, PartialEq)]
+pub struct FastPathStats {
+    /// 已建立会话的数据报
+    pub passed: u64,
+    /// 交给用户态的握手消息
+    pub handshakes: u64,
+    /// 在 XDP 中丢弃的数据报
+    pub dropped: u64,
+    /// 按来源端点的包数和字节数
+    pub sessions: Vec<(SocketAddr, u64, u64)>,
+}
+
+impl FastPathStats {
+    /// 文本报告：总计数和按字节数排序的前 top 个端点
+    pub fn report(mut self, top: usize) -> String {
+        let mut out = format!(
+            "已建立会话: {} 个包  握手: {}  XDP 丢弃: {}\n",
+            self.passed, self.handshakes, self.dropped
+        );
+        if self.sessions.is_empty() {
+            out.push_str("（sessions 表为空）\n");
+            return out;
+        }
+        self.sessions.sort_by_key(|s| std::cmp::Reverse(s.2));
+        out.push_str(&format!("{:<46} {:>12} {:>14}\n", "来源端点", "包数", "字节数"));
+        for (addr, packets, bytes) in self.sessions.iter().take(top) {
+            out.push_str(&format!("{:<46} {:>12} {:>14}\n", addr, packets, bytes));
+        }
+        if self.sessions.len() > top {
+            out.push_str(&format!("…… 另有 {} 个端点未列出\n", self.sessions.len() - top));
+        }
+        out
+    }
+}
+
+/// 已加载的快速路径
+pub struct FastPath {
+    pub config: FastPathConfig,
+    port: u16,
+    /// 已写入 sessions 表的端点
+    installed: Mutex<HashSet<SocketAddr>>,
+}
+
+impl FastPath {
+    pub fn new(config: FastPathConfig, port: u16) -> Self {
+        Self { config, port, installed: Mutex::new(HashSet::new()) }
+    }
+
+    /// 编译、加载并挂载 XDP 程序（替换接口上已有的 XDP 程序）
+    pub fn install(&self) -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rust-vpn-fastpath-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let result = self.compile(&dir).and_then(|object| self.load(&object));
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
+
+    fn compile(&self, dir: &std::path::Path) -> Result<PathBuf> {
+        let source = dir.join("fastpath.bpf.c");
+        let object = dir.join("fastpath.bpf.o");
+        std::fs::write(&source, PROGRAM)?;
+        run("clang", &compile_args(self.port, &source.to_string_lossy(), &object.to_string_lossy()))?;
+        Ok(object)
+    }
+
+    fn load(&self, object: &std::path::Path) -> Result<()> {
+        // 上次异常退出可能遗留固定的程序和表
+        let _ = std::fs::remove_dir_all(PIN_DIR);
+        std::fs::create_dir_all(PIN_DIR).map_err(|e| anyhow!("无法创建 {}（bpffs 是否已挂载？）: {}", PIN_DIR, e))?;
+        for args in load_commands(&object.to_string_lossy(), &self.config) {
+            run("bpftool", &args)?;
+        }
+        Ok(())
+    }
+
+    /// 试运行：列出 install 执行的命令
+    pub fn plan(&self, plan: &mut Plan) {
+        plan.command(Category::Other, "clang", &compile_args(self.port, "fastpath.bpf.c", "fastpath.bpf.o"));
+        for args in load_commands("fastpath.bpf.o", &self.config) {
+            plan.command(Category::Other, "bpftool", &args);
+        }
+        plan.note(Category::Other, format!("会话建立/结束时更新 {}/maps/sessions", PIN_DIR));
+        if self.config.af_xdp {
+            plan.note(Category::Other, format!("为 {} 的每个接收队列创建 AF_XDP socket 并登记到 {}/maps/xsks", self.config.interface, PIN_DIR));
+        }
+    }
+
+    /// 卸载 XDP 程序并删除固定的程序和表（忽略错误，接口可能已经不存在）
+    pub fn teardown(&self) {
+        let _ = Command::new("bpftool")
+            .args(["net", "detach", self.config.mode.attach_type(), "dev", &self.config.interface])
+            .output();
+        let _ = std::fs::remove_dir_all(PIN_DIR);
+    }
+
+    /// 按会话表的来源端点更新 sessions 表，只写入变化的部分
+    fn sync(&self, endpoints: &HashSet<SocketAddr>) {
+        let mut installed = self.installed.lock().unwrap();
+        let map = sessions_map();
+        for addr in installed.difference(endpoints).copied().collect::<Vec<_>>() {
+            let mut args = vec!["map".to_string(), "delete".to_string(), "pinned".to_string(), map.clone(), "key".to_string()];
+            args.extend(hex_args(&endpoint_key(addr)));
+            // 表项已不存在时 bpftool 也会报错，结果都是不在表中
+            let _ = run("bpftool", &args);
+            installed.remove(&addr);
+        }
+        let added: Vec<SocketAddr> = endpoints.difference(&installed).copied().collect();
+        for addr in added {
+            if installed.len() >= MAX_SESSIONS {
+                break;
+            }
+            let mut args = vec!["map".to_string(), "update".to_string(), "pinned".to_string(), map.clone(), "key".to_string()];
+            args.extend(hex_args(&endpoint_key(addr)));
+            args.push("value".to_string());
+            args.extend(hex_args(&[0; 16]));
+            match run("bpftool", &args) {
+                Ok(_) => {
+                    installed.insert(addr);
+                }
+                Err(e) => eprintln!("⚠️  无法把 {} 写入 XDP 会话表: {}", addr, e),
+            }
+        }
+    }
+
+    /// 启动同步任务
+    pub fn spawn_sync(self: Arc<Self>, state: Arc<ServerState>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SYNC_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let mut endpoints: HashSet<SocketAddr> = state.sessions.lock().await.keys().copied().collect();
+                if let Some(bonding) = &state.bonding {
+                    endpoints.extend(bonding.secondaries());
+                }
+                if *self.installed.lock().unwrap() == endpoints {
+                    continue;
+                }
+                // bpftool 是阻塞调用
+                let fastpath = self.clone();
+                let _ = tokio::task::spawn_blocking(move || fastpath.sync(&endpoints)).await;
+            }
+        });
+    }
+
+    /// 把一个接收队列上的 AF_XDP socket 登记到 xsks 表
+    pub fn register_xsk(&self, queue: u32, xsk: RawFd) -> Result<()> {
+        if queue >= MAX_QUEUES {
+            return Err(anyhow!("接收队列 {} 超过上限 {}", queue, MAX_QUEUES));
+        }
+        let map = bpf_obj_get(&format!("{}/maps/xsks", PIN_DIR))?;
+        bpf_map_update(map.as_raw_fd(), &queue.to_ne_bytes(), &(xsk as u32).to_ne_bytes())
+    }
+
+    /// 读取内核里的计数
+    pub fn stats(&self) -> Result<FastPathStats> {
+        let counters = dump(&format!("{}/maps/counters", PIN_DIR))?;
+        let sessions = dump(&sessions_map())?;
+        Ok(parse_stats(&counters, &sessions))
+    }
+}
+
+fn sessions_map() -> String {
+    format!("{}/maps/sessions", PIN_DIR)
+}
+
+fn compile_args(port: u16, source: &str, object: &str) -> Vec<String> {
+    format!("-O2 -g -target bpf -DVPN_PORT={} -DMAX_SESSIONS={} -DMAX_QUEUES={} -c {} -o {}", port, MAX_SESSIONS, MAX_QUEUES, source, object)
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+fn load_commands(object: &str, config: &FastPathConfig) -> Vec<Vec<String>> {
+    [
+        format!("prog load {} {}/prog type xdp pinmaps {}/maps", object, PIN_DIR, PIN_DIR),
+        format!("net attach {} pinned {}/prog dev {} overwrite", config.mode.attach_type(), PIN_DIR, config.interface),
+    ]
+    .iter()
+    .map(|cmd| cmd.split_whitespace().map(String::from).collect())
+    .collect()
+}
+
+/// sessions 表的键：IPv4 地址转为 IPv4 映射的 IPv6 地址，端口为网络字节序
+fn endpoint_key(addr: SocketAddr) -> [u8; KEY_LEN] {
+    let ip = match addr.ip() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    };
+    let mut key = [0; KEY_LEN];
+    key[..16].copy_from_slice(&ip.octets());
+    key[16..18].copy_from_slice(&addr.port().to_be_bytes());
+    key
+}
+
+fn parse_endpoint_key(key: &[u8]) -> Option<SocketAddr> {
+    let addr: [u8; 16] = key.get(..16)?.try_into().ok()?;
+    let port = u16::from_be_bytes(key.get(16..18)?.try_into().ok()?);
+    let ip = Ipv6Addr::from(addr);
+    let ip = ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip));
+    Some(SocketAddr::new(ip, port))
+}
+
+fn hex_args(bytes: &[u8]) -> Vec<String> {
+    std::iter::once("hex".to_string()).chain(bytes.iter().map(|b| format!("{:02x}", b))).collect()
+}
+
+/// `bpftool -j map dump` 的输出：每个表项的 key 和 value 为 "0x.." 字符串数组
+fn dump(path: &str) -> Result<serde_json::Value> {
+    let output = Command::new("bpftool").args(["-j", "map", "dump", "pinned", path]).output()?;
+    if !output.status.success() {
+        anyhow::bail!("bpftool map dump {} 失败: {}", path, String::from_utf8_lossy(&output.stderr).trim())
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+fn entries(dump: &serde_json::Value) -> impl Iterator<Item = (Vec<u8>, Vec<u8>)> + '_ {
+    let bytes = |value: &serde_json::Value| -> Option<Vec<u8>> {
+        value.as_array()?.iter().map(|b| u8::from_str_radix(b.as_str()?.trim_start_matches("0x"), 16).ok()).collect()
+    };
+    dump.as_array().into_iter().flatten().filter_map(move |entry| Some((bytes(&entry["key"])?, bytes(&entry["value"])?)))
+}
+
+/// 内核按本机字节序存放计数
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    bytes.get(offset..offset + 8).and_then(|b| b.try_into().ok()).map(u64::from_ne_bytes).unwrap_or(0)
+}
+
+fn parse_stats(counters: &serde_json::Value, sessions: &serde_json::Value) -> FastPathStats {
+    let mut stats = FastPathStats::default();
+    for (key, value) in entries(counters) {
+        let Ok(index) = <[u8; 4]>::try_from(key.as_slice()) else { continue };
+        match u32::from_ne_bytes(index) {
+            0 => stats.passed = u64_at(&value, 0),
+            1 => stats.handshakes = u64_at(&value, 0),
+            2 => stats.dropped = u64_at(&value, 0),
+            _ => {}
+        }
+    }
+    stats.sessions = entries(sessions)
+        .filter_map(|(key, value)| Some((parse_endpoint_key(&key)?, u64_at(&value, 0), u64_at(&value, 8))))
+        .collect();
+    stats
+}
+
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+/// bpf(2) 中 BPF_OBJ_GET 使用的 bpf_attr 字段
+#[repr(C)]
+struct ObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+/// bpf(2) 中 BPF_MAP_UPDATE_ELEM 使用的 bpf_attr 字段
+#[repr(C)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+/// 打开固定在 bpffs 上的表
+///
+/// socket 类的表项（AF_XDP socket 的 fd）只能在持有 fd 的进程里写入，bpftool 做不到，所以这里直接调用 bpf(2)
+fn bpf_obj_get(path: &str) -> Result<OwnedFd> {
+    let path = std::ffi::CString::new(path)?;
+    let attr = ObjGetAttr { pathname: path.as_ptr() as u64, bpf_fd: 0, file_flags: 0 };
+    // SAFETY: attr 和 path 在调用期间有效
+    let fd = unsafe { libc::syscall(libc::SYS_bpf, BPF_OBJ_GET, &attr as *const ObjGetAttr, std::mem::size_of::<ObjGetAttr>()) };
+    if fd < 0 {
+        return Err(anyhow!("无法打开 {}: {}", path.to_string_lossy(), std::io::Error::last_os_error()));
+    }
+    // SAFETY: fd 是 bpf(2) 新返回的
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn bpf_map_update(map: RawFd, key: &[u8], value: &[u8]) -> Result<()> {
+    let attr = MapUpdateAttr { map_fd: map as u32, _pad: 0, key: key.as_ptr() as u64, value: value.as_ptr() as u64, flags: 0 };
+    // SAFETY: attr、key 和 value 在调用期间有效
+    let ret = unsafe { libc::syscall(libc::SYS_bpf, BPF_MAP_UPDATE_ELEM, &attr as *const MapUpdateAttr, std::mem::size_of::<MapUpdateAttr>()) };
+    if ret < 0 {
+        return Err(anyhow!("更新表项失败: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+fn run(program: &str, args: &[String]) -> Result<()> {
+    let output = Command::new(program).args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!("{} {} 失败: {}", program, args.join(" "), String::from_utf8_lossy(&output.stderr).trim())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_array(bytes: &[u8]) -> serde_json::Value {
+        bytes.iter().map(|b| format!("0x{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn test_endpoint_key() {
+        let v4: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        let key = endpoint_key(v4);
+        assert_eq!(&key[10..16], &[0xff, 0xff, 203, 0, 113, 5]);
+        assert_eq!(&key[16..], &[0x9c, 0x40, 0, 0]);
+        assert_eq!(parse_endpoint_key(&key), Some(v4));
+        // 双栈 socket 上的 IPv4 映射地址与 IPv4 地址是同一个端点
+        assert_eq!(endpoint_key("[::ffff:203.0.113.5]:40000".parse().unwrap()), key);
+        let v6: SocketAddr = "[2001:db8::1]:51820".parse().unwrap();
+        assert_eq!(parse_endpoint_key(&endpoint_key(v6)), Some(v6));
+        assert_eq!(hex_args(&[0x0a, 0xff]), ["hex", "0a", "ff"]);
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let counter = |index: u32, value: u64| serde_json::json!({ "key": hex_array(&index.to_ne_bytes()), "value": hex_array(&value.to_ne_bytes()) });
+        let counters = serde_json::json!([counter(0, 10), counter(1, 2), counter(2, 300)]);
+        let mut value = 7u64.to_ne_bytes().to_vec();
+        value.extend(1400u64.to_ne_bytes());
+        let addr: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        let sessions = serde_json::json!([{ "key": hex_array(&endpoint_key(addr)), "value": hex_array(&value), "formatted": {} }]);
+
+        let stats = parse_stats(&counters, &sessions);
+        assert_eq!((stats.passed, stats.handshakes, stats.dropped), (10, 2, 300));
+        assert_eq!(stats.sessions, [(addr, 7, 1400)]);
+        let report = stats.report(20);
+        assert!(report.lines().nth(2).unwrap().starts_with("198.51.100.7:5000"));
+    }
+}
@@ -0,0 +1,180 @@
+// vpn_server/src/filter.rs
+// 内层流量的协议/端口白名单
+//
+// 有的部署只允许隧道承载少数几种流量（例如只放行 DNS 和 HTTPS）。配置白名单后，
+// 客户端发进隧道的包在转发到 TUN 或其他客户端之前按目的协议/端口匹配，不在白名单内的包被丢弃。
+//
+// 过滤是无状态的：
+// * 发往互联网的包只按目的端口匹配，回程流量从 TUN 进来，不经过过滤
+// * 发往隧道网段（其他客户端或服务端本机）的包也可以按源端口匹配，这样被访问的一方能回复
+//   白名单内的服务（例如对端访问本机的 443 端口后，本机从 443 端口发出的回包）
+// * 非首个分片没有端口信息，只要协议在白名单内就放行（首个分片已经过端口检查）
+//
+// 规则的格式和匹配见 vpn_core::firewall。
+//
+// 客户端之间能否互访由 --peer-acl 另行控制（PeerAcl）：两个客户端互相在对方的 ACL 内才转发，
+// 客户端查询在线对端（vpn_client peers）时也只列出这些对端。
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use anyhow::{Result, anyhow};
+use vpn_core::firewall::Allowlist;
+
+/// 全局和按客户端的白名单
+#[derive(Debug, Clone)]
+pub struct FilterConfig {
+    /// 未单独配置的客户端使用的白名单
+    pub default: Allowlist,
+    /// 按虚拟 IP 单独配置的白名单（替代全局配置）
+    pub overrides: HashMap<Ipv4Addr, Allowlist>,
+}
+
+impl FilterConfig {
+    /// 从命令行参数构建，两个参数都未指定时返回 None
+    ///
+    /// * `--allow <规则>[,<规则>...]`：全局白名单，可重复（合并），例如 `--allow udp:53,tcp:443,icmp`
+    /// * `--client-allow <虚拟IP>=<规则>[,<规则>...]`：单个客户端的白名单，可重复；`all` 表示不限制
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let global = crate::arg_values(args, "--allow");
+        let clients = crate::arg_values(args, "--client-allow");
+        if global.is_empty() && clients.is_empty() {
+            return Ok(None);
+        }
+
+        let default = if global.is_empty() { Allowlist::unrestricted() } else { Allowlist::parse(&global.join(","))? };
+        let overrides = clients
+            .iter()
+            .map(|spec| {
+                let (ip, rules) = spec
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("无效的 --client-allow: {}（格式 10.0.0.2=tcp:22,icmp）", spec))?;
+                let ip: Ipv4Addr = ip.parse().map_err(|_| anyhow!("无效的虚拟 IP: {}", ip))?;
+                Ok((ip, Allowlist::parse(rules)?))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Some(Self { default, overrides }))
+    }
+
+    /// 某个客户端的白名单（虚拟 IP 未知时使用全局配置）
+    pub fn allowlist_for(&self, vip: Option<Ipv4Addr>) -> &Allowlist {
+        vip.and_then(|ip| self.overrides.get(&ip)).unwrap_or(&self.default)
+    }
+}
+
+/// 客户端互访的 ACL：按虚拟 IP 配置允许互访的网段，未配置的客户端不受限制
+#[derive(Debug, Clone)]
+pub struct PeerAcl {
+    rules: HashMap<Ipv4Addr, Vec<(Ipv4Addr, u8)>>,
+}
+
+impl PeerAcl {
+    /// `--peer-acl <虚拟IP>=<网段>[,<网段>...]`，可重复；网段可以是单个地址。未指定时返回 None
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let specs = crate::arg_values(args, "--peer-acl");
+        if specs.is_empty() {
+            return Ok(None);
+        }
+        let mut rules: HashMap<Ipv4Addr, Vec<(Ipv4Addr, u8)>> = HashMap::new();
+        for spec in &specs {
+            let (ip, nets) = spec
+                .split_once('=')
+                .ok_or_else(|| anyhow!("无效的 --peer-acl: {}（格式 10.0.0.2=10.0.0.3,10.0.0.16/28）", spec))?;
+            let ip: Ipv4Addr = ip.parse().map_err(|_| anyhow!("无效的虚拟 IP: {}", ip))?;
+            for net in nets.split(',') {
+                rules.entry(ip).or_default().push(parse_net(net).ok_or_else(|| anyhow!("无效的网段: {}", net))?);
+            }
+        }
+        Ok(Some(Self { rules }))
+    }
+
+    /// 两个客户端能否互访：双方都允许对方时才放行
+    pub fn allows(&self, a: Ipv4Addr, b: Ipv4Addr) -> bool {
+        self.permits(a, b) && self.permits(b, a)
+    }
+
+    fn permits(&self, from: Ipv4Addr, to: Ipv4Addr) -> bool {
+        self.rules.get(&from).is_none_or(|nets| {
+            nets.iter().any(|(net, len)| {
+                let mask = u32::MAX.checked_shl(32 - *len as u32).unwrap_or(0);
+                u32::from(to) & mask == u32::from(*net) & mask
+            })
+        })
+    }
+
+    /// 单独配置了 ACL 的客户端数
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+/// IPv4 网段或单个地址
+fn parse_net(net: &str) -> Option<(Ipv4Addr, u8)> {
+    let net = net.trim();
+    match vpn_core::config::parse_cidr(net) {
+        Some((std::net::IpAddr::V4(ip), len)) => Some((ip, len)),
+        Some(_) => None,
+        None => net.parse().ok().map(|ip| (ip, 32)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(protocol: u8, dst_port: u16) -> Vec<u8> {
+        let mut p = vec![0x45, 0, 0, 28, 0, 0, 0x40, 0, 64, protocol, 0, 0, 10, 0, 0, 2, 1, 1, 1, 1];
+        p.extend(40000u16.to_be_bytes());
+        p.extend(dst_port.to_be_bytes());
+        p.extend([0, 8, 0, 0]);
+        p
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_filter_config() {
+        assert!(FilterConfig::from_args(&[]).unwrap().is_none());
+
+        let config = FilterConfig::from_args(&args(&[
+            "--allow", "udp:53", "--allow", "tcp:443", "--client-allow", "10.0.0.5=tcp:22", "--client-allow", "10.0.0.6=all",
+        ]))
+        .unwrap()
+        .unwrap();
+        let dns = packet(17, 53);
+        let ssh = packet(6, 22);
+        let gre = packet(47, 0);
+        assert!(config.allowlist_for(None).allows(&dns, false));
+        assert!(config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 2))).allows(&packet(6, 443), false));
+        assert!(!config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 2))).allows(&ssh, false));
+        // 单独配置替代全局配置
+        assert!(config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 5))).allows(&ssh, false));
+        assert!(!config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 5))).allows(&dns, false));
+        assert!(config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 6))).allows(&gre, false));
+
+        // 只配置单个客户端时其他客户端不受限制
+        let config = FilterConfig::from_args(&args(&["--client-allow", "10.0.0.5=tcp:22"])).unwrap().unwrap();
+        assert!(config.allowlist_for(Some(Ipv4Addr::new(10, 0, 0, 2))).allows(&gre, false));
+        assert!(FilterConfig::from_args(&args(&["--client-allow", "10.0.0.5"])).is_err());
+    }
+
+    #[test]
+    fn test_peer_acl() {
+        assert!(PeerAcl::from_args(&[]).unwrap().is_none());
+        let acl = PeerAcl::from_args(&args(&["--peer-acl", "10.0.0.2=10.0.0.3,10.0.0.16/28", "--peer-acl", "10.0.0.3=0.0.0.0/0"]))
+            .unwrap()
+            .unwrap();
+        let ip = |last| Ipv4Addr::new(10, 0, 0, last);
+        assert!(acl.allows(ip(2), ip(3)));
+        assert!(acl.allows(ip(3), ip(2)));
+        assert!(acl.allows(ip(2), ip(20)));
+        assert!(!acl.allows(ip(2), ip(4)));
+        assert!(!acl.allows(ip(4), ip(2)));
+        // 未配置的客户端之间不受限制
+        assert!(acl.allows(ip(4), ip(5)));
+        assert!(PeerAcl::from_args(&args(&["--peer-acl", "10.0.0.2"])).is_err());
+        assert!(PeerAcl::from_args(&args(&["--peer-acl", "10.0.0.2=fd00::/64"])).is_err());
+    }
+}
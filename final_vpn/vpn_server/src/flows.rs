@@ -0,0 +1,275 @@
+// vpn_server/src/flows.rs
+// 轻量级流表：按内层包的五元组统计字节数、包数和最后活跃时间
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// 流表最多保留的条目数，超过后新流不再记录（只计数）
+pub const MAX_FLOWS: usize = 65536;
+/// 空闲多久的流会被清理
+pub const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 内层包的五元组（非 TCP/UDP 协议端口为 0）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub protocol: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+impl FlowKey {
+    /// 从 IPv4/IPv6 包中解析五元组
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let (src, dst, protocol, l4) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let ihl = ((packet[0] & 0x0f) as usize) * 4;
+                let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+                (IpAddr::V4(src), IpAddr::V4(dst), packet[9], packet.get(ihl..)?)
+            }
+            6 if packet.len() >= 40 => {
+                let src: [u8; 16] = packet[8..24].try_into().ok()?;
+                let dst: [u8; 16] = packet[24..40].try_into().ok()?;
+                (IpAddr::V6(Ipv6Addr::from(src)), IpAddr::V6(Ipv6Addr::from(dst)), packet[6], &packet[40..])
+            }
+            _ => return None,
+        };
+
+        // TCP(6) / UDP(17) 取端口
+        let (src_port, dst_port) = match protocol {
+            6 | 17 if l4.len() >= 4 => (
+                u16::from_be_bytes([l4[0], l4[1]]),
+                u16::from_be_bytes([l4[2], l4[3]]),
+            ),
+            _ => (0, 0),
+        };
+
+        Some(Self { src, dst, protocol, src_port, dst_port })
+    }
+}
+
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = match self.protocol {
+            1 => "icmp".to_string(),
+            6 => "tcp".to_string(),
+            17 => "udp".to_string(),
+            58 => "icmpv6".to_string(),
+            p => p.to_string(),
+        };
+        if self.src_port == 0 && self.dst_port == 0 {
+            write!(f, "{} {} -> {}", proto, self.src, self.dst)
+        } else {
+            write!(f, "{} {}:{} -> {}:{}", proto, self.src, self.src_port, self.dst, self.dst_port)
+        }
+    }
+}
+
+/// 单条流的统计
+#[derive(Debug, Clone, Copy)]
+pub struct FlowStats {
+    pub bytes: u64,
+    pub packets: u64,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    /// 已经导出（IPFIX）的部分，导出时只发送增量
+    pub exported_bytes: u64,
+    pub exported_packets: u64,
+}
+
+impl FlowStats {
+    /// 自上次导出以来的 (字节数, 包数)
+    pub fn delta(&self) -> (u64, u64) {
+        (self.bytes - self.exported_bytes, self.packets - self.exported_packets)
+    }
+}
+
+/// 流表
+#[derive(Debug)]
+pub struct FlowTable {
+    flows: HashMap<FlowKey, FlowStats>,
+    /// 流表满时未能记录的包数
+    pub overflow_packets: u64,
+    /// 包采样：每 sample_rate 个包记录一个，计数按比例放大（1 表示不采样）
+    sample_rate: u32,
+    sample_counter: u32,
+}
+
+impl Default for FlowTable {
+    fn default() -> Self {
+        Self::with_sampling(1)
+    }
+}
+
+impl FlowTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 创建 1/N 包采样的流表
+    pub fn with_sampling(sample_rate: u32) -> Self {
+        Self {
+            flows: HashMap::new(),
+            overflow_packets: 0,
+            sample_rate: sample_rate.max(1),
+            sample_counter: 0,
+        }
+    }
+
+    /// 记录一个内层包
+    pub fn record(&mut self, packet: &[u8]) {
+        if self.sample_rate > 1 {
+            self.sample_counter = (self.sample_counter + 1) % self.sample_rate;
+            if self.sample_counter != 0 {
+                return;
+            }
+        }
+
+        let Some(key) = FlowKey::parse(packet) else { return };
+        let now = Instant::now();
+        let bytes = packet.len() as u64 * self.sample_rate as u64;
+        let packets = self.sample_rate as u64;
+
+        if let Some(stats) = self.flows.get_mut(&key) {
+            stats.bytes += bytes;
+            stats.packets += packets;
+            stats.last_seen = now;
+        } else if self.flows.len() < MAX_FLOWS {
+            self.flows.insert(key, FlowStats {
+                bytes,
+                packets,
+                first_seen: now,
+                last_seen: now,
+                exported_bytes: 0,
+                exported_packets: 0,
+            });
+        } else {
+            self.overflow_packets += 1;
+        }
+    }
+
+    /// 取出所有有增量的流并标记为已导出
+    pub fn take_deltas(&mut self) -> Vec<(FlowKey, FlowStats)> {
+        self.flows.iter_mut()
+            .filter(|(_, s)| s.delta().1 > 0)
+            .map(|(k, s)| {
+                let snapshot = *s;
+                s.exported_bytes = s.bytes;
+                s.exported_packets = s.packets;
+                (*k, snapshot)
+            })
+            .collect()
+    }
+
+    /// 清理空闲超时的流，返回被清理的条目
+    pub fn expire(&mut self, idle: Duration) -> Vec<(FlowKey, FlowStats)> {
+        let now = Instant::now();
+        let expired: Vec<FlowKey> = self.flows.iter()
+            .filter(|(_, s)| now.duration_since(s.last_seen) >= idle)
+            .map(|(k, _)| *k)
+            .collect();
+        expired.into_iter()
+            .filter_map(|k| self.flows.remove(&k).map(|s| (k, s)))
+            .collect()
+    }
+
+    /// 按字节数降序取前 n 条
+    pub fn top(&self, n: usize) -> Vec<(FlowKey, FlowStats)> {
+        let mut flows: Vec<(FlowKey, FlowStats)> = self.flows.iter().map(|(k, s)| (*k, *s)).collect();
+        flows.sort_by_key(|(_, s)| std::cmp::Reverse(s.bytes));
+        flows.truncate(n);
+        flows
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// 生成 `flows --top N` 的文本报告
+    pub fn report(&self, n: usize) -> String {
+        let now = Instant::now();
+        let mut out = format!("活跃流: {}（按字节数前 {} 条）\n", self.len(), n.min(self.len()));
+        if self.sample_rate > 1 {
+            out.push_str(&format!("采样率 1/{}，计数为估算值\n", self.sample_rate));
+        }
+        out.push_str(&format!("{:>12} {:>8} {:>7} {:>6}  {}\n", "BYTES", "PACKETS", "AGE(s)", "IDLE", "FLOW"));
+        for (key, stats) in self.top(n) {
+            out.push_str(&format!(
+                "{:>12} {:>8} {:>7} {:>6}  {}\n",
+                stats.bytes,
+                stats.packets,
+                now.duration_since(stats.first_seen).as_secs(),
+                now.duration_since(stats.last_seen).as_secs(),
+                key
+            ));
+        }
+        if self.overflow_packets > 0 {
+            out.push_str(&format!("⚠️  流表已满，{} 个包未计入\n", self.overflow_packets));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_packet(src_port: u16, payload: usize) -> Vec<u8> {
+        let mut p = vec![0u8; 28 + payload];
+        p[0] = 0x45;
+        p[9] = 17;
+        p[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        p[16..20].copy_from_slice(&[8, 8, 8, 8]);
+        p[20..22].copy_from_slice(&src_port.to_be_bytes());
+        p[22..24].copy_from_slice(&53u16.to_be_bytes());
+        p
+    }
+
+    #[test]
+    fn test_flow_key_parse() {
+        let key = FlowKey::parse(&udp_packet(40000, 10)).unwrap();
+        assert_eq!(key.src, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(key.dst_port, 53);
+        assert_eq!(key.to_string(), "udp 10.0.0.2:40000 -> 8.8.8.8:53");
+
+        assert!(FlowKey::parse(&[0x45, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn test_top_and_expire() {
+        let mut table = FlowTable::new();
+        table.record(&udp_packet(1000, 10));
+        table.record(&udp_packet(2000, 500));
+        table.record(&udp_packet(1000, 10));
+
+        let top = table.top(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0.src_port, 2000);
+        assert_eq!(table.top(10)[1].1.packets, 2);
+
+        // 增量导出后不再重复导出
+        assert_eq!(table.take_deltas().len(), 2);
+        assert!(table.take_deltas().is_empty());
+        table.record(&udp_packet(1000, 10));
+        assert_eq!(table.take_deltas()[0].1.delta(), (38, 1));
+
+        assert!(table.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(table.expire(Duration::ZERO).len(), 2);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn test_sampling() {
+        let mut table = FlowTable::with_sampling(4);
+        for _ in 0..8 {
+            table.record(&udp_packet(1000, 10));
+        }
+        let top = table.top(1);
+        assert_eq!(top[0].1.packets, 8);
+        assert_eq!(top[0].1.bytes, 8 * 38);
+    }
+}
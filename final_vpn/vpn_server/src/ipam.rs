@@ -0,0 +1,191 @@
+// vpn_server/src/ipam.rs
+// 虚拟 IP 自动分配（--ipam）
+//
+// 客户端把虚拟 IP 写成 `auto`（vpn_core::handshake::AUTO_VIRTUAL_IP）发起握手时，
+// 服务端从 VPN 网段中选一个地址，随 ServerHello 下发（纳入签名）。
+// 自己指定地址的客户端不经过这里，仍按原来的规则检查（--client-ip-map、地址冲突）。
+// 客户端重新握手时请求上次分到的具体地址，所以一次运行期间地址不变。
+//
+// --client-ip-map 中的绑定总是优先：有绑定的身份分到绑定的地址，其他身份不会分到这些地址。
+// 没有绑定的身份按 --ipam 选择的策略分配：
+//   sequential  最小的空闲地址（默认），地址紧凑、好记
+//   random      随机的空闲地址，每次连接通常不同，不能凭地址长期追踪某个客户端
+//   hashed      从身份公钥的哈希位置开始找空闲地址，没有冲突时同一身份总是分到同一个地址
+//   static      不分配，只有在 --client-ip-map 中有绑定的身份可以使用 auto
+
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use rand::Rng;
+
+use crate::auth::IdentityIpMap;
+
+/// 可分配的地址：VPN 网段中去掉网络地址、广播地址和服务端自己的地址
+#[derive(Debug, Clone, Copy)]
+pub struct AddressPool {
+    first: u32,
+    last: u32,
+    server: Ipv4Addr,
+}
+
+impl AddressPool {
+    /// server 为服务端在 VPN 网段中的地址，prefix 为网段前缀长度（1 ~ 30）
+    pub fn new(server: Ipv4Addr, prefix: u8) -> Self {
+        let mask = u32::MAX << (32 - prefix);
+        let network = u32::from(server) & mask;
+        Self { first: network + 1, last: (network | !mask) - 1, server }
+    }
+
+    fn size(&self) -> u32 {
+        self.last - self.first + 1
+    }
+
+    /// 从第 start 个地址开始依次列出全部可分配的地址（到末尾后回到开头）
+    fn starting_at(&self, start: u32) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        let size = self.size();
+        (0..size)
+            .map(move |i| Ipv4Addr::from(self.first + (start % size + i) % size))
+            .filter(|ip| *ip != self.server)
+    }
+}
+
+/// 分配策略
+pub trait IpAllocator: Send + Sync {
+    /// 为身份选一个不在 taken 中的地址，没有可用地址时返回 None
+    fn allocate(&self, client_id: &str, identity_key: &[u8; 32], taken: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr>;
+}
+
+/// 最小的空闲地址
+pub struct Sequential(pub AddressPool);
+
+impl IpAllocator for Sequential {
+    fn allocate(&self, _client_id: &str, _identity_key: &[u8; 32], taken: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        self.0.starting_at(0).find(|ip| !taken.contains(ip))
+    }
+}
+
+/// 随机的空闲地址
+pub struct Random(pub AddressPool);
+
+impl IpAllocator for Random {
+    fn allocate(&self, _client_id: &str, _identity_key: &[u8; 32], taken: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        let start = rand::thread_rng().gen_range(0..self.0.size());
+        self.0.starting_at(start).find(|ip| !taken.contains(ip))
+    }
+}
+
+/// 从身份公钥的哈希位置开始的第一个空闲地址
+pub struct IdentityHashed(pub AddressPool);
+
+impl IpAllocator for IdentityHashed {
+    fn allocate(&self, _client_id: &str, identity_key: &[u8; 32], taken: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        let hash = blake3::hash(identity_key);
+        let start = u32::from_le_bytes(hash.as_bytes()[..4].try_into().unwrap());
+        self.0.starting_at(start).find(|ip| !taken.contains(ip))
+    }
+}
+
+/// 按 client_id 绑定的地址（--client-ip-map）
+pub struct StaticMap(pub IdentityIpMap);
+
+impl IpAllocator for StaticMap {
+    fn allocate(&self, client_id: &str, _identity_key: &[u8; 32], taken: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        self.0.get(client_id).filter(|ip| !taken.contains(ip))
+    }
+}
+
+/// 服务端的地址分配：--client-ip-map 的绑定优先，其余身份交给 --ipam 选择的策略
+pub struct Ipam {
+    bindings: StaticMap,
+    /// --ipam static 时为 None
+    strategy: Option<Box<dyn IpAllocator>>,
+    name: &'static str,
+}
+
+impl Ipam {
+    /// `--ipam sequential|random|hashed|static`，默认 sequential
+    pub fn from_args(args: &[String], pool: AddressPool) -> Result<Self> {
+        let bindings = match crate::arg_value(args, "--client-ip-map") {
+            Some(file) => IdentityIpMap::load(Path::new(&file))?,
+            None => IdentityIpMap::default(),
+        };
+        let (name, strategy): (_, Option<Box<dyn IpAllocator>>) = match crate::arg_value(args, "--ipam").as_deref() {
+            None | Some("sequential") => ("sequential", Some(Box::new(Sequential(pool)))),
+            Some("random") => ("random", Some(Box::new(Random(pool)))),
+            Some("hashed") => ("hashed", Some(Box::new(IdentityHashed(pool)))),
+            Some("static") => ("static", None),
+            Some(other) => return Err(anyhow!("无效的 --ipam: {}（可选 sequential、random、hashed、static）", other)),
+        };
+        Ok(Self { bindings: StaticMap(bindings), strategy, name })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// in_use 为其他身份的会话正在使用的地址
+    pub fn allocate(&self, client_id: &str, identity_key: &[u8; 32], in_use: &HashSet<Ipv4Addr>) -> Option<Ipv4Addr> {
+        if self.bindings.0.get(client_id).is_some() {
+            return self.bindings.allocate(client_id, identity_key, in_use);
+        }
+        let mut taken = in_use.clone();
+        taken.extend(self.bindings.0.addresses());
+        self.strategy.as_ref()?.allocate(client_id, identity_key, &taken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    #[test]
+    fn test_strategies() {
+        let pool = AddressPool::new(SERVER, 24);
+        let taken: HashSet<Ipv4Addr> = [Ipv4Addr::new(10, 0, 0, 2)].into();
+
+        assert_eq!(Sequential(pool).allocate("a", &[1u8; 32], &taken), Some(Ipv4Addr::new(10, 0, 0, 3)));
+
+        // 同一公钥总是同一个地址，不同公钥通常不同
+        let hashed = IdentityHashed(pool);
+        let first = hashed.allocate("a", &[1u8; 32], &HashSet::new()).unwrap();
+        assert_eq!(hashed.allocate("a", &[1u8; 32], &HashSet::new()), Some(first));
+        assert_ne!(hashed.allocate("b", &[2u8; 32], &HashSet::new()), Some(first));
+        // 被占用时顺延
+        assert_ne!(hashed.allocate("a", &[1u8; 32], &[first].into()), Some(first));
+
+        for _ in 0..100 {
+            let ip = Random(pool).allocate("a", &[1u8; 32], &taken).unwrap();
+            assert!(ip != SERVER && !taken.contains(&ip) && ip.octets()[..3] == [10, 0, 0] && ip.octets()[3] != 255 && ip.octets()[3] != 0);
+        }
+
+        // 地址用完
+        let small = AddressPool::new(SERVER, 30);
+        assert_eq!(Sequential(small).allocate("a", &[1u8; 32], &HashSet::new()), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(Random(small).allocate("a", &[1u8; 32], &[Ipv4Addr::new(10, 0, 0, 2)].into()), None);
+    }
+
+    #[test]
+    fn test_bindings_take_precedence() {
+        let map = std::env::temp_dir().join(format!("rust-vpn-ipam-{}", std::process::id()));
+        std::fs::write(&map, "alice 10.0.0.2\n").unwrap();
+        let args = |ipam: &str| vec!["--client-ip-map".to_string(), map.display().to_string(), "--ipam".to_string(), ipam.to_string()];
+        let pool = AddressPool::new(SERVER, 24);
+
+        let ipam = Ipam::from_args(&args("sequential"), pool).unwrap();
+        assert_eq!(ipam.allocate("alice", &[1u8; 32], &HashSet::new()), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        // 绑定给别人的地址不会分出去；绑定的地址被占用时不改分其他地址
+        assert_eq!(ipam.allocate("bob", &[2u8; 32], &HashSet::new()), Some(Ipv4Addr::new(10, 0, 0, 3)));
+        assert_eq!(ipam.allocate("alice", &[1u8; 32], &[Ipv4Addr::new(10, 0, 0, 2)].into()), None);
+
+        let ipam = Ipam::from_args(&args("static"), pool).unwrap();
+        assert_eq!(ipam.allocate("alice", &[1u8; 32], &HashSet::new()), Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(ipam.allocate("bob", &[2u8; 32], &HashSet::new()), None);
+
+        assert!(Ipam::from_args(&args("dhcp"), pool).is_err());
+        std::fs::remove_file(&map).unwrap();
+    }
+}
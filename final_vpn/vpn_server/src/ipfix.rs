@@ -0,0 +1,329 @@
+// vpn_server/src/ipfix.rs
+// IPFIX（RFC 7011）流记录导出：周期性把流表的增量发送给采集器
+//
+// 每个消息都带上模板集合（UDP 传输下采集器可能随时重启），
+// IPv4 / IPv6 流分别使用模板 256 / 257。
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+
+use crate::flows::{FlowKey, FlowStats};
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_ID_V4: u16 = 256;
+const TEMPLATE_ID_V6: u16 = 257;
+/// 单个 IPFIX 消息的大小上限，避免在路径上分片
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+/// 默认导出周期
+pub const DEFAULT_EXPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+// IANA 信息元素 (ID, 长度)
+const IE_OCTET_DELTA_COUNT: (u16, u16) = (1, 8);
+const IE_PACKET_DELTA_COUNT: (u16, u16) = (2, 8);
+const IE_PROTOCOL_IDENTIFIER: (u16, u16) = (4, 1);
+const IE_SOURCE_TRANSPORT_PORT: (u16, u16) = (7, 2);
+const IE_SOURCE_IPV4_ADDRESS: (u16, u16) = (8, 4);
+const IE_DESTINATION_TRANSPORT_PORT: (u16, u16) = (11, 2);
+const IE_DESTINATION_IPV4_ADDRESS: (u16, u16) = (12, 4);
+const IE_SOURCE_IPV6_ADDRESS: (u16, u16) = (27, 16);
+const IE_DESTINATION_IPV6_ADDRESS: (u16, u16) = (28, 16);
+const IE_FLOW_START_MILLISECONDS: (u16, u16) = (152, 8);
+const IE_FLOW_END_MILLISECONDS: (u16, u16) = (153, 8);
+
+/// 导出前的聚合方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    /// 不聚合，按五元组导出
+    FiveTuple,
+    /// 按 (源 IP, 目的 IP, 协议) 聚合，端口置 0
+    HostPair,
+    /// 按 (源 IP, 协议) 聚合
+    Source,
+}
+
+impl Aggregation {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "five-tuple" => Ok(Aggregation::FiveTuple),
+            "host-pair" => Ok(Aggregation::HostPair),
+            "source" => Ok(Aggregation::Source),
+            other => Err(anyhow!("未知的聚合方式: {}（可用: five-tuple / host-pair / source）", other)),
+        }
+    }
+
+    fn apply(&self, key: &FlowKey) -> FlowKey {
+        match self {
+            Aggregation::FiveTuple => *key,
+            Aggregation::HostPair => FlowKey { src_port: 0, dst_port: 0, ..*key },
+            Aggregation::Source => FlowKey {
+                dst: match key.src {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+                },
+                src_port: 0,
+                dst_port: 0,
+                ..*key
+            },
+        }
+    }
+}
+
+/// 一条待导出的流记录
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlowRecord {
+    pub key: FlowKey,
+    pub octets: u64,
+    pub packets: u64,
+    /// Unix 毫秒时间戳
+    pub start_ms: u64,
+    pub end_ms: u64,
+}
+
+/// 把流表增量转换为导出记录并按聚合方式合并
+pub fn build_records(deltas: &[(FlowKey, FlowStats)], aggregation: Aggregation) -> Vec<FlowRecord> {
+    let now_instant = Instant::now();
+    let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let to_ms = |t: Instant| now_ms.saturating_sub(now_instant.duration_since(t).as_millis() as u64);
+
+    let mut merged: HashMap<FlowKey, FlowRecord> = HashMap::new();
+    for (key, stats) in deltas {
+        let (octets, packets) = stats.delta();
+        let key = aggregation.apply(key);
+        let record = FlowRecord {
+            key,
+            octets,
+            packets,
+            start_ms: to_ms(stats.first_seen),
+            end_ms: to_ms(stats.last_seen),
+        };
+        merged.entry(key)
+            .and_modify(|r| {
+                r.octets += record.octets;
+                r.packets += record.packets;
+                r.start_ms = r.start_ms.min(record.start_ms);
+                r.end_ms = r.end_ms.max(record.end_ms);
+            })
+            .or_insert(record);
+    }
+    merged.into_values().collect()
+}
+
+fn template_fields(v6: bool) -> [(u16, u16); 9] {
+    let (src, dst) = if v6 {
+        (IE_SOURCE_IPV6_ADDRESS, IE_DESTINATION_IPV6_ADDRESS)
+    } else {
+        (IE_SOURCE_IPV4_ADDRESS, IE_DESTINATION_IPV4_ADDRESS)
+    };
+    [
+        src,
+        dst,
+        IE_PROTOCOL_IDENTIFIER,
+        IE_SOURCE_TRANSPORT_PORT,
+        IE_DESTINATION_TRANSPORT_PORT,
+        IE_OCTET_DELTA_COUNT,
+        IE_PACKET_DELTA_COUNT,
+        IE_FLOW_START_MILLISECONDS,
+        IE_FLOW_END_MILLISECONDS,
+    ]
+}
+
+/// 模板集合（同时包含 IPv4 和 IPv6 模板）
+fn encode_template_set() -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, v6) in [(TEMPLATE_ID_V4, false), (TEMPLATE_ID_V6, true)] {
+        let fields = template_fields(v6);
+        body.extend_from_slice(&id.to_be_bytes());
+        body.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+        for (ie, len) in fields {
+            body.extend_from_slice(&ie.to_be_bytes());
+            body.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+    encode_set(TEMPLATE_SET_ID, &body)
+}
+
+fn encode_set(set_id: u16, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.extend_from_slice(&set_id.to_be_bytes());
+    out.extend_from_slice(&((4 + body.len()) as u16).to_be_bytes());
+    out.extend_from_slice(body);
+    out
+}
+
+fn encode_record(record: &FlowRecord, out: &mut Vec<u8>) {
+    match (record.key.src, record.key.dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            out.extend_from_slice(&s.octets());
+            out.extend_from_slice(&d.octets());
+        }
+        (s, d) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            out.extend_from_slice(&v6(s).octets());
+            out.extend_from_slice(&v6(d).octets());
+        }
+    }
+    out.push(record.key.protocol);
+    out.extend_from_slice(&record.key.src_port.to_be_bytes());
+    out.extend_from_slice(&record.key.dst_port.to_be_bytes());
+    out.extend_from_slice(&record.octets.to_be_bytes());
+    out.extend_from_slice(&record.packets.to_be_bytes());
+    out.extend_from_slice(&record.start_ms.to_be_bytes());
+    out.extend_from_slice(&record.end_ms.to_be_bytes());
+}
+
+/// IPFIX 导出器
+pub struct IpfixExporter {
+    collector: SocketAddr,
+    observation_domain: u32,
+    /// 已导出的数据记录总数（IPFIX 序列号）
+    sequence: u32,
+    pub interval: Duration,
+    pub aggregation: Aggregation,
+}
+
+impl IpfixExporter {
+    /// 从命令行参数构建，未指定 --ipfix-collector 时返回 None
+    ///
+    /// * `--ipfix-collector <host:port>`：采集器地址（通常是 4739 端口）
+    /// * `--ipfix-interval <秒>`：导出周期，默认 60
+    /// * `--ipfix-aggregate five-tuple|host-pair|source`：导出前的聚合方式，默认 five-tuple
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(collector) = crate::arg_value(args, "--ipfix-collector") else {
+            return Ok(None);
+        };
+        let collector = std::net::ToSocketAddrs::to_socket_addrs(&collector)?
+            .next()
+            .ok_or_else(|| anyhow!("无法解析 IPFIX 采集器地址: {}", collector))?;
+
+        let interval = match crate::arg_value(args, "--ipfix-interval") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| anyhow!("无效的 --ipfix-interval: {}", secs))?),
+            None => DEFAULT_EXPORT_INTERVAL,
+        };
+        let aggregation = match crate::arg_value(args, "--ipfix-aggregate") {
+            Some(s) => Aggregation::parse(&s)?,
+            None => Aggregation::FiveTuple,
+        };
+
+        Ok(Some(Self {
+            collector,
+            observation_domain: 1,
+            sequence: 0,
+            interval,
+            aggregation,
+        }))
+    }
+
+    /// 把记录编码为一个或多个 IPFIX 消息
+    pub fn encode_messages(&mut self, records: &[FlowRecord], export_time: u32) -> Vec<Vec<u8>> {
+        let template_set = encode_template_set();
+        let mut messages = Vec::new();
+
+        let (v4, v6): (Vec<&FlowRecord>, Vec<&FlowRecord>) = records.iter()
+            .partition(|r| matches!((r.key.src, r.key.dst), (IpAddr::V4(_), IpAddr::V4(_))));
+
+        for (template_id, group) in [(TEMPLATE_ID_V4, v4), (TEMPLATE_ID_V6, v6)] {
+            let record_len: usize = template_fields(template_id == TEMPLATE_ID_V6).iter().map(|(_, l)| *l as usize).sum();
+            let per_message = ((MAX_MESSAGE_SIZE - 16 - template_set.len() - 4) / record_len).max(1);
+
+            for chunk in group.chunks(per_message) {
+                let mut body = Vec::with_capacity(chunk.len() * record_len);
+                for record in chunk {
+                    encode_record(record, &mut body);
+                }
+                let data_set = encode_set(template_id, &body);
+
+                let length = 16 + template_set.len() + data_set.len();
+                let mut message = Vec::with_capacity(length);
+                message.extend_from_slice(&IPFIX_VERSION.to_be_bytes());
+                message.extend_from_slice(&(length as u16).to_be_bytes());
+                message.extend_from_slice(&export_time.to_be_bytes());
+                message.extend_from_slice(&self.sequence.to_be_bytes());
+                message.extend_from_slice(&self.observation_domain.to_be_bytes());
+                message.extend_from_slice(&template_set);
+                message.extend_from_slice(&data_set);
+                messages.push(message);
+
+                self.sequence = self.sequence.wrapping_add(chunk.len() as u32);
+            }
+        }
+        messages
+    }
+
+    /// 编码并发送给采集器
+    pub async fn export(&mut self, socket: &UdpSocket, records: &[FlowRecord]) -> Result<usize> {
+        let export_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+        let messages = self.encode_messages(records, export_time);
+        for message in &messages {
+            socket.send_to(message, self.collector).await?;
+        }
+        Ok(messages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(src_port: u16) -> FlowKey {
+        FlowKey {
+            src: "10.0.0.2".parse().unwrap(),
+            dst: "1.1.1.1".parse().unwrap(),
+            protocol: 6,
+            src_port,
+            dst_port: 443,
+        }
+    }
+
+    fn stats(bytes: u64) -> FlowStats {
+        let now = Instant::now();
+        FlowStats { bytes, packets: 1, first_seen: now, last_seen: now, exported_bytes: 0, exported_packets: 0 }
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let deltas = vec![(key(1000), stats(100)), (key(2000), stats(50))];
+
+        assert_eq!(build_records(&deltas, Aggregation::FiveTuple).len(), 2);
+
+        let merged = build_records(&deltas, Aggregation::HostPair);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].octets, 150);
+        assert_eq!(merged[0].packets, 2);
+        assert_eq!(merged[0].key.dst_port, 0);
+    }
+
+    #[test]
+    fn test_encode_messages() {
+        let mut exporter = IpfixExporter {
+            collector: "127.0.0.1:4739".parse().unwrap(),
+            observation_domain: 1,
+            sequence: 0,
+            interval: DEFAULT_EXPORT_INTERVAL,
+            aggregation: Aggregation::FiveTuple,
+        };
+        let records: Vec<FlowRecord> = (0..100)
+            .map(|i| FlowRecord { key: key(i), octets: 1, packets: 1, start_ms: 0, end_ms: 0 })
+            .collect();
+
+        let messages = exporter.encode_messages(&records, 1_700_000_000);
+        assert!(messages.len() > 1);
+        for m in &messages {
+            assert_eq!(u16::from_be_bytes([m[0], m[1]]), IPFIX_VERSION);
+            assert_eq!(u16::from_be_bytes([m[2], m[3]]) as usize, m.len());
+            assert!(m.len() <= MAX_MESSAGE_SIZE);
+            // 第一个集合是模板集合
+            assert_eq!(u16::from_be_bytes([m[16], m[17]]), TEMPLATE_SET_ID);
+        }
+        // 序列号累计的是数据记录数
+        assert_eq!(exporter.sequence, 100);
+    }
+}
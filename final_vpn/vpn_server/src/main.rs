@@ -1,60 +1,372 @@
 // vpn_server/src/main.rs
 
 use tokio::net::UdpSocket;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use std::collections::HashMap;
-use std::net::{SocketAddr, Ipv4Addr};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, Ipv4Addr};
 use std::sync::Arc;
-use tokio::sync::Mutex; // 用于多线程/异步任务间共享 Map
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Mutex, Semaphore}; // 用于多线程/异步任务间共享 Map
 use anyhow::Result;
-use tun::Device; // 导入 Device trait
 
 // 引入核心库
-use vpn_core::symmetric::Cipher;
-use vpn_core::handshake::{ServerHandshake, HandshakeMessage, serialize_message, deserialize_message};
-use vpn_core::asymmetric::{ServerIdentity, get_keys_dir};
+use vpn_core::symmetric::{self, PacketKeys};
+use vpn_core::replay::ReplayWindow;
+use vpn_core::psk::{self, PskSet};
+use vpn_core::icmp::{self, HopLimit};
+use vpn_core::pmtu::{self, PmtuMessage};
+use vpn_core::control::{self, ClientMetadata, ControlMessage, LinkHealth, PayloadKind, PeerInfo, RttEstimator, Service};
+use filter::{FilterConfig, PeerAcl};
+use flows::FlowTable;
+use denials::{DenialTable, DenyReason};
+use clients::{ClientRegistry, DuplicateDecision, DuplicatePolicy};
+use ipam::{AddressPool, Ipam};
+use shaping::{ShapingConfig, TrafficShaper};
+use fastpath::{FastPath, FastPathConfig};
+use transport::Transport;
+use rehello::PendingRehellos;
+use vpn_core::handshake::{Capabilities, ServerHandshake, HandshakeMessage, AuthCredential, serialize_message, deserialize_message, handshake_error_message, handshake_version, verify_client_identity, FEATURE_VERSION, verify_client_finish, server_finish, CookieJar, HandshakeErrorCode, AUTO_VIRTUAL_IP};
+use vpn_core::wire::{self, Datagram, WIRE_VERSION};
+use vpn_core::asymmetric::{Pkcs11Signer, ServerIdentity, get_keys_dir, key_fingerprint};
+use vpn_core::tpm::KeyProtection;
 use vpn_core::local_tun;
 use vpn_core::gateway;
+use vpn_core::mdns;
+use vpn_core::netwatch::{self, NetworkEvent};
+use vpn_core::telemetry::{Telemetry, Metrics, Span};
+use vpn_core::datapath_log::{self, DataPathLog};
+use vpn_core::keylog::{self, KeyEvent};
+use vpn_core::logsink;
+use vpn_core::crash;
+use vpn_core::trace_packet;
+use vpn_core::tuning::Tuning;
+use vpn_core::engine::{PacketHandler, Role, TunnelEngine};
+use vpn_core::config;
+use vpn_core::dryrun::{self, Category, Plan};
+use vpn_core::preflight::{self, Preflight};
+use vpn_core::undo::UndoJournal;
+use vpn_core::resume::{self, TicketId};
+use vpn_core::multipath;
+use vpn_core::fec::{self, Fec, FecFrames};
+use vpn_core::recursion::{self, LocalEndpoints};
+use vpn_core::hooks::{Direction, HookChain, HookVerdict, PacketContext};
 
-// 预共享密钥 (PSK) - 需与客户端一致
-const PSK: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
-// 监听端口
+mod accounting;
+mod admin;
+mod backup;
+mod bonding;
+mod clients;
+mod ddns;
+mod denials;
+mod dns;
+mod fastpath;
+mod filter;
+mod flows;
+mod ipam;
+mod ipfix;
+mod martians;
+mod portmap;
+mod presence;
+mod rehello;
+mod shaping;
+mod tickets;
+mod transport;
+mod xsk;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod auth;
+use accounting::{Accounting, AcctRecord, AcctStatus, TerminateCause};
+use auth::AuthConfig;
+use bonding::Bonding;
+use presence::RecentPeers;
+use tickets::{Ticket, TicketStore};
+
+// 默认监听地址（--listen 或配置文件 network.listen 覆盖）
 const LISTEN_ADDR: &str = "0.0.0.0:9000";
 // 服务端TUN设备配置
-const SERVER_TUN_IP: &str = "10.0.0.1";
+const SERVER_TUN_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
 const SERVER_TUN_MASK: &str = "255.255.255.0";
+const SERVER_TUN_PREFIX: u8 = 24;
+// ClientAuth 失败时的最短响应时间（从收到请求算起），覆盖常见认证后端的耗时差异
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+// 有界资源：超出时丢弃新请求并计入 vpn.queue.dropped，过载或受攻击时不会无限占用内存
+// 等待密钥确认（ClientFinish）或外部认证（ClientAuth）的会话数上限，以及等待的最长时间
+const MAX_PENDING_SESSIONS: usize = 1024;
+const PENDING_SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+// 同时处理的 ClientAuth 数（每个请求在失败时至少占用 AUTH_FAILURE_DELAY）
+const MAX_AUTH_TASKS: usize = 64;
+// 同时处理的 ClientHello 数（密钥运算在阻塞线程池里执行）
+const MAX_HANDSHAKE_TASKS: usize = 64;
+// 同时进行的 RADIUS 计费上报数（计费服务器变慢时的积压上限）
+const MAX_ACCOUNTING_TASKS: usize = 256;
+// 清理流时待导出 IPFIX 的批次数
+const EXPIRED_FLOW_QUEUE: usize = 16;
 
-#[cfg(target_os = "macos")]
-const TUN_READ_OFFSET: usize = 4;
-
-#[cfg(target_os = "linux")]
-const TUN_READ_OFFSET: usize = 0;
 
 /// 定义 PeerMap: 记录 虚拟IP (10.0.0.x) -> 真实 UDP 地址 的映射
 type PeerMap = Arc<Mutex<HashMap<Ipv4Addr, SocketAddr>>>;
 
 /// 会话信息：记录每个客户端的会话密钥和状态
 struct Session {
+    /// 会话密钥：握手确认、会话恢复、多路径证明和密钥轮换使用，数据包使用由它展开的 keys
     session_key: [u8; 32],
-    #[allow(dead_code)]
+    /// 数据包密钥（两个方向各一个，见 kdf::DataKeys）
+    keys: Arc<PacketKeys>,
+    /// 密钥轮换前的数据包密钥，用于解密轮换时仍在途中的包
+    previous_keys: Option<Arc<PacketKeys>>,
+    /// 是否已向客户端下发会话信息（公网映射地址和路由）
+    info_sent: bool,
+    /// 控制通道 Echo 测得的 RTT 和链路状态
+    rtt: RttEstimator,
+    /// 下一个发往客户端的数据包序号
+    send_seq: u64,
+    /// 收到的序号，丢弃链路复制出的重复包和重放的包（密钥轮换前后共用）
+    replay: ReplayWindow,
     peer_addr: SocketAddr,
+    /// 客户端在 ClientHello 中声明的虚拟 IP
+    virtual_ip: Option<Ipv4Addr>,
+    /// 是否已通过认证（未启用外部认证时收到 ClientFinish 即为 true）
+    authenticated: bool,
+    /// 客户端是否已用 ClientFinish 证明持有会话密钥（会话恢复自带双向证明，直接为 true）
+    confirmed: bool,
+    /// 外部认证后端返回的身份
+    identity: Option<String>,
+    /// 客户端在 ClientHello 中上报的标识
+    client_id: String,
+    /// 客户端身份公钥（会话恢复时重新检查登记表）
+    identity_key: [u8; 32],
+    /// 客户端上报的主机名（DNS 转发器解析 `<hostname>.vpn`）
+    hostname: Option<String>,
+    /// 客户端自愿上报的设备信息（vpn_server clients 显示）
+    metadata: Option<ClientMetadata>,
+    /// 双方都支持的功能（客户端没有声明功能标志时为 None）
+    capabilities: Option<Capabilities>,
+    /// 握手用的是宽限期内的旧 PSK（确认会话后通知客户端更换）
+    previous_psk: bool,
+    /// 最近互相转发过包的其他客户端（对端上下线时通知本客户端）
+    recent_peers: RecentPeers,
+    /// 客户端登记的对隧道开放的服务（vpn_client services）
+    services: Vec<Service>,
+    /// 会话恢复票据（启用 --session-resume 时，下发会话信息时签发）
+    ticket: Option<TicketId>,
+    /// 握手时协商的前向纠错（客户端请求且服务端未指定 --no-fec 时启用）
+    fec: Option<Fec>,
+    /// 确认后接替的同一身份的旧会话（--duplicate-policy replace / allow）
+    ///
+    /// ClientHello 的身份签名只绑定客户端自己选的临时公钥，重放旧的 ClientHello 也能通过签名检查；
+    /// 重放者算不出会话密钥，发不出有效的 ClientFinish，旧会话和虚拟 IP 的路由因此不受影响
+    takeover: Vec<SocketAddr>,
+    /// 计费用的会话 ID 和起始时间
+    session_id: String,
+    started_at: Instant,
+    /// 流量计数（以服务端为视角：in = 来自客户端）
+    bytes_in: u64,
+    bytes_out: u64,
+    packets_in: u64,
+    packets_out: u64,
+}
+
+/// 服务端一侧的数据包密钥：由会话密钥展开，用服务端 -> 客户端的密钥发送
+fn server_packet_keys(session_key: &[u8; 32]) -> anyhow::Result<Arc<PacketKeys>> {
+    Ok(Arc::new(PacketKeys::from_session_key(session_key, symmetric::Direction::ToClient)?))
+}
+
+impl Session {
+    /// 预留 count 个连续的发送序号，返回第一个
+    fn reserve_seq(&mut self, count: u64) -> u64 {
+        let seq = self.send_seq;
+        self.send_seq += count;
+        seq
+    }
+
+    /// 生成当前时刻的计费记录
+    fn acct_record(&self, terminate_cause: Option<TerminateCause>) -> AcctRecord {
+        AcctRecord {
+            session_id: self.session_id.clone(),
+            user_name: self.identity.clone().unwrap_or_else(|| self.client_id.clone()),
+            framed_ip: self.virtual_ip,
+            calling_station: self.peer_addr,
+            input_octets: self.bytes_in,
+            output_octets: self.bytes_out,
+            input_packets: self.packets_in,
+            output_packets: self.packets_out,
+            session_time: self.started_at.elapsed(),
+            terminate_cause,
+        }
+    }
 }
 
 /// 会话表：UDP地址 -> Session
 type SessionMap = Arc<Mutex<HashMap<SocketAddr, Session>>>;
 
+/// 服务端共享状态：各个处理函数和任务都通过它访问 socket、会话表等资源
+struct ServerState {
+    socket: Arc<Transport>,
+    sessions: SessionMap,
+    peers: PeerMap,
+    identity: Arc<ServerIdentity>,
+    /// 客户端身份登记表（TOFU）和按身份绑定的虚拟 IP
+    clients: ClientRegistry,
+    /// 同一身份重复连接时的处理方式（--duplicate-policy）
+    duplicate_policy: DuplicatePolicy,
+    /// 请求 `auto` 的客户端的虚拟 IP 分配（--ipam）
+    ipam: Ipam,
+    /// ClientHello 来源地址验证
+    cookies: CookieJar,
+    telemetry: Telemetry,
+    auth: Option<Arc<AuthConfig>>,
+    accounting: Option<Arc<Accounting>>,
+    /// 通过控制通道下发给客户端的路由（--push-route）
+    pushed_routes: Vec<String>,
+    /// 内层流量的流表（只在同步代码中访问，使用 std Mutex）
+    flows: std::sync::Mutex<FlowTable>,
+    /// 数据面汇总计数（每秒打印一行，代替逐包日志）
+    datapath: Arc<DataPathLog>,
+    /// 按来源和原因统计的拒绝记录（vpn_server denials）
+    denials: std::sync::Mutex<DenialTable>,
+    /// TUN 上的 tc HTB 整形（--tc-rate）
+    shaper: Option<Arc<TrafficShaper>>,
+    /// 外网接口上的 eBPF 快速路径（--xdp）
+    fastpath: Option<Arc<FastPath>>,
+    /// 内层流量的协议/端口白名单（--allow / --client-allow）
+    filter: Option<FilterConfig>,
+    /// 客户端互访的 ACL（--peer-acl），同时决定 vpn_client peers 能看到哪些对端
+    peer_acl: Option<PeerAcl>,
+    /// 会话恢复票据（--session-resume）
+    tickets: Option<std::sync::Mutex<TicketStore>>,
+    /// 多路径绑定：第二条链路的别名和重排缓冲区（--bonding）
+    bonding: Option<Bonding>,
+    /// 是否接受客户端的 FEC 请求（--no-fec 时为 false）
+    fec_enabled: bool,
+    /// 本端的功能标志，随 ServerHello 发给声明了功能标志的客户端
+    capabilities: Capabilities,
+    /// 隐蔽模式（--stealth）：拒绝握手时不回复 HandshakeError
+    stealth: bool,
+    /// 同时在线的客户端上限（--max-clients）
+    max_clients: Option<usize>,
+    /// 接受的最低客户端版本（--min-client-version）
+    version_policy: Option<VersionPolicy>,
+    /// 接受的 PSK：当前的和宽限期内的旧 PSK（--psk-file / --previous-psk-file）
+    psks: PskSet,
+    /// 本机的监听端点（隧道递归检测）
+    local_endpoints: LocalEndpoints,
+    /// 转发路径上的自定义钩子（见 vpn_core::hooks），运行时注册
+    hooks: Arc<HookChain>,
+    /// 正在处理的 ClientAuth（MAX_AUTH_TASKS）
+    auth_tasks: Arc<Semaphore>,
+    /// 正在处理的 ClientHello（MAX_HANDSHAKE_TASKS）
+    handshake_tasks: Arc<Semaphore>,
+    /// 同一地址上已有确认的会话时，新握手在 ClientFinish 之前暂存在这里（见 rehello）
+    rehellos: std::sync::Mutex<PendingRehellos<Session>>,
+    /// 正在进行的计费上报（MAX_ACCOUNTING_TASKS）
+    accounting_tasks: Arc<Semaphore>,
+}
+
+impl ServerState {
+    /// 取发往 addr 的下一个数据包序号，会话已不存在时返回 None
+    async fn next_seq(&self, addr: SocketAddr) -> Option<u64> {
+        self.sessions.lock().await.get_mut(&addr).map(|s| s.reserve_seq(1))
+    }
+
+    /// 异步上报一条计费记录（失败只打日志，不影响转发）
+    ///
+    /// 积压的上报达到 MAX_ACCOUNTING_TASKS 时丢弃这一条
+    fn report_accounting(&self, status: AcctStatus, record: AcctRecord) {
+        if let Some(acct) = self.accounting.clone() {
+            let Ok(permit) = self.accounting_tasks.clone().try_acquire_owned() else {
+                eprintln!("⚠️  RADIUS 计费上报积压，丢弃 {:?} {}", status, record.session_id);
+                Metrics::incr(&self.telemetry.metrics().queue_drops);
+                return;
+            };
+            tokio::spawn(async move {
+                if let Err(e) = acct.send(status, &record).await {
+                    eprintln!("⚠️  RADIUS 计费上报失败 ({:?} {}): {}", status, record.session_id, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    
+    // 管理子命令：连接正在运行的服务端，例如 `vpn_server flows --top 20`
+    if args.get(1).is_some_and(|a| admin::AdminCommand::is_subcommand(a)) {
+        return admin::run_client(&args).await;
+    }
+    // 性能自测：本机回环上的加解密和转发吞吐，不需要 root
+    if args.get(1).map(String::as_str) == Some("bench") {
+        return vpn_core::bench::run_command(&args).await;
+    }
+    // PSK 文件的生成和轮换：`vpn_server psk rotate /etc/rust-vpn/psk`
+    if args.get(1).map(String::as_str) == Some("psk") {
+        return psk::run_command(&args);
+    }
+    // 状态备份和恢复（迁移主机、灾难恢复）：`vpn_server export-state /backup/vpn.rvpn --config /etc/rust-vpn/server.toml`
+    match args.get(1).map(String::as_str) {
+        Some("export-state") => return backup::run_export(&args),
+        Some("import-state") => return backup::run_import(&args),
+        _ => {}
+    }
+    
+    // 配置文件（--config）展开为命令行参数，命令行上的值优先
+    let args = config::load_args(&args, Role::Server)?;
+    if args.contains(&"--check-config".to_string()) {
+        return check_config(&args);
+    }
+    if let Some(profile) = arg_value(&args, "--profile") {
+        println!("🎚️  运行档位: {}（未显式指定的参数按档位取值）", profile);
+    }
+    if dryrun::requested(&args) {
+        return dry_run(&args);
+    }
+    // 在修改任何系统状态（包括生成密钥）之前检查权限和依赖的命令
+    if !preflight::skipped(&args) {
+        preflight_checks(&args).run()?;
+    }
+    
     // 1. 初始化
+    logsink::init_from_args(&args, "vpn_server")?;
+    crash::install();
     println!("🚀 VPN Server 启动中...");
+    datapath_log::init_trace_from_args(&args);
+    keylog::init_from_args(&args)?;
     println!("⚠️  注意：网关模式需要 sudo 权限！");
     
     // 检测参数：是否启用网关模式
-    let args: Vec<String> = std::env::args().collect();
     let enable_gateway = args.contains(&"--gateway".to_string());
+    // 隧道内 IPv6（fd00::/96），网关模式下同时配置 IPv6 转发和 NAT
+    let enable_ipv6 = args.contains(&"--ipv6".to_string());
+    // 出口策略：按客户端（--egress-client）或目的网段（--egress-dest）选择外网接口
+    let egress_rules = arg_values(&args, "--egress-client").iter()
+        .map(|spec| gateway::EgressRule::parse(spec, false))
+        .chain(arg_values(&args, "--egress-dest").iter().map(|spec| gateway::EgressRule::parse(spec, true)))
+        .collect::<Result<Vec<_>>>()?;
+    // 隧道可以访问的本机服务端口白名单（--host-services tcp:22,udp:53），不指定时不限制
+    let host_services = arg_value(&args, "--host-services")
+        .map(|spec| gateway::parse_host_services(&spec))
+        .transpose()?;
+    
+    // 可选：OTLP 导出（--otlp-endpoint 或 OTEL_EXPORTER_OTLP_ENDPOINT）
+    let telemetry = Telemetry::from_env_or_arg(arg_value(&args, "--otlp-endpoint").as_deref(), "vpn_server");
+    
+    // 可选：外部认证后端（--auth ldap|oidc）
+    let auth_config = auth::from_args(&args)?;
+    if let Some(cfg) = &auth_config {
+        println!("🪪 客户端准入由 {} 认证后端决定", cfg.backend.name());
+    }
+    
+    // 可选：RADIUS 计费（--radius-acct host:port）
+    let accounting = Accounting::from_args(&args)?.map(Arc::new);
+    if let Some(acct) = &accounting {
+        println!("🧾 RADIUS 计费已启用（Interim 周期 {} 秒）", acct.interim_interval.as_secs());
+    }
     
     if enable_gateway {
         println!("🌐 启用网关模式（NAT转发到互联网）");
+    } else if !egress_rules.is_empty() {
+        println!("⚠️  出口策略需要 --gateway，已忽略");
     } else {
         println!("🔗 点对点模式（仅客户端间互联）");
         println!("   提示：使用 --gateway 参数启用互联网转发");
@@ -62,31 +374,89 @@ async fn main() -> Result<()> {
     
     // 加载或生成服务端密钥对
     let keys_dir = get_keys_dir()?;
-    let server_identity = ServerIdentity::load_or_generate(&keys_dir)?;
+    // --pkcs11-module 指定时签名密钥留在 HSM / PKCS#11 token 中，不读取 keys/server_private.key
+    let server_identity = match arg_value(&args, "--pkcs11-module") {
+        Some(module) => {
+            let key_id = arg_value(&args, "--pkcs11-key-id").ok_or_else(|| anyhow::anyhow!("--pkcs11-module 需要同时指定 --pkcs11-key-id"))?;
+            let pin = std::env::var("VPN_PKCS11_PIN").ok();
+            let signer = Pkcs11Signer::open(module.into(), arg_value(&args, "--pkcs11-token"), key_id, pin)?;
+            ServerIdentity::with_signer(Box::new(signer), &keys_dir)?
+        }
+        // --tpm-seal：私钥密封到本机 TPM，TPM 不可用时回退为明文文件
+        None if args.contains(&"--tpm-seal".to_string()) => ServerIdentity::load_or_generate(&keys_dir, KeyProtection::Tpm)?,
+        None => ServerIdentity::load_or_generate(&keys_dir, KeyProtection::File)?,
+    };
     server_identity.print_public_key();
     let server_identity = Arc::new(server_identity);
+    let client_registry = ClientRegistry::from_args(&args, &keys_dir)?;
+    println!("🪪 已登记的客户端身份: {}", client_registry.known_count());
+    let ipam = Ipam::from_args(&args, AddressPool::new(SERVER_TUN_IP, SERVER_TUN_PREFIX))?;
+    println!("🏷️  虚拟 IP 自动分配: {}", ipam.name());
+    println!("🔐 加密后端: {}", vpn_core::crypto::backend_name());
     
     // 创建 TUN 设备
-    let tun_dev = local_tun::create_device(SERVER_TUN_IP, SERVER_TUN_MASK)?;
-    let tun_name = tun_dev.get_ref().name()?;
+    // --tun-name 指定设备名；--tun-reuse 挂接预先创建的持久化设备（由 systemd 等负责地址配置）
+    // --tun-offload 开启 TSO/GSO 卸载（仅 Linux）
+    let device_options = local_tun::DeviceOptions {
+        name: arg_value(&args, "--tun-name"),
+        reuse_existing: args.contains(&"--tun-reuse".to_string()),
+        offload: args.contains(&"--tun-offload".to_string()),
+    };
+    // 启动失败（返回错误或 panic）时按相反顺序撤销下面已应用的配置；启动完成后交给退出处理
+    let mut journal = UndoJournal::new();
+    let (tun_dev, tun_name) = local_tun::open_device(&SERVER_TUN_IP.to_string(), SERVER_TUN_MASK, &device_options)?;
     println!("✅ TUN 设备创建成功: {}", tun_name);
     
+    // 性能参数；--mtu 同时决定收包缓冲区大小（巨型帧需要两端一致）
+    let tuning = Tuning::from_args(&args)?;
+    if let Some(mtu) = tuning.mtu {
+        match local_tun::set_mtu(&tun_name, mtu) {
+            Ok(_) => println!("✅ TUN MTU: {}", mtu),
+            Err(e) => println!("⚠️  MTU 配置警告: {}", e),
+        }
+    }
+    
     // 配置路由
     match local_tun::configure_route(&tun_name, "10.0.0.0/24") {
-        Ok(_) => println!("✅ 路由配置成功"),
+        Ok(_) => {
+            println!("✅ 路由配置成功");
+            let dev = tun_name.clone();
+            journal.record(format!("删除路由 10.0.0.0/24 dev {}", tun_name), move || {
+                let _ = local_tun::remove_route(&dev, "10.0.0.0/24");
+            });
+        }
         Err(e) => println!("⚠️  路由配置警告: {}", e),
     }
+    if let Some(services) = &host_services {
+        // 失败时可能已经建了一半的链，同样需要撤销
+        let dev = tun_name.clone();
+        journal.record("删除本机服务白名单", move || gateway::cleanup_host_services(&dev));
+        if let Err(e) = gateway::setup_host_services(&tun_name, services) {
+            eprintln!("⚠️  本机服务白名单配置失败: {}", e);
+        }
+    }
+    if enable_ipv6 {
+        match local_tun::add_ipv6_address(&tun_name, local_tun::tunnel_ipv6(SERVER_TUN_IP)) {
+            Ok(_) => println!("✅ IPv6 地址配置成功"),
+            Err(e) => println!("⚠️  IPv6 地址配置警告: {}", e),
+        }
+    }
     
-    // 如果启用网关模式，配置IP转发和NAT
+    // 如果启用网关模式，配置IP转发和NAT；记录当前外网接口，接口切换和退出时用来迁移/清理规则
+    let mut nat_interface: Option<Arc<std::sync::Mutex<String>>> = None;
     if enable_gateway {
         println!("\n🔧 配置网关功能...");
         
-        // 启用IP转发
+        // 启用IP转发（原本未开启时，启动失败后关闭）
+        let was_forwarding = gateway::forwarding_enabled(false);
         if let Err(e) = gateway::enable_ip_forwarding() {
             eprintln!("❌ 启用IP转发失败: {}", e);
             eprintln!("   请使用 sudo 运行服务端");
             return Err(anyhow::anyhow!("IP转发失败"));
         }
+        if was_forwarding == Some(false) {
+            journal.record("关闭 IPv4 转发", || gateway::disable_forwarding(false));
+        }
         
         // 检测外网接口
         let external_if = match gateway::detect_default_interface() {
@@ -102,334 +472,2070 @@ async fn main() -> Result<()> {
         };
         
         // 配置NAT
+        {
+            let (dev, iface) = (tun_name.clone(), external_if.clone());
+            journal.record(format!("删除 {} 上的 NAT 规则", external_if), move || {
+                let _ = gateway::cleanup_nat(&dev, &iface);
+            });
+        }
         if let Err(e) = gateway::setup_nat(&tun_name, &external_if) {
             eprintln!("⚠️  NAT配置失败: {}", e);
             #[cfg(target_os = "macos")]
             println!("   macOS 用户需要手动配置 pfctl（参考上方提示）");
         }
         
+        if !egress_rules.is_empty() {
+            if let Err(e) = gateway::setup_egress(&tun_name, &egress_rules) {
+                eprintln!("⚠️  出口策略配置失败: {}", e);
+                gateway::cleanup_egress(&tun_name, &egress_rules);
+            } else {
+                let (dev, rules) = (tun_name.clone(), egress_rules.clone());
+                journal.record("删除出口策略", move || gateway::cleanup_egress(&dev, &rules));
+            }
+        }
+        
+        if enable_ipv6 {
+            let was_forwarding = gateway::forwarding_enabled(true);
+            if let Err(e) = gateway::enable_ipv6_forwarding() {
+                eprintln!("⚠️  启用 IPv6 转发失败: {}", e);
+            } else {
+                if was_forwarding == Some(false) {
+                    journal.record("关闭 IPv6 转发", || gateway::disable_forwarding(true));
+                }
+                let (dev, iface) = (tun_name.clone(), external_if.clone());
+                journal.record(format!("删除 {} 上的 IPv6 NAT 规则", external_if), move || {
+                    let _ = gateway::cleanup_nat6(&dev, &iface);
+                });
+                if let Err(e) = gateway::setup_nat6(&tun_name, &external_if) {
+                    eprintln!("⚠️  IPv6 NAT配置失败: {}", e);
+                }
+            }
+        }
+        
+        // 外网接口切换（eth0 -> eth1）后旧规则不再生效，监听路由变化自动迁移
+        let current = Arc::new(std::sync::Mutex::new(external_if));
+        nat_interface = Some(current.clone());
+        let tun_name = tun_name.clone();
+        let mut events = netwatch::spawn_interface_watcher();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                // 默认路由暂时消失（网卡 down、DHCP 续租中）时保留旧规则，等新接口出现
+                let NetworkEvent::DefaultInterfaceChanged { interface: Some(new_if) } = event else { continue };
+                let old_if = current.lock().unwrap().clone();
+                if new_if == old_if {
+                    continue;
+                }
+                println!("🔄 外网接口变化: {} -> {}，重新配置 NAT", old_if, new_if);
+                if let Err(e) = gateway::move_nat(&tun_name, &old_if, &new_if, enable_ipv6) {
+                    eprintln!("⚠️  NAT 迁移失败: {}", e);
+                }
+                *current.lock().unwrap() = new_if;
+            }
+        });
+        
         println!("✅ 网关配置完成\n");
     }
     
-    let socket = UdpSocket::bind(LISTEN_ADDR).await?;
+    let socket = UdpSocket::bind(arg_value(&args, "--listen").as_deref().unwrap_or(LISTEN_ADDR)).await?;
     println!("📡 正在监听 UDP: {}", socket.local_addr()?);
+    // 本机的监听端点：客户端发进隧道、目的地是这些端点的包会回到本 socket（隧道里的隧道），直接丢弃
+    let listen_addr = socket.local_addr()?;
+    let tunnel_addrs = [IpAddr::V4(SERVER_TUN_IP), IpAddr::V6(local_tun::tunnel_ipv6(SERVER_TUN_IP))];
+    let local_endpoints = LocalEndpoints::new(listen_addr, &tunnel_addrs).unwrap_or_else(|e| {
+        eprintln!("⚠️  无法读取本机地址（{}），隧道递归检测只覆盖隧道地址", e);
+        LocalEndpoints::with_addrs(listen_addr.port(), tunnel_addrs.to_vec())
+    });
+    // 客户端多时默认的接收缓冲区容易被突发流量打满（--recv-buffer / --send-buffer）
+    if tuning.recv_buffer.is_some() || tuning.send_buffer.is_some() {
+        let (recv, send) = tuning.apply_socket(&socket)?;
+        println!("   📦 socket 缓冲区: 接收 {} 字节，发送 {} 字节", recv, send);
+    }
+    
+    // 可选：在上游路由器上映射监听端口（--port-map auto|natpmp|upnp）
+    let port_mapper = portmap::PortMapper::from_args(&args, socket.local_addr()?.port())?.map(Arc::new);
+    if let Some(mapper) = &port_mapper {
+        match mapper.start().await {
+            Ok(mapping) => println!("📣 端口映射成功（{:?}，租期 {} 秒），客户端请连接: {}", mapping.method, mapping.lifetime.as_secs(), mapping.external),
+            Err(e) => eprintln!("⚠️  端口映射失败，需要在路由器上手动转发 UDP 端口: {}", e),
+        }
+    }
     
-    let socket = Arc::new(socket);
+    // 可选：局域网 mDNS/DNS-SD 广播（--mdns [--mdns-name <实例名>]，默认使用主机名）
+    if args.contains(&"--mdns".to_string()) {
+        let instance = arg_value(&args, "--mdns-name").unwrap_or_else(|| {
+            std::process::Command::new("hostname").output().ok()
+                .map(|o| String::from_utf8_lossy(&o.stdout).trim().trim_end_matches(".local").to_string())
+                .filter(|h| !h.is_empty())
+                .unwrap_or_else(|| "rust-vpn".to_string())
+        });
+        let info = mdns::ServiceInfo {
+            instance,
+            port: socket.local_addr()?.port(),
+            // 公钥前 8 字节作为指纹，便于在客户端核对
+            txt: vec!["v=1".to_string(), format!("pk={}", hex::encode(&server_identity.public_key_bytes()[..8]))],
+        };
+        if let Err(e) = mdns::advertise(info) {
+            eprintln!("⚠️  mDNS 广播启动失败: {}", e);
+        }
+    }
+    
+    // 可选：把公网 IP 发布到 DDNS（--ddns duckdns|cloudflare|generic）
+    if let Some(updater) = ddns::DdnsUpdater::from_args(&args)? {
+        updater.spawn(port_mapper.clone());
+    }
+    
+    let mut transport = Transport::new(socket);
     
     // 初始化空的 Peer 表和会话表
+    // 可选：tc HTB 下行整形（--tc-rate）
+    let shaper = match ShapingConfig::from_args(&args)? {
+        Some(config) => {
+            let shaper = Arc::new(TrafficShaper::new(&tun_name, config));
+            let installed = shaper.clone();
+            journal.record("删除 tc 整形", move || installed.teardown());
+            match shaper.install() {
+                Ok(_) => {
+                    println!("🚦 tc HTB 整形已启用: 总带宽 {} bit/s", shaper.config.total);
+                    Some(shaper)
+                }
+                Err(e) => {
+                    eprintln!("⚠️  tc 整形配置失败（需要 sudo 和 sch_htb 模块）: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    
+    // 可选：eBPF 快速路径（--xdp <外网接口>），在网卡驱动里丢弃不属于任何会话的数据报
+    let fastpath = match FastPathConfig::from_args(&args)? {
+        Some(config) => {
+            let fastpath = Arc::new(FastPath::new(config, listen_addr.port()));
+            let installed = fastpath.clone();
+            journal.record("卸载 XDP 程序", move || installed.teardown());
+            match fastpath.install() {
+                Ok(_) => {
+                    println!("⚡ eBPF 快速路径已挂载到 {}（{:?} 模式）", fastpath.config.interface, fastpath.config.mode);
+                    // 可选：AF_XDP（--af-xdp），失败时所有数据报继续走 UDP socket
+                    if fastpath.config.af_xdp {
+                        match transport.enable_af_xdp(&fastpath) {
+                            Ok(queues) => println!("   ⚡ AF_XDP 已启用: {} 个接收队列", queues),
+                            Err(e) => eprintln!("⚠️  AF_XDP 不可用，回退到 UDP socket: {}", e),
+                        }
+                    }
+                    Some(fastpath)
+                }
+                Err(e) => {
+                    eprintln!("⚠️  eBPF 快速路径加载失败，所有数据报走协议栈（需要 sudo、clang 和 bpftool）: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    // UDP GSO（仅 Linux，--no-gso 关闭）：下行时把发往同一客户端的等长数据报合并成一次 sendmsg
+    if vpn_core::gso::supported() && !args.contains(&"--no-gso".to_string()) {
+        transport.enable_gso();
+        println!("📦 UDP GSO 已启用");
+    }
+    let socket = Arc::new(transport);
+    
+    // 可选：内层流量白名单
+    let filter = FilterConfig::from_args(&args)?;
+    if let Some(config) = &filter {
+        println!("🧱 流量白名单已启用（{} 个客户端单独配置）", config.overrides.len());
+    }
+    let peer_acl = PeerAcl::from_args(&args)?;
+    if let Some(acl) = &peer_acl {
+        println!("🧱 客户端互访 ACL 已启用（{} 个客户端单独配置）", acl.len());
+    }
+    
+    // 可选：会话恢复
+    let tickets = TicketStore::from_args(&args);
+    if let Some(store) = &tickets {
+        println!("🎫 会话恢复已启用（会话结束后票据保留 {} 秒）", store.lifetime_secs());
+    }
+    
+    // 可选：多路径绑定（实验性）
+    let bonding = Bonding::from_args(&args);
+    if bonding.is_some() {
+        println!("🔀 多路径绑定已启用：客户端可以用第二条链路加入会话");
+    }
+    
+    // PSK：未指定 --psk-file 时使用内置的 PSK；轮换期间旧 PSK 在宽限期内仍然接受
+    let psks = PskSet::from_args(&args)?;
+    if let Some(path) = arg_value(&args, "--psk-file") {
+        println!("🔑 PSK: {}（ID {}）", path, hex::encode(psks.current_id()));
+    }
+    match psks.grace_remaining(SystemTime::now()) {
+        Some(remaining) => println!("🔁 旧 PSK 在宽限期内仍然接受，还剩 {} 小时", remaining.as_secs() / 3600),
+        None if arg_value(&args, "--previous-psk-file").is_some() => eprintln!("⚠️  --previous-psk-file 的宽限期已过，只接受新的 PSK"),
+        None => {}
+    }
+    
     let peers: PeerMap = Arc::new(Mutex::new(HashMap::new()));
     let sessions: SessionMap = Arc::new(Mutex::new(HashMap::new()));
 
-    // 分离 TUN 设备读写
-    let (mut tun_reader, tun_writer) = tokio::io::split(tun_dev);
-    let tun_writer = Arc::new(Mutex::new(tun_writer));
+    // 本端的功能标志：只声明已经启用的功能
+    let capabilities = Capabilities::REKEY
+        .with(Capabilities::ROAMING, tickets.is_some())
+        .with(Capabilities::IPV6, enable_ipv6)
+        .with(Capabilities::FEC, !args.contains(&"--no-fec".to_string()))
+        .with(Capabilities::BONDING, bonding.is_some());
+    let state = Arc::new(ServerState {
+        socket: socket.clone(),
+        sessions,
+        peers,
+        identity: server_identity,
+        clients: client_registry,
+        duplicate_policy: DuplicatePolicy::from_args(&args)?,
+        ipam,
+        cookies: CookieJar::new(),
+        telemetry,
+        auth: auth_config,
+        accounting,
+        pushed_routes: arg_values(&args, "--push-route"),
+        flows: std::sync::Mutex::new(match arg_value(&args, "--ipfix-sample").and_then(|n| n.parse().ok()) {
+            Some(rate) => FlowTable::with_sampling(rate),
+            None => FlowTable::new(),
+        }),
+        datapath: Arc::new(DataPathLog::new()),
+        denials: std::sync::Mutex::new(DenialTable::new()),
+        shaper,
+        fastpath,
+        filter,
+        peer_acl,
+        tickets: tickets.map(std::sync::Mutex::new),
+        bonding,
+        fec_enabled: !args.contains(&"--no-fec".to_string()),
+        capabilities,
+        stealth: args.contains(&"--stealth".to_string()),
+        max_clients: parse_max_clients(&args)?,
+        version_policy: VersionPolicy::from_args(&args)?,
+        psks,
+        local_endpoints,
+        hooks: Arc::new(HookChain::new()),
+        auth_tasks: Arc::new(Semaphore::new(MAX_AUTH_TASKS)),
+        handshake_tasks: Arc::new(Semaphore::new(MAX_HANDSHAKE_TASKS)),
+        rehellos: std::sync::Mutex::new(PendingRehellos::default()),
+        accounting_tasks: Arc::new(Semaphore::new(MAX_ACCOUNTING_TASKS)),
+    });
+    state.datapath.spawn_reporter(datapath_log::report_interval_from_args(&args));
+    if let Some(fastpath) = &state.fastpath {
+        fastpath.clone().spawn_sync(state.clone());
+    }
+    
+    // 可选：隧道内的 DNS 转发器（--dns-forwarder），客户端可以用 <主机名>.vpn 互相访问
+    if let Some(forwarder) = dns::DnsForwarder::from_args(&args, enable_ipv6)? {
+        if host_services.as_ref().is_some_and(|services| !services.iter().any(|s| s.protocol == "udp" && s.port == 53)) {
+            eprintln!("⚠️  --host-services 没有放行 udp:53，客户端无法访问 DNS 转发器");
+        }
+        if let Err(e) = forwarder.spawn(state.clone(), SocketAddr::new(IpAddr::V4(SERVER_TUN_IP), 53)).await {
+            eprintln!("⚠️  DNS 转发器启动失败: {}", e);
+        }
+    }
+    
+    // 可选：WASM 策略模块，注册为转发路径钩子
+    for policy in wasm_policies(&args)? {
+        println!("🧩 已加载策略模块: {}", policy.name());
+        state.hooks.register(policy)?;
+    }
 
-    // 启动 TUN -> UDP 任务（从TUN读取，发送到客户端）
-    let socket_tun_to_udp = socket.clone();
-    let peers_tun_to_udp = peers.clone();
-    let sessions_tun_to_udp = sessions.clone();
+    // 管理接口（vpn_server flows --top 20）
+    let admin_tokens = admin::AdminTokens::from_args(&args)?;
+    let admin_socket = admin::socket_path(&args, admin_tokens.is_some());
+    if let Err(e) = admin::spawn(state.clone(), &admin_socket, admin_tokens) {
+        println!("⚠️  管理接口启动失败: {}", e);
+    }
+    
+    // IPFIX 导出（--ipfix-collector）
+    let ipfix_exporter = match ipfix::IpfixExporter::from_args(&args) {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            println!("❌ IPFIX 配置错误: {}", e);
+            return Err(e);
+        }
+    };
+    // 被清理的流里还没导出的增量，交给导出任务在下一周期发送
+    let (expired_tx, mut expired_rx) = tokio::sync::mpsc::channel::<Vec<(flows::FlowKey, flows::FlowStats)>>(EXPIRED_FLOW_QUEUE);
+    let expired_tx = ipfix_exporter.is_some().then_some(expired_tx);
+    if let Some(mut exporter) = ipfix_exporter {
+        println!("📤 IPFIX 导出已启用: 每 {:?} 一次，聚合方式 {:?}", exporter.interval, exporter.aggregation);
+        let state_ipfix = state.clone();
+        let export_socket = UdpSocket::bind("0.0.0.0:0").await?;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(exporter.interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let mut deltas = state_ipfix.flows.lock().unwrap().take_deltas();
+                while let Ok(expired) = expired_rx.try_recv() {
+                    deltas.extend(expired);
+                }
+                if deltas.is_empty() {
+                    continue;
+                }
+                let records = ipfix::build_records(&deltas, exporter.aggregation);
+                if let Err(e) = exporter.export(&export_socket, &records).await {
+                    eprintln!("⚠️  IPFIX 导出失败: {}", e);
+                }
+            }
+        });
+    }
     
+    // 定期清理空闲的流
+    let state_flows = state.clone();
     tokio::spawn(async move {
-        let mut buf = [0u8; 1500];
-        println!("⬆️  TUN->UDP 任务启动");
-        
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(30));
         loop {
-            let n = match tun_reader.read(&mut buf).await {
-                Ok(n) => n,
-                Err(e) => {
-                    eprintln!("TUN 读取错误: {}", e);
-                    break;
+            ticker.tick().await;
+            let expired = state_flows.flows.lock().unwrap().expire(flows::FLOW_IDLE_TIMEOUT);
+            if let Some(tx) = &expired_tx {
+                let pending: Vec<_> = expired.into_iter().filter(|(_, s)| s.delta().1 > 0).collect();
+                if !pending.is_empty() && tx.try_send(pending).is_err() {
+                    Metrics::incr(&state_flows.telemetry.metrics().queue_drops);
+                }
+            }
+        }
+    });
+    
+    // 计费：周期性 Interim-Update
+    if let Some(interim_interval) = state.accounting.as_ref().map(|acct| acct.interim_interval) {
+        let state_interim = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interim_interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let records: Vec<AcctRecord> = state_interim.sessions.lock().await
+                    .values()
+                    .filter(|s| s.authenticated)
+                    .map(|s| s.acct_record(None))
+                    .collect();
+                for record in records {
+                    state_interim.report_accounting(AcctStatus::Interim, record);
                 }
+            }
+        });
+    }
+    
+    // 控制通道 Echo：测量每个会话的 RTT，连续无回复的会话视为掉线并清理
+    let state_echo = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(control::KEEPALIVE_INTERVAL);
+        let mut next_id: u32 = 0;
+        loop {
+            ticker.tick().await;
+            let (alive, dead): (Vec<_>, Vec<_>) = {
+                let mut map = state_echo.sessions.lock().await;
+                // 握手后迟迟不确认（ClientFinish）或不认证的会话（PENDING_SESSION_TIMEOUT）
+                let stale: Vec<SocketAddr> = map.iter()
+                    .filter(|(_, s)| !s.authenticated && s.started_at.elapsed() >= PENDING_SESSION_TIMEOUT)
+                    .map(|(addr, _)| *addr)
+                    .collect();
+                for addr in &stale {
+                    // 要接替旧会话的新连接没有确认：旧会话照常保留
+                    if map.remove(addr).is_some_and(|s| !s.confirmed && !s.takeover.is_empty()) {
+                        record_denial(&state_echo, *addr, DenyReason::TakeoverUnconfirmed);
+                        println!("⌛ 来自 {} 的新连接没有证明持有会话密钥（疑似重放的 ClientHello），已丢弃，旧会话保留", addr);
+                    }
+                }
+                if !stale.is_empty() {
+                    println!("⌛ 清理 {} 个超时未确认或未认证的会话", stale.len());
+                }
+                for addr in state_echo.rehellos.lock().unwrap().expire(PENDING_SESSION_TIMEOUT) {
+                    record_denial(&state_echo, addr, DenyReason::TakeoverUnconfirmed);
+                    println!("⌛ 来自 {} 的重新握手没有证明持有会话密钥（疑似重放的 ClientHello），已丢弃，原会话保留", addr);
+                }
+                map.values_mut()
+                    .filter(|s| s.authenticated)
+                    .map(|s| {
+                        let down = s.rtt.health() == LinkHealth::Down;
+                        if !down {
+                            s.rtt.on_echo_sent();
+                        }
+                        (s.peer_addr, s.session_key, down)
+                    })
+                    .partition(|(_, _, down)| !down)
             };
             
-            if n <= TUN_READ_OFFSET {
-                continue;
+            for (addr, _, _) in dead {
+                println!("💀 客户端 {} 连续 {} 次无响应，清理会话", addr, control::MAX_MISSED_ECHOES);
+                remove_session(&state_echo, addr, TerminateCause::LostCarrier).await;
             }
-            
-            let ip_packet = &buf[TUN_READ_OFFSET..n];
-            
-            // 解析目标IP
-            if ip_packet.len() < 20 {
+            for (addr, key, _) in alive {
+                let echo = ControlMessage::Echo { id: next_id, timestamp_us: control::monotonic_micros() };
+                next_id = next_id.wrapping_add(1);
+                send_control(&state_echo, addr, &key, &echo).await;
+            }
+        }
+    });
+    
+    // 周期性打印会话状态（RTT、流量）
+    let state_status = state.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(control::STATUS_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let map = state_status.sessions.lock().await;
+            if map.is_empty() {
                 continue;
             }
-            
-            let dst_ip = Ipv4Addr::new(
-                ip_packet[16],
-                ip_packet[17],
-                ip_packet[18],
-                ip_packet[19],
-            );
-            
-            // 查找目标客户端
-            let target_addr = {
-                let map = peers_tun_to_udp.lock().await;
-                map.get(&dst_ip).cloned()
-            };
-            
-            if let Some(addr) = target_addr {
-                // 获取目标的会话密钥
-                let session_key = {
-                    let map = sessions_tun_to_udp.lock().await;
-                    match map.get(&addr) {
-                        Some(s) => s.session_key,
-                        None => continue,
-                    }
-                };
-                
-                // 加密并发送
-                if let Ok(cipher) = Cipher::new(&session_key) {
-                    if let Ok(encrypted) = cipher.encrypt(ip_packet) {
-                        let _ = socket_tun_to_udp.send_to(&encrypted, addr).await;
-                        println!("🔁 [TUN->客户端] {} ({} 字节)", dst_ip, n);
-                    }
+            println!("📊 当前会话 ({}):", map.len());
+            for s in map.values() {
+                let vip = s.virtual_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string());
+                println!("   {} {} {} [{}] ↑{}B ↓{}B", s.client_id, s.peer_addr, vip, s.rtt.summary(), s.bytes_in, s.bytes_out);
+                if let Some(fec) = &s.fec {
+                    println!("      🧩 FEC 分组 {}，已恢复 {} 个包", fec.group_size, fec.decoder.recovered());
                 }
             }
+            if let Some(tickets) = &state_status.tickets {
+                println!("   🎫 会话恢复票据: {}", tickets.lock().unwrap().len());
+            }
+            if let Some(bonding) = &state_status.bonding {
+                println!("   🔀 多路径别名: {}", bonding.aliases());
+            }
         }
     });
-
-    // UDP 接收循环
-    let mut buf = [0u8; 4096];
-
-    loop {
-        // 2. 接收 UDP 数据
-        let (len, src_addr) = match socket.recv_from(&mut buf).await {
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("接收错误: {}", e);
-                continue;
+    
+    // Ctrl+C 或运行期间的致命错误（任务 panic、转发循环退出）：通过控制通道通知所有客户端断开，
+    // 为所有会话补发计费 Stop，撤销网关配置后退出
+    let state_stop = state.clone();
+    let tun_name_stop = tun_name.clone();
+    tokio::spawn(async move {
+        let code = tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                println!("\n🛑 收到退出信号，通知客户端断开...");
+                0
+            }
+            report = crash::wait() => {
+                eprintln!("{}", report);
+                crash::CRASH_EXIT
             }
         };
-
-        let raw_data = &buf[..len];
-        
-        // 3. 尝试识别是握手消息还是数据包
-        if let Ok(handshake_msg) = deserialize_message(raw_data) {
-            // 这是握手消息
-            handle_handshake(
-                &socket,
-                src_addr,
-                handshake_msg,
-                &sessions,
-                &peers,
-                &server_identity,
-            ).await;
-            continue;
-        }
-        
-        // 4. 否则，这是加密的数据包
-        handle_data_packet(
-            &socket,
-            src_addr,
-            raw_data,
-            &peers,
-            &sessions,
-            &tun_writer,
-        ).await;
-    }
-}
-
-/// 处理握手消息
-async fn handle_handshake(
-    socket: &UdpSocket,
-    client_addr: SocketAddr,
-    msg: HandshakeMessage,
-    sessions: &SessionMap,
-    peers: &PeerMap,
-    server_identity: &ServerIdentity,
-) {
-    match msg {
-        HandshakeMessage::ClientHello { client_pubkey, client_mlkem_pk, client_id, virtual_ip } => {
-            println!("🤝 收到握手请求: {} ({}) IP: {}", client_id, client_addr, virtual_ip);
-            
-            // 创建服务端握手实例
-            let server_handshake = ServerHandshake::new(PSK);
-            
-            // 生成 ServerHello（使用ML-KEM封装，返回密文和共享密钥）
-            let (mut server_hello, mlkem_shared) = match server_handshake.process_client_hello(client_pubkey, &client_mlkem_pk) {
-                Ok(result) => result,
-                Err(e) => {
-                    eprintln!("❌ ML-KEM封装失败: {}", e);
-                    return;
-                }
-            };
+        let cleanup = tokio::spawn(async move {
+            let sessions: Vec<(SocketAddr, [u8; 32], Option<AcctRecord>)> = state_stop.sessions.lock().await
+                .values()
+                .map(|s| (s.peer_addr, s.session_key, s.authenticated.then(|| s.acct_record(Some(TerminateCause::NasRequest)))))
+                .collect();
             
-            // 对握手消息签名：签名内容 = server_pubkey || client_pubkey
-            if let HandshakeMessage::ServerHello { server_pubkey, ref mut signature, .. } = server_hello {
-                let message_to_sign = [
-                    &server_pubkey[..],
-                    &client_pubkey[..],
-                ].concat();
-                
-                *signature = server_identity.sign(&message_to_sign);
-                println!("   ✍️  已对握手消息签名");
+            let disconnect = ControlMessage::Disconnect { reason: "server shutting down".to_string() };
+            for (addr, key, _) in &sessions {
+                send_control(&state_stop, *addr, key, &disconnect).await;
             }
             
-            // 计算会话密钥（混合：X25519 + ML-KEM，消耗 server_handshake）
-            let session_key = match server_handshake.compute_session_key(client_pubkey, &mlkem_shared) {
-                Ok(key) => key,
-                Err(e) => {
-                    eprintln!("❌ 密钥计算失败: {}", e);
-                    return;
+            if let Some(acct) = &state_stop.accounting {
+                println!("   上报会话结束记录...");
+                for record in sessions.iter().filter_map(|(_, _, r)| r.as_ref()) {
+                    let _ = acct.send(AcctStatus::Stop, record).await;
                 }
-            };
-            println!("   🔑 会话密钥协商成功（X25519 + ML-KEM-768）");
-            
-            // 保存会话
-            {
-                let mut map = sessions.lock().await;
-                map.insert(client_addr, Session {
-                    session_key,
-                    peer_addr: client_addr,
-                });
             }
             
-            // 立即建立路由映射（解析虚拟 IP）
-            if let Ok(vip) = virtual_ip.parse::<Ipv4Addr>() {
-                let mut peer_map = peers.lock().await;
-                peer_map.insert(vip, client_addr);
-                println!("   🗺️  路由映射: {} -> {}", vip, client_addr);
+            if let Some(mapper) = &port_mapper {
+                mapper.release().await;
             }
-            
-            // 发送 ServerHello
-            if let Ok(response) = serialize_message(&server_hello) {
-                if let Err(e) = socket.send_to(&response, client_addr).await {
-                    eprintln!("发送 ServerHello 失败: {}", e);
-                } else {
-                    println!("   ✅ 握手完成，会话已建立");
+            if let Some(shaper) = &state_stop.shaper {
+                shaper.teardown();
+            }
+            if let Some(fastpath) = &state_stop.fastpath {
+                fastpath.teardown();
+            }
+            if host_services.is_some() {
+                gateway::cleanup_host_services(&tun_name_stop);
+            }
+            if let Some(external_if) = &nat_interface {
+                gateway::cleanup_egress(&tun_name_stop, &egress_rules);
+                let external_if = external_if.lock().unwrap().clone();
+                let _ = gateway::cleanup_nat(&tun_name_stop, &external_if);
+                if enable_ipv6 {
+                    let _ = gateway::cleanup_nat6(&tun_name_stop, &external_if);
                 }
             }
-        }
-        _ => {
-            // 其他握手消息类型（ClientFinish等）暂不实现
-        }
+        });
+        crash::finish(cleanup, code).await;
+    });
+
+    // 转发核心：TUN -> UDP 发往客户端，UDP -> 握手/控制/数据包处理 -> TUN
+    let datapath = state.datapath.clone();
+    let handler = Arc::new(ServerHandler { state });
+    // 启动完成，此后由上面的退出任务处理清理
+    journal.commit();
+    TunnelEngine::new(Role::Server, handler, datapath, &tuning).run(tun_dev, socket).await;
+    // 转发循环只会因为 TUN 设备或 socket 出错而结束：和 panic 一样交给退出任务
+    crash::fail("转发循环意外退出（TUN 设备或 UDP socket 已关闭）");
+    std::future::pending().await
+}
+
+/// `--check-config`：解析（配置文件展开后的）全部参数但不启动，打印生效的参数
+fn check_config(args: &[String]) -> Result<()> {
+    let listen = arg_value(args, "--listen").unwrap_or_else(|| LISTEN_ADDR.to_string());
+    listen.parse::<SocketAddr>().map_err(|_| anyhow::anyhow!("无效的 --listen: {}", listen))?;
+    for route in arg_values(args, "--push-route") {
+        config::parse_cidr(&route).ok_or_else(|| anyhow::anyhow!("无效的 --push-route: {}", route))?;
     }
+    Tuning::from_args(args)?;
+    DuplicatePolicy::from_args(args)?;
+    Ipam::from_args(args, AddressPool::new(SERVER_TUN_IP, SERVER_TUN_PREFIX))?;
+    FilterConfig::from_args(args)?;
+    PeerAcl::from_args(args)?;
+    ShapingConfig::from_args(args)?;
+    FastPathConfig::from_args(args)?;
+    parse_max_clients(args)?;
+    VersionPolicy::from_args(args)?;
+    PskSet::from_args(args)?;
+    admin::AdminTokens::from_args(args)?;
+    dns::DnsForwarder::from_args(args, false)?;
+    wasm_policies(args)?;
+    println!("✅ 配置有效，生效的参数: {}", args[1..].iter().filter(|a| *a != "--check-config").cloned().collect::<Vec<_>>().join(" "));
+    Ok(())
 }
 
-/// 处理加密数据包
-async fn handle_data_packet(
-    socket: &UdpSocket,
-    src_addr: SocketAddr,
-    encrypted_data: &[u8],
-    peers: &PeerMap,
-    sessions: &SessionMap,
-    tun_writer: &Arc<Mutex<tokio::io::WriteHalf<tun::AsyncDevice>>>,
-) {
-    // 1. 查找会话
-    let session_key = {
-        let map = sessions.lock().await;
-        match map.get(&src_addr) {
-            Some(session) => session.session_key,
-            None => {
-                // 未握手的客户端，静默丢弃
-                return;
-            }
-        }
-    };
-    
-    // 2. 解密
-    let cipher = match Cipher::new(&session_key) {
-        Ok(c) => c,
-        Err(_) => return,
-    };
-    
-    let ip_packet = match cipher.decrypt(encrypted_data) {
-        Ok(data) => data,
-        Err(_) => {
-            // 解密失败，可能是错误的数据
-            return;
-        }
-    };
+/// `--max-clients <n>`：同时在线的客户端上限，未指定时不限制
+fn parse_max_clients(args: &[String]) -> Result<Option<usize>> {
+    arg_value(args, "--max-clients")
+        .map(|v| v.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| anyhow::anyhow!("无效的 --max-clients: {}", v)))
+        .transpose()
+}
 
-    // 3. 解析 IP 头
-    let (src_ip, dst_ip) = match parse_ipv4_header(&ip_packet) {
-        Ok(ips) => ips,
-        Err(_) => return,
-    };
+/// 最低客户端版本：FEATURE_VERSION 更低（或没有上报版本）的客户端收到 UpgradeRequired
+struct VersionPolicy {
+    min_version: u32,
+    /// 随 UpgradeRequired 发给客户端显示的说明（--upgrade-message），未指定时说明版本要求
+    message: Option<String>,
+}
 
-    // 4. 更新路由表
-    {
-        let mut map = peers.lock().await;
-        if map.get(&src_ip) != Some(&src_addr) {
-            println!("🔗 客户端上线/更新: {} -> {}", src_ip, src_addr);
-            map.insert(src_ip, src_addr);
+impl VersionPolicy {
+    /// `--min-client-version <n> [--upgrade-message <文本>]`，未指定时不限制
+    fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let message = arg_value(args, "--upgrade-message");
+        let Some(value) = arg_value(args, "--min-client-version") else {
+            if message.is_some() {
+                return Err(anyhow::anyhow!("--upgrade-message 需要同时指定 --min-client-version"));
+            }
+            return Ok(None);
+        };
+        let min_version = value.parse::<u32>().map_err(|_| anyhow::anyhow!("无效的 --min-client-version: {}", value))?;
+        if min_version > FEATURE_VERSION {
+            return Err(anyhow::anyhow!("--min-client-version {} 高于本版本的功能版本 {}", min_version, FEATURE_VERSION));
         }
+        Ok(Some(Self { min_version, message }))
     }
 
-    // 5. 转发逻辑：优先客户端互联，其次转发到TUN（网关模式）
-    let target_peer = {
-        let map = peers.lock().await;
-        map.get(&dst_ip).cloned()
+    /// 客户端版本不满足要求时返回要发给客户端的说明
+    fn check(&self, client_version: Option<u32>) -> Option<String> {
+        let version = client_version.unwrap_or(0);
+        (version < self.min_version).then(|| match &self.message {
+            Some(message) => message.clone(),
+            None => format!("要求版本 {} 及以上，当前客户端为 {}", self.min_version, version),
+        })
+    }
+}
+
+/// `--dry-run`：按参数列出启动时对系统的修改，不创建设备、不生成密钥、不监听端口
+///
+/// 步骤与 main 中的顺序一致；设备名未指定时由系统分配，这里用 <tun> 代替
+fn dry_run(args: &[String]) -> Result<()> {
+    let mut plan = Plan::new();
+    let tun_name = arg_value(args, "--tun-name").unwrap_or_else(|| "<tun>".to_string());
+    let device_options = local_tun::DeviceOptions {
+        name: arg_value(args, "--tun-name"),
+        reuse_existing: args.contains(&"--tun-reuse".to_string()),
+        offload: args.contains(&"--tun-offload".to_string()),
     };
+    local_tun::plan_device(&mut plan, &SERVER_TUN_IP.to_string(), SERVER_TUN_MASK, &device_options);
+    if let Some(mtu) = Tuning::from_args(args)?.mtu {
+        local_tun::plan_mtu(&mut plan, &tun_name, mtu);
+    }
+    local_tun::plan_route(&mut plan, &tun_name, "10.0.0.0/24", &local_tun::RouteOptions::default());
+    if let Some(spec) = arg_value(args, "--host-services") {
+        gateway::plan_host_services(&mut plan, &tun_name, &gateway::parse_host_services(&spec)?);
+    }
+    let enable_ipv6 = args.contains(&"--ipv6".to_string());
+    if enable_ipv6 {
+        local_tun::plan_ipv6_address(&mut plan, &tun_name, local_tun::tunnel_ipv6(SERVER_TUN_IP));
+    }
 
-    match target_peer {
-        Some(target_addr) => {
-            // 目标是另一个客户端，直接转发
-            let target_session_key = {
-                let map = sessions.lock().await;
-                match map.get(&target_addr) {
-                    Some(s) => s.session_key,
-                    None => return,
-                }
-            };
-            
-            let target_cipher = match Cipher::new(&target_session_key) {
-                Ok(c) => c,
-                Err(_) => return,
-            };
-            
-            match target_cipher.encrypt(&ip_packet) {
-                Ok(new_packet) => {
-                    let _ = socket.send_to(&new_packet, target_addr).await;
-                    println!("🔁 [客户端互联] {} -> {}", src_ip, dst_ip);
-                }
-                Err(e) => eprintln!("加密转发失败: {}", e),
-            }
-        }
-        None => {
-            // 目标不是客户端，尝试转发到TUN（互联网）
-            // 检查目标IP是否是本地VPN网段
-            if dst_ip.octets()[0] == 10 && dst_ip.octets()[1] == 0 && dst_ip.octets()[2] == 0 {
-                // 仍然是10.0.0.x，但客户端不在线，丢弃
-                println!("🚫 丢弃: {} -> {} (目标不在线)", src_ip, dst_ip);
-            } else {
-                // 目标是外网IP，写入TUN设备
-                #[cfg(target_os = "macos")]
-                let data_to_write = {
-                    let mut out = Vec::with_capacity(4 + ip_packet.len());
-                    out.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]);
-                    out.extend_from_slice(&ip_packet);
-                    out
-                };
-                
-                #[cfg(target_os = "linux")]
-                let data_to_write = ip_packet.clone();
-                
-                let mut writer = tun_writer.lock().await;
-                if let Err(e) = writer.write_all(&data_to_write).await {
-                    eprintln!("TUN 写入失败: {}", e);
-                } else {
-                    println!("🌐 [转发到互联网] {} -> {}", src_ip, dst_ip);
+    if args.contains(&"--gateway".to_string()) {
+        let egress_rules = arg_values(args, "--egress-client").iter()
+            .map(|spec| gateway::EgressRule::parse(spec, false))
+            .chain(arg_values(args, "--egress-dest").iter().map(|spec| gateway::EgressRule::parse(spec, true)))
+            .collect::<Result<Vec<_>>>()?;
+        // 只读查询，不修改系统
+        match gateway::detect_default_interface() {
+            Ok(external_if) => {
+                gateway::plan_gateway(&mut plan, &tun_name, &external_if, enable_ipv6);
+                if !egress_rules.is_empty() {
+                    gateway::plan_egress(&mut plan, &tun_name, &egress_rules);
                 }
+                plan.note(Category::Firewall, format!("外网接口变化时把 NAT 规则从 {} 迁移到新接口", external_if));
             }
+            Err(e) => plan.note(Category::Firewall, format!("无法检测外网接口（{}），启动时会失败", e)),
         }
     }
-}
 
-/// 简单的 IPv4 头解析器
-/// 只需要提取 Source IP (Byte 12-15) 和 Dest IP (Byte 16-19)
-fn parse_ipv4_header(data: &[u8]) -> Result<(Ipv4Addr, Ipv4Addr), &'static str> {
-    // IPv4 头最小 20 字节
-    if data.len() < 20 {
-        return Err("数据包太短");
+    if let Some(config) = ShapingConfig::from_args(args)? {
+        TrafficShaper::new(&tun_name, config).plan(&mut plan);
     }
-
-    // 检查版本号 (Byte 0 的高 4 位)
-    if data[0] >> 4 != 4 {
-        return Err("不是 IPv4 包");
+    let listen: SocketAddr = arg_value(args, "--listen").as_deref().unwrap_or(LISTEN_ADDR).parse()?;
+    if let Some(config) = FastPathConfig::from_args(args)? {
+        FastPath::new(config, listen.port()).plan(&mut plan);
+    }
+    if let Some(mapper) = portmap::PortMapper::from_args(args, listen.port())? {
+        mapper.plan(&mut plan);
+    }
+    plan.print();
+    let issues = preflight_checks(args).check();
+    if !issues.is_empty() {
+        println!();
+        preflight::print_issues(&issues);
+    }
+    Ok(())
+}
+
+/// 按参数确定启动需要的权限和外部命令
+fn preflight_checks(args: &[String]) -> Preflight {
+    let has = |flag: &str| args.iter().any(|a| a == flag);
+    let mut checks = Preflight::new(has("--tun-reuse")).require("ip", "配置地址和路由");
+    if has("--gateway") || has("--host-services") {
+        checks = checks.require("iptables", "NAT 和防火墙规则");
+    }
+    if has("--gateway") {
+        checks = checks.recommend("conntrack", "客户端断开后清理连接跟踪条目");
+        if has("--ipv6") {
+            checks = checks.require("ip6tables", "IPv6 NAT").require("sysctl", "开启 IPv6 转发");
+        }
+    }
+    if has("--host-services") {
+        checks = checks.recommend("ip6tables", "IPv6 本机服务白名单");
+    }
+    if has("--tc-rate") {
+        checks = checks.require("tc", "下行流量整形");
+    }
+    if arg_value(args, "--xdp").is_some() {
+        checks = checks.require("clang", "编译 XDP 程序").require("bpftool", "加载 XDP 程序和更新会话表");
+    }
+    if arg_value(args, "--port-map").is_some_and(|m| m != "natpmp") {
+        checks = checks.recommend("upnpc", "UPnP 端口映射");
+    }
+    checks
+}
+
+/// 服务端的转发逻辑：按虚拟 IP 查找客户端，处理握手、认证和隧道数据
+struct ServerHandler {
+    state: Arc<ServerState>,
+}
+
+impl PacketHandler for ServerHandler {
+    async fn on_tun_packet(&self, ip_packet: &[u8]) {
+        forward_tun_packet(&self.state, ip_packet).await;
+    }
+
+    async fn on_datagram(&self, data: &[u8], src_addr: SocketAddr) -> Option<Vec<u8>> {
+        let state = &self.state;
+        // 按数据报头分派：加密数据包、握手消息，其他报文直接丢弃
+        match wire::classify(data) {
+            Datagram::Data(body) => return handle_data_packet(state, src_addr, body).await,
+            Datagram::Handshake => {}
+            Datagram::Stun | Datagram::Invalid(_) => {
+                record_denial(state, src_addr, DenyReason::BadPacket);
+                return None;
+            }
+        }
+
+        let handshake_msg = match deserialize_message(data) {
+            Ok(msg) => msg,
+            Err(_) => {
+                // 版本不兼容的握手消息：回复 HandshakeError，客户端能提示升级
+                match handshake_version(data) {
+                    Some(version) if version != WIRE_VERSION => reject_bad_version(state, src_addr, version, data.len()).await,
+                    _ => record_drop(state, "malformed_handshake"),
+                }
+                return None;
+            }
+        };
+        // 认证可能需要访问外部系统（失败时还要等待固定延迟），放到独立任务中，避免阻塞接收循环
+        if let HandshakeMessage::ClientAuth { encrypted_credential } = handshake_msg {
+            let Ok(permit) = state.auth_tasks.clone().try_acquire_owned() else {
+                record_overflow(state, "auth_queue_full");
+                return None;
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                handle_client_auth(state, src_addr, encrypted_credential).await;
+                drop(permit);
+            });
+            return None;
+        }
+        
+        // ClientHello 的密钥运算较重：同样放到独立任务中，握手较多时接收循环照常转发数据
+        if let HandshakeMessage::ClientHello { .. } = handshake_msg {
+            let Ok(permit) = state.handshake_tasks.clone().try_acquire_owned() else {
+                record_overflow(state, "handshake_queue_full");
+                return None;
+            };
+            let state = state.clone();
+            let received = Instant::now();
+            tokio::spawn(async move {
+                handle_handshake(&state, src_addr, handshake_msg, received).await;
+                drop(permit);
+            });
+            return None;
+        }
+        
+        handle_handshake(state, src_addr, handshake_msg, Instant::now()).await;
+        None
+    }
+
+    async fn flush(&self) -> Vec<Vec<u8>> {
+        let state = &self.state;
+        let Some(bonding) = &state.bonding else { return Vec::new() };
+        let mut packets = Vec::new();
+        for (addr, ip_packet) in bonding.take_ready() {
+            let Some(keys) = state.sessions.lock().await.get(&addr).map(|s| s.keys.clone()) else { continue };
+            if let Some(ip_packet) = forward_client_packet(state, addr, &keys, ip_packet).await {
+                packets.push(ip_packet);
+            }
+        }
+        packets
+    }
+
+    fn flush_interval(&self) -> Option<Duration> {
+        self.state.bonding.as_ref().map(Bonding::flush_interval)
+    }
+
+    async fn end_tun_batch(&self) {
+        self.state.socket.flush().await;
+    }
+
+    fn queue_depths(&self) -> Vec<(&'static str, usize)> {
+        let state = &self.state;
+        vec![
+            ("auth", MAX_AUTH_TASKS - state.auth_tasks.available_permits()),
+            ("handshake", MAX_HANDSHAKE_TASKS - state.handshake_tasks.available_permits()),
+            ("accounting", MAX_ACCOUNTING_TASKS - state.accounting_tasks.available_permits()),
+        ]
+    }
+}
+
+/// 处理握手消息（received 为收到消息的时间，用于统计握手延迟）
+async fn handle_handshake(state: &ServerState, client_addr: SocketAddr, msg: HandshakeMessage, received: Instant) {
+    let telemetry = &state.telemetry;
+    
+    // 来源地址验证：没有带上有效 cookie 的 ClientHello 只回复一个很小的 Cookie 消息，
+    // 伪造来源地址的请求既不能借服务端放大流量，也不会触发 ML-KEM 运算
+    if let HandshakeMessage::ClientHello { client_pubkey, cookie, .. } = &msg
+        && !state.cookies.verify(client_addr, client_pubkey, cookie)
+    {
+        let reply = HandshakeMessage::Cookie { cookie: state.cookies.issue(client_addr, client_pubkey) };
+        if let Ok(data) = serialize_message(&reply) {
+            let _ = state.socket.send_to(&data, client_addr).await;
+        }
+        return;
+    }
+    
+    // 客户端身份：签名无效的请求不做任何密钥运算
+    if let HandshakeMessage::ClientHello { client_pubkey, .. } = &msg
+        && verify_client_identity(&msg).is_err()
+    {
+        reject_hello(state, client_addr, DenyReason::BadIdentity, client_pubkey, String::new()).await;
+        return;
+    }
+    
+    match msg {
+        HandshakeMessage::Resume { ticket, proof, fec } => {
+            handle_resume(state, client_addr, ticket, &proof, fec).await;
+        }
+        HandshakeMessage::PathJoin { path_id, proof } => {
+            handle_path_join(state, client_addr, path_id, &proof).await;
+        }
+        client_hello @ HandshakeMessage::ClientHello { .. } => {
+            // ServerHello 的签名覆盖整个 ClientHello（见 handshake::Transcript），client_hello 本身交给密钥运算
+            let HandshakeMessage::ClientHello { client_pubkey, client_id, virtual_ip, identity_key, fec: requested_fec, capabilities: client_capabilities, feature_version, psk_id, .. } = client_hello.clone() else { unreachable!() };
+            println!("🤝 收到握手请求: {} ({}) IP: {} 公钥: {}", client_id, client_addr, virtual_ip, key_fingerprint(&identity_key));
+            
+            if let Some(detail) = state.version_policy.as_ref().and_then(|policy| policy.check(feature_version)) {
+                eprintln!("🚫 拒绝客户端 {} ({}): 客户端版本 {} 低于 --min-client-version", client_id, client_addr, feature_version.unwrap_or(0));
+                reject_hello(state, client_addr, DenyReason::OutdatedClient, &client_pubkey, detail).await;
+                return;
+            }
+            let Some(selected_psk) = state.psks.select(psk_id, SystemTime::now()) else {
+                eprintln!("🚫 拒绝客户端 {} ({}): PSK {} 不是当前的 PSK，也不在宽限期内", client_id, client_addr, psk_id.map(hex::encode).unwrap_or_default());
+                reject_hello(state, client_addr, DenyReason::UnknownPsk, &client_pubkey, "PSK 未知或已过宽限期，请更新客户端的 --psk-file".to_string()).await;
+                return;
+            };
+            if selected_psk.previous {
+                println!("   🔁 客户端使用旧 PSK（宽限期内）");
+            }
+            
+            let vip = if virtual_ip == AUTO_VIRTUAL_IP {
+                // 避开其他身份的会话（包括还在等待确认的）正在使用的地址
+                let in_use: HashSet<Ipv4Addr> = state.sessions.lock().await.values()
+                    .filter(|s| s.client_id != client_id)
+                    .filter_map(|s| s.virtual_ip)
+                    .collect();
+                let Some(ip) = state.ipam.allocate(&client_id, &identity_key, &in_use) else {
+                    eprintln!("🚫 拒绝客户端 {} ({}): 没有可自动分配的虚拟 IP（--ipam {}）", client_id, client_addr, state.ipam.name());
+                    reject_hello(state, client_addr, DenyReason::NoFreeAddress, &client_pubkey, "没有可自动分配的虚拟 IP".to_string()).await;
+                    return;
+                };
+                println!("   🏷️  分配虚拟 IP {}（{}）", ip, state.ipam.name());
+                Some(ip)
+            } else {
+                virtual_ip.parse::<Ipv4Addr>().ok()
+            };
+            let assigned_ip = vip.filter(|_| virtual_ip == AUTO_VIRTUAL_IP);
+            if let Err(reason) = state.clients.check(&client_id, &identity_key, vip) {
+                eprintln!("🚫 拒绝客户端 {} ({}): {}", client_id, client_addr, reason);
+                reject_hello(state, client_addr, reason, &client_pubkey, String::new()).await;
+                return;
+            }
+            
+            // 其他身份已认证的会话：虚拟 IP 不能被两个身份同时使用；--max-clients 限制同时在线的客户端数
+            // （等待确认或认证的会话不占用虚拟 IP，否则任何人都能先发起握手占住别人的地址，它们由 MAX_PENDING_SESSIONS 限制）
+            let (others, pending) = {
+                let sessions = state.sessions.lock().await;
+                let others: Vec<Option<Ipv4Addr>> = sessions.iter()
+                    .filter(|(addr, s)| s.authenticated && **addr != client_addr && s.client_id != client_id)
+                    .map(|(_, s)| s.virtual_ip)
+                    .collect();
+                (others, sessions.values().filter(|s| !s.authenticated).count() + state.rehellos.lock().unwrap().count())
+            };
+            if let Some(ip) = vip.filter(|_| others.contains(&vip)) {
+                eprintln!("🚫 拒绝客户端 {} ({}): 虚拟 IP {} 已被其他客户端使用", client_id, client_addr, ip);
+                reject_hello(state, client_addr, DenyReason::VirtualIpInUse, &client_pubkey, format!("{} 已被占用", ip)).await;
+                return;
+            }
+            if let Some(max) = state.max_clients
+                && others.len() >= max
+            {
+                eprintln!("🚫 拒绝客户端 {} ({}): 已达到 --max-clients {}", client_id, client_addr, max);
+                reject_hello(state, client_addr, DenyReason::ServerFull, &client_pubkey, format!("上限 {} 个客户端", max)).await;
+                return;
+            }
+            // 等待确认或认证的会话过多（认证后端变慢，或有人大量发起握手却不完成）
+            if pending >= MAX_PENDING_SESSIONS {
+                Metrics::incr(&telemetry.metrics().queue_drops);
+                reject_hello(state, client_addr, DenyReason::Overloaded, &client_pubkey, "等待认证的连接过多，请稍后重试".to_string()).await;
+                return;
+            }
+            
+            // 同一身份在其他地址上已有会话（--duplicate-policy）
+            let existing: Vec<(SocketAddr, Option<Ipv4Addr>)> = state.sessions.lock().await.iter()
+                .filter(|(addr, s)| **addr != client_addr && s.client_id == client_id)
+                .map(|(addr, s)| (*addr, s.virtual_ip))
+                .collect();
+            let takeover = match state.duplicate_policy.decide(vip, &existing) {
+                DuplicateDecision::Accept { replace } => replace,
+                DuplicateDecision::Reject => {
+                    eprintln!("🚫 客户端 {} 已在 {:?} 连接，拒绝来自 {} 的新连接", client_id, existing.iter().map(|(a, _)| a).collect::<Vec<_>>(), client_addr);
+                    let detail = format!("已有 {} 个连接（--duplicate-policy reject）", existing.len());
+                    reject_hello(state, client_addr, DenyReason::DuplicateIdentity, &client_pubkey, detail).await;
+                    return;
+                }
+            };
+            
+            Metrics::incr(&telemetry.metrics().handshakes_started);
+            let mut span = telemetry.start_span("handshake");
+            span.set_attribute("client_id", &client_id);
+            span.set_attribute("client_addr", client_addr);
+            span.set_attribute("virtual_ip", vip.map(|ip| ip.to_string()).unwrap_or(virtual_ip));
+            
+            // ML-KEM 封装、签名和会话密钥派生都是纯 CPU 运算（PKCS#11 签名还要启动外部进程），
+            // 合在一起放到阻塞线程池里执行，不占用异步 worker
+            let fec_group = fec::negotiate(requested_fec, state.fec_enabled);
+            // 功能标志只回复声明了功能标志的客户端，旧客户端收到的 ServerHello 不变
+            let reply = HelloReply { assigned_ip, fec: fec_group, capabilities: client_capabilities.map(|_| state.capabilities) };
+            let identity = state.identity.clone();
+            let job = tokio::task::spawn_blocking(move || {
+                let result = server_key_exchange(&mut span, &identity, &selected_psk.psk, &client_hello, client_addr, reply);
+                (span, result)
+            });
+            let (mut span, (server_hello, session_key)) = match job.await {
+                Ok((span, Ok(result))) => (span, result),
+                Ok((_, Err(reason))) => {
+                    Metrics::incr(&telemetry.metrics().handshakes_failed);
+                    reject_hello(state, client_addr, reason, &client_pubkey, String::new()).await;
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("❌ 握手任务异常: {}", e);
+                    Metrics::incr(&telemetry.metrics().handshakes_failed);
+                    record_denial(state, client_addr, DenyReason::ServerError);
+                    return;
+                }
+            };
+            println!("   ✍️  已对握手转录签名");
+            println!("   🔑 会话密钥协商成功（X25519 + ML-KEM-768）");
+            keylog::record(KeyEvent::Handshake, client_addr, &session_key);
+            let Ok(keys) = server_packet_keys(&session_key) else { return };
+            
+            let takeover_pending = !takeover.is_empty();
+            // 保存会话：收到 ClientFinish（启用外部认证时还要通过 ClientAuth）之前不可用
+            let session = Session {
+                session_key,
+                keys,
+                previous_keys: None,
+                info_sent: false,
+                send_seq: 0,
+                replay: ReplayWindow::new(),
+                rtt: RttEstimator::new(),
+                peer_addr: client_addr,
+                virtual_ip: vip,
+                authenticated: false,
+                confirmed: false,
+                identity: None,
+                client_id,
+                identity_key,
+                hostname: None,
+                metadata: None,
+                capabilities: client_capabilities.map(|c| c.intersect(state.capabilities)),
+                previous_psk: selected_psk.previous,
+                recent_peers: RecentPeers::default(),
+                services: Vec::new(),
+                ticket: None,
+                fec: fec_group.map(Fec::new),
+                takeover,
+                session_id: hex::encode(rand::random::<[u8; 8]>()),
+                started_at: Instant::now(),
+                bytes_in: 0,
+                bytes_out: 0,
+                packets_in: 0,
+                packets_out: 0,
+            };
+            // 同一地址上已有确认的会话：新会话证明持有密钥（ClientFinish）之前不动它，
+            // 否则重放或伪造来源地址的 ClientHello 就能断开正在使用的隧道
+            let staged = {
+                let mut sessions = state.sessions.lock().await;
+                if sessions.get(&client_addr).is_some_and(|s| s.confirmed) {
+                    Some(session)
+                } else {
+                    retire_replaced(state, client_addr, sessions.insert(client_addr, session));
+                    None
+                }
+            };
+            if let Some(session) = staged {
+                state.rehellos.lock().unwrap().stage(client_addr, session, session_key);
+                println!("   ⏳ 该地址已有确认的会话，收到 ClientFinish 后替换");
+            }
+            if takeover_pending {
+                println!("   ⏳ 同一身份已在其他地址连接，收到 ClientFinish 后接替旧会话");
+            }
+            
+            // 发送 ServerHello
+            let mut phase = span.child("send_server_hello");
+            if let Ok(response) = serialize_message(&server_hello) {
+                if let Err(e) = state.socket.send_to(&response, client_addr).await {
+                    eprintln!("发送 ServerHello 失败: {}", e);
+                    phase.set_error(&e);
+                    span.set_error("send_server_hello failed");
+                    Metrics::incr(&telemetry.metrics().handshakes_failed);
+                } else {
+                    println!("   ⏳ 密钥协商完成，等待客户端确认（ClientFinish）");
+                    Metrics::incr(&telemetry.metrics().handshakes_completed);
+                    telemetry.metrics().handshake_latency.record(received.elapsed());
+                }
+            }
+        }
+        HandshakeMessage::ClientFinish { encrypted_confirm } => {
+            handle_client_finish(state, client_addr, &encrypted_confirm).await;
+        }
+        _ => {
+            // 其他握手消息类型（ServerHello 等服务端发出的消息）
+            record_denial(state, client_addr, DenyReason::UnexpectedHandshake);
+        }
+    }
+}
+
+/// 处理 ClientFinish：客户端证明持有会话密钥后会话才可用
+///
+/// 验证通过后回复带确认值的 ServerFinish（服务端同样证明持有会话密钥），接替同一身份的旧会话；
+/// 未启用外部认证时会话就此建立：上报计费 Start、建立虚拟 IP 的路由映射。
+/// ServerFinish 丢失时客户端会重发 ClientFinish，已确认的会话只重发 ServerFinish
+async fn handle_client_finish(state: &ServerState, client_addr: SocketAddr, encrypted_confirm: &[u8]) {
+    // 同一地址上暂存的重新握手：用它的密钥验证通过后才替换已确认的旧会话，之后按新会话继续确认
+    let rehello = state.rehellos.lock().unwrap().confirm(client_addr, encrypted_confirm);
+    if let Some(session) = rehello {
+        println!("   🔁 {} 的重新握手已确认，替换旧会话", client_addr);
+        let replaced = state.sessions.lock().await.insert(client_addr, session);
+        retire_replaced(state, client_addr, replaced);
+    }
+    let Some((session_key, confirmed)) = state.sessions.lock().await.get(&client_addr).map(|s| (s.session_key, s.confirmed)) else {
+        record_denial(state, client_addr, DenyReason::UnknownSession);
+        return;
+    };
+    if verify_client_finish(encrypted_confirm, &session_key).is_err() {
+        eprintln!("🚫 ClientFinish 验证失败: {}", client_addr);
+        if !confirmed {
+            state.sessions.lock().await.remove(&client_addr);
+        }
+        record_denial(state, client_addr, DenyReason::BadFinish);
+        send_server_finish(state, client_addr, false).await;
+        return;
+    }
+    
+    if !confirmed {
+        let require_auth = state.auth.is_some();
+        let session = state.sessions.lock().await.get_mut(&client_addr).map(|s| {
+            s.confirmed = true;
+            s.authenticated = !require_auth;
+            (std::mem::take(&mut s.takeover), s.virtual_ip, s.acct_record(None))
+        });
+        let Some((takeover, vip, record)) = session else { return };
+        for old_addr in takeover {
+            replace_session(state, old_addr, client_addr).await;
+        }
+        if require_auth {
+            println!("   ⏳ {} 已确认会话密钥，等待客户端认证", client_addr);
+        } else {
+            println!("   ✅ {} 已确认会话密钥，会话已建立", client_addr);
+            state.report_accounting(AcctStatus::Start, record);
+            if let Some(vip) = vip {
+                map_virtual_ip(state, vip, client_addr).await;
+            }
+        }
+    }
+    
+    match server_finish(&session_key).and_then(|msg| serialize_message(&msg)) {
+        Ok(data) => { let _ = state.socket.send_to(&data, client_addr).await; }
+        Err(e) => eprintln!("❌ 生成 ServerFinish 失败: {}", e),
+    }
+}
+
+/// 同一地址上被新会话替换的旧会话：作废它的恢复票据，已认证的上报计费 Stop
+fn retire_replaced(state: &ServerState, client_addr: SocketAddr, replaced: Option<Session>) {
+    let Some(old) = replaced else { return };
+    if let (Some(tickets), Some(id)) = (&state.tickets, &old.ticket) {
+        tickets.lock().unwrap().revoke(id, client_addr);
+    }
+    if old.authenticated {
+        state.report_accounting(AcctStatus::Stop, old.acct_record(Some(TerminateCause::LostCarrier)));
+    }
+}
+
+/// ServerHello 中由握手流程决定的字段（签名前填入，见 server_key_exchange）
+struct HelloReply {
+    assigned_ip: Option<Ipv4Addr>,
+    fec: Option<u8>,
+    capabilities: Option<Capabilities>,
+}
+
+/// 握手中的密钥运算（在阻塞线程里执行）：ML-KEM 封装、对握手转录签名、派生会话密钥
+///
+/// 返回填好签名的 ServerHello 与会话密钥；失败时已打印原因并记录到 span，返回拒绝原因：
+/// ML-KEM 封装失败是 ClientHello 的问题（KeyExchangeFailed，回复 HandshakeError），其余是服务端自身的问题
+fn server_key_exchange(
+    span: &mut Span,
+    identity: &ServerIdentity,
+    psk: &[u8; 32],
+    client_hello: &HandshakeMessage,
+    client_addr: SocketAddr,
+    reply: HelloReply,
+) -> Result<(HandshakeMessage, [u8; 32]), DenyReason> {
+    let HandshakeMessage::ClientHello { client_pubkey, .. } = *client_hello else { return Err(DenyReason::UnexpectedHandshake) };
+    // 创建服务端握手实例（psk 为按 ClientHello 的 PSK ID 选中的 PSK）
+    let mut server_handshake = ServerHandshake::new(psk);
+    
+    // 生成 ServerHello（使用ML-KEM封装，返回密文和共享密钥；ClientHello 计入握手转录）
+    let mut phase = span.child("mlkem_encapsulate");
+    let (mut server_hello, mlkem_shared) = match server_handshake.process_client_hello(client_hello, client_addr) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("❌ ML-KEM封装失败: {}", e);
+            phase.set_error(&e);
+            span.set_error("mlkem_encapsulate failed");
+            return Err(DenyReason::KeyExchangeFailed);
+        }
+    };
+    phase.end();
+    
+    // 填好其余字段后对握手转录签名：签名覆盖 ClientHello 和 ServerHello 的全部字段
+    let mut phase = span.child("sign");
+    if let HandshakeMessage::ServerHello { assigned_ip, fec, capabilities, .. } = &mut server_hello {
+        *assigned_ip = reply.assigned_ip;
+        *fec = reply.fec;
+        *capabilities = reply.capabilities;
+    }
+    if let Err(e) = server_handshake.sign_server_hello(&mut server_hello, identity) {
+        eprintln!("❌ 握手消息签名失败: {}", e);
+        phase.set_error(&e);
+        span.set_error("sign failed");
+        return Err(DenyReason::ServerError);
+    }
+    phase.end();
+    
+    // 计算会话密钥（混合：X25519 + ML-KEM，消耗 server_handshake）
+    let mut phase = span.child("derive_session_key");
+    match server_handshake.compute_session_key(client_pubkey, &mlkem_shared) {
+        Ok(key) => {
+            phase.end();
+            Ok((server_hello, key))
+        }
+        Err(e) => {
+            eprintln!("❌ 密钥计算失败: {}", e);
+            phase.set_error(&e);
+            span.set_error("derive_session_key failed");
+            Err(DenyReason::ServerError)
+        }
+    }
+}
+
+/// 处理 PathJoin：把第二条链路的来源地址登记为已有会话的别名，回复 PathAck
+async fn handle_path_join(state: &ServerState, client_addr: SocketAddr, path_id: multipath::PathId, proof: &[u8]) {
+    let Some(bonding) = &state.bonding else {
+        record_denial(state, client_addr, DenyReason::PathJoinRejected);
+        return;
+    };
+    let session = state.sessions.lock().await.iter()
+        .find(|(_, s)| s.authenticated && multipath::path_id(&s.session_key) == path_id)
+        .map(|(addr, s)| (*addr, s.session_key, s.client_id.clone()));
+    let Some((primary, session_key, client_id)) = session.filter(|(addr, _, _)| *addr != client_addr) else {
+        record_denial(state, client_addr, DenyReason::PathJoinRejected);
+        return;
+    };
+    if let Err(e) = multipath::verify_join_proof(&session_key, proof) {
+        eprintln!("🚫 拒绝第二条链路 {} ({}): {}", client_addr, client_id, e);
+        record_denial(state, client_addr, DenyReason::PathJoinRejected);
+        return;
+    }
+
+    if bonding.join(client_addr, primary) {
+        println!("🔀 客户端 {} 的第二条链路已加入: {} (主链路 {})", client_id, client_addr, primary);
+    }
+    // 客户端在收到确认之前只用主链路发送，之后周期性重发 PathJoin 作为第二条链路的保活
+    if let Ok(ack) = multipath::join_ack(&session_key).map(|proof| HandshakeMessage::PathAck { proof })
+        && let Ok(data) = serialize_message(&ack)
+    {
+        let _ = state.socket.send_to(&data, client_addr).await;
+    }
+}
+
+/// 处理 Resume：凭票据把会话恢复到新的源地址，不需要重新握手和认证
+///
+/// 被拒绝时回复 ServerFinish { success: false }，客户端据此回退到完整握手
+async fn handle_resume(state: &ServerState, client_addr: SocketAddr, ticket_id: TicketId, proof: &[u8], requested_fec: Option<u8>) {
+    let redeemed = match &state.tickets {
+        Some(tickets) => tickets.lock().unwrap().redeem(&ticket_id, proof, client_addr, resume::unix_now()),
+        None => Err(anyhow::anyhow!("未启用 --session-resume")),
+    };
+    let ticket = match redeemed {
+        Ok(ticket) => ticket,
+        Err(e) => {
+            eprintln!("🚫 拒绝会话恢复: {} ({})", client_addr, e);
+            record_denial(state, client_addr, DenyReason::ResumeRejected);
+            send_server_finish(state, client_addr, false).await;
+            return;
+        }
+    };
+    
+    // 客户端的身份可能在此期间被撤销或改绑了虚拟 IP
+    if let Err(reason) = state.clients.check(&ticket.client_id, &ticket.identity_key, ticket.virtual_ip) {
+        eprintln!("🚫 拒绝会话恢复 {} ({}): {}", ticket.client_id, client_addr, reason);
+        if let Some(tickets) = &state.tickets {
+            tickets.lock().unwrap().revoke(&ticket_id, client_addr);
+        }
+        record_denial(state, client_addr, reason);
+        send_server_finish(state, client_addr, false).await;
+        return;
+    }
+    
+    let fec_group = fec::negotiate(requested_fec, state.fec_enabled);
+    let ack = match resume::resume_ack(&ticket.session_key, &ticket_id).map(|proof| HandshakeMessage::ResumeAck { proof, fec: fec_group }) {
+        Ok(ack) => ack,
+        Err(_) => return,
+    };
+    keylog::record(KeyEvent::Resume, client_addr, &ticket.session_key);
+    let Ok(keys) = server_packet_keys(&ticket.session_key) else { return };
+    
+    let session = Session {
+        session_key: ticket.session_key,
+        keys,
+        previous_keys: None,
+        info_sent: false,
+        send_seq: 0,
+        replay: ReplayWindow::new(),
+        rtt: RttEstimator::new(),
+        peer_addr: client_addr,
+        virtual_ip: ticket.virtual_ip,
+        authenticated: true,
+        confirmed: true,
+        identity: ticket.identity.clone(),
+        client_id: ticket.client_id.clone(),
+        identity_key: ticket.identity_key,
+        hostname: None,
+        metadata: None,
+        capabilities: ticket.capabilities,
+        previous_psk: false,
+        recent_peers: RecentPeers::default(),
+        services: Vec::new(),
+        ticket: Some(ticket_id),
+        fec: fec_group.map(Fec::new),
+        takeover: Vec::new(),
+        session_id: hex::encode(rand::random::<[u8; 8]>()),
+        started_at: Instant::now(),
+        bytes_in: 0,
+        bytes_out: 0,
+        packets_in: 0,
+        packets_out: 0,
+    };
+    let start_record = session.acct_record(None);
+    
+    // 客户端重启前的会话可能还在（服务端要等 Echo 超时才发现）：直接接替，
+    // 不清理 conntrack，虚拟 IP 上经 NAT 的连接在恢复后继续可用
+    let replaced = {
+        let mut map = state.sessions.lock().await;
+        let old = map.values().find(|s| s.ticket == Some(ticket_id)).map(|s| s.peer_addr);
+        let old = old.and_then(|addr| map.remove(&addr));
+        let same_addr = map.insert(client_addr, session);
+        old.or(same_addr)
+    };
+    if let Some(old) = replaced.filter(|s| s.authenticated) {
+        state.report_accounting(AcctStatus::Stop, old.acct_record(Some(TerminateCause::LostCarrier)));
+    }
+    state.report_accounting(AcctStatus::Start, start_record);
+    if let Some(vip) = ticket.virtual_ip {
+        state.peers.lock().await.insert(vip, client_addr);
+        notify_peer_status(state, vip, true).await;
+    }
+    
+    if let Ok(data) = serialize_message(&ack) {
+        let _ = state.socket.send_to(&data, client_addr).await;
+    }
+    println!("🎫 会话已恢复: {} ({}) -> {}", ticket.client_id, ticket.identity.as_deref().unwrap_or("-"), client_addr);
+}
+
+/// 处理 ClientAuth：解密凭据，交给认证后端校验，通过后才建立路由映射
+async fn handle_client_auth(state: Arc<ServerState>, client_addr: SocketAddr, encrypted_credential: Vec<u8>) {
+    let received_at = Instant::now();
+    let Some(auth_config) = state.auth.clone() else {
+        reject_client_auth(&state, client_addr, DenyReason::AuthNotEnabled, received_at).await;
+        return;
+    };
+    
+    // 认证在 ClientFinish 确认会话密钥之后进行
+    let session = state.sessions.lock().await.get(&client_addr).filter(|s| s.confirmed).map(|s| (s.session_key, s.virtual_ip));
+    let Some((session_key, virtual_ip)) = session else {
+        reject_client_auth(&state, client_addr, DenyReason::AuthWithoutSession, received_at).await;
+        return;
+    };
+    
+    let credential = match AuthCredential::open(&encrypted_credential, &session_key) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("🚫 认证凭据无法解密: {} ({})", client_addr, e);
+            state.sessions.lock().await.remove(&client_addr);
+            reject_client_auth(&state, client_addr, DenyReason::BadCredential, received_at).await;
+            return;
+        }
+    };
+    
+    let result = async {
+        let identity = auth_config.backend.authenticate(&credential).await?;
+        let vip = virtual_ip.ok_or_else(|| anyhow::anyhow!("客户端未声明合法的虚拟 IP"))?;
+        auth_config.ip_map.check(&identity.subject, vip)?;
+        Ok::<_, anyhow::Error>((identity, vip))
+    }.await;
+    
+    match result {
+        Ok((identity, vip)) => {
+            println!("🪪 认证通过: {} ({}) 身份: {}", client_addr, vip, identity.subject);
+            
+            if let Some(session) = state.sessions.lock().await.get_mut(&client_addr) {
+                session.authenticated = true;
+                session.identity = Some(identity.subject);
+                state.report_accounting(AcctStatus::Start, session.acct_record(None));
+            }
+            map_virtual_ip(&state, vip, client_addr).await;
+            send_server_finish(&state, client_addr, true).await;
+        }
+        Err(e) => {
+            eprintln!("🚫 认证失败: {} ({})", client_addr, e);
+            state.sessions.lock().await.remove(&client_addr);
+            reject_client_auth(&state, client_addr, DenyReason::AuthRejected, received_at).await;
+        }
+    }
+}
+
+/// 拒绝 ClientAuth
+///
+/// 无论是未握手、凭据无法解密还是后端拒绝，都在收到请求 AUTH_FAILURE_DELAY 后回复同一个
+/// ServerFinish { success: false }，外部无法从响应内容或时间区分失败原因；
+/// 具体原因只记入服务端日志和拒绝统计
+async fn reject_client_auth(state: &ServerState, client_addr: SocketAddr, reason: DenyReason, received_at: Instant) {
+    record_denial(state, client_addr, reason);
+    tokio::time::sleep_until((received_at + AUTH_FAILURE_DELAY).into()).await;
+    send_server_finish(state, client_addr, false).await;
+}
+
+/// 发送认证结果
+async fn send_server_finish(state: &ServerState, client_addr: SocketAddr, success: bool) {
+    let finish = HandshakeMessage::ServerFinish { success, encrypted_confirm: Vec::new() };
+    if let Ok(data) = serialize_message(&finish) {
+        let _ = state.socket.send_to(&data, client_addr).await;
+    }
+}
+
+/// 拒绝 ClientHello：记录原因；可以告诉客户端的原因（未启用 --stealth 时）回复签名的 HandshakeError
+///
+/// 走到这里的 ClientHello 已经通过 cookie 验证了来源地址，签名覆盖本次握手的 client_pubkey，
+/// 客户端据此确认错误来自服务端，而不是伪造的响应
+async fn reject_hello(state: &ServerState, client_addr: SocketAddr, reason: DenyReason, client_pubkey: &[u8; 32], detail: String) {
+    record_denial(state, client_addr, reason);
+    let Some(code) = reason.error_code().filter(|_| !state.stealth) else { return };
+    let message = handshake_error_message(code as u8, &detail, client_pubkey, client_addr);
+    let identity = state.identity.clone();
+    let signature = match tokio::task::spawn_blocking(move || identity.sign(&message)).await {
+        Ok(Ok(sig)) => sig,
+        _ => Vec::new(),
+    };
+    let reply = HandshakeMessage::HandshakeError { code: code as u8, detail, observed_addr: client_addr, signature };
+    if let Ok(data) = serialize_message(&reply) {
+        let _ = state.socket.send_to(&data, client_addr).await;
+    }
+}
+
+/// 版本不兼容的握手消息：回复未签名的 BadVersion（未启用 --stealth 时）
+///
+/// 来源地址没有经过验证，不签名（不为伪造的请求消耗签名运算），回复也不大于请求，不能被用来放大流量
+async fn reject_bad_version(state: &ServerState, client_addr: SocketAddr, version: u8, request_len: usize) {
+    record_denial(state, client_addr, DenyReason::BadVersion);
+    if state.stealth {
+        return;
+    }
+    let reply = HandshakeMessage::HandshakeError {
+        code: HandshakeErrorCode::BadVersion as u8,
+        detail: format!("服务端协议版本 {}，请求版本 {}", WIRE_VERSION, version),
+        observed_addr: client_addr,
+        signature: Vec::new(),
+    };
+    if let Ok(data) = serialize_message(&reply)
+        && data.len() <= request_len
+    {
+        let _ = state.socket.send_to(&data, client_addr).await;
+    }
+}
+
+/// 处理从 TUN 读到的一个包：按目标虚拟 IP 找到客户端，加密后发送
+async fn forward_tun_packet(state: &ServerState, ip_packet: &[u8]) {
+    
+    // 解析目标IP
+    let Ok((src_ip, dst_ip)) = parse_ip_header(ip_packet) else { return };
+    
+    // 查找目标客户端
+    let target_addr = match peer_key(dst_ip) {
+        Some(key) => state.peers.lock().await.get(&key).cloned(),
+        None => None,
+    };
+    
+    if let Some(addr) = target_addr {
+        // 自定义钩子可能改写或丢弃包，只在有钩子时复制
+        let hooked;
+        let ip_packet = if state.hooks.is_empty() {
+            ip_packet
+        } else {
+            let mut packet = ip_packet.to_vec();
+            let ctx = PacketContext { direction: Direction::ToClient, client: peer_key(dst_ip), src: src_ip, dst: dst_ip };
+            if let HookVerdict::Drop(reason) = state.hooks.run(&ctx, &mut packet) {
+                trace_packet!("🪝 钩子丢弃: {} -> {} ({})", src_ip, dst_ip, reason);
+                record_drop(state, reason);
+                return;
+            }
+            hooked = packet;
+            &hooked[..]
+        };
+        state.flows.lock().unwrap().record(ip_packet);
+        
+        // 获取目标的会话密钥（启用 FEC 时同时编码）
+        let (keys, seq, frames) = {
+            let mut map = state.sessions.lock().await;
+            match map.get_mut(&addr) {
+                Some(s) => {
+                    s.bytes_out += ip_packet.len() as u64;
+                    s.packets_out += 1;
+                    let frames = s.fec.as_mut().map(|fec| fec.encoder.encode(ip_packet));
+                    (s.keys.clone(), s.reserve_seq(datagram_count(&frames)), frames)
+                }
+                None => return,
+            }
+        };
+        
+        // 加密后暂存，这一批 TUN 包处理完时一起发出（见 end_tun_batch）
+        if let Ok(datagrams) = encrypt_for_client(&keys, seq, ip_packet, frames) {
+                for datagram in &datagrams {
+                    state.socket.queue(datagram, addr).await;
+                }
+                trace_packet!("🔁 [TUN->客户端] {} ({} 字节)", dst_ip, ip_packet.len());
+                record_forward(state, "tun_to_client", src_ip, dst_ip, ip_packet.len());
+            }
+    }
+}
+
+/// 发往客户端的一个 IP 包要占用的数据包数（也是要预留的序号数）
+fn datagram_count(frames: &Option<FecFrames>) -> u64 {
+    match frames {
+        Some(FecFrames { parity: Some(_), .. }) => 2,
+        _ => 1,
+    }
+}
+
+/// 加密一个发往客户端的 IP 包；会话启用了 FEC 时加密编码后的数据包，凑满一组时紧跟着校验包
+///
+/// seq 为预留的第一个序号（见 datagram_count）
+fn encrypt_for_client(keys: &PacketKeys, seq: u64, ip_packet: &[u8], frames: Option<FecFrames>) -> anyhow::Result<Vec<Vec<u8>>> {
+    let Some(FecFrames { data, parity }) = frames else {
+        return Ok(vec![keys.encrypt_packet(seq, ip_packet)?]);
+    };
+    let mut datagrams = vec![keys.encrypt_packet(seq, &data)?];
+    if let Some(parity) = parity {
+        datagrams.push(keys.encrypt_packet(seq + 1, &parity)?);
+    }
+    Ok(datagrams)
+}
+
+/// 加密并立即发送一个发往客户端的 IP 包
+async fn send_to_client(state: &ServerState, addr: SocketAddr, keys: &PacketKeys, seq: u64, ip_packet: &[u8], frames: Option<FecFrames>) -> anyhow::Result<()> {
+    for datagram in encrypt_for_client(keys, seq, ip_packet, frames)? {
+        let _ = state.socket.send_to(&datagram, addr).await;
+    }
+    Ok(())
+}
+
+/// 用指定会话密钥加密并发送一条控制消息（序号取自 addr 的会话，会话已不存在时不发送）
+async fn send_control(state: &ServerState, addr: SocketAddr, session_key: &[u8; 32], msg: &ControlMessage) {
+    let Some(seq) = state.next_seq(addr).await else { return };
+    if let Ok(keys) = PacketKeys::from_session_key(session_key, symmetric::Direction::ToClient)
+        && let Ok(plaintext) = msg.encode()
+        && let Ok(data) = keys.encrypt_packet(seq, &plaintext)
+    {
+        let _ = state.socket.send_to(&data, addr).await;
+    }
+}
+
+/// 通知旧会话已被同一身份的新连接替换，然后移除它
+async fn replace_session(state: &ServerState, old_addr: SocketAddr, new_addr: SocketAddr) {
+    let old_key = state.sessions.lock().await.get(&old_addr).map(|s| s.session_key);
+    let Some(old_key) = old_key else { return };
+    let reason = format!("replaced by a new connection from {}", new_addr);
+    send_control(state, old_addr, &old_key, &ControlMessage::Disconnect { reason }).await;
+    remove_session(state, old_addr, TerminateCause::LostCarrier).await;
+    println!("   🔁 已替换同一身份的旧会话: {}", old_addr);
+}
+
+/// 建立虚拟 IP 到客户端地址的路由映射，并通知最近通信过的对端
+async fn map_virtual_ip(state: &ServerState, vip: Ipv4Addr, addr: SocketAddr) {
+    state.peers.lock().await.insert(vip, addr);
+    println!("   🗺️  路由映射: {} -> {}", vip, addr);
+    notify_peer_status(state, vip, true).await;
+}
+
+/// 移除会话及其路由映射，并上报计费 Stop
+async fn remove_session(state: &ServerState, addr: SocketAddr, cause: TerminateCause) {
+    let removed = state.sessions.lock().await.remove(&addr);
+    if let Some(bonding) = &state.bonding {
+        bonding.forget(addr);
+    }
+    if let Some(session) = removed {
+        // 主动断开或被管理员断开时作废票据，其他原因（超时、被替换）保留一段时间供客户端恢复
+        if let (Some(id), Some(tickets)) = (&session.ticket, &state.tickets) {
+            let mut tickets = tickets.lock().unwrap();
+            if matches!(cause, TerminateCause::UserRequest | TerminateCause::AdminReset) {
+                tickets.revoke(id, addr);
+            } else {
+                tickets.detach(id, addr);
+            }
+        }
+        if let Some(vip) = session.virtual_ip {
+            let mut peers = state.peers.lock().await;
+            if peers.get(&vip) == Some(&addr) {
+                peers.remove(&vip);
+                drop(peers);
+                // 虚拟 IP 没有被新会话接管时才清理，避免误删新客户端的连接
+                tokio::task::spawn_blocking(move || flush_client_conntrack(vip));
+                notify_peer_status(state, vip, false).await;
+            }
+        }
+        if session.authenticated {
+            state.report_accounting(AcctStatus::Stop, session.acct_record(Some(cause)));
+        }
+    }
+}
+
+/// 向最近与 vip 通信过的客户端通知它上线/下线（见 presence 模块）
+async fn notify_peer_status(state: &ServerState, vip: Ipv4Addr, online: bool) {
+    let now = Instant::now();
+    let targets: Vec<(SocketAddr, [u8; 32])> = state.sessions.lock().await.iter_mut()
+        .filter(|(_, s)| s.virtual_ip != Some(vip))
+        .filter_map(|(addr, s)| s.recent_peers.should_notify(vip, online, now).then_some((*addr, s.session_key)))
+        .collect();
+    if !targets.is_empty() {
+        println!("👥 {} {}，通知 {} 个最近通信过的客户端", vip, if online { "重新上线" } else { "已离线" }, targets.len());
+    }
+    let msg = ControlMessage::PeerStatus { peer: vip, online };
+    for (addr, session_key) in targets {
+        send_control(state, addr, &session_key, &msg).await;
+    }
+}
+
+/// 删除离线客户端（虚拟 IPv4 及对应的隧道 IPv6 地址）残留的 conntrack 条目
+fn flush_client_conntrack(vip: Ipv4Addr) {
+    let mut deleted = 0;
+    for ip in [IpAddr::V4(vip), IpAddr::V6(local_tun::tunnel_ipv6(vip))] {
+        // 未安装 conntrack 工具时静默跳过
+        deleted += gateway::flush_conntrack(ip).unwrap_or(0);
+    }
+    if deleted > 0 {
+        println!("🧹 已清理 {} 的 {} 条 conntrack 条目", vip, deleted);
+    }
+}
+
+/// 会话收到第一个包时下发一次会话信息：客户端的公网映射地址、会话恢复票据，以及 --push-route 配置的路由
+async fn send_session_info_once(state: &ServerState, addr: SocketAddr, session_key: &[u8; 32]) {
+    let (ticket, previous_psk) = {
+        let mut map = state.sessions.lock().await;
+        let Some(s) = map.get_mut(&addr).filter(|s| !s.info_sent) else { return };
+        s.info_sent = true;
+        // 恢复的会话沿用原来的票据
+        let ticket = state.tickets.as_ref().map(|tickets| {
+            let mut tickets = tickets.lock().unwrap();
+            let id = *s.ticket.get_or_insert_with(|| {
+                tickets.issue(Ticket::new(s.session_key, s.client_id.clone(), s.identity_key, s.virtual_ip, s.identity.clone(), s.capabilities, addr))
+            });
+            ControlMessage::SessionTicket { id, lifetime_secs: tickets.lifetime_secs() }
+        });
+        (ticket, s.previous_psk)
+    };
+    
+    // 客户端据此显示公网地址、判断 NAT 类型
+    send_control(state, addr, session_key, &ControlMessage::ObservedAddr { addr }).await;
+    if let Some(ticket) = ticket {
+        send_control(state, addr, session_key, &ticket).await;
+    }
+    // 用旧 PSK 握手的客户端：提醒它在宽限期结束前换上新的 PSK
+    if previous_psk && let Some(remaining) = state.psks.grace_remaining(SystemTime::now()) {
+        let notice = ControlMessage::PskUpdated { new_id: state.psks.current_id(), grace_secs: remaining.as_secs() };
+        send_control(state, addr, session_key, &notice).await;
+        println!("🔁 已通知 {} 更换 PSK（宽限期还剩 {} 小时）", addr, remaining.as_secs() / 3600);
+    }
+    
+    if state.pushed_routes.is_empty() {
+        return;
+    }
+    let msg = ControlMessage::RoutePush { routes: state.pushed_routes.clone() };
+    send_control(state, addr, session_key, &msg).await;
+    println!("🧭 已向 {} 下发路由: {:?}", addr, state.pushed_routes);
+}
+
+/// 处理客户端发来的控制消息
+async fn handle_control_message(state: &ServerState, addr: SocketAddr, session_key: &[u8; 32], msg: ControlMessage) {
+    match msg {
+        ControlMessage::Keepalive => {
+            send_control(state, addr, session_key, &ControlMessage::Keepalive).await;
+        }
+        ControlMessage::RekeyRequest { public_key } => {
+            let (new_key, response) = control::respond_rekey(session_key, public_key);
+            // 响应仍用旧密钥加密，客户端收到后才切换
+            send_control(state, addr, session_key, &response).await;
+            if let Some(session) = state.sessions.lock().await.get_mut(&addr)
+                && let Ok(keys) = server_packet_keys(&new_key)
+            {
+                session.previous_keys = Some(std::mem::replace(&mut session.keys, keys));
+                session.session_key = new_key;
+                if let (Some(id), Some(tickets)) = (&session.ticket, &state.tickets) {
+                    tickets.lock().unwrap().update_key(id, new_key);
+                }
+            }
+            println!("🔄 会话密钥已轮换: {}", addr);
+            keylog::record(KeyEvent::Rekey, addr, &new_key);
+        }
+        ControlMessage::Disconnect { reason } => {
+            println!("👋 客户端 {} 断开: {}", addr, reason);
+            remove_session(state, addr, TerminateCause::UserRequest).await;
+        }
+        ControlMessage::Echo { id, timestamp_us } => {
+            send_control(state, addr, session_key, &ControlMessage::EchoReply { id, timestamp_us }).await;
+        }
+        ControlMessage::EchoReply { timestamp_us, .. } => {
+            if let Some(session) = state.sessions.lock().await.get_mut(&addr) {
+                session.rtt.on_echo_reply(timestamp_us);
+            }
+        }
+        ControlMessage::Hostname { name } => {
+            if !control::is_valid_hostname(&name) {
+                record_drop(state, "invalid_hostname");
+                return;
+            }
+            if let Some(session) = state.sessions.lock().await.get_mut(&addr)
+                && session.hostname.as_ref() != Some(&name)
+            {
+                println!("🏷️  {} 的主机名: {}", addr, name);
+                session.hostname = Some(name);
+            }
+        }
+        ControlMessage::Metadata { client } => {
+            if !client.is_valid() {
+                record_drop(state, "invalid_metadata");
+                return;
+            }
+            if let Some(session) = state.sessions.lock().await.get_mut(&addr)
+                && session.metadata.as_ref() != Some(&client)
+            {
+                println!("🖥️  {} 的设备信息: {} / {} / v{}", addr, client.hostname, client.os, client.version);
+                session.metadata = Some(client);
+            }
+        }
+        ControlMessage::ServiceRegister { services } => {
+            if services.len() > control::MAX_SERVICES || !services.iter().all(Service::is_valid) {
+                record_drop(state, "invalid_service");
+                return;
+            }
+            if let Some(session) = state.sessions.lock().await.get_mut(&addr)
+                && session.services != services
+            {
+                let list: Vec<String> = services.iter().map(Service::to_string).collect();
+                println!("📇 {} 登记的服务: {}", addr, if list.is_empty() { "（无）".to_string() } else { list.join(", ") });
+                session.services = services;
+            }
+        }
+        ControlMessage::ServicesRequest { id } => {
+            let (services, total) = list_services(state, addr).await;
+            send_control(state, addr, session_key, &ControlMessage::ServiceList { id, services, total }).await;
+        }
+        ControlMessage::PeersRequest { id } => {
+            let (peers, total) = list_peers(state, addr).await;
+            send_control(state, addr, session_key, &ControlMessage::PeerList { id, peers, total }).await;
+        }
+        // 以下消息只应由服务端发出
+        ControlMessage::RoutePush { .. }
+        | ControlMessage::RekeyResponse { .. }
+        | ControlMessage::ObservedAddr { .. }
+        | ControlMessage::SessionTicket { .. }
+        | ControlMessage::PeerStatus { .. }
+        | ControlMessage::PeerList { .. }
+        | ControlMessage::ServiceList { .. }
+        | ControlMessage::PskUpdated { .. } => {
+            record_drop(state, "unexpected_control");
+        }
+    }
+}
+
+/// 处理加密数据包
+async fn handle_data_packet(state: &ServerState, src_addr: SocketAddr, encrypted_data: &[u8]) -> Option<Vec<u8>> {
+    // 第二条链路上的包按主链路的会话处理
+    let src_addr = match &state.bonding {
+        Some(bonding) => bonding.primary(src_addr),
+        None => src_addr,
+    };
+    
+    // 1. 查找会话
+    let (session_key, keys, previous_keys) = {
+        let map = state.sessions.lock().await;
+        match map.get(&src_addr) {
+            Some(session) if session.authenticated => (session.session_key, session.keys.clone(), session.previous_keys.clone()),
+            Some(session) if !session.confirmed => {
+                // 还没有收到 ClientFinish 的会话，不接受数据
+                record_denial(state, src_addr, DenyReason::Unconfirmed);
+                return None;
+            }
+            Some(_) => {
+                // 尚未通过认证的会话，不接受数据
+                record_denial(state, src_addr, DenyReason::Unauthenticated);
+                return None;
+            }
+            None => {
+                // 未握手的客户端，静默丢弃
+                record_denial(state, src_addr, DenyReason::UnknownSession);
+                return None;
+            }
+        }
+    };
+    
+    // 2. 解密（密钥轮换后仍可能收到用旧密钥加密的在途包）
+    let decrypted = keys.decrypt_packet(encrypted_data).or_else(|e| match &previous_keys {
+        Some(previous) => previous.decrypt_packet(encrypted_data),
+        None => Err(e),
+    });
+    let (seq, ip_packet) = match decrypted {
+        Ok(data) => data,
+        Err(_) => {
+            // 解密失败，可能是错误的数据
+            record_denial(state, src_addr, DenyReason::DecryptFailed);
+            return None;
+        }
+    };
+    
+    // 同一个包被链路复制多次或被重放时只处理第一份
+    let duplicate = state.sessions.lock().await.get_mut(&src_addr).is_some_and(|s| !s.replay.check(seq));
+    if duplicate {
+        record_drop(state, "duplicate");
+        return None;
+    }
+    
+    // 会话建立后第一次收到包时下发公网地址和路由
+    send_session_info_once(state, src_addr, &session_key).await;
+    
+    // 控制消息在隧道内处理，不进入转发流程
+    if control::classify(&ip_packet) == PayloadKind::Control {
+        match ControlMessage::decode(&ip_packet) {
+            Ok(msg) => handle_control_message(state, src_addr, &session_key, msg).await,
+            Err(_) => record_drop(state, "malformed_control"),
+        }
+        return None;
+    }
+
+    // 隧道内的 PMTU 探测：原样回复确认，不进入转发流程
+    if pmtu::is_pmtu_message(&ip_packet) {
+        if let Ok(PmtuMessage::Probe { id, size }) = PmtuMessage::decode(&ip_packet)
+            && let Some(seq) = state.next_seq(src_addr).await
+            && let Ok(reply) = keys.encrypt_packet(seq, &PmtuMessage::Ack { id, size }.encode())
+        {
+            let _ = state.socket.send_to(&reply, src_addr).await;
+        }
+        return None;
+    }
+
+    // 前向纠错：数据包取出其中的 IP 包，校验包在组内恰好丢了一个包时恢复出这个包
+    let ip_packet = if control::classify(&ip_packet) == PayloadKind::Fec {
+        let decoded = state.sessions.lock().await.get_mut(&src_addr)
+            .and_then(|s| s.fec.as_mut())
+            .map(|fec| fec.decoder.receive(&ip_packet));
+        match decoded {
+            Some(Some(inner)) => inner,
+            Some(None) => return None,
+            None => {
+                record_drop(state, "unknown_payload");
+                return None;
+            }
+        }
+    } else {
+        ip_packet
+    };
+
+    // round-robin 模式的包带有序号，按序放行后再转发
+    if control::classify(&ip_packet) == PayloadKind::Bonded {
+        let (Some(bonding), Some((seq, inner))) = (&state.bonding, multipath::unwrap(&ip_packet)) else {
+            record_drop(state, "unknown_payload");
+            return None;
+        };
+        let released = bonding.push(src_addr, seq, inner.to_vec())?;
+        return forward_client_packet(state, src_addr, &keys, released).await;
+    }
+
+    forward_client_packet(state, src_addr, &keys, ip_packet).await
+}
+
+/// 客户端发进隧道的 IP 包：检查、记账、学习路由，然后转发给其他客户端，或返回给引擎写入 TUN
+async fn forward_client_packet(state: &ServerState, src_addr: SocketAddr, keys: &PacketKeys, mut ip_packet: Vec<u8>) -> Option<Vec<u8>> {
+    // 3. 解析 IP 头
+    let (src_ip, dst_ip) = match parse_ip_header(&ip_packet) {
+        Ok(ips) => ips,
+        Err(_) => {
+            record_drop(state, "malformed_ip");
+            return None;
+        }
+    };
+
+    // 源/目的地址明显非法的包直接丢弃，不参与路由学习
+    if let Some(martian) = martians::check(src_ip, dst_ip, SERVER_TUN_IP, local_tun::tunnel_ipv6(SERVER_TUN_IP)) {
+        trace_packet!("👽 丢弃非法地址的包: {} -> {} ({})", src_ip, dst_ip, martian);
+        record_drop(state, martian.as_str());
+        return None;
+    }
+    
+    // 发往服务端自身监听端口的包：客户端在隧道里又跑了一个连到本服务端的隧道
+    if state.local_endpoints.matches(&ip_packet) {
+        trace_packet!("🪆 丢弃隧道里的隧道: {} -> {}", src_ip, dst_ip);
+        record_drop(state, recursion::DROP_REASON);
+        return None;
+    }
+
+    // 协议/端口白名单：发往隧道网段的包按源端口匹配也放行（对端访问本机服务的回包）
+    if let Some(filter) = &state.filter {
+        let vip = state.sessions.lock().await.get(&src_addr).and_then(|s| s.virtual_ip);
+        let to_tunnel = peer_key(dst_ip).is_some_and(is_vpn_subnet);
+        if !filter.allowlist_for(vip).allows(&ip_packet, to_tunnel) {
+            trace_packet!("🧱 白名单外的包: {} -> {}", src_ip, dst_ip);
+            record_drop(state, "filtered");
+            return None;
+        }
+    }
+
+    // 自定义钩子（见 vpn_core::hooks）在内置检查之后执行；钩子可能改写了地址，之后按改写后的包头转发
+    let (src_ip, dst_ip) = if state.hooks.is_empty() {
+        (src_ip, dst_ip)
+    } else {
+        let ctx = PacketContext { direction: Direction::FromClient, client: peer_key(src_ip), src: src_ip, dst: dst_ip };
+        if let HookVerdict::Drop(reason) = state.hooks.run(&ctx, &mut ip_packet) {
+            trace_packet!("🪝 钩子丢弃: {} -> {} ({})", src_ip, dst_ip, reason);
+            record_drop(state, reason);
+            return None;
+        }
+        match parse_ip_header(&ip_packet) {
+            Ok(ips) => ips,
+            Err(_) => {
+                record_drop(state, "malformed_ip");
+                return None;
+            }
+        }
+    };
+
+    // 4. 更新流量计数、流表和路由表
+    state.flows.lock().unwrap().record(&ip_packet);
+    if let Some(session) = state.sessions.lock().await.get_mut(&src_addr) {
+        session.bytes_in += ip_packet.len() as u64;
+        session.packets_in += 1;
+    }
+    // 隧道外的 IPv6 源地址（链路本地地址等）不对应任何虚拟 IP，不参与学习
+    if let Some(src_key) = peer_key(src_ip) {
+        let mut map = state.peers.lock().await;
+        if map.get(&src_key) != Some(&src_addr) {
+            println!("🔗 客户端上线/更新: {} -> {}", src_key, src_addr);
+            map.insert(src_key, src_addr);
+            if let Some(shaper) = state.shaper.as_ref().filter(|_| is_vpn_subnet(src_key)) {
+                shaper.add_client(src_key);
+            }
+            drop(map);
+            notify_peer_status(state, src_key, true).await;
+        }
+    }
+
+    // 5. 转发逻辑：优先客户端互联，其次转发到TUN（网关模式）
+    let target_peer = match peer_key(dst_ip) {
+        Some(key) => state.peers.lock().await.get(&key).cloned(),
+        None => None,
+    };
+
+    match target_peer {
+        Some(target_addr) => {
+            if let (Some(acl), Some(src_key), Some(dst_key)) = (&state.peer_acl, peer_key(src_ip), peer_key(dst_ip))
+                && !acl.allows(src_key, dst_key)
+            {
+                trace_packet!("🧱 ACL 不允许互访: {} -> {}", src_ip, dst_ip);
+                record_drop(state, "peer_acl");
+                return None;
+            }
+            // 目标是另一个客户端，直接转发；服务端在这里充当一跳路由，递减 TTL 防止路由环路
+            if icmp::decrement_hop_limit(&mut ip_packet) == Some(HopLimit::Expired) {
+                trace_packet!("⌛ TTL 耗尽: {} -> {}", src_ip, dst_ip);
+                record_drop(state, "ttl_expired");
+                if let Some(reply) = icmp::time_exceeded(&ip_packet, SERVER_TUN_IP, local_tun::tunnel_ipv6(SERVER_TUN_IP))
+                    && let Some(seq) = state.next_seq(src_addr).await
+                    && let Ok(encrypted) = keys.encrypt_packet(seq, &reply)
+                {
+                    let _ = state.socket.send_to(&encrypted, src_addr).await;
+                }
+                return None;
+            }
+            let (target_keys, seq, frames) = {
+                let mut map = state.sessions.lock().await;
+                let now = Instant::now();
+                // 记录双方最近通信过的对端，任一方下线时通知另一方
+                if let (Some(src_key), Some(dst_key)) = (peer_key(src_ip), peer_key(dst_ip)) {
+                    if let Some(s) = map.get_mut(&src_addr) {
+                        s.recent_peers.touch(dst_key, now);
+                    }
+                    if let Some(s) = map.get_mut(&target_addr) {
+                        s.recent_peers.touch(src_key, now);
+                    }
+                }
+                match map.get_mut(&target_addr) {
+                    Some(s) => {
+                        s.bytes_out += ip_packet.len() as u64;
+                        s.packets_out += 1;
+                        let frames = s.fec.as_mut().map(|fec| fec.encoder.encode(&ip_packet));
+                        (s.keys.clone(), s.reserve_seq(datagram_count(&frames)), frames)
+                    }
+                    None => return None,
+                }
+            };
+            
+            match send_to_client(state, target_addr, &target_keys, seq, &ip_packet, frames).await {
+                Ok(()) => {
+                    trace_packet!("🔁 [客户端互联] {} -> {}", src_ip, dst_ip);
+                    record_forward(state, "client_to_client", src_ip, dst_ip, ip_packet.len());
+                }
+                Err(e) => {
+                    trace_packet!("加密转发失败: {}", e);
+                    record_drop(state, "encrypt_failed");
+                }
+            }
+        }
+        None => {
+            // 目标不是客户端，尝试转发到TUN（互联网）
+            // 检查目标IP是否是本地VPN网段
+            // 发往服务端自身 TUN 地址的包交给本机协议栈（访问服务端上的服务）
+            if peer_key(dst_ip).is_some_and(|ip| is_vpn_subnet(ip) && ip != SERVER_TUN_IP) {
+                // 仍然是10.0.0.x（或对应的 fd00::/96），但客户端不在线，丢弃
+                trace_packet!("🚫 丢弃: {} -> {} (目标不在线)", src_ip, dst_ip);
+                record_drop(state, "peer_offline");
+            } else {
+                // 目标是外网IP或服务端本机，交给引擎写入TUN设备（本机地址由内核直接交付，不经过 NAT）
+                trace_packet!("🌐 [转发到互联网] {} -> {}", src_ip, dst_ip);
+                let direction = if peer_key(dst_ip) == Some(SERVER_TUN_IP) { "client_to_host" } else { "client_to_internet" };
+                record_forward(state, direction, src_ip, dst_ip, ip_packet.len());
+                return Some(ip_packet);
+            }
+        }
+    }
+    None
+}
+
+/// 记录一次成功转发（计数 + 汇总日志 + 按采样率生成 span）
+fn record_forward(state: &ServerState, direction: &'static str, src_ip: IpAddr, dst_ip: IpAddr, bytes: usize) {
+    state.datapath.forwarded(direction, bytes);
+    let telemetry = &state.telemetry;
+    let metrics = telemetry.metrics();
+    Metrics::incr(&metrics.packets_forwarded);
+    metrics.bytes_forwarded.fetch_add(bytes as u64, std::sync::atomic::Ordering::Relaxed);
+
+    if telemetry.should_sample() {
+        let mut span = telemetry.start_span("forward_packet");
+        span.set_attribute("direction", direction);
+        span.set_attribute("src_ip", src_ip);
+        span.set_attribute("dst_ip", dst_ip);
+        span.set_attribute("bytes", bytes);
+    }
+}
+
+/// 记录一次丢包（计数 + 汇总日志 + 按采样率生成带原因的 span）
+fn record_drop(state: &ServerState, reason: &'static str) {
+    state.datapath.dropped(reason);
+    let telemetry = &state.telemetry;
+    Metrics::incr(&telemetry.metrics().packets_dropped);
+
+    if telemetry.should_sample() {
+        let mut span = telemetry.start_span("packet_dropped");
+        span.set_attribute("reason", reason);
+        span.set_error(reason);
+    }
+}
+
+/// 按主机名查找在线客户端的虚拟 IP；多个会话同名时取最早上线的一个
+async fn lookup_hostname(state: &ServerState, name: &str) -> Option<Ipv4Addr> {
+    state.sessions.lock().await
+        .values()
+        .filter(|s| s.authenticated && s.hostname.as_deref() == Some(name))
+        .filter_map(|s| Some((s.started_at, s.virtual_ip?)))
+        .min_by_key(|(started_at, _)| *started_at)
+        .map(|(_, vip)| vip)
+}
+
+/// --wasm-policy 指定的策略模块
+#[cfg(feature = "wasm")]
+fn wasm_policies(args: &[String]) -> Result<Vec<Arc<dyn vpn_core::hooks::PacketHook>>> {
+    wasm::from_args(args)
+}
+
+#[cfg(not(feature = "wasm"))]
+fn wasm_policies(args: &[String]) -> Result<Vec<Arc<dyn vpn_core::hooks::PacketHook>>> {
+    if arg_values(args, "--wasm-policy").is_empty() {
+        Ok(Vec::new())
+    } else {
+        Err(anyhow::anyhow!("--wasm-policy 需要以 wasm feature 编译服务端（cargo build --features wasm）"))
+    }
+}
+
+/// 请求方可以看到的在线客户端（按虚拟 IP 排序，最多 MAX_PEER_LIST 个）和实际数量
+async fn list_peers(state: &ServerState, addr: SocketAddr) -> (Vec<PeerInfo>, u32) {
+    let sessions = state.sessions.lock().await;
+    let online = visible_peers(state, &sessions, addr);
+    let total = online.len() as u32;
+    let peers = online
+        .into_iter()
+        .take(control::MAX_PEER_LIST)
+        .map(|(vip, s)| PeerInfo { virtual_ip: vip, hostname: s.hostname.clone() })
+        .collect();
+    (peers, total)
+}
+
+/// 请求方可以访问的对端登记的服务（按虚拟 IP 排序，最多 MAX_SERVICE_LIST 条）和实际数量
+async fn list_services(state: &ServerState, addr: SocketAddr) -> (Vec<(Ipv4Addr, Service)>, u32) {
+    let sessions = state.sessions.lock().await;
+    let services: Vec<(Ipv4Addr, Service)> = visible_peers(state, &sessions, addr)
+        .into_iter()
+        .flat_map(|(vip, s)| s.services.iter().map(move |service| (vip, service.clone())))
+        .collect();
+    let total = services.len() as u32;
+    (services.into_iter().take(control::MAX_SERVICE_LIST).collect(), total)
+}
+
+/// 请求方按 --peer-acl 可以访问的在线客户端，按虚拟 IP 排序
+fn visible_peers<'a>(state: &ServerState, sessions: &'a HashMap<SocketAddr, Session>, addr: SocketAddr) -> std::collections::BTreeMap<Ipv4Addr, &'a Session> {
+    let own_vip = sessions.get(&addr).and_then(|s| s.virtual_ip);
+    let visible = |vip: Ipv4Addr| match (&state.peer_acl, own_vip) {
+        (None, _) => true,
+        (Some(acl), Some(own)) => acl.allows(own, vip),
+        (Some(_), None) => false,
+    };
+    // 同一虚拟 IP 短暂存在新旧两个会话时取先建立的
+    let mut online: std::collections::BTreeMap<Ipv4Addr, &Session> = std::collections::BTreeMap::new();
+    for s in sessions.iter().filter(|(a, s)| **a != addr && s.authenticated).map(|(_, s)| s) {
+        let Some(vip) = s.virtual_ip.filter(|vip| Some(*vip) != own_vip && visible(*vip)) else { continue };
+        online.entry(vip).and_modify(|e| if s.started_at < e.started_at { *e = s }).or_insert(s);
+    }
+    online
+}
+
+/// 有界队列已满而丢弃一个请求（丢包计数 + vpn.queue.dropped）
+fn record_overflow(state: &ServerState, queue: &'static str) {
+    Metrics::incr(&state.telemetry.metrics().queue_drops);
+    record_drop(state, queue);
+}
+
+/// 记录一次拒绝（按来源统计 + 丢包计数）
+fn record_denial(state: &ServerState, addr: SocketAddr, reason: DenyReason) {
+    state.denials.lock().unwrap().record(addr.ip(), reason);
+    Metrics::incr(&state.telemetry.metrics().denials);
+    record_drop(state, reason.as_str());
+}
+
+/// 从命令行参数中读取 `--name value` 形式的值
+fn arg_value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// 读取可重复出现的 `--name value` 参数
+fn arg_values(args: &[String], name: &str) -> Vec<String> {
+    args.windows(2)
+        .filter(|w| w[0] == name)
+        .map(|w| w[1].clone())
+        .collect()
+}
+
+/// 简单的 IP 头解析器
+/// IPv4 提取 Source IP (Byte 12-15) 和 Dest IP (Byte 16-19)，
+/// IPv6 提取 Source (Byte 8-23) 和 Dest (Byte 24-39)
+fn parse_ip_header(data: &[u8]) -> Result<(IpAddr, IpAddr), &'static str> {
+    // 检查版本号 (Byte 0 的高 4 位)
+    match data.first().map(|b| b >> 4) {
+        // IPv4 头最小 20 字节
+        Some(4) if data.len() >= 20 => {
+            let src = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+            let dst = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+            Ok((src.into(), dst.into()))
+        }
+        // IPv6 固定头 40 字节
+        Some(6) if data.len() >= 40 => {
+            let src: [u8; 16] = data[8..24].try_into().unwrap();
+            let dst: [u8; 16] = data[24..40].try_into().unwrap();
+            Ok((IpAddr::from(src), IpAddr::from(dst)))
+        }
+        Some(4 | 6) => Err("数据包太短"),
+        _ => Err("不是 IP 包"),
+    }
+}
+
+/// 是否属于 VPN 网段 10.0.0.0/24
+fn is_vpn_subnet(ip: Ipv4Addr) -> bool {
+    ip.octets()[..3] == [10, 0, 0]
+}
+
+/// 地址对应的 PeerMap 键：IPv4 原样使用，隧道 IPv6 地址取其中嵌入的虚拟 IPv4 地址
+fn peer_key(ip: IpAddr) -> Option<Ipv4Addr> {
+    match ip {
+        IpAddr::V4(v4) => Some(v4),
+        IpAddr::V6(v6) => local_tun::tunnel_ipv4(v6),
     }
-
-    let src = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
-    let dst = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
-
-    Ok((src, dst))
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+// vpn_server/src/martians.rs
+// 内层包的火星地址过滤
+//
+// 客户端发进隧道的包理论上都来自它自己的虚拟 IP，但被入侵或配置错误的客户端可能发出
+// 源地址为 0.0.0.0、环回、组播或服务端自身地址的包。路由器会丢弃这些包（RFC 1812 5.3.7），
+// 服务端直接转发时也要丢弃：否则它们会被写入 TUN 交给内核，或被学习进 PeerMap
+// （源地址为服务端 TUN 地址的包会让服务端把发往自己的流量转给该客户端）。
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// 被丢弃的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Martian {
+    /// 源地址为 0.0.0.0、0.0.0.0/8 或 ::
+    UnspecifiedSource,
+    /// 源地址为 127.0.0.0/8 或 ::1
+    LoopbackSource,
+    /// 源地址为组播地址
+    MulticastSource,
+    /// 源地址为广播地址或保留地址（240.0.0.0/4）
+    ReservedSource,
+    /// 源地址为服务端自身的隧道地址
+    ServerSource,
+    /// 目的地址为未指定、0.0.0.0/8、环回或受限广播地址
+    BadDestination,
+}
+
+impl Martian {
+    /// 丢包原因（datapath 计数和 span 中使用）
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Martian::UnspecifiedSource => "martian_unspecified_source",
+            Martian::LoopbackSource => "martian_loopback_source",
+            Martian::MulticastSource => "martian_multicast_source",
+            Martian::ReservedSource => "martian_reserved_source",
+            Martian::ServerSource => "martian_server_source",
+            Martian::BadDestination => "martian_destination",
+        }
+    }
+}
+
+impl fmt::Display for Martian {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 检查内层包的源/目的地址；正常的包返回 None
+pub fn check(src: IpAddr, dst: IpAddr, server_v4: Ipv4Addr, server_v6: Ipv6Addr) -> Option<Martian> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => check_v4(src, dst, server_v4),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => check_v6(src, dst, server_v6),
+        _ => None,
+    }
+}
+
+fn check_v4(src: Ipv4Addr, dst: Ipv4Addr, server: Ipv4Addr) -> Option<Martian> {
+    let source = if src.octets()[0] == 0 {
+        Some(Martian::UnspecifiedSource)
+    } else if src.is_loopback() {
+        Some(Martian::LoopbackSource)
+    } else if src.is_multicast() {
+        Some(Martian::MulticastSource)
+    } else if src.octets()[0] >= 240 {
+        Some(Martian::ReservedSource)
+    } else if src == server {
+        Some(Martian::ServerSource)
+    } else {
+        None
+    };
+    source.or_else(|| (dst.octets()[0] == 0 || dst.is_loopback() || dst.is_broadcast()).then_some(Martian::BadDestination))
+}
+
+fn check_v6(src: Ipv6Addr, dst: Ipv6Addr, server: Ipv6Addr) -> Option<Martian> {
+    let source = if src.is_unspecified() {
+        Some(Martian::UnspecifiedSource)
+    } else if src.is_loopback() {
+        Some(Martian::LoopbackSource)
+    } else if src.is_multicast() {
+        Some(Martian::MulticastSource)
+    } else if src == server {
+        Some(Martian::ServerSource)
+    } else {
+        None
+    };
+    source.or_else(|| (dst.is_unspecified() || dst.is_loopback()).then_some(Martian::BadDestination))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_str(src: &str, dst: &str) -> Option<Martian> {
+        let server_v6: Ipv6Addr = "fd00::a00:1".parse().unwrap();
+        check(src.parse().unwrap(), dst.parse().unwrap(), Ipv4Addr::new(10, 0, 0, 1), server_v6)
+    }
+
+    #[test]
+    fn test_martians() {
+        // 正常流量：客户端访问外网、其他客户端、服务端，发组播（mDNS）
+        assert_eq!(check_str("10.0.0.2", "1.1.1.1"), None);
+        assert_eq!(check_str("10.0.0.2", "10.0.0.3"), None);
+        assert_eq!(check_str("10.0.0.2", "10.0.0.1"), None);
+        assert_eq!(check_str("10.0.0.2", "224.0.0.251"), None);
+        assert_eq!(check_str("fd00::a00:2", "2001:db8::1"), None);
+        assert_eq!(check_str("fe80::1", "ff02::2"), None);
+
+        assert_eq!(check_str("0.0.0.0", "1.1.1.1"), Some(Martian::UnspecifiedSource));
+        assert_eq!(check_str("0.1.2.3", "1.1.1.1"), Some(Martian::UnspecifiedSource));
+        assert_eq!(check_str("127.0.0.1", "10.0.0.3"), Some(Martian::LoopbackSource));
+        assert_eq!(check_str("224.0.0.1", "10.0.0.3"), Some(Martian::MulticastSource));
+        assert_eq!(check_str("255.255.255.255", "1.1.1.1"), Some(Martian::ReservedSource));
+        assert_eq!(check_str("240.0.0.1", "1.1.1.1"), Some(Martian::ReservedSource));
+        assert_eq!(check_str("10.0.0.1", "10.0.0.3"), Some(Martian::ServerSource));
+        assert_eq!(check_str("10.0.0.2", "127.0.0.1"), Some(Martian::BadDestination));
+        assert_eq!(check_str("10.0.0.2", "0.0.0.0"), Some(Martian::BadDestination));
+        assert_eq!(check_str("10.0.0.2", "255.255.255.255"), Some(Martian::BadDestination));
+
+        assert_eq!(check_str("::", "2001:db8::1"), Some(Martian::UnspecifiedSource));
+        assert_eq!(check_str("::1", "2001:db8::1"), Some(Martian::LoopbackSource));
+        assert_eq!(check_str("ff02::1", "2001:db8::1"), Some(Martian::MulticastSource));
+        assert_eq!(check_str("fd00::a00:1", "fd00::a00:3"), Some(Martian::ServerSource));
+        assert_eq!(check_str("fd00::a00:2", "::1"), Some(Martian::BadDestination));
+    }
+}
@@ -0,0 +1,300 @@
+// vpn_server/src/portmap.rs
+// 家用路由器后的端口映射：NAT-PMP（RFC 6886）或 UPnP IGD
+//
+// * NAT-PMP：直接向默认网关的 5351 端口发送 UDP 请求
+// * UPnP IGD：SSDP 发现 + SOAP 调用交给 miniupnpc 的 `upnpc` 命令
+//
+// 映射带租期，后台任务在租期过半时续期；退出时删除映射。
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use vpn_core::dryrun::{Category, Plan};
+use tokio::net::UdpSocket;
+
+/// NAT-PMP 服务端口
+const NATPMP_PORT: u16 = 5351;
+/// 首次重传间隔（RFC 6886 建议 250ms 起，每次翻倍）
+const NATPMP_INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const NATPMP_ATTEMPTS: u32 = 4;
+/// 默认租期
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// 端口映射方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PortMapMethod {
+    /// 先尝试 NAT-PMP，失败再用 UPnP
+    Auto,
+    NatPmp,
+    Upnp,
+}
+
+impl PortMapMethod {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(PortMapMethod::Auto),
+            "natpmp" | "nat-pmp" => Ok(PortMapMethod::NatPmp),
+            "upnp" => Ok(PortMapMethod::Upnp),
+            other => Err(anyhow!("未知的端口映射方式: {}（可选 auto / natpmp / upnp）", other)),
+        }
+    }
+}
+
+/// 一次成功的映射
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortMapping {
+    /// 实际使用的方式（Auto 时为最终成功的那一种）
+    pub method: PortMapMethod,
+    /// 路由器上的外部地址和端口，客户端应连接这个地址
+    pub external: SocketAddr,
+    /// 路由器同意的租期
+    pub lifetime: Duration,
+}
+
+/// 端口映射客户端
+pub struct PortMapper {
+    method: PortMapMethod,
+    port: u16,
+    lifetime: Duration,
+    current: std::sync::Mutex<Option<PortMapping>>,
+}
+
+impl PortMapper {
+    /// 从命令行参数构建，未指定 --port-map 时返回 None
+    ///
+    /// * `--port-map auto|natpmp|upnp`：开启端口映射
+    /// * `--port-map-lifetime <秒>`：租期，默认 3600
+    pub fn from_args(args: &[String], port: u16) -> Result<Option<Self>> {
+        let Some(method) = crate::arg_value(args, "--port-map") else {
+            return Ok(None);
+        };
+        let lifetime = match crate::arg_value(args, "--port-map-lifetime") {
+            Some(secs) => Duration::from_secs(secs.parse().map_err(|_| anyhow!("无效的 --port-map-lifetime: {}", secs))?),
+            None => DEFAULT_LIFETIME,
+        };
+        Ok(Some(Self {
+            method: PortMapMethod::parse(&method)?,
+            port,
+            lifetime: lifetime.max(Duration::from_secs(120)),
+            current: std::sync::Mutex::new(None),
+        }))
+    }
+
+    /// 试运行：列出将在上游路由器上建立的映射
+    pub fn plan(&self, plan: &mut Plan) {
+        plan.note(Category::Other, format!("在上游路由器上映射 UDP 端口 {}（{:?}，租期 {} 秒，定期续期）", self.port, self.method, self.lifetime.as_secs()));
+    }
+
+    /// 建立映射并启动续期任务
+    pub async fn start(self: &Arc<Self>) -> Result<PortMapping> {
+        let mapping = self.map().await?;
+        *self.current.lock().unwrap() = Some(mapping.clone());
+
+        let mapper = self.clone();
+        tokio::spawn(async move {
+            let mut lifetime = mapping.lifetime;
+            loop {
+                tokio::time::sleep(lifetime / 2).await;
+                match mapper.map().await {
+                    Ok(renewed) => {
+                        if mapper.current.lock().unwrap().as_ref().map(|m| m.external) != Some(renewed.external) {
+                            println!("📣 外部端点变化: {}（{:?}）", renewed.external, renewed.method);
+                        }
+                        lifetime = renewed.lifetime;
+                        *mapper.current.lock().unwrap() = Some(renewed);
+                    }
+                    Err(e) => {
+                        eprintln!("⚠️  端口映射续期失败: {}", e);
+                        // 一分钟后重试
+                        lifetime = Duration::from_secs(120);
+                    }
+                }
+            }
+        });
+        Ok(mapping)
+    }
+
+    /// 当前的外部端点（尚未映射成功时为 None）
+    pub fn external(&self) -> Option<SocketAddr> {
+        self.current.lock().unwrap().as_ref().map(|m| m.external)
+    }
+
+    /// 删除当前映射（退出时调用，忽略错误）
+    pub async fn release(&self) {
+        let Some(mapping) = self.current.lock().unwrap().take() else { return };
+        let _ = match mapping.method {
+            PortMapMethod::Upnp => upnp_unmap(self.port).await,
+            _ => natpmp_map(self.port, Duration::ZERO).await.map(|_| ()),
+        };
+    }
+
+    async fn map(&self) -> Result<PortMapping> {
+        match self.method {
+            PortMapMethod::NatPmp => self.map_natpmp().await,
+            PortMapMethod::Upnp => self.map_upnp().await,
+            PortMapMethod::Auto => match self.map_natpmp().await {
+                Ok(mapping) => Ok(mapping),
+                Err(e) => {
+                    println!("   NAT-PMP 不可用（{}），改用 UPnP", e);
+                    self.map_upnp().await
+                }
+            },
+        }
+    }
+
+    async fn map_natpmp(&self) -> Result<PortMapping> {
+        let (external_port, lifetime) = natpmp_map(self.port, self.lifetime).await?;
+        let ip = natpmp_external_address().await?;
+        Ok(PortMapping {
+            method: PortMapMethod::NatPmp,
+            external: SocketAddr::from((ip, external_port)),
+            lifetime,
+        })
+    }
+
+    async fn map_upnp(&self) -> Result<PortMapping> {
+        let port = self.port;
+        let lifetime = self.lifetime;
+        let gateway = default_gateway()?;
+        let local_ip = local_address_towards(gateway).await?;
+        let output = tokio::task::spawn_blocking(move || upnpc(&[
+            "-e", "rust-vpn",
+            "-a", &local_ip.to_string(), &port.to_string(), &port.to_string(), "UDP", &lifetime.as_secs().to_string(),
+        ])).await??;
+        let ip = parse_upnpc_external_ip(&output).ok_or_else(|| anyhow!("upnpc 输出中没有外部地址"))?;
+        Ok(PortMapping {
+            method: PortMapMethod::Upnp,
+            external: SocketAddr::from((ip, port)),
+            lifetime,
+        })
+    }
+}
+
+fn default_gateway() -> Result<Ipv4Addr> {
+    vpn_core::netwatch::default_gateway()
+        .and_then(|gw| gw.parse().ok())
+        .ok_or_else(|| anyhow!("无法确定默认网关"))
+}
+
+/// 发往网关时本机使用的源地址（UDP connect 不发送数据，只做路由选择）
+async fn local_address_towards(gateway: Ipv4Addr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NATPMP_PORT)).await?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(_) => Err(anyhow!("默认网关不是 IPv4 地址")),
+    }
+}
+
+/// 向网关发送 NAT-PMP 请求，按 RFC 6886 的退避规则重传
+async fn natpmp_request(request: &[u8], expected_opcode: u8) -> Result<Vec<u8>> {
+    let gateway = default_gateway()?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NATPMP_PORT)).await?;
+
+    let mut timeout = NATPMP_INITIAL_TIMEOUT;
+    let mut buf = [0u8; 64];
+    for _ in 0..NATPMP_ATTEMPTS {
+        socket.send(request).await?;
+        if let Ok(Ok(n)) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await
+            && n >= 8
+            && buf[1] == expected_opcode
+        {
+            let result = u16::from_be_bytes([buf[2], buf[3]]);
+            if result != 0 {
+                return Err(anyhow!("NAT-PMP 返回错误码 {}", result));
+            }
+            return Ok(buf[..n].to_vec());
+        }
+        timeout *= 2;
+    }
+    Err(anyhow!("网关 {} 没有响应 NAT-PMP", gateway))
+}
+
+/// 查询外部地址（opcode 0）
+async fn natpmp_external_address() -> Result<Ipv4Addr> {
+    let response = natpmp_request(&[0, 0], 128).await?;
+    decode_natpmp_address(&response).ok_or_else(|| anyhow!("NAT-PMP 外部地址响应格式错误"))
+}
+
+/// 映射 UDP 端口（opcode 1），lifetime 为 0 时删除映射；返回 (外部端口, 租期)
+async fn natpmp_map(port: u16, lifetime: Duration) -> Result<(u16, Duration)> {
+    // 删除映射时建议的外部端口必须为 0
+    let external_port = if lifetime.is_zero() { 0 } else { port };
+    let response = natpmp_request(&encode_natpmp_map(port, external_port, lifetime), 129).await?;
+    decode_natpmp_map(&response).ok_or_else(|| anyhow!("NAT-PMP 映射响应格式错误"))
+}
+
+fn encode_natpmp_map(internal_port: u16, external_port: u16, lifetime: Duration) -> [u8; 12] {
+    let mut request = [0u8; 12];
+    request[1] = 1; // opcode 1 = UDP
+    request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request
+}
+
+/// 响应: version, opcode, result(2), epoch(4), internal(2), external(2), lifetime(4)
+fn decode_natpmp_map(response: &[u8]) -> Option<(u16, Duration)> {
+    if response.len() < 16 {
+        return None;
+    }
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes(response[12..16].try_into().ok()?);
+    Some((external_port, Duration::from_secs(lifetime as u64)))
+}
+
+/// 响应: version, opcode, result(2), epoch(4), address(4)
+fn decode_natpmp_address(response: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = response.get(8..12)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+fn upnpc(args: &[&str]) -> Result<String> {
+    let output = Command::new("upnpc")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("无法执行 upnpc（请安装 miniupnpc）: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    if !output.status.success() || stdout.contains("No IGD UPnP Device found") {
+        return Err(anyhow!("upnpc 映射失败: {}", stdout.lines().last().unwrap_or("").trim()));
+    }
+    Ok(stdout)
+}
+
+async fn upnp_unmap(port: u16) -> Result<()> {
+    tokio::task::spawn_blocking(move || upnpc(&["-d", &port.to_string(), "UDP"]).map(|_| ())).await?
+}
+
+/// 从 upnpc 输出中取外部地址，格式: ExternalIPAddress = 203.0.113.7
+fn parse_upnpc_external_ip(output: &str) -> Option<Ipv4Addr> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("ExternalIPAddress = "))
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natpmp_and_upnpc_parsing() {
+        let request = encode_natpmp_map(9000, 9000, Duration::from_secs(3600));
+        assert_eq!(request, [0, 1, 0, 0, 0x23, 0x28, 0x23, 0x28, 0, 0, 0x0e, 0x10]);
+
+        let response = [0, 129, 0, 0, 0, 0, 0, 1, 0x23, 0x28, 0x9c, 0x40, 0, 0, 0x07, 0x08];
+        assert_eq!(decode_natpmp_map(&response), Some((40000, Duration::from_secs(1800))));
+        assert_eq!(decode_natpmp_map(&response[..12]), None);
+
+        let response = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        assert_eq!(decode_natpmp_address(&response), Some(Ipv4Addr::new(203, 0, 113, 7)));
+
+        let output = "Found valid IGD : http://192.168.1.1:5000/ctl/IPConn\nLocal LAN ip address : 192.168.1.10\nExternalIPAddress = 203.0.113.7\nInternalIP:Port = 192.168.1.10:9000\n";
+        assert_eq!(parse_upnpc_external_ip(output), Some(Ipv4Addr::new(203, 0, 113, 7)));
+        assert!(PortMapMethod::parse("pcp").is_err());
+    }
+}
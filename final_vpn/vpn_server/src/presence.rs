@@ -0,0 +1,104 @@
+// vpn_server/src/presence.rs
+// 对端在线状态通知
+//
+// 每个会话记录最近与它互相转发过包的对端（客户端互联）。对端会话结束时，服务端向这些客户端发
+// PeerStatus { online: false }，客户端立即清理与它的连接，不必等超时；对端重新上线时再通知一次。
+// 只通知最近 RECENT_WINDOW 内通信过的对端，同一状态不重复通知。
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// 多久以内通信过的对端算"最近"
+pub const RECENT_WINDOW: Duration = Duration::from_secs(600);
+
+/// 每个会话最多跟踪的对端数
+pub const MAX_RECENT_PEERS: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+struct Contact {
+    last_seen: Instant,
+    /// 已通知过对端下线
+    offline: bool,
+}
+
+/// 一个会话最近通信过的对端（按虚拟 IP）
+#[derive(Debug, Default)]
+pub struct RecentPeers {
+    contacts: HashMap<Ipv4Addr, Contact>,
+}
+
+impl RecentPeers {
+    /// 记录与对端的一次通信；表满时先清理过期的对端，仍然满则不再记录新对端
+    pub fn touch(&mut self, peer: Ipv4Addr, now: Instant) {
+        if let Some(contact) = self.contacts.get_mut(&peer) {
+            *contact = Contact { last_seen: now, offline: false };
+            return;
+        }
+        if self.contacts.len() >= MAX_RECENT_PEERS {
+            self.contacts.retain(|_, c| now.duration_since(c.last_seen) < RECENT_WINDOW);
+            if self.contacts.len() >= MAX_RECENT_PEERS {
+                return;
+            }
+        }
+        self.contacts.insert(peer, Contact { last_seen: now, offline: false });
+    }
+
+    /// 对端变为在线（online=true）或离线时是否需要通知本会话：只通知最近通信过、且状态确实变化的对端
+    pub fn should_notify(&mut self, peer: Ipv4Addr, online: bool, now: Instant) -> bool {
+        let Some(contact) = self.contacts.get_mut(&peer) else { return false };
+        if now.duration_since(contact.last_seen) >= RECENT_WINDOW {
+            self.contacts.remove(&peer);
+            return false;
+        }
+        if contact.offline != online {
+            return false;
+        }
+        contact.offline = !online;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+    #[test]
+    fn test_offline_then_online() {
+        let now = Instant::now();
+        let mut peers = RecentPeers::default();
+        // 没通信过的对端不通知
+        assert!(!peers.should_notify(PEER, false, now));
+
+        peers.touch(PEER, now);
+        assert!(!peers.should_notify(PEER, true, now));
+        assert!(peers.should_notify(PEER, false, now));
+        assert!(!peers.should_notify(PEER, false, now));
+        assert!(peers.should_notify(PEER, true, now));
+        assert!(!peers.should_notify(PEER, true, now));
+
+        // 太久没通信的对端不再通知
+        assert!(!peers.should_notify(PEER, false, now + RECENT_WINDOW));
+        assert_eq!(peers.contacts.len(), 0);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let now = Instant::now();
+        let mut peers = RecentPeers::default();
+        for i in 0..MAX_RECENT_PEERS as u32 {
+            peers.touch(Ipv4Addr::from(0x0a00_0000 + i), now);
+        }
+        // 满了以后新对端不记录，已有的对端照常更新
+        let newcomer = Ipv4Addr::new(10, 1, 0, 1);
+        peers.touch(newcomer, now);
+        assert_eq!(peers.contacts.len(), MAX_RECENT_PEERS);
+        assert!(!peers.should_notify(newcomer, false, now));
+        // 过期后腾出空间
+        peers.touch(newcomer, now + RECENT_WINDOW);
+        assert_eq!(peers.contacts.len(), 1);
+        assert!(peers.should_notify(newcomer, false, now + RECENT_WINDOW));
+    }
+}
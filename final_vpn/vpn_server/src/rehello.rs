@@ -0,0 +1,102 @@
+// vpn_server/src/rehello.rs
+// 同一地址上的重新握手：新会话证明持有会话密钥（ClientFinish）之前，已确认的旧会话照常工作
+//
+// 重放或伪造来源地址的 ClientHello 同样能让服务端算出 ServerHello，但发送方拿不到会话密钥，
+// 发不出有效的 ClientFinish。这类新会话先暂存在这里，ClientFinish 验证通过后才替换旧会话、
+// 作废旧会话的恢复票据；超时仍未确认的直接丢弃，旧会话不受影响。
+// （其他地址上同一身份的旧会话由 Session::takeover 在确认后接替，见 handle_client_finish）
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use vpn_core::handshake::verify_client_finish;
+
+struct Staged<S> {
+    session: S,
+    session_key: [u8; 32],
+    staged_at: Instant,
+}
+
+/// 等待 ClientFinish 的重新握手（每个地址只保留最新的一个）
+pub struct PendingRehellos<S> {
+    slots: HashMap<SocketAddr, Staged<S>>,
+}
+
+impl<S> Default for PendingRehellos<S> {
+    fn default() -> Self {
+        Self { slots: HashMap::new() }
+    }
+}
+
+impl<S> PendingRehellos<S> {
+    /// 暂存新会话；同一地址上之前暂存的会话被丢弃
+    pub fn stage(&mut self, addr: SocketAddr, session: S, session_key: [u8; 32]) {
+        self.slots.insert(addr, Staged { session, session_key, staged_at: Instant::now() });
+    }
+
+    /// ClientFinish 能用暂存会话的密钥验证时取出该会话，否则保持不变（可能是旧会话重发的 ClientFinish）
+    pub fn confirm(&mut self, addr: SocketAddr, encrypted_confirm: &[u8]) -> Option<S> {
+        let staged = self.slots.get(&addr)?;
+        verify_client_finish(encrypted_confirm, &staged.session_key).ok()?;
+        self.slots.remove(&addr).map(|s| s.session)
+    }
+
+    /// 丢弃暂存超过 timeout 仍未确认的会话，返回它们的地址
+    pub fn expire(&mut self, timeout: Duration) -> Vec<SocketAddr> {
+        let expired: Vec<SocketAddr> = self.slots.iter()
+            .filter(|(_, s)| s.staged_at.elapsed() >= timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &expired {
+            self.slots.remove(addr);
+        }
+        expired
+    }
+
+    pub fn count(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vpn_core::handshake::{HandshakeMessage, client_finish};
+
+    fn finish(session_key: &[u8; 32]) -> Vec<u8> {
+        let HandshakeMessage::ClientFinish { encrypted_confirm } = client_finish(session_key).unwrap() else { unreachable!() };
+        encrypted_confirm
+    }
+
+    #[test]
+    fn test_rehello_replaces_only_after_finish() {
+        let addr: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let mut sessions: HashMap<SocketAddr, &str> = HashMap::from([(addr, "confirmed")]);
+        let mut pending = PendingRehellos::default();
+
+        // 同一地址的第二个 ClientHello（重放或真正的重新握手）只是暂存，已确认的会话不变
+        pending.stage(addr, "rehello", [2u8; 32]);
+        assert_eq!(sessions[&addr], "confirmed");
+        assert_eq!(pending.count(), 1);
+
+        // 旧会话密钥的 ClientFinish（旧会话的重发）或伪造的确认不会换掉旧会话
+        assert!(pending.confirm(addr, &finish(&[1u8; 32])).is_none());
+        assert!(pending.confirm(addr, &[0u8; 40]).is_none());
+        assert!(pending.confirm("198.51.100.8:40000".parse().unwrap(), &finish(&[2u8; 32])).is_none());
+        assert_eq!(sessions[&addr], "confirmed");
+        assert_eq!(pending.count(), 1);
+
+        // 新会话证明持有密钥后才替换
+        let session = pending.confirm(addr, &finish(&[2u8; 32])).unwrap();
+        sessions.insert(addr, session);
+        assert_eq!(sessions[&addr], "rehello");
+        assert_eq!(pending.count(), 0);
+
+        // 一直不确认的在超时后丢弃
+        pending.stage(addr, "replayed", [3u8; 32]);
+        assert!(pending.expire(Duration::from_secs(60)).is_empty());
+        assert_eq!(pending.expire(Duration::ZERO), vec![addr]);
+        assert_eq!(pending.count(), 0);
+    }
+}
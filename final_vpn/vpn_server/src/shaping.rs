@@ -0,0 +1,219 @@
+// vpn_server/src/shaping.rs
+// 内核流量整形：在 TUN 接口上建立 tc HTB 层级，每个客户端一个 class（按虚拟 IP 匹配）
+//
+//   1:    htb 根 qdisc（未匹配的流量进入 1:2）
+//   1:1   总带宽（--tc-rate）
+//   1:2   默认 class：服务端自身及未上线地址的流量
+//   1:1xx 客户端 class，xx 为虚拟 IP 最后一个字节的十六进制，叶子挂 fq_codel
+//
+// TUN 的出方向就是服务端发往客户端的下行流量，所以这里整形的是下行；
+// 客户端 class 在首次上线时创建，之后一直保留（空闲 class 不占用带宽，可以被其他 class 借用）。
+
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::sync::Mutex;
+
+use anyhow::{Result, anyhow};
+use vpn_core::dryrun::{Category, Plan};
+use vpn_core::tuning::parse_rate;
+
+/// 客户端默认保证带宽
+const DEFAULT_CLIENT_RATE: u64 = 1_000_000;
+/// 默认 class 的 minor 号
+const DEFAULT_CLASS: u16 = 0x2;
+
+/// 单个 class 的保证带宽和上限（bit/s）
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClassRate {
+    pub rate: u64,
+    pub ceil: u64,
+}
+
+/// 解析 `--tc-class <虚拟IP>=<rate>[:<ceil>]`，未指定 ceil 时使用 default_ceil
+fn parse_class(spec: &str, default_ceil: u64) -> Result<(Ipv4Addr, ClassRate)> {
+    let (ip, rates) = spec.split_once('=').ok_or_else(|| anyhow!("无效的 --tc-class: {}（格式 10.0.0.2=10mbit:50mbit）", spec))?;
+    let ip: Ipv4Addr = ip.parse().map_err(|_| anyhow!("无效的虚拟 IP: {}", ip))?;
+    let (rate, ceil) = match rates.split_once(':') {
+        Some((rate, ceil)) => (parse_rate(rate)?, parse_rate(ceil)?),
+        None => (parse_rate(rates)?, default_ceil),
+    };
+    Ok((ip, ClassRate { rate, ceil: ceil.max(rate) }))
+}
+
+/// 客户端 class 的 minor 号
+fn class_minor(vip: Ipv4Addr) -> u16 {
+    0x100 + vip.octets()[3] as u16
+}
+
+/// 整形配置
+#[derive(Debug, Clone)]
+pub struct ShapingConfig {
+    /// 总带宽
+    pub total: u64,
+    /// 未单独配置的客户端使用的速率
+    pub default_class: ClassRate,
+    /// 按虚拟 IP 单独配置的速率
+    pub overrides: HashMap<Ipv4Addr, ClassRate>,
+}
+
+impl ShapingConfig {
+    /// 从命令行参数构建，未指定 --tc-rate 时返回 None
+    ///
+    /// * `--tc-rate <rate>`：TUN 下行总带宽，开启整形
+    /// * `--tc-client-rate <rate>`：每个客户端的默认保证带宽，默认 1mbit
+    /// * `--tc-client-ceil <rate>`：每个客户端的默认上限，默认等于总带宽
+    /// * `--tc-class <虚拟IP>=<rate>[:<ceil>]`：单个客户端的速率，可重复
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(total) = crate::arg_value(args, "--tc-rate") else {
+            return Ok(None);
+        };
+        let total = parse_rate(&total)?;
+        let rate = match crate::arg_value(args, "--tc-client-rate") {
+            Some(r) => parse_rate(&r)?,
+            None => DEFAULT_CLIENT_RATE.min(total),
+        };
+        let ceil = match crate::arg_value(args, "--tc-client-ceil") {
+            Some(c) => parse_rate(&c)?,
+            None => total,
+        };
+
+        let overrides = crate::arg_values(args, "--tc-class")
+            .iter()
+            .map(|spec| parse_class(spec, ceil))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let guaranteed: u64 = overrides.values().map(|c| c.rate).sum();
+        if guaranteed > total {
+            println!("⚠️  --tc-class 的保证带宽之和超过了 --tc-rate，HTB 无法同时满足");
+        }
+
+        Ok(Some(Self {
+            total,
+            default_class: ClassRate { rate: rate.min(total), ceil: ceil.min(total).max(rate.min(total)) },
+            overrides,
+        }))
+    }
+
+    /// 某个客户端的速率
+    pub fn class_for(&self, vip: Ipv4Addr) -> ClassRate {
+        self.overrides.get(&vip).copied().unwrap_or(self.default_class)
+    }
+}
+
+/// 在 TUN 接口上维护 HTB 层级
+pub struct TrafficShaper {
+    dev: String,
+    pub config: ShapingConfig,
+    /// 已创建 class 的客户端
+    classes: Mutex<HashSet<Ipv4Addr>>,
+}
+
+impl TrafficShaper {
+    pub fn new(dev: &str, config: ShapingConfig) -> Self {
+        Self { dev: dev.to_string(), config, classes: Mutex::new(HashSet::new()) }
+    }
+
+    /// 建立根 qdisc、总带宽 class 和默认 class（会替换接口上已有的根 qdisc）
+    pub fn install(&self) -> Result<()> {
+        for args in root_commands(&self.dev, &self.config) {
+            tc(&args)?;
+        }
+        Ok(())
+    }
+
+    /// 试运行：列出 install 执行的 tc 命令
+    pub fn plan(&self, plan: &mut Plan) {
+        for args in root_commands(&self.dev, &self.config) {
+            plan.command(Category::Other, "tc", &args);
+        }
+        plan.note(Category::Other, format!("客户端上线时在 {} 上为其创建 HTB class 和 u32 过滤器", self.dev));
+    }
+
+    /// 客户端上线时为其创建 class 和过滤器（已创建过则直接返回）
+    pub fn add_client(&self, vip: Ipv4Addr) {
+        if !self.classes.lock().unwrap().insert(vip) {
+            return;
+        }
+        let class = self.config.class_for(vip);
+        for args in client_commands(&self.dev, vip, class) {
+            if let Err(e) = tc(&args) {
+                eprintln!("⚠️  为 {} 创建 tc class 失败: {}", vip, e);
+                self.classes.lock().unwrap().remove(&vip);
+                return;
+            }
+        }
+        println!("🚦 {} 下行整形: 保证 {} / 上限 {}", vip, format_rate(class.rate), format_rate(class.ceil));
+    }
+
+    /// 删除整个 HTB 层级（忽略错误，接口可能已经不存在）
+    pub fn teardown(&self) {
+        let _ = Command::new("tc").args(["qdisc", "del", "dev", &self.dev, "root"]).output();
+    }
+}
+
+fn format_rate(bits: u64) -> String {
+    format!("{}bit", bits)
+}
+
+fn root_commands(dev: &str, config: &ShapingConfig) -> Vec<Vec<String>> {
+    let total = format_rate(config.total);
+    let default_rate = format_rate(config.default_class.rate);
+    [
+        format!("qdisc replace dev {} root handle 1: htb default {:x}", dev, DEFAULT_CLASS),
+        format!("class replace dev {} parent 1: classid 1:1 htb rate {} ceil {}", dev, total, total),
+        format!("class replace dev {} parent 1:1 classid 1:{:x} htb rate {} ceil {}", dev, DEFAULT_CLASS, default_rate, total),
+        format!("qdisc replace dev {} parent 1:{:x} fq_codel", dev, DEFAULT_CLASS),
+    ]
+    .iter()
+    .map(|cmd| cmd.split_whitespace().map(String::from).collect())
+    .collect()
+}
+
+fn client_commands(dev: &str, vip: Ipv4Addr, class: ClassRate) -> Vec<Vec<String>> {
+    let minor = class_minor(vip);
+    let vip6 = vpn_core::local_tun::tunnel_ipv6(vip);
+    [
+        format!("class replace dev {} parent 1:1 classid 1:{:x} htb rate {} ceil {}", dev, minor, format_rate(class.rate), format_rate(class.ceil)),
+        format!("qdisc replace dev {} parent 1:{:x} fq_codel", dev, minor),
+        format!("filter add dev {} parent 1: protocol ip prio 1 u32 match ip dst {}/32 flowid 1:{:x}", dev, vip, minor),
+        format!("filter add dev {} parent 1: protocol ipv6 prio 2 u32 match ip6 dst {}/128 flowid 1:{:x}", dev, vip6, minor),
+    ]
+    .iter()
+    .map(|cmd| cmd.split_whitespace().map(String::from).collect())
+    .collect()
+}
+
+fn tc(args: &[String]) -> Result<()> {
+    let output = Command::new("tc").args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!("tc {} 失败: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rates_and_commands() {
+        assert_eq!(parse_rate("100mbit").unwrap(), 100_000_000);
+        assert_eq!(parse_rate("512Kbit").unwrap(), 512_000);
+        assert!(parse_rate("100mb").is_err());
+        assert!(parse_rate("0mbit").is_err());
+
+        let args: Vec<String> = ["--tc-rate", "100mbit", "--tc-class", "10.0.0.5=10mbit:50mbit", "--tc-class", "10.0.0.6=20mbit"]
+            .iter().map(|s| s.to_string()).collect();
+        let config = ShapingConfig::from_args(&args).unwrap().unwrap();
+        let vip: Ipv4Addr = "10.0.0.5".parse().unwrap();
+        assert_eq!(config.class_for(vip), ClassRate { rate: 10_000_000, ceil: 50_000_000 });
+        assert_eq!(config.class_for("10.0.0.6".parse().unwrap()).ceil, 100_000_000);
+        assert_eq!(config.class_for("10.0.0.9".parse().unwrap()), ClassRate { rate: 1_000_000, ceil: 100_000_000 });
+
+        let cmds = client_commands("tun0", vip, config.class_for(vip));
+        assert_eq!(cmds[0].join(" "), "class replace dev tun0 parent 1:1 classid 1:105 htb rate 10000000bit ceil 50000000bit");
+        assert!(cmds[2].join(" ").ends_with("match ip dst 10.0.0.5/32 flowid 1:105"));
+        assert!(cmds[3].join(" ").contains("fd00::a00:5/128"));
+    }
+}
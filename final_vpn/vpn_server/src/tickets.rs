@@ -0,0 +1,166 @@
+// vpn_server/src/tickets.rs
+// 会话恢复票据（--session-resume）
+//
+// 会话建立后为它发一张票据（见 vpn_core::resume），票据记录恢复会话所需的全部状态：
+// 会话密钥、客户端身份、虚拟 IP 和外部认证的结果。会话存活期间票据一直有效（客户端进程崩溃后，
+// 服务端要等 Echo 超时才发现），会话因超时、被替换等原因结束后再保留 RESUME_TTL；
+// 客户端主动断开时立即作废。票据只保存在内存中，服务端重启后客户端回退到完整握手。
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use vpn_core::resume::{self, MAX_CLOCK_SKEW, RESUME_TTL, TicketId};
+use vpn_core::handshake::Capabilities;
+
+/// 票据对应的会话状态
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub session_key: [u8; 32],
+    pub client_id: String,
+    pub identity_key: [u8; 32],
+    pub virtual_ip: Option<Ipv4Addr>,
+    /// 外部认证后端返回的身份
+    pub identity: Option<String>,
+    /// 完整握手时协商的功能，恢复的会话沿用
+    pub capabilities: Option<Capabilities>,
+    /// 当前使用这张票据的会话地址
+    pub addr: SocketAddr,
+    /// 最近一次被接受的 Resume 证明中的时间戳，更早或相同的证明视为重放
+    last_timestamp: u64,
+    /// 会话结束后的过期时间；会话存活期间为 None
+    expires: Option<Instant>,
+}
+
+impl Ticket {
+    pub fn new(session_key: [u8; 32], client_id: String, identity_key: [u8; 32], virtual_ip: Option<Ipv4Addr>, identity: Option<String>, capabilities: Option<Capabilities>, addr: SocketAddr) -> Self {
+        Self { session_key, client_id, identity_key, virtual_ip, identity, capabilities, addr, last_timestamp: 0, expires: None }
+    }
+}
+
+/// 票据表
+pub struct TicketStore {
+    tickets: HashMap<TicketId, Ticket>,
+    ttl: Duration,
+}
+
+impl TicketStore {
+    /// 指定 --session-resume 时启用
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        args.iter().any(|a| a == "--session-resume").then(|| Self::new(RESUME_TTL))
+    }
+
+    pub fn new(ttl: Duration) -> Self {
+        Self { tickets: HashMap::new(), ttl }
+    }
+
+    /// 下发给客户端的票据有效期（秒）
+    pub fn lifetime_secs(&self) -> u32 {
+        self.ttl.as_secs() as u32
+    }
+
+    /// 为新会话发一张票据
+    pub fn issue(&mut self, ticket: Ticket) -> TicketId {
+        self.purge();
+        let id: TicketId = rand::random();
+        self.tickets.insert(id, ticket);
+        id
+    }
+
+    /// 密钥轮换后更新票据中的会话密钥
+    pub fn update_key(&mut self, id: &TicketId, session_key: [u8; 32]) {
+        if let Some(ticket) = self.tickets.get_mut(id) {
+            ticket.session_key = session_key;
+        }
+    }
+
+    /// 会话结束（非主动断开）：票据再保留 ttl；票据已被恢复到其他地址时忽略
+    pub fn detach(&mut self, id: &TicketId, addr: SocketAddr) {
+        let expires = Instant::now() + self.ttl;
+        if let Some(ticket) = self.tickets.get_mut(id).filter(|t| t.addr == addr) {
+            ticket.expires = Some(expires);
+        }
+    }
+
+    /// 客户端主动断开：立即作废票据
+    pub fn revoke(&mut self, id: &TicketId, addr: SocketAddr) {
+        if self.tickets.get(id).is_some_and(|t| t.addr == addr) {
+            self.tickets.remove(id);
+        }
+    }
+
+    /// 校验 Resume：票据有效、证明能用会话密钥解开且时间戳新鲜
+    ///
+    /// 成功时票据转到新地址、会话密钥换成 resume::resumed_key 派生的新密钥，返回更新后的票据
+    pub fn redeem(&mut self, id: &TicketId, proof: &[u8], addr: SocketAddr, now_unix: u64) -> Result<Ticket> {
+        self.purge();
+        let ticket = self.tickets.get_mut(id).ok_or_else(|| anyhow!("票据不存在或已过期"))?;
+        let timestamp = resume::open_resume_proof(&ticket.session_key, id, proof)?;
+        if timestamp.abs_diff(now_unix) > MAX_CLOCK_SKEW {
+            return Err(anyhow!("证明的时间戳与服务端时钟相差 {} 秒", timestamp.abs_diff(now_unix)));
+        }
+        if timestamp <= ticket.last_timestamp {
+            return Err(anyhow!("重放的会话恢复请求"));
+        }
+        ticket.last_timestamp = timestamp;
+        ticket.session_key = resume::resumed_key(&ticket.session_key, proof);
+        ticket.addr = addr;
+        ticket.expires = None;
+        Ok(ticket.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tickets.len()
+    }
+
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.tickets.retain(|_, t| t.expires.is_none_or(|e| e > now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_lifecycle() {
+        let mut store = TicketStore::new(Duration::from_secs(300));
+        let old_addr: SocketAddr = "198.51.100.1:40000".parse().unwrap();
+        let new_addr: SocketAddr = "198.51.100.1:40001".parse().unwrap();
+        let key = [5u8; 32];
+        let id = store.issue(Ticket::new(key, "client".to_string(), [6u8; 32], Some(Ipv4Addr::new(10, 0, 0, 2)), None, None, old_addr));
+        let now = 1_700_000_000;
+
+        // 错误的密钥、过旧的时间戳被拒绝
+        let forged = resume::resume_proof(&[9u8; 32], &id, now).unwrap();
+        assert!(store.redeem(&id, &forged, new_addr, now).is_err());
+        let stale = resume::resume_proof(&key, &id, now - MAX_CLOCK_SKEW - 1).unwrap();
+        assert!(store.redeem(&id, &stale, new_addr, now).is_err());
+
+        let proof = resume::resume_proof(&key, &id, now).unwrap();
+        let ticket = store.redeem(&id, &proof, new_addr, now).unwrap();
+        assert_eq!(ticket.addr, new_addr);
+        assert_eq!(ticket.session_key, resume::resumed_key(&key, &proof));
+        // 同一证明重放无效
+        assert!(store.redeem(&id, &proof, "203.0.113.9:1".parse().unwrap(), now).is_err());
+
+        // 旧地址上的会话结束不影响已恢复的票据
+        store.detach(&id, old_addr);
+        store.revoke(&id, old_addr);
+        assert_eq!(store.len(), 1);
+
+        // 会话超时后票据只保留 ttl
+        let mut short = TicketStore::new(Duration::ZERO);
+        let id2 = short.issue(Ticket::new(key, "client".to_string(), [6u8; 32], None, None, None, old_addr));
+        short.detach(&id2, old_addr);
+        let proof = resume::resume_proof(&key, &id2, now).unwrap();
+        assert!(short.redeem(&id2, &proof, new_addr, now).is_err());
+        assert_eq!(short.len(), 0);
+
+        // 主动断开立即作废
+        store.revoke(&id, new_addr);
+        assert_eq!(store.len(), 0);
+    }
+}
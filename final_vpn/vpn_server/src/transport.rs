@@ -0,0 +1,465 @@
+// vpn_server/src/transport.rs
+// 服务端的数据报传输：监听的 UDP socket，加上可选的 AF_XDP 加速（--af-xdp，见 fastpath 和 xsk 模块）
+//
+// 开启 AF_XDP 后，XDP 程序把发往监听端口、需要用户态处理的数据报重定向到各接收队列上的 AF_XDP socket：
+// * 接收：每个 socket 一个任务，解析以太网/IP/UDP 头后把载荷交给接收循环，同时记下回复这个来源
+//   需要的二层信息（双方 MAC、本机地址、所在队列）
+// * 发送：目的端点有二层信息、数据报放得进一帧时，构造完整的帧从同一队列发出；否则走 UDP socket
+//
+// UDP socket 一直保留：IP 分片、没有 AF_XDP socket 的队列上的数据报、放不进一帧的大包，
+// 以及 AF_XDP 不可用时的全部流量都经由它，所以 AF_XDP 的任何失败都会自动回退，不影响连通。
+// 绕过协议栈意味着这些数据报不经过 iptables/nftables 和连接跟踪；UDP 校验和由网卡和 AEAD 兜底，这里不再检查。
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use anyhow::Result;
+use tokio::io::unix::AsyncFd;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use vpn_core::engine::DatagramSocket;
+use vpn_core::gso::{self, GsoBatch};
+use vpn_core::packet::{fold, pseudo_header_sum, recompute_ipv4_header_checksum, sum_words};
+
+use crate::fastpath::{FastPath, MAX_QUEUES};
+use crate::xsk::{self, FRAME_SIZE, XskSocket};
+
+/// AF_XDP 收到、还没被接收循环取走的数据报上限（相当于 socket 接收缓冲区）
+const INCOMING_QUEUE: usize = 8192;
+/// 最多记住多少个来源端点的二层信息，满了清空重新学习
+const MAX_ROUTES: usize = 65536;
+
+const ETH_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const UDP_HEADER_LEN: usize = 8;
+const PROTO_UDP: u8 = 17;
+const DEFAULT_TTL: u8 = 64;
+
+/// 回复一个来源端点需要的二层信息，从它发来的帧里学到
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct L2Route {
+    /// 本机网卡的 MAC（收到的帧的目的 MAC）
+    local_mac: [u8; 6],
+    /// 下一跳的 MAC（收到的帧的源 MAC，通常是网关）
+    peer_mac: [u8; 6],
+    /// 对端发往的本机地址
+    local_ip: IpAddr,
+    queue: u32,
+}
+
+/// 从帧中解析出的数据报
+#[derive(Debug, PartialEq)]
+struct Incoming {
+    /// 载荷在帧中的位置
+    payload: Range<usize>,
+    src: SocketAddr,
+    route: L2Route,
+}
+
+/// AF_XDP 的收发计数
+#[derive(Debug, Default)]
+pub struct XdpCounters {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+    /// 没有二层信息、放不进一帧或没有空闲帧，改走 UDP socket 的发送
+    pub fallback: AtomicU64,
+    /// 接收循环来不及处理而丢弃的数据报
+    pub overflow: AtomicU64,
+}
+
+impl XdpCounters {
+    pub fn report(&self) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        format!(
+            "AF_XDP: 收 {}  发 {}  回退到 UDP socket {}  接收溢出 {}\n",
+            get(&self.received), get(&self.sent), get(&self.fallback), get(&self.overflow)
+        )
+    }
+}
+
+struct XdpPath {
+    sockets: Vec<Arc<XskSocket>>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr)>>,
+    routes: Arc<std::sync::Mutex<HashMap<SocketAddr, L2Route>>>,
+    counters: Arc<XdpCounters>,
+}
+
+/// 服务端收发数据报的传输
+pub struct Transport {
+    udp: UdpSocket,
+    xdp: Option<XdpPath>,
+    /// UDP GSO：TUN 任务一批之内暂存的数据报，由 flush 合并发送
+    gso: Option<std::sync::Mutex<GsoBatch>>,
+    /// GSO 发送失败（内核或网卡不支持）后停用
+    gso_failed: AtomicBool,
+}
+
+impl Transport {
+    pub fn new(udp: UdpSocket) -> Self {
+        Self { udp, xdp: None, gso: None, gso_failed: AtomicBool::new(false) }
+    }
+
+    /// 开启 UDP GSO：queue 暂存的数据报在 flush 时按目的端点合并发送
+    pub fn enable_gso(&mut self) {
+        self.gso = Some(std::sync::Mutex::new(GsoBatch::new()));
+    }
+
+    /// 在快速路径的接口上为每个接收队列打开 AF_XDP socket 并登记到 XDP 程序，返回队列数
+    ///
+    /// 失败时不改变传输，所有数据报继续走 UDP socket
+    pub fn enable_af_xdp(&mut self, fastpath: &FastPath) -> Result<u32> {
+        let interface = &fastpath.config.interface;
+        let ifindex = xsk::ifindex(interface)?;
+        let queues = xsk::rx_queues(interface)?.min(MAX_QUEUES);
+        let mut sockets = Vec::new();
+        for queue in 0..queues {
+            let socket = XskSocket::bind(ifindex, queue)
+                .map_err(|e| anyhow::anyhow!("无法在 {} 的队列 {} 上创建 AF_XDP socket: {}", interface, queue, e))?;
+            sockets.push(Arc::new(socket));
+        }
+        // 全部创建成功后再登记；中途失败时已登记的 socket 随之关闭，内核自动删除对应的表项
+        for socket in &sockets {
+            fastpath.register_xsk(socket.queue, socket.fd())?;
+        }
+
+        let local = self.udp.local_addr()?;
+        let (tx, rx) = mpsc::channel(INCOMING_QUEUE);
+        let routes = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let counters = Arc::new(XdpCounters::default());
+        for socket in &sockets {
+            let fd = AsyncFd::new(socket.fd())?;
+            tokio::spawn(receive(fd, socket.clone(), local, tx.clone(), routes.clone(), counters.clone()));
+        }
+        self.xdp = Some(XdpPath { sockets, incoming: tokio::sync::Mutex::new(rx), routes, counters });
+        Ok(queues)
+    }
+
+    /// AF_XDP 的计数，未开启时返回 None
+    pub fn xdp_counters(&self) -> Option<&XdpCounters> {
+        self.xdp.as_ref().map(|xdp| &*xdp.counters)
+    }
+
+    /// 发送一个数据报：能走 AF_XDP 时直接构造帧发出，否则走 UDP socket
+    pub async fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        if let Some(xdp) = &self.xdp {
+            if xdp.send(data, addr, self.udp.local_addr()?.port()) {
+                return Ok(data.len());
+            }
+            xdp.counters.fallback.fetch_add(1, Ordering::Relaxed);
+        }
+        self.udp.send_to(data, addr).await
+    }
+
+    /// 暂存一个数据报，等 flush 时发出；没有开启 GSO 时立即发送
+    pub async fn queue(&self, data: &[u8], addr: SocketAddr) {
+        match &self.gso {
+            Some(batch) if !self.gso_failed.load(Ordering::Relaxed) => batch.lock().unwrap().push(addr, data),
+            _ => {
+                let _ = self.send_to(data, addr).await;
+            }
+        }
+    }
+
+    /// 发出暂存的数据报：发往同一端点的等长数据报用一次 sendmsg 发出，其余逐个发送
+    pub async fn flush(&self) {
+        let Some(batch) = &self.gso else { return };
+        let groups = batch.lock().unwrap().take();
+        for segments in groups {
+            // 走 AF_XDP 的端点逐帧发送
+            let xdp = self.xdp.as_ref().is_some_and(|xdp| xdp.has_route(segments.addr));
+            if segments.count() > 1 && !xdp && !self.gso_failed.load(Ordering::Relaxed) {
+                match gso::send(&self.udp, &segments).await {
+                    Ok(()) => continue,
+                    // 段长超过出接口 MTU：只有这一组逐个发送（由协议栈分片）
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {}
+                    Err(e) => {
+                        if !self.gso_failed.swap(true, Ordering::Relaxed) {
+                            eprintln!("⚠️  UDP GSO 发送失败，改为逐个发送: {}", e);
+                        }
+                    }
+                }
+            }
+            for datagram in segments.datagrams() {
+                let _ = self.send_to(datagram, segments.addr).await;
+            }
+        }
+    }
+}
+
+impl XdpPath {
+    fn has_route(&self, addr: SocketAddr) -> bool {
+        self.routes.lock().unwrap().contains_key(&addr)
+    }
+
+    fn send(&self, data: &[u8], addr: SocketAddr, port: u16) -> bool {
+        let Some(route) = self.routes.lock().unwrap().get(&addr).copied() else { return false };
+        let dst = SocketAddr::new(canonical(addr.ip()), addr.port());
+        if route.local_ip.is_ipv4() != dst.is_ipv4() {
+            return false;
+        }
+        let Some(len) = frame_len(&route, data.len()) else { return false };
+        let Some(socket) = self.sockets.get(route.queue as usize) else { return false };
+        let sent = socket.send(len, |frame| build_frame(frame, &route, port, dst, data)).is_ok();
+        if sent {
+            self.counters.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+}
+
+impl DatagramSocket for Transport {
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let Some(xdp) = &self.xdp else {
+            return self.udp.recv_from(buf).await;
+        };
+        let mut incoming = xdp.incoming.lock().await;
+        tokio::select! {
+            received = self.udp.recv_from(buf) => received,
+            Some((data, src)) = incoming.recv() => Ok((copy_truncated(&data, buf), src)),
+        }
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some(xdp) = &self.xdp
+            && let Ok(mut incoming) = xdp.incoming.try_lock()
+            && let Ok((data, src)) = incoming.try_recv()
+        {
+            return Ok((copy_truncated(&data, buf), src));
+        }
+        self.udp.try_recv_from(buf)
+    }
+}
+
+/// 与 recv_from 一致：超出缓冲区的部分被截断（接收循环据此判断截断）
+fn copy_truncated(data: &[u8], buf: &mut [u8]) -> usize {
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    n
+}
+
+/// 一个 AF_XDP socket 的接收任务
+async fn receive(
+    fd: AsyncFd<i32>,
+    socket: Arc<XskSocket>,
+    local: SocketAddr,
+    tx: mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    routes: Arc<std::sync::Mutex<HashMap<SocketAddr, L2Route>>>,
+    counters: Arc<XdpCounters>,
+) {
+    let mut batch = Vec::new();
+    loop {
+        let Ok(mut guard) = fd.readable().await else { return };
+        let frames = socket.recv_batch(|frame| {
+            if let Some(incoming) = parse_frame(frame, socket.queue, local) {
+                batch.push((frame[incoming.payload].to_vec(), incoming.src, incoming.route));
+            }
+        });
+        if frames == 0 {
+            guard.clear_ready();
+            continue;
+        }
+        {
+            let mut routes = routes.lock().unwrap();
+            for (_, src, route) in &batch {
+                if routes.get(src) != Some(route) {
+                    if routes.len() >= MAX_ROUTES {
+                        routes.clear();
+                    }
+                    routes.insert(*src, *route);
+                }
+            }
+        }
+        counters.received.fetch_add(batch.len() as u64, Ordering::Relaxed);
+        for (data, src, _) in batch.drain(..) {
+            if tx.try_send((data, src)).is_err() {
+                counters.overflow.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// IPv4 映射的 IPv6 地址还原为 IPv4
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        v4 => v4,
+    }
+}
+
+/// 解析发往 local（监听地址）的 UDP 帧；来源地址按监听 socket 的地址族表示（双栈 socket 上 IPv4 为映射地址）
+fn parse_frame(frame: &[u8], queue: u32, local: SocketAddr) -> Option<Incoming> {
+    let eth = frame.get(..ETH_HEADER_LEN)?;
+    let (src, dst, udp) = match u16::from_be_bytes([eth[12], eth[13]]) {
+        ETHERTYPE_IPV4 => {
+            let ip = &frame[ETH_HEADER_LEN..];
+            let header_len = vpn_core::packet::ipv4_header_len(ip)?;
+            let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+            if ip[9] != PROTO_UDP || fragmented {
+                return None;
+            }
+            let src = IpAddr::V4(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]));
+            let dst = IpAddr::V4(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]));
+            (src, dst, ETH_HEADER_LEN + header_len)
+        }
+        ETHERTYPE_IPV6 => {
+            let ip = frame.get(ETH_HEADER_LEN..ETH_HEADER_LEN + 40)?;
+            if ip[6] != PROTO_UDP {
+                return None;
+            }
+            let src = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[8..24]).ok()?));
+            let dst = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&ip[24..40]).ok()?));
+            (src, dst, ETH_HEADER_LEN + 40)
+        }
+        _ => return None,
+    };
+    let header = frame.get(udp..udp + UDP_HEADER_LEN)?;
+    if u16::from_be_bytes([header[2], header[3]]) != local.port() {
+        return None;
+    }
+    // 监听在具体地址上时，发往本机其他地址的数据报内核同样不会交给 socket
+    if !local.ip().is_unspecified() && canonical(local.ip()) != dst {
+        return None;
+    }
+    let udp_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if udp_len < UDP_HEADER_LEN || udp + udp_len > frame.len() {
+        return None;
+    }
+    let src_ip = match (src, local) {
+        (IpAddr::V4(v4), SocketAddr::V6(_)) => IpAddr::V6(v4.to_ipv6_mapped()),
+        _ => src,
+    };
+    let src = SocketAddr::new(src_ip, u16::from_be_bytes([header[0], header[1]]));
+    let route = L2Route {
+        local_mac: eth[0..6].try_into().ok()?,
+        peer_mac: eth[6..12].try_into().ok()?,
+        local_ip: dst,
+        queue,
+    };
+    Some(Incoming { payload: udp + UDP_HEADER_LEN..udp + udp_len, src, route })
+}
+
+/// 发给 route 所在链路的帧长度；放不进一帧时返回 None
+fn frame_len(route: &L2Route, payload_len: usize) -> Option<usize> {
+    let ip_header = if route.local_ip.is_ipv4() { 20 } else { 40 };
+    let len = ETH_HEADER_LEN + ip_header + UDP_HEADER_LEN + payload_len;
+    (len <= FRAME_SIZE).then_some(len)
+}
+
+/// 在 frame（长度为 frame_len）里构造以太网/IP/UDP 头和载荷；dst 的地址族必须与 route.local_ip 一致
+fn build_frame(frame: &mut [u8], route: &L2Route, src_port: u16, dst: SocketAddr, payload: &[u8]) {
+    frame[0..6].copy_from_slice(&route.peer_mac);
+    frame[6..12].copy_from_slice(&route.local_mac);
+    let ip = &mut frame[ETH_HEADER_LEN..];
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let header_len = match (route.local_ip, dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            let total = (20 + udp_len) as u16;
+            ip[..20].copy_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, DEFAULT_TTL, PROTO_UDP, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            ip[2..4].copy_from_slice(&total.to_be_bytes());
+            ip[12..16].copy_from_slice(&src.octets());
+            ip[16..20].copy_from_slice(&dst.octets());
+            recompute_ipv4_header_checksum(ip);
+            20
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            ip[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 0, PROTO_UDP, DEFAULT_TTL]);
+            ip[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+            ip[8..24].copy_from_slice(&to_v6(src).octets());
+            ip[24..40].copy_from_slice(&to_v6(dst).octets());
+            40
+        }
+    };
+    frame[12..14].copy_from_slice(&if header_len == 20 { ETHERTYPE_IPV4 } else { ETHERTYPE_IPV6 }.to_be_bytes());
+
+    let ip = &mut frame[ETH_HEADER_LEN..];
+    let (header, rest) = ip.split_at_mut(header_len);
+    let udp = &mut rest[..udp_len];
+    udp[0..2].copy_from_slice(&src_port.to_be_bytes());
+    udp[2..4].copy_from_slice(&dst.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
+    udp[6..8].copy_from_slice(&[0, 0]);
+    udp[UDP_HEADER_LEN..].copy_from_slice(payload);
+    let sum = !fold(sum_words(udp, pseudo_header_sum(header, PROTO_UDP, udp_len)));
+    // 计算结果为 0 时发送全 1（0 在 IPv4 中表示未计算）
+    let sum = if sum == 0 { 0xffff } else { sum };
+    udp[6..8].copy_from_slice(&sum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vpn_core::packet::{ipv4_header_checksum_valid, transport_checksum_valid};
+
+    const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 1];
+    const GATEWAY_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0xfe];
+
+    fn route(local_ip: &str) -> L2Route {
+        L2Route { local_mac: SERVER_MAC, peer_mac: GATEWAY_MAC, local_ip: local_ip.parse().unwrap(), queue: 0 }
+    }
+
+    /// 构造一个从 peer 发往 route.local_ip:9000 的帧（即反过来调用 build_frame）
+    fn frame_from(peer: SocketAddr, local_ip: &str, payload: &[u8]) -> Vec<u8> {
+        let reverse = L2Route { local_mac: GATEWAY_MAC, peer_mac: SERVER_MAC, local_ip: canonical(peer.ip()), queue: 0 };
+        let mut frame = vec![0; frame_len(&reverse, payload.len()).unwrap()];
+        build_frame(&mut frame, &reverse, peer.port(), SocketAddr::new(local_ip.parse().unwrap(), 9000), payload);
+        frame
+    }
+
+    #[test]
+    fn test_build_frame() {
+        let peer: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let route4 = route("203.0.113.1");
+        let mut frame = vec![0; frame_len(&route4, 5).unwrap()];
+        build_frame(&mut frame, &route4, 9000, peer, b"hello");
+        assert_eq!(&frame[0..12], [GATEWAY_MAC, SERVER_MAC].concat());
+        let ip = &frame[ETH_HEADER_LEN..];
+        assert!(ipv4_header_checksum_valid(ip));
+        assert_eq!(transport_checksum_valid(ip), Some(true));
+        assert_eq!(&ip[16..20], &[198, 51, 100, 7]);
+        assert_eq!(&ip[28..], b"hello");
+
+        let route6 = route("2001:db8::1");
+        let peer6: SocketAddr = "[2001:db8::7]:40000".parse().unwrap();
+        let mut frame = vec![0; frame_len(&route6, 5).unwrap()];
+        build_frame(&mut frame, &route6, 9000, peer6, b"hello");
+        assert_eq!(transport_checksum_valid(&frame[ETH_HEADER_LEN..]), Some(true));
+
+        // 放不进一帧的数据报走 UDP socket
+        assert_eq!(frame_len(&route4, FRAME_SIZE), None);
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let peer: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+        let frame = frame_from(peer, "203.0.113.1", b"RV\x01");
+        let any: SocketAddr = "0.0.0.0:9000".parse().unwrap();
+        let incoming = parse_frame(&frame, 3, any).unwrap();
+        assert_eq!(&frame[incoming.payload.clone()], b"RV\x01");
+        assert_eq!(incoming.src, peer);
+        assert_eq!(incoming.route, L2Route { queue: 3, ..route("203.0.113.1") });
+
+        // 双栈 socket 上 IPv4 来源表示为映射地址，回复时还原
+        let dual: SocketAddr = "[::]:9000".parse().unwrap();
+        let mapped = parse_frame(&frame, 0, dual).unwrap().src;
+        assert_eq!(mapped, "[::ffff:198.51.100.7]:40000".parse().unwrap());
+        assert_eq!(canonical(mapped.ip()), peer.ip());
+
+        // 其他端口、其他本机地址、截断的帧
+        assert!(parse_frame(&frame, 0, "0.0.0.0:9001".parse().unwrap()).is_none());
+        assert!(parse_frame(&frame, 0, "203.0.113.2:9000".parse().unwrap()).is_none());
+        assert!(parse_frame(&frame[..frame.len() - 1], 0, any).is_none());
+
+        let peer6: SocketAddr = "[2001:db8::7]:40000".parse().unwrap();
+        let frame = frame_from(peer6, "2001:db8::1", b"data");
+        assert_eq!(parse_frame(&frame, 0, dual).unwrap().src, peer6);
+    }
+}
@@ -0,0 +1,214 @@
+// vpn_server/src/wasm.rs
+// WASM 策略模块（--wasm-policy，需要以 wasm feature 编译）
+//
+// 运维人员可以把自定义的包策略编译成 WebAssembly 模块，服务端启动时加载，注册为转发路径钩子（见 vpn_core::hooks），
+// 不用重新编译服务端。模块的接口：
+//
+//     (memory (export "memory") ...)
+//     (func (export "packet_buffer") (result i32))          ;; 包缓冲区在线性内存中的偏移
+//     (func (export "on_packet") (param $len i32) (param $direction i32) (param $client i32) (result i32))
+//
+// * 服务端把内层 IP 包写到 packet_buffer() 处再调用 on_packet；direction 为 0（来自客户端）或 1（发往客户端），
+//   client 为所属客户端的虚拟 IP（大端 u32，未知时为 0）
+// * 返回 0 继续交给下一个钩子，1 放行，2 丢弃（计入 wasm_policy）；返回 0/1 时缓冲区中的内容写回，
+//   模块可以原地改写包（长度不变，自行更新校验和）
+//
+// 沙箱：模块不能导入任何宿主函数（没有 WASI，无法访问文件和网络），每个包最多执行 FUEL_PER_PACKET 单位的指令，
+// 线性内存不超过 MAX_MEMORY。超出限制、陷入（trap）或返回未知值时丢弃该包（计入 wasm_error）。
+// 每个模块一个实例，调用时加锁：模块串行处理包，吞吐要求高时应保持策略简单。
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow};
+use vpn_core::hooks::{Direction, HookVerdict, PacketContext, PacketHook};
+use vpn_core::trace_packet;
+use wasmtime::{Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+/// 每个包最多消耗的 fuel（大致对应 WASM 指令数）
+pub const FUEL_PER_PACKET: u64 = 100_000;
+/// 模块线性内存的上限
+pub const MAX_MEMORY: usize = 16 << 20;
+
+const VERDICT_CONTINUE: i32 = 0;
+const VERDICT_ACCEPT: i32 = 1;
+const VERDICT_DROP: i32 = 2;
+
+/// 加载 `--wasm-policy <文件>`（可重复）指定的模块，按参数顺序返回
+pub fn from_args(args: &[String]) -> Result<Vec<Arc<dyn PacketHook>>> {
+    let paths = crate::arg_values(args, "--wasm-policy");
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let engine = engine()?;
+    paths
+        .iter()
+        .map(|path| WasmPolicy::load(&engine, Path::new(path)).map(|p| Arc::new(p) as Arc<dyn PacketHook>))
+        .collect()
+}
+
+/// 开启 fuel 计量的引擎
+fn engine() -> Result<Engine> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    Ok(Engine::new(&config)?)
+}
+
+/// 一个已加载的策略模块
+pub struct WasmPolicy {
+    name: String,
+    instance: Mutex<PolicyInstance>,
+}
+
+struct PolicyInstance {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    /// 包缓冲区在线性内存中的偏移
+    buffer: usize,
+    on_packet: TypedFunc<(i32, i32, i32), i32>,
+}
+
+impl WasmPolicy {
+    /// 从 .wasm 或 .wat 文件加载，钩子名称为 `wasm:<文件名>`
+    pub fn load(engine: &Engine, path: &Path) -> Result<Self> {
+        let module = Module::from_file(engine, path).map_err(|e| anyhow!("无法加载策略模块 {}: {}", path.display(), e))?;
+        let name = format!("wasm:{}", path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default());
+        Self::instantiate(name, engine, &module)
+    }
+
+    fn instantiate(name: String, engine: &Engine, module: &Module) -> Result<Self> {
+        if let Some(import) = module.imports().next() {
+            return Err(anyhow!("策略模块 {} 不能导入宿主函数: {}::{}", name, import.module(), import.name()));
+        }
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).instances(1).build();
+        let mut store = Store::new(engine, limits);
+        store.limiter(|limits| limits);
+        // 模块的初始化（start 函数）同样受 fuel 限制
+        store.set_fuel(FUEL_PER_PACKET)?;
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(|| anyhow!("策略模块 {} 没有导出 memory", name))?;
+        let buffer = instance.get_typed_func::<(), i32>(&mut store, "packet_buffer")?.call(&mut store, ())? as u32 as usize;
+        let on_packet = instance.get_typed_func::<(i32, i32, i32), i32>(&mut store, "on_packet")?;
+        Ok(Self { name, instance: Mutex::new(PolicyInstance { store, memory, buffer, on_packet }) })
+    }
+}
+
+impl PolicyInstance {
+    fn call(&mut self, ctx: &PacketContext, packet: &mut [u8]) -> Result<HookVerdict> {
+        let range = self.buffer..self.buffer + packet.len();
+        self.memory
+            .data_mut(&mut self.store)
+            .get_mut(range.clone())
+            .ok_or_else(|| anyhow!("包缓冲区越界"))?
+            .copy_from_slice(packet);
+        self.store.set_fuel(FUEL_PER_PACKET)?;
+        let direction = match ctx.direction {
+            Direction::FromClient => 0,
+            Direction::ToClient => 1,
+        };
+        let client = ctx.client.map_or(0, u32::from) as i32;
+        let verdict = match self.on_packet.call(&mut self.store, (packet.len() as i32, direction, client))? {
+            VERDICT_CONTINUE => HookVerdict::Continue,
+            VERDICT_ACCEPT => HookVerdict::Accept,
+            VERDICT_DROP => return Ok(HookVerdict::Drop("wasm_policy")),
+            other => return Err(anyhow!("未知的返回值: {}", other)),
+        };
+        // 线性内存可能在调用中增长，重新取一次
+        let data = self.memory.data(&self.store).get(range).ok_or_else(|| anyhow!("包缓冲区越界"))?;
+        packet.copy_from_slice(data);
+        Ok(verdict)
+    }
+}
+
+impl PacketHook for WasmPolicy {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn on_packet(&self, ctx: &PacketContext, packet: &mut Vec<u8>) -> HookVerdict {
+        match self.instance.lock().unwrap().call(ctx, packet) {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                trace_packet!("🧩 {} 出错: {}", self.name, e);
+                HookVerdict::Drop("wasm_error")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// 丢弃 UDP，其余包的 TTL 改为 9
+    const POLICY: &str = r#"
+        (module
+          (memory (export "memory") 2)
+          (func (export "packet_buffer") (result i32) (i32.const 1024))
+          (func (export "on_packet") (param $len i32) (param $direction i32) (param $client i32) (result i32)
+            (if (i32.eq (i32.load8_u (i32.const 1033)) (i32.const 17)) (then (return (i32.const 2))))
+            (i32.store8 (i32.const 1032) (i32.const 9))
+            (i32.const 0)))
+    "#;
+
+    fn policy(wat: &str) -> Result<WasmPolicy> {
+        let engine = engine()?;
+        let module = Module::new(&engine, wat)?;
+        WasmPolicy::instantiate("wasm:test".to_string(), &engine, &module)
+    }
+
+    fn packet(protocol: u8) -> Vec<u8> {
+        vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, protocol, 0, 0, 10, 0, 0, 2, 1, 1, 1, 1]
+    }
+
+    #[test]
+    fn test_wasm_policy() {
+        let policy = policy(POLICY).unwrap();
+        let ctx = PacketContext {
+            direction: Direction::FromClient,
+            client: Some(Ipv4Addr::new(10, 0, 0, 2)),
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            dst: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        };
+        let mut udp = packet(17);
+        assert_eq!(policy.on_packet(&ctx, &mut udp), HookVerdict::Drop("wasm_policy"));
+        let mut tcp = packet(6);
+        assert_eq!(policy.on_packet(&ctx, &mut tcp), HookVerdict::Continue);
+        assert_eq!(tcp[8], 9);
+        // 超出缓冲区（线性内存）的包
+        let mut huge = vec![0x45; 200_000];
+        assert_eq!(policy.on_packet(&ctx, &mut huge), HookVerdict::Drop("wasm_error"));
+    }
+
+    #[test]
+    fn test_sandbox_limits() {
+        // 死循环耗尽 fuel 后丢弃，之后的包照常处理
+        let spin = policy(
+            r#"(module
+                 (memory (export "memory") 1)
+                 (func (export "packet_buffer") (result i32) (i32.const 0))
+                 (func (export "on_packet") (param i32 i32 i32) (result i32)
+                   (if (i32.eq (local.get 1) (i32.const 1)) (then (loop (br 0))))
+                   (i32.const 1)))"#,
+        )
+        .unwrap();
+        let mut ctx = PacketContext {
+            direction: Direction::ToClient,
+            client: None,
+            src: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+        };
+        assert_eq!(spin.on_packet(&ctx, &mut packet(6)), HookVerdict::Drop("wasm_error"));
+        ctx.direction = Direction::FromClient;
+        assert_eq!(spin.on_packet(&ctx, &mut packet(6)), HookVerdict::Accept);
+
+        // 不能导入宿主函数，也不能申请超过上限的内存
+        let import = r#"(module (import "env" "open" (func)) (memory (export "memory") 1))"#;
+        assert!(policy(import).is_err());
+        let big = r#"(module (memory (export "memory") 1000)
+                      (func (export "packet_buffer") (result i32) (i32.const 0))
+                      (func (export "on_packet") (param i32 i32 i32) (result i32) (i32.const 0)))"#;
+        assert!(policy(big).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+// vpn_server/src/xsk.rs
+// AF_XDP socket：网卡一个接收队列上的 UMEM 和四个共享环（见内核文档 networking/af_xdp.rst）
+//
+//   UMEM    NUM_FRAMES 个 FRAME_SIZE 字节的帧，前一半用于接收，后一半用于发送
+//   fill    用户态 -> 内核：可以写入接收数据的帧
+//   rx      内核 -> 用户态：收到的帧（XDP 程序重定向过来的）
+//   tx      用户态 -> 内核：要发送的帧
+//   completion 内核 -> 用户态：发送完成、可以复用的帧
+//
+// 每个环是单生产者单消费者的：接收侧（fill + rx）和发送侧（tx + completion）各用一把锁。
+// 这里只处理帧，以太网/IP/UDP 头的解析和构造见 transport 模块。
+
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// 每个帧的大小（对齐模式下必须是 2 的幂）
+pub const FRAME_SIZE: usize = 2048;
+/// 每个 socket 的帧数
+const NUM_FRAMES: usize = 4096;
+/// 接收用的帧数，也是 fill / rx 环的大小
+const RX_FRAMES: usize = NUM_FRAMES / 2;
+/// 发送用的帧数，也是 tx / completion 环的大小
+const TX_FRAMES: usize = NUM_FRAMES - RX_FRAMES;
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpUmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct XdpRingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct XdpMmapOffsets {
+    rx: XdpRingOffset,
+    tx: XdpRingOffset,
+    fr: XdpRingOffset,
+    cr: XdpRingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// rx / tx 环中的描述符
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct XdpDesc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+/// 一段 mmap 出来的内存，释放时 munmap
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(len: usize, flags: libc::c_int, fd: RawFd, offset: libc::off_t) -> io::Result<Self> {
+        // SAFETY: 新建映射，不涉及已有内存
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, flags, fd, offset) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr.cast(), len })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: ptr/len 来自 mmap，之后不再使用
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// 共享环：生产者/消费者下标是映射内的原子变量，大小为 2 的幂，下标自由增长、按大小取模
+struct Ring<T> {
+    _mapping: Mapping,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    entries: *mut T,
+    size: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    fn map(fd: RawFd, offset: &XdpRingOffset, size: usize, pgoff: libc::off_t) -> io::Result<Self> {
+        let len = offset.desc as usize + size * std::mem::size_of::<T>();
+        let mapping = Mapping::new(len, libc::MAP_SHARED | libc::MAP_POPULATE, fd, pgoff)?;
+        let base = mapping.ptr;
+        // SAFETY: 偏移由内核给出，都在映射范围内
+        unsafe {
+            Ok(Self {
+                producer: base.add(offset.producer as usize).cast(),
+                consumer: base.add(offset.consumer as usize).cast(),
+                entries: base.add(offset.desc as usize).cast(),
+                size: size as u32,
+                _mapping: mapping,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // SAFETY: 指向映射内对齐的 u32，映射与 Ring 同生命周期
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // SAFETY: 同上
+        unsafe { &*self.consumer }
+    }
+
+    fn read(&self, index: u32) -> T {
+        // SAFETY: 下标按环大小取模
+        unsafe { *self.entries.add((index & (self.size - 1)) as usize) }
+    }
+
+    fn write(&self, index: u32, value: T) {
+        // SAFETY: 同上
+        unsafe { *self.entries.add((index & (self.size - 1)) as usize) = value }
+    }
+}
+
+struct RxSide {
+    fill: Ring<u64>,
+    rx: Ring<XdpDesc>,
+}
+
+struct TxSide {
+    tx: Ring<XdpDesc>,
+    completion: Ring<u64>,
+    /// 空闲的发送帧
+    free: Vec<u64>,
+}
+
+/// 绑定在一个接收队列上的 AF_XDP socket
+pub struct XskSocket {
+    // 字段按声明顺序释放：先解除环的映射，再释放 UMEM，最后关闭 socket
+    rx: Mutex<RxSide>,
+    tx: Mutex<TxSide>,
+    umem: Mapping,
+    fd: OwnedFd,
+    pub queue: u32,
+}
+
+// SAFETY: 环和 UMEM 中的裸指针只在对应的锁内访问
+unsafe impl Send for XskSocket {}
+unsafe impl Sync for XskSocket {}
+
+fn setsockopt<T>(fd: RawFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: value 在调用期间有效，长度与类型一致
+    let ret = unsafe { libc::setsockopt(fd, SOL_XDP, name, (value as *const T).cast(), std::mem::size_of::<T>() as libc::socklen_t) };
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+}
+
+/// 网卡的接口索引
+pub fn ifindex(interface: &str) -> io::Result<u32> {
+    let name = CString::new(interface).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: name 是有效的 C 字符串
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// 网卡的接收队列数（/sys/class/net/<接口>/queues/rx-*）
+pub fn rx_queues(interface: &str) -> io::Result<u32> {
+    let count = std::fs::read_dir(format!("/sys/class/net/{}/queues", interface))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+    Ok(count.max(1) as u32)
+}
+
+impl XskSocket {
+    /// 创建 socket 并绑定到接口的一个接收队列（由内核决定零拷贝还是复制模式）
+    pub fn bind(ifindex: u32, queue: u32) -> io::Result<Self> {
+        // SAFETY: 普通的 socket 调用，返回值检查后交给 OwnedFd
+        let raw = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if raw < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: raw 是刚创建的有效 fd
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+        let raw = fd.as_raw_fd();
+
+        let umem = Mapping::new(NUM_FRAMES * FRAME_SIZE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)?;
+        let reg = XdpUmemReg { addr: umem.ptr as u64, len: umem.len as u64, chunk_size: FRAME_SIZE as u32, ..Default::default() };
+        setsockopt(raw, XDP_UMEM_REG, &reg)?;
+        setsockopt(raw, XDP_UMEM_FILL_RING, &(RX_FRAMES as u32))?;
+        setsockopt(raw, XDP_UMEM_COMPLETION_RING, &(TX_FRAMES as u32))?;
+        setsockopt(raw, XDP_RX_RING, &(RX_FRAMES as u32))?;
+        setsockopt(raw, XDP_TX_RING, &(TX_FRAMES as u32))?;
+
+        let mut offsets = XdpMmapOffsets::default();
+        let mut len = std::mem::size_of::<XdpMmapOffsets>() as libc::socklen_t;
+        // SAFETY: offsets 足够容纳 len 字节
+        let ret = unsafe { libc::getsockopt(raw, SOL_XDP, XDP_MMAP_OFFSETS, (&mut offsets as *mut XdpMmapOffsets).cast(), &mut len) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if len as usize != std::mem::size_of::<XdpMmapOffsets>() {
+            // 5.4 之前的内核没有 flags 字段
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "内核的 AF_XDP 版本过旧"));
+        }
+
+        let rx = RxSide {
+            fill: Ring::map(raw, &offsets.fr, RX_FRAMES, XDP_UMEM_PGOFF_FILL_RING)?,
+            rx: Ring::map(raw, &offsets.rx, RX_FRAMES, XDP_PGOFF_RX_RING)?,
+        };
+        let tx = TxSide {
+            tx: Ring::map(raw, &offsets.tx, TX_FRAMES, XDP_PGOFF_TX_RING)?,
+            completion: Ring::map(raw, &offsets.cr, TX_FRAMES, XDP_UMEM_PGOFF_COMPLETION_RING)?,
+            free: (RX_FRAMES..NUM_FRAMES).map(|i| (i * FRAME_SIZE) as u64).collect(),
+        };
+
+        // 接收帧全部交给内核
+        for i in 0..RX_FRAMES {
+            rx.fill.write(i as u32, (i * FRAME_SIZE) as u64);
+        }
+        rx.fill.producer().store(RX_FRAMES as u32, Ordering::Release);
+
+        let addr = SockaddrXdp { family: AF_XDP as u16, flags: 0, ifindex, queue_id: queue, shared_umem_fd: 0 };
+        // SAFETY: addr 在调用期间有效
+        let ret = unsafe { libc::bind(raw, (&addr as *const SockaddrXdp).cast(), std::mem::size_of::<SockaddrXdp>() as libc::socklen_t) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { rx: Mutex::new(rx), tx: Mutex::new(tx), umem, fd, queue })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+
+    /// UMEM 中一帧的起始地址。只有持有这一帧（刚从 rx 环取出或是空闲的发送帧，还没交回内核）时才能访问
+    fn frame(&self, addr: u64, len: usize) -> *mut u8 {
+        debug_assert!(addr as usize + len <= self.umem.len);
+        self.umem.ptr.wrapping_add(addr as usize)
+    }
+
+    /// 取出 rx 环中所有已到达的帧，逐个交给 f，之后把帧放回 fill 环；返回帧数
+    pub fn recv_batch(&self, mut f: impl FnMut(&[u8])) -> usize {
+        let side = self.rx.lock().unwrap();
+        let consumer = side.rx.consumer().load(Ordering::Relaxed);
+        let available = side.rx.producer().load(Ordering::Acquire).wrapping_sub(consumer);
+        if available == 0 {
+            return 0;
+        }
+        let fill = side.fill.producer().load(Ordering::Relaxed);
+        for i in 0..available {
+            let desc = side.rx.read(consumer.wrapping_add(i));
+            // SAFETY: 帧刚从 rx 环取出，放回 fill 环之前归本端所有
+            f(unsafe { std::slice::from_raw_parts(self.frame(desc.addr, desc.len as usize), desc.len as usize) });
+            // 对齐模式下 addr 可能带有 headroom 偏移，放回时取帧的起始地址
+            side.fill.write(fill.wrapping_add(i), desc.addr - desc.addr % FRAME_SIZE as u64);
+        }
+        side.rx.consumer().store(consumer.wrapping_add(available), Ordering::Release);
+        // 接收帧总数等于 fill 环大小，放回时不会溢出
+        side.fill.producer().store(fill.wrapping_add(available), Ordering::Release);
+        available as usize
+    }
+
+    /// 发送一帧：build 在 len 字节的帧里写入内容。没有空闲帧时返回 WouldBlock，由调用方改走普通 socket
+    pub fn send(&self, len: usize, build: impl FnOnce(&mut [u8])) -> io::Result<()> {
+        if len > FRAME_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut side = self.tx.lock().unwrap();
+        // 回收发送完成的帧
+        let consumer = side.completion.consumer().load(Ordering::Relaxed);
+        let completed = side.completion.producer().load(Ordering::Acquire).wrapping_sub(consumer);
+        for i in 0..completed {
+            let addr = side.completion.read(consumer.wrapping_add(i));
+            side.free.push(addr);
+        }
+        side.completion.consumer().store(consumer.wrapping_add(completed), Ordering::Release);
+
+        let Some(addr) = side.free.pop() else {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        };
+        // SAFETY: 空闲帧在提交到 tx 环之前归本端所有
+        build(unsafe { std::slice::from_raw_parts_mut(self.frame(addr, len), len) });
+        // 发送帧总数等于 tx 环大小，拿到空闲帧就一定有空位
+        let producer = side.tx.producer().load(Ordering::Relaxed);
+        side.tx.write(producer, XdpDesc { addr, len: len as u32, options: 0 });
+        side.tx.producer().store(producer.wrapping_add(1), Ordering::Release);
+        drop(side);
+
+        // 通知内核处理 tx 环（复制模式下由这次调用完成发送）。失败（EAGAIN / EBUSY 等）时帧仍在环里，
+        // 下一次唤醒时一起发送，所以不能再改走普通 socket，否则会发两份
+        // SAFETY: 不带缓冲区的 sendto，只是唤醒
+        unsafe { libc::sendto(self.fd(), ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        Ok(())
+    }
+}